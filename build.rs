@@ -0,0 +1,58 @@
+fn main() {
+    // Links the Node.js addon
+    #[cfg(feature = "node")]
+    napi_build::setup();
+
+    // Generates the gRPC service stubs. Stubs are built from this description rather than
+    // proto/rules/v1/rules.proto so the build doesn't need protoc, so keep the two in step.
+    #[cfg(feature = "grpc")]
+    {
+        use tonic_build::manual::{Builder, Method, Service};
+
+        let method = |name: &str, route: &str, input: &str, output: &str| {
+            Method::builder()
+                .name(name)
+                .route_name(route)
+                .input_type(format!("crate::grpc::proto::{}", input))
+                .output_type(format!("crate::grpc::proto::{}", output))
+                .codec_path("tonic::codec::ProstCodec")
+        };
+
+        let service = Service::builder()
+            .name("RulesService")
+            .package("rules.v1")
+            .method(
+                method(
+                    "evaluate",
+                    "Evaluate",
+                    "EvaluateRequest",
+                    "EvaluateResponse",
+                )
+                .build(),
+            )
+            .method(
+                method(
+                    "validate",
+                    "Validate",
+                    "ValidateRequest",
+                    "ValidateResponse",
+                )
+                .build(),
+            )
+            .method(
+                method(
+                    "stream_evaluate",
+                    "StreamEvaluate",
+                    "EvaluateRequest",
+                    "EvaluateResponse",
+                )
+                .client_streaming()
+                .server_streaming()
+                .build(),
+            )
+            .build();
+
+        Builder::new().compile(&[service]);
+        println!("cargo:rerun-if-changed=build.rs");
+    }
+}
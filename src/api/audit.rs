@@ -0,0 +1,187 @@
+// Append-only log of configuration writes -- e.g. "1760000000\twrite_tag\tcolours.tags\t9c4f...\talice"
+use crate::err::RulesError;
+use crate::types::{AuditEntry, AuditOperation, RuleId};
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Kept in the config directory, next to the files it records
+pub const AUDIT_FILE: &str = "audit.log";
+
+const FIELD_SEPARATOR: char = '\t';
+
+// Seconds since the Unix epoch
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// 64-bit FNV-1a, stable across Rust versions unlike std's hashers
+pub fn content_hash(content: &[u8]) -> String {
+    let hash = content.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+// Actors are free text, so separators and line breaks are replaced to keep one entry per line
+fn sanitise_actor(actor: &str) -> String {
+    actor
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+fn format_entry(entry: &AuditEntry) -> String {
+    [
+        entry.timestamp.to_string(),
+        entry.operation.as_str().to_string(),
+        entry.file.clone(),
+        entry.content_hash.clone(),
+        entry
+            .actor
+            .as_deref()
+            .map(sanitise_actor)
+            .unwrap_or_default(),
+        entry
+            .rule_id
+            .as_ref()
+            .map(RuleId::to_string)
+            .unwrap_or_default(),
+    ]
+    .join(&FIELD_SEPARATOR.to_string())
+}
+
+fn parse_entry(line: &str, line_no: usize) -> Result<AuditEntry, RulesError> {
+    let invalid = || {
+        RulesError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}:{}: invalid audit entry '{}'", AUDIT_FILE, line_no, line),
+        ))
+    };
+
+    // Entries written before rules were identified have no rule field
+    let fields: Vec<&str> = line.split(FIELD_SEPARATOR).collect();
+    let (timestamp, operation, file, content_hash, actor, rule_id) = match fields[..] {
+        [timestamp, operation, file, content_hash, actor] => {
+            (timestamp, operation, file, content_hash, actor, "")
+        }
+        [timestamp, operation, file, content_hash, actor, rule_id] => {
+            (timestamp, operation, file, content_hash, actor, rule_id)
+        }
+        _ => return Err(invalid()),
+    };
+
+    Ok(AuditEntry {
+        timestamp: timestamp.parse().map_err(|_| invalid())?,
+        operation: AuditOperation::parse(operation).ok_or_else(invalid)?,
+        file: file.to_string(),
+        content_hash: content_hash.to_string(),
+        actor: (!actor.is_empty()).then(|| actor.to_string()),
+        rule_id: match rule_id {
+            "" => None,
+            rule_id => Some(RuleId::parse(rule_id).ok_or_else(invalid)?),
+        },
+    })
+}
+
+// Record a write to `file`, relative to `base_dir`, hashing the file as it is now. `rule_id` names
+// the rule written, for writes of a single rule
+pub fn append(
+    base_dir: &str,
+    operation: AuditOperation,
+    file: &str,
+    actor: Option<&str>,
+    rule_id: Option<&RuleId>,
+) -> Result<AuditEntry, RulesError> {
+    let content = fs::read(Path::new(base_dir).join(file)).unwrap_or_default();
+
+    let entry = AuditEntry {
+        timestamp: unix_timestamp(),
+        operation,
+        file: file.to_string(),
+        content_hash: content_hash(&content),
+        actor: actor.map(sanitise_actor),
+        rule_id: rule_id.cloned(),
+    };
+
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(Path::new(base_dir).join(AUDIT_FILE))?;
+    writeln!(log, "{}", format_entry(&entry))?;
+
+    Ok(entry)
+}
+
+// Every entry in `base_dir`'s audit log, oldest first
+pub fn read(base_dir: &str) -> Result<Vec<AuditEntry>, RulesError> {
+    let path = Path::new(base_dir).join(AUDIT_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| parse_entry(line, i + 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(content_hash(b""), "cbf29ce484222325");
+        assert_eq!(content_hash(b"a"), "af63dc4c8601ec8c");
+        assert_ne!(
+            content_hash(b"- colour: red"),
+            content_hash(b"- colour: blue")
+        );
+    }
+
+    #[test]
+    fn test_format_and_parse_entry() {
+        let entry = AuditEntry {
+            timestamp: 1760000000,
+            operation: AuditOperation::WriteRule,
+            file: "my_rules.rules".to_string(),
+            content_hash: content_hash(b"- colour = red"),
+            actor: Some("ci\tbot".to_string()),
+            rule_id: Some(RuleId::new("my_rules.rules", 3, "- colour = red")),
+        };
+
+        let line = format_entry(&entry);
+        assert_eq!(line.matches('\t').count(), 5);
+
+        let parsed = parse_entry(&line, 1).unwrap();
+        assert_eq!(parsed.actor.as_deref(), Some("ci bot"));
+        assert_eq!(parsed.operation, AuditOperation::WriteRule);
+        assert_eq!(parsed.content_hash, entry.content_hash);
+        assert_eq!(parsed.rule_id, entry.rule_id);
+
+        let anonymous = AuditEntry {
+            actor: None,
+            ..entry
+        };
+        assert_eq!(
+            parse_entry(&format_entry(&anonymous), 1).unwrap(),
+            anonymous
+        );
+
+        // Entries from before rules were identified
+        let legacy = parse_entry("1760000000\twrite_tag\tcolours.tags\tabc\t", 2).unwrap();
+        assert_eq!(legacy.rule_id, None);
+
+        assert!(parse_entry("1760000000\twrite_tag\tcolours.tags", 3).is_err());
+        assert!(parse_entry("1760000000\twrite_rule\tr.rules\tabc\t\tr.rules#abc", 3).is_err());
+        assert!(parse_entry("soon\twrite_tag\tcolours.tags\tabc\t", 3).is_err());
+        assert!(parse_entry("1760000000\tdelete\tcolours.tags\tabc\t", 3).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+// Evaluation results keyed by object fingerprint, emptied whenever the ruleset changes
+use crate::parser::rules::TODAY_VARIABLE;
+use crate::types::{CacheOptions, CacheStats, LoadedRule, Object};
+use crate::utils::time;
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::time::Instant;
+
+struct CacheEntry {
+    // Kept to rule out fingerprint collisions
+    m_object: Object,
+    m_matched: Vec<LoadedRule>,
+    m_inserted: Instant,
+    // Unix seconds the result holds for, between the time-scoped rules' boundaries
+    m_valid: Range<u64>,
+    // Tick of the last lookup, for evicting the least recently used
+    m_last_used: u64,
+}
+
+pub struct EvaluationCache {
+    m_options: CacheOptions,
+    m_entries: HashMap<u64, CacheEntry>,
+    m_version: u64,
+    m_tick: u64,
+    m_hits: u64,
+    m_misses: u64,
+}
+
+// Hash of an object's tags and values, and the ruleset version it was evaluated against. Tags
+// are hashed by name order, so the same object always has the same fingerprint.
+pub fn fingerprint(obj: &Object, version: u64) -> u64 {
+    let mut tags: Vec<(&String, &Vec<String>)> = obj.iter().collect();
+    tags.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut hasher = DefaultHasher::new();
+    version.hash(&mut hasher);
+    tags.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Window around `timestamp` in which no rule comes into or goes out of effect, so results at
+// any time in it are the same -- e.g. 2025-01-01..2025-07-01
+pub fn validity(rules: &[LoadedRule], timestamp: u64) -> Range<u64> {
+    let boundaries = rules
+        .iter()
+        .flat_map(|loaded| [loaded.effective_from(), loaded.expires()])
+        .flatten();
+
+    // Rules comparing against $today can change result at midnight
+    let day_start = timestamp - timestamp % time::SECONDS_PER_DAY;
+    let date_boundaries = rules
+        .iter()
+        .any(|loaded| loaded.rule.contains(TODAY_VARIABLE))
+        .then_some([day_start, day_start + time::SECONDS_PER_DAY])
+        .into_iter()
+        .flatten();
+
+    let mut valid = 0..u64::MAX;
+    for boundary in boundaries.chain(date_boundaries) {
+        if boundary <= timestamp {
+            valid.start = valid.start.max(boundary);
+        } else {
+            valid.end = valid.end.min(boundary);
+        }
+    }
+    valid
+}
+
+impl EvaluationCache {
+    pub fn new(options: CacheOptions) -> Self {
+        Self {
+            m_options: options,
+            m_entries: HashMap::new(),
+            m_version: 0,
+            m_tick: 0,
+            m_hits: 0,
+            m_misses: 0,
+        }
+    }
+
+    // Rules the object matched last time, if it's cached, hasn't expired and the result holds
+    // at `timestamp`
+    pub fn get(&mut self, obj: &Object, timestamp: u64) -> Option<Vec<LoadedRule>> {
+        let key = fingerprint(obj, self.m_version);
+        self.m_tick += 1;
+
+        let ttl = self.m_options.ttl;
+        let expired = self
+            .m_entries
+            .get(&key)
+            .is_some_and(|entry| ttl.is_some_and(|ttl| entry.m_inserted.elapsed() >= ttl));
+        if expired {
+            self.m_entries.remove(&key);
+        }
+
+        match self.m_entries.get_mut(&key) {
+            Some(entry) if entry.m_object == *obj && entry.m_valid.contains(&timestamp) => {
+                entry.m_last_used = self.m_tick;
+                self.m_hits += 1;
+                Some(entry.m_matched.clone())
+            }
+            _ => {
+                self.m_misses += 1;
+                None
+            }
+        }
+    }
+
+    // Ruleset version new results are cached under
+    pub fn version(&self) -> u64 {
+        self.m_version
+    }
+
+    // Cache the rules an object matched, unless the ruleset has changed since `version`, when
+    // the evaluation started
+    pub fn insert(
+        &mut self,
+        obj: &Object,
+        matched: &[LoadedRule],
+        version: u64,
+        valid: Range<u64>,
+    ) {
+        if self.m_options.capacity == 0 || version != self.m_version {
+            return;
+        }
+
+        let key = fingerprint(obj, self.m_version);
+        if !self.m_entries.contains_key(&key) && self.m_entries.len() >= self.m_options.capacity {
+            self.evict();
+        }
+
+        self.m_tick += 1;
+        self.m_entries.insert(
+            key,
+            CacheEntry {
+                m_object: obj.clone(),
+                m_matched: matched.to_vec(),
+                m_inserted: Instant::now(),
+                m_valid: valid,
+                m_last_used: self.m_tick,
+            },
+        );
+    }
+
+    fn evict(&mut self) {
+        let oldest = self
+            .m_entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.m_last_used)
+            .map(|(key, _)| *key);
+
+        if let Some(key) = oldest {
+            self.m_entries.remove(&key);
+        }
+    }
+
+    // Drop every entry after the ruleset changes -- e.g. on reload
+    pub fn invalidate(&mut self) {
+        self.m_version += 1;
+        self.m_entries.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.m_hits,
+            misses: self.m_misses,
+            entries: self.m_entries.len(),
+            ruleset_version: self.m_version,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::RuleAnnotation;
+    use std::time::Duration;
+
+    const ALWAYS: Range<u64> = 0..u64::MAX;
+
+    fn object(colour: &str) -> Object {
+        let mut obj = Object::new();
+        obj.insert("colour".to_string(), vec![colour.to_string()]);
+        obj.insert("size".to_string(), vec!["small".to_string()]);
+        obj
+    }
+
+    fn matched(rule: &str) -> Vec<LoadedRule> {
+        vec![LoadedRule {
+            file: "test.rules".to_string(),
+            line: 1,
+            rule: rule.to_string(),
+            annotations: Vec::new(),
+        }]
+    }
+
+    fn scoped(effective_from: Option<&str>, expires: Option<&str>) -> LoadedRule {
+        let mut loaded = matched("- colour = red").remove(0);
+        for (name, value) in [("effective_from", effective_from), ("expires", expires)] {
+            if let Some(value) = value {
+                loaded.annotations.push(RuleAnnotation {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                });
+            }
+        }
+        loaded
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut reordered = Object::new();
+        reordered.insert("size".to_string(), vec!["small".to_string()]);
+        reordered.insert("colour".to_string(), vec!["red".to_string()]);
+
+        assert_eq!(fingerprint(&object("red"), 0), fingerprint(&reordered, 0));
+        assert_ne!(
+            fingerprint(&object("red"), 0),
+            fingerprint(&object("blue"), 0)
+        );
+        assert_ne!(
+            fingerprint(&object("red"), 0),
+            fingerprint(&object("red"), 1)
+        );
+    }
+
+    #[test]
+    fn test_cache_hits_and_invalidation() {
+        let mut cache = EvaluationCache::new(CacheOptions::default());
+        assert!(cache.get(&object("red"), 0).is_none());
+
+        cache.insert(
+            &object("red"),
+            &matched("- colour = red"),
+            cache.version(),
+            ALWAYS,
+        );
+        assert_eq!(
+            cache.get(&object("red"), 0),
+            Some(matched("- colour = red"))
+        );
+        assert!(cache.get(&object("blue"), 0).is_none());
+
+        cache.invalidate();
+        assert!(cache.get(&object("red"), 0).is_none());
+
+        // Results from before the ruleset changed aren't kept
+        cache.insert(&object("red"), &matched("- colour = red"), 0, ALWAYS);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 3,
+                entries: 0,
+                ruleset_version: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cache_capacity_and_ttl() {
+        let mut cache = EvaluationCache::new(CacheOptions {
+            capacity: 2,
+            ttl: None,
+        });
+        cache.insert(
+            &object("red"),
+            &matched("- colour = red"),
+            cache.version(),
+            ALWAYS,
+        );
+        cache.insert(
+            &object("blue"),
+            &matched("- colour = blue"),
+            cache.version(),
+            ALWAYS,
+        );
+        cache.get(&object("red"), 0);
+
+        // Blue is the least recently used
+        cache.insert(
+            &object("green"),
+            &matched("- colour = green"),
+            cache.version(),
+            ALWAYS,
+        );
+        assert_eq!(cache.stats().entries, 2);
+        assert!(cache.get(&object("red"), 0).is_some());
+        assert!(cache.get(&object("blue"), 0).is_none());
+
+        let mut cache = EvaluationCache::new(CacheOptions {
+            capacity: 0,
+            ttl: None,
+        });
+        cache.insert(
+            &object("red"),
+            &matched("- colour = red"),
+            cache.version(),
+            ALWAYS,
+        );
+        assert!(cache.get(&object("red"), 0).is_none());
+
+        let mut cache = EvaluationCache::new(CacheOptions {
+            capacity: 2,
+            ttl: Some(Duration::ZERO),
+        });
+        cache.insert(
+            &object("red"),
+            &matched("- colour = red"),
+            cache.version(),
+            ALWAYS,
+        );
+        assert!(cache.get(&object("red"), 0).is_none());
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn test_validity() {
+        let rules = vec![
+            scoped(Some("100"), Some("200")),
+            scoped(None, Some("300")),
+            matched("- colour = blue").remove(0),
+        ];
+
+        assert_eq!(validity(&rules, 50), 0..100);
+        assert_eq!(validity(&rules, 100), 100..200);
+        assert_eq!(validity(&rules, 250), 200..300);
+        assert_eq!(validity(&rules, 300), 300..u64::MAX);
+
+        // A result only holds inside its window
+        let mut cache = EvaluationCache::new(CacheOptions::default());
+        cache.insert(&object("red"), &rules[..1], 0, validity(&rules, 150));
+        assert!(cache.get(&object("red"), 150).is_some());
+        assert!(cache.get(&object("red"), 199).is_some());
+        assert!(cache.get(&object("red"), 200).is_none());
+        assert!(cache.get(&object("red"), 50).is_none());
+
+        // Rules comparing against $today only hold until midnight
+        let dated = matched("- colour = $today");
+        assert_eq!(validity(&dated, 86_400 + 50), 86_400..172_800);
+    }
+}
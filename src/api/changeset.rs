@@ -0,0 +1,361 @@
+// Semantic diff between two configurations -- e.g. tag colour gained green, one rule removed
+use crate::types::{
+    ChangeKind, LoadedObject, LoadedRule, Object, ObjectChange, RuleChange, TagChange,
+    TagConstraints, TagConstraintsMap, TagName, TagValues,
+};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+// File name and canonical form of each rule -- e.g. ("a.rules", "colour = red")
+type FileRules = Vec<(String, String)>;
+
+// Name of a config file without its directory -- e.g. "config/my_rules.rules" gives "my_rules.rules"
+pub fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map_or_else(
+        || path.to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+// Values are compared as a set, unless their order ranks them
+fn same_tag(
+    old_values: &TagValues,
+    old_constraints: Option<&TagConstraints>,
+    new_values: &TagValues,
+    new_constraints: Option<&TagConstraints>,
+) -> bool {
+    let default = TagConstraints::default();
+    let old_constraints = old_constraints.unwrap_or(&default);
+    let new_constraints = new_constraints.unwrap_or(&default);
+
+    let same_values = if old_constraints.ordered || new_constraints.ordered {
+        old_values == new_values
+    } else {
+        old_values.iter().collect::<BTreeSet<_>>() == new_values.iter().collect::<BTreeSet<_>>()
+    };
+
+    same_values
+        && old_constraints.pattern.as_ref().map(|p| p.as_str())
+            == new_constraints.pattern.as_ref().map(|p| p.as_str())
+        && old_constraints.open == new_constraints.open
+        && old_constraints.required == new_constraints.required
+        && old_constraints.flag == new_constraints.flag
+        && old_constraints.single == new_constraints.single
+        && old_constraints.ordered == new_constraints.ordered
+        && old_constraints.max_values == new_constraints.max_values
+        && old_constraints.max_length == new_constraints.max_length
+}
+
+// Tags added, removed or redefined, by name
+pub fn diff_tags(
+    old_tags: &HashMap<TagName, TagValues>,
+    old_constraints: &TagConstraintsMap,
+    new_tags: &HashMap<TagName, TagValues>,
+    new_constraints: &TagConstraintsMap,
+) -> Vec<TagChange> {
+    let names: BTreeSet<&TagName> = old_tags.keys().chain(new_tags.keys()).collect();
+    let mut changes: Vec<TagChange> = Vec::new();
+
+    for name in names {
+        let kind = match (old_tags.get(name), new_tags.get(name)) {
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(_), None) => ChangeKind::Removed,
+            (Some(old_values), Some(new_values)) => {
+                if same_tag(
+                    old_values,
+                    old_constraints.get(name),
+                    new_values,
+                    new_constraints.get(name),
+                ) {
+                    continue;
+                }
+                ChangeKind::Modified
+            }
+            (None, None) => continue,
+        };
+
+        changes.push(TagChange {
+            kind,
+            name: name.clone(),
+            old_values: old_tags.get(name).cloned().unwrap_or_default(),
+            new_values: new_tags.get(name).cloned().unwrap_or_default(),
+        });
+    }
+
+    changes
+}
+
+// Rules of each side without an equivalent in the same file on the other, given as file name,
+// canonical rule and canonicalized rule. Rewriting a rule without changing what it matches isn't a
+// change -- e.g. "a & (b | c)" becoming "a & b | a & c".
+pub fn cancel_equivalent(
+    old_rules: Vec<(String, String, String)>,
+    new_rules: Vec<(String, String, String)>,
+) -> (FileRules, FileRules) {
+    let mut old_rules = old_rules;
+    let mut added: FileRules = Vec::new();
+
+    for (file, rule, normal) in new_rules {
+        match old_rules
+            .iter()
+            .position(|(f, _, n)| *f == file && *n == normal)
+        {
+            Some(i) => {
+                old_rules.remove(i);
+            }
+            None => added.push((file, rule)),
+        }
+    }
+
+    (
+        old_rules
+            .into_iter()
+            .map(|(file, rule, _)| (file, rule))
+            .collect(),
+        added,
+    )
+}
+
+// Rules added, removed or modified in each file, given as file name and canonical rule. Rules
+// in both are unchanged wherever they moved, and the rest are paired in order as modifications.
+pub fn diff_rules(
+    old_rules: Vec<(String, String)>,
+    new_rules: Vec<(String, String)>,
+) -> Vec<RuleChange> {
+    let mut by_file: BTreeMap<String, (Vec<String>, Vec<String>)> = BTreeMap::new();
+    for (file, rule) in old_rules {
+        by_file.entry(file).or_default().0.push(rule);
+    }
+    for (file, rule) in new_rules {
+        by_file.entry(file).or_default().1.push(rule);
+    }
+
+    let mut changes: Vec<RuleChange> = Vec::new();
+
+    for (file, (mut removed, added)) in by_file {
+        let mut added_only: Vec<String> = Vec::new();
+        for rule in added {
+            match removed.iter().position(|r| *r == rule) {
+                Some(i) => {
+                    removed.remove(i);
+                }
+                None => added_only.push(rule),
+            }
+        }
+
+        let paired = removed.len().max(added_only.len());
+        let mut removed = removed.into_iter();
+        let mut added = added_only.into_iter();
+
+        for _ in 0..paired {
+            let (old_rule, new_rule) = (removed.next(), added.next());
+            let kind = match (&old_rule, &new_rule) {
+                (Some(_), Some(_)) => ChangeKind::Modified,
+                (Some(_), None) => ChangeKind::Removed,
+                _ => ChangeKind::Added,
+            };
+
+            changes.push(RuleChange {
+                kind,
+                file: file.clone(),
+                old_rule,
+                new_rule,
+            });
+        }
+    }
+
+    changes
+}
+
+// Rules matched only by the old and only by the new configuration, each keyed by file name and
+// canonical rule so the same rule matched on both sides cancels out wherever it moved
+pub fn diff_matches(
+    old_matched: Vec<((String, String), LoadedRule)>,
+    new_matched: Vec<((String, String), LoadedRule)>,
+) -> (Vec<LoadedRule>, Vec<LoadedRule>) {
+    let mut removed = old_matched;
+    let mut added: Vec<LoadedRule> = Vec::new();
+
+    for (key, loaded) in new_matched {
+        match removed.iter().position(|(k, _)| *k == key) {
+            Some(i) => {
+                removed.remove(i);
+            }
+            None => added.push(loaded),
+        }
+    }
+
+    (
+        removed.into_iter().map(|(_, loaded)| loaded).collect(),
+        added,
+    )
+}
+
+// Value order on an object doesn't matter -- e.g. [red, blue] is the same as [blue, red]
+fn same_object(old: &Object, new: &Object) -> bool {
+    let normalise = |obj: &Object| -> BTreeMap<String, BTreeSet<String>> {
+        obj.iter()
+            .map(|(k, v)| (k.clone(), v.iter().cloned().collect()))
+            .collect()
+    };
+
+    normalise(old) == normalise(new)
+}
+
+// Objects added, removed or modified, by file, type and position within the type
+pub fn diff_objects(
+    old_objects: Vec<LoadedObject>,
+    new_objects: Vec<LoadedObject>,
+) -> Vec<ObjectChange> {
+    type Key = (String, String, usize);
+    let key = |loaded: &LoadedObject| -> Key {
+        (
+            file_name(&loaded.file),
+            loaded.obj_type.clone(),
+            loaded.index,
+        )
+    };
+
+    let old: BTreeMap<Key, Object> = old_objects
+        .into_iter()
+        .map(|o| (key(&o), o.object))
+        .collect();
+    let new: BTreeMap<Key, Object> = new_objects
+        .into_iter()
+        .map(|o| (key(&o), o.object))
+        .collect();
+    let keys: BTreeSet<&Key> = old.keys().chain(new.keys()).collect();
+    let mut changes: Vec<ObjectChange> = Vec::new();
+
+    for (file, obj_type, index) in keys {
+        let k = (file.clone(), obj_type.clone(), *index);
+        let (old_object, new_object) = (old.get(&k), new.get(&k));
+        let kind = match (old_object, new_object) {
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(_), None) => ChangeKind::Removed,
+            (Some(o), Some(n)) if !same_object(o, n) => ChangeKind::Modified,
+            _ => continue,
+        };
+
+        changes.push(ObjectChange {
+            kind,
+            file: file.clone(),
+            obj_type: obj_type.clone(),
+            index: *index,
+            old_object: old_object.cloned(),
+            new_object: new_object.cloned(),
+        });
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[(&str, &str)]) -> Vec<(String, String)> {
+        rules
+            .iter()
+            .map(|(file, rule)| (file.to_string(), rule.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_tags() {
+        let mut old_tags = HashMap::new();
+        old_tags.insert(
+            "colour".to_string(),
+            vec!["red".to_string(), "blue".to_string()],
+        );
+        old_tags.insert("shape".to_string(), vec!["circle".to_string()]);
+        let mut new_tags = HashMap::new();
+        new_tags.insert(
+            "colour".to_string(),
+            vec!["blue".to_string(), "red".to_string()],
+        );
+        new_tags.insert("size".to_string(), vec!["small".to_string()]);
+
+        let changes = diff_tags(&old_tags, &HashMap::new(), &new_tags, &HashMap::new());
+        let summary: Vec<(&str, ChangeKind)> =
+            changes.iter().map(|c| (c.name.as_str(), c.kind)).collect();
+        assert_eq!(
+            summary,
+            vec![("shape", ChangeKind::Removed), ("size", ChangeKind::Added)]
+        );
+
+        // Reordering an ordered tag changes its ranks
+        let mut ordered = TagConstraintsMap::new();
+        ordered.insert(
+            "colour".to_string(),
+            TagConstraints {
+                ordered: true,
+                ..Default::default()
+            },
+        );
+        let changes = diff_tags(&old_tags, &ordered, &new_tags, &ordered);
+        assert_eq!(changes[0].name, "colour");
+        assert_eq!(changes[0].kind, ChangeKind::Modified);
+        assert_eq!(changes[0].new_values, vec!["blue", "red"]);
+    }
+
+    #[test]
+    fn test_diff_rules() {
+        let old = rules(&[
+            ("a.rules", "colour = red"),
+            ("a.rules", "size = small"),
+            ("b.rules", "shape = circle"),
+        ]);
+        let new = rules(&[
+            ("a.rules", "size = small"),
+            ("a.rules", "colour = blue"),
+            ("a.rules", "colour = red"),
+            ("c.rules", "shape = circle"),
+        ]);
+
+        let changes = diff_rules(old, new);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].kind, ChangeKind::Added);
+        assert_eq!(changes[0].new_rule.as_deref(), Some("colour = blue"));
+        assert_eq!(changes[1].kind, ChangeKind::Removed);
+        assert_eq!(changes[1].file, "b.rules");
+        assert_eq!(changes[2].kind, ChangeKind::Added);
+        assert_eq!(changes[2].file, "c.rules");
+
+        let changes = diff_rules(
+            rules(&[("a.rules", "colour = red")]),
+            rules(&[("a.rules", "colour = blue")]),
+        );
+        assert_eq!(changes[0].kind, ChangeKind::Modified);
+        assert_eq!(changes[0].old_rule.as_deref(), Some("colour = red"));
+    }
+
+    #[test]
+    fn test_cancel_equivalent() {
+        let normal = |file: &str, rule: &str, normal: &str| {
+            (file.to_string(), rule.to_string(), normal.to_string())
+        };
+        let (old, new) = cancel_equivalent(
+            vec![
+                normal(
+                    "a.rules",
+                    "colour = red & (size = large | size = small)",
+                    "x",
+                ),
+                normal("a.rules", "shape = circle", "y"),
+            ],
+            vec![
+                normal(
+                    "a.rules",
+                    "colour = red & size = large | colour = red & size = small",
+                    "x",
+                ),
+                normal("b.rules", "shape = circle", "y"),
+            ],
+        );
+
+        assert_eq!(old, rules(&[("a.rules", "shape = circle")]));
+        assert_eq!(new, rules(&[("b.rules", "shape = circle")]));
+    }
+}
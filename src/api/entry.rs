@@ -1,8 +1,18 @@
+use crate::Rules;
 use crate::err::RulesError;
-use crate::orchestrator::Orchestrator;
+use crate::types::{EvaluationOptions, EvaluationReport};
 
-pub fn evaluate() -> Result<(), RulesError> {
-    Orchestrator::run()
+// Evaluate every object in `config_dir` against its rules -- e.g. evaluate("config")
+pub fn evaluate(config_dir: &str) -> Result<EvaluationReport, RulesError> {
+    evaluate_with_options(config_dir, EvaluationOptions::default())
 }
 
-// TODO: Create base class which takes dir parameter, then all API-exposed methods belong to this class
+pub fn evaluate_with_options(
+    config_dir: &str,
+    options: EvaluationOptions,
+) -> Result<EvaluationReport, RulesError> {
+    let mut rules = Rules::new(config_dir);
+    rules.set_required_tag_policy(options.required_tags);
+    rules.load_tags()?;
+    rules.evaluate()
+}
@@ -0,0 +1,163 @@
+// Content-addressed snapshots of config files -- e.g. config/.history/objects/9c4f0a1b2d3e4f56
+use crate::api::audit::{content_hash, unix_timestamp};
+use crate::err::RulesError;
+use crate::types::FileVersion;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Kept in the config directory, out of reach of the *.tags and *.rules globs
+pub const HISTORY_DIR: &str = ".history";
+
+// File contents by hash, shared between every file and version
+const OBJECTS_DIR: &str = "objects";
+
+fn versions_path(base_dir: &str, file: &str) -> PathBuf {
+    Path::new(base_dir)
+        .join(HISTORY_DIR)
+        .join(format!("{}.versions", file))
+}
+
+fn object_path(base_dir: &str, hash: &str) -> PathBuf {
+    Path::new(base_dir)
+        .join(HISTORY_DIR)
+        .join(OBJECTS_DIR)
+        .join(hash)
+}
+
+fn invalid_data(message: String) -> RulesError {
+    RulesError::IoError(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+// Record `file`'s content as a new version, unless the file doesn't exist or is unchanged
+// since the latest version
+pub fn snapshot(base_dir: &str, file: &str) -> Result<(), RulesError> {
+    let Ok(content) = fs::read(Path::new(base_dir).join(file)) else {
+        return Ok(());
+    };
+    let hash = content_hash(&content);
+
+    if read(base_dir, file)?
+        .last()
+        .is_some_and(|latest| latest.content_hash == hash)
+    {
+        return Ok(());
+    }
+
+    let object = object_path(base_dir, &hash);
+    if !object.exists() {
+        fs::create_dir_all(Path::new(base_dir).join(HISTORY_DIR).join(OBJECTS_DIR))?;
+        fs::write(&object, &content)?;
+    }
+
+    let mut versions = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(versions_path(base_dir, file))?;
+    writeln!(versions, "{}\t{}", unix_timestamp(), hash)?;
+
+    Ok(())
+}
+
+// Every version of `file`, oldest first
+pub fn read(base_dir: &str, file: &str) -> Result<Vec<FileVersion>, RulesError> {
+    let path = versions_path(base_dir, file);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    fs::read_to_string(&path)?
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let (timestamp, hash) = line
+                .split_once('\t')
+                .and_then(|(t, h)| Some((t.parse().ok()?, h)))
+                .ok_or_else(|| {
+                    invalid_data(format!("{}:{}: invalid version", path.display(), i + 1))
+                })?;
+
+            Ok(FileVersion {
+                version: i + 1,
+                timestamp,
+                content_hash: hash.to_string(),
+            })
+        })
+        .collect()
+}
+
+// Content of `file` at `version`
+pub fn content(base_dir: &str, file: &str, version: usize) -> Result<String, RulesError> {
+    let found = read(base_dir, file)?
+        .into_iter()
+        .find(|v| v.version == version)
+        .ok_or_else(|| {
+            RulesError::IoError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Version {} of '{}' not found", version, file),
+            ))
+        })?;
+
+    let content = fs::read_to_string(object_path(base_dir, &found.content_hash))?;
+    if content_hash(content.as_bytes()) != found.content_hash {
+        return Err(invalid_data(format!(
+            "Snapshot {} of '{}' is corrupt",
+            found.content_hash, file
+        )));
+    }
+
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        test_dir
+    }
+
+    #[test]
+    fn test_snapshot_and_content() {
+        let test_dir = setup_test_env("test_history_snapshot");
+
+        // Nothing to keep for a file that doesn't exist yet
+        snapshot(&test_dir, "test.rules").unwrap();
+        assert!(read(&test_dir, "test.rules").unwrap().is_empty());
+
+        fs::write(format!("{}/test.rules", test_dir), "- colour = red\n").unwrap();
+        snapshot(&test_dir, "test.rules").unwrap();
+        snapshot(&test_dir, "test.rules").unwrap();
+        fs::write(format!("{}/test.rules", test_dir), "- colour = blue\n").unwrap();
+        snapshot(&test_dir, "test.rules").unwrap();
+
+        let versions = read(&test_dir, "test.rules").unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[1].version, 2);
+        assert_eq!(
+            content(&test_dir, "test.rules", 1).unwrap(),
+            "- colour = red\n"
+        );
+        assert_eq!(
+            content(&test_dir, "test.rules", 2).unwrap(),
+            "- colour = blue\n"
+        );
+        assert!(content(&test_dir, "test.rules", 3).is_err());
+
+        // A tampered snapshot isn't restored
+        let object = object_path(&test_dir, &versions[0].content_hash);
+        fs::write(&object, "- colour = green\n").unwrap();
+        let result = content(&test_dir, "test.rules", 1);
+        if let Err(RulesError::IoError(e)) = result {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        } else {
+            panic!("Expected IoError for corrupt snapshot");
+        }
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
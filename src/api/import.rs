@@ -0,0 +1,137 @@
+// Import tag definitions from CSV files -- e.g. "colour,red,Bright red" rows
+use crate::err::RulesError;
+use crate::parser::tags;
+use crate::types::{TagName, TagValues};
+use crate::utils::string;
+
+use std::fs;
+
+// Column names accepted on an optional header row
+const HEADER: [&str; 3] = ["tag", "value", "description"];
+
+fn is_header(record: &csv::StringRecord) -> bool {
+    record
+        .iter()
+        .zip(HEADER)
+        .all(|(field, expected)| field.eq_ignore_ascii_case(expected))
+}
+
+// Tag definitions from CSV rows of tag name, value and optional description, grouped by
+// tag in first-seen order. Descriptions are accepted but not kept, .tags files have no
+// place for them -- e.g. "colour,red\ncolour,blue" gives [("colour", ["red", "blue"])]
+pub fn parse_tags_csv(path: &str, content: &str) -> Result<Vec<(TagName, TagValues)>, RulesError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_reader(content.as_bytes());
+
+    let mut imported: Vec<(TagName, TagValues)> = Vec::new();
+
+    for (index, record) in reader.records().enumerate() {
+        let record = record
+            .map_err(|e| RulesError::TagParseError(format!("{}: invalid CSV: {}", path, e)))?;
+        let line = record
+            .position()
+            .map(|p| p.line())
+            .unwrap_or(index as u64 + 1);
+
+        if record.iter().all(str::is_empty) || (index == 0 && is_header(&record)) {
+            continue;
+        }
+
+        if record.len() < 2 || record.len() > 3 {
+            return Err(RulesError::TagParseError(format!(
+                "{}:{}: expected 2 or 3 columns (tag, value, description), found {}",
+                path,
+                line,
+                record.len()
+            )));
+        }
+
+        let tag_name = &record[0];
+        let value = &record[1];
+
+        if tag_name.is_empty() || value.is_empty() {
+            return Err(RulesError::TagParseError(format!(
+                "{}:{}: tag name and value cannot be empty",
+                path, line
+            )));
+        }
+
+        if value.contains(',') {
+            return Err(RulesError::TagParseError(format!(
+                "{}:{}: value '{}' cannot contain commas, use one row per value",
+                path, line, value
+            )));
+        }
+
+        // Validate through the tag line parser so imports follow the .tags syntax, quoting values
+        // with spaces or reserved characters as write_tag does -- e.g. "fish & chips"
+        tags::get_tag_from_line(&format!(
+            "- {}: {}",
+            tag_name,
+            string::quote_if_needed(value)
+        ))
+        .map_err(|e| RulesError::TagParseError(format!("{}:{}: {}", path, line, e)))?;
+
+        match imported
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case(tag_name))
+        {
+            Some((_, values)) => {
+                if !values.iter().any(|v| v.eq_ignore_ascii_case(value)) {
+                    values.push(value.to_string());
+                }
+            }
+            None => imported.push((tag_name.to_string(), vec![value.to_string()])),
+        }
+    }
+
+    Ok(imported)
+}
+
+pub fn read_tags_csv(path: &str) -> Result<Vec<(TagName, TagValues)>, RulesError> {
+    let content = fs::read_to_string(path)?;
+    parse_tags_csv(path, &content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tags_csv() {
+        let content = "Tag,Value,Description\ncolour,red,Bright red\nsize, small\n\n# Comment\ncolour,blue,\"Blue, dark\"\ncolour,Red\n";
+        let imported = parse_tags_csv("tags.csv", content).unwrap();
+
+        assert_eq!(
+            imported,
+            vec![
+                (
+                    "colour".to_string(),
+                    vec!["red".to_string(), "blue".to_string()]
+                ),
+                ("size".to_string(), vec!["small".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_tags_csv_invalid_rows() {
+        let result = parse_tags_csv("tags.csv", "colour,red\ncolour\n");
+        if let Err(RulesError::TagParseError(msg)) = result {
+            assert!(msg.contains("tags.csv:2"));
+            assert!(msg.contains("expected 2 or 3 columns"));
+        } else {
+            panic!("Expected TagParseError about column count");
+        }
+
+        // Values with spaces are quoted, like write_tag's
+        assert!(parse_tags_csv("tags.csv", "colour,dark red\n").is_ok());
+        assert!(parse_tags_csv("tags.csv", "dark colour,red\n").is_err());
+        assert!(parse_tags_csv("tags.csv", "colour,\n").is_err());
+        assert!(parse_tags_csv("tags.csv", "colour,\"red, blue\"\n").is_err());
+    }
+}
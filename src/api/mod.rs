@@ -1,5 +1,11 @@
 // Files
+pub mod audit;
+pub mod cache;
+pub mod changeset;
 pub mod entry;
+pub mod history;
+pub mod import;
+pub mod policy;
 
 // Directories
 pub mod write;
@@ -0,0 +1,40 @@
+// Hooks for restricting config writes -- e.g. only admins may change safety.rules
+use crate::err::RulesError;
+use crate::types::WriteRequest;
+
+use std::io;
+
+// Consulted before every write, so embedders can allow or deny it. Returning Err denies the
+// write with the given reason, and nothing is written.
+pub trait WritePolicy: Send + Sync {
+    fn check(&self, request: &WriteRequest) -> Result<(), String>;
+}
+
+// Closures work as policies -- e.g. |request: &WriteRequest| Ok(())
+impl<F> WritePolicy for F
+where
+    F: Fn(&WriteRequest) -> Result<(), String> + Send + Sync,
+{
+    fn check(&self, request: &WriteRequest) -> Result<(), String> {
+        self(request)
+    }
+}
+
+// Ask `policy`, if any, whether `request` may go ahead
+pub fn check(policy: Option<&dyn WritePolicy>, request: &WriteRequest) -> Result<(), RulesError> {
+    let Some(policy) = policy else {
+        return Ok(());
+    };
+
+    policy.check(request).map_err(|reason| {
+        RulesError::IoError(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} to '{}' denied: {}",
+                request.operation.as_str(),
+                request.file,
+                reason
+            ),
+        ))
+    })
+}
@@ -108,7 +108,7 @@ fn test_write_rule_appends_to_existing_file() {
 
     write_with_base_dir(file_name, "-size = large", tags.clone(), TEST_CONFIG_DIR).unwrap();
 
-    let content = fs::read_to_string(&format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
+    let content = fs::read_to_string(format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
     assert!(content.contains("-colour = red"));
     assert!(content.contains("-size = large"));
 
@@ -135,6 +135,33 @@ fn test_write_rule_prevents_duplicates() {
     cleanup_test_file(file_name);
 }
 
+#[test]
+fn test_write_rule_prevents_equivalent_duplicates() {
+    let file_name = "test_equivalent_duplicate.rules";
+    setup_and_cleanup_test_file(file_name);
+
+    let tags = create_test_tags();
+    let write = |rule: &str| write_with_base_dir(file_name, rule, tags.clone(), TEST_CONFIG_DIR);
+
+    write("- colour = red & (size = small | size = large)").unwrap();
+    for duplicate in [
+        "-colour=red&(size=small|size=large)",
+        "- (size = large | size = small) & colour = red",
+        "- (size = small, large) & COLOUR = Red",
+        "named: - colour = red & size = small | colour = red & size = large",
+    ] {
+        let error = write(duplicate).unwrap_err();
+        assert!(error.to_string().contains("at line 1"), "{}", error);
+        assert_eq!(error.code().as_str(), "R017");
+    }
+
+    // A different priority or outcome makes a different rule
+    write("-[5] colour = red & (size = small | size = large)").unwrap();
+    write("- colour = red & (size = small | size = large) => reject").unwrap();
+
+    cleanup_test_file(file_name);
+}
+
 #[test]
 fn test_write_rule_creates_config_dir() {
     let test_dir = "src/api/tests/test_config_creation";
@@ -166,7 +193,9 @@ fn test_write_rule_complex_rules() {
         "-(colour = red) & (size = large)",
     ];
 
+    // Some of the rules are the same rule written differently, so each is written on its own
     for rule in complex_rules {
+        setup_and_cleanup_test_file(file_name);
         let result = write_with_base_dir(file_name, rule, tags.clone(), TEST_CONFIG_DIR);
         assert!(result.is_ok(), "Failed to write rule: {}", rule);
     }
@@ -190,7 +219,9 @@ fn test_write_rule_comma_syntax() {
         "-size = small, medium, large",
     ];
 
+    // Some of the rules are the same rule written differently, so each is written on its own
     for rule in valid_comma_rules {
+        setup_and_cleanup_test_file(file_name);
         let result = write_with_base_dir(file_name, rule, tags.clone(), TEST_CONFIG_DIR);
         assert!(result.is_ok(), "Failed to write valid comma rule: {}", rule);
     }
@@ -1,6 +1,16 @@
-use crate::Rules;
+use crate::types::{
+    AuditOperation, EvalContext, EvalMode, LoadedRule, MatchedRule, MissingTagPolicy, ObjectId,
+    ObjectMatches, OperatorPrecedence, RequiredTagPolicy, RuleId, RuleMeta, Severity, Truth,
+    UnknownPolicy,
+};
+use crate::{
+    CacheOptions, CaseFolding, ChangeKind, DuplicateTagPolicy, GrammarVersion, RuleLimits,
+    RulePlugin, Rules, RulesError, TagLimits, WritePayload, WriteRequest, evaluate,
+};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 fn setup_test_env(test_name: &str) -> String {
     let test_dir = format!("src/api/tests/test_config/{}", test_name);
@@ -91,3 +101,2938 @@ fn test_rules_api_write_methods() {
 
     cleanup_test_env(&test_dir);
 }
+
+#[test]
+fn test_rules_api_pattern_tags() {
+    let test_dir = setup_test_env("test_pattern_tags");
+    fs::write(
+        format!("{}/pattern.tags", test_dir),
+        "- sku: /^[A-Z]{2}\\d{4}$/",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    assert!(rules.validate_rule("- sku = AB1234").is_ok());
+    assert!(rules.validate_rule("- sku = AB12").is_err());
+
+    rules.write_rule("api_test", "- sku = XY9876").unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("sku".to_string(), vec!["CD4321".to_string()]);
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    assert!(rules.validate_object(&obj).is_ok());
+
+    obj.insert("sku".to_string(), vec!["not-a-sku".to_string()]);
+    assert!(rules.validate_object(&obj).is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_open_tags() {
+    let test_dir = setup_test_env("test_open_tags");
+    fs::write(
+        format!("{}/open.tags", test_dir),
+        "- vendor (open): acme, globex",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    assert!(rules.validate_rule("- vendor = acme").is_ok());
+    assert!(rules.validate_rule("- vendor = initech").is_ok());
+    assert!(rules.validate_rule("- colour = purple").is_err());
+
+    let mut obj = HashMap::new();
+    obj.insert("vendor".to_string(), vec!["hooli".to_string()]);
+    assert!(rules.validate_object(&obj).is_ok());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_duplicate_tags_merge() {
+    let test_dir = setup_test_env("test_duplicate_merge");
+    fs::write(format!("{}/extra.tags", test_dir), "- colour: yellow, red").unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    assert!(rules.validate_rule("- colour = green").is_ok());
+    assert!(rules.validate_rule("- colour = yellow").is_ok());
+
+    let diagnostics = rules.tag_diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code.as_str(), "T004");
+    assert!(diagnostics[0].message.contains("'colour'"));
+    assert!(diagnostics[0].message.contains("merged"));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_duplicate_tags_error() {
+    let test_dir = setup_test_env("test_duplicate_error");
+    fs::write(format!("{}/extra.tags", test_dir), "- colour: yellow").unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.set_duplicate_tag_policy(DuplicateTagPolicy::Error);
+
+    let result = rules.load_tags();
+    if let Err(RulesError::TagParseError(msg)) = result {
+        assert!(msg.contains("defined more than once"));
+        assert!(msg.contains("extra.tags:1"));
+        assert!(msg.contains("test.tags:2"));
+    } else {
+        panic!("Expected TagParseError about duplicate tag");
+    }
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_duplicate_tags_last_wins() {
+    let test_dir = setup_test_env("test_duplicate_last_wins");
+    fs::write(format!("{}/zz.tags", test_dir), "- colour: yellow").unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.set_duplicate_tag_policy(DuplicateTagPolicy::LastWins);
+    rules.load_tags().unwrap();
+
+    assert!(rules.validate_rule("- colour = yellow").is_ok());
+    assert!(rules.validate_rule("- colour = red").is_err());
+    assert!(rules.tag_diagnostics()[0].message.contains("replaced"));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_tag_usages() {
+    let test_dir = setup_test_env("test_tag_usages");
+    fs::write(
+        format!("{}/usages.rules", test_dir),
+        "# Comment\n- colour = red, blue & size = large\n\n- shape ! circle | colour = red",
+    )
+    .unwrap();
+
+    let rules = Rules::new(&test_dir);
+
+    let usages = rules.tag_usages("colour", None).unwrap();
+    assert_eq!(usages.len(), 3);
+    assert!(usages.iter().all(|u| u.file.ends_with("usages.rules")));
+    assert_eq!(usages[0].line, 2);
+    assert_eq!(usages[0].clause.to_string(), "colour = red");
+    assert_eq!(usages[1].clause.to_string(), "colour = blue");
+    assert_eq!(usages[2].line, 4);
+    assert_eq!(usages[2].rule, "- shape ! circle | colour = red");
+
+    let usages = rules.tag_usages("Colour", Some("RED")).unwrap();
+    assert_eq!(usages.len(), 2);
+
+    let usages = rules.tag_usages("shape", Some("circle")).unwrap();
+    assert_eq!(usages.len(), 1);
+    assert_eq!(usages[0].clause.to_string(), "shape ! circle");
+
+    // Globs use each value they match
+    fs::write(format!("{}/globs.rules", test_dir), "- size = l*").unwrap();
+    let usages = rules.tag_usages("size", Some("large")).unwrap();
+    assert_eq!(usages.len(), 2);
+    assert!(usages.iter().any(|u| u.clause.to_string() == "size = l*"));
+    assert_eq!(rules.tag_usages("size", Some("small")).unwrap().len(), 0);
+
+    assert!(rules.tag_usages("material", None).unwrap().is_empty());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_can_remove_value() {
+    let test_dir = setup_test_env("test_can_remove_value");
+    fs::write(
+        format!("{}/impact.rules", test_dir),
+        "- colour = red & size = large\n- colour ! blue",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/impact.yaml", test_dir),
+        "objects:\n  shapes:\n    - colour: [red, green]\n      size: large\n    - colour: green\n",
+    )
+    .unwrap();
+    fs::write(format!("{}/open.tags", test_dir), "- vendor (open): acme").unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let impact = rules.can_remove_value("colour", "red").unwrap();
+    assert!(!impact.is_safe());
+    assert_eq!(impact.rules.len(), 1);
+    assert_eq!(impact.rules[0].line, 1);
+    assert_eq!(impact.objects.len(), 1);
+    assert_eq!(impact.objects[0].obj_type, "shapes");
+    assert_eq!(impact.objects[0].index, 0);
+
+    let impact = rules.can_remove_value("colour", "blue").unwrap();
+    assert_eq!(impact.rules.len(), 1);
+    assert!(impact.objects.is_empty());
+
+    assert!(rules.can_remove_value("shape", "square").unwrap().is_safe());
+    assert!(rules.can_remove_value("vendor", "acme").unwrap().is_safe());
+
+    // A glob is affected once the value is the only one it matches
+    fs::write(
+        format!("{}/globs.rules", test_dir),
+        "- shape = c*\n- shape = *r*",
+    )
+    .unwrap();
+    let impact = rules.can_remove_value("shape", "circle").unwrap();
+    assert_eq!(impact.rules.len(), 1);
+    assert_eq!(impact.rules[0].rule, "- shape = c*");
+    assert!(rules.can_remove_value("shape", "square").unwrap().is_safe());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_required_tags() {
+    let test_dir = setup_test_env("test_required_tags");
+    fs::write(
+        format!("{}/test.tags", test_dir),
+        "- colour (required): red, blue\n- size: small, large",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("size".to_string(), vec!["small".to_string()]);
+    assert!(rules.validate_object(&obj).is_err());
+
+    // Evaluated, it matches no rules by default, or is an error under RequiredTagPolicy::Error
+    rules.write_rule("test", "- size = small").unwrap();
+    let context = EvalContext::default();
+    assert!(rules.matching_rules(&obj).unwrap().is_empty());
+    assert!(rules.matching_rules_at(&obj, 0).unwrap().is_empty());
+    assert!(rules.evaluate_object(&obj).unwrap().is_empty());
+    assert!(
+        rules
+            .evaluate_with_context(&obj, &context)
+            .unwrap()
+            .is_empty()
+    );
+
+    rules.set_required_tag_policy(RequiredTagPolicy::Error);
+    let missing = "Object is missing required TagName: colour";
+    assert!(
+        rules
+            .matching_rules(&obj)
+            .unwrap_err()
+            .to_string()
+            .contains(missing)
+    );
+    assert!(rules.matching_rules_at(&obj, 0).is_err());
+    assert!(rules.evaluate_object(&obj).is_err());
+    assert!(rules.evaluate_with_context(&obj, &context).is_err());
+
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    assert!(rules.validate_object(&obj).is_ok());
+    assert_eq!(rules.evaluate_object(&obj).unwrap().len(), 1);
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_exclusive_groups() {
+    let test_dir = setup_test_env("test_exclusive_groups");
+    fs::write(
+        format!("{}/exclusive.tags", test_dir),
+        "@exclusive colour: red, green\n@exclusive shape, size",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert(
+        "colour".to_string(),
+        vec!["red".to_string(), "green".to_string()],
+    );
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+
+    assert_eq!(rules.check_exclusions(&obj).len(), 1);
+    assert!(rules.validate_object(&obj).is_err());
+
+    obj.insert("size".to_string(), vec!["small".to_string()]);
+    let conflicts = rules.check_exclusions(&obj);
+    assert_eq!(conflicts.len(), 2);
+    assert!(conflicts[1].to_string().contains("shape, size"));
+
+    obj.insert("colour".to_string(), vec!["blue".to_string()]);
+    obj.remove("size");
+    assert!(rules.check_exclusions(&obj).is_empty());
+    assert!(rules.validate_object(&obj).is_ok());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_exclusive_group_unknown_value() {
+    let test_dir = setup_test_env("test_exclusive_unknown");
+    fs::write(
+        format!("{}/exclusive.tags", test_dir),
+        "@exclusive colour: red, purple",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    assert!(rules.load_tags().is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_import_tags_csv() {
+    let test_dir = setup_test_env("test_import_tags_csv");
+
+    let csv_path = format!("{}/catalogue.csv", test_dir);
+    fs::write(
+        &csv_path,
+        "tag,value,description\ncolour,red,Already defined\ncolour,purple,\"Deep, rich\"\nmaterial,wood\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    rules.import_tags_csv(&csv_path, "test").unwrap();
+
+    let content = fs::read_to_string(format!("{}/test.tags", test_dir)).unwrap();
+    assert!(content.contains("- colour: red, blue, green, purple"));
+    assert!(content.contains("- material: wood"));
+    assert!(
+        rules
+            .validate_rule("- colour = purple & material = wood")
+            .is_ok()
+    );
+
+    // Invalid rows stop the import before anything is written
+    fs::write(&csv_path, "size,huge\nsize\n").unwrap();
+    assert!(rules.import_tags_csv(&csv_path, "test").is_err());
+    assert!(
+        !fs::read_to_string(format!("{}/test.tags", test_dir))
+            .unwrap()
+            .contains("huge")
+    );
+
+    // As do tags refused part way through, by the write policy or the limits once the tags before
+    // them are added
+    fs::write(&csv_path, "shape,hexagon\nsecret,hidden\n").unwrap();
+    rules.set_write_policy(|request: &WriteRequest| match request.payload {
+        WritePayload::Tag { name: "secret", .. } => Err("not imported".to_string()),
+        _ => Ok(()),
+    });
+    assert!(rules.import_tags_csv(&csv_path, "test").is_err());
+    rules.clear_write_policy();
+
+    fs::write(&csv_path, "pattern,striped\nfinish,matt\n").unwrap();
+    rules.set_tag_limits(TagLimits {
+        max_tags: Some(5),
+        max_values: None,
+    });
+    assert!(rules.import_tags_csv(&csv_path, "test").is_err());
+
+    let content = fs::read_to_string(format!("{}/test.tags", test_dir)).unwrap();
+    assert!(!content.contains("hexagon") && !content.contains("striped"));
+    assert!(!rules.tags().contains_key("pattern"));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_case_folding_round_trip() {
+    let test_dir = setup_test_env("test_case_folding_round_trip");
+
+    // Tags written through the API keep their case on disk
+    let mut rules = Rules::new(&test_dir);
+    rules
+        .write_tag("api_test", "Material", vec!["Wood"])
+        .unwrap();
+    rules.load_tags().unwrap();
+
+    assert!(rules.validate_rule("- material = wood").is_ok());
+    assert!(rules.validate_rule("- Material = Wood").is_ok());
+    rules.write_rule("api_test", "- material = wood").unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.set_case_folding(CaseFolding::Preserve);
+    rules.load_tags().unwrap();
+
+    assert!(rules.validate_rule("- Material = Wood").is_ok());
+    assert!(rules.validate_rule("- material = wood").is_err());
+
+    let mut obj = HashMap::new();
+    obj.insert("Material".to_string(), vec!["Wood".to_string()]);
+    assert!(rules.validate_object(&obj).is_ok());
+    obj.insert("Material".to_string(), vec!["wood".to_string()]);
+    assert!(rules.validate_object(&obj).is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_case_folding_objects_and_groups() {
+    let test_dir = setup_test_env("test_case_folding_objects_and_groups");
+    fs::write(
+        format!("{}/case.tags", test_dir),
+        "- Material (required): Wood, Oak\n@exclusive Material: Wood, Oak\n",
+    )
+    .unwrap();
+    fs::write(format!("{}/case.rules", test_dir), "- Material = Wood\n").unwrap();
+    fs::write(
+        format!("{}/case.yaml", test_dir),
+        "objects:\n  tables:\n    - Material: Wood\n    - Material: Oak\n",
+    )
+    .unwrap();
+
+    // Objects, groups and clauses keep their case, so they agree with the tags
+    let mut rules = Rules::new(&test_dir);
+    rules.set_case_folding(CaseFolding::Preserve);
+    rules.load_tags().unwrap();
+
+    let report = rules.evaluate().unwrap();
+    assert_eq!(report.results.len(), 2);
+    assert_eq!(report.results[0].matched.len(), 1);
+    assert!(report.results[1].matched.is_empty());
+
+    let mut obj = HashMap::new();
+    obj.insert(
+        "Material".to_string(),
+        vec!["Wood".to_string(), "Oak".to_string()],
+    );
+    let conflicts = rules.check_exclusions(&obj);
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(
+        conflicts[0].found,
+        vec!["Wood".to_string(), "Oak".to_string()]
+    );
+
+    assert_eq!(rules.tag_usages("Material", Some("Wood")).unwrap().len(), 1);
+    assert!(rules.tag_usages("material", None).unwrap().is_empty());
+
+    // Lowercasing folds all of them alike
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    assert_eq!(rules.evaluate().unwrap().results[0].matched.len(), 1);
+    assert_eq!(rules.check_exclusions(&obj).len(), 1);
+    assert_eq!(rules.tag_usages("MATERIAL", Some("wood")).unwrap().len(), 1);
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_tag_limits() {
+    let test_dir = setup_test_env("test_tag_limits");
+
+    // test.tags has 3 tags with 9 values
+    let mut rules = Rules::new(&test_dir);
+    rules.set_tag_limits(TagLimits {
+        max_tags: Some(2),
+        max_values: None,
+    });
+    let result = rules.load_tags();
+    if let Err(RulesError::TagParseError(msg)) = result {
+        assert!(msg.contains("test.tags:4"));
+        assert!(msg.contains("limit of 2 tags"));
+    } else {
+        panic!("Expected TagParseError about the tag limit");
+    }
+
+    rules.set_tag_limits(TagLimits {
+        max_tags: Some(4),
+        max_values: Some(11),
+    });
+    rules.load_tags().unwrap();
+
+    rules
+        .write_tag("test", "colour", vec!["red", "black"])
+        .unwrap();
+    assert!(
+        rules
+            .write_tag("extra", "material", vec!["wood", "metal"])
+            .is_err()
+    );
+    assert!(!Path::new(&format!("{}/extra.tags", test_dir)).exists());
+    rules.write_tag("extra", "material", vec!["wood"]).unwrap();
+    assert!(rules.write_tag("extra", "finish", vec!["matt"]).is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_matching_rules() {
+    let test_dir = setup_test_env("test_matching_rules");
+    fs::write(
+        format!("{}/match.rules", test_dir),
+        "# Comment\n- colour = red & size = large\n\n- shape ! circle\n- colour = blue",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let loaded = rules.rules().unwrap();
+    assert_eq!(loaded.len(), 3);
+    assert_eq!(loaded[1].line, 4);
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+
+    let matched = rules.matching_rules(&obj).unwrap();
+    let lines: Vec<usize> = matched.iter().map(|r| r.line).collect();
+    assert_eq!(lines, vec![2, 4]);
+
+    obj.insert("colour".to_string(), vec!["purple".to_string()]);
+    assert!(rules.matching_rules(&obj).is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_audit_log() {
+    let test_dir = setup_test_env("test_audit_log");
+    let _ = fs::remove_file(format!("{}/audit.log", test_dir));
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    assert!(rules.audit_log(None).unwrap().is_empty());
+
+    rules.write_tag("test", "material", vec!["wood"]).unwrap();
+    rules.set_audit_actor(Some("alice"));
+    rules.write_rule("audit", "- material = wood").unwrap();
+    rules.format_tags_file("test.tags").unwrap();
+    assert!(rules.write_rule("audit", "- colour = orange").is_err());
+
+    let entries = rules.audit_log(None).unwrap();
+    let operations: Vec<AuditOperation> = entries.iter().map(|e| e.operation).collect();
+    assert_eq!(
+        operations,
+        vec![
+            AuditOperation::WriteTag,
+            AuditOperation::WriteRule,
+            AuditOperation::FormatTags
+        ]
+    );
+    assert_eq!(entries[0].file, "test.tags");
+    assert_eq!(entries[0].actor, None);
+    assert_eq!(entries[1].file, "audit.rules");
+    assert_eq!(entries[1].actor.as_deref(), Some("alice"));
+    assert!(entries[0].timestamp > 0);
+
+    // Each entry hashes the file as that write left it, and formatting moved "material"
+    let tags_history = rules.audit_log(Some("test.tags")).unwrap();
+    assert_eq!(tags_history.len(), 2);
+    assert_ne!(tags_history[0].content_hash, tags_history[1].content_hash);
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_history_and_rollback() {
+    let test_dir = setup_test_env("test_history_rollback");
+    let _ = fs::remove_dir_all(format!("{}/.history", test_dir));
+    let _ = fs::remove_file(format!("{}/versioned.rules", test_dir));
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    assert!(rules.history("versioned.rules").unwrap().is_empty());
+
+    // The original tags are kept before the first write
+    rules.write_tag("test", "material", vec!["wood"]).unwrap();
+    assert_eq!(rules.history("test.tags").unwrap().len(), 2);
+
+    let rules_path = format!("{}/versioned.rules", test_dir);
+    rules.write_rule("versioned", "- material = wood").unwrap();
+    let first = fs::read_to_string(&rules_path).unwrap();
+    rules.write_rule("versioned", "- colour = red").unwrap();
+
+    let versions = rules.history("versioned.rules").unwrap();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0].version, 1);
+    assert_ne!(versions[0].content_hash, versions[1].content_hash);
+
+    rules.rollback("versioned.rules", 1).unwrap();
+    assert_eq!(fs::read_to_string(&rules_path).unwrap(), first);
+    let versions = rules.history("versioned.rules").unwrap();
+    assert_eq!(versions.len(), 3);
+    assert_eq!(versions[2].content_hash, versions[0].content_hash);
+
+    // Restoring the original tags would leave "- material = wood" invalid
+    let tags_before = fs::read_to_string(format!("{}/test.tags", test_dir)).unwrap();
+    let result = rules.rollback("test.tags", 1);
+    if let Err(RulesError::RuleParseError(msg)) = result {
+        assert!(msg.contains("versioned.rules:1"));
+        assert!(msg.contains("material"));
+    } else {
+        panic!("Expected RuleParseError for rule using a removed tag");
+    }
+    assert_eq!(
+        fs::read_to_string(format!("{}/test.tags", test_dir)).unwrap(),
+        tags_before
+    );
+
+    rules.write_tag("test", "finish", vec!["matt"]).unwrap();
+    rules.rollback("test.tags", 2).unwrap();
+    assert!(!rules.tags().contains_key("finish"));
+    assert!(rules.tags().contains_key("material"));
+
+    let result = rules.rollback("versioned.rules", 99);
+    if let Err(RulesError::IoError(e)) = result {
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    } else {
+        panic!("Expected IoError for unknown version");
+    }
+    assert!(rules.rollback("../test.tags", 1).is_err());
+
+    let last = rules.audit_log(None).unwrap().pop().unwrap();
+    assert_eq!(last.operation, AuditOperation::Rollback);
+    assert_eq!(last.file, "test.tags");
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_changeset() {
+    let old_dir = setup_test_env("test_changeset_old");
+    let new_dir = setup_test_env("test_changeset_new");
+
+    fs::write(
+        format!("{}/test.rules", old_dir),
+        "- colour = red & size = small\n- shape = circle\n",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/test.tags", new_dir),
+        "# Reordered\n- shape: square, circle, rectangle\n- colour: red, blue, green, yellow\n- size: small, medium, large",
+    )
+    .unwrap();
+    // Reformatted and reordered, but the same rule
+    fs::write(
+        format!("{}/test.rules", new_dir),
+        "- (Size=small)&colour=red\n- shape = square\n",
+    )
+    .unwrap();
+
+    let mut old = Rules::new(&old_dir);
+    old.load_tags().unwrap();
+    let mut new = Rules::new(&new_dir);
+    new.load_tags().unwrap();
+
+    assert!(old.changeset(&old).unwrap().is_empty());
+
+    let changes = old.changeset(&new).unwrap();
+    assert_eq!(changes.tags.len(), 1);
+    assert_eq!(changes.tags[0].name, "colour");
+    assert_eq!(changes.tags[0].kind, ChangeKind::Modified);
+    assert_eq!(changes.rules.len(), 1);
+    assert_eq!(changes.rules[0].kind, ChangeKind::Modified);
+    assert_eq!(changes.rules[0].file, "test.rules");
+    assert_eq!(changes.rules[0].old_rule.as_deref(), Some("shape = circle"));
+    assert_eq!(changes.rules[0].new_rule.as_deref(), Some("shape = square"));
+    assert!(changes.objects.is_empty());
+
+    let reverse = new.changeset(&old).unwrap();
+    assert_eq!(reverse.rules[0].old_rule.as_deref(), Some("shape = square"));
+    assert!(
+        changes
+            .to_string()
+            .contains("~ rule test.rules: shape = circle -> shape = square")
+    );
+
+    cleanup_test_env(&old_dir);
+    cleanup_test_env(&new_dir);
+}
+
+#[test]
+fn test_rules_api_write_policy() {
+    let test_dir = setup_test_env("test_write_policy");
+    let _ = fs::remove_file(format!("{}/safety.rules", test_dir));
+    let _ = fs::remove_file(format!("{}/general.rules", test_dir));
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let seen: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&seen);
+    rules.set_write_policy(move |request: &WriteRequest| {
+        if let WritePayload::Rule(rule) = request.payload {
+            log.lock().unwrap().push(rule.to_string());
+        }
+        if request.file == "safety.rules" && request.actor != Some("admin") {
+            return Err("only admin may change safety rules".to_string());
+        }
+        Ok(())
+    });
+
+    rules.write_rule("general", "- colour = red").unwrap();
+
+    let result = rules.write_rule("safety", "- colour = blue");
+    if let Err(RulesError::IoError(e)) = result {
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(e.to_string().contains("safety.rules"));
+        assert!(e.to_string().contains("only admin"));
+    } else {
+        panic!("Expected IoError for denied write");
+    }
+    assert!(!Path::new(&format!("{}/safety.rules", test_dir)).exists());
+    assert!(rules.audit_log(Some("safety.rules")).unwrap().is_empty());
+
+    rules.set_audit_actor(Some("admin"));
+    rules.write_rule("safety", "- colour = blue").unwrap();
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec!["- colour = red", "- colour = blue", "- colour = blue"]
+    );
+
+    rules.set_audit_actor(None);
+    rules.set_write_policy(|request: &WriteRequest| match request.payload {
+        WritePayload::Tag { values, .. } if values.len() > 1 => {
+            Err("one value at a time".to_string())
+        }
+        _ => Ok(()),
+    });
+    assert!(
+        rules
+            .write_tag("test", "material", vec!["wood", "metal"])
+            .is_err()
+    );
+    assert!(!rules.tags().contains_key("material"));
+    rules.write_tag("test", "material", vec!["wood"]).unwrap();
+
+    rules.clear_write_policy();
+    rules.write_rule("safety", "- material = wood").unwrap();
+
+    cleanup_test_env(&test_dir);
+}
+
+struct Before;
+
+impl RulePlugin for Before {
+    fn name(&self) -> &str {
+        "before"
+    }
+
+    fn call(&self, values: &[String], args: &[String]) -> bool {
+        args.first()
+            .is_some_and(|date| values.iter().any(|v| v < date))
+    }
+}
+
+#[test]
+fn test_rules_api_plugins() {
+    let test_dir = setup_test_env("test_plugins");
+    let _ = fs::remove_file(format!("{}/dated.rules", test_dir));
+    fs::write(
+        format!("{}/dates.tags", test_dir),
+        "- created_at (open): 2024-01-01",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let rule = "- created_at before(2024-01-01) & colour = red";
+    assert!(rules.write_rule("dated", rule).is_err());
+
+    rules.register_plugin(Before);
+    rules.write_rule("dated", rule).unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("created_at".to_string(), vec!["2023-06-01".to_string()]);
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 1);
+
+    obj.insert("created_at".to_string(), vec!["2024-06-01".to_string()]);
+    assert!(rules.matching_rules(&obj).unwrap().is_empty());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_evaluation_cache() {
+    let test_dir = setup_test_env("test_evaluation_cache");
+    let _ = fs::remove_file(format!("{}/cached.rules", test_dir));
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    assert!(rules.cache_stats().is_none());
+
+    rules.enable_cache(CacheOptions::default());
+    rules.write_rule("cached", "- colour = red").unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 1);
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 1);
+
+    let stats = rules.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+    // Writes empty the cache, so the new rule is seen
+    rules
+        .write_rule("cached", "- colour = red & shape = circle")
+        .unwrap();
+    assert_eq!(rules.cache_stats().unwrap().entries, 0);
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 2);
+
+    // Hand edits are seen after a reload
+    fs::write(format!("{}/cached.rules", test_dir), "- colour = blue").unwrap();
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 2);
+    rules.load_tags().unwrap();
+    assert!(rules.matching_rules(&obj).unwrap().is_empty());
+
+    rules.disable_cache();
+    assert!(rules.cache_stats().is_none());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_time_scoped_rules() {
+    let test_dir = setup_test_env("test_time_scoped_rules");
+    fs::write(
+        format!("{}/scoped.rules", test_dir),
+        "@expires 2025-01-01\n- colour = red\n\n@effective_from 2025-01-01\n- colour = red & shape = circle\n\n- colour = blue",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    rules.enable_cache(CacheOptions::default());
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+
+    let before = rules.matching_rules_at(&obj, 1_700_000_000).unwrap();
+    assert_eq!(before.len(), 1);
+    assert_eq!(before[0].line, 2);
+
+    // Cached results are reused within the window but don't leak across a rule's start or end
+    let after = rules.matching_rules_at(&obj, 1_800_000_000).unwrap();
+    assert_eq!(after.len(), 1);
+    assert_eq!(after[0].line, 5);
+    assert_eq!(rules.matching_rules_at(&obj, 1_750_000_000).unwrap(), after);
+    assert_eq!(rules.cache_stats().unwrap().hits, 1);
+    assert_eq!(
+        rules.matching_rules_at(&obj, 1_700_000_000).unwrap(),
+        before
+    );
+    assert_eq!(rules.cache_stats().unwrap().hits, 1);
+
+    assert!(rules.lint(1_700_000_000).unwrap().is_empty());
+    let warnings = rules.lint(1_800_000_000).unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].line, 2);
+    assert!(warnings[0].message.contains("2025-01-01T00:00:00Z"));
+
+    fs::write(
+        format!("{}/scoped.rules", test_dir),
+        "@expires 2025-01-01\n- colour = purple",
+    )
+    .unwrap();
+    let result = rules.lint(1_800_000_000);
+    if let Err(RulesError::RuleParseError(msg)) = result {
+        assert!(msg.contains("scoped.rules:2"));
+    } else {
+        panic!("Expected RuleParseError for invalid rule");
+    }
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_shadow_compare() {
+    let active_dir = setup_test_env("test_shadow_compare_active");
+    let candidate_dir = setup_test_env("test_shadow_compare_candidate");
+    fs::write(
+        format!("{}/shadow.rules", active_dir),
+        "- colour = red\n- shape = circle & colour = blue\n- size = small",
+    )
+    .unwrap();
+    // Reordered and reformatted, with the size rule changed
+    fs::write(
+        format!("{}/shadow.rules", candidate_dir),
+        "- colour = blue & shape = circle\n-colour=red\n- size = large",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/test.tags", candidate_dir),
+        "- colour: red, blue\n- shape: circle, square, rectangle\n- size: small, medium, large",
+    )
+    .unwrap();
+
+    let mut active = Rules::new(&active_dir);
+    active.load_tags().unwrap();
+    let mut candidate = Rules::new(&candidate_dir);
+    candidate.load_tags().unwrap();
+
+    let object = |pairs: &[(&str, &str)]| {
+        pairs
+            .iter()
+            .map(|(tag, value)| (tag.to_string(), vec![value.to_string()]))
+            .collect::<HashMap<String, Vec<String>>>()
+    };
+    let objects = vec![
+        object(&[("colour", "red"), ("shape", "square")]),
+        object(&[("colour", "blue"), ("shape", "circle"), ("size", "small")]),
+        object(&[("colour", "green")]),
+    ];
+
+    let report = active.shadow_compare(&candidate, &objects).unwrap();
+    assert_eq!(report.evaluated, 3);
+    assert_eq!(report.divergences.len(), 2);
+
+    let changed = &report.divergences[0];
+    assert_eq!(changed.index, 1);
+    assert_eq!(changed.removed.len(), 1);
+    assert_eq!(changed.removed[0].rule, "- size = small");
+    assert!(changed.added.is_empty());
+
+    // Green was removed from the candidate's tags
+    let failed = &report.divergences[1];
+    assert_eq!(failed.index, 2);
+    assert!(failed.candidate_error.is_some());
+
+    assert!(report.to_string().starts_with("2 of 3 objects diverge"));
+    assert!(active.shadow_compare(&active, &objects).unwrap().is_empty());
+
+    cleanup_test_env(&active_dir);
+    cleanup_test_env(&candidate_dir);
+}
+
+// Serve one canned response per connection, returning the request bodies -- e.g. a config
+// file with an ETag, or a webhook accepting a batch
+#[cfg(any(feature = "remote", feature = "webhooks"))]
+fn serve_files(responses: Vec<String>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = std::thread::spawn(move || {
+        let mut bodies = Vec::new();
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            let mut length = 0;
+            while reader.read_line(&mut line).unwrap() > 0 && !line.trim().is_empty() {
+                if let Some((name, value)) = line.split_once(':')
+                    && name.eq_ignore_ascii_case("content-length")
+                {
+                    length = value.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            bodies.push(String::from_utf8(body).unwrap());
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+        bodies
+    });
+
+    (format!("http://{}", addr), handle)
+}
+
+#[cfg(feature = "remote")]
+fn file_response(etag: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nETag: \"{}\"\r\nContent-Length: {}\r\n\r\n{}",
+        etag,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(feature = "remote")]
+#[test]
+fn test_rules_api_pull() {
+    let test_dir = setup_test_env("test_pull");
+    let not_modified = "HTTP/1.1 304 Not Modified\r\n\r\n".to_string();
+
+    let (base, server) = serve_files(vec![
+        file_response("t1", "- colour: red, blue, purple"),
+        file_response("r1", "- colour = purple"),
+        not_modified.clone(),
+        not_modified,
+        file_response("r2", "- colour = orange"),
+    ]);
+    let urls = [
+        format!("{}/test.tags", base),
+        format!("{}/base.rules", base),
+    ];
+    let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let changed = rules.pull(&urls).unwrap();
+    assert_eq!(changed, vec!["test.tags", "base.rules"]);
+    assert!(rules.validate_rule("- colour = purple").is_ok());
+    let last = rules.audit_log(Some("base.rules")).unwrap().pop().unwrap();
+    assert_eq!(last.operation, AuditOperation::Pull);
+
+    // Unchanged files aren't downloaded or written again
+    assert!(rules.pull(&urls).unwrap().is_empty());
+    assert_eq!(rules.audit_log(Some("base.rules")).unwrap().len(), 1);
+
+    // An invalid file leaves the config untouched
+    let result = rules.pull(&urls[1..]);
+    if let Err(RulesError::RuleParseError(msg)) = result {
+        assert!(msg.contains("base.rules:1"));
+    } else {
+        panic!("Expected RuleParseError for a rule using an unknown value");
+    }
+    assert_eq!(
+        fs::read_to_string(format!("{}/base.rules", test_dir)).unwrap(),
+        "- colour = purple"
+    );
+
+    server.join().unwrap();
+    cleanup_test_env(&test_dir);
+}
+
+#[cfg(feature = "webhooks")]
+#[test]
+fn test_rules_api_webhooks() {
+    use crate::webhooks::{Notifier, NotifierStats, WebhookOptions};
+
+    let test_dir = setup_test_env("test_webhooks");
+    fs::write(
+        format!("{}/notify.rules", test_dir),
+        "@notify pager\n- colour = red\n- shape = circle",
+    )
+    .unwrap();
+
+    let (base, server) = serve_files(vec![
+        "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string(),
+    ]);
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    rules.set_notifier(Notifier::new(
+        HashMap::from([("pager".to_string(), format!("{}/hook", base))]),
+        WebhookOptions::default(),
+    ));
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 2);
+
+    // Only the annotated rule is sent
+    rules.notifier().unwrap().flush();
+    assert_eq!(
+        rules.notifier().unwrap().stats(),
+        NotifierStats {
+            delivered: 1,
+            dropped: 0,
+            retries: 0,
+        }
+    );
+    let body = server.join().unwrap().remove(0);
+    assert!(body.contains("\"rule\":\"- colour = red\""));
+    assert!(body.contains("\"webhook\":\"pager\""));
+
+    rules.clear_notifier();
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_draft_rules() {
+    let test_dir = setup_test_env("test_draft_rules");
+    fs::write(
+        format!("{}/drafts.rules", test_dir),
+        "- colour = red\n\n@draft red_large\n# Trial\n- colour = red & size = large\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+
+    // Drafts are listed and shadow evaluated, but not matched
+    assert_eq!(rules.rules().unwrap().len(), 2);
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 1);
+    let report = rules
+        .shadow_compare(&rules, std::slice::from_ref(&obj))
+        .unwrap();
+    assert_eq!(report.divergences[0].added.len(), 1);
+
+    let promoted = rules.promote_rule("red_large").unwrap();
+    assert_eq!(promoted.line, 4);
+    assert!(!promoted.is_draft());
+    assert_eq!(
+        fs::read_to_string(format!("{}/drafts.rules", test_dir)).unwrap(),
+        "- colour = red\n\n# Trial\n- colour = red & size = large\n"
+    );
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 2);
+    let last = rules
+        .audit_log(Some("drafts.rules"))
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(last.operation, AuditOperation::PromoteRule);
+
+    let result = rules.promote_rule("red_large");
+    if let Err(RulesError::IoError(e)) = result {
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    } else {
+        panic!("Expected NotFound for a promoted draft");
+    }
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_named_rules() {
+    let test_dir = setup_test_env("test_named_rules");
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    rules.write_rule("named", "- colour = blue").unwrap();
+    rules
+        .write_rule("named", "promo_1: - colour = red & size = large")
+        .unwrap();
+    let result = rules.write_rule("other", "promo_1: - shape = circle");
+    if let Err(RulesError::RuleParseError(msg)) = result {
+        assert!(msg.contains("Rule name 'promo_1' is already used at"));
+    } else {
+        panic!("Expected RuleParseError about a duplicate rule name");
+    }
+
+    let found = rules.find_rule_by_name("promo_1").unwrap().unwrap();
+    assert_eq!(found.line, 2);
+    assert!(rules.find_rule_by_name("promo_2").unwrap().is_none());
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+    let matched = rules.evaluate_object(&obj).unwrap();
+    assert_eq!(matched.len(), 1);
+    assert_eq!(matched[0].name.as_deref(), Some("promo_1"));
+
+    let deleted = rules.delete_rule("promo_1").unwrap();
+    assert_eq!(deleted.id(), found.id());
+    assert_eq!(
+        fs::read_to_string(format!("{}/named.rules", test_dir)).unwrap(),
+        "- colour = blue"
+    );
+    let last = rules.audit_log(Some("named.rules")).unwrap().pop().unwrap();
+    assert_eq!(last.operation, AuditOperation::DeleteRule);
+
+    let result = rules.delete_rule("promo_1");
+    if let Err(RulesError::IoError(e)) = result {
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    } else {
+        panic!("Expected NotFound for a deleted rule");
+    }
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_rule_meta() {
+    let test_dir = setup_test_env("test_rule_meta");
+    fs::write(
+        format!("{}/meta.rules", test_dir),
+        "@description(\"Red items\") @severity(critical)\n@owner(alice)\n- colour = red\n- shape = circle\n",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/meta.yaml", test_dir),
+        "objects:\n  shapes:\n    - colour: red\n      shape: circle\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let listed = rules.rules().unwrap();
+    assert_eq!(listed[0].meta().severity, Some(Severity::Critical));
+    assert!(listed[1].meta().is_empty());
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    let matched = rules.evaluate_object(&obj).unwrap();
+    assert_eq!(
+        matched[0].meta,
+        RuleMeta {
+            description: Some("Red items".to_string()),
+            severity: Some(Severity::Critical),
+            owner: Some("alice".to_string()),
+        }
+    );
+
+    let report = rules.evaluate().unwrap().to_string();
+    assert!(report.contains(
+        "meta.rules:3 - colour = red @description(\"Red items\") @severity(critical) @owner(alice)\n"
+    ));
+    assert!(report.contains("meta.rules:4 - shape = circle\n"));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_includes() {
+    let test_dir = setup_test_env("test_includes");
+    fs::create_dir_all(format!("{}/shared", test_dir)).unwrap();
+    fs::write(
+        format!("{}/shared/common.rules", test_dir),
+        "- colour = red & size = large",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/main.rules", test_dir),
+        "#include shared/common.rules\n- shape = circle",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/other.rules", test_dir),
+        "#include shared/common.rules",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let files: Vec<String> = rules.rules().unwrap().into_iter().map(|r| r.file).collect();
+    assert_eq!(
+        files,
+        vec![
+            format!("{}/main.rules", test_dir),
+            format!("{}/shared/common.rules", test_dir),
+        ]
+    );
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+    let matched = rules.evaluate_object(&obj).unwrap();
+    assert_eq!(matched.len(), 1);
+
+    fs::write(
+        format!("{}/shared/common.rules", test_dir),
+        "@draft\n- colour = red",
+    )
+    .unwrap();
+    let result = rules.rules();
+    if let Err(RulesError::RuleParseError(msg)) = result {
+        assert!(msg.ends_with(&format!("(included from {}/main.rules:1)", test_dir)));
+    } else {
+        panic!("Expected RuleParseError from the included file");
+    }
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_macros() {
+    let test_dir = setup_test_env("test_macros");
+    fs::write(
+        format!("{}/macros.rules", test_dir),
+        "$warm = colour = red | colour = green\n- $warm & size = large\n- !$warm",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["green".to_string()]);
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+    let matched = rules.evaluate_object(&obj).unwrap();
+    assert_eq!(matched.len(), 1);
+    assert_eq!(matched[0].line, 2);
+
+    obj.insert("colour".to_string(), vec!["blue".to_string()]);
+    let matched = rules.evaluate_object(&obj).unwrap();
+    assert_eq!(matched.len(), 1);
+    assert_eq!(matched[0].line, 3);
+
+    fs::write(
+        format!("{}/macros.rules", test_dir),
+        "$warm = $hot | colour = green\n$hot = $warm\n- $warm",
+    )
+    .unwrap();
+    // Edits made by hand are seen after a reload
+    rules.load_tags().unwrap();
+    let result = rules.evaluate_object(&obj);
+    if let Err(RulesError::RuleParseError(msg)) = result {
+        assert!(msg.ends_with("macros.rules:1: Macro cycle: $warm -> $hot -> $warm"));
+    } else {
+        panic!("Expected RuleParseError about a macro cycle");
+    }
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_tag_references() {
+    let test_dir = setup_test_env("test_tag_references");
+    fs::write(
+        format!("{}/trim.tags", test_dir),
+        "- trim: red, blue, green",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    rules.write_rule("trim", "- trim = @colour").unwrap();
+    assert!(rules.write_rule("trim", "- trim = @finish").is_err());
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert(
+        "trim".to_string(),
+        vec!["red".to_string(), "blue".to_string()],
+    );
+    assert_eq!(rules.evaluate_object(&obj).unwrap().len(), 1);
+
+    obj.insert("colour".to_string(), vec!["green".to_string()]);
+    assert!(rules.evaluate_object(&obj).unwrap().is_empty());
+
+    assert_eq!(rules.tag_usages("colour", None).unwrap().len(), 1);
+    assert!(rules.tag_usages("colour", Some("red")).unwrap().is_empty());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_operator_precedence() {
+    let test_dir = setup_test_env("test_operator_precedence");
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    rules
+        .write_rule("order", "- colour = red & size = large | shape = circle")
+        .unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["blue".to_string()]);
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+    assert_eq!(rules.evaluate_object(&obj).unwrap().len(), 1);
+
+    rules
+        .set_operator_precedence(OperatorPrecedence::default().with("|", 2))
+        .unwrap();
+    assert_eq!(rules.operator_precedence().level("|"), Some(2));
+    assert!(rules.evaluate_object(&obj).unwrap().is_empty());
+
+    assert!(
+        rules
+            .set_operator_precedence(OperatorPrecedence::default().with("^", 3))
+            .is_err()
+    );
+    assert_eq!(rules.operator_precedence().level("|"), Some(2));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_error_location() {
+    let test_dir = setup_test_env("test_error_location");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "# Rules\n- colour = red\n  - shape = circle | (size = large",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let error = rules.compile().err().unwrap();
+    let location = error.location().unwrap();
+    assert!(location.file.ends_with("test.rules"));
+    assert_eq!(location.line, 3);
+    assert_eq!(location.column, Some(20));
+    assert_eq!(error.column(), Some(20));
+    assert!(error.to_string().contains("test.rules:3:20: "));
+    assert!(error.to_string().ends_with("Unmatched opening parenthesis"));
+
+    let error = rules.rule_conflicts().unwrap_err();
+    assert_eq!(error.location(), Some(location));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_rule_limits() {
+    let test_dir = setup_test_env("test_rule_limits");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red\n- ((colour = red | size = small) & shape = circle)\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    rules.validate_rules().unwrap();
+
+    rules.set_rule_limits(RuleLimits {
+        max_depth: 1,
+        ..Default::default()
+    });
+    let Err(RulesError::ValidationError(report)) = rules.validate_rules() else {
+        panic!("Expected a ValidationError");
+    };
+    assert_eq!(report.failures.len(), 1);
+    let failure = &report.failures[0];
+    assert!(matches!(failure.error, RulesError::RuleLimitError(_)));
+    assert_eq!(
+        (failure.location.line, failure.location.column),
+        (2, Some(4))
+    );
+    assert_eq!(failure.error.code().as_str(), "R014");
+    assert!(rules.engine().is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_long_rule() {
+    let test_dir = setup_test_env("test_long_rule");
+    let long = format!(
+        "- {}",
+        vec!["colour = red & shape = circle"; 15000].join(" & ")
+    );
+    fs::write(format!("{}/test.rules", test_dir), format!("{}\n", long)).unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.set_rule_limits(RuleLimits {
+        max_length: usize::MAX,
+        ..Default::default()
+    });
+    rules.load_tags().unwrap();
+
+    // Rules far over the default limit are checked and evaluated without exhausting the stack
+    assert!(rules.validate_rule(&long).is_ok());
+    assert_eq!(
+        rules.parse_rule(&long).unwrap().root_node.chain().len(),
+        30000
+    );
+    assert!(rules.is_satisfiable(&long).unwrap());
+    assert_eq!(
+        rules.canonicalize(&long).unwrap(),
+        "- colour = red & shape = circle"
+    );
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+    assert_eq!(rules.rule_truth(&long, &obj).unwrap(), Truth::True);
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 1);
+
+    cleanup_test_env(&test_dir);
+}
+
+#[cfg(feature = "json_logic")]
+#[test]
+fn test_rules_api_rule_to_json_logic() {
+    let test_dir = setup_test_env("test_rule_to_json_logic");
+    fs::write(
+        format!("{}/test.tags", test_dir),
+        "- colour: red, blue\n- size: small < medium < large\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // Rank comparisons become the values on their side, in order
+    let logic = rules
+        .rule_to_json_logic("- colour = red & size > small")
+        .unwrap();
+    assert_eq!(
+        logic,
+        serde_json::json!({"and": [
+            {"in": ["red", {"var": "colour"}]},
+            {"some": [{"var": "size"}, {"in": [{"var": ""}, ["medium", "large"]]}]},
+        ]})
+    );
+    let logic = rules.rule_to_json_logic("- size < small").unwrap();
+    assert_eq!(logic["some"][1]["in"][1], serde_json::json!([]));
+
+    assert!(rules.rule_to_json_logic("- colour = purple").is_err());
+    let error = rules
+        .rule_to_json_logic("- colour = $ctx.colour")
+        .unwrap_err();
+    assert!(error.to_string().contains("variables"));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_ruleset_to_dot() {
+    let test_dir = setup_test_env("test_ruleset_to_dot");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "promo: -[10] colour = red & size = large => reject\n\n@draft blue\n- colour = blue\n",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/other.rules", test_dir),
+        "- shape = \"circle\"\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let dot = rules.ruleset_to_dot().unwrap();
+    assert!(dot.starts_with("digraph rules {\n"));
+    assert!(dot.ends_with("    }\n}\n"));
+    assert_eq!(dot.matches("subgraph cluster_").count(), 2);
+    assert!(dot.contains(&format!("label=\"{}/other.rules\";", test_dir)));
+
+    // Each rule's note points to the root of its tree
+    assert!(dot.contains("r0 [label=\"line 1\", shape=note];"));
+    assert!(dot.contains("r0n0 [label=\"shape = circle\", shape=box];"));
+    assert!(dot.contains("r1 [label=\"line 1\\npromo\\n[10]\\n=> reject\", shape=note];"));
+    assert!(dot.contains("r1 -> r1n0;\n"));
+    assert!(dot.contains("r1n0 -> r1n2;\n"));
+    assert!(dot.contains("r2 [label=\"line 4\", shape=note, style=dashed];"));
+
+    fs::write(format!("{}/other.rules", test_dir), "- colour = purple\n").unwrap();
+    let error = rules.ruleset_to_dot().unwrap_err();
+    assert!(error.to_string().contains("other.rules:1"));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_validate_rules() {
+    let test_dir = setup_test_env("test_validate_rules");
+    let rules_file = format!("{}/test.rules", test_dir);
+    fs::write(
+        &rules_file,
+        "- colour = red\n- colour = purple\n- shape = circle\n- size = (large",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let Err(RulesError::ValidationError(report)) = rules.validate_rules() else {
+        panic!("Expected a ValidationError");
+    };
+    let lines: Vec<usize> = report
+        .failures
+        .iter()
+        .map(|failure| failure.location.line)
+        .collect();
+    assert_eq!(lines, vec![2, 4]);
+    assert_eq!(report.failures[1].location.column, Some(10));
+    assert!(report.failures[0].error.to_string().contains("purple"));
+    let codes: Vec<&str> = report
+        .diagnostics()
+        .iter()
+        .map(|diagnostic| diagnostic.code.as_str())
+        .collect();
+    assert_eq!(codes, vec!["R005", "R002"]);
+
+    fs::write(&rules_file, "- colour = red\n- size = large").unwrap();
+    assert!(rules.validate_rules().unwrap().warnings.is_empty());
+
+    // Rules which can never or always match are valid, with warnings
+    fs::write(
+        &rules_file,
+        "- size = small & size ! small & colour = red\n- colour = red\n\
+         - shape = circle | colour = blue | shape ! circle",
+    )
+    .unwrap();
+    let report = rules.validate_rules().unwrap();
+    let warnings: Vec<String> = report
+        .warnings
+        .iter()
+        .map(|warning| format!("{}:{}", warning.code, warning.span.line.unwrap()))
+        .collect();
+    assert_eq!(warnings, vec!["R016:1", "R018:3"]);
+    assert!(
+        report.warnings[0]
+            .message
+            .ends_with("as size = small and size ! small can't all hold")
+    );
+    assert!(
+        report.warnings[1]
+            .message
+            .ends_with("as shape = circle or shape ! circle holds for every object")
+    );
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_format_file() {
+    let test_dir = setup_test_env("test_format_file");
+    let messy = "# Shapes\n@draft red_large\n-(size=small|size=large)&colour=red\n\
+                 #test: match { colour: red, size: small }\n\n  p:-[5]shape=circle=>reject\n\
+                 $warm = colour = red\n- $warm & size=large\n- colour = blue\n";
+    fs::write(format!("{}/test.rules", test_dir), messy).unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // Comments, annotations, tests, indentation and rules using macros stay as they were
+    assert_eq!(rules.format_file("test").unwrap(), 2);
+    let formatted = "# Shapes\n@draft red_large\n- (size = small | size = large) & colour = red\n\
+                     #test: match { colour: red, size: small }\n\n  p: -[5] shape = circle => reject\n\
+                     $warm = colour = red\n- $warm & size=large\n- colour = blue\n";
+    assert_eq!(
+        fs::read_to_string(format!("{}/test.rules", test_dir)).unwrap(),
+        formatted
+    );
+    let last = rules.audit_log(Some("test.rules")).unwrap().pop().unwrap();
+    assert_eq!(last.operation, AuditOperation::FormatRules);
+    assert!(rules.run_inline_tests().unwrap()[0].passed());
+
+    // Formatting again changes nothing and isn't recorded
+    let writes = rules.audit_log(Some("test.rules")).unwrap().len();
+    assert_eq!(rules.format_file("test.rules").unwrap(), 0);
+    assert_eq!(rules.audit_log(Some("test.rules")).unwrap().len(), writes);
+
+    // Invalid rules stop the formatting before anything is written
+    fs::write(
+        format!("{}/bad.rules", test_dir),
+        "-colour=red\n- colour = purple\n",
+    )
+    .unwrap();
+    let error = rules.format_file("bad").unwrap_err();
+    assert_eq!(error.location().unwrap().line, 2);
+    assert_eq!(
+        fs::read_to_string(format!("{}/bad.rules", test_dir)).unwrap(),
+        "-colour=red\n- colour = purple\n"
+    );
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_dedup_rules() {
+    let test_dir = setup_test_env("test_dedup_rules");
+    let content = "- colour = red\n@weight 2\n- size = large\n# Duplicates\n-colour=red\n\
+                   #test: match { colour: red }\n@weight 2\n- size=large\n- size = large\n\
+                   -[5] colour = red\n";
+    fs::write(format!("{}/test.rules", test_dir), content).unwrap();
+    fs::write(format!("{}/other.rules", test_dir), "- colour = red\n").unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // Only rules with the same annotations, priority and outcome in the same file are duplicates
+    let duplicates = rules.duplicate_rules().unwrap();
+    let lines: Vec<(usize, usize)> = duplicates
+        .iter()
+        .map(|d| (d.rule.line, d.duplicate_of.line))
+        .collect();
+    assert_eq!(lines, vec![(5, 1), (8, 3)]);
+    assert!(
+        duplicates[0]
+            .to_string()
+            .ends_with("test.rules:5 duplicates line 1: -colour=red")
+    );
+
+    assert_eq!(rules.dedup_rules().unwrap(), duplicates);
+    assert_eq!(
+        fs::read_to_string(format!("{}/test.rules", test_dir)).unwrap(),
+        "- colour = red\n@weight 2\n- size = large\n# Duplicates\n- size = large\n\
+         -[5] colour = red\n"
+    );
+    let last = rules.audit_log(Some("test.rules")).unwrap().pop().unwrap();
+    assert_eq!(last.operation, AuditOperation::DedupRules);
+    assert!(rules.duplicate_rules().unwrap().is_empty());
+    assert!(rules.dedup_rules().unwrap().is_empty());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_rules_equivalent() {
+    let test_dir = setup_test_env("test_rules_equivalent");
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let ugly = "- !(colour ! red | size = small) | colour = red & size = small";
+    assert!(rules.rules_equivalent(ugly, "- colour = red").unwrap());
+    assert!(!rules.is_equivalent(ugly, "- colour = red").unwrap());
+    assert!(
+        !rules
+            .rules_equivalent(ugly, "- colour = red & size ! small")
+            .unwrap()
+    );
+    assert!(rules.rules_equivalent(ugly, "- colour = orange").is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_unsatisfiable_rules() {
+    let test_dir = setup_test_env("test_unsatisfiable_rules");
+    fs::write(
+        format!("{}/test.tags", test_dir),
+        "- colour (single): red, blue, green\n- size: small, medium, large",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red\n- size = small & size ! small\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    assert!(
+        rules
+            .is_satisfiable("- size = small & size = large")
+            .unwrap()
+    );
+    assert!(
+        !rules
+            .is_satisfiable("- colour = red & colour = blue")
+            .unwrap()
+    );
+
+    let clauses = rules
+        .contradiction("- colour = red & colour = blue & size = small")
+        .unwrap()
+        .unwrap();
+    assert_eq!(clauses.len(), 2);
+    assert!(rules.contradiction("- colour = red").unwrap().is_none());
+    assert!(
+        rules
+            .tautology("- colour = red | colour ! red")
+            .unwrap()
+            .is_some()
+    );
+    assert!(
+        rules
+            .tautology("- colour = red | size = small")
+            .unwrap()
+            .is_none()
+    );
+
+    let result = rules.write_rule("test", "- colour = red & colour = blue");
+    if let Err(RulesError::RuleParseError(msg)) = result {
+        assert!(msg.contains("Rule can never match"));
+    } else {
+        panic!("Expected RuleParseError for an unsatisfiable rule");
+    }
+    assert_eq!(rules.rules().unwrap().len(), 2);
+
+    let warnings = rules.lint(0).unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].line, 2);
+    assert!(warnings[0].message.starts_with("Rule can never match"));
+    assert!(
+        warnings[0]
+            .message
+            .contains("size = small and size ! small")
+    );
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_rule_conflicts() {
+    let test_dir = setup_test_env("test_rule_conflicts");
+    fs::write(
+        format!("{}/test.tags", test_dir),
+        "- colour (single): red, blue, green\n- size: small, medium, large",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red\n- colour = red & size = large\n- colour = blue\n\n\
+         @effective_from 2030-01-01\n- colour = blue, red\n\n\
+         @draft blue_small\n- colour = blue & size = small\n",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/old.rules", test_dir),
+        "@expires 2025-01-01\n- colour = red\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // Colour is single-valued, so only rules which can agree on a colour conflict. Rules in
+    // effect at different times, and drafts, never do.
+    let conflicts = rules.rule_conflicts().unwrap();
+    let pairs: Vec<(String, usize, String, usize)> = conflicts
+        .iter()
+        .map(|c| {
+            (
+                c.first.file.rsplit('/').next().unwrap().to_string(),
+                c.first.line,
+                c.second.file.rsplit('/').next().unwrap().to_string(),
+                c.second.line,
+            )
+        })
+        .collect();
+    assert_eq!(
+        pairs,
+        vec![
+            ("old.rules".to_string(), 2, "test.rules".to_string(), 1),
+            ("old.rules".to_string(), 2, "test.rules".to_string(), 2),
+            ("test.rules".to_string(), 1, "test.rules".to_string(), 2),
+            ("test.rules".to_string(), 1, "test.rules".to_string(), 6),
+            ("test.rules".to_string(), 2, "test.rules".to_string(), 6),
+            ("test.rules".to_string(), 3, "test.rules".to_string(), 6),
+        ]
+    );
+    assert_eq!(conflicts[2].overlap, vec!["colour = red & size = large"]);
+    assert_eq!(conflicts[3].overlap, vec!["colour = red"]);
+    assert!(
+        conflicts[2]
+            .to_string()
+            .ends_with("both match when: colour = red & size = large")
+    );
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_analyze() {
+    let test_dir = setup_test_env("test_analyze");
+    fs::write(
+        format!("{}/test.tags", test_dir),
+        "- colour (single): red, blue, green\n- size: small, medium, large\n\
+         - shape (required): circle, square",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red => review\n- colour = red & size = large => reject\n\
+         - size = small, large\n- size = large | size = small\n- colour = blue => approve\n",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/other.rules", test_dir),
+        "- shape = circle | shape = square\n- size = small & size ! small\n\
+         @draft red\n- colour = red => approve\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // Shape is required and lists only circle and square, so other.rules:1 matches every
+    // object and subsumes every rule. The rule which can never match, and the draft, are
+    // left out.
+    let analysis = rules.analyze().unwrap();
+    let location =
+        |rule: &LoadedRule| format!("{}:{}", rule.file.rsplit('/').next().unwrap(), rule.line);
+    let subsumptions: Vec<(String, String, bool)> = analysis
+        .subsumptions
+        .iter()
+        .map(|s| (location(&s.broader), location(&s.narrower), s.equivalent))
+        .collect();
+    let expected: Vec<(&str, &str, bool)> = vec![
+        ("other.rules:1", "test.rules:1", false),
+        ("other.rules:1", "test.rules:2", false),
+        ("other.rules:1", "test.rules:3", false),
+        ("other.rules:1", "test.rules:4", false),
+        ("other.rules:1", "test.rules:5", false),
+        ("test.rules:1", "test.rules:2", false),
+        ("test.rules:3", "test.rules:2", false),
+        ("test.rules:4", "test.rules:2", false),
+        ("test.rules:3", "test.rules:4", true),
+    ];
+    assert_eq!(
+        subsumptions,
+        expected
+            .into_iter()
+            .map(|(b, n, e)| (b.to_string(), n.to_string(), e))
+            .collect::<Vec<_>>()
+    );
+    assert!(
+        analysis.subsumptions[8]
+            .to_string()
+            .contains(" is equivalent to ")
+    );
+
+    // Colour is single-valued, so the blue rule's outcome can't clash with the red ones
+    assert_eq!(analysis.outcome_conflicts.len(), 1);
+    let conflict = &analysis.outcome_conflicts[0];
+    assert_eq!(
+        (location(&conflict.first), location(&conflict.second)),
+        ("test.rules:1".to_string(), "test.rules:2".to_string())
+    );
+    assert_eq!(conflict.overlap, vec!["colour = red & size = large"]);
+    assert!(
+        analysis
+            .to_string()
+            .contains("both match when: colour = red & size = large (=> review and => reject)")
+    );
+    assert!(!analysis.is_empty());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_equivalent_rules() {
+    let test_dir = setup_test_env("test_equivalent_rules");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red & (size = small | size = large)\n\
+         - colour = blue\n\
+         - size = large & colour = red | colour = red & size = small\n",
+    )
+    .unwrap();
+    let proposed_dir = setup_test_env("test_equivalent_rules_proposed");
+    fs::write(
+        format!("{}/test.rules", proposed_dir),
+        "- (colour = red & size = large) | (size = small & colour = red)\n- colour = blue\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    let mut proposed = Rules::new(&proposed_dir);
+    proposed.load_tags().unwrap();
+
+    assert!(
+        rules
+            .is_equivalent(
+                "- colour = red | colour = red & size = small",
+                "- colour = red"
+            )
+            .unwrap()
+    );
+    assert!(
+        !rules
+            .is_equivalent("- colour = red", "- colour = blue")
+            .unwrap()
+    );
+
+    let warnings = rules.lint(0).unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].line, 3);
+    assert!(
+        warnings[0]
+            .message
+            .starts_with("Rule is equivalent to the rule at line 1")
+    );
+
+    // Rewriting a rule without changing what it matches isn't a change
+    let changes = rules.changeset(&proposed).unwrap();
+    assert_eq!(changes.rules.len(), 1);
+    assert_eq!(changes.rules[0].kind, ChangeKind::Removed);
+
+    cleanup_test_env(&test_dir);
+    cleanup_test_env(&proposed_dir);
+}
+
+#[test]
+fn test_rules_api_subsumed_rules() {
+    let test_dir = setup_test_env("test_subsumed_rules");
+    fs::write(
+        format!("{}/a.rules", test_dir),
+        "- colour = red, blue\n\n@expires 2030-01-01\n- shape = circle\n",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/b.rules", test_dir),
+        "- colour = red & size = large\n- shape = circle & size = small\n- colour = green\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // The circle rule expires, so the later one isn't always covered by it
+    let warnings = rules.lint(0).unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].file.ends_with("b.rules"));
+    assert_eq!(warnings[0].line, 1);
+    assert!(warnings[0].message.starts_with(&format!(
+        "Rule only matches objects {}/a.rules:1 already matches (- colour = red, blue)",
+        test_dir
+    )));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_coverage_gaps() {
+    let test_dir = setup_test_env("test_coverage_gaps");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red, blue\n- size = large\n\n@draft green\n- colour = green\n\n\
+         @expires 2020-01-01\n- colour = green\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // Drafts and expired rules don't cover anything
+    let report = rules.coverage_gaps(1).unwrap();
+    assert_eq!(report.tags, vec!["colour", "size"]);
+    assert_eq!(report.combinations, 16);
+    assert_eq!(report.uncovered, 6);
+    assert_eq!(
+        report.to_string(),
+        "6 of 16 combinations of colour, size match no rule\n  { colour = green, size = small }\n"
+    );
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_inline_tests() {
+    let test_dir = setup_test_env("test_inline_tests");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red & size = large\n\
+         #test: match { colour: red, size: large }\n\
+         #test: no_match { colour: red, size: small }\n\
+         \n\
+         @expires 2020-01-01\n\
+         - shape = circle\n\
+         #test: no_match { shape: circle }\n\
+         #test: match { shape: hexagon }\n",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // Tests are comments, so evaluation doesn't see them
+    assert_eq!(rules.rules().unwrap().len(), 2);
+
+    let results = rules.run_inline_tests().unwrap();
+    assert_eq!(results.len(), 4);
+    assert!(results[0].passed() && results[1].passed());
+
+    // The expired rule is still tested on its condition
+    assert_eq!(results[2].outcome, Ok(true));
+    assert!(
+        results[2]
+            .to_string()
+            .ends_with(":7: expected no_match but got match: - shape = circle")
+    );
+    assert!(results[3].outcome.is_err());
+    assert!(!results[3].passed());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_generate_examples() {
+    let test_dir = setup_test_env("test_generate_examples");
+    fs::write(
+        format!("{}/test.tags", test_dir),
+        "- colour: red, blue, green\n- shape (required): circle, square\n- size: small, medium, large",
+    )
+    .unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let rule = "- colour = red & size ! small";
+    let examples = rules.generate_examples(rule, 3, 42).unwrap();
+    assert_eq!(examples.matching.len(), 3);
+    assert_eq!(examples.non_matching.len(), 3);
+
+    // Every object is valid, so has the required shape
+    for obj in examples.matching.iter().chain(&examples.non_matching) {
+        assert!(rules.validate_object(obj).is_ok());
+        assert!(obj.contains_key("shape"));
+    }
+    for obj in &examples.matching {
+        assert_eq!(obj["colour"][0], "red");
+    }
+
+    assert!(rules.generate_examples("- colour = purple", 3, 42).is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_why_not() {
+    let test_dir = setup_test_env("test_why_not");
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+
+    let explanation = rules
+        .why_not("- colour = red & shape ! circle & size = large", &obj)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        explanation.to_string(),
+        "Fails: shape ! circle & size = large\n  - shape: circle\n  + size: large\n"
+    );
+    assert!(rules.why_not("- colour = red", &obj).unwrap().is_none());
+
+    obj.insert("colour".to_string(), vec!["purple".to_string()]);
+    assert!(rules.why_not("- colour = red", &obj).is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_truth_table() {
+    let test_dir = setup_test_env("test_truth_table");
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let table = rules.truth_table("- colour = red & size = large").unwrap();
+    assert_eq!(table.tags, vec!["colour", "size"]);
+    assert_eq!(table.rows.len(), 16);
+    assert_eq!(table.rows.iter().filter(|row| row.matched).count(), 1);
+
+    let csv = table.to_csv().unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "colour,size,matched");
+    assert_eq!(lines[1], "red,small,false");
+    assert_eq!(lines[4], ",small,false");
+    assert_eq!(lines[9], "red,large,true");
+
+    assert!(rules.truth_table("- colour = purple").is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_migrate_syntax() {
+    let test_dir = setup_test_env("test_migrate_syntax");
+    let legacy = "# Shapes\n@draft red_large\n- colour ! red, blue & size = large\n\
+                  #test: match { colour: green, size: large }\n\n  -shape=circle\n";
+    fs::write(format!("{}/test.rules", test_dir), legacy).unwrap();
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // Comments, annotations, tests and indentation stay where they were
+    assert_eq!(rules.migrate_syntax(GrammarVersion::V2).unwrap(), 2);
+    assert_eq!(
+        fs::read_to_string(format!("{}/test.rules", test_dir)).unwrap(),
+        "# Shapes\n@draft red_large\n- colour != \"red\", \"blue\" & size == \"large\"\n\
+         #test: match { colour: green, size: large }\n\n  -shape==\"circle\"\n"
+    );
+    let last = rules.audit_log(Some("test.rules")).unwrap().pop().unwrap();
+    assert_eq!(last.operation, AuditOperation::MigrateRules);
+    assert!(rules.run_inline_tests().unwrap()[0].passed());
+
+    assert_eq!(rules.migrate_syntax(GrammarVersion::V2).unwrap(), 0);
+    assert_eq!(rules.migrate_syntax(GrammarVersion::V1).unwrap(), 2);
+    assert_eq!(
+        fs::read_to_string(format!("{}/test.rules", test_dir)).unwrap(),
+        legacy
+    );
+
+    // Invalid rules stop the migration before anything is written
+    fs::write(format!("{}/bad.rules", test_dir), "- colour = purple\n").unwrap();
+    assert!(rules.migrate_syntax(GrammarVersion::V2).is_err());
+    assert_eq!(
+        fs::read_to_string(format!("{}/test.rules", test_dir)).unwrap(),
+        legacy
+    );
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_canonicalize() {
+    let test_dir = setup_test_env("test_canonicalize");
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    assert_eq!(
+        rules
+            .canonicalize("- (Shape=circle,square)&colour=red")
+            .unwrap(),
+        "- colour = red & shape = circle | colour = red & shape = square"
+    );
+    let fingerprint = rules.fingerprint("- colour = red & size = large").unwrap();
+    assert_eq!(fingerprint.len(), 16);
+    assert_eq!(
+        rules.fingerprint("- size=large & Colour=red").unwrap(),
+        fingerprint
+    );
+    assert!(rules.fingerprint("- colour = purple").is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_rule_ids() {
+    let test_dir = setup_test_env("test_rule_ids");
+    let _ = fs::remove_file(format!("{}/audit.log", test_dir));
+    let rules_path = format!("{}/ids.rules", test_dir);
+    let _ = fs::remove_file(&rules_path);
+
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    rules.write_rule("ids", "- colour = red").unwrap();
+    rules.write_rule("ids", "- colour = blue").unwrap();
+
+    // Writes of a rule record which rule was written
+    let entries = rules.audit_log(Some("ids.rules")).unwrap();
+    let id = entries[1].rule_id.clone().unwrap();
+    assert_eq!(id.file, "ids.rules");
+    assert_eq!(id.line, 2);
+    assert_eq!(RuleId::parse(&id.to_string()), Some(id.clone()));
+    let loaded = rules.find_rule(&id).unwrap().unwrap();
+    assert_eq!(loaded.rule, "- colour = blue");
+    assert_eq!(loaded.id(), id);
+
+    // The rule is still found after lines are added above it, but not once it's changed
+    fs::write(
+        &rules_path,
+        "# Colours\n- colour = green\n- colour = red\n- colour = blue",
+    )
+    .unwrap();
+    assert_eq!(rules.find_rule(&id).unwrap().unwrap().line, 4);
+    fs::write(&rules_path, "- colour = red\n- colour = blue, green").unwrap();
+    assert_eq!(rules.find_rule(&id).unwrap(), None);
+
+    fs::write(&rules_path, "- colour = red\n- colour = red").unwrap();
+    let warnings = rules.lint(0).unwrap();
+    let warning = warnings
+        .iter()
+        .find(|warning| warning.file.ends_with("ids.rules"))
+        .unwrap();
+    assert_eq!(
+        warning.rule_id,
+        RuleId::new("ids.rules", 2, "- colour = red")
+    );
+
+    let _ = fs::remove_file(&rules_path);
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_query() {
+    let test_dir = setup_test_env("test_query");
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let objects: Vec<HashMap<String, Vec<String>>> = ["red", "blue"]
+        .iter()
+        .map(|colour| HashMap::from([("colour".to_string(), vec![colour.to_string()])]))
+        .collect();
+    let query = rules
+        .query()
+        .tag("colour")
+        .ne("red")
+        .or()
+        .tag("colour")
+        .eq("red")
+        .and()
+        .tag("shape")
+        .eq("circle");
+    assert_eq!(query.run(&objects).unwrap(), vec![&objects[1]]);
+    assert_eq!(
+        query.to_string(),
+        "- colour ! red | colour = red & shape = circle"
+    );
+    assert!(
+        rules
+            .query()
+            .tag("colour")
+            .eq("orange")
+            .matches(&objects[0])
+            .is_err()
+    );
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_evaluate_with_context() {
+    let test_dir = setup_test_env("test_evaluate_with_context");
+    fs::write(
+        format!("{}/context.rules", test_dir),
+        "- colour = $ctx.colour & shape = circle",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["blue".to_string()]);
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+
+    let in_context = |colour: &str| {
+        rules
+            .evaluate_with_context(&obj, &EvalContext::new().with("colour", colour))
+            .unwrap()
+            .into_iter()
+            .filter(|loaded| loaded.file.ends_with("context.rules"))
+            .count()
+    };
+    assert_eq!(in_context("blue"), 1);
+    assert_eq!(in_context("red"), 0);
+
+    // Without a value for the variable, the rule's location is reported
+    let error = rules
+        .evaluate_with_context(&obj, &EvalContext::new())
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("context.rules:1"));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_evaluate() {
+    let test_dir = setup_test_env("test_evaluate");
+    fs::write(
+        format!("{}/test.tags", test_dir),
+        "- colour (required): red, blue\n- shape: circle, square\n- size: small, large",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red\n- shape = circle\n\n@draft trial\n- size = large\n",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/test.yaml", test_dir),
+        "objects:\n  shapes:\n    - colour: red\n      shape: circle\n    - colour: blue\n      size: large\n    - shape: square\n",
+    )
+    .unwrap();
+
+    // Drafts don't match, and the object without a colour is left out
+    let report = evaluate(&test_dir).unwrap();
+    assert_eq!(report.results.len(), 2);
+    assert_eq!(report.skipped_objects, 1);
+    let lines = |result: &ObjectMatches| -> Vec<usize> {
+        result.matched.iter().map(|loaded| loaded.line).collect()
+    };
+    assert_eq!(report.results[0].object.index, 0);
+    assert_eq!(lines(&report.results[0]), vec![1, 2]);
+    assert!(report.results[1].matched.is_empty());
+    assert_eq!(report.matched().count(), 1);
+    assert!(
+        report
+            .to_string()
+            .starts_with("1 of 2 objects matched (1 skipped for missing required tags)\n")
+    );
+
+    let mut rules = Rules::new(&test_dir);
+    rules.set_required_tag_policy(RequiredTagPolicy::Error);
+    rules.load_tags().unwrap();
+    assert!(matches!(
+        rules.evaluate(),
+        Err(RulesError::ObjectParseError(_))
+    ));
+
+    // Invalid objects are reported with where they were found
+    fs::write(
+        format!("{}/test.yaml", test_dir),
+        "objects:\n  shapes:\n    - colour: green\n",
+    )
+    .unwrap();
+    let error = evaluate(&test_dir).unwrap_err().to_string();
+    assert!(error.contains("test.yaml: object 0 of type 'shapes'"));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_explain() {
+    let test_dir = setup_test_env("test_explain");
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("size".to_string(), vec!["small".to_string()]);
+
+    let explanation = rules
+        .explain("- colour = red & size = large", &obj)
+        .unwrap();
+    assert!(!explanation.matched);
+    let outcomes: Vec<bool> = explanation
+        .children
+        .iter()
+        .map(|child| child.matched)
+        .collect();
+    assert_eq!(outcomes, vec![true, false]);
+
+    // Objects are validated like any other evaluation
+    obj.insert("colour".to_string(), vec!["purple".to_string()]);
+    assert!(rules.explain("- colour = red", &obj).is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_evaluate_object() {
+    let test_dir = setup_test_env("test_evaluate_object");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red\n- shape = circle & size = large\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // No objects file is needed
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+    let matched = rules.evaluate_object(&obj).unwrap();
+    assert_eq!(matched.len(), 1);
+    assert_eq!(matched[0].rule, "- colour = red");
+    assert_eq!(matched[0].id, rules.rules().unwrap()[0].id());
+
+    obj.insert("size".to_string(), vec!["huge".to_string()]);
+    assert!(rules.evaluate_object(&obj).is_err());
+
+    // Rules are compiled by load_tags, and again after a write
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+    fs::remove_file(format!("{}/test.rules", test_dir)).unwrap();
+    assert_eq!(rules.evaluate_object(&obj).unwrap().len(), 2);
+    rules.write_rule("test.rules", "- size = large").unwrap();
+    let matched = rules.evaluate_object(&obj).unwrap();
+    assert_eq!(matched.len(), 1);
+    assert_eq!(matched[0].rule, "- size = large");
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_compile() {
+    let test_dir = setup_test_env("test_compile");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red\n- shape = $ctx.shape\n\n@draft trial\n- size = large\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let ruleset = rules.compile().unwrap();
+    assert_eq!(ruleset.rules().len(), 2);
+
+    // Evaluating doesn't read the rules files again
+    fs::remove_file(format!("{}/test.rules", test_dir)).unwrap();
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+    let lines = |matched: Vec<MatchedRule>| -> Vec<usize> {
+        matched.iter().map(|matched| matched.line).collect()
+    };
+    assert_eq!(lines(ruleset.evaluate(&obj).unwrap()), vec![1]);
+
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+    let context = EvalContext::new().with("shape", "circle");
+    assert_eq!(
+        lines(ruleset.evaluate_with_context(&obj, &context).unwrap()),
+        vec![1, 2]
+    );
+    let error = ruleset.evaluate(&obj).unwrap_err().to_string();
+    assert!(error.contains("test.rules:2"));
+
+    fs::write(format!("{}/test.rules", test_dir), "- colour = purple").unwrap();
+    assert!(rules.compile().is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_evaluate_with() {
+    let test_dir = setup_test_env("test_evaluate_with");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red\n- shape = circle\n",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/test.yaml", test_dir),
+        "objects:\n  shapes:\n    - colour: red\n      shape: circle\n    - colour: blue\n    - shape: circle\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // Matches arrive one at a time, objects in file order
+    let mut seen: Vec<(usize, usize)> = Vec::new();
+    let matches = rules
+        .evaluate_with(|object_id, matched| seen.push((object_id.index, matched.line)))
+        .unwrap();
+    assert_eq!(matches, 3);
+    assert_eq!(seen, vec![(0, 1), (0, 2), (2, 2)]);
+
+    let mut first: Option<ObjectId> = None;
+    rules
+        .evaluate_with(|object_id, _| {
+            first.get_or_insert(object_id);
+        })
+        .unwrap();
+    assert!(first.unwrap().to_string().ends_with("test.yaml shapes[0]"));
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_eval_mode() {
+    let test_dir = setup_test_env("test_eval_mode");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- shape = circle\n- colour = red\n- colour = red & size = large\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    rules.enable_cache(CacheOptions::default());
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+    let lines = |matched: Vec<LoadedRule>| -> Vec<usize> {
+        matched.iter().map(|loaded| loaded.line).collect()
+    };
+    assert_eq!(lines(rules.matching_rules(&obj).unwrap()), vec![2, 3]);
+
+    // Only the first matching rule, and cached results from before aren't reused
+    rules.set_eval_mode(EvalMode::FirstMatch);
+    assert_eq!(lines(rules.matching_rules(&obj).unwrap()), vec![2]);
+    let compiled: Vec<usize> = rules
+        .compile()
+        .unwrap()
+        .evaluate(&obj)
+        .unwrap()
+        .iter()
+        .map(|matched| matched.line)
+        .collect();
+    assert_eq!(compiled, vec![2]);
+
+    rules.set_eval_mode(EvalMode::AllMatches);
+    assert_eq!(lines(rules.matching_rules(&obj).unwrap()), vec![2, 3]);
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_rule_priority() {
+    let test_dir = setup_test_env("test_rule_priority");
+    fs::write(format!("{}/a.rules", test_dir), "- colour = red\n").unwrap();
+    fs::write(
+        format!("{}/b.rules", test_dir),
+        "-[10] size = large\n-[5] colour = red\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+    let found = |matched: Vec<MatchedRule>| -> Vec<String> {
+        matched
+            .into_iter()
+            .map(|matched| {
+                format!(
+                    "{}:{}",
+                    matched.file.rsplit('/').next().unwrap(),
+                    matched.line
+                )
+            })
+            .collect()
+    };
+
+    // Higher priorities come first, whichever file they're in
+    assert_eq!(
+        found(rules.evaluate_object(&obj).unwrap()),
+        vec!["b.rules:1", "b.rules:2", "a.rules:1"]
+    );
+    let ruleset = rules.compile().unwrap();
+    assert_eq!(
+        found(ruleset.evaluate(&obj).unwrap()),
+        vec!["b.rules:1", "b.rules:2", "a.rules:1"]
+    );
+
+    // The highest priority match wins under FirstMatch
+    obj.remove("size");
+    rules.set_eval_mode(EvalMode::FirstMatch);
+    assert_eq!(
+        found(rules.evaluate_object(&obj).unwrap()),
+        vec!["b.rules:2"]
+    );
+    assert_eq!(
+        found(rules.compile().unwrap().evaluate(&obj).unwrap()),
+        vec!["b.rules:2"]
+    );
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_rule_outcome() {
+    let test_dir = setup_test_env("test_rule_outcome");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "-[10] colour = red & size = large => reject\n- colour = red => review\n- shape = circle\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+    let outcomes = |matched: Vec<MatchedRule>| -> Vec<Option<String>> {
+        matched.into_iter().map(|matched| matched.outcome).collect()
+    };
+    let expected = vec![Some("reject".to_string()), Some("review".to_string()), None];
+
+    assert_eq!(outcomes(rules.evaluate_object(&obj).unwrap()), expected);
+    assert_eq!(
+        outcomes(rules.compile().unwrap().evaluate(&obj).unwrap()),
+        expected
+    );
+    assert!(rules.validate_rule("- colour = red => ").is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_score() {
+    let test_dir = setup_test_env("test_score");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "@weight 2.5\n- colour = red\n@weight 0.5\n- size = large\n- shape = circle\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+    assert_eq!(rules.score(&obj).unwrap(), 3.0);
+    assert_eq!(rules.compile().unwrap().score(&obj).unwrap(), 3.0);
+
+    // Rules without a weight count 1, and objects matching nothing score 0
+    obj.insert("shape".to_string(), vec!["circle".to_string()]);
+    assert_eq!(rules.score(&obj).unwrap(), 4.0);
+    let mut unmatched = HashMap::new();
+    unmatched.insert("colour".to_string(), vec!["blue".to_string()]);
+    assert_eq!(rules.score(&unmatched).unwrap().to_string(), "0");
+
+    // Matches carry their weight
+    let weights: Vec<f64> = rules
+        .evaluate_object(&obj)
+        .unwrap()
+        .iter()
+        .map(|matched| matched.weight)
+        .collect();
+    assert_eq!(weights, vec![2.5, 0.5, 1.0]);
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_unknown_policy() {
+    let test_dir = setup_test_env("test_unknown_policy");
+    fs::write(
+        format!("{}/open.tags", test_dir),
+        "- vendor (open): acme, globex",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- vendor ! acme\n- vendor = acme | colour = red\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    rules.enable_cache(CacheOptions::default());
+
+    let mut obj = HashMap::new();
+    obj.insert("vendor".to_string(), vec!["initech".to_string()]);
+    obj.insert("colour".to_string(), vec!["blue".to_string()]);
+    let lines = |matched: Vec<LoadedRule>| -> Vec<usize> {
+        matched.iter().map(|loaded| loaded.line).collect()
+    };
+    assert_eq!(
+        rules
+            .rule_truth("- vendor = acme | colour = red", &obj)
+            .unwrap(),
+        Truth::Unknown
+    );
+
+    // Two-valued by default, initech being compared like any other value
+    assert_eq!(lines(rules.matching_rules(&obj).unwrap()), vec![1]);
+
+    rules.set_unknown_policy(UnknownPolicy::UnknownIsFalse);
+    assert!(rules.matching_rules(&obj).unwrap().is_empty());
+    assert!(rules.compile().unwrap().evaluate(&obj).unwrap().is_empty());
+
+    rules.set_unknown_policy(UnknownPolicy::UnknownIsTrue);
+    assert_eq!(lines(rules.matching_rules(&obj).unwrap()), vec![1, 2]);
+    assert_eq!(rules.compile().unwrap().evaluate(&obj).unwrap().len(), 2);
+
+    // A listed value is never Unknown
+    obj.insert("vendor".to_string(), vec!["globex".to_string()]);
+    assert_eq!(lines(rules.matching_rules(&obj).unwrap()), vec![1]);
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_missing_tag_policy() {
+    let test_dir = setup_test_env("test_missing_tag_policy");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red & size = large\n- shape ! circle\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+    rules.enable_cache(CacheOptions::default());
+
+    let mut obj = HashMap::new();
+    obj.insert("colour".to_string(), vec!["red".to_string()]);
+    let lines = |matched: Vec<LoadedRule>| -> Vec<usize> {
+        matched.iter().map(|loaded| loaded.line).collect()
+    };
+    let compiled = |rules: &Rules| -> Vec<usize> {
+        let ruleset = rules.compile().unwrap();
+        let matched = ruleset.evaluate(&obj).unwrap();
+        matched.iter().map(|matched| matched.line).collect()
+    };
+    assert_eq!(lines(rules.matching_rules(&obj).unwrap()), vec![2]);
+
+    rules.set_missing_tag_policy(MissingTagPolicy::FailClause);
+    assert!(rules.matching_rules(&obj).unwrap().is_empty());
+    assert!(compiled(&rules).is_empty());
+
+    // Rules indexed under a tag the object lacks are still checked
+    rules.set_missing_tag_policy(MissingTagPolicy::SkipClause);
+    assert_eq!(lines(rules.matching_rules(&obj).unwrap()), vec![1, 2]);
+    assert_eq!(compiled(&rules), vec![1, 2]);
+
+    rules.set_missing_tag_policy(MissingTagPolicy::Error);
+    assert!(matches!(
+        rules.matching_rules(&obj),
+        Err(RulesError::ObjectParseError(_))
+    ));
+    assert!(rules.compile().unwrap().evaluate(&obj).is_err());
+    obj.insert("size".to_string(), vec!["large".to_string()]);
+    obj.insert("shape".to_string(), vec!["square".to_string()]);
+    assert_eq!(lines(rules.matching_rules(&obj).unwrap()), vec![1, 2]);
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_rule_stats() {
+    let test_dir = setup_test_env("test_rule_stats");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red\n- shape = circle\n-[5] size = small\n\n@draft trial\n- size = large\n",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/test.yaml", test_dir),
+        "objects:\n  shapes:\n    - colour: red\n      shape: circle\n    - colour: red\n    - colour: blue\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    // Drafts aren't counted, rules are in file then line order whatever their priority, and
+    // rules are only checked against objects with the tags they need
+    let report = rules.evaluate().unwrap();
+    let counts: Vec<(usize, usize, usize)> = report
+        .stats()
+        .iter()
+        .map(|stats| (stats.rule.line, stats.evaluated, stats.matched))
+        .collect();
+    assert_eq!(counts, vec![(1, 3, 2), (2, 1, 1), (3, 0, 0)]);
+    assert!((report.stats()[0].match_rate() - 2.0 / 3.0).abs() < 1e-9);
+    assert!(
+        report.stats()[2]
+            .to_string()
+            .ends_with("size = small: matched 0 of 0")
+    );
+    assert_eq!(report.results[0].matched.len(), 2);
+
+    // Under FirstMatch, rules after an object's first match aren't checked
+    rules.set_eval_mode(EvalMode::FirstMatch);
+    let report = rules.evaluate().unwrap();
+    let counts: Vec<(usize, usize)> = report
+        .stats()
+        .iter()
+        .map(|stats| (stats.evaluated, stats.matched))
+        .collect();
+    assert_eq!(counts, vec![(3, 2), (0, 0), (0, 0)]);
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_rule_coverage() {
+    let test_dir = setup_test_env("test_rule_coverage");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red & size ! small\n- shape = circle | colour = green\n\n@draft trial\n- size = large\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let object = |colour: &str, size: &str| -> HashMap<String, Vec<String>> {
+        HashMap::from([
+            ("colour".to_string(), vec![colour.to_string()]),
+            ("size".to_string(), vec![size.to_string()]),
+        ])
+    };
+    let objects = vec![
+        object("red", "large"),
+        object("red", "small"),
+        object("blue", "medium"),
+    ];
+    let coverage = rules.rule_coverage(&objects).unwrap();
+    assert_eq!(coverage.objects, 3);
+
+    // Drafts are left out, and every comparison is counted, even after the rule is decided
+    let hits: Vec<(usize, usize, Vec<usize>)> = coverage
+        .rules
+        .iter()
+        .map(|hits| {
+            let clauses = hits.clauses.iter().map(|clause| clause.matched).collect();
+            (hits.rule.line, hits.matched, clauses)
+        })
+        .collect();
+    assert_eq!(hits, vec![(1, 1, vec![2, 2]), (2, 0, vec![0, 0])]);
+    let unmatched: Vec<usize> = coverage
+        .unmatched_rules()
+        .map(|loaded| loaded.line)
+        .collect();
+    assert_eq!(unmatched, vec![2]);
+
+    // Only tags the rules compare are listed
+    assert_eq!(
+        coverage.unexercised_values.get("colour"),
+        Some(&vec!["green".to_string()])
+    );
+    assert_eq!(
+        coverage.unexercised_values.get("shape").map(Vec::len),
+        Some(3)
+    );
+    assert!(!coverage.unexercised_values.contains_key("size"));
+    assert!(
+        coverage
+            .to_string()
+            .starts_with("1 of 2 rules matched none of 3 objects\n")
+    );
+
+    // Objects are validated
+    assert!(rules.rule_coverage(&[object("purple", "large")]).is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_trace() {
+    let test_dir = setup_test_env("test_trace");
+    fs::write(
+        format!("{}/test.rules", test_dir),
+        "- colour = red & size = large\n- shape = circle | colour = blue\n\n@draft trial\n- size = large\n",
+    )
+    .unwrap();
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    let obj = HashMap::from([
+        ("colour".to_string(), vec!["blue".to_string()]),
+        ("shape".to_string(), vec!["square".to_string()]),
+    ]);
+    let trace = rules.trace(&obj, &EvalContext::default()).unwrap();
+
+    // Drafts aren't traced, and operands already decided aren't visited
+    let steps: Vec<(usize, usize)> = trace
+        .rules
+        .iter()
+        .map(|rule| (rule.rule.line, rule.steps.len()))
+        .collect();
+    assert_eq!(steps, vec![(1, 2), (2, 3)]);
+    let matched: Vec<usize> = trace.matched().map(|loaded| loaded.line).collect();
+    assert_eq!(matched, vec![2]);
+    assert_eq!(trace.rules[1].steps[1].values, vec!["square".to_string()]);
+    assert!(trace.to_string().contains(
+        "test.rules:1 - colour = red & size = large: false\n  &: false\n    colour = red: false (blue)\n"
+    ));
+
+    // The trace agrees with matching_rules
+    let matched_rules: Vec<usize> = rules
+        .matching_rules(&obj)
+        .unwrap()
+        .iter()
+        .map(|loaded| loaded.line)
+        .collect();
+    assert_eq!(matched_rules, matched);
+
+    // Objects are validated
+    let invalid = HashMap::from([("colour".to_string(), vec!["purple".to_string()])]);
+    assert!(rules.trace(&invalid, &EvalContext::default()).is_err());
+
+    cleanup_test_env(&test_dir);
+}
+
+#[test]
+fn test_rules_api_quoted_values_with_spaces() {
+    let test_dir = setup_test_env("test_quoted_values");
+    let mut rules = Rules::new(&test_dir);
+    rules.load_tags().unwrap();
+
+    rules.write_tag("test", "colour", vec!["dark red"]).unwrap();
+    let tags = fs::read_to_string(format!("{}/test.tags", test_dir)).unwrap();
+    assert!(tags.contains("- colour: red, blue, green, \"dark red\""));
+
+    rules
+        .write_rule("test", "- colour = \"Dark Red\" & size = large")
+        .unwrap();
+    assert!(rules.validate_rule("- colour = \"light red\"").is_err());
+
+    let obj = HashMap::from([
+        ("colour".to_string(), vec!["dark red".to_string()]),
+        ("size".to_string(), vec!["large".to_string()]),
+    ]);
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 1);
+
+    // Quotes are kept through a migration either way
+    rules.migrate_syntax(GrammarVersion::V2).unwrap();
+    let migrated = fs::read_to_string(format!("{}/test.rules", test_dir)).unwrap();
+    assert!(migrated.contains("- colour == \"Dark Red\" & size == \"large\""));
+    rules.migrate_syntax(GrammarVersion::V1).unwrap();
+    let migrated = fs::read_to_string(format!("{}/test.rules", test_dir)).unwrap();
+    assert!(migrated.contains("- colour = \"Dark Red\" & size = large"));
+    assert_eq!(rules.matching_rules(&obj).unwrap().len(), 1);
+
+    cleanup_test_env(&test_dir);
+}
@@ -1,4 +1,4 @@
-use crate::api::write::tag::write_with_base_dir;
+use crate::api::write::tag::{format_with_base_dir, write_with_base_dir};
 use crate::err::RulesError;
 use std::fs;
 use std::path::Path;
@@ -122,24 +122,44 @@ fn test_write_tag_validates_spaces_in_name() {
 }
 
 #[test]
-fn test_write_tag_validates_spaces_in_values() {
+fn test_write_tag_quotes_values_with_spaces() {
     let file_name = "test_spaces_value.tags";
     setup_and_cleanup_test_file(file_name);
 
-    let result = write_with_base_dir(
+    write_with_base_dir(
         file_name,
         "colour".to_string(),
-        vec!["dark red".to_string()],
+        vec!["dark red".to_string(), "blue".to_string()],
         TEST_CONFIG_DIR,
-    );
+    )
+    .unwrap();
+    // Appending skips a value already there, quoted or not
+    write_with_base_dir(
+        file_name,
+        "colour".to_string(),
+        vec!["Dark Red".to_string(), "navy blue".to_string()],
+        TEST_CONFIG_DIR,
+    )
+    .unwrap();
 
-    assert!(result.is_err());
-    if let Err(RulesError::TagParseError(msg)) = result {
-        assert!(msg.contains("cannot contain spaces"));
-        assert!(msg.contains("dark red"));
-    } else {
-        panic!("Expected TagParseError about spaces in value");
-    }
+    let content = fs::read_to_string(format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
+    assert_eq!(content, "- colour: \"dark red\", blue, \"navy blue\"");
+    let tag = crate::parser::tags::get_tag_from_line(&content).unwrap();
+    assert_eq!(tag.values, vec!["dark red", "blue", "navy blue"]);
+
+    // Quotes and reserved characters are escaped
+    write_with_base_dir(
+        file_name,
+        "shape".to_string(),
+        vec!["8\" disc".to_string(), "a|b".to_string()],
+        TEST_CONFIG_DIR,
+    )
+    .unwrap();
+    let content = fs::read_to_string(format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
+    let line = content.lines().last().unwrap();
+    assert_eq!(line, "- shape: \"8\\\" disc\", \"a|b\"");
+    let tag = crate::parser::tags::get_tag_from_line(line).unwrap();
+    assert_eq!(tag.values, vec!["8\" disc", "a|b"]);
 
     cleanup_test_file(file_name);
 }
@@ -168,7 +188,7 @@ fn test_write_tag_appends_values_to_existing_tag() {
     .unwrap();
 
     // Read file and verify
-    let content = fs::read_to_string(&format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
+    let content = fs::read_to_string(format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
     assert!(content.contains("red"));
     assert!(content.contains("blue"));
     assert!(content.contains("green"));
@@ -205,7 +225,7 @@ fn test_write_tag_adds_multiple_tags() {
     .unwrap();
 
     // Read file and verify both tags exist
-    let content = fs::read_to_string(&format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
+    let content = fs::read_to_string(format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
     assert!(content.contains("colour"));
     assert!(content.contains("size"));
     assert!(content.contains("red"));
@@ -251,10 +271,224 @@ fn test_write_tag_formats_correctly() {
     )
     .unwrap();
 
-    let content = fs::read_to_string(&format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
+    let content = fs::read_to_string(format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
 
     // Should be formatted as "- colour: red, blue, green"
     assert!(content.contains("- colour: red, blue, green"));
 
     cleanup_test_file(file_name);
 }
+
+#[test]
+fn test_write_tag_rejects_appending_to_pattern_tag() {
+    let file_name = "test_pattern.tags";
+    setup_and_cleanup_test_file(file_name);
+
+    fs::write(
+        format!("{}/{}", TEST_CONFIG_DIR, file_name),
+        "- sku: /^[a-z]{2}\\d{4}$/",
+    )
+    .unwrap();
+
+    let result = write_with_base_dir(
+        file_name,
+        "sku".to_string(),
+        vec!["ab1234".to_string()],
+        TEST_CONFIG_DIR,
+    );
+
+    assert!(result.is_err());
+    if let Err(RulesError::TagParseError(msg)) = result {
+        assert!(msg.contains("pattern-constrained"));
+    } else {
+        panic!("Expected TagParseError about pattern tag");
+    }
+
+    cleanup_test_file(file_name);
+}
+
+#[test]
+fn test_write_tag_skips_existing_values() {
+    let file_name = "test_append_existing.tags";
+    setup_and_cleanup_test_file(file_name);
+
+    fs::write(
+        format!("{}/{}", TEST_CONFIG_DIR, file_name),
+        "- colour: red, blue",
+    )
+    .unwrap();
+
+    write_with_base_dir(
+        file_name,
+        "colour".to_string(),
+        vec!["Red".to_string(), "green".to_string()],
+        TEST_CONFIG_DIR,
+    )
+    .unwrap();
+
+    let content = fs::read_to_string(format!("{}/{}", TEST_CONFIG_DIR, file_name)).unwrap();
+    assert_eq!(content, "- colour: red, blue, green");
+
+    cleanup_test_file(file_name);
+}
+
+#[test]
+fn test_format_tags_file() {
+    let file_name = "test_format.tags";
+    setup_and_cleanup_test_file(file_name);
+
+    let path = format!("{}/{}", TEST_CONFIG_DIR, file_name);
+    fs::write(
+        &path,
+        "-size:small ,large\n# Colours\n-colour: red, blue, red\n",
+    )
+    .unwrap();
+
+    format_with_base_dir("test_format", TEST_CONFIG_DIR).unwrap();
+
+    let content = fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        content,
+        "# Colours\n- colour: red, blue\n- size: small, large"
+    );
+
+    // Invalid files are left untouched
+    fs::write(&path, "- colour: red\n- bad line").unwrap();
+    assert!(format_with_base_dir(file_name, TEST_CONFIG_DIR).is_err());
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "- colour: red\n- bad line"
+    );
+
+    cleanup_test_file(file_name);
+}
+
+#[test]
+fn test_write_tag_rejects_appending_to_flag_tag() {
+    let file_name = "test_flag.tags";
+    setup_and_cleanup_test_file(file_name);
+
+    let path = format!("{}/{}", TEST_CONFIG_DIR, file_name);
+    fs::write(&path, "- is_fragile (flag)").unwrap();
+
+    // Restating the flag's own values is a no-op
+    write_with_base_dir(
+        file_name,
+        "is_fragile".to_string(),
+        vec!["true".to_string()],
+        TEST_CONFIG_DIR,
+    )
+    .unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "- is_fragile (flag)");
+
+    let result = write_with_base_dir(
+        file_name,
+        "is_fragile".to_string(),
+        vec!["maybe".to_string()],
+        TEST_CONFIG_DIR,
+    );
+    if let Err(RulesError::TagParseError(msg)) = result {
+        assert!(msg.contains("is a flag"));
+    } else {
+        panic!("Expected TagParseError about flag tag");
+    }
+
+    cleanup_test_file(file_name);
+}
+
+#[test]
+fn test_write_tag_preserves_case() {
+    let file_name = "test_case.tags";
+    setup_and_cleanup_test_file(file_name);
+
+    let path = format!("{}/{}", TEST_CONFIG_DIR, file_name);
+    fs::write(&path, "- Colour: Red").unwrap();
+
+    write_with_base_dir(
+        file_name,
+        "colour".to_string(),
+        vec!["red".to_string(), "Blue".to_string()],
+        TEST_CONFIG_DIR,
+    )
+    .unwrap();
+    write_with_base_dir(
+        file_name,
+        "Size".to_string(),
+        vec!["Large".to_string()],
+        TEST_CONFIG_DIR,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "- Colour: Red, Blue\n- Size: Large"
+    );
+
+    cleanup_test_file(file_name);
+}
+
+#[test]
+fn test_write_tag_appends_to_ordered_tag() {
+    let file_name = "test_ordered.tags";
+    setup_and_cleanup_test_file(file_name);
+
+    let path = format!("{}/{}", TEST_CONFIG_DIR, file_name);
+    fs::write(&path, "- size: small < medium").unwrap();
+
+    write_with_base_dir(
+        file_name,
+        "size".to_string(),
+        vec!["medium".to_string(), "large".to_string()],
+        TEST_CONFIG_DIR,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "- size: small < medium < large"
+    );
+
+    cleanup_test_file(file_name);
+}
+
+#[test]
+fn test_write_tag_respects_tag_limits() {
+    let file_name = "test_limits.tags";
+    setup_and_cleanup_test_file(file_name);
+
+    let path = format!("{}/{}", TEST_CONFIG_DIR, file_name);
+    fs::write(&path, "- code (max_values=2, max_length=4): ab12").unwrap();
+
+    assert!(
+        write_with_base_dir(
+            file_name,
+            "code".to_string(),
+            vec!["cd345".to_string()],
+            TEST_CONFIG_DIR,
+        )
+        .is_err()
+    );
+    assert!(
+        write_with_base_dir(
+            file_name,
+            "code".to_string(),
+            vec!["cd34".to_string(), "ef56".to_string()],
+            TEST_CONFIG_DIR,
+        )
+        .is_err()
+    );
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "- code (max_values=2, max_length=4): ab12"
+    );
+
+    write_with_base_dir(
+        file_name,
+        "code".to_string(),
+        vec!["cd34".to_string()],
+        TEST_CONFIG_DIR,
+    )
+    .unwrap();
+
+    cleanup_test_file(file_name);
+}
@@ -1,11 +1,11 @@
 use crate::err::RulesError;
-use crate::parser::rules::RuleParser;
-use crate::types::{TagName, TagValues};
+use crate::parser::rules::{self, RuleParser};
+use crate::types::{TagName, TagValues, ValidationReport};
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
-fn normalise_filename(file_name: &str) -> String {
+pub(crate) fn normalise_filename(file_name: &str) -> String {
     if file_name.ends_with(".rules") {
         file_name.to_string()
     } else {
@@ -38,7 +38,7 @@ pub(crate) fn write_with_base_dir(
 ) -> Result<(), RulesError> {
     let base = base_dir;
 
-    write_internal(file_name, rule, tags, base)
+    write_internal(file_name, rule, &RuleParser::new(tags), base)
 }
 
 #[cfg(not(test))]
@@ -48,13 +48,23 @@ pub(crate) fn write_with_base_dir(
     tags: HashMap<TagName, TagValues>,
     base_dir: &str,
 ) -> Result<(), RulesError> {
-    write_internal(file_name, rule, tags, base_dir)
+    write_internal(file_name, rule, &RuleParser::new(tags), base_dir)
+}
+
+// Validates with a caller-configured parser -- e.g. one aware of tag constraints
+pub(crate) fn write_with_parser(
+    file_name: &str,
+    rule: &str,
+    parser: &RuleParser,
+    base_dir: &str,
+) -> Result<(), RulesError> {
+    write_internal(file_name, rule, parser, base_dir)
 }
 
 fn write_internal(
     file_name: &str,
     rule: &str,
-    tags: HashMap<TagName, TagValues>,
+    parser: &RuleParser,
     base_dir: &str,
 ) -> Result<(), RulesError> {
     // normalise filename
@@ -63,18 +73,21 @@ fn write_internal(
 
     ensure_config_dir(base_dir)?;
 
-    let parser = RuleParser::new(tags);
     parser.validate_rule(rule)?;
+    if !parser.is_satisfiable(rule)? {
+        return Err(RulesError::RuleParseError(format!(
+            "Rule can never match: {}",
+            rule.trim()
+        )));
+    }
 
     // Read existing file or create new content
-    let mut lines: Vec<String> = if Path::new(&full_path).exists() {
+    let content = if Path::new(&full_path).exists() {
         fs::read_to_string(&full_path)?
-            .lines()
-            .map(|l: &str| l.to_string())
-            .collect()
     } else {
-        Vec::new()
+        String::new()
     };
+    let mut lines: Vec<String> = content.lines().map(|l: &str| l.to_string()).collect();
 
     let rule_trimmed = rule.trim();
     if lines
@@ -86,6 +99,24 @@ fn write_internal(
         ));
     }
 
+    // Rules differing only in spacing, case, grouping or operand order are the same rule -- e.g.
+    // "-colour=red" and "- colour = red". Annotated rules, such as drafts, aren't duplicated by a
+    // rule without them, and lines which no longer parse are left for validation to report.
+    let key = parser.duplicate_key(rule)?;
+    let existing =
+        rules::read_rules_from_str(&full_path, &content, &mut ValidationReport::default());
+    if let Some(duplicate) = existing.iter().find(|loaded| {
+        loaded.annotations.is_empty()
+            && parser
+                .duplicate_key(&loaded.rule)
+                .is_ok_and(|existing_key| existing_key == key)
+    }) {
+        return Err(RulesError::RuleParseError(format!(
+            "Rule already exists in file at line {}: {}",
+            duplicate.line, duplicate.rule
+        )));
+    }
+
     lines.push(rule_trimmed.to_string());
 
     fs::write(&full_path, lines.join("\n"))?;
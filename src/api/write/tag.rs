@@ -1,9 +1,10 @@
 use crate::err::RulesError;
 use crate::parser::tags;
+use crate::utils::string;
 use std::fs;
 use std::path::Path;
 
-fn normalise_filename(file_name: &str) -> String {
+pub(crate) fn normalise_filename(file_name: &str) -> String {
     if file_name.ends_with(".tags") {
         file_name.to_string()
     } else {
@@ -19,6 +20,19 @@ fn ensure_config_dir(base_dir: &str) -> Result<(), RulesError> {
     Ok(())
 }
 
+// Rewrite a .tags file with tags sorted, values deduplicated and spacing normalised
+pub(crate) fn format_with_base_dir(file_name: &str, base_dir: &str) -> Result<(), RulesError> {
+    let normalised_name = normalise_filename(file_name);
+    let full_path = format!("{}/{}", base_dir, normalised_name);
+
+    let content = fs::read_to_string(&full_path)?;
+    let formatted = tags::format_tags(&content)?;
+
+    fs::write(&full_path, formatted)?;
+
+    Ok(())
+}
+
 pub fn write(file_name: &str, tag_name: String, tag_values: Vec<String>) -> Result<(), RulesError> {
     write_with_base_dir(file_name, tag_name, tag_values, "config")
 }
@@ -49,6 +63,32 @@ fn write_internal(
     tag_values: Vec<String>,
     base_dir: &str,
 ) -> Result<(), RulesError> {
+    let content = with_tag(&read(file_name, base_dir)?, &tag_name, &tag_values)?;
+
+    ensure_config_dir(base_dir)?;
+    let full_path = format!("{}/{}", base_dir, normalise_filename(file_name));
+    fs::write(&full_path, content)?;
+
+    Ok(())
+}
+
+// Content of a .tags file in `base_dir`, or nothing if it doesn't exist yet
+pub(crate) fn read(file_name: &str, base_dir: &str) -> Result<String, RulesError> {
+    let full_path = format!("{}/{}", base_dir, normalise_filename(file_name));
+    if !Path::new(&full_path).exists() {
+        return Ok(String::new());
+    }
+    Ok(fs::read_to_string(&full_path)?)
+}
+
+// `content` of a .tags file with `tag_values` appended to `tag_name`, or the tag added if it's
+// not there, checked as the file would be when loaded -- e.g. "- colour: red" with colour: blue
+// gives "- colour: red, blue"
+pub(crate) fn with_tag(
+    content: &str,
+    tag_name: &str,
+    tag_values: &[String],
+) -> Result<String, RulesError> {
     if tag_name.trim().is_empty() {
         return Err(RulesError::TagParseError(
             "Tag name cannot be empty".to_string(),
@@ -67,41 +107,58 @@ fn write_internal(
         ));
     }
 
-    for value in &tag_values {
-        if value.contains(' ') {
-            return Err(RulesError::TagParseError(format!(
-                "Tag value '{}' cannot contain spaces",
-                value
-            )));
-        }
-    }
-
-    let normalised_name = normalise_filename(file_name);
-    let full_path = format!("{}/{}", base_dir, normalised_name);
-
-    ensure_config_dir(base_dir)?;
+    // Values with spaces are written quoted -- e.g. "dark red"
+    let tag_values: Vec<String> = tag_values
+        .iter()
+        .map(|value| string::quote_if_needed(value.trim()))
+        .collect();
 
-    let mut lines: Vec<String> = if Path::new(&full_path).exists() {
-        fs::read_to_string(&full_path)?
-            .lines()
-            .map(|l: &str| l.to_string())
-            .collect()
-    } else {
-        Vec::new()
-    };
+    let mut lines: Vec<String> = content.lines().map(|l: &str| l.to_string()).collect();
 
     let mut tag_exists = false;
-    let tag_name_trimmed = tag_name.trim().to_lowercase();
+    // Written as given, matched against existing tags ignoring case
+    let tag_name_trimmed = tag_name.trim();
 
     for line in &mut lines {
         if line.trim().starts_with('#') || line.trim().is_empty() {
             continue;
         }
 
-        match tags::get_name_and_values_from_tag(line) {
-            Ok((extracted_name, _extracted_values)) => {
-                if extracted_name.trim() == tag_name_trimmed {
-                    line.push_str(&format!(", {}", tag_values.join(", ")));
+        match tags::get_tag_from_line(line) {
+            Ok(tag) => {
+                if tag.name.trim().eq_ignore_ascii_case(tag_name_trimmed) {
+                    if tag.constraints.pattern.is_some() {
+                        return Err(RulesError::TagParseError(format!(
+                            "Tag '{}' is pattern-constrained, values cannot be appended",
+                            tag_name_trimmed
+                        )));
+                    }
+
+                    // Only append values the tag doesn't already have
+                    let new_values: Vec<&String> = tag_values
+                        .iter()
+                        .filter(|v| {
+                            let v = string::unquote(v);
+                            !tag.values.iter().any(|e| e.eq_ignore_ascii_case(&v))
+                        })
+                        .collect();
+
+                    if tag.constraints.flag && !new_values.is_empty() {
+                        return Err(RulesError::TagParseError(format!(
+                            "Tag '{}' is a flag, its only values are true and false",
+                            tag_name_trimmed
+                        )));
+                    }
+
+                    // New values of an ordered tag rank above the existing ones
+                    let separator = if tag.constraints.ordered { " < " } else { ", " };
+                    for value in new_values {
+                        line.push_str(&format!("{}{}", separator, value));
+                    }
+
+                    // The extended line must still fit the tag's own limits
+                    tags::get_tag_from_line(line)?;
+
                     tag_exists = true;
                     break;
                 }
@@ -114,10 +171,10 @@ fn write_internal(
 
     if !tag_exists {
         let new_tag = format!("- {}: {}", tag_name_trimmed, tag_values.join(", "));
+        // Checked as it would be when loaded -- e.g. a value holding a quote
+        tags::validate_tag(&new_tag)?;
         lines.push(new_tag);
     }
 
-    fs::write(&full_path, lines.join("\n"))?;
-
-    Ok(())
+    Ok(lines.join("\n"))
 }
@@ -0,0 +1,290 @@
+// Parsed rules for analyses and transpilers outside the crate -- e.g. listing the tags a rule
+// compares, or writing it as SQL, from Rules::parse_rule without re-parsing the rule text
+use crate::types::Clause;
+
+pub use crate::parser::types::{AstRule, Node, Token};
+
+// Called on each node of a rule, operators before and after their operands -- e.g.
+// enter_op(&), visit_clause(colour = red), visit_clause(size = large), leave_op(&)
+pub trait AstVisitor {
+    // Before the operands of an '&' or '|'
+    fn enter_op(&mut self, _op: &Token) {}
+
+    // After the operands of an '&' or '|'
+    fn leave_op(&mut self, _op: &Token) {}
+
+    // A comparison at a leaf -- e.g. colour = red
+    fn visit_clause(&mut self, _clause: &Clause) {}
+}
+
+/// Walks a parsed rule depth first, left operand before right, calling the
+/// visitor on each node.
+///
+/// Leaves hold a comparison, and every other node is an `&` or `|` with two
+/// operands. Comma lists have already been expanded into `|`, flag
+/// shorthand into `= true` or `= false`, and `!` before a group pushed down
+/// to the group's comparisons.
+///
+/// # Arguments
+/// * `node` - Node to start from, usually a rule's `root_node`
+/// * `visitor` - Called on each node
+///
+/// # Examples
+/// ```ignore
+/// struct TagNames(Vec<String>);
+///
+/// impl AstVisitor for TagNames {
+///     fn visit_clause(&mut self, clause: &Clause) {
+///         self.0.push(clause.tag_name.clone());
+///     }
+/// }
+///
+/// let mut names = TagNames(Vec::new());
+/// ast::walk(&rules.parse_rule("- colour = red & size = large")?.root_node, &mut names);
+/// ```
+pub fn walk(node: &Node, visitor: &mut impl AstVisitor) {
+    // Nodes still to visit, and operators to leave once their operands are visited, on a stack
+    // rather than recursing, as a long rule's chain of operators is as deep as it is long
+    enum Step<'a> {
+        Visit(&'a Node),
+        Leave(&'a Token),
+    }
+
+    let mut steps = vec![Step::Visit(node)];
+    while let Some(step) = steps.pop() {
+        let node = match step {
+            Step::Visit(node) => node,
+            Step::Leave(op) => {
+                visitor.leave_op(op);
+                continue;
+            }
+        };
+        if let Some(clause) = &node.clause {
+            visitor.visit_clause(clause);
+            continue;
+        }
+
+        visitor.enter_op(&node.token);
+        steps.push(Step::Leave(&node.token));
+        steps.extend(node.right.as_deref().map(Step::Visit));
+        steps.extend(node.left.as_deref().map(Step::Visit));
+    }
+}
+
+/// Combines a parsed rule into one value, bottom up, e.g. to transpile it.
+///
+/// Each comparison is turned into a value by `on_clause`, then each `&` or
+/// `|` combines the values of its operands with `on_op`.
+///
+/// # Arguments
+/// * `node` - Node to start from, usually a rule's `root_node`
+/// * `on_clause` - Value of a comparison
+/// * `on_op` - Value of an operator, given the values of its left and right operands
+///
+/// # Returns
+/// * The value of `node`
+///
+/// # Panics
+/// If an operator node is missing an operand, which parsed rules never are.
+///
+/// # Examples
+/// ```ignore
+/// let ast = rules.parse_rule("- colour = red & (size = small | size = large)")?;
+/// let sql = ast::fold(
+///     &ast.root_node,
+///     &mut |clause| format!("{} = '{}'", clause.tag_name, clause.tag_value),
+///     &mut |op, left, right| match op {
+///         Token::And => format!("({} AND {})", left, right),
+///         _ => format!("({} OR {})", left, right),
+///     },
+/// );
+/// ```
+pub fn fold<T>(
+    node: &Node,
+    on_clause: &mut impl FnMut(&Clause) -> T,
+    on_op: &mut impl FnMut(&Token, T, T) -> T,
+) -> T {
+    if let Some(clause) = &node.clause {
+        return on_clause(clause);
+    }
+
+    let (Some(_), Some(_)) = (&node.left, &node.right) else {
+        panic!("'{}' node is missing an operand", node.token);
+    };
+
+    // Down the chain of the operator rather than recursing, combining from the right as it groups
+    let mut values: Vec<T> = node
+        .chain()
+        .into_iter()
+        .map(|operand| fold(operand, on_clause, on_op))
+        .collect();
+    let mut right = values
+        .pop()
+        .expect("an operator's chain has two or more operands");
+    while let Some(left) = values.pop() {
+        right = on_op(&node.token, left, right);
+    }
+    right
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::rules::RuleParser;
+    #[cfg(feature = "json_logic")]
+    use crate::types::ComparisonOp;
+    use std::collections::HashMap;
+
+    fn parse(rule: &str) -> AstRule {
+        let mut tags = HashMap::new();
+        tags.insert(
+            "colour".to_string(),
+            vec!["red".to_string(), "blue".to_string()],
+        );
+        tags.insert(
+            "size".to_string(),
+            vec!["small".to_string(), "large".to_string()],
+        );
+        RuleParser::new(tags).string_to_rule(rule).unwrap()
+    }
+
+    // Records each call, in order -- e.g. ["&", "colour = red", "/&"]
+    struct Trace(Vec<String>);
+
+    impl AstVisitor for Trace {
+        fn enter_op(&mut self, op: &Token) {
+            self.0.push(op.to_string());
+        }
+
+        fn leave_op(&mut self, op: &Token) {
+            self.0.push(format!("/{}", op));
+        }
+
+        fn visit_clause(&mut self, clause: &Clause) {
+            self.0.push(clause.to_string());
+        }
+    }
+
+    #[test]
+    fn test_walk() {
+        let ast = parse("- colour = red & size = small, large");
+        let mut trace = Trace(Vec::new());
+        walk(&ast.root_node, &mut trace);
+        assert_eq!(
+            trace.0,
+            vec![
+                "|",
+                "&",
+                "colour = red",
+                "size = small",
+                "/&",
+                "size = large",
+                "/|"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold() {
+        let ast = parse("- colour ! red & (size = small | size = large)");
+        let sql = fold(
+            &ast.root_node,
+            &mut |clause| {
+                format!(
+                    "{} {} '{}'",
+                    clause.tag_name,
+                    clause.comparison_op.as_str(),
+                    clause.tag_value
+                )
+            },
+            &mut |op, left, right| match op {
+                Token::And => format!("({} AND {})", left, right),
+                _ => format!("({} OR {})", left, right),
+            },
+        );
+        assert_eq!(
+            sql,
+            "(colour ! 'red' AND (size = 'small' OR size = 'large'))"
+        );
+
+        let depth = fold(&ast.root_node, &mut |_| 0, &mut |_, left, right| {
+            1 + left.max(right)
+        });
+        assert_eq!(depth, 2);
+    }
+
+    #[test]
+    fn test_to_canonical_string() {
+        let ast = parse("- colour = red & size = small, large");
+        assert_eq!(
+            ast.to_canonical_string(),
+            "- colour = red & size = small | size = large"
+        );
+
+        let ast = parse("-colour!red&(size=small|(size=large))");
+        assert_eq!(
+            ast.to_canonical_string(),
+            "- colour ! red & (size = small | size = large)"
+        );
+        assert_eq!(parse(&ast.to_canonical_string()), ast);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let dot = parse("- colour = red & (size = small | size = \"large\")").to_dot();
+        assert_eq!(
+            dot,
+            "digraph rule {\n\
+             \x20   n0 [label=\"&\"];\n\
+             \x20   n1 [label=\"colour = red\", shape=box];\n\
+             \x20   n0 -> n1;\n\
+             \x20   n2 [label=\"|\"];\n\
+             \x20   n3 [label=\"size = small\", shape=box];\n\
+             \x20   n2 -> n3;\n\
+             \x20   n4 [label=\"size = large\", shape=box];\n\
+             \x20   n2 -> n4;\n\
+             \x20   n0 -> n2;\n\
+             }\n"
+        );
+    }
+
+    #[cfg(feature = "json_logic")]
+    #[test]
+    fn test_to_json_logic() {
+        // Negations are pushed down to comparisons, and the chain of '&' is one list
+        let logic = parse("- colour = red & colour ! blue & !(size = small | size?)")
+            .to_json_logic()
+            .unwrap();
+        assert_eq!(
+            logic.to_string(),
+            "{\"and\":[{\"in\":[\"red\",{\"var\":\"colour\"}]},\
+             {\"!\":{\"in\":[\"blue\",{\"var\":\"colour\"}]}},\
+             {\"!\":{\"in\":[\"small\",{\"var\":\"size\"}]}},{\"!\":{\"var\":\"size\"}}]}"
+        );
+
+        // Ranks need the tag's values, and wildcards have no JsonLogic equivalent
+        let mut ranked = parse("- size = small");
+        if let Some(clause) = ranked.root_node.clause.as_mut() {
+            clause.comparison_op = ComparisonOp::GRTH;
+        }
+        let error = ranked.to_json_logic().unwrap_err();
+        assert!(error.to_string().contains("ranks"));
+        let error = parse("- colour = r*").to_json_logic().unwrap_err();
+        assert!(error.to_string().contains("wildcards"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let ast = parse("- colour = red & !(size = small | size?)");
+        let serialized = serde_yaml::to_string(&ast).unwrap();
+        assert!(serialized.contains("token: And"));
+
+        let reloaded: AstRule = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(reloaded, ast);
+        assert_eq!(
+            reloaded.to_canonical_string(),
+            "- colour = red & size ! small & !(size?)"
+        );
+    }
+}
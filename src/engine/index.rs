@@ -0,0 +1,137 @@
+// Subrules by the tag an object must have for them to match, so evaluating an object only checks
+// subrules it could match -- e.g. "colour" -> [0, 3] for "- colour = red" and "- colour > blue"
+use crate::types::{CaseFolding, ComparisonOp, Object, SubRule, SubRuleNumber, TagName};
+
+use std::collections::HashMap;
+
+pub struct SubRuleIndex {
+    m_case_folding: CaseFolding,
+    // Subrules with an '=', '<', '>', regex or presence clause, under the folded tag of the first one
+    m_by_tag: HashMap<TagName, Vec<SubRuleNumber>>,
+    // Subrules of only '!' clauses and plugin calls, which can match objects without their tags
+    m_unindexed: Vec<SubRuleNumber>,
+}
+
+impl SubRuleIndex {
+    pub fn new(subrules: &HashMap<SubRuleNumber, SubRule>, case_folding: CaseFolding) -> Self {
+        let mut by_tag: HashMap<TagName, Vec<SubRuleNumber>> = HashMap::new();
+        let mut unindexed: Vec<SubRuleNumber> = Vec::new();
+
+        for (number, subrule) in subrules {
+            // '=', '<', '>', regexes and presence checks all fail on an object without the tag
+            let needed = subrule.clauses.iter().find(|clause| {
+                matches!(
+                    clause.comparison_op,
+                    ComparisonOp::ISEQ
+                        | ComparisonOp::LSTH
+                        | ComparisonOp::GRTH
+                        | ComparisonOp::REGX
+                        | ComparisonOp::PRES
+                )
+            });
+            match needed {
+                Some(clause) => by_tag
+                    .entry(case_folding.fold(&clause.tag_name))
+                    .or_default()
+                    .push(*number),
+                None => unindexed.push(*number),
+            }
+        }
+        for numbers in by_tag.values_mut() {
+            numbers.sort_unstable();
+        }
+        unindexed.sort_unstable();
+
+        SubRuleIndex {
+            m_case_folding: case_folding,
+            m_by_tag: by_tag,
+            m_unindexed: unindexed,
+        }
+    }
+
+    // Subrules `obj` could match, in number order -- those under a tag it has, and the unindexed
+    pub fn candidates(&self, obj: &Object) -> Vec<SubRuleNumber> {
+        let mut candidates: Vec<SubRuleNumber> = obj
+            .keys()
+            .filter_map(|tag_name| self.m_by_tag.get(&self.m_case_folding.fold(tag_name)))
+            .flatten()
+            .chain(&self.m_unindexed)
+            .copied()
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+
+    // Subrules listed under `tag_name` -- e.g. every subrule needing a colour
+    pub fn subrules_for(&self, tag_name: &str) -> &[SubRuleNumber] {
+        self.m_by_tag
+            .get(&self.m_case_folding.fold(tag_name))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    // Subrules checked for every object
+    pub fn unindexed(&self) -> &[SubRuleNumber] {
+        &self.m_unindexed
+    }
+}
+
+impl Default for SubRuleIndex {
+    fn default() -> Self {
+        SubRuleIndex::new(&HashMap::new(), CaseFolding::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Clause;
+
+    fn clause(tag_name: &str, comparison_op: ComparisonOp, tag_value: &str) -> Clause {
+        Clause {
+            tag_name: tag_name.to_string(),
+            comparison_op,
+            tag_value: tag_value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_subrule_index() {
+        let subrules = HashMap::from([
+            (
+                0,
+                SubRule::new(0, vec![clause("colour", ComparisonOp::ISEQ, "red")]),
+            ),
+            (
+                1,
+                SubRule::new(
+                    1,
+                    vec![
+                        clause("shape", ComparisonOp::NOEQ, "circle"),
+                        clause("size", ComparisonOp::GRTH, "small"),
+                    ],
+                ),
+            ),
+            (
+                2,
+                SubRule::new(2, vec![clause("shape", ComparisonOp::NOEQ, "circle")]),
+            ),
+        ]);
+        let index = SubRuleIndex::new(&subrules, CaseFolding::default());
+
+        // Subrules are listed under the first tag they need, '!' alone needing none
+        assert_eq!(index.subrules_for("colour"), &[0]);
+        assert_eq!(index.subrules_for("size"), &[1]);
+        assert!(index.subrules_for("shape").is_empty());
+        assert_eq!(index.unindexed(), &[2]);
+
+        let obj = |tag_names: &[&str]| -> Object {
+            tag_names
+                .iter()
+                .map(|tag_name| (tag_name.to_string(), vec!["x".to_string()]))
+                .collect()
+        };
+        assert_eq!(index.candidates(&obj(&["Colour", "size"])), vec![0, 1, 2]);
+        assert_eq!(index.candidates(&obj(&["shape"])), vec![2]);
+    }
+}
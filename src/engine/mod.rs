@@ -0,0 +1,446 @@
+// Loaded configuration the matching pipeline runs on: tags, rules as DNF subrules and objects --
+// e.g. Engine::new("config").load(), then engine.subrules()
+mod index;
+mod ruleset;
+
+pub use index::SubRuleIndex;
+pub use ruleset::RuleSet;
+
+use crate::api::audit;
+use crate::err::RulesError;
+use crate::parser::objects;
+use crate::parser::plugin::PluginMap;
+use crate::parser::rules::{self, RuleParser};
+use crate::parser::tags;
+use crate::types::{
+    EngineStats, EvaluationOptions, LoadedObject, LoadedRule, MatchedRule, Object,
+    OperatorPrecedence, RuleLimits, SubRule, SubRuleNumber, TagConstraintsMap, TagLoadOptions,
+    TagName, TagValues,
+};
+use crate::utils::file;
+
+use std::collections::HashMap;
+
+pub struct Engine {
+    m_config_dir: String,
+    m_tag_load_options: TagLoadOptions,
+    m_options: EvaluationOptions,
+    m_precedence: OperatorPrecedence,
+    m_rule_limits: RuleLimits,
+    m_plugins: PluginMap,
+    m_tags: HashMap<TagName, TagValues>,
+    m_tag_constraints: TagConstraintsMap,
+    m_ruleset: RuleSet,
+    m_objects: Vec<LoadedObject>,
+    m_stats: EngineStats,
+}
+
+impl Engine {
+    /// Creates an engine for a config directory, with nothing loaded yet.
+    ///
+    /// # Arguments
+    /// * `config_dir` - Directory holding the .tags, .rules and .yaml files
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let mut engine = Engine::new("config");
+    /// engine.load()?;
+    /// println!("{}", engine.stats());
+    /// ```
+    pub fn new(config_dir: &str) -> Self {
+        Engine {
+            m_config_dir: config_dir.to_string(),
+            m_tag_load_options: TagLoadOptions::default(),
+            m_options: EvaluationOptions::default(),
+            m_precedence: OperatorPrecedence::default(),
+            m_rule_limits: RuleLimits::default(),
+            m_plugins: PluginMap::new(),
+            m_tags: HashMap::new(),
+            m_tag_constraints: TagConstraintsMap::new(),
+            m_ruleset: RuleSet::default(),
+            m_objects: Vec::new(),
+            m_stats: EngineStats::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: EvaluationOptions) -> Self {
+        self.m_options = options;
+        self
+    }
+
+    pub fn with_tag_load_options(mut self, options: TagLoadOptions) -> Self {
+        self.m_tag_load_options = options;
+        self
+    }
+
+    // How tightly '&', '|' and '->' bind in the rules loaded, checked when they're loaded
+    pub fn with_operator_precedence(mut self, precedence: OperatorPrecedence) -> Self {
+        self.m_precedence = precedence;
+        self
+    }
+
+    // How deeply the rules loaded may nest groups and how many tokens they may have
+    pub fn with_rule_limits(mut self, limits: RuleLimits) -> Self {
+        self.m_rule_limits = limits;
+        self
+    }
+
+    // Functions rules may call, by name -- e.g. "before"
+    pub fn with_plugins(mut self, plugins: PluginMap) -> Self {
+        self.m_plugins = plugins;
+        self
+    }
+
+    /// Loads the config directory: tags, then every rule in effect as DNF
+    /// subrules, then the objects to evaluate.
+    ///
+    /// Drafts and rules outside their `@effective_from` and `@expires`
+    /// window are left out. Objects missing a required tag are left out, or
+    /// fail the load, per the engine's
+    /// [`RequiredTagPolicy`](crate::types::RequiredTagPolicy). Loading again
+    /// replaces everything, and a failed load leaves the last successful one
+    /// in place.
+    ///
+    /// # Returns
+    /// * `Ok(())` if every file loaded
+    /// * `Err(RulesError)` if a file is invalid or an object misses a required tag
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let mut engine = Engine::new("config");
+    /// engine.load()?;
+    /// ```
+    pub fn load(&mut self) -> Result<(), RulesError> {
+        self.m_precedence.check()?;
+
+        // Parsing and storage
+        let parsed = tags::parse_tags_with_options(
+            &format!("{}/*.tags", self.m_config_dir),
+            self.m_tag_load_options,
+        )?;
+        let mut tags: HashMap<TagName, TagValues> = HashMap::new();
+        let mut tag_constraints = TagConstraintsMap::new();
+        for tag in parsed.tags {
+            tag_constraints.insert(tag.name.clone(), tag.constraints);
+            tags.insert(tag.name, tag.values);
+        }
+
+        // Parsing, convertion to DNF and storage
+        let parser = RuleParser::new(tags.clone())
+            .with_tag_constraints(tag_constraints.clone())
+            .with_case_folding(self.m_tag_load_options.case_folding)
+            .with_plugins(self.m_plugins.clone())
+            .with_exclusive_groups(parsed.exclusive_groups)
+            .with_unknown_policy(self.m_options.unknown_values)
+            .with_missing_tag_policy(self.m_options.missing_tags)
+            .with_precedence(self.m_precedence.clone())
+            .with_rule_limits(self.m_rule_limits);
+        let pattern = format!("{}/*.rules", self.m_config_dir);
+        let loaded_rules = rules::parse_rules_with_includes(
+            &self.m_config_dir,
+            file::read_files_in_dir_with_paths(&pattern)?,
+        )?;
+        rules::check_rule_names(&loaded_rules)?;
+        let ruleset = RuleSet::new(parser, loaded_rules, self.m_tag_load_options.case_folding)?
+            .with_mode(self.m_options.mode);
+
+        // Parsing, required tag checks and storage
+        let mut loaded_objects: Vec<LoadedObject> = Vec::new();
+        let mut skipped_objects = 0;
+        let case_folding = self.m_tag_load_options.case_folding;
+        for loaded in
+            objects::parse_objects(&format!("{}/*.yaml", self.m_config_dir), case_folding)?
+        {
+            match objects::check_required_tags(
+                &loaded.object,
+                &tag_constraints,
+                case_folding,
+                self.m_options.required_tags,
+            ) {
+                Ok(true) => loaded_objects.push(loaded),
+                // Objects without their required tags can't match anything
+                Ok(false) => skipped_objects += 1,
+                Err(e) => {
+                    return Err(RulesError::ObjectParseError(format!(
+                        "{}: object {} of type '{}': {}",
+                        loaded.file, loaded.index, loaded.obj_type, e
+                    )));
+                }
+            }
+        }
+
+        self.m_stats = EngineStats {
+            tag_count: tags.len(),
+            subrule_count: ruleset.subrules().len(),
+            object_count: loaded_objects.len(),
+            skipped_objects,
+            loads: self.m_stats.loads + 1,
+            last_load_at: Some(audit::unix_timestamp()),
+        };
+        self.m_tags = tags;
+        self.m_tag_constraints = tag_constraints;
+        self.m_ruleset = ruleset;
+        self.m_objects = loaded_objects;
+
+        Ok(())
+    }
+
+    pub fn config_dir(&self) -> &str {
+        &self.m_config_dir
+    }
+
+    pub fn options(&self) -> EvaluationOptions {
+        self.m_options
+    }
+
+    // Loaded tags and their values, keyed by name
+    pub fn tags(&self) -> &HashMap<TagName, TagValues> {
+        &self.m_tags
+    }
+
+    pub fn tag_constraints(&self) -> &TagConstraintsMap {
+        &self.m_tag_constraints
+    }
+
+    // The rules which aren't drafts, split into subrules numbered highest priority first
+    pub fn subrules(&self) -> &HashMap<SubRuleNumber, SubRule> {
+        self.m_ruleset.subrules()
+    }
+
+    // Subrules by the tag an object needs for them to match
+    pub fn subrule_index(&self) -> &SubRuleIndex {
+        self.m_ruleset.subrule_index()
+    }
+
+    // The loaded rules, compiled for evaluating objects
+    pub fn ruleset(&self) -> &RuleSet {
+        &self.m_ruleset
+    }
+
+    // Objects to evaluate, in file order
+    pub fn objects(&self) -> &[LoadedObject] {
+        &self.m_objects
+    }
+
+    pub fn stats(&self) -> EngineStats {
+        self.m_stats.clone()
+    }
+
+    // Rules which aren't drafts, highest priority first, then in file then line order
+    pub fn rules(&self) -> &[LoadedRule] {
+        self.m_ruleset.rules()
+    }
+
+    /// Finds the loaded rules an object satisfies, without reading any files.
+    ///
+    /// An object missing a required tag matches no rules, or is an error,
+    /// per the engine's [`RequiredTagPolicy`](crate::types::RequiredTagPolicy).
+    /// Any other object is validated against the loaded tags first. Rules
+    /// are matched as they stood at the last load, and only those in effect
+    /// now. Only subrules under a tag the object has, or which need no tag,
+    /// are checked.
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * `Ok(Vec<MatchedRule>)` with every matching rule, highest priority first, then in file
+    ///   then line order
+    /// * `Err(RulesError)` if the object is invalid, or a rule uses a `$ctx` variable
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let mut engine = Engine::new("config");
+    /// engine.load()?;
+    /// for payload in requests {
+    ///     let matched = engine.evaluate_object(&payload)?;
+    /// }
+    /// ```
+    pub fn evaluate_object(&self, obj: &Object) -> Result<Vec<MatchedRule>, RulesError> {
+        if !objects::check_required_tags(
+            obj,
+            &self.m_tag_constraints,
+            self.m_tag_load_options.case_folding,
+            self.m_options.required_tags,
+        )? {
+            return Ok(Vec::new());
+        }
+        self.m_ruleset.evaluate(obj)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{EvalContext, EvalMode, RequiredTagPolicy};
+    use std::fs;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour (required): red, blue\n- size: small, large",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/test.rules", test_dir),
+            "- colour = red\n\n@draft trial\n- size = large\n",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/test.yaml", test_dir),
+            "objects:\n  shapes:\n    - colour: red\n      size: small\n    - size: large\n",
+        )
+        .unwrap();
+
+        test_dir
+    }
+
+    #[test]
+    fn test_engine_load() {
+        let test_dir = setup_test_env("test_engine_load");
+
+        let mut engine = Engine::new(&test_dir);
+        assert_eq!(engine.stats(), EngineStats::default());
+        engine.load().unwrap();
+
+        // The object without a colour can't match anything, so isn't kept
+        assert_eq!(engine.tags().len(), 2);
+        assert!(engine.tag_constraints()["colour"].required);
+        assert_eq!(engine.objects().len(), 1);
+        let stats = engine.stats();
+        assert_eq!(stats.object_count, 1);
+        assert_eq!(stats.skipped_objects, 1);
+        assert_eq!(stats.loads, 1);
+        assert!(stats.last_load_at.is_some());
+        assert_eq!(
+            stats.to_string(),
+            format!(
+                "2 tags, {} subrules, 1 objects (1 skipped for missing required tags)",
+                stats.subrule_count
+            )
+        );
+
+        // A failed load keeps what was loaded before
+        fs::write(format!("{}/test.rules", test_dir), "- colour = green").unwrap();
+        assert!(engine.load().is_err());
+        assert_eq!(engine.tags().len(), 2);
+        assert_eq!(engine.stats().loads, 1);
+
+        let mut strict = Engine::new(&test_dir).with_options(EvaluationOptions {
+            required_tags: RequiredTagPolicy::Error,
+            ..Default::default()
+        });
+        fs::write(format!("{}/test.rules", test_dir), "- colour = red").unwrap();
+        assert!(matches!(
+            strict.load(),
+            Err(RulesError::ObjectParseError(_))
+        ));
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[test]
+    fn test_engine_evaluate_object() {
+        let test_dir = setup_test_env("test_engine_evaluate_object");
+
+        let mut engine = Engine::new(&test_dir);
+        engine.load().unwrap();
+        assert_eq!(engine.rules().len(), 1);
+
+        // Drafts don't match, and the rules files aren't read again
+        let obj = HashMap::from([
+            ("colour".to_string(), vec!["red".to_string()]),
+            ("size".to_string(), vec!["large".to_string()]),
+        ]);
+        fs::remove_file(format!("{}/test.rules", test_dir)).unwrap();
+        let matched = engine.evaluate_object(&obj).unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].line, 1);
+        assert_eq!(
+            matched[0].id.to_string(),
+            engine.rules()[0].id().to_string()
+        );
+
+        // Objects missing a required tag match nothing, or are an error, per the policy
+        let missing_colour = HashMap::from([("size".to_string(), vec!["large".to_string()])]);
+        assert!(engine.evaluate_object(&missing_colour).unwrap().is_empty());
+
+        fs::write(format!("{}/test.rules", test_dir), "- size = large").unwrap();
+        let mut strict = Engine::new(&test_dir).with_options(EvaluationOptions {
+            required_tags: RequiredTagPolicy::Error,
+            ..Default::default()
+        });
+        fs::remove_file(format!("{}/test.yaml", test_dir)).unwrap();
+        strict.load().unwrap();
+        assert!(matches!(
+            strict.evaluate_object(&missing_colour),
+            Err(RulesError::ObjectParseError(_))
+        ));
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[test]
+    fn test_engine_subrule_index() {
+        let test_dir = setup_test_env("test_engine_subrule_index");
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour: red, blue\n- size: small < large\n- shape: circle, square",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/test.rules", test_dir),
+            "- (colour = red, blue) & shape ! circle\n- shape ! square\n- size > small | colour = red\n",
+        )
+        .unwrap();
+
+        let mut engine = Engine::new(&test_dir);
+        engine.load().unwrap();
+        assert_eq!(engine.stats().subrule_count, 5);
+        assert_eq!(engine.subrule_index().subrules_for("colour"), &[0, 1, 4]);
+        assert_eq!(engine.subrule_index().unindexed(), &[2]);
+
+        // Only candidate subrules are checked, matching the same rules as checking every rule
+        let parser = RuleParser::new(engine.tags().clone())
+            .with_tag_constraints(engine.tag_constraints().clone());
+        let objects: Vec<Object> = vec![
+            HashMap::new(),
+            HashMap::from([("shape".to_string(), vec!["square".to_string()])]),
+            HashMap::from([
+                ("colour".to_string(), vec!["blue".to_string()]),
+                ("size".to_string(), vec!["large".to_string()]),
+            ]),
+            HashMap::from([
+                ("colour".to_string(), vec!["red".to_string()]),
+                ("shape".to_string(), vec!["circle".to_string()]),
+            ]),
+        ];
+        for obj in &objects {
+            let expected: Vec<usize> = parser
+                .matching_rules(
+                    engine.rules(),
+                    obj,
+                    &EvalContext::default(),
+                    0,
+                    EvalMode::AllMatches,
+                )
+                .unwrap()
+                .iter()
+                .map(|loaded| loaded.line)
+                .collect();
+            let lines: Vec<usize> = engine
+                .evaluate_object(obj)
+                .unwrap()
+                .iter()
+                .map(|matched| matched.line)
+                .collect();
+            assert_eq!(lines, expected, "{:?}", obj);
+        }
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
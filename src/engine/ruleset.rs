@@ -0,0 +1,258 @@
+// Rules parsed and converted to DNF subrules once, for evaluating many objects without reading
+// any files -- e.g. rules.compile()?, then ruleset.evaluate(&obj) per object
+use crate::api::audit;
+use crate::engine::SubRuleIndex;
+use crate::err::RulesError;
+use crate::parser::rules::{self, RuleParser};
+use crate::types::{
+    self, CaseFolding, EvalContext, EvalMode, LoadedRule, MatchedRule, MissingTagPolicy, Object,
+    SubRule, SubRuleNumber,
+};
+use crate::utils::metrics;
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+pub struct RuleSet {
+    // Parser for the tags the rules were compiled against, which evaluates objects
+    m_parser: RuleParser,
+    // Every rule which isn't a draft, highest priority first, then in file then line order
+    m_rules: Vec<LoadedRule>,
+    // Positions in m_rules of the rules in the order they were loaded, file then line
+    m_file_order: Vec<usize>,
+    m_subrules: HashMap<SubRuleNumber, SubRule>,
+    m_subrule_index: SubRuleIndex,
+    m_mode: EvalMode,
+}
+
+impl RuleSet {
+    // Compile `rules` with `parser`, leaving out drafts. Errors name the invalid rule's file and
+    // line -- e.g. "my_rules.rules:3: ..."
+    pub(crate) fn new(
+        parser: RuleParser,
+        mut rules: Vec<LoadedRule>,
+        case_folding: CaseFolding,
+    ) -> Result<Self, RulesError> {
+        rules.retain(|loaded| !loaded.is_draft());
+        let mut by_priority: Vec<usize> = (0..rules.len()).collect();
+        by_priority.sort_by(|a, b| rules::priority_order(&rules[*a], &rules[*b]));
+        let mut file_order = vec![0; rules.len()];
+        for (position, loaded_at) in by_priority.iter().enumerate() {
+            file_order[*loaded_at] = position;
+        }
+        rules::sort_by_priority(&mut rules);
+        let subrules: HashMap<SubRuleNumber, SubRule> = parser
+            .parse_rules(&rules)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, subrule)| (i as SubRuleNumber, subrule))
+            .collect();
+        let subrule_index = SubRuleIndex::new(&subrules, case_folding);
+
+        Ok(RuleSet {
+            m_parser: parser,
+            m_rules: rules,
+            m_file_order: file_order,
+            m_subrules: subrules,
+            m_subrule_index: subrule_index,
+            m_mode: EvalMode::default(),
+        })
+    }
+
+    // Whether evaluation stops at the first matching rule -- e.g. EvalMode::FirstMatch
+    pub fn with_mode(mut self, mode: EvalMode) -> Self {
+        self.m_mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> EvalMode {
+        self.m_mode
+    }
+
+    // Rules which aren't drafts, highest priority first, then in file then line order
+    pub fn rules(&self) -> &[LoadedRule] {
+        &self.m_rules
+    }
+
+    // Positions in rules() of the rules in file then line order, as they were loaded
+    pub(crate) fn file_order(&self) -> &[usize] {
+        &self.m_file_order
+    }
+
+    // The rules split into subrules numbered in the rules' order
+    pub fn subrules(&self) -> &HashMap<SubRuleNumber, SubRule> {
+        &self.m_subrules
+    }
+
+    // Subrules by the tag an object needs for them to match
+    pub fn subrule_index(&self) -> &SubRuleIndex {
+        &self.m_subrule_index
+    }
+
+    /// Finds the compiled rules an object satisfies, without reading any files.
+    ///
+    /// The object is validated against the tags the rules were compiled
+    /// against first. Only rules in effect now are matched, and only
+    /// subrules under a tag the object has, or which need no tag, are checked,
+    /// unless [`MissingTagPolicy::SkipClause`] or [`MissingTagPolicy::Error`]
+    /// needs every subrule checked.
+    /// Under [`EvalMode::FirstMatch`], only the highest priority matching rule
+    /// is returned.
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * `Ok(Vec<MatchedRule>)` with every matching rule, highest priority first, then in file
+    ///   then line order
+    /// * `Err(RulesError)` if the object is invalid, a rule uses a `$ctx` variable, or under
+    ///   [`MissingTagPolicy::Error`] a rule compares a tag the object lacks
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let ruleset = rules.compile()?;
+    /// for obj in &objects {
+    ///     let matched = ruleset.evaluate(obj)?;
+    /// }
+    /// ```
+    pub fn evaluate(&self, obj: &Object) -> Result<Vec<MatchedRule>, RulesError> {
+        self.evaluate_with_context(obj, &EvalContext::default())
+    }
+
+    /// Finds the compiled rules an object satisfies, with `$ctx` variables in
+    /// rules filled in from an evaluation context, as for
+    /// [`crate::Rules::evaluate_with_context`].
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    /// * `context` - Values for the `$ctx` variables
+    ///
+    /// # Returns
+    /// * `Ok(Vec<MatchedRule>)` with every matching rule, highest priority first, then in file
+    ///   then line order
+    /// * `Err(RulesError)` if the object is invalid, or a subrule checked uses a variable the
+    ///   context has no value for
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let context = EvalContext::new().with("region", "eu");
+    /// let matched = ruleset.evaluate_with_context(&obj, &context)?;
+    /// ```
+    pub fn evaluate_with_context(
+        &self,
+        obj: &Object,
+        context: &EvalContext,
+    ) -> Result<Vec<MatchedRule>, RulesError> {
+        self.m_parser.validate_object(obj)?;
+        let matched = self.matching_rules_at(obj, context, audit::unix_timestamp())?;
+        Ok(matched.into_iter().map(MatchedRule::from).collect())
+    }
+
+    // As evaluate, with the rules as loaded -- e.g. for the daemon, which answers with them
+    #[cfg(feature = "watch")]
+    pub(crate) fn matching_rules(&self, obj: &Object) -> Result<Vec<LoadedRule>, RulesError> {
+        self.m_parser.validate_object(obj)?;
+        self.matching_rules_at(obj, &EvalContext::default(), audit::unix_timestamp())
+    }
+
+    // Rules `obj` matches at `timestamp`, highest priority first, for an object already
+    // validated against the tags the rules were compiled against
+    pub(crate) fn matching_rules_at(
+        &self,
+        obj: &Object,
+        context: &EvalContext,
+        timestamp: u64,
+    ) -> Result<Vec<LoadedRule>, RulesError> {
+        let started = Instant::now();
+        let matched: Vec<LoadedRule> = self
+            .evaluated_rules(obj, context, timestamp)?
+            .into_iter()
+            .filter(|(_, is_match)| *is_match)
+            .map(|(rule_index, _)| self.m_rules[rule_index].clone())
+            .collect();
+        metrics::record_evaluation(self.m_rules.len(), &matched, started.elapsed());
+
+        Ok(matched)
+    }
+
+    // Positions in rules() of the rules with a subrule checked against `obj` at `timestamp`, with
+    // whether the rule matched, highest priority first -- e.g. for counting how often each rule
+    // is evaluated. Rules the index rules out for `obj` aren't checked.
+    pub(crate) fn evaluated_rules(
+        &self,
+        obj: &Object,
+        context: &EvalContext,
+        timestamp: u64,
+    ) -> Result<Vec<(usize, bool)>, RulesError> {
+        let mut evaluated: BTreeMap<usize, bool> = BTreeMap::new();
+
+        // The index leaves out subrules comparing tags the object lacks, which only fail under
+        // the default and FailClause policies
+        let numbers: Vec<SubRuleNumber> = match self.m_parser.missing_tag_policy() {
+            MissingTagPolicy::NoValues | MissingTagPolicy::FailClause => {
+                self.m_subrule_index.candidates(obj)
+            }
+            MissingTagPolicy::SkipClause | MissingTagPolicy::Error => {
+                let mut numbers: Vec<SubRuleNumber> = self.m_subrules.keys().copied().collect();
+                numbers.sort_unstable();
+                numbers
+            }
+        };
+
+        for number in numbers {
+            let subrule = &self.m_subrules[&number];
+            let loaded = &self.m_rules[subrule.rule_index];
+            if evaluated.get(&subrule.rule_index) == Some(&true) || !loaded.in_effect_at(timestamp)
+            {
+                continue;
+            }
+
+            let is_match = self
+                .m_parser
+                .subrule_matches(subrule, obj, context, timestamp)
+                .map_err(|e| rules::rule_error_at(loaded, e))?;
+            evaluated.insert(subrule.rule_index, is_match);
+            // Subrules are numbered in priority order, so this is the first matching rule
+            if is_match && self.m_mode == EvalMode::FirstMatch {
+                break;
+            }
+        }
+
+        Ok(evaluated.into_iter().collect())
+    }
+
+    /// Scores an object by the compiled rules it satisfies, as for
+    /// [`crate::Rules::score`].
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * `Ok(f64)` with the sum of the weights of the matching rules, 0 if none match
+    /// * `Err(RulesError)` if the object is invalid, or a rule uses a `$ctx` variable
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let ruleset = rules.compile()?;
+    /// let risk = ruleset.score(&obj)?;
+    /// ```
+    pub fn score(&self, obj: &Object) -> Result<f64, RulesError> {
+        let matched = self.evaluate(obj)?;
+        Ok(types::total_weight(
+            matched.iter().map(|matched| matched.weight),
+        ))
+    }
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        RuleSet {
+            m_parser: RuleParser::new(HashMap::new()),
+            m_rules: Vec::new(),
+            m_file_order: Vec::new(),
+            m_subrules: HashMap::new(),
+            m_subrule_index: SubRuleIndex::default(),
+            m_mode: EvalMode::default(),
+        }
+    }
+}
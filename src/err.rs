@@ -1,5 +1,8 @@
 // Errors used across the codebase
 
+use crate::types::{
+    Diagnostic, DiagnosticCode, DiagnosticSeverity, DiagnosticSpan, ValidationReport,
+};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -22,4 +25,315 @@ pub enum RulesError {
 
     #[error("Error parsing Object: {0}")]
     ObjectParseError(String),
+
+    // A rule beyond the parser's RuleLimits, rejected before it's read into a syntax tree
+    #[error("Rule exceeds limit: {0}")]
+    RuleLimitError(String),
+
+    // Every failure found when checking a whole set of rules, not just the first
+    #[error("Error validating Rules: {0}")]
+    ValidationError(ValidationReport),
+}
+
+// Put after a rule error's message by the rule parser, giving the column of the part of the rule
+// it's about -- e.g. "Expected a tag name, found '&' at column 12"
+pub const COLUMN_SUFFIX: &str = " at column ";
+
+// Where an error is in a config file, counting lines and columns from 1 -- e.g. line 3, column 12
+// of my_rules.rules for "my_rules.rules:3:12: ...". Columns of rule errors count from the start
+// of the rule, after any indentation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: Option<usize>,
+}
+
+// A message without its COLUMN_SUFFIX, and the column -- e.g. ("Unmatched opening parenthesis",
+// 2) for "Unmatched opening parenthesis at column 2"
+pub fn split_column(message: &str) -> Option<(&str, usize)> {
+    let (message, column) = message.rsplit_once(COLUMN_SUFFIX)?;
+    Some((message, column.parse().ok()?))
+}
+
+// Start of a tag error about one line, before the line -- e.g. "Errors parsing line: '- colour
+// red': Tag must contain a ':' separator"
+const TAG_LINE_PREFIX: &str = "Errors parsing line: ";
+
+// Code of each kind of rule error, by what its message contains, checked in order -- e.g.
+// "Rule compares TagName 'size' with unknown TagName 'sise'" is UnknownTag, not
+// InvalidComparison. Every message a rule or tag can be rejected with is matched by a test.
+const RULE_CODES: &[(DiagnosticCode, &[&str])] = &[
+    (
+        DiagnosticCode::UnmatchedParen,
+        &[
+            "Unmatched opening parenthesis",
+            "Unmatched closing parenthesis",
+        ],
+    ),
+    (
+        DiagnosticCode::IncompleteRule,
+        &["Rule ends after", "Empty rule"],
+    ),
+    (
+        DiagnosticCode::UnexpectedToken,
+        &[
+            "Expected '&', '|' or",
+            "Expected a tag name, found",
+            "Expected a comparison after",
+            "Expected a tag value after",
+            "Comma must follow a complete tag comparison",
+            "'?' takes no value",
+            "'!' before a tag name",
+            "Rule is missing initial dash",
+            "Rule should only contain 1 dash",
+            "Invalid comparison operator",
+            "Unterminated quoted value",
+            "Quoted value must not be blank",
+            "Operator precedence has no level",
+        ],
+    ),
+    (
+        DiagnosticCode::UnknownTag,
+        &[
+            "Rule contains invalid TagName",
+            "with unknown TagName",
+            "No TagName",
+            "has no associated TagName",
+        ],
+    ),
+    (
+        DiagnosticCode::UnknownValue,
+        &["Rule contains invalid TagValue"],
+    ),
+    (DiagnosticCode::InvalidRuleName, &["Invalid rule name"]),
+    (DiagnosticCode::DuplicateRuleName, &["is already used at"]),
+    (
+        DiagnosticCode::InvalidAnnotation,
+        &[
+            "Invalid annotation",
+            "Annotation isn't followed by a rule",
+            "Invalid weight for @",
+            "Invalid severity for @",
+            "Invalid timestamp for @",
+            "Invalid name for @",
+            "Rule expires before it takes effect",
+        ],
+    ),
+    (
+        DiagnosticCode::InvalidMacro,
+        &["Invalid macro", "Unknown macro", "Macro "],
+    ),
+    (
+        DiagnosticCode::InvalidInclude,
+        &[
+            "Invalid include",
+            "Include cycle",
+            "Included file not found",
+        ],
+    ),
+    (
+        DiagnosticCode::InvalidCall,
+        &[
+            "unknown function",
+            "Invalid function call",
+            "Invalid arguments to",
+        ],
+    ),
+    (
+        DiagnosticCode::InvalidComparison,
+        &[
+            "Rule compares TagName",
+            "Range '",
+            "Invalid range",
+            "Invalid regex",
+            "Can't negate",
+        ],
+    ),
+    (
+        DiagnosticCode::UnknownVariable,
+        &["Unknown variable", "No value for"],
+    ),
+    (
+        DiagnosticCode::RuleTooLarge,
+        &["Rule has more than", "Rule is too large"],
+    ),
+    (
+        DiagnosticCode::InvalidPrefix,
+        &["Invalid priority", "Invalid outcome"],
+    ),
+    (DiagnosticCode::Unsatisfiable, &["Rule can never match"]),
+    (
+        DiagnosticCode::DuplicateRule,
+        &["Rule already exists in file"],
+    ),
+];
+
+// Code of each kind of tag error, as for RULE_CODES
+const TAG_CODES: &[(DiagnosticCode, &[&str])] = &[
+    (
+        DiagnosticCode::MissingSeparator,
+        &[
+            "Tag must contain a ':' separator",
+            "Tag must only contain one name",
+        ],
+    ),
+    (DiagnosticCode::MissingDash, &["Tag must begin with '-'"]),
+    (DiagnosticCode::DuplicateTag, &["is defined more than once"]),
+    (DiagnosticCode::InvalidExclusiveGroup, &["Exclusive group"]),
+    (
+        DiagnosticCode::TagLimitExceeded,
+        &[
+            "more than its limit",
+            "longer than its limit",
+            "Tags exceed the limit",
+        ],
+    ),
+    (
+        DiagnosticCode::InvalidPattern,
+        &["Invalid tag pattern", "Tag pattern cannot be empty"],
+    ),
+    (
+        DiagnosticCode::InvalidAttribute,
+        &[
+            "Tag attribute",
+            "Unknown tag attribute",
+            "both open and closed",
+            "Flag tags",
+            "Ordered tag",
+        ],
+    ),
+    (DiagnosticCode::InvalidTagName, &["Tag name cannot"]),
+    (DiagnosticCode::InvalidTagValue, &["Tag values"]),
+];
+
+// First code whose messages `message` contains, or `fallback`
+fn code_for(
+    codes: &[(DiagnosticCode, &[&str])],
+    message: &str,
+    fallback: DiagnosticCode,
+) -> DiagnosticCode {
+    codes
+        .iter()
+        .find(|(_, needles)| needles.iter().any(|needle| message.contains(needle)))
+        .map_or(fallback, |(code, _)| *code)
+}
+
+impl RulesError {
+    fn message(&self) -> Option<&str> {
+        match self {
+            RulesError::TagParseError(msg)
+            | RulesError::RuleParseError(msg)
+            | RulesError::ObjectParseError(msg)
+            | RulesError::RuleLimitError(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    // The error with its message rewritten by `f` if it's about a rule, keeping its variant --
+    // e.g. to put the rule's file and line before it
+    pub fn map_rule_message(self, f: impl FnOnce(String) -> String) -> RulesError {
+        match self {
+            RulesError::RuleParseError(msg) => RulesError::RuleParseError(f(msg)),
+            RulesError::RuleLimitError(msg) => RulesError::RuleLimitError(f(msg)),
+            other => other,
+        }
+    }
+
+    // The file, line and any column an error starts with, for errors about a line of a config
+    // file -- e.g. "my_rules.rules:3:12 (promo_1): ..." or "my_rules.tags:2: ..."
+    pub fn location(&self) -> Option<ErrorLocation> {
+        let message = self.message()?;
+        let (position, _) = message.split_once(": ")?;
+        // Without the name of a named rule -- e.g. " (promo_1)"
+        let position = position.split(" (").next().unwrap_or(position);
+
+        let (rest, last) = position.rsplit_once(':')?;
+        let last: usize = last.parse().ok()?;
+        if let Some((file, line)) = rest.rsplit_once(':')
+            && let Ok(line) = line.parse()
+            && !file.is_empty()
+        {
+            return Some(ErrorLocation {
+                file: file.to_string(),
+                line,
+                column: Some(last),
+            });
+        }
+
+        (!rest.is_empty()).then(|| ErrorLocation {
+            file: rest.to_string(),
+            line: last,
+            column: split_column(message).map(|(_, column)| column),
+        })
+    }
+
+    // The message without its location, the rule or tag line it's about, or its column -- e.g.
+    // "Expected a tag name, found '&'" for "a.rules:2:12: '- colour = &': Expected a tag name,
+    // found '&'"
+    fn message_body(&self) -> Option<&str> {
+        let mut body = self.message()?;
+        if self.location().is_some() {
+            body = body.split_once(": ").map_or(body, |(_, rest)| rest);
+        }
+        body = body.strip_prefix(TAG_LINE_PREFIX).unwrap_or(body);
+        if body.starts_with('\'')
+            && let Some((_, rest)) = body.split_once("': ")
+        {
+            body = rest;
+        }
+        Some(split_column(body).map_or(body, |(body, _)| body))
+    }
+
+    // Stable code of the kind of error this is -- e.g. DiagnosticCode::UnmatchedParen. A report
+    // of several errors has the code of the first.
+    pub fn code(&self) -> DiagnosticCode {
+        match self {
+            RulesError::IoError(_) | RulesError::GlobPatternError(_) | RulesError::GlobError(_) => {
+                DiagnosticCode::FileError
+            }
+            RulesError::RuleParseError(_) => code_for(
+                RULE_CODES,
+                self.message_body().unwrap_or_default(),
+                DiagnosticCode::InvalidRule,
+            ),
+            RulesError::TagParseError(_) => code_for(
+                TAG_CODES,
+                self.message_body().unwrap_or_default(),
+                DiagnosticCode::InvalidTag,
+            ),
+            RulesError::ObjectParseError(_) => DiagnosticCode::InvalidObject,
+            RulesError::RuleLimitError(_) => DiagnosticCode::RuleTooLarge,
+            RulesError::ValidationError(report) => report
+                .failures
+                .first()
+                .map_or(DiagnosticCode::InvalidRule, |failure| failure.error.code()),
+        }
+    }
+
+    // The error as a diagnostic, with its code and where it is
+    pub fn diagnostic(&self) -> Diagnostic {
+        let location = self.location();
+        Diagnostic {
+            code: self.code(),
+            severity: DiagnosticSeverity::Error,
+            message: self
+                .message_body()
+                .map_or_else(|| self.to_string(), str::to_string),
+            span: DiagnosticSpan {
+                file: location.as_ref().map(|location| location.file.clone()),
+                line: location.as_ref().map(|location| location.line),
+                column: self.column(),
+            },
+        }
+    }
+
+    // The column of the part of a rule an error is about, whether or not it's located in a
+    // file -- e.g. 12 for "'- colour = & size = large': ... at column 12"
+    pub fn column(&self) -> Option<usize> {
+        match self.location() {
+            Some(location) => location.column,
+            None => split_column(self.message()?).map(|(_, column)| column),
+        }
+    }
 }
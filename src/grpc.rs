@@ -0,0 +1,503 @@
+// gRPC service exposing the engine -- `rules grpc`, API in proto/rules/v1/rules.proto
+use crate::Rules;
+use crate::err::RulesError;
+use crate::types::{LoadedRule, Object};
+
+use proto::rules_service_server::{RulesService, RulesServiceServer};
+use proto::{EvaluateRequest, EvaluateResponse, MatchedRule, ValidateRequest, ValidateResponse};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:50051";
+
+// Responses buffered per stream before the server waits on the client
+const STREAM_BUFFER: usize = 64;
+
+// Messages of the rules.v1 package. Field numbers must match proto/rules/v1/rules.proto.
+pub mod proto {
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TagValues {
+        #[prost(string, repeated, tag = "1")]
+        pub values: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct EvaluateRequest {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(map = "string, message", tag = "2")]
+        pub object: HashMap<String, TagValues>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct MatchedRule {
+        #[prost(string, tag = "1")]
+        pub file: String,
+        #[prost(uint32, tag = "2")]
+        pub line: u32,
+        #[prost(string, tag = "3")]
+        pub rule: String,
+        #[prost(string, tag = "4")]
+        pub id: String,
+        #[prost(string, tag = "5")]
+        pub outcome: String,
+        #[prost(string, tag = "6")]
+        pub name: String,
+        #[prost(string, tag = "7")]
+        pub description: String,
+        #[prost(string, tag = "8")]
+        pub severity: String,
+        #[prost(string, tag = "9")]
+        pub owner: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct EvaluateResponse {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(message, repeated, tag = "2")]
+        pub matched: Vec<MatchedRule>,
+        #[prost(string, tag = "3")]
+        pub error: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ValidateRequest {
+        #[prost(string, tag = "1")]
+        pub rule: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ValidateResponse {
+        #[prost(bool, tag = "1")]
+        pub valid: bool,
+        #[prost(string, tag = "2")]
+        pub error: String,
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/rules.v1.RulesService.rs"));
+}
+
+pub struct RulesGrpc {
+    m_rules: Arc<RwLock<Rules>>,
+}
+
+type EvaluateStream = Pin<Box<dyn Stream<Item = Result<EvaluateResponse, Status>> + Send>>;
+
+impl From<LoadedRule> for MatchedRule {
+    fn from(loaded: LoadedRule) -> Self {
+        let meta = loaded.meta();
+        MatchedRule {
+            id: loaded.id().to_string(),
+            outcome: loaded.outcome().unwrap_or_default().to_string(),
+            name: loaded.name().unwrap_or_default().to_string(),
+            description: meta.description.unwrap_or_default(),
+            severity: meta
+                .severity
+                .map(|s| s.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            owner: meta.owner.unwrap_or_default(),
+            file: loaded.file,
+            line: loaded.line as u32,
+            rule: loaded.rule,
+        }
+    }
+}
+
+// Config problems are the caller's, IO problems are ours
+fn to_status(error: RulesError) -> Status {
+    match error {
+        RulesError::TagParseError(_)
+        | RulesError::RuleParseError(_)
+        | RulesError::ObjectParseError(_)
+        | RulesError::RuleLimitError(_) => Status::invalid_argument(error.to_string()),
+        _ => Status::internal(error.to_string()),
+    }
+}
+
+// A panicking handler can't leave Rules half-updated, so a poisoned lock is still usable
+fn read(rules: &RwLock<Rules>) -> RwLockReadGuard<'_, Rules> {
+    rules.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn evaluate_request(
+    rules: &Rules,
+    request: EvaluateRequest,
+) -> Result<EvaluateResponse, RulesError> {
+    let obj: Object = request
+        .object
+        .into_iter()
+        .map(|(tag_name, values)| (tag_name, values.values))
+        .collect();
+    let matched = rules.matching_rules(&obj)?;
+
+    Ok(EvaluateResponse {
+        id: request.id,
+        matched: matched.into_iter().map(MatchedRule::from).collect(),
+        error: String::new(),
+    })
+}
+
+impl RulesGrpc {
+    pub fn new(rules: Rules) -> Self {
+        RulesGrpc {
+            m_rules: Arc::new(RwLock::new(rules)),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl RulesService for RulesGrpc {
+    async fn evaluate(
+        &self,
+        request: Request<EvaluateRequest>,
+    ) -> Result<Response<EvaluateResponse>, Status> {
+        evaluate_request(&read(&self.m_rules), request.into_inner())
+            .map(Response::new)
+            .map_err(to_status)
+    }
+
+    async fn validate(
+        &self,
+        request: Request<ValidateRequest>,
+    ) -> Result<Response<ValidateResponse>, Status> {
+        let response = match read(&self.m_rules).validate_rule(&request.into_inner().rule) {
+            Ok(()) => ValidateResponse {
+                valid: true,
+                error: String::new(),
+            },
+            Err(e) => ValidateResponse {
+                valid: false,
+                error: e.to_string(),
+            },
+        };
+
+        Ok(Response::new(response))
+    }
+
+    type StreamEvaluateStream = EvaluateStream;
+
+    async fn stream_evaluate(
+        &self,
+        request: Request<Streaming<EvaluateRequest>>,
+    ) -> Result<Response<Self::StreamEvaluateStream>, Status> {
+        let mut inbound = request.into_inner();
+        let rules = Arc::clone(&self.m_rules);
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            while let Some(message) = inbound.next().await {
+                let response = message.map(|request| {
+                    let id = request.id.clone();
+                    evaluate_request(&read(&rules), request).unwrap_or_else(|e| EvaluateResponse {
+                        id,
+                        error: e.to_string(),
+                        ..Default::default()
+                    })
+                });
+
+                // Client hung up
+                if tx.send(response).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
+
+// Load the config in `config_dir` and serve it on `addr` until the process is stopped
+pub fn serve(config_dir: &str, addr: &str) -> Result<(), RulesError> {
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", addr, e)))?;
+
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        Server::builder()
+            .add_service(RulesServiceServer::new(RulesGrpc::new(rules)))
+            .serve(addr)
+            .await
+            .map_err(io::Error::other)
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::TagValues;
+    use proto::rules_service_client::RulesServiceClient;
+    use std::collections::HashMap;
+    use std::fs;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::codec::{Codec, EncodeBody, ProstCodec};
+    use tonic::transport::Channel;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour: red, blue\n- size: small < medium < large",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/test.rules", test_dir),
+            "- colour = red & size > small\n- colour = blue",
+        )
+        .unwrap();
+
+        test_dir
+    }
+
+    async fn start_server(test_dir: &str) -> RulesServiceClient<Channel> {
+        let mut rules = Rules::new(test_dir);
+        rules.load_tags().unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(RulesServiceServer::new(RulesGrpc::new(rules)))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        RulesServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap()
+    }
+
+    fn service(test_dir: &str) -> RulesGrpc {
+        let mut rules = Rules::new(test_dir);
+        rules.load_tags().unwrap();
+        RulesGrpc::new(rules)
+    }
+
+    // Requests encoded as a client sends them, for calling stream_evaluate without a connection
+    fn streaming(requests: Vec<EvaluateRequest>) -> Streaming<EvaluateRequest> {
+        let mut codec = ProstCodec::<EvaluateRequest, EvaluateRequest>::default();
+        let source = tokio_stream::iter(requests.into_iter().map(Ok));
+        let body = EncodeBody::new_client(codec.encoder(), source, None, None);
+        Streaming::new_request(codec.decoder(), body, None, None)
+    }
+
+    fn request(id: &str, tags: &[(&str, &str)]) -> EvaluateRequest {
+        let object: HashMap<String, TagValues> = tags
+            .iter()
+            .map(|(name, value)| {
+                let values = vec![value.to_string()];
+                (name.to_string(), TagValues { values })
+            })
+            .collect();
+
+        EvaluateRequest {
+            id: id.to_string(),
+            object,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_grpc_service() {
+        let test_dir = setup_test_env("test_grpc_service");
+        let mut client = start_server(&test_dir).await;
+
+        let response = client
+            .evaluate(request("a", &[("colour", "red"), ("size", "large")]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.id, "a");
+        assert_eq!(response.matched.len(), 1);
+        assert_eq!(response.matched[0].line, 1);
+
+        let status = client
+            .evaluate(request("b", &[("colour", "green")]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let response = client
+            .validate(ValidateRequest {
+                rule: "- size > huge".to_string(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!response.valid);
+        assert!(response.error.contains("huge"));
+
+        let outbound = tokio_stream::iter(vec![
+            request("1", &[("colour", "blue")]),
+            request("2", &[("colour", "green")]),
+            request("3", &[("colour", "red"), ("size", "small")]),
+        ]);
+        let responses: Vec<EvaluateResponse> = client
+            .stream_evaluate(outbound)
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        let ids: Vec<&str> = responses.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+        assert_eq!(responses[0].matched[0].rule, "- colour = blue");
+        assert!(responses[1].error.contains("green"));
+        assert!(responses[2].matched.is_empty());
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_evaluate() {
+        let test_dir = setup_test_env("test_grpc_evaluate");
+        let service = service(&test_dir);
+
+        let response = service
+            .evaluate(Request::new(request("a", &[("colour", "blue")])))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.id, "a");
+        assert_eq!(response.matched.len(), 1);
+        assert!(response.matched[0].file.ends_with("test.rules"));
+        assert_eq!(response.matched[0].line, 2);
+        assert_eq!(response.matched[0].rule, "- colour = blue");
+        assert!(response.error.is_empty());
+
+        let response = service
+            .evaluate(Request::new(request(
+                "b",
+                &[("colour", "red"), ("size", "small")],
+            )))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.matched.is_empty());
+
+        // Objects the tags reject are the caller's problem
+        let status = service
+            .evaluate(Request::new(request("c", &[("colour", "green")])))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("green"));
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_validate() {
+        let test_dir = setup_test_env("test_grpc_validate");
+        let service = service(&test_dir);
+        let validate = |rule: &str| {
+            let request = Request::new(ValidateRequest {
+                rule: rule.to_string(),
+            });
+            async { service.validate(request).await.unwrap().into_inner() }
+        };
+
+        let response = validate("- colour = red & size > small").await;
+        assert!(response.valid);
+        assert!(response.error.is_empty());
+
+        // Invalid rules are a response, not a failed call
+        let response = validate("- size > huge").await;
+        assert!(!response.valid);
+        assert!(response.error.contains("huge"));
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_stream_evaluate() {
+        let test_dir = setup_test_env("test_grpc_stream_evaluate");
+        let service = service(&test_dir);
+
+        let inbound = streaming(vec![
+            request("1", &[("colour", "red"), ("size", "large")]),
+            request("2", &[("colour", "green")]),
+            request("3", &[("colour", "blue")]),
+        ]);
+        let responses: Vec<EvaluateResponse> = service
+            .stream_evaluate(Request::new(inbound))
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        // A bad object fails its own response, not the stream
+        let ids: Vec<&str> = responses.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+        assert_eq!(responses[0].matched[0].line, 1);
+        assert!(responses[1].matched.is_empty());
+        assert!(responses[1].error.contains("green"));
+        assert_eq!(responses[2].matched[0].rule, "- colour = blue");
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[test]
+    fn test_grpc_to_status() {
+        let parse_errors = [
+            RulesError::TagParseError("bad tag".to_string()),
+            RulesError::RuleParseError("bad rule".to_string()),
+            RulesError::ObjectParseError("bad object".to_string()),
+            RulesError::RuleLimitError("too long".to_string()),
+        ];
+        for error in parse_errors {
+            let message = error.to_string();
+            let status = to_status(error);
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            assert_eq!(status.message(), message);
+        }
+
+        let error = RulesError::from(io::Error::new(io::ErrorKind::NotFound, "test.tags"));
+        let status = to_status(error);
+        assert_eq!(status.code(), tonic::Code::Internal);
+        assert!(status.message().contains("test.tags"));
+    }
+
+    #[tokio::test]
+    async fn test_grpc_required_tags() {
+        let test_dir = setup_test_env("test_grpc_required_tags");
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour (required): red, blue\n- size: small < medium < large",
+        )
+        .unwrap();
+        let mut client = start_server(&test_dir).await;
+
+        // Objects missing a required tag match nothing under the default policy
+        let response = client
+            .evaluate(request("a", &[("size", "large")]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.matched.is_empty());
+        assert!(response.error.is_empty());
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
@@ -1,8 +1,37 @@
 // Src files
+pub mod ast;
+pub mod engine;
 pub mod err;
 pub mod orchestrator;
+pub mod query;
+pub mod tenants;
+pub mod testing;
 pub mod types;
 
+#[cfg(feature = "server")]
+pub mod server;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(feature = "node")]
+pub mod node;
+
+#[cfg(feature = "lsp")]
+pub mod lsp;
+
+#[cfg(feature = "scripting")]
+pub mod scripting;
+
+#[cfg(feature = "remote")]
+pub mod remote;
+
+#[cfg(feature = "webhooks")]
+pub mod webhooks;
+
 // Internal impl directories
 // src/lib.rs
 
@@ -13,8 +42,33 @@ mod utils;
 // Re-export the main Rules struct
 pub use rules::Rules;
 
+// Re-export the per-tenant wrapper around Rules
+pub use tenants::Tenants;
+
 // Re-export error types for users to handle
-pub use err::RulesError;
+pub use err::{ErrorLocation, RulesError};
+
+// Re-export config options
+pub use types::{
+    CacheOptions, CacheStats, CaseFolding, DuplicateTagPolicy, EvalMode, MissingTagPolicy,
+    OperatorPrecedence, RequiredTagPolicy, RuleLimits, TagLimits, TagLoadOptions, UnknownPolicy,
+};
+
+// Re-export three-valued results returned by Rules::rule_truth
+pub use types::Truth;
+
+// Re-export the plugin trait for Rules::register_plugin
+pub use parser::plugin::RulePlugin;
+
+// Re-export write policy hooks for Rules::set_write_policy
+pub use api::policy::WritePolicy;
+pub use types::{WritePayload, WriteRequest};
+
+// Re-export diff types returned by Rules::changeset
+pub use types::{ChangeKind, ChangeSet, ObjectChange, RuleChange, TagChange};
+
+// Re-export shadow evaluation results returned by Rules::shadow_compare
+pub use types::{ShadowDivergence, ShadowReport};
 
 // Keep the lower-level API available for advanced users
 pub mod write {
@@ -25,4 +79,84 @@ pub mod write {
 
 pub use api::entry::evaluate;
 
+// Re-export per-object results from Rules::evaluate and evaluate
+pub use types::{EvaluationReport, ObjectMatches, RuleStats};
+
+// Re-export corpus coverage returned by Rules::rule_coverage
+pub use types::{ClauseHits, RuleCoverage, RuleHits};
+
+// Re-export the object identities passed to Rules::evaluate_with callbacks
+pub use types::ObjectId;
+
+// Re-export matches of in-memory objects from Rules::evaluate_object
+pub use types::MatchedRule;
+
+// Re-export rule metadata from LoadedRule::meta and MatchedRule::meta
+pub use types::{RuleMeta, Severity};
+
+// Re-export every failing rule line found by Rules::validate_rules
+pub use types::{RuleFailure, ValidationReport};
+
+// Re-export coded diagnostics from RulesError::diagnostic and Rules::tag_diagnostics
+pub use types::{Diagnostic, DiagnosticCode, DiagnosticSeverity, DiagnosticSpan};
+
+// Re-export lint results from Rules::lint
+pub use types::LintWarning;
+
+// Re-export overlapping rule pairs from Rules::rule_conflicts
+pub use types::RuleConflict;
+
+// Re-export duplicated rules from Rules::duplicate_rules and Rules::dedup_rules
+pub use types::RuleDuplicate;
+
+// Re-export conjunctive normal form from Rules::rule_to_cnf
+pub use types::CnfRule;
+
+// Re-export subsumed rules and conflicting outcomes from Rules::analyze
+pub use types::{RuleAnalysis, Subsumption};
+
+// Re-export uncovered tag combinations from Rules::coverage_gaps
+pub use types::CoverageReport;
+
+// Re-export "#test:" examples and their outcomes from Rules::run_inline_tests
+pub use types::{InlineTest, InlineTestResult};
+
+// Re-export generated objects from Rules::generate_examples
+pub use types::RuleExamples;
+
+// Re-export explanations of failed matches from Rules::why_not
+pub use types::{MatchExplanation, TagEdit};
+
+// Re-export node-by-node explanations from Rules::explain
+pub use types::ExplanationNode;
+
+// Re-export evaluation traces from Rules::trace
+pub use types::{EvaluationTrace, RuleTrace, TraceStep};
+
+// Re-export truth tables from Rules::truth_table
+pub use types::{TruthTable, TruthTableRow};
+
+// Re-export the variables passed to Rules::evaluate_with_context
+pub use types::EvalContext;
+
+// Re-export rule identities from LoadedRule::id, for Rules::find_rule
+pub use types::RuleId;
+
+// Re-export rule syntax versions for Rules::migrate_syntax
+pub use types::GrammarVersion;
+
+// Re-export the engine returned by Rules::engine and its stats
+pub use engine::Engine;
+pub use types::EngineStats;
+
+// Re-export compiled rules from Rules::compile
+pub use engine::RuleSet;
+
+// Re-export timestamp parsing used by @effective_from and @expires -- e.g. "2025-01-01"
+pub use utils::time::parse_timestamp;
+
+// Re-export single-object parsing for request payloads, folded like the tags they're checked
+// against -- e.g. "{ colour: red }"
+pub use parser::objects::parse_object_from_str as parse_object;
+
 mod rules;
@@ -0,0 +1,582 @@
+// Language server for .rules and .tags files -- `rules lsp`
+use crate::err::RulesError;
+use crate::parser::rules::{self, RuleParser};
+use crate::parser::tags;
+use crate::types::{Tag, TagLoadOptions};
+use crate::utils::file;
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as LspNotification, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as LspRequest};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind,
+    NumberOrString, OneOf, Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Characters after which a tag name or value can be completed -- e.g. "colour =" or "red,"
+const TRIGGER_CHARACTERS: [&str; 9] = ["-", "=", "!", "<", ">", ",", "&", "|", "("];
+
+// Open documents over the config directory, read as the editor has them rather than as saved
+pub struct Workspace {
+    m_config_dir: String,
+    m_documents: HashMap<PathBuf, String>,
+}
+
+// Same path for a file however it was reached -- e.g. "config/a.tags" and "/repo/config/a.tags"
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|e| e == extension)
+}
+
+fn line_at(text: &str, line: u32) -> &str {
+    text.lines().nth(line as usize).unwrap_or("")
+}
+
+fn line_range(line_no: usize, line: &str) -> Range {
+    Range::new(
+        Position::new(line_no as u32, 0),
+        Position::new(line_no as u32, line.chars().count() as u32),
+    )
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Word under the cursor -- e.g. "colour" anywhere within "colour = red"
+fn word_at(line: &str, character: u32) -> Option<String> {
+    let chars: Vec<char> = line.chars().collect();
+    let cursor = (character as usize).min(chars.len());
+
+    let start = chars[..cursor]
+        .iter()
+        .rposition(|c| !is_word_char(*c))
+        .map_or(0, |i| i + 1);
+    let end = chars[cursor..]
+        .iter()
+        .position(|c| !is_word_char(*c))
+        .map_or(chars.len(), |i| cursor + i);
+
+    (start < end).then(|| chars[start..end].iter().collect())
+}
+
+// Tag whose values are being typed before the cursor, None where a tag name is expected
+// -- e.g. Some("colour") for "- size = large & colour = red, "
+fn completion_tag(prefix: &str) -> Option<String> {
+    let clause_start = prefix.rfind(['&', '|', '(']).map_or(0, |i| i + 1);
+    let clause = &prefix[clause_start..];
+    let op = clause.find(['=', '!', '<', '>'])?;
+    let tag_name = clause[..op].trim().trim_start_matches('-').trim();
+
+    // "-!is_fragile" is flag shorthand, so a name follows
+    (!tag_name.is_empty()).then(|| tag_name.to_string())
+}
+
+// Allowed values of a tag as written in a .tags file -- e.g. "small < medium < large"
+fn describe_values(tag: &Tag) -> String {
+    match &tag.constraints.pattern {
+        Some(pattern) => format!("/{}/", pattern.as_str()),
+        None if tag.constraints.ordered => tag.values.join(" < "),
+        None => tag.values.join(", "),
+    }
+}
+
+fn describe_tag(tag: &Tag) -> String {
+    let attributes: Vec<&str> = [
+        (tag.constraints.open, "open"),
+        (tag.constraints.required, "required"),
+        (tag.constraints.flag, "flag"),
+        (tag.constraints.single, "single"),
+    ]
+    .into_iter()
+    .filter_map(|(set, name)| set.then_some(name))
+    .collect();
+
+    let mut description = format!("**{}**", tag.name);
+    if !attributes.is_empty() {
+        description.push_str(&format!(" ({})", attributes.join(", ")));
+    }
+    description.push_str(&format!("\n\n{}", describe_values(tag)));
+    description
+}
+
+// An error's diagnostic, from the column it gives to the end of the line -- or the whole line
+// when there's no column, or it is in a rule changed by its macros
+fn error_diagnostic(line_no: usize, line: &str, error: RulesError, located: bool) -> Diagnostic {
+    let mut range = line_range(line_no, line);
+    if located && let Some(column) = error.column() {
+        range.start.character = (column as u32 - 1).min(range.end.character);
+    }
+    Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::ERROR),
+        code: Some(NumberOrString::String(error.code().to_string())),
+        source: Some("rules".to_string()),
+        message: error.to_string(),
+        ..Default::default()
+    }
+}
+
+impl Workspace {
+    pub fn new(config_dir: impl Into<String>) -> Self {
+        Workspace {
+            m_config_dir: config_dir.into(),
+            m_documents: HashMap::new(),
+        }
+    }
+
+    pub fn open(&mut self, path: &Path, text: String) {
+        self.m_documents.insert(canonical(path), text);
+    }
+
+    pub fn close(&mut self, path: &Path) {
+        self.m_documents.remove(&canonical(path));
+    }
+
+    fn document(&self, path: &Path) -> &str {
+        self.m_documents
+            .get(&canonical(path))
+            .map_or("", String::as_str)
+    }
+
+    // .tags files in the config directory, open documents in place of their saved contents
+    fn tag_sources(&self) -> Vec<(PathBuf, String)> {
+        let pattern = format!("{}/*.tags", self.m_config_dir);
+
+        file::read_files_in_dir_with_paths(&pattern)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(path, content)| {
+                let path = canonical(Path::new(&path));
+                let content = self.m_documents.get(&path).cloned().unwrap_or(content);
+                (path, content)
+            })
+            .collect()
+    }
+
+    fn loaded_tags(&self) -> Result<Vec<Tag>, RulesError> {
+        let sources = self
+            .tag_sources()
+            .into_iter()
+            .map(|(path, content)| (path.display().to_string(), content))
+            .collect();
+
+        Ok(tags::parse_tags_from_sources(sources, TagLoadOptions::default())?.tags)
+    }
+
+    fn find_tag(tags: Vec<Tag>, name: &str) -> Option<Tag> {
+        let name = name.to_lowercase();
+        tags.into_iter().find(|tag| tag.name == name)
+    }
+
+    // Problems in an open document, one per invalid line. Rules aren't checked while the tags
+    // themselves are invalid, the .tags file reports those.
+    pub fn diagnostics(&self, path: &Path) -> Vec<Diagnostic> {
+        let text = self.document(path);
+        let lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !file::line_blank_or_comment(line));
+
+        if has_extension(path, "tags") {
+            lines
+                .filter_map(|(i, line)| {
+                    let result = match tags::get_exclusive_group_from_line(line) {
+                        Ok(Some(_)) => Ok(()),
+                        Ok(None) => tags::get_tag_from_line(line).map(|_| ()),
+                        Err(e) => Err(e),
+                    };
+                    result.err().map(|e| error_diagnostic(i, line, e, false))
+                })
+                .collect()
+        } else if has_extension(path, "rules") {
+            let Ok(tags) = self.loaded_tags() else {
+                return Vec::new();
+            };
+            let parser = RuleParser::from_tags(tags);
+            // Macro cycles and duplicates leave the rules using them reported instead
+            let macros = rules::parse_macros(&path.display().to_string(), text).unwrap_or_default();
+
+            lines
+                .filter_map(|(i, line)| {
+                    let mut located = false;
+                    let result = match rules::get_macro_from_line(line) {
+                        Ok(Some(_)) => Ok(()),
+                        Ok(None) => match rules::get_annotations_from_line(line) {
+                            Ok(Some(annotations)) => {
+                                annotations.iter().try_for_each(rules::check_annotation)
+                            }
+                            Ok(None) => macros.expand(line).and_then(|rule| {
+                                located = rule == line;
+                                parser.validate_rule(&rule)
+                            }),
+                            Err(e) => Err(e),
+                        },
+                        Err(e) => Err(e),
+                    };
+                    result.err().map(|e| error_diagnostic(i, line, e, located))
+                })
+                .collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    // Tag names, or the values of the tag being compared, at a position in a .rules document
+    pub fn completions(&self, path: &Path, position: Position) -> Vec<CompletionItem> {
+        let Ok(tags) = self.loaded_tags() else {
+            return Vec::new();
+        };
+        if !has_extension(path, "rules") {
+            return Vec::new();
+        }
+
+        let line = line_at(self.document(path), position.line);
+        let prefix: String = line.chars().take(position.character as usize).collect();
+
+        match completion_tag(&prefix) {
+            Some(tag_name) => Self::find_tag(tags, &tag_name)
+                .map(|tag| {
+                    tag.values
+                        .iter()
+                        .map(|value| CompletionItem {
+                            label: value.clone(),
+                            kind: Some(CompletionItemKind::VALUE),
+                            detail: Some(tag.name.clone()),
+                            ..Default::default()
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            None => tags
+                .iter()
+                .map(|tag| CompletionItem {
+                    label: tag.name.clone(),
+                    kind: Some(CompletionItemKind::FIELD),
+                    detail: Some(describe_values(tag)),
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    // Allowed values of the tag under the cursor
+    pub fn hover(&self, path: &Path, position: Position) -> Option<Hover> {
+        let line = line_at(self.document(path), position.line);
+        let word = word_at(line, position.character)?;
+        let tag = Self::find_tag(self.loaded_tags().ok()?, &word)?;
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: describe_tag(&tag),
+            }),
+            range: None,
+        })
+    }
+
+    // Line declaring the tag under the cursor, the first if it's declared more than once
+    pub fn definition(&self, path: &Path, position: Position) -> Option<Location> {
+        let line = line_at(self.document(path), position.line);
+        let name = word_at(line, position.character)?.to_lowercase();
+
+        self.tag_sources()
+            .into_iter()
+            .find_map(|(tags_path, content)| {
+                let (line_no, line) = content.lines().enumerate().find(|(_, line)| {
+                    !file::line_blank_or_comment(line)
+                        && tags::get_tag_from_line(line)
+                            .is_ok_and(|tag| tag.name.to_lowercase() == name)
+                })?;
+
+                Some(Location::new(
+                    Url::from_file_path(&tags_path).ok()?,
+                    line_range(line_no, line),
+                ))
+            })
+    }
+
+    fn open_paths(&self) -> Vec<PathBuf> {
+        self.m_documents.keys().cloned().collect()
+    }
+}
+
+fn protocol_error(error: impl std::error::Error + Send + Sync + 'static) -> RulesError {
+    RulesError::IoError(io::Error::other(error))
+}
+
+fn uri_to_path(uri: &Url) -> Option<PathBuf> {
+    uri.to_file_path().ok()
+}
+
+fn send(connection: &Connection, message: Message) -> Result<(), RulesError> {
+    connection.sender.send(message).map_err(protocol_error)
+}
+
+fn publish(
+    connection: &Connection,
+    uri: Url,
+    diagnostics: Vec<Diagnostic>,
+) -> Result<(), RulesError> {
+    let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+    let notification = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+    send(connection, Message::Notification(notification))
+}
+
+// Any edit can change whether other documents are valid, e.g. removing a tag value, so every
+// open document is rechecked
+fn publish_all(connection: &Connection, workspace: &Workspace) -> Result<(), RulesError> {
+    for path in workspace.open_paths() {
+        if let Ok(uri) = Url::from_file_path(&path) {
+            publish(connection, uri, workspace.diagnostics(&path))?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_request(workspace: &Workspace, request: Request) -> Response {
+    let id = request.id.clone();
+    let invalid_params = |e| {
+        Response::new_err(
+            id.clone(),
+            ErrorCode::InvalidParams as i32,
+            format!("{:?}", e),
+        )
+    };
+
+    match request.method.as_str() {
+        Completion::METHOD => match request.extract::<CompletionParams>(Completion::METHOD) {
+            Ok((_, params)) => {
+                let doc = params.text_document_position;
+                let items = uri_to_path(&doc.text_document.uri)
+                    .map(|path| workspace.completions(&path, doc.position))
+                    .unwrap_or_default();
+                Response::new_ok(id, CompletionResponse::Array(items))
+            }
+            Err(e) => invalid_params(e),
+        },
+        HoverRequest::METHOD => match request.extract::<HoverParams>(HoverRequest::METHOD) {
+            Ok((_, params)) => {
+                let doc = params.text_document_position_params;
+                let hover = uri_to_path(&doc.text_document.uri)
+                    .and_then(|path| workspace.hover(&path, doc.position));
+                Response::new_ok(id, hover)
+            }
+            Err(e) => invalid_params(e),
+        },
+        GotoDefinition::METHOD => {
+            match request.extract::<GotoDefinitionParams>(GotoDefinition::METHOD) {
+                Ok((_, params)) => {
+                    let doc = params.text_document_position_params;
+                    let location = uri_to_path(&doc.text_document.uri)
+                        .and_then(|path| workspace.definition(&path, doc.position))
+                        .map(GotoDefinitionResponse::Scalar);
+                    Response::new_ok(id, location)
+                }
+                Err(e) => invalid_params(e),
+            }
+        }
+        method => Response::new_err(
+            id,
+            ErrorCode::MethodNotFound as i32,
+            format!("Unsupported request: {}", method),
+        ),
+    }
+}
+
+fn handle_notification(
+    connection: &Connection,
+    workspace: &mut Workspace,
+    notification: Notification,
+) -> Result<(), RulesError> {
+    match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params = notification
+                .extract::<lsp_types::DidOpenTextDocumentParams>(DidOpenTextDocument::METHOD)
+                .map_err(protocol_error)?;
+            if let Some(path) = uri_to_path(&params.text_document.uri) {
+                workspace.open(&path, params.text_document.text);
+            }
+        }
+        DidChangeTextDocument::METHOD => {
+            let params = notification
+                .extract::<lsp_types::DidChangeTextDocumentParams>(DidChangeTextDocument::METHOD)
+                .map_err(protocol_error)?;
+            // Full sync, so the last change holds the whole document
+            if let (Some(path), Some(change)) = (
+                uri_to_path(&params.text_document.uri),
+                params.content_changes.into_iter().last(),
+            ) {
+                workspace.open(&path, change.text);
+            }
+        }
+        DidCloseTextDocument::METHOD => {
+            let params = notification
+                .extract::<lsp_types::DidCloseTextDocumentParams>(DidCloseTextDocument::METHOD)
+                .map_err(protocol_error)?;
+            if let Some(path) = uri_to_path(&params.text_document.uri) {
+                workspace.close(&path);
+            }
+            publish(connection, params.text_document.uri, Vec::new())?;
+        }
+        _ => return Ok(()),
+    }
+
+    publish_all(connection, workspace)
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(TRIGGER_CHARACTERS.map(String::from).to_vec()),
+            ..Default::default()
+        }),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    }
+}
+
+// Serve the language server over stdin and stdout until the editor shuts it down
+pub fn run(config_dir: &str) -> Result<(), RulesError> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = serde_json::to_value(capabilities()).map_err(protocol_error)?;
+    connection
+        .initialize(capabilities)
+        .map_err(protocol_error)?;
+
+    let mut workspace = Workspace::new(config_dir);
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection
+                    .handle_shutdown(&request)
+                    .map_err(protocol_error)?
+                {
+                    break;
+                }
+                let response = handle_request(&workspace, request);
+                send(&connection, Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                handle_notification(&connection, &mut workspace, notification)?;
+            }
+            Message::Response(_) => {}
+        }
+    }
+
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "# Tags\n- colour (required): red, blue\n- size: small < medium < large",
+        )
+        .unwrap();
+
+        test_dir
+    }
+
+    fn labels(items: Vec<CompletionItem>) -> Vec<String> {
+        let mut labels: Vec<String> = items.into_iter().map(|item| item.label).collect();
+        labels.sort();
+        labels
+    }
+
+    #[test]
+    fn test_completion_tag() {
+        assert_eq!(completion_tag("- col"), None);
+        assert_eq!(completion_tag("- colour = red & "), None);
+        assert_eq!(completion_tag("-!"), None);
+        assert_eq!(completion_tag("- colour ="), Some("colour".to_string()));
+        assert_eq!(
+            completion_tag("- size = large & (colour = red, "),
+            Some("colour".to_string())
+        );
+    }
+
+    #[test]
+    fn test_workspace() {
+        let test_dir = setup_test_env("test_lsp_workspace");
+        let rules_path = PathBuf::from(format!("{}/test.rules", test_dir));
+        let tags_path = PathBuf::from(format!("{}/test.tags", test_dir));
+
+        let mut workspace = Workspace::new(&test_dir);
+        workspace.open(
+            &rules_path,
+            "# Rules\n- colour = red & size > small\n- colour = green\n- colour = \n- colour = red & & size = small"
+                .to_string(),
+        );
+
+        let diagnostics = workspace.diagnostics(&rules_path);
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics[0].range.start.line, 2);
+        assert_eq!(diagnostics[0].range.start.character, 0);
+        assert!(diagnostics[0].message.contains("green"));
+        assert_eq!(diagnostics[2].range.start.line, 4);
+        assert_eq!(diagnostics[2].range.start.character, 17);
+        assert_eq!(
+            diagnostics[2].code,
+            Some(NumberOrString::String("R002".to_string()))
+        );
+
+        let names = labels(workspace.completions(&rules_path, Position::new(1, 4)));
+        assert_eq!(names, vec!["colour", "size"]);
+        let values = labels(workspace.completions(&rules_path, Position::new(3, 11)));
+        assert_eq!(values, vec!["blue", "red"]);
+
+        let hover = workspace.hover(&rules_path, Position::new(1, 20)).unwrap();
+        let HoverContents::Markup(content) = hover.contents else {
+            panic!("Expected markup hover");
+        };
+        assert_eq!(content.value, "**size**\n\nsmall < medium < large");
+        assert!(workspace.hover(&rules_path, Position::new(1, 12)).is_none());
+
+        let location = workspace
+            .definition(&rules_path, Position::new(1, 4))
+            .unwrap();
+        assert_eq!(location.uri.to_file_path().unwrap(), canonical(&tags_path));
+        assert_eq!(location.range.start.line, 1);
+
+        // Unsaved edits to the tags are used in place of the file
+        workspace.open(
+            &tags_path,
+            "- colour: red, blue, green\n- size: small < medium < large".to_string(),
+        );
+        assert_eq!(workspace.diagnostics(&rules_path).len(), 2);
+
+        workspace.open(&tags_path, "- colour: red\n- shape circle".to_string());
+        let diagnostics = workspace.diagnostics(&tags_path);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start.line, 1);
+        assert!(workspace.diagnostics(&rules_path).is_empty());
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
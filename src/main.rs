@@ -1,6 +1,432 @@
+use rules::Rules;
+use rules::engine::Engine;
 use rules::err::RulesError;
-use rules::orchestrator::Orchestrator;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() -> Result<(), RulesError> {
-    Orchestrator::run()
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("serve") => serve(&args[1..]),
+        Some("grpc") => grpc(&args[1..]),
+        Some("lsp") => lsp(&args[1..]),
+        Some("diff") => diff(&args[1..]),
+        Some("evaluate") => evaluate(&args[1..]),
+        Some("daemon") => daemon(&args[1..]),
+        Some("lint") => lint(&args[1..]),
+        Some("conflicts") => conflicts(&args[1..]),
+        Some("coverage") => coverage(&args[1..]),
+        Some("truth-table") => truth_table(&args[1..]),
+        Some("explain") => explain(&args[1..]),
+        Some("migrate") => migrate(&args[1..]),
+        Some("generate-rules") => generate_rules(&args[1..]),
+        Some("test") => test(&args[1..]),
+        Some("pull") => pull(&args[1..]),
+        _ => run(&args),
+    }
+}
+
+// rules [--config <dir>], loading the config directory into an engine and printing what was
+// loaded -- e.g. "3 tags, 12 subrules, 40 objects"
+fn run(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+
+    let mut engine = Engine::new(config_dir);
+    engine.load()?;
+
+    println!("{}", engine.stats());
+
+    Ok(())
+}
+
+// rules evaluate [--config <dir>] [--scores | --stats], printing the rules each object in the
+// config directory matched -- e.g. "config/my_objects.yaml shapes[0]:", with --scores each
+// object's score -- e.g. "config/my_objects.yaml shapes[0]: 3.5", or with --stats how often
+// each rule matched -- e.g. "config/my_rules.rules:3 - colour = red: matched 3 of 40"
+fn evaluate(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+    let report = rules::evaluate(config_dir)?;
+
+    if args.iter().any(|arg| arg == "--scores") {
+        for result in &report.results {
+            println!("{}: {}", result.object.id(), result.score());
+        }
+    } else if args.iter().any(|arg| arg == "--stats") {
+        for stats in report.stats() {
+            println!("{}", stats);
+        }
+    } else {
+        print!("{}", report);
+    }
+
+    Ok(())
+}
+
+// rules diff <old config dir> <new config dir>, exiting with 1 if they differ
+fn diff(args: &[String]) -> Result<(), RulesError> {
+    let [old_dir, new_dir] = args else {
+        eprintln!("usage: rules diff <old config dir> <new config dir>");
+        std::process::exit(2);
+    };
+
+    let mut old = Rules::new(old_dir.as_str());
+    old.load_tags()?;
+    let mut new = Rules::new(new_dir.as_str());
+    new.load_tags()?;
+
+    let changes = old.changeset(&new)?;
+    if changes.is_empty() {
+        println!("No changes");
+        return Ok(());
+    }
+
+    print!("{}", changes);
+    std::process::exit(1);
+}
+
+// rules lint [--config <dir>] [--at <date>], failing with every invalid rule and warning about
+// rules that are valid but probably mistakes -- e.g. expired rules left in a file
+fn lint(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+    let timestamp = match flag_value(args, "--at") {
+        Some(at) => rules::parse_timestamp(at).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid timestamp: '{}', expected a date like 2025-01-01",
+                    at
+                ),
+            )
+        })?,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    };
+
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    match rules.validate_rules() {
+        Err(RulesError::ValidationError(report)) => {
+            for diagnostic in report.diagnostics() {
+                println!("{}", diagnostic);
+            }
+            std::process::exit(1);
+        }
+        result => {
+            result?;
+        }
+    }
+
+    let warnings = rules.lint(timestamp)?;
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    println!("{} warnings", warnings.len());
+
+    Ok(())
+}
+
+// rules conflicts [--config <dir>], listing pairs of rules an object could match at once
+fn conflicts(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    let conflicts = rules.rule_conflicts()?;
+    for conflict in &conflicts {
+        println!("{}", conflict);
+    }
+    println!("{} conflicts", conflicts.len());
+
+    Ok(())
+}
+
+// rules test [--config <dir>], running the "#test:" examples under rules and exiting with 1 if
+// any fail
+fn test(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    let results = rules.run_inline_tests()?;
+    let failed: Vec<_> = results.iter().filter(|result| !result.passed()).collect();
+    for result in &failed {
+        println!("FAIL {}", result);
+    }
+    println!(
+        "{} passed, {} failed",
+        results.len() - failed.len(),
+        failed.len()
+    );
+
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// rules coverage [--config <dir>] [--examples <n>], listing combinations of tag values no rule
+// matches -- e.g. "{ colour = green, size = small }"
+fn coverage(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+    let max_examples = match flag_value(args, "--examples") {
+        Some(n) => n.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid --examples: '{}', expected a number", n),
+            )
+        })?,
+        None => 10,
+    };
+
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    print!("{}", rules.coverage_gaps(max_examples)?);
+
+    Ok(())
+}
+
+// rules truth-table <rule> [--config <dir>], printing whether the rule matches each combination
+// of values of the tags it compares as CSV -- e.g. "red,small,true"
+fn truth_table(args: &[String]) -> Result<(), RulesError> {
+    let Some(rule) = args.first().filter(|rule| !rule.starts_with("--")) else {
+        eprintln!("usage: rules truth-table <rule> [--config <dir>]");
+        std::process::exit(2);
+    };
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    print!("{}", rules.truth_table(rule)?.to_csv()?);
+
+    Ok(())
+}
+
+// rules migrate --to <version> [--config <dir>], rewriting every rule in another grammar version
+// -- e.g. "--to v2" turns "colour ! red" into `colour != "red"`
+fn migrate(args: &[String]) -> Result<(), RulesError> {
+    let Some(to) = flag_value(args, "--to") else {
+        eprintln!("usage: rules migrate --to <v1|v2> [--config <dir>]");
+        std::process::exit(2);
+    };
+    let version = rules::GrammarVersion::parse(to).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid --to: '{}', expected v1 or v2", to),
+        )
+    })?;
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    let migrated = rules.migrate_syntax(version)?;
+    println!("Migrated {} rules to {}", migrated, version.as_str());
+
+    Ok(())
+}
+
+// rules explain <rule> [--config <dir>], printing how the rule was parsed as a tree -- e.g.
+// "&" above "|-- colour = red" and "`-- size = large"
+fn explain(args: &[String]) -> Result<(), RulesError> {
+    let Some(rule) = args.first().filter(|rule| !rule.starts_with("--")) else {
+        eprintln!("usage: rules explain <rule> [--config <dir>]");
+        std::process::exit(2);
+    };
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    print!("{}", rules.render_tree(rule)?);
+
+    Ok(())
+}
+
+// rules generate-rules [--config <dir>] [--count <n>] [--depth <n>] [--seed <n>], printing random
+// rules valid against the config's tags, one per line -- e.g. for a benchmark corpus
+fn generate_rules(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+    let number = |flag: &str, default: u64| -> Result<u64, RulesError> {
+        match flag_value(args, flag) {
+            Some(n) => Ok(n.parse().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid {}: '{}', expected a number", flag, n),
+                )
+            })?),
+            None => Ok(default),
+        }
+    };
+    let count = number("--count", 100)?;
+    let depth = number("--depth", 3)?;
+    let seed = number("--seed", 0)?;
+
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    let mut generator = rules.rule_generator(seed).with_max_depth(depth as usize);
+    for rule in generator.generate_many(count as usize)? {
+        println!("{}", rule);
+    }
+
+    Ok(())
+}
+
+// rules daemon [--config <dir>] [--poll-ms <ms>], evaluating one object per line of stdin --
+// e.g. "{ colour: red, size: [small] }". A "status" line prints the daemon's health.
+#[cfg(feature = "watch")]
+fn daemon(args: &[String]) -> Result<(), RulesError> {
+    use rules::CaseFolding;
+    use rules::orchestrator::{self, Orchestrator};
+    use std::io::BufRead;
+    use std::time::Duration;
+
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+    let poll_interval = match flag_value(args, "--poll-ms") {
+        Some(ms) => Duration::from_millis(ms.parse().map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}: {}", ms, e))
+        })?),
+        None => orchestrator::DEFAULT_POLL_INTERVAL,
+    };
+
+    let daemon = Orchestrator::daemon(Rules::new(config_dir), poll_interval)?;
+    eprintln!(
+        "Serving rules from '{}', reading objects from stdin",
+        config_dir
+    );
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if line.trim() == "status" {
+            println!("{}", daemon.status()?);
+            continue;
+        }
+
+        let result =
+            rules::parse_object(&line, CaseFolding::default()).and_then(|obj| daemon.evaluate(obj));
+        match result {
+            Ok(matched) => {
+                for loaded in &matched {
+                    println!("{}:{} {}", loaded.file, loaded.line, loaded.rule);
+                }
+                println!("{} matched", matched.len());
+            }
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "watch"))]
+fn daemon(_args: &[String]) -> Result<(), RulesError> {
+    missing_feature("watch")
+}
+
+// Value following `flag` -- e.g. "--addr 0.0.0.0:8080"
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+#[cfg(not(all(
+    feature = "watch",
+    feature = "server",
+    feature = "grpc",
+    feature = "lsp",
+    feature = "remote"
+)))]
+fn missing_feature(feature: &str) -> ! {
+    eprintln!(
+        "rules was built without the '{}' feature, rebuild with --features {}",
+        feature, feature
+    );
+    std::process::exit(2);
+}
+
+// rules serve [--config <dir>] [--addr <host:port>]
+#[cfg(feature = "server")]
+fn serve(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+    let addr = flag_value(args, "--addr").unwrap_or(rules::server::DEFAULT_ADDR);
+
+    println!("Serving rules from '{}' on http://{}", config_dir, addr);
+    rules::server::serve(config_dir, addr)
+}
+
+#[cfg(not(feature = "server"))]
+fn serve(_args: &[String]) -> Result<(), RulesError> {
+    missing_feature("server")
+}
+
+// rules grpc [--config <dir>] [--addr <host:port>]
+#[cfg(feature = "grpc")]
+fn grpc(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+    let addr = flag_value(args, "--addr").unwrap_or(rules::grpc::DEFAULT_ADDR);
+
+    println!("Serving rules from '{}' over gRPC on {}", config_dir, addr);
+    rules::grpc::serve(config_dir, addr)
+}
+
+#[cfg(not(feature = "grpc"))]
+fn grpc(_args: &[String]) -> Result<(), RulesError> {
+    missing_feature("grpc")
+}
+
+// rules lsp [--config <dir>], talking to the editor over stdin and stdout
+#[cfg(feature = "lsp")]
+fn lsp(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+    rules::lsp::run(config_dir)
+}
+
+#[cfg(not(feature = "lsp"))]
+fn lsp(_args: &[String]) -> Result<(), RulesError> {
+    missing_feature("lsp")
+}
+
+// rules pull [--config <dir>] <url>..., run at startup and on refresh -- e.g.
+// "rules pull https://config.example.com/base.rules s3://config-bucket/base.tags"
+#[cfg(feature = "remote")]
+fn pull(args: &[String]) -> Result<(), RulesError> {
+    let config_dir = flag_value(args, "--config").unwrap_or("config");
+    let urls: Vec<&str> = args
+        .iter()
+        .enumerate()
+        .filter(|(i, arg)| *arg != "--config" && (*i == 0 || args[i - 1] != "--config"))
+        .map(|(_, arg)| arg.as_str())
+        .collect();
+    if urls.is_empty() {
+        eprintln!("usage: rules pull [--config <dir>] <url>...");
+        std::process::exit(2);
+    }
+
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    let changed = rules.pull(&urls)?;
+    for file in &changed {
+        println!("Updated {}", file);
+    }
+    println!("{} of {} files changed", changed.len(), urls.len());
+
+    Ok(())
+}
+
+#[cfg(not(feature = "remote"))]
+fn pull(_args: &[String]) -> Result<(), RulesError> {
+    missing_feature("remote")
 }
@@ -0,0 +1,207 @@
+// Node.js addon exposing the engine -- `new RulesEngine("config")` in JavaScript
+use crate::Rules;
+use crate::err::RulesError;
+use crate::types::{LoadedRule, Object};
+
+use napi::bindgen_prelude::{AsyncTask, Either};
+use napi::{Env, Error, Result, Task};
+use napi_derive::napi;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+// Object tag values from JavaScript -- e.g. { colour: "red", size: ["small", "large"] }
+type JsObject = HashMap<String, Either<String, Vec<String>>>;
+
+/// Rule read from a `.rules` file, with where it was found.
+#[napi(object)]
+pub struct Rule {
+    /// Stable identity of the rule, e.g. `my_rules.rules:3#9c4f0a1b2d3e4f56`.
+    pub id: String,
+    pub file: String,
+    pub line: u32,
+    pub rule: String,
+    /// Name the rule is written with, e.g. `promo_1`.
+    pub name: Option<String>,
+    /// From the rule's `@description`, `@severity` and `@owner` annotations.
+    pub description: Option<String>,
+    pub severity: Option<String>,
+    pub owner: Option<String>,
+}
+
+/// Rules engine loaded from a config directory.
+#[napi]
+pub struct RulesEngine {
+    m_rules: Arc<RwLock<Rules>>,
+}
+
+pub struct EvaluateTask {
+    m_rules: Arc<RwLock<Rules>>,
+    m_object: Object,
+}
+
+impl From<LoadedRule> for Rule {
+    fn from(loaded: LoadedRule) -> Self {
+        let meta = loaded.meta();
+        Rule {
+            id: loaded.id().to_string(),
+            name: loaded.name().map(str::to_string),
+            description: meta.description,
+            severity: meta.severity.map(|s| s.as_str().to_string()),
+            owner: meta.owner,
+            file: loaded.file,
+            line: loaded.line as u32,
+            rule: loaded.rule,
+        }
+    }
+}
+
+fn to_js_error(error: RulesError) -> Error {
+    Error::from_reason(error.to_string())
+}
+
+// A panicking call can't leave Rules half-updated, so a poisoned lock is still usable
+fn read(rules: &RwLock<Rules>) -> RwLockReadGuard<'_, Rules> {
+    rules.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn to_object(object: JsObject) -> Object {
+    object
+        .into_iter()
+        .map(|(tag_name, values)| match values {
+            Either::A(value) => (tag_name, vec![value]),
+            Either::B(values) => (tag_name, values),
+        })
+        .collect()
+}
+
+// Evaluation runs on the libuv thread pool, off the JavaScript thread
+impl Task for EvaluateTask {
+    type Output = Vec<LoadedRule>;
+    type JsValue = Vec<Rule>;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        read(&self.m_rules)
+            .matching_rules(&self.m_object)
+            .map_err(to_js_error)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+        Ok(output.into_iter().map(Rule::from).collect())
+    }
+}
+
+#[napi]
+impl RulesEngine {
+    /// Loads the tags in `configDir`. Throws if they are invalid.
+    #[napi(constructor)]
+    pub fn new(config_dir: String) -> Result<Self> {
+        let mut rules = Rules::new(config_dir);
+        rules.load_tags().map_err(to_js_error)?;
+
+        Ok(RulesEngine {
+            m_rules: Arc::new(RwLock::new(rules)),
+        })
+    }
+
+    /// Re-reads the tags files.
+    #[napi]
+    pub fn reload(&self) -> Result<()> {
+        let mut rules = self.m_rules.write().unwrap_or_else(|e| e.into_inner());
+        rules.load_tags().map_err(to_js_error)
+    }
+
+    /// Tag names to their allowed values.
+    #[napi]
+    pub fn tags(&self) -> HashMap<String, Vec<String>> {
+        read(&self.m_rules).tags().clone()
+    }
+
+    /// Rules in the config directory's `.rules` files.
+    #[napi]
+    pub fn rules(&self) -> Result<Vec<Rule>> {
+        let loaded = read(&self.m_rules).rules().map_err(to_js_error)?;
+        Ok(loaded.into_iter().map(Rule::from).collect())
+    }
+
+    /// Throws if `rule` is invalid against the loaded tags.
+    #[napi]
+    pub fn validate_rule(&self, rule: String) -> Result<()> {
+        read(&self.m_rules)
+            .validate_rule(&rule)
+            .map_err(to_js_error)
+    }
+
+    /// Resolves to the rules `object` matches. Rejects if the object is invalid.
+    #[napi]
+    pub fn evaluate(&self, object: JsObject) -> AsyncTask<EvaluateTask> {
+        AsyncTask::new(EvaluateTask {
+            m_rules: Arc::clone(&self.m_rules),
+            m_object: to_object(object),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+
+        fs::write(format!("{}/test.tags", test_dir), "- colour: red, blue").unwrap();
+        fs::write(
+            format!("{}/test.rules", test_dir),
+            "- colour = red\n- colour = blue",
+        )
+        .unwrap();
+
+        test_dir
+    }
+
+    #[test]
+    fn test_rules_engine() {
+        let test_dir = setup_test_env("test_node_rules_engine");
+        let engine = RulesEngine::new(test_dir.clone()).unwrap();
+
+        assert_eq!(engine.tags()["colour"], vec!["red", "blue"]);
+        assert_eq!(engine.rules().unwrap().len(), 2);
+        assert!(engine.validate_rule("- colour = blue".to_string()).is_ok());
+        assert!(
+            engine
+                .validate_rule("- colour = green".to_string())
+                .is_err()
+        );
+
+        let mut object = JsObject::new();
+        object.insert("colour".to_string(), Either::A("blue".to_string()));
+        let mut task = EvaluateTask {
+            m_rules: Arc::clone(&engine.m_rules),
+            m_object: to_object(object),
+        };
+        let matched = task.compute().unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].line, 2);
+
+        let mut object = JsObject::new();
+        object.insert("colour".to_string(), Either::B(vec!["green".to_string()]));
+        task.m_object = to_object(object);
+        assert!(task.compute().unwrap_err().reason.contains("green"));
+
+        // Objects missing a required tag match nothing under the default policy
+        fs::write(
+            format!("{}/shape.tags", test_dir),
+            "- shape (required): circle",
+        )
+        .unwrap();
+        engine.reload().unwrap();
+        let mut object = JsObject::new();
+        object.insert("colour".to_string(), Either::A("blue".to_string()));
+        task.m_object = to_object(object);
+        assert!(task.compute().unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
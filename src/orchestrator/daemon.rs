@@ -0,0 +1,373 @@
+// Long-running engine reloading on config changes -- e.g. Orchestrator::daemon(rules, interval)
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::Orchestrator;
+use crate::{
+    Rules, api::audit::unix_timestamp, engine::RuleSet, err::RulesError, types::DaemonStatus,
+    types::LoadedRule, types::Object, utils::file,
+};
+
+// How often the daemon checks the config files for changes
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Requests served by the daemon's engine thread, each with a channel for the reply
+enum DaemonRequest {
+    Evaluate(Object, Sender<Result<Vec<LoadedRule>, RulesError>>),
+    Reload(Option<Sender<Result<(), RulesError>>>),
+    Status(Sender<DaemonStatus>),
+    Stop,
+}
+
+// Long-running engine, serving requests until dropped
+pub struct Daemon {
+    m_handle: DaemonHandle,
+    m_engine: Option<JoinHandle<()>>,
+    m_watcher: Option<JoinHandle<()>>,
+    // Dropped to wake and stop the watcher
+    m_stop_watcher: Option<Sender<()>>,
+}
+
+// Cheap to clone, so any thread can send requests to the daemon
+#[derive(Clone)]
+pub struct DaemonHandle {
+    m_sender: Sender<DaemonRequest>,
+}
+
+impl Orchestrator {
+    // Load `rules` and serve it on a background thread, reloading whenever a .tags, .rules or
+    // .yaml file in its config directory changes. Fails if the initial load does, tags or rules.
+    pub fn daemon(mut rules: Rules, poll_interval: Duration) -> Result<Daemon, RulesError> {
+        let ruleset = load(&mut rules)?;
+
+        let mut status = DaemonStatus {
+            healthy: true,
+            started_at: unix_timestamp(),
+            ..Default::default()
+        };
+        count_config(&rules, &ruleset, &mut status);
+
+        let config_dir = rules.config_dir().to_string();
+        let (sender, receiver) = mpsc::channel();
+        let engine = thread::spawn(move || serve_requests(rules, ruleset, status, receiver));
+
+        let (stop_watcher, stopped) = mpsc::channel();
+        let reload = sender.clone();
+        let watcher =
+            thread::spawn(move || watch_config(&config_dir, poll_interval, reload, stopped));
+
+        Ok(Daemon {
+            m_handle: DaemonHandle { m_sender: sender },
+            m_engine: Some(engine),
+            m_watcher: Some(watcher),
+            m_stop_watcher: Some(stop_watcher),
+        })
+    }
+}
+
+// The tags, then the rules compiled against them, so a broken .rules file fails the load too
+fn load(rules: &mut Rules) -> Result<Arc<RuleSet>, RulesError> {
+    rules.load_tags()?;
+    rules.compiled()
+}
+
+fn count_config(rules: &Rules, ruleset: &RuleSet, status: &mut DaemonStatus) {
+    status.tag_count = rules.tags().len();
+    status.rule_count = ruleset.rules().len();
+}
+
+// Objects are evaluated against the rules compiled by the last successful load, so edits are
+// only served once a reload picks them up, and a broken config leaves the last good one in place
+fn serve_requests(
+    mut rules: Rules,
+    mut ruleset: Arc<RuleSet>,
+    mut status: DaemonStatus,
+    receiver: Receiver<DaemonRequest>,
+) {
+    for request in receiver {
+        match request {
+            DaemonRequest::Evaluate(obj, reply) => {
+                status.evaluations += 1;
+                let matched = rules.check_required_tags(&obj).and_then(|evaluated| {
+                    if evaluated {
+                        ruleset.matching_rules(&obj)
+                    } else {
+                        Ok(Vec::new())
+                    }
+                });
+                #[cfg(feature = "webhooks")]
+                if let Ok(matched) = &matched {
+                    rules.notify_matches(&obj, matched, unix_timestamp());
+                }
+                let _ = reply.send(matched);
+            }
+            DaemonRequest::Reload(reply) => {
+                let result = load(&mut rules).map(|compiled| {
+                    ruleset = compiled;
+                });
+
+                status.reloads += 1;
+                status.last_reload_at = Some(unix_timestamp());
+                status.healthy = result.is_ok();
+                status.last_error = result.as_ref().err().map(RulesError::to_string);
+                if result.is_ok() {
+                    count_config(&rules, &ruleset, &mut status);
+                }
+
+                if let Some(reply) = reply {
+                    let _ = reply.send(result);
+                }
+            }
+            DaemonRequest::Status(reply) => {
+                let _ = reply.send(status.clone());
+            }
+            DaemonRequest::Stop => break,
+        }
+    }
+}
+
+// Fingerprint of the config files, changing whenever one is added, removed or modified
+fn config_stamps(config_dir: &str) -> Vec<(String, std::time::SystemTime, u64)> {
+    ["tags", "rules", "yaml"]
+        .iter()
+        .flat_map(|ext| file::file_stamps(&format!("{}/*.{}", config_dir, ext)).unwrap_or_default())
+        .collect()
+}
+
+fn watch_config(
+    config_dir: &str,
+    poll_interval: Duration,
+    reload: Sender<DaemonRequest>,
+    stopped: Receiver<()>,
+) {
+    let mut stamps = config_stamps(config_dir);
+
+    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(poll_interval) {
+        let latest = config_stamps(config_dir);
+        if latest == stamps {
+            continue;
+        }
+
+        stamps = latest;
+        if reload.send(DaemonRequest::Reload(None)).is_err() {
+            break;
+        }
+    }
+}
+
+fn stopped_error() -> RulesError {
+    RulesError::IoError(io::Error::new(
+        io::ErrorKind::NotConnected,
+        "The daemon has stopped",
+    ))
+}
+
+impl DaemonHandle {
+    // Send `request` and wait for the reply sent on the channel it was built with
+    fn call<T>(&self, request: impl FnOnce(Sender<T>) -> DaemonRequest) -> Result<T, RulesError> {
+        let (reply, response) = mpsc::channel();
+        self.m_sender
+            .send(request(reply))
+            .map_err(|_| stopped_error())?;
+        response.recv().map_err(|_| stopped_error())
+    }
+
+    // Rules `obj` matches, as Rules::matching_rules gives them
+    pub fn evaluate(&self, obj: Object) -> Result<Vec<LoadedRule>, RulesError> {
+        self.call(|reply| DaemonRequest::Evaluate(obj, reply))?
+    }
+
+    // Reload now rather than waiting for the watcher to notice a change
+    pub fn reload(&self) -> Result<(), RulesError> {
+        self.call(|reply| DaemonRequest::Reload(Some(reply)))?
+    }
+
+    pub fn status(&self) -> Result<DaemonStatus, RulesError> {
+        self.call(DaemonRequest::Status)
+    }
+}
+
+impl Daemon {
+    pub fn handle(&self) -> DaemonHandle {
+        self.m_handle.clone()
+    }
+
+    pub fn evaluate(&self, obj: Object) -> Result<Vec<LoadedRule>, RulesError> {
+        self.m_handle.evaluate(obj)
+    }
+
+    pub fn reload(&self) -> Result<(), RulesError> {
+        self.m_handle.reload()
+    }
+
+    pub fn status(&self) -> Result<DaemonStatus, RulesError> {
+        self.m_handle.status()
+    }
+}
+
+// Stops both threads. Handles still held elsewhere get an error from then on.
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.m_stop_watcher.take();
+        if let Some(watcher) = self.m_watcher.take() {
+            let _ = watcher.join();
+        }
+
+        let _ = self.m_handle.m_sender.send(DaemonRequest::Stop);
+        if let Some(engine) = self.m_engine.take() {
+            let _ = engine.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::RequiredTagPolicy;
+    use std::fs;
+    use std::time::Instant;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+
+        fs::write(format!("{}/test.tags", test_dir), "- colour: red, blue").unwrap();
+        fs::write(format!("{}/test.rules", test_dir), "- colour = red").unwrap();
+
+        test_dir
+    }
+
+    fn object(colour: &str) -> Object {
+        let mut obj = Object::new();
+        obj.insert("colour".to_string(), vec![colour.to_string()]);
+        obj
+    }
+
+    // Wait for the watcher to pick up a change
+    fn wait_for_reloads(daemon: &Daemon, reloads: u64) -> DaemonStatus {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let status = daemon.status().unwrap();
+            if status.reloads >= reloads || Instant::now() > deadline {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_daemon() {
+        let test_dir = setup_test_env("test_orchestrator_daemon");
+        let daemon =
+            Orchestrator::daemon(Rules::new(&test_dir), Duration::from_millis(20)).unwrap();
+
+        let status = daemon.status().unwrap();
+        assert!(status.healthy);
+        assert_eq!(status.tag_count, 1);
+        assert_eq!(status.rule_count, 1);
+
+        let handle = daemon.handle();
+        let matched = thread::spawn(move || handle.evaluate(object("red")).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(matched.len(), 1);
+        assert!(daemon.evaluate(object("green")).is_err());
+
+        // Hot reload picks up the new value
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour: red, blue, green",
+        )
+        .unwrap();
+        let status = wait_for_reloads(&daemon, 1);
+        assert!(status.healthy);
+        assert!(daemon.evaluate(object("green")).unwrap().is_empty());
+
+        // A broken config leaves the last good one in place
+        fs::write(format!("{}/test.tags", test_dir), "- colour red").unwrap();
+        let status = wait_for_reloads(&daemon, 2);
+        assert!(!status.healthy);
+        assert!(status.last_error.is_some());
+        assert!(daemon.evaluate(object("green")).is_ok());
+        assert_eq!(daemon.status().unwrap().evaluations, 4);
+
+        let handle = daemon.handle();
+        drop(daemon);
+        assert!(handle.status().is_err());
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[test]
+    fn test_daemon_invalid_rules() {
+        let test_dir = setup_test_env("test_orchestrator_daemon_invalid_rules");
+        let rules_file = format!("{}/test.rules", test_dir);
+        let daemon =
+            Orchestrator::daemon(Rules::new(&test_dir), Duration::from_secs(3600)).unwrap();
+
+        // Rules are served as loaded, not read again for each request
+        fs::write(&rules_file, "- colour = red &").unwrap();
+        assert_eq!(daemon.evaluate(object("red")).unwrap().len(), 1);
+
+        // A broken rules file fails the reload, and the last good rules are still served
+        assert!(daemon.reload().is_err());
+        let status = daemon.status().unwrap();
+        assert!(!status.healthy);
+        assert!(status.last_error.unwrap().contains("test.rules:1"));
+        assert_eq!(daemon.evaluate(object("red")).unwrap().len(), 1);
+
+        fs::write(&rules_file, "- colour = blue").unwrap();
+        daemon.reload().unwrap();
+        assert!(daemon.status().unwrap().healthy);
+        assert!(daemon.evaluate(object("red")).unwrap().is_empty());
+
+        drop(daemon);
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[test]
+    fn test_daemon_required_tags() {
+        let test_dir = setup_test_env("test_orchestrator_daemon_required_tags");
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour: red, blue\n- size (required): small",
+        )
+        .unwrap();
+        let daemon =
+            Orchestrator::daemon(Rules::new(&test_dir), Duration::from_secs(3600)).unwrap();
+
+        // Objects missing a required tag match nothing under the default policy
+        assert!(daemon.evaluate(object("red")).unwrap().is_empty());
+
+        let mut rules = Rules::new(&test_dir);
+        rules.set_required_tag_policy(RequiredTagPolicy::Error);
+        let strict = Orchestrator::daemon(rules, Duration::from_secs(3600)).unwrap();
+        assert!(strict.evaluate(object("red")).is_err());
+
+        let mut obj = object("red");
+        obj.insert("size".to_string(), vec!["small".to_string()]);
+        assert_eq!(strict.evaluate(obj).unwrap().len(), 1);
+
+        drop(daemon);
+        drop(strict);
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[test]
+    fn test_daemon_invalid_config() {
+        let test_dir = setup_test_env("test_orchestrator_daemon_invalid");
+        fs::write(format!("{}/test.tags", test_dir), "- colour red").unwrap();
+
+        assert!(Orchestrator::daemon(Rules::new(&test_dir), DEFAULT_POLL_INTERVAL).is_err());
+
+        fs::write(format!("{}/test.tags", test_dir), "- colour: red").unwrap();
+        fs::write(format!("{}/test.rules", test_dir), "- colour = green").unwrap();
+        assert!(Orchestrator::daemon(Rules::new(&test_dir), DEFAULT_POLL_INTERVAL).is_err());
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
@@ -0,0 +1,21 @@
+// Business logic orchestration (state machine)
+use crate::{engine::Engine, err::RulesError, types::EvaluationOptions};
+
+#[cfg(feature = "watch")]
+mod daemon;
+
+#[cfg(feature = "watch")]
+pub use daemon::{DEFAULT_POLL_INTERVAL, Daemon, DaemonHandle};
+
+// Runs the engine once over the default config directory -- e.g. Orchestrator::run()
+pub struct Orchestrator;
+
+impl Orchestrator {
+    pub fn run() -> Result<(), RulesError> {
+        Self::run_with_options(EvaluationOptions::default())
+    }
+
+    pub fn run_with_options(options: EvaluationOptions) -> Result<(), RulesError> {
+        Engine::new("config").with_options(options).load()
+    }
+}
@@ -1,4 +1,5 @@
 pub mod objects;
+pub mod plugin;
 pub mod rules;
 pub mod tags;
 pub mod types;
@@ -1,7 +1,482 @@
 // Parser for objects in .yaml files in config dir
-use crate::{err::RulesError, types::Object};
+use crate::{
+    err::RulesError,
+    types::{
+        CaseFolding, ExclusionConflict, ExclusiveGroup, LoadedObject, Object, RequiredTagPolicy,
+        TagConstraintsMap, TagName, TagValues,
+    },
+    utils::file,
+};
+
+use serde_yaml::Value;
+use std::collections::HashMap;
+
+// Attribute value as strings -- e.g. "red" gives ["red"], [red, green] gives ["red", "green"]
+fn yaml_to_values(value: &Value, case_folding: CaseFolding) -> Result<Vec<String>, RulesError> {
+    match value {
+        Value::Sequence(items) => items
+            .iter()
+            .map(|item| yaml_scalar_to_string(item, case_folding))
+            .collect::<Result<Vec<String>, RulesError>>(),
+        other => Ok(vec![yaml_scalar_to_string(other, case_folding)?]),
+    }
+}
+
+fn yaml_scalar_to_string(value: &Value, case_folding: CaseFolding) -> Result<String, RulesError> {
+    match value {
+        Value::String(s) => Ok(case_folding.fold(s.trim())),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        other => Err(RulesError::ObjectParseError(format!(
+            "Object values must be scalars or lists of scalars, found {:?}",
+            other
+        ))),
+    }
+}
+
+// Object from a mapping of tags to values -- e.g. { colour: red, size: [small, large] }
+fn mapping_to_object(
+    attributes: &serde_yaml::Mapping,
+    case_folding: CaseFolding,
+) -> Result<Object, RulesError> {
+    let mut obj: Object = HashMap::new();
+    for (tag_name, values) in attributes {
+        obj.insert(
+            yaml_scalar_to_string(tag_name, case_folding)?,
+            yaml_to_values(values, case_folding)?,
+        );
+    }
+
+    Ok(obj)
+}
+
+// Parse a single object written as a YAML mapping -- e.g. "{ colour: red, size: [small] }".
+// Tag names and string values are folded per `case_folding`.
+pub fn parse_object_from_str(
+    content: &str,
+    case_folding: CaseFolding,
+) -> Result<Object, RulesError> {
+    let value: Value =
+        serde_yaml::from_str(content).map_err(|e| RulesError::ObjectParseError(e.to_string()))?;
+
+    let attributes = value.as_mapping().ok_or_else(|| {
+        RulesError::ObjectParseError("Object must be a mapping of tags to values".to_string())
+    })?;
+
+    mapping_to_object(attributes, case_folding)
+}
+
+// Parse the contents of one objects file -- e.g. "objects: { shapes: [ { colour: red } ] }"
+pub fn parse_objects_from_str(
+    path: &str,
+    content: &str,
+    case_folding: CaseFolding,
+) -> Result<Vec<LoadedObject>, RulesError> {
+    let context = |msg: String| RulesError::ObjectParseError(format!("{}: {}", path, msg));
+
+    let root: Value = serde_yaml::from_str(content).map_err(|e| context(e.to_string()))?;
+    if root.is_null() {
+        return Ok(Vec::new());
+    }
+
+    let groups = root
+        .get("objects")
+        .and_then(Value::as_mapping)
+        .ok_or_else(|| context("File must contain an 'objects' mapping".to_string()))?;
+
+    let mut objects: Vec<LoadedObject> = Vec::new();
+
+    for (obj_type, items) in groups {
+        let obj_type = yaml_scalar_to_string(obj_type, CaseFolding::Lowercase)
+            .map_err(|e| context(e.to_string()))?;
+        let items = items.as_sequence().ok_or_else(|| {
+            context(format!(
+                "Object type '{}' must contain a list of objects",
+                obj_type
+            ))
+        })?;
+
+        for (index, item) in items.iter().enumerate() {
+            let attributes = item.as_mapping().ok_or_else(|| {
+                context(format!(
+                    "Object {} of type '{}' must be a mapping of tags to values",
+                    index, obj_type
+                ))
+            })?;
+
+            let obj =
+                mapping_to_object(attributes, case_folding).map_err(|e| context(e.to_string()))?;
+
+            objects.push(LoadedObject {
+                file: path.to_string(),
+                obj_type: obj_type.clone(),
+                index,
+                object: obj,
+            });
+        }
+    }
+
+    Ok(objects)
+}
+
+// Parse every objects file matching `pattern` -- e.g. "config/*.yaml"
+pub fn parse_objects(
+    pattern: &str,
+    case_folding: CaseFolding,
+) -> Result<Vec<LoadedObject>, RulesError> {
+    let mut objects: Vec<LoadedObject> = Vec::new();
+
+    for (path, content) in file::read_files_in_dir_with_paths(pattern)? {
+        objects.extend(parse_objects_from_str(&path, &content, case_folding)?);
+    }
+
+    Ok(objects)
+}
+
+// Tags marked (required) which the object doesn't have, sorted by name. Names are compared
+// folded per `case_folding`.
+pub fn missing_required_tags(
+    obj: &Object,
+    tag_constraints: &TagConstraintsMap,
+    case_folding: CaseFolding,
+) -> Vec<TagName> {
+    let mut missing: Vec<TagName> = tag_constraints
+        .iter()
+        .filter(|(_, constraints)| constraints.required)
+        .map(|(tag_name, _)| case_folding.fold(tag_name))
+        .filter(|tag_name| !obj.keys().any(|k| case_folding.fold(k) == *tag_name))
+        .collect();
+
+    missing.sort();
+    missing
+}
+
+// Whether `obj` is evaluated at all, per `policy` if it's missing a tag marked (required): false
+// under RequiredTagPolicy::NoMatch, as it can't match any rule, and an error naming the tags under
+// RequiredTagPolicy::Error -- e.g. "Object is missing required TagName: colour"
+pub fn check_required_tags(
+    obj: &Object,
+    tag_constraints: &TagConstraintsMap,
+    case_folding: CaseFolding,
+    policy: RequiredTagPolicy,
+) -> Result<bool, RulesError> {
+    let missing = missing_required_tags(obj, tag_constraints, case_folding);
+    if missing.is_empty() {
+        return Ok(true);
+    }
+
+    match policy {
+        RequiredTagPolicy::NoMatch => Ok(false),
+        RequiredTagPolicy::Error => Err(RulesError::ObjectParseError(format!(
+            "Object is missing required TagName: {}",
+            missing.join(", ")
+        ))),
+    }
+}
+
+// Every exclusive group the object holds more than one member of. Groups are expected folded
+// per `case_folding`, as they are when loaded with the tags.
+pub fn check_exclusive_groups(
+    obj: &Object,
+    groups: &[ExclusiveGroup],
+    case_folding: CaseFolding,
+) -> Vec<ExclusionConflict> {
+    let fold = |s: &str| case_folding.fold(s);
+    let obj_values = |tag_name: &str| -> Vec<String> {
+        obj.iter()
+            .filter(|(k, _)| fold(k) == tag_name)
+            .flat_map(|(_, values)| values.iter().map(|v| fold(v)))
+            .collect()
+    };
+
+    groups
+        .iter()
+        .filter_map(|group| {
+            let found: Vec<String> = match group {
+                ExclusiveGroup::Values { tag_name, values } => {
+                    let present = obj_values(tag_name);
+                    values
+                        .iter()
+                        .filter(|v| present.contains(v))
+                        .cloned()
+                        .collect()
+                }
+                ExclusiveGroup::Tags { tag_names } => tag_names
+                    .iter()
+                    .filter(|t| obj.keys().any(|k| fold(k) == **t))
+                    .cloned()
+                    .collect(),
+            };
+
+            (found.len() > 1).then(|| ExclusionConflict {
+                group: group.clone(),
+                found,
+            })
+        })
+        .collect()
+}
+
+// Object tag names and values are folded per `case_folding` before being checked against `tags`
+pub fn validate_object(
+    obj: &Object,
+    tags: &HashMap<TagName, TagValues>,
+    tag_constraints: &TagConstraintsMap,
+    case_folding: CaseFolding,
+) -> Result<(), RulesError> {
+    check_required_tags(obj, tag_constraints, case_folding, RequiredTagPolicy::Error)?;
+
+    for (tag_name, obj_values) in obj {
+        let tag_name = case_folding.fold(tag_name);
+
+        let valid_values = tags.get(&tag_name).ok_or_else(|| {
+            RulesError::ObjectParseError(format!("Object contains invalid TagName: {}", tag_name))
+        })?;
+
+        let constraints = tag_constraints.get(&tag_name).cloned().unwrap_or_default();
+        if constraints.single && obj_values.len() > 1 {
+            return Err(RulesError::ObjectParseError(format!(
+                "Object has {} values for single-valued TagName '{}', expected at most one",
+                obj_values.len(),
+                tag_name
+            )));
+        }
+
+        for value in obj_values {
+            let value = case_folding.fold(value);
+            if !constraints.allows(valid_values, &value) {
+                return Err(RulesError::ObjectParseError(format!(
+                    "Object contains invalid TagValue: '{}' is not a valid value for TagName '{}'",
+                    value, tag_name
+                )));
+            }
+        }
+    }
 
-pub fn validate_object(obj: Object) -> Result<(), RulesError> {
-    // TODO: Check its tags are valid
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TagConstraints;
+    use regex::RegexBuilder;
+
+    fn create_test_tags() -> (HashMap<TagName, TagValues>, TagConstraintsMap) {
+        let mut tags = HashMap::new();
+        tags.insert(
+            "colour".to_string(),
+            vec!["red".to_string(), "blue".to_string()],
+        );
+        tags.insert("sku".to_string(), vec![]);
+
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "sku".to_string(),
+            TagConstraints {
+                pattern: Some(
+                    RegexBuilder::new(r"^[A-Z]{2}\d{4}$")
+                        .case_insensitive(true)
+                        .build()
+                        .unwrap(),
+                ),
+                ..Default::default()
+            },
+        );
+
+        (tags, constraints)
+    }
+
+    fn create_object(pairs: &[(&str, &str)]) -> Object {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_object_valid() {
+        let (tags, constraints) = create_test_tags();
+        let obj = create_object(&[("colour", "Red"), ("sku", "AB1234")]);
+        assert!(validate_object(&obj, &tags, &constraints, CaseFolding::default()).is_ok());
+    }
+
+    #[test]
+    fn test_validate_object_invalid_tag_name() {
+        let (tags, constraints) = create_test_tags();
+        let obj = create_object(&[("shape", "circle")]);
+
+        let result = validate_object(&obj, &tags, &constraints, CaseFolding::default());
+        if let Err(RulesError::ObjectParseError(msg)) = result {
+            assert!(msg.contains("invalid TagName"));
+        } else {
+            panic!("Expected ObjectParseError about invalid tag name");
+        }
+    }
+
+    #[test]
+    fn test_validate_object_value_not_matching_pattern() {
+        let (tags, constraints) = create_test_tags();
+        let obj = create_object(&[("sku", "A12345")]);
+
+        let result = validate_object(&obj, &tags, &constraints, CaseFolding::default());
+        if let Err(RulesError::ObjectParseError(msg)) = result {
+            assert!(msg.contains("a12345"));
+        } else {
+            panic!("Expected ObjectParseError about invalid tag value");
+        }
+    }
+
+    #[test]
+    fn test_parse_objects_from_str() {
+        let content = "# Comment\nobjects:\n  shapes:\n    - colour: [Red, green]\n      shape: rectangle\n\n    - colour: green\n  cars:\n    - doors: 3\n";
+        let objects =
+            parse_objects_from_str("objects.yaml", content, CaseFolding::default()).unwrap();
+
+        assert_eq!(objects.len(), 3);
+        assert_eq!(objects[0].obj_type, "shapes");
+        assert_eq!(objects[0].index, 0);
+        assert_eq!(
+            objects[0].object.get("colour"),
+            Some(&vec!["red".to_string(), "green".to_string()])
+        );
+        assert_eq!(objects[1].index, 1);
+        assert_eq!(objects[2].obj_type, "cars");
+        assert_eq!(objects[2].object.get("doors"), Some(&vec!["3".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_objects_from_str_invalid_structure() {
+        let missing_root = "shapes:\n  - colour: red\n";
+        let result = parse_objects_from_str("objects.yaml", missing_root, CaseFolding::default());
+        if let Err(RulesError::ObjectParseError(msg)) = result {
+            assert!(msg.contains("objects.yaml"));
+            assert!(msg.contains("'objects' mapping"));
+        } else {
+            panic!("Expected ObjectParseError about missing objects mapping");
+        }
+
+        let nested = "objects:\n  shapes:\n    - colour: { a: b }\n";
+        assert!(parse_objects_from_str("objects.yaml", nested, CaseFolding::default()).is_err());
+
+        let not_a_list = "objects:\n  shapes: red\n";
+        assert!(
+            parse_objects_from_str("objects.yaml", not_a_list, CaseFolding::default()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_object_from_str() {
+        let content = "{ colour: Red, size: [small, large], doors: 3 }";
+        let obj = parse_object_from_str(content, CaseFolding::default()).unwrap();
+        assert_eq!(obj.get("colour"), Some(&vec!["red".to_string()]));
+        assert_eq!(obj.get("size").map(Vec::len), Some(2));
+        assert_eq!(obj.get("doors"), Some(&vec!["3".to_string()]));
+
+        let obj = parse_object_from_str(content, CaseFolding::Preserve).unwrap();
+        assert_eq!(obj.get("colour"), Some(&vec!["Red".to_string()]));
+
+        assert!(parse_object_from_str("[red, blue]", CaseFolding::default()).is_err());
+        assert!(parse_object_from_str("{ colour: { a: b } }", CaseFolding::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_objects_from_str_empty() {
+        assert!(
+            parse_objects_from_str("objects.yaml", "", CaseFolding::default())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_validate_object_missing_required_tag() {
+        let (tags, mut constraints) = create_test_tags();
+        constraints.insert(
+            "colour".to_string(),
+            TagConstraints {
+                required: true,
+                ..Default::default()
+            },
+        );
+
+        let obj = create_object(&[("sku", "AB1234")]);
+        assert_eq!(
+            missing_required_tags(&obj, &constraints, CaseFolding::default()),
+            vec!["colour".to_string()]
+        );
+
+        let result = validate_object(&obj, &tags, &constraints, CaseFolding::default());
+        if let Err(RulesError::ObjectParseError(msg)) = result {
+            assert!(msg.contains("missing required TagName: colour"));
+        } else {
+            panic!("Expected ObjectParseError about missing required tag");
+        }
+
+        let obj = create_object(&[("Colour", "red")]);
+        assert!(missing_required_tags(&obj, &constraints, CaseFolding::default()).is_empty());
+        assert_eq!(
+            missing_required_tags(&obj, &constraints, CaseFolding::Preserve),
+            vec!["colour".to_string()]
+        );
+        assert!(validate_object(&obj, &tags, &constraints, CaseFolding::default()).is_ok());
+    }
+
+    #[test]
+    fn test_validate_object_single_valued_tag() {
+        let (tags, mut constraints) = create_test_tags();
+        constraints.insert(
+            "colour".to_string(),
+            TagConstraints {
+                single: true,
+                ..Default::default()
+            },
+        );
+
+        let mut obj = create_object(&[("colour", "red")]);
+        assert!(validate_object(&obj, &tags, &constraints, CaseFolding::default()).is_ok());
+
+        obj.insert(
+            "colour".to_string(),
+            vec!["red".to_string(), "blue".to_string()],
+        );
+        let result = validate_object(&obj, &tags, &constraints, CaseFolding::default());
+        if let Err(RulesError::ObjectParseError(msg)) = result {
+            assert!(msg.contains("2 values for single-valued TagName 'colour'"));
+        } else {
+            panic!("Expected ObjectParseError about a single-valued tag");
+        }
+    }
+
+    #[test]
+    fn test_check_exclusive_groups() {
+        let groups = vec![
+            ExclusiveGroup::Values {
+                tag_name: "colour".to_string(),
+                values: vec!["red".to_string(), "blue".to_string()],
+            },
+            ExclusiveGroup::Tags {
+                tag_names: vec!["discount".to_string(), "clearance".to_string()],
+            },
+        ];
+
+        let mut obj = create_object(&[("discount", "ten"), ("Clearance", "yes")]);
+        obj.insert(
+            "colour".to_string(),
+            vec!["Red".to_string(), "blue".to_string()],
+        );
+
+        let conflicts = check_exclusive_groups(&obj, &groups, CaseFolding::default());
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(
+            conflicts[0].found,
+            vec!["red".to_string(), "blue".to_string()]
+        );
+        assert_eq!(
+            conflicts[1].found,
+            vec!["discount".to_string(), "clearance".to_string()]
+        );
+
+        let obj = create_object(&[("colour", "red"), ("discount", "ten")]);
+        assert!(check_exclusive_groups(&obj, &groups, CaseFolding::default()).is_empty());
+    }
+}
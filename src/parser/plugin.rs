@@ -0,0 +1,61 @@
+// Named functions usable in rules -- e.g. "-created_at before(2024-01-01)"
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Registered plugins by function name
+pub type PluginMap = HashMap<String, Arc<dyn RulePlugin>>;
+
+// Function a rule can call on a tag's values. The rule "-created_at before(2024-01-01)" calls the
+// plugin named "before" with the object's created_at values and the arguments ["2024-01-01"].
+pub trait RulePlugin: Send + Sync {
+    // Name used in rules -- e.g. "before"
+    fn name(&self) -> &str;
+
+    // Check the arguments when a rule is parsed, returning why they're invalid
+    fn validate(&self, _args: &[String]) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Whether an object with `values` for the tag satisfies the call. `values` is empty when the
+    // object doesn't have the tag.
+    fn call(&self, values: &[String], args: &[String]) -> bool;
+}
+
+// Function name and arguments of a call -- e.g. "between(1, 5)" gives ("between", ["1", "5"]).
+// A name is letters, digits and underscores, so a regex like "^(a|b)" isn't a call.
+pub fn parse_call(call: &str) -> Option<(&str, Vec<String>)> {
+    let (name, rest) = call.split_once('(')?;
+    let args = rest.strip_suffix(')')?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+
+    let args = if args.trim().is_empty() {
+        Vec::new()
+    } else {
+        args.split(',').map(|arg| arg.trim().to_string()).collect()
+    };
+
+    Some((name, args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_call() {
+        assert_eq!(
+            parse_call("before(2024-01-01)"),
+            Some(("before", vec!["2024-01-01".to_string()]))
+        );
+        assert_eq!(
+            parse_call("between(1, 5)"),
+            Some(("between", vec!["1".to_string(), "5".to_string()]))
+        );
+        assert_eq!(parse_call("is_set()"), Some(("is_set", Vec::new())));
+        assert_eq!(parse_call("red"), None);
+        assert_eq!(parse_call("before(2024"), None);
+    }
+}
@@ -1,1508 +1,7506 @@
 // Parser for .rules files
-use crate::err::RulesError;
-use crate::parser::types::{AstRule, MappedRuleTokens, Node, Token, TokenDepth, TokenType};
-use crate::types::{self, ComparisonOp, SubRule};
+use crate::api::audit;
+use crate::err::{self, RulesError};
+use crate::parser::objects;
+use crate::parser::plugin::{self, PluginMap, RulePlugin};
+use crate::parser::types::{
+    AstRule, Lexeme, LexemeKind, MappedRuleTokens, Node, Span, Token, TokenDepth, TokenType,
+};
+use crate::types::{
+    self, CaseFolding, Clause, CnfRule, ComparisonOp, CoverageReport, Diagnostic, DiagnosticCode,
+    EvalContext, EvalMode, ExclusiveGroup, ExplanationNode, GrammarVersion, InlineTest, LoadedRule,
+    MatchExplanation, MissingTagPolicy, OperatorPrecedence, RuleAnnotation, RuleExamples,
+    RuleLimits, RuleTrace, SubRule, TagEdit, TraceStep, Truth, TruthTable, TruthTableRow,
+    UnknownPolicy, ValidationReport,
+};
 use crate::utils::file;
 use crate::utils::string;
+use crate::utils::time;
 
-use std::collections::HashMap;
-use std::sync::LazyLock;
+use regex::{Regex, RegexBuilder};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
 
-static TOKEN_PRECEDENCE: LazyLock<HashMap<&str, i32>> = LazyLock::new(|| {
-    let mut m = HashMap::new();
-    m.insert("|", 0);
-    m.insert("&", 1);
-    m.insert("=", 2);
-    m.insert("!", 2);
-    m
-});
+// Most conjunctions a rule's disjunctive form is expanded to when checking satisfiability.
+// Larger rules are assumed satisfiable.
+const MAX_CONJUNCTIONS: usize = 4096;
+
+// Most combinations of tag values checked for coverage
+const MAX_COVERAGE_COMBINATIONS: usize = 100_000;
+
+// Most rows in a truth table
+const MAX_TRUTH_TABLE_ROWS: usize = 10_000;
+
+// Most values a range expands to -- e.g. "floor = 1..1000"
+const MAX_RANGE_VALUES: u64 = 1000;
+
+// Random objects tried per example wanted, before settling for fewer
+const EXAMPLE_ATTEMPTS: usize = 100;
 
 // All valid operator characters in rule syntax
-const ALL_OP_CHARS: &[char] = &['(', ')', '=', '!', '&', '|', ','];
-// Operators that expect a TagValue on the right-hand side
-const RHS_CHARS: &[char] = &['=', '!', ',', ')'];
+const ALL_OP_CHARS: &[char] = &['(', ')', '=', '!', '<', '>', '~', '?', '&', '|', ',', '→'];
 // Operators that expect a TagName on the left-hand side
-const LHS_CHARS: &[char] = &['&', '|', '('];
-
-pub struct RuleParser {
-    m_mapped_tags: HashMap<types::TagName, types::TagValues>,
+const LHS_CHARS: &[char] = &['&', '|', '(', '→'];
+
+// "If left then right" between two expressions -- e.g. "- colour = red -> size = large", read as
+// "!(colour = red) | size = large". The lexer reads it as IMPLIES_CHAR, so it's one character like
+// the other operators.
+pub const IMPLIES: &str = "->";
+const IMPLIES_CHAR: char = '→';
+
+// Comment under a rule giving an example object -- e.g. "#test: match { colour: red }"
+const TEST_PREFIX: &str = "#test:";
+
+// Comment pulling in the rules of another file in the config directory -- e.g.
+// "#include shared/common.rules"
+const INCLUDE_PREFIX: &str = "#include";
+
+// Values filled in when a rule is evaluated -- e.g. "- region = $ctx.region" from the evaluation
+// context, or "- holiday = $today" as the evaluation date
+pub const VARIABLE_PREFIX: char = '$';
+pub const CONTEXT_VARIABLE_PREFIX: &str = "$ctx.";
+pub const TODAY_VARIABLE: &str = "$today";
+
+// Compares against another tag of the same object -- e.g. "- shipping_colour = @ordered_colour"
+pub const TAG_REFERENCE_PREFIX: char = '@';
+
+// The tag `value` refers to, if it refers to one -- e.g. "ordered_colour" for "@ordered_colour"
+pub fn tag_reference(value: &str) -> Option<&str> {
+    value
+        .strip_prefix(TAG_REFERENCE_PREFIX)
+        .filter(|name| !name.is_empty())
 }
 
-impl RuleParser {
-    pub fn new(tags: HashMap<types::TagName, types::TagValues>) -> Self {
-        RuleParser {
-            m_mapped_tags: tags,
-        }
+// Errors if `value` isn't a known variable -- e.g. "$ctx.region" or "$today", but not "$region"
+fn check_variable(value: &str) -> Result<(), RulesError> {
+    let valid_context_name = value
+        .strip_prefix(CONTEXT_VARIABLE_PREFIX)
+        .is_some_and(|name| {
+            !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        });
+    if value == TODAY_VARIABLE || valid_context_name {
+        return Ok(());
     }
 
-    pub fn validate_rule(&self, rule: &str) -> Result<(), RulesError> {
-        self.validate_rule_internal(rule)
+    Err(RulesError::RuleParseError(format!(
+        "Unknown variable '{}', expected {} or {}<name>",
+        value, TODAY_VARIABLE, CONTEXT_VARIABLE_PREFIX
+    )))
+}
+
+// Between a macro's name and the expression it stands for -- e.g. "$warm = colour = red"
+const MACRO_SEPARATOR: char = '=';
+
+// Named expressions defined in one .rules file, each with the line defining it -- e.g.
+// "$warm = colour = red | colour = orange", which its rules can use as "- $warm & size = large"
+#[derive(Debug, Default)]
+pub struct Macros {
+    m_bodies: HashMap<String, (usize, String)>,
+}
+
+impl Macros {
+    // `rule` with each macro it uses replaced by the macro's expression in parentheses -- e.g.
+    // "- (colour = red | colour = orange) & size = large" for "- $warm & size = large".
+    // Variables, unknown names and quoted values are left as they are.
+    pub fn expand(&self, rule: &str) -> Result<String, RulesError> {
+        self.expand_with(rule, &mut Vec::new())
     }
 
-    fn validate_rule_internal(&self, line: &str) -> Result<(), RulesError> {
-        if file::line_blank_or_comment(line) {
-            return Ok(());
+    fn expand_with(&self, rule: &str, stack: &mut Vec<String>) -> Result<String, RulesError> {
+        if self.m_bodies.is_empty() || !rule.contains(VARIABLE_PREFIX) {
+            return Ok(rule.to_string());
         }
 
-        let original_line = line.to_string();
+        let mut expanded = String::new();
+        let mut chars = rule.chars().peekable();
+        let mut quoted = false;
+        let mut escaped = false;
+
+        while let Some(c) = chars.next() {
+            if c != VARIABLE_PREFIX || quoted {
+                match c {
+                    _ if escaped => escaped = false,
+                    string::VALUE_ESCAPE if quoted => escaped = true,
+                    string::VALUE_QUOTE => quoted = !quoted,
+                    _ => {}
+                }
+                expanded.push(c);
+                continue;
+            }
 
-        let line =
-            string::normalise(line).map_err(|e| Self::add_error_context(e, &original_line))?;
+            let mut name = String::new();
+            while let Some(&next) = chars.peek()
+                && (next.is_ascii_alphanumeric() || next == '_')
+            {
+                name.push(next);
+                chars.next();
+            }
 
-        let tokens: MappedRuleTokens =
-            Self::map_rule_tokens(&Self::tokenise_rule(&line.to_string())?);
+            if self.m_bodies.contains_key(&name) {
+                expanded.push('(');
+                expanded.push_str(&self.expand_macro(&name, stack)?);
+                expanded.push(')');
+            } else {
+                expanded.push(c);
+                expanded.push_str(&name);
+            }
+        }
 
-        Self::check_rule_syntax(&tokens).map_err(|e| Self::add_error_context(e, &original_line))?;
+        Ok(expanded)
+    }
 
-        self.check_valid_tags(&tokens)
-            .map_err(|e| Self::add_error_context(e, &original_line))?;
+    // A macro's expression with the macros it uses expanded, erroring if it uses itself -- e.g.
+    // "$a = $b & size = large" and "$b = $a | colour = red"
+    fn expand_macro(&self, name: &str, stack: &mut Vec<String>) -> Result<String, RulesError> {
+        if stack.iter().any(|used| used == name) {
+            let cycle: Vec<String> = stack
+                .iter()
+                .map(String::as_str)
+                .chain([name])
+                .map(|used| format!("{}{}", VARIABLE_PREFIX, used))
+                .collect();
+            return Err(RulesError::RuleParseError(format!(
+                "Macro cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
 
-        Ok(())
+        let (_, body) = &self.m_bodies[name];
+        stack.push(name.to_string());
+        let expanded = self.expand_with(body, stack);
+        stack.pop();
+        expanded
     }
+}
 
-    fn add_error_context(error: RulesError, rule: &str) -> RulesError {
-        match error {
-            RulesError::RuleParseError(msg) => {
-                RulesError::RuleParseError(format!("'{}': {}", rule, msg))
-            }
-            other => other,
-        }
+// Name and expression of the macro `line` defines, if it defines one -- e.g. ("warm", "colour =
+// red | colour = orange") for "$warm = colour = red | colour = orange"
+pub fn get_macro_from_line(line: &str) -> Result<Option<(&str, &str)>, RulesError> {
+    let Some(definition) = line.trim().strip_prefix(VARIABLE_PREFIX) else {
+        return Ok(None);
+    };
+
+    let invalid = || {
+        RulesError::RuleParseError(format!(
+            "Invalid macro: '{}', expected a definition like $warm = colour = red | colour = orange",
+            line.trim()
+        ))
+    };
+    let (name, body) = definition.split_once(MACRO_SEPARATOR).ok_or_else(invalid)?;
+    let (name, body) = (name.trim(), body.trim());
+    if name.is_empty()
+        || body.is_empty()
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(invalid());
     }
 
-    fn get_expected_token_type(
-        parsed_tokens: &Vec<String>,
-        paren_depth: i32,
-    ) -> Result<TokenType, RulesError> {
-        // If no tokens yet, first token should be TagName or opening paren
-        if parsed_tokens.is_empty() {
-            return Ok(TokenType::TagName);
-        }
+    // $today and $ctx.<name> stay variables
+    let variable_names = [TODAY_VARIABLE, CONTEXT_VARIABLE_PREFIX].map(|variable| {
+        variable
+            .trim_start_matches(VARIABLE_PREFIX)
+            .trim_end_matches('.')
+    });
+    if variable_names.contains(&name) {
+        return Err(RulesError::RuleParseError(format!(
+            "Macro name '{}{}' is reserved for variables",
+            VARIABLE_PREFIX, name
+        )));
+    }
 
-        let last_token = parsed_tokens.last().unwrap();
+    Ok(Some((name, body)))
+}
 
-        // Last token is an operator
-        let c = if last_token.len() == 1 {
-            last_token.chars().next()
-        } else {
-            None
+// Macros defined anywhere in the contents of one .rules file, so rules can use macros defined
+// below them. Every macro is expanded once, so cycles are found even in macros no rule uses.
+pub fn parse_macros(path: &str, content: &str) -> Result<Macros, RulesError> {
+    let located = |line: usize| {
+        move |e: RulesError| e.map_rule_message(|msg| format!("{}:{}: {}", path, line, msg))
+    };
+
+    let mut macros = Macros::default();
+    for (i, line) in content.lines().enumerate() {
+        let Some((name, body)) = get_macro_from_line(line).map_err(located(i + 1))? else {
+            continue;
         };
+        if let Some((defined_at, _)) = macros.m_bodies.get(name) {
+            return Err(located(i + 1)(RulesError::RuleParseError(format!(
+                "Macro '{}{}' is already defined at line {}",
+                VARIABLE_PREFIX, name, defined_at
+            ))));
+        }
+        macros
+            .m_bodies
+            .insert(name.to_string(), (i + 1, body.to_string()));
+    }
 
-        if let Some(ch) = c {
-            // Last token is an operator
-            if ALL_OP_CHARS.contains(&ch) {
-                if ch == '(' {
-                    // After '(', could be TagName or another '(' for nesting
-                    Ok(TokenType::TagName) // Both '(' and TagName are valid here
-                } else if ch == ')' {
-                    // After ')', could be LogicalOp, another ')', or end of expression
-                    if paren_depth > 0 {
-                        // Still inside parens, could be ')' or LogicalOp
-                        Ok(TokenType::LogicalOp) // Accept both
-                    } else {
-                        // All parens closed, must be LogicalOp or end
-                        Ok(TokenType::LogicalOp)
-                    }
-                } else if RHS_CHARS.contains(&ch) {
-                    Ok(TokenType::TagValue)
-                } else if LHS_CHARS.contains(&ch) {
-                    Ok(TokenType::TagName)
-                } else {
-                    Err(RulesError::RuleParseError(
-                        format!("Invalid token encountered: {}", ch).to_string(),
-                    ))
-                }
-            } else {
-                Err(RulesError::RuleParseError(
-                    format!("Invalid token encountered: {}", ch).to_string(),
-                ))
+    let mut defined: Vec<(&String, &usize)> = macros
+        .m_bodies
+        .iter()
+        .map(|(name, (line, _))| (name, line))
+        .collect();
+    defined.sort_by_key(|(_, line)| **line);
+    for (name, line) in defined {
+        macros
+            .expand_macro(name, &mut Vec::new())
+            .map_err(located(*line))?;
+    }
+
+    Ok(macros)
+}
+
+// Annotations on the rule below, if `line` holds any -- e.g. "@on_match notify.rhai", or several
+// written as calls -- e.g. `@description("Red items") @severity(high)`
+pub fn get_annotations_from_line(line: &str) -> Result<Option<Vec<RuleAnnotation>>, RulesError> {
+    let Some(annotation) = line.trim().strip_prefix('@') else {
+        return Ok(None);
+    };
+
+    let name_end = annotation
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(annotation.len());
+    if annotation[name_end..].starts_with('(') {
+        return get_annotation_calls(line.trim()).map(Some);
+    }
+
+    let (name, value) = annotation
+        .split_once(char::is_whitespace)
+        .unwrap_or((annotation, ""));
+
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(RulesError::RuleParseError(format!(
+            "Invalid annotation name: '@{}'",
+            name
+        )));
+    }
+
+    Ok(Some(vec![RuleAnnotation {
+        name: name.to_lowercase(),
+        value: value.trim().to_string(),
+    }]))
+}
+
+// Annotations written as calls, with quoted values able to hold spaces and parentheses -- e.g.
+// `@description("Red (or pink) items") @severity(high)`
+fn get_annotation_calls(line: &str) -> Result<Vec<RuleAnnotation>, RulesError> {
+    let invalid = || {
+        RulesError::RuleParseError(format!(
+            "Invalid annotations: '{}', expected annotations like @severity(high)",
+            line
+        ))
+    };
+
+    let mut chars = line.chars().peekable();
+    let mut annotations: Vec<RuleAnnotation> = Vec::new();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.next() {
+            Some('@') => {}
+            Some(_) => return Err(invalid()),
+            None => break,
+        }
+
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+        if name.is_empty() || chars.next() != Some('(') {
+            return Err(invalid());
+        }
+
+        let value = if chars.next_if_eq(&string::VALUE_QUOTE).is_some() {
+            let value = string::read_quoted(&mut chars).ok_or_else(invalid)?;
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.next() != Some(')') {
+                return Err(invalid());
             }
+            value
         } else {
-            // Last token is a word, check operator before it
-            if parsed_tokens.len() < 2 {
-                // Only one token (the word itself), next should be a comparison operator
-                return Ok(TokenType::ComparisonOp);
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some(')') => break,
+                    Some(c) => value.push(c),
+                    None => return Err(invalid()),
+                }
             }
+            value.trim().to_string()
+        };
 
-            let second_to_last_token = &parsed_tokens[parsed_tokens.len() - 2];
-            if second_to_last_token.len() > 1 {
-                return Err(RulesError::RuleParseError(
-                    format!(
-                        "Expected operator but got string instead: {}",
-                        second_to_last_token
-                    )
-                    .to_string(),
-                ));
-            }
+        annotations.push(RuleAnnotation {
+            name: name.to_lowercase(),
+            value,
+        });
+    }
 
-            let c: char = second_to_last_token.chars().next().unwrap();
+    Ok(annotations)
+}
 
-            if c == '(' {
-                Ok(TokenType::ComparisonOp)
-            } else if c == ')' {
-                Ok(TokenType::TagName)
-            } else if RHS_CHARS.contains(&c) {
-                Ok(TokenType::LogicalOp)
-            } else if LHS_CHARS.contains(&c) {
-                Ok(TokenType::ComparisonOp)
-            } else {
-                Err(RulesError::RuleParseError(
-                    format!("Invalid token encountered: {}", c).to_string(),
-                ))
-            }
-        }
+// Check the value of an annotation the engine understands -- e.g. "@expires 2025-01-01"
+pub fn check_annotation(annotation: &RuleAnnotation) -> Result<(), RulesError> {
+    let is_time = [types::EFFECTIVE_FROM, types::EXPIRES].contains(&annotation.name.as_str());
+    if is_time && time::parse_timestamp(&annotation.value).is_none() {
+        return Err(RulesError::RuleParseError(format!(
+            "Invalid timestamp for @{}: '{}', expected a date like 2025-01-01",
+            annotation.name, annotation.value
+        )));
     }
 
-    fn tokenise_rule(rule: &String) -> Result<Vec<String>, RulesError> {
-        let mut parsed_tokens: Vec<String> = Vec::new();
-        let mut current_word = String::new();
-        let mut paren_depth = 0;
+    let is_name = |value: &str| {
+        !value.is_empty()
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    };
+    let is_weight = |value: &str| value.parse::<f64>().is_ok_and(f64::is_finite);
+    if annotation.name == types::WEIGHT && !is_weight(&annotation.value) {
+        return Err(RulesError::RuleParseError(format!(
+            "Invalid weight for @weight: '{}', expected a number like 2.5",
+            annotation.value
+        )));
+    }
 
-        // For comma expansion
-        let mut last_tag_name: Option<String> = None;
-        let mut last_comparison_op: Option<String> = None;
-
-        for c in rule.trim().chars() {
-            if ALL_OP_CHARS.contains(&c) {
-                if !current_word.is_empty() {
-                    let expected_token_type =
-                        Self::get_expected_token_type(&parsed_tokens, paren_depth)?;
-                    let token = current_word.trim().to_string();
-                    parsed_tokens.push(token.clone());
-
-                    if expected_token_type == TokenType::TagName {
-                        last_tag_name = Some(token);
-                    }
+    if annotation.name == types::SEVERITY && types::Severity::parse(&annotation.value).is_none() {
+        return Err(RulesError::RuleParseError(format!(
+            "Invalid severity for @severity: '{}', expected low, medium, high or critical",
+            annotation.value
+        )));
+    }
 
-                    current_word.clear();
-                }
+    if annotation.name == types::DRAFT && !is_name(&annotation.value) {
+        return Err(RulesError::RuleParseError(format!(
+            "Invalid name for @draft: '{}', expected a name like red_large",
+            annotation.value
+        )));
+    }
 
-                // Expand comma to regular OR expression
-                if c == ',' {
-                    let tag_name = last_tag_name.as_ref().ok_or_else(|| {
-                        RulesError::RuleParseError(
-                            "Comma must follow a complete tag comparison".to_string(),
-                        )
-                    })?;
-                    let comparison_op = last_comparison_op.as_ref().ok_or_else(|| {
-                        RulesError::RuleParseError(
-                            "Comma must follow a complete tag comparison".to_string(),
-                        )
-                    })?;
+    Ok(())
+}
 
-                    parsed_tokens.push("|".to_string());
-                    parsed_tokens.push(tag_name.to_string());
-                    parsed_tokens.push(comparison_op.to_string());
+// @effective_from and @expires must be timestamps, with the rule in effect for some time
+fn check_time_scope(loaded: &LoadedRule) -> Result<(), RulesError> {
+    for annotation in &loaded.annotations {
+        check_annotation(annotation)?;
+    }
 
-                    continue;
-                }
+    if let (Some(from), Some(expires)) = (loaded.effective_from(), loaded.expires())
+        && from >= expires
+    {
+        return Err(RulesError::RuleParseError(
+            "Rule expires before it takes effect".to_string(),
+        ));
+    }
 
-                let expected_token_type =
-                    Self::get_expected_token_type(&parsed_tokens, paren_depth)?;
-                let token = c.to_string();
-                parsed_tokens.push(token.clone());
+    Ok(())
+}
 
-                if expected_token_type == TokenType::ComparisonOp {
-                    last_comparison_op = Some(token);
-                }
+// Rules highest priority first, ties in file then line order, so conflicting matches always
+// resolve the same way -- e.g. "-[10] colour = red" before "- colour = red" in an earlier file
+pub fn sort_by_priority(rules: &mut [LoadedRule]) {
+    rules.sort_by(priority_order);
+}
 
-                if c == '(' {
-                    paren_depth += 1;
-                } else if c == ')' {
-                    paren_depth -= 1;
-                    if paren_depth < 0 {
-                        return Err(RulesError::RuleParseError(
-                            "Unmatched closing parenthesis".to_string(),
-                        ));
-                    }
-                }
-            } else if c == ' ' {
-                if !current_word.is_empty() {
-                    let expected_token_type =
-                        Self::get_expected_token_type(&parsed_tokens, paren_depth)?;
-                    let token = current_word.trim().to_string();
-                    parsed_tokens.push(token.clone());
-
-                    if expected_token_type == TokenType::TagName {
-                        last_tag_name = Some(token);
-                    }
+// The order sort_by_priority puts two rules in
+pub fn priority_order(a: &LoadedRule, b: &LoadedRule) -> std::cmp::Ordering {
+    b.priority()
+        .cmp(&a.priority())
+        .then_with(|| a.file.cmp(&b.file))
+        .then_with(|| a.line.cmp(&b.line))
+}
 
-                    current_word.clear();
-                }
-            } else {
-                current_word.push(c);
-            }
+// A rule's error with its file, line, any column and any name in front -- e.g.
+// "my_rules.rules:3: Invalid tag: colr" or "my_rules.rules:3:12 (promo_1): Expected a tag name,
+// found '&'". A column at the end of the error is moved in front.
+pub fn rule_error_at(loaded: &LoadedRule, error: RulesError) -> RulesError {
+    error.map_rule_message(|msg| {
+        let (msg, position) = match err::split_column(&msg) {
+            Some((msg, column)) => (msg, format!("{}:{}:{}", loaded.file, loaded.line, column)),
+            None => (msg.as_str(), format!("{}:{}", loaded.file, loaded.line)),
+        };
+        match loaded.name() {
+            Some(name) => format!("{} ({}): {}", position, name, msg),
+            None => format!("{}: {}", position, msg),
         }
+    })
+}
 
-        if !current_word.is_empty() {
-            let token = current_word.trim().to_string();
-            parsed_tokens.push(token);
-        }
+// Named rules must have different names across every file -- e.g. two "promo_1: ..." rules
+pub fn check_rule_names(rules: &[LoadedRule]) -> Result<(), RulesError> {
+    let mut report = ValidationReport::default();
+    report_duplicate_names(rules, &mut report);
+    report.first_error()
+}
 
-        if paren_depth != 0 {
-            return Err(RulesError::RuleParseError(
-                "Unmatched opening parenthesis".to_string(),
+// As check_rule_names, adding every rule reusing a name to `report`
+pub fn report_duplicate_names(rules: &[LoadedRule], report: &mut ValidationReport) {
+    let mut seen: HashMap<&str, &LoadedRule> = HashMap::new();
+
+    for loaded in rules {
+        let Some(name) = loaded.name() else {
+            continue;
+        };
+        if let Some(first) = seen.get(name) {
+            let error = RulesError::RuleParseError(format!(
+                "{}:{}: Rule name '{}' is already used at {}:{}",
+                loaded.file, loaded.line, name, first.file, first.line
             ));
+            report.add(&loaded.file, loaded.line, error);
+        } else {
+            seen.insert(name, loaded);
         }
-
-        Ok(parsed_tokens)
     }
+}
 
-    fn map_rule_tokens(tokens: &Vec<String>) -> MappedRuleTokens {
-        let mut mapped_token_list: Vec<(String, TokenType, TokenDepth)> = Vec::new();
-        let mut parsed_tokens: Vec<String> = Vec::new();
-        let mut paren_depth = 0;
+// Rules in the contents of one .rules file, each with the annotations directly above it -- e.g.
+// "@on_match notify.rhai\n- colour = red". Rules themselves aren't validated, but have the
+// file's macros expanded.
+pub fn parse_rules_from_str(path: &str, content: &str) -> Result<Vec<LoadedRule>, RulesError> {
+    let mut report = ValidationReport::default();
+    let rules = read_rules_from_str(path, content, &mut report);
+    report.first_error()?;
 
-        for token in tokens {
-            let expected_token_type = Self::get_expected_token_type(&parsed_tokens, paren_depth);
-            parsed_tokens.push(token.clone());
-            mapped_token_list.push((token.clone(), expected_token_type.unwrap(), paren_depth));
+    Ok(rules)
+}
 
-            if token == "(" {
-                paren_depth += 1;
-            } else if token == ")" {
-                paren_depth -= 1;
-            }
+// As parse_rules_from_str, but a line which fails is added to `report` and the rest of the file
+// still read. A file whose macros fail gives no rules, as they may use them.
+pub fn read_rules_from_str(
+    path: &str,
+    content: &str,
+    report: &mut ValidationReport,
+) -> Vec<LoadedRule> {
+    let located = |line: usize| {
+        move |e: RulesError| e.map_rule_message(|msg| format!("{}:{}: {}", path, line, msg))
+    };
+
+    let macros = match parse_macros(path, content) {
+        Ok(macros) => macros,
+        Err(e) => {
+            let line = e.location().map_or(1, |location| location.line);
+            report.add(path, line, e);
+            return Vec::new();
         }
+    };
+    let mut rules: Vec<LoadedRule> = Vec::new();
+    let mut annotations: Vec<RuleAnnotation> = Vec::new();
+    let mut last_annotation_line = 0;
 
-        mapped_token_list
-    }
+    for (i, line) in content.lines().enumerate() {
+        if file::line_blank_or_comment(line) {
+            continue;
+        }
 
-    fn check_rule_syntax(tokens: &MappedRuleTokens) -> Result<(), RulesError> {
-        let mut prev_token: Option<&TokenType> = None;
+        // Macro definitions were read by parse_macros
+        if line.trim_start().starts_with(VARIABLE_PREFIX) {
+            if !annotations.is_empty() {
+                break;
+            }
+            continue;
+        }
 
-        for (key, token_type, _paren_depth) in tokens.iter() {
-            if key == "(" || key == ")" {
+        match get_annotations_from_line(line) {
+            Ok(Some(line_annotations)) => {
+                annotations.extend(line_annotations);
+                last_annotation_line = i + 1;
                 continue;
             }
-
-            match (prev_token, token_type) {
-                // Valid transitions
-                (None, TokenType::TagName) => {}
-                (Some(TokenType::TagName), TokenType::ComparisonOp) => {}
-                (Some(TokenType::ComparisonOp), TokenType::TagValue) => {}
-                (Some(TokenType::TagValue), TokenType::LogicalOp) => {}
-                (Some(TokenType::LogicalOp), TokenType::TagName) => {}
-
-                // Invalid transitions
-                (None, _) => {
-                    return Err(RulesError::RuleParseError(format!(
-                        "Rule must start with a tag name, found {:?}",
-                        token_type
-                    )));
-                }
-                (Some(prev), current) => {
-                    return Err(RulesError::RuleParseError(format!(
-                        "Invalid token sequence: {:?} followed by {:?}",
-                        prev, current
-                    )));
-                }
+            Ok(None) => {}
+            Err(e) => {
+                report.add(path, i + 1, located(i + 1)(e));
+                continue;
             }
-
-            prev_token = Some(token_type);
         }
 
-        match prev_token {
-            Some(TokenType::TagValue) => Ok(()),
-            Some(other) => Err(RulesError::RuleParseError(format!(
-                "Rule must end with a tag value, ended with {:?}",
-                other
-            ))),
-            None => Err(RulesError::RuleParseError("Empty rule".to_string())),
+        // The annotations above a rule which fails are dropped with it
+        let annotations = std::mem::take(&mut annotations);
+        let rule = match macros.expand(line.trim()) {
+            Ok(rule) => rule,
+            Err(e) => {
+                report.add(path, i + 1, located(i + 1)(e));
+                continue;
+            }
+        };
+        let loaded = LoadedRule {
+            file: path.to_string(),
+            line: i + 1,
+            rule,
+            annotations,
+        };
+        match string::split_name(&loaded.rule).and_then(|_| check_time_scope(&loaded)) {
+            Ok(_) => rules.push(loaded),
+            Err(e) => report.add(path, i + 1, located(i + 1)(e)),
         }
     }
 
-    fn check_valid_tags(&self, tokens: &MappedRuleTokens) -> Result<(), RulesError> {
-        let mut last_tag_name: Option<String> = None;
+    if !annotations.is_empty() {
+        let error = RulesError::RuleParseError(format!(
+            "{}:{}: Annotation isn't followed by a rule",
+            path, last_annotation_line
+        ));
+        report.add(path, last_annotation_line, error);
+    }
 
-        for (key, token_type, _paren_depth) in tokens.iter() {
-            let key = String::from(key).to_lowercase();
-            if *token_type == TokenType::TagName {
-                if key == "(" || key == ")" {
-                    continue;
-                }
+    rules
+}
 
-                if !self.m_mapped_tags.contains_key(&key) {
-                    return Err(RulesError::RuleParseError(format!(
-                        "Rule contains invalid TagName: {}",
-                        key
-                    )));
-                }
-                last_tag_name = Some(key.clone());
-            } else if *token_type == TokenType::TagValue {
-                let tag_name = last_tag_name.as_ref().ok_or_else(|| {
-                    RulesError::RuleParseError(format!(
-                        "TagValue '{}' has no associated TagName",
-                        key
-                    ))
-                })?;
+// File an include line names, relative to the config directory -- e.g. "shared/common.rules"
+// for "#include shared/common.rules"
+fn get_include_from_line(line: &str) -> Option<&str> {
+    let include = line.trim().strip_prefix(INCLUDE_PREFIX)?;
+    include
+        .starts_with(char::is_whitespace)
+        .then(|| include.trim())
+}
 
-                let valid_values = self.m_mapped_tags.get(tag_name).ok_or_else(|| {
-                    RulesError::RuleParseError(format!(
-                        "No TagName '{}' found for TagValue '{}'",
-                        tag_name, key
-                    ))
-                })?;
+// Same key for a file however its path is written -- e.g. "config/a.rules" and "config/./a.rules"
+fn include_key(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
 
-                if !valid_values.contains(&key) {
-                    return Err(RulesError::RuleParseError(format!(
-                        "Rule contains invalid TagValue: '{}' is not a valid value for TagName '{}'",
-                        key, tag_name
-                    )));
-                }
-            }
-        }
+// Rules of every source and of the files they #include, which are read from `config_dir` unless
+// they're one of the sources. Each file's rules come once, after those of the first file
+// including it, so a file both included and matched by the rules glob isn't loaded twice.
+pub fn parse_rules_with_includes(
+    config_dir: &str,
+    sources: Vec<(String, String)>,
+) -> Result<Vec<LoadedRule>, RulesError> {
+    let mut report = ValidationReport::default();
+    let rules = read_rules_with_includes(config_dir, sources, &mut report);
+    report.first_error()?;
+
+    Ok(rules)
+}
 
-        Ok(())
+// As parse_rules_with_includes, but every line which fails is added to `report` -- e.g. a bad
+// rule name, or an include of a missing file -- and the rest still read
+pub fn read_rules_with_includes(
+    config_dir: &str,
+    sources: Vec<(String, String)>,
+    report: &mut ValidationReport,
+) -> Vec<LoadedRule> {
+    let mut includes = Includes {
+        m_config_dir: config_dir,
+        m_contents: sources
+            .iter()
+            .map(|(path, content)| (include_key(path), content.clone()))
+            .collect(),
+        m_stack: Vec::new(),
+        m_loaded: HashSet::new(),
+        m_report: report,
+    };
+    let mut rules: Vec<LoadedRule> = Vec::new();
+
+    for (path, content) in &sources {
+        includes.add_file(path, content, &mut rules);
     }
 
-    fn find_lowest_prec_op_index(tokens: &Vec<String>) -> usize {
-        let mut lowest_prec_token: Option<(usize, TokenDepth, i32)> = None; // Index, parenthesis depth and precedence
-        let mapped_tokens: MappedRuleTokens = Self::map_rule_tokens(&tokens);
+    rules
+}
 
-        for (i, token) in mapped_tokens.iter().enumerate() {
-            let paren_depth: i32 = token.2;
-            let token: &String = &token.0;
+// Files being followed through #include lines, for parse_rules_with_includes
+struct Includes<'a> {
+    m_config_dir: &'a str,
+    // Contents of the sources, used in place of what's on disk
+    m_contents: HashMap<PathBuf, String>,
+    // Files including the one being read, outermost first
+    m_stack: Vec<String>,
+    m_loaded: HashSet<PathBuf>,
+    m_report: &'a mut ValidationReport,
+}
 
-            if let Some(&token_prec) = TOKEN_PRECEDENCE.get(token.as_str()) {
-                let mut reassign: bool = false;
+impl Includes<'_> {
+    fn add_file(&mut self, path: &str, content: &str, rules: &mut Vec<LoadedRule>) {
+        if !self.m_loaded.insert(include_key(path)) {
+            return;
+        }
+        rules.extend(read_rules_from_str(path, content, self.m_report));
 
-                if let Some(lowest) = lowest_prec_token {
-                    let lowest_prec_token_depth: i32 = lowest.1;
-                    if paren_depth < lowest_prec_token_depth {
-                        reassign = true;
-                    } else if lowest_prec_token_depth == paren_depth && token_prec < lowest.2 {
-                        reassign = true;
-                    }
-                } else {
-                    reassign = true;
-                }
+        self.m_stack.push(path.to_string());
+        for (i, line) in content.lines().enumerate() {
+            let Some(include) = get_include_from_line(line) else {
+                continue;
+            };
+            let located =
+                |msg: String| RulesError::RuleParseError(format!("{}:{}: {}", path, i + 1, msg));
+
+            let inside = Path::new(include)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+            if !inside || !include.ends_with(".rules") {
+                let error = located(format!(
+                    "Invalid include: '{}', expected a .rules file in the config directory",
+                    include
+                ));
+                self.m_report.add(path, i + 1, error);
+                continue;
+            }
+
+            let included = format!("{}/{}", self.m_config_dir, include);
+            let key = include_key(&included);
+            if self.m_stack.iter().any(|p| include_key(p) == key) {
+                let error = located(format!(
+                    "Include cycle: {} -> {}",
+                    self.m_stack.join(" -> "),
+                    included
+                ));
+                self.m_report.add(path, i + 1, error);
+                continue;
+            }
 
-                if reassign {
-                    lowest_prec_token = Some((i, paren_depth, token_prec));
+            let included_content = match self.m_contents.get(&key) {
+                Some(content) => content.clone(),
+                None => match fs::read_to_string(&included) {
+                    Ok(content) => content,
+                    Err(_) => {
+                        let error = located(format!("Included file not found: {}", include));
+                        self.m_report.add(path, i + 1, error);
+                        continue;
+                    }
+                },
+            };
+            // Errors name the included file, and where it was included from
+            let first_failure = self.m_report.failures.len();
+            self.add_file(&included, &included_content, rules);
+            for failure in &mut self.m_report.failures[first_failure..] {
+                if let RulesError::RuleParseError(msg) = &mut failure.error {
+                    msg.push_str(&format!(" (included from {}:{})", path, i + 1));
                 }
             }
         }
-
-        lowest_prec_token.map(|(idx, _, _)| idx).unwrap_or(0)
-    }
-
-    fn contains_logical_op(tokens: &[String]) -> bool {
-        tokens.iter().any(|t| t == "&" || t == "|")
+        self.m_stack.pop();
     }
+}
 
-    fn create_leaf_node(tokens: Vec<String>) -> Result<Node, RulesError> {
-        if tokens.len() != 3 {
-            return Err(RulesError::RuleParseError(format!(
-                "Invalid leaf node: expected 3 tokens, got {}",
-                tokens.len()
-            )));
+// Content with the annotation called `name` removed from above the rule on `rule_line` -- e.g.
+// dropping "@draft red_large" to promote a draft. A line holding other annotations too keeps
+// them, rewritten as calls. Other lines are kept as they are.
+pub fn remove_annotation(content: &str, rule_line: usize, name: &str) -> String {
+    let lines: Vec<&str> = content.lines().collect();
+    // Line to what replaces it, None to drop it
+    let mut replaced: HashMap<usize, Option<String>> = HashMap::new();
+
+    for i in (0..rule_line.saturating_sub(1).min(lines.len())).rev() {
+        if file::line_blank_or_comment(lines[i]) {
+            continue;
         }
-
-        // Middle token is comparator
-        let operator = &tokens[1];
-        let token = if operator == "=" {
-            crate::parser::types::Token::Equals
-        } else if operator == "!" {
-            crate::parser::types::Token::NotEquals
-        } else {
-            return Err(RulesError::RuleParseError(format!(
-                "Invalid comparison operator: {}",
-                operator
-            )));
+        let Ok(Some(annotations)) = get_annotations_from_line(lines[i]) else {
+            break;
         };
+        if annotations.iter().all(|annotation| annotation.name != name) {
+            continue;
+        }
 
-        Ok(Node {
-            token,
-            left: None,
-            right: None,
+        let others: Vec<String> = annotations
+            .iter()
+            .filter(|annotation| annotation.name != name)
+            .map(RuleAnnotation::to_string)
+            .collect();
+        replaced.insert(i, (!others.is_empty()).then(|| others.join(" ")));
+    }
+
+    let mut kept: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| match replaced.get(&i) {
+            Some(replacement) => replacement.as_deref(),
+            None => Some(*line),
         })
+        .collect();
+    if content.ends_with('\n') {
+        kept.push("");
     }
+    kept.join("\n")
+}
 
-    // Recursive function to build AST from tokens
-    fn build_ast(tokens: Vec<String>) -> Result<Node, RulesError> {
-        let tokens: Vec<String> = if tokens.len() > 2
-            && tokens.first() == Some(&"(".to_string())
-            && tokens.last() == Some(&")".to_string())
-        {
-            tokens[1..tokens.len() - 1].to_vec()
-        } else {
-            tokens
-        };
+// Content without the rule on `rule_line`, its annotations and the #test lines under it -- e.g.
+// deleting "- colour = red" also drops "@weight 2" above it. Other lines are kept as they are.
+pub fn remove_rule(content: &str, rule_line: usize) -> String {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut removed = vec![rule_line.saturating_sub(1)];
 
-        // If no logical operators, this is a leaf node
-        // E.g., ["colour", "=", "red"] is a leaf
-        if !Self::contains_logical_op(&tokens) {
-            return Self::create_leaf_node(tokens);
+    for i in (0..rule_line.saturating_sub(1).min(lines.len())).rev() {
+        if file::line_blank_or_comment(lines[i]) {
+            continue;
         }
+        match get_annotations_from_line(lines[i]) {
+            Ok(Some(_)) => removed.push(i),
+            _ => break,
+        }
+    }
 
-        let op_index = Self::find_lowest_prec_op_index(&tokens);
-
-        let operator_str = &tokens[op_index];
-        let operator_token = if operator_str == "&" {
-            crate::parser::types::Token::And
-        } else if operator_str == "|" {
-            crate::parser::types::Token::Or
-        } else {
-            return Err(RulesError::RuleParseError(format!(
-                "Expected logical operator, found: {}",
-                operator_str
-            )));
-        };
-
-        // Split tokens into left and right subtrees
-        // e.g. left: ["colour", "=", "red"]
-        //      right: ["size", "=", "large"]
-        let left_tokens = tokens[0..op_index].to_vec();
-        let right_tokens = tokens[op_index + 1..].to_vec();
-
-        // Build subtrees
-        let left_child = Self::build_ast(left_tokens)?;
-        let right_child = Self::build_ast(right_tokens)?;
+    // Tests belong to the nearest rule above them
+    for (i, line) in lines.iter().enumerate().skip(rule_line) {
+        if line.trim().starts_with(TEST_PREFIX) {
+            removed.push(i);
+        } else if !file::line_blank_or_comment(line) {
+            break;
+        }
+    }
 
-        Ok(Node {
-            token: operator_token,
-            left: Some(Box::new(left_child)),
-            right: Some(Box::new(right_child)),
-        })
+    let mut kept: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !removed.contains(i))
+        .map(|(_, line)| *line)
+        .collect();
+    if content.ends_with('\n') {
+        kept.push("");
     }
+    kept.join("\n")
+}
 
-    fn string_to_rule(&self, rule_str: &str) -> Result<AstRule, RulesError> {
-        // Validate the rule syntax first
-        Self::validate_rule(&self, rule_str)?;
+// Whether `c` opens a call rather than a group, being straight after a name -- e.g. "before("
+fn opens_call(c: char, previous: Option<char>) -> bool {
+    c == '(' && previous.is_some_and(|p| p.is_alphanumeric() || p == '_')
+}
 
-        // Tokenize the rule string into a vector of tokens
-        // E.g., "colour = red & size = large" becomes:
-        // ["colour", "=", "red", "&", "size", "=", "large"]
-        let tokens = Self::tokenise_rule(&rule_str.to_string())?;
-        let root: Node = Self::build_ast(tokens)?;
+// `message` with the column `offset` is at in a rule line, counting characters from 1 -- e.g.
+// "Expected a tag name, found '&' at column 12"
+fn error_at_column(line: &str, offset: usize, message: &str) -> RulesError {
+    let column = line[..offset].chars().count() + 1;
+    RulesError::RuleParseError(format!("{}{}{}", message, err::COLUMN_SUFFIX, column))
+}
 
-        Ok(AstRule { root_node: root })
-    }
+// The lexemes of the comparisons at `body` in a rule line, each with its span in the line -- e.g.
+// `colour == "dark red"` gives colour, '=' and the quoted dark red. Both grammars are read, so
+// "==" and "!=" are '=' and '!', and "->" is '→'. A '(' straight after a word opens a call, which
+// is one lexeme that is never looked inside -- e.g. "before(2024-01-01)".
+fn lex_rule(line: &str, body: Span) -> Result<Vec<Lexeme>, RulesError> {
+    let offset = body.start;
+    let body = &line[body];
+    let mut lexemes: Vec<Lexeme> = Vec::new();
+    let mut chars = body.char_indices().peekable();
+    let span = |start: usize, end: usize| offset + start..offset + end;
+    let ends_word = |i: usize, c: char| {
+        c.is_whitespace()
+            || c == string::VALUE_QUOTE
+            || ALL_OP_CHARS.contains(&c)
+            || body[i..].starts_with(IMPLIES)
+    };
+
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
 
-    fn ast_to_dnf(&self, node: &Node) -> Result<Vec<SubRule>, RulesError> {
-        match node.token {
-            Token::Equals | Token::NotEquals => {
-                // create subrule
-                Ok(vec![])
+        let next = chars.peek().map(|(_, next)| *next);
+        match c {
+            '=' | '!' if next == Some('=') => {
+                chars.next();
+                lexemes.push(Lexeme::op(c, span(start, start + 2)));
+            }
+            '-' if next == Some('>') => {
+                chars.next();
+                lexemes.push(Lexeme::op(IMPLIES_CHAR, span(start, start + 2)));
             }
-            Token::Or => {
-                let left_clauses = self.ast_to_dnf(node.left.as_ref().unwrap())?;
-                let right_clauses = self.ast_to_dnf(node.right.as_ref().unwrap())?;
-                // concatenate the clauses (add)
-                Ok(vec![])
+            string::VALUE_QUOTE => {
+                let value =
+                    string::read_quoted(&mut chars.by_ref().map(|(_, c)| c)).ok_or_else(|| {
+                        RulesError::RuleParseError(format!(
+                            "Unterminated quoted value in: {}",
+                            body
+                        ))
+                    })?;
+                if value.trim().is_empty() {
+                    return Err(RulesError::RuleParseError(
+                        "Quoted value must not be blank".to_string(),
+                    ));
+                }
+                let end = chars.peek().map_or(body.len(), |(i, _)| *i);
+                let value: String = value
+                    .chars()
+                    .map(|c| if c.is_whitespace() { ' ' } else { c })
+                    .collect();
+                lexemes.push(Lexeme::new(LexemeKind::Quoted, &value, span(start, end)));
             }
-            Token::And => {
-                let left_clauses = self.ast_to_dnf(node.left.as_ref().unwrap())?;
-                let right_clauses = self.ast_to_dnf(node.right.as_ref().unwrap())?;
-                // cross multiply
-                Ok(vec![])
+            _ if ALL_OP_CHARS.contains(&c) => {
+                lexemes.push(Lexeme::op(c, span(start, start + c.len_utf8())));
             }
-            _ => Err(RulesError::RuleParseError(
-                format!("Invalid token: {}", node.token).to_string(),
-            )),
-        }
-    }
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, next)) = chars.peek()
+                    && !ends_word(i, next)
+                {
+                    chars.next();
+                    end = i + next.len_utf8();
+                }
 
-    fn rule_to_dnf_subrule(&self, rule: AstRule) -> Result<SubRule, RulesError> {
-        self.ast_to_dnf(&rule.root_node)
+                let mut kind = LexemeKind::Word;
+                if chars.peek().is_some_and(|(_, next)| *next == '(') {
+                    let open = end;
+                    let mut call_depth = 0;
+                    for (i, c) in chars.by_ref() {
+                        call_depth += RuleParser::call_depth_change(c);
+                        end = i + c.len_utf8();
+                        if call_depth == 0 {
+                            break;
+                        }
+                    }
+                    if call_depth != 0 {
+                        return Err(error_at_column(
+                            line,
+                            offset + open,
+                            "Unmatched opening parenthesis",
+                        ));
+                    }
+                    kind = LexemeKind::Call;
+                }
+                lexemes.push(Lexeme::new(kind, &body[start..end], span(start, end)));
+            }
+        }
     }
 
-    // Main entry point for parsing rule files.
-    // Converts all .rules files into Disjunctive Normal Form (DNF) subrules.
-    pub fn parse_rules(
-        mapped_tags: HashMap<types::TagName, types::TagValues>,
-    ) -> Result<Vec<SubRule>, RulesError> {
-        let parser = RuleParser {
-            m_mapped_tags: mapped_tags,
-        };
-
-        let mut dnf_subrules: Vec<SubRule> = Vec::new();
-        let all_files: Vec<String> = file::read_files_in_dir("config/*.rules")?;
-
-        for file in all_files.iter() {
-            for line in file.lines() {
-                if file::line_blank_or_comment(line) {
-                    continue;
-                }
+    Ok(lexemes)
+}
 
-                // Parse string to AST, then convert to DNF representation
-                let rule: AstRule = parser.string_to_rule(line)?;
-                let subrule: SubRule = parser.rule_to_dnf_subrule(rule)?;
+// A rule in the legacy grammar, rewriting v2 operators and quoted values -- e.g.
+// `- colour != "red"` gives "- colour ! red". Spaces and operators in a quoted value are
+// hidden, so the value stays one word. Legacy rules are returned as they are, and call arguments
+// are never rewritten.
+fn legacy_syntax(rule: &str) -> Result<String, RulesError> {
+    let mut legacy = String::new();
+    let mut chars = rule.chars().peekable();
+    let mut call_depth = 0;
+    let mut previous: Option<char> = None;
+
+    while let Some(c) = chars.next() {
+        if call_depth > 0 || opens_call(c, previous) {
+            call_depth += RuleParser::call_depth_change(c);
+            legacy.push(c);
+            previous = Some(c);
+            continue;
+        }
 
-                dnf_subrules.push(subrule);
+        match c {
+            '=' | '!' if chars.peek() == Some(&'=') => {
+                chars.next();
+                legacy.push(c);
             }
+            '-' if chars.peek() == Some(&'>') => {
+                chars.next();
+                legacy.push(IMPLIES_CHAR);
+            }
+            string::VALUE_QUOTE => {
+                let value = string::read_quoted(&mut chars).ok_or_else(|| {
+                    RulesError::RuleParseError(format!("Unterminated quoted value in: {}", rule))
+                })?;
+                if value.trim().is_empty() {
+                    return Err(RulesError::RuleParseError(
+                        "Quoted value must not be blank".to_string(),
+                    ));
+                }
+                // Kept as one word while the rule is migrated -- e.g. "fish & chips"
+                legacy.push_str(&string::hide_reserved(&value));
+            }
+            _ => legacy.push(c),
         }
+        previous = Some(c);
+    }
 
-        Ok(dnf_subrules)
+    Ok(legacy)
+}
+
+// `legacy` with the values legacy_syntax kept as one word quoted again -- e.g. `colour = "dark
+// red"` back from its hidden form
+fn requote_hidden_values(legacy: &str) -> String {
+    let mut requoted = String::new();
+    let mut word = String::new();
+    let end_word = |word: &mut String, requoted: &mut String| {
+        if string::has_hidden(word) {
+            requoted.push_str(&string::quote(&string::reveal_reserved(word)));
+        } else {
+            requoted.push_str(word);
+        }
+        word.clear();
+    };
+
+    for c in legacy.chars() {
+        if c == IMPLIES_CHAR {
+            end_word(&mut word, &mut requoted);
+            requoted.push_str(IMPLIES);
+        } else if ALL_OP_CHARS.contains(&c) || c.is_whitespace() {
+            end_word(&mut word, &mut requoted);
+            requoted.push(c);
+        } else {
+            word.push(c);
+        }
     }
+    end_word(&mut word, &mut requoted);
+
+    requoted
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// A rule rewritten in another grammar version, keeping its spacing, comma lists and flag
+// shorthand -- e.g. "- colour ! red, blue" to v2 gives `- colour != "red", "blue"`
+pub fn migrate_rule(rule: &str, to: GrammarVersion) -> Result<String, RulesError> {
+    // The name and outcome are the same in both grammars, and neither is an operator
+    let (name, rule) = string::split_name(rule)?;
+    let (rule, outcome) = string::split_outcome(rule)?;
+    let with_name_and_outcome = |migrated: String| {
+        let migrated = match outcome {
+            Some(outcome) => format!("{} {} {}", migrated, string::OUTCOME_ARROW, outcome),
+            None => migrated,
+        };
+        match name {
+            Some(name) => format!("{}{} {}", name, string::NAME_SEPARATOR, migrated),
+            None => migrated,
+        }
+    };
 
-    // Find a token in the token list
-    fn find_token<'a>(
-        tokens: &'a [(String, TokenType, TokenDepth)],
-        token_str: &str,
-    ) -> Option<&'a TokenType> {
-        tokens
-            .iter()
-            .find(|(s, _, _)| s == token_str)
-            .map(|(_, t, _)| t)
+    let legacy = legacy_syntax(rule)?;
+    if to == GrammarVersion::V1 {
+        return Ok(with_name_and_outcome(requote_hidden_values(&legacy)));
     }
 
-    // Count occurrences of a token
-    fn count_token(tokens: &[(String, TokenType, TokenDepth)], token_str: &str) -> usize {
-        tokens.iter().filter(|(s, _, _)| s == token_str).count()
+    let mut migrated = String::new();
+    let mut word = String::new();
+    let mut call_depth = 0;
+    let mut previous: Option<char> = None;
+    // Last operator outside calls, so a word after a comparison is known to be a value
+    let mut last_op: Option<char> = None;
+    let mut after_word = false;
+
+    let end_word = |word: &mut String, migrated: &mut String, last_op: Option<char>| {
+        if word.is_empty() {
+            return;
+        }
+        let is_value = last_op.is_some_and(|op| ['=', '!', '<', '>', ','].contains(&op));
+        if is_value && !word.contains('(') {
+            migrated.push_str(&string::quote(&string::reveal_reserved(word)));
+        } else {
+            migrated.push_str(word);
+        }
+        word.clear();
+    };
+
+    for c in legacy.chars() {
+        if call_depth > 0 || opens_call(c, previous) {
+            call_depth += RuleParser::call_depth_change(c);
+            word.push(c);
+            previous = Some(c);
+            continue;
+        }
+
+        if ALL_OP_CHARS.contains(&c) || c.is_whitespace() {
+            if !word.is_empty() {
+                end_word(&mut word, &mut migrated, last_op);
+                after_word = true;
+            }
+
+            match c {
+                '=' => migrated.push_str("=="),
+                '!' if after_word => migrated.push_str("!="),
+                IMPLIES_CHAR => migrated.push_str(IMPLIES),
+                _ => migrated.push(c),
+            }
+            // '!' before a flag name negates it, so a name still follows
+            let negation = c == '!' && !after_word;
+            if !c.is_whitespace() && !negation {
+                last_op = Some(c);
+                after_word = c == ')';
+            }
+        } else if c == '-' && last_op.is_none() && !after_word && word.is_empty() {
+            // The dash starting the rule
+            migrated.push(c);
+        } else {
+            word.push(c);
+        }
+        previous = Some(c);
     }
+    end_word(&mut word, &mut migrated, last_op);
 
-    // Tests for get_expected_token_type
-    #[test]
-    fn test_get_expected_token_type_after_open_paren() {
-        let tokens = vec!["(".to_string()];
-        let result = RuleParser::get_expected_token_type(&tokens, 1);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), TokenType::TagName);
+    Ok(with_name_and_outcome(migrated))
+}
+
+// Example objects given under each rule in the contents of one .rules file, with whether the rule
+// should match them -- e.g. "- colour = red\n#test: match { colour: red }\n#test: no_match {
+// colour: blue }". The objects aren't validated, but are folded per `case_folding`.
+pub fn parse_inline_tests_from_str(
+    path: &str,
+    content: &str,
+    case_folding: CaseFolding,
+) -> Result<Vec<InlineTest>, RulesError> {
+    let rules = parse_rules_from_str(path, content)?;
+    let mut tests: Vec<InlineTest> = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let Some(test) = line.trim().strip_prefix(TEST_PREFIX) else {
+            continue;
+        };
+        let located =
+            |msg: String| RulesError::RuleParseError(format!("{}:{}: {}", path, i + 1, msg));
+
+        let (expect_match, object) = match test.trim().split_once(char::is_whitespace) {
+            Some(("match", object)) => (true, object),
+            Some(("no_match", object)) => (false, object),
+            _ => {
+                return Err(located(format!(
+                    "Invalid test: '{}', expected '#test: match {{ tag: value }}' or '#test: no_match {{ tag: value }}'",
+                    line.trim()
+                )));
+            }
+        };
+        let object = objects::parse_object_from_str(object, case_folding)
+            .map_err(|e| located(e.to_string()))?;
+
+        let rule = rules
+            .iter()
+            .rev()
+            .find(|loaded| loaded.line < i + 1)
+            .ok_or_else(|| located("Test isn't under a rule".to_string()))?;
+
+        tests.push(InlineTest {
+            file: path.to_string(),
+            line: i + 1,
+            rule: rule.clone(),
+            object,
+            expect_match,
+        });
     }
 
-    #[test]
-    fn test_get_expected_token_type_after_close_paren() {
-        let tokens = vec![
-            "(".to_string(),
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            ")".to_string(),
-        ];
-        let result = RuleParser::get_expected_token_type(&tokens, 0);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), TokenType::LogicalOp);
+    Ok(tests)
+}
+
+// Every combination of values of the tags some rules compare -- e.g. { colour: red, size: small }
+struct TagSpace {
+    // Tags with listed values, in name order
+    tags: Vec<types::TagName>,
+    // Tags with no listed values to combine, like pattern tags
+    skipped_tags: Vec<types::TagName>,
+    // The value of each tag in each combination, None if it's absent
+    combinations: Vec<Vec<Option<String>>>,
+}
+
+// What a RuleReader has begun but not finished, innermost last -- e.g. after "a | !(b &", the '|'
+// with a on its left, the '!', its group and the '&' with b on its left
+enum Pending {
+    // Group opened by the lexeme, until its ')'
+    Group(Lexeme),
+    // '!' before the group after it
+    Not,
+    // Operator at its level, with its left operand read
+    Op(Token, i32),
+}
+
+// Reads a rule's lexemes into its syntax tree by operator precedence, giving each token its type
+// on the way -- e.g. "colour = red | size = large & shape = circle" gives '|' over colour = red and
+// the '&'. A comma continues the comparison before it, as '|', or as '&' after '!'. Errors give
+// the column of the lexeme they're about.
+struct RuleReader<'a> {
+    m_line: &'a str,
+    m_lexemes: Vec<Lexeme>,
+    m_position: usize,
+    m_precedence: &'a OperatorPrecedence,
+    m_depth: TokenDepth,
+    m_tokens: MappedRuleTokens,
+    // Tag name and comparison operator a comma continues -- e.g. colour and '=' in
+    // "colour = red, blue"
+    m_last_comparison: Option<(String, String)>,
+    // Set after a comma, whose operand is only a value
+    m_continues_list: bool,
+}
+
+impl<'a> RuleReader<'a> {
+    fn new(line: &'a str, lexemes: Vec<Lexeme>, precedence: &'a OperatorPrecedence) -> Self {
+        RuleReader {
+            m_line: line,
+            m_lexemes: lexemes,
+            m_position: 0,
+            m_precedence: precedence,
+            m_depth: 0,
+            m_tokens: Vec::new(),
+            m_last_comparison: None,
+            m_continues_list: false,
+        }
     }
 
-    #[test]
-    fn test_get_expected_token_type_after_close_paren_nested() {
-        let tokens = vec![
-            "(".to_string(),
-            "(".to_string(),
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            ")".to_string(),
-        ];
-        let result = RuleParser::get_expected_token_type(&tokens, 1);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), TokenType::LogicalOp);
+    // The rule's syntax tree, with '->' read as '!' of its left side '|' its right side, and
+    // its tokens in order
+    fn read(mut self) -> Result<(Node, MappedRuleTokens), RulesError> {
+        if self.m_lexemes.is_empty() {
+            return Err(RulesError::RuleParseError("Empty rule".to_string()));
+        }
+
+        let root = self.read_expression()?;
+        Ok((root, self.m_tokens))
     }
 
-    #[test]
-    fn test_get_expected_token_type_after_equals() {
-        let tokens = vec!["colour".to_string(), "=".to_string()];
-        let result = RuleParser::get_expected_token_type(&tokens, 0);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), TokenType::TagValue);
+    fn peek(&self) -> Option<&Lexeme> {
+        self.m_lexemes.get(self.m_position)
     }
 
-    #[test]
-    fn test_get_expected_token_type_after_not_equals() {
-        let tokens = vec!["colour".to_string(), "!".to_string()];
-        let result = RuleParser::get_expected_token_type(&tokens, 0);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), TokenType::TagValue);
+    fn next_lexeme(&mut self) -> Option<Lexeme> {
+        let lexeme = self.m_lexemes.get(self.m_position).cloned();
+        self.m_position += 1;
+        lexeme
     }
 
-    #[test]
-    fn test_get_expected_token_type_after_and() {
-        let tokens = vec![
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            "&".to_string(),
-        ];
-        let result = RuleParser::get_expected_token_type(&tokens, 0);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), TokenType::TagName);
+    fn push_token(&mut self, token: &str, token_type: TokenType) {
+        self.m_tokens
+            .push((token.to_string(), token_type, self.m_depth));
     }
 
-    #[test]
-    fn test_get_expected_token_type_after_or() {
-        let tokens = vec![
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            "|".to_string(),
-        ];
-        let result = RuleParser::get_expected_token_type(&tokens, 0);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), TokenType::TagName);
+    // How `lexeme` was written in the rule -- e.g. "==" for '='
+    fn written(&self, lexeme: &Lexeme) -> &str {
+        &self.m_line[lexeme.span.clone()]
     }
 
-    #[test]
-    fn test_get_expected_token_type_after_tag_name_following_open_paren() {
-        let tokens = vec!["(".to_string(), "colour".to_string()];
-        let result = RuleParser::get_expected_token_type(&tokens, 1);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), TokenType::ComparisonOp);
+    fn error_at(&self, lexeme: &Lexeme, message: &str) -> RulesError {
+        error_at_column(self.m_line, lexeme.span.start, message)
     }
 
-    #[test]
-    fn test_get_expected_token_type_after_tag_value() {
-        let tokens = vec!["colour".to_string(), "=".to_string(), "red".to_string()];
-        let result = RuleParser::get_expected_token_type(&tokens, 0);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), TokenType::LogicalOp);
+    // Error for a lexeme after a whole comparison or group that doesn't join another to it
+    fn unexpected(&self, lexeme: &Lexeme) -> RulesError {
+        if lexeme.is_op(')') {
+            return self.error_at(lexeme, "Unmatched closing parenthesis");
+        }
+        self.error_at(
+            lexeme,
+            &format!(
+                "Expected '&', '|' or '{}', found '{}'",
+                IMPLIES,
+                self.written(lexeme)
+            ),
+        )
     }
 
-    #[test]
-    fn test_get_expected_token_type_empty_vector() {
-        let tokens = vec![];
-        let result = RuleParser::get_expected_token_type(&tokens, 0);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), TokenType::TagName);
+    // Error for a rule ending where `expected` should be
+    fn ended_early(&self, expected: &str) -> RulesError {
+        let last = self
+            .m_lexemes
+            .last()
+            .map_or("", |lexeme| self.written(lexeme));
+        RulesError::RuleParseError(format!("Rule ends after '{}', expected {}", last, expected))
     }
 
-    // Tests for tokenise_rule (which now does the parsing)
-    #[test]
-    fn test_tokenise_rule_simple_rule() {
-        let rule = "colour = red";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+    // The logical operator the next lexeme is and its level, if it is one. A comma is '|', or
+    // '&' after '!'.
+    fn peek_logical_op(&self) -> Result<Option<(Token, i32)>, RulesError> {
+        let Some(lexeme) = self.peek() else {
+            return Ok(None);
+        };
 
-        assert!(result.is_ok());
-        let tokens = result.unwrap();
-        assert_eq!(tokens.len(), 3);
-        assert_eq!(tokens, vec!["colour", "=", "red"]);
-    }
+        let token = match lexeme.text.as_str() {
+            _ if lexeme.kind != LexemeKind::Op => return Ok(None),
+            "," => match &self.m_last_comparison {
+                Some((_, op)) if op == "!" => Token::And,
+                Some(_) => Token::Or,
+                None => {
+                    return Err(
+                        self.error_at(lexeme, "Comma must follow a complete tag comparison")
+                    );
+                }
+            },
+            _ => match RuleParser::token_from_str(&lexeme.text) {
+                Some(token @ (Token::And | Token::Or | Token::Implies)) => token,
+                _ => return Ok(None),
+            },
+        };
 
-    #[test]
-    fn test_tokenise_rule_with_parentheses() {
-        let rule = "(colour = red)";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+        let operator = match token {
+            Token::Implies => IMPLIES.to_string(),
+            _ => token.to_string(),
+        };
+        let level = self.m_precedence.level(&operator).ok_or_else(|| {
+            RulesError::RuleParseError(format!(
+                "Operator precedence has no level for '{}'",
+                operator
+            ))
+        })?;
 
-        assert!(result.is_ok());
-        let tokens = result.unwrap();
-        assert_eq!(tokens.len(), 5);
-        assert_eq!(tokens, vec!["(", "colour", "=", "red", ")"]);
+        Ok(Some((token, level)))
     }
 
-    #[test]
-    fn test_tokenise_rule_nested_parentheses() {
-        let rule = "((colour = red))";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+    // The whole rule, kept on stacks of operands and of what's pending rather than recursing per
+    // operator and group, so long or deeply nested rules can't exhaust the stack. An operator is
+    // joined to its operands once one binding less tightly follows it, so operators at the same
+    // level group to the right -- e.g. "a & b & c" is "a & (b & c)".
+    fn read_expression(&mut self) -> Result<Node, RulesError> {
+        let mut operands: Vec<Node> = Vec::new();
+        let mut pending: Vec<Pending> = Vec::new();
+
+        loop {
+            let operand = loop {
+                if let Some(operand) = self.read_operand(&mut pending)? {
+                    break operand;
+                }
+            };
+            operands.push(operand);
+
+            // Close groups until an operator joins another operand on
+            loop {
+                Self::apply_negations(&mut operands, &mut pending);
+
+                if let Some((token, level)) = self.peek_logical_op()? {
+                    Self::reduce(&mut operands, &mut pending, level);
+                    let Some(lexeme) = self.next_lexeme() else {
+                        break;
+                    };
+                    self.push_token(&token.to_string(), TokenType::LogicalOp);
+
+                    // "colour = red, blue" reads as "colour = red | colour = blue"
+                    if lexeme.is_op(',')
+                        && let Some((tag_name, op)) = self.m_last_comparison.clone()
+                    {
+                        self.push_token(&tag_name, TokenType::TagName);
+                        self.push_token(&op, TokenType::ComparisonOp);
+                        self.m_continues_list = true;
+                    }
 
-        assert!(result.is_ok());
-        let tokens = result.unwrap();
-        assert_eq!(tokens.len(), 7);
-        assert_eq!(tokens, vec!["(", "(", "colour", "=", "red", ")", ")"]);
+                    pending.push(Pending::Op(token, level));
+                    break;
+                }
+
+                let in_group = pending
+                    .iter()
+                    .any(|entry| matches!(entry, Pending::Group(_)));
+                match self.next_lexeme() {
+                    Some(close) if close.is_op(')') && in_group => {
+                        Self::reduce(&mut operands, &mut pending, i32::MIN);
+                        pending.pop();
+                        self.m_depth -= 1;
+                    }
+                    Some(other) => return Err(self.unexpected(&other)),
+                    None => {
+                        Self::reduce(&mut operands, &mut pending, i32::MIN);
+                        if let Some(Pending::Group(open)) = pending.last() {
+                            return Err(self.error_at(open, "Unmatched opening parenthesis"));
+                        }
+                        return operands.pop().ok_or_else(|| self.ended_early("a tag name"));
+                    }
+                }
+            }
+        }
     }
 
-    #[test]
-    fn test_tokenise_rule_with_and_operator() {
-        let rule = "colour = red & size = large";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+    // Join the operators pending since the innermost open group which bind more tightly than
+    // `level` to their operands, innermost first
+    fn reduce(operands: &mut Vec<Node>, pending: &mut Vec<Pending>, level: i32) {
+        while let Some(Pending::Op(_, op_level)) = pending.last()
+            && *op_level > level
+        {
+            let Some(Pending::Op(token, _)) = pending.pop() else {
+                break;
+            };
+            let (Some(right), Some(left)) = (operands.pop(), operands.pop()) else {
+                break;
+            };
+
+            operands.push(match token {
+                // "a -> b" holds unless a does and b doesn't, so it's "!(a) | b"
+                Token::Implies => Node {
+                    token: Token::Or,
+                    left: Some(Box::new(Node {
+                        token: Token::Not,
+                        left: Some(Box::new(left)),
+                        right: None,
+                        clause: None,
+                    })),
+                    right: Some(Box::new(right)),
+                    clause: None,
+                },
+                token => Node {
+                    token,
+                    left: Some(Box::new(left)),
+                    right: Some(Box::new(right)),
+                    clause: None,
+                },
+            });
+        }
+    }
 
-        assert!(result.is_ok());
-        let tokens = result.unwrap();
-        assert_eq!(tokens.len(), 7);
-        assert_eq!(
-            tokens,
-            vec!["colour", "=", "red", "&", "size", "=", "large"]
-        );
+    // Apply each '!' pending straight before the operand just read, which is the group it negates
+    fn apply_negations(operands: &mut Vec<Node>, pending: &mut Vec<Pending>) {
+        while let Some(Pending::Not) = pending.last() {
+            pending.pop();
+            if let Some(operand) = operands.pop() {
+                operands.push(Node {
+                    token: Token::Not,
+                    left: Some(Box::new(operand)),
+                    right: None,
+                    clause: None,
+                });
+            }
+        }
     }
 
-    #[test]
-    fn test_tokenise_rule_with_or_operator() {
-        let rule = "colour = red | colour = blue";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+    // A comparison, or None for a '(' or '!', which is left pending until the group after it
+    // closes -- e.g. "!(colour = red)" gives None twice, then colour = red
+    fn read_operand(&mut self, pending: &mut Vec<Pending>) -> Result<Option<Node>, RulesError> {
+        if std::mem::take(&mut self.m_continues_list)
+            && let Some((tag_name, op)) = self.m_last_comparison.clone()
+        {
+            return self.read_value(&tag_name, &op).map(Some);
+        }
 
-        assert!(result.is_ok());
-        let tokens = result.unwrap();
-        assert_eq!(tokens.len(), 7);
-        assert_eq!(
-            tokens,
-            vec!["colour", "=", "red", "|", "colour", "=", "blue"]
-        );
-    }
+        let Some(lexeme) = self.next_lexeme() else {
+            return Err(self.ended_early("a tag name"));
+        };
 
-    #[test]
-    fn test_tokenise_rule_with_not_equals() {
-        let rule = "colour ! red";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+        if lexeme.is_op('(') {
+            self.m_depth += 1;
+            pending.push(Pending::Group(lexeme));
+            return Ok(None);
+        }
 
-        assert!(result.is_ok());
-        let tokens = result.unwrap();
-        assert_eq!(tokens.len(), 3);
-        assert_eq!(tokens, vec!["colour", "!", "red"]);
-    }
+        // E.g., "!(colour = red & size = large)"
+        if lexeme.is_op('!') {
+            if !self.peek().is_some_and(|next| next.is_op('(')) {
+                return Err(self.error_at(
+                    &lexeme,
+                    "'!' before a tag name must be followed by a group -- e.g. !(colour = red)",
+                ));
+            }
+            self.push_token("!", TokenType::Negation);
+            pending.push(Pending::Not);
+            return Ok(None);
+        }
 
-    #[test]
-    fn test_tokenise_rule_complex_nested() {
-        let rule = "((colour = red) & (size = large))";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+        if !lexeme.is_word() {
+            return Err(self.error_at(
+                &lexeme,
+                &format!("Expected a tag name, found '{}'", self.written(&lexeme)),
+            ));
+        }
 
-        assert!(result.is_ok());
-        let tokens = result.unwrap();
-        assert_eq!(tokens.len(), 13);
-        assert_eq!(
-            tokens,
-            vec![
-                "(", "(", "colour", "=", "red", ")", "&", "(", "size", "=", "large", ")", ")"
-            ]
-        );
+        self.read_comparison(&lexeme.text).map(Some)
     }
 
-    #[test]
-    fn test_tokenise_rule_unmatched_opening_paren() {
-        let rule = "(colour = red";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+    // The comparison after `tag_name` -- e.g. "= red", "?" or a call, compared with '~'
+    fn read_comparison(&mut self, tag_name: &str) -> Result<Node, RulesError> {
+        self.push_token(tag_name, TokenType::TagName);
 
-        assert!(result.is_err());
-        if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("Unmatched opening parenthesis"));
-        } else {
-            panic!("Expected RuleParseError about unmatched opening parenthesis");
+        let Some(lexeme) = self.peek().cloned() else {
+            return Err(self.ended_early("a comparison"));
+        };
+
+        // A presence check has no value -- e.g. "colour?"
+        if lexeme.is_op('?') {
+            self.next_lexeme();
+            self.push_token("?", TokenType::Presence);
+            self.m_last_comparison = None;
+            if let Some(next) = self.peek()
+                && next.kind != LexemeKind::Op
+            {
+                return Err(self.error_at(
+                    next,
+                    &format!(
+                        "'?' takes no value, found '{}' -- e.g. colour? & size = large",
+                        self.written(next)
+                    ),
+                ));
+            }
+            return Ok(Node {
+                token: Token::Exists,
+                clause: Some(Clause {
+                    tag_name: tag_name.to_string(),
+                    comparison_op: ComparisonOp::PRES,
+                    tag_value: String::new(),
+                }),
+                left: None,
+                right: None,
+            });
         }
-    }
 
-    #[test]
-    fn test_tokenise_rule_unmatched_closing_paren() {
-        let rule = "colour = red)";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+        // A call straight after a tag name is compared with '~' -- e.g.
+        // "created_at ~ before(2024-01-01)"
+        if lexeme.kind == LexemeKind::Call {
+            self.push_token("~", TokenType::ComparisonOp);
+            return self.read_value(tag_name, "~");
+        }
 
-        assert!(result.is_err());
-        if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("Unmatched closing parenthesis"));
-        } else {
-            panic!("Expected RuleParseError about unmatched closing parenthesis");
+        if lexeme.kind != LexemeKind::Op
+            || !matches!(lexeme.text.as_str(), "=" | "!" | "<" | ">" | "~")
+        {
+            return Err(self.error_at(
+                &lexeme,
+                &format!(
+                    "Expected a comparison after '{}', found '{}'",
+                    tag_name,
+                    self.written(&lexeme)
+                ),
+            ));
         }
+
+        self.next_lexeme();
+        self.push_token(&lexeme.text, TokenType::ComparisonOp);
+        self.read_value(tag_name, &lexeme.text)
     }
 
-    #[test]
-    fn test_tokenise_rule_extra_whitespace() {
-        let rule = "  colour   =   red  ";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+    // The value `tag_name` is compared with by `op`, as a leaf node -- e.g. colour = red
+    fn read_value(&mut self, tag_name: &str, op: &str) -> Result<Node, RulesError> {
+        let Some(lexeme) = self.next_lexeme() else {
+            return Err(self.ended_early("a tag value"));
+        };
+        if !lexeme.is_word() && lexeme.kind != LexemeKind::Call {
+            return Err(self.error_at(
+                &lexeme,
+                &format!(
+                    "Expected a tag value after '{}', found '{}'",
+                    op,
+                    self.written(&lexeme)
+                ),
+            ));
+        }
 
-        assert!(result.is_ok());
-        let tokens = result.unwrap();
-        assert_eq!(tokens.len(), 3);
-        assert_eq!(tokens, vec!["colour", "=", "red"]);
-    }
+        self.push_token(&lexeme.text, TokenType::TagValue);
+        self.m_last_comparison = Some((tag_name.to_string(), op.to_string()));
 
-    #[test]
-    fn test_tokenise_rule_no_spaces() {
-        let rule = "colour=red";
-        let result = RuleParser::tokenise_rule(&rule.to_string());
+        let comparison = RuleParser::token_from_str(op).and_then(|token| {
+            RuleParser::comparison_op_for(&token, &lexeme.text).map(|op| (token, op))
+        });
+        let Some((token, comparison_op)) = comparison else {
+            return Err(RulesError::RuleParseError(format!(
+                "Invalid comparison operator: {}",
+                op
+            )));
+        };
 
-        assert!(result.is_ok());
-        let tokens = result.unwrap();
-        assert_eq!(tokens.len(), 3);
-        assert_eq!(tokens, vec!["colour", "=", "red"]);
+        Ok(Node {
+            token,
+            clause: Some(Clause {
+                tag_name: tag_name.to_string(),
+                comparison_op,
+                tag_value: lexeme.text,
+            }),
+            left: None,
+            right: None,
+        })
     }
+}
 
-    // Tests for map_rule_tokens (now takes Vec<String> and returns MappedRuleTokens)
-    #[test]
-    fn test_map_rule_tokens_simple() {
-        let tokens = vec!["colour".to_string(), "=".to_string(), "red".to_string()];
-        let mapped = RuleParser::map_rule_tokens(&tokens);
+pub struct RuleParser {
+    m_mapped_tags: HashMap<types::TagName, types::TagValues>,
+    m_tag_constraints: types::TagConstraintsMap,
+    m_case_folding: CaseFolding,
+    m_plugins: PluginMap,
+    m_exclusive_groups: Vec<ExclusiveGroup>,
+    m_unknown_policy: UnknownPolicy,
+    m_missing_tag_policy: MissingTagPolicy,
+    m_precedence: OperatorPrecedence,
+    m_limits: RuleLimits,
+    // Regexes compared with '~', compiled once each -- e.g. "^AB[0-9]+$"
+    m_regexes: Mutex<HashMap<String, Regex>>,
+}
 
-        assert_eq!(mapped.len(), 3);
-        assert_eq!(find_token(&mapped, "colour"), Some(&TokenType::TagName));
-        assert_eq!(find_token(&mapped, "="), Some(&TokenType::ComparisonOp));
-        assert_eq!(find_token(&mapped, "red"), Some(&TokenType::TagValue));
+impl RuleParser {
+    pub fn new(tags: HashMap<types::TagName, types::TagValues>) -> Self {
+        RuleParser {
+            m_mapped_tags: tags,
+            m_tag_constraints: HashMap::new(),
+            m_case_folding: CaseFolding::default(),
+            m_plugins: HashMap::new(),
+            m_exclusive_groups: Vec::new(),
+            m_unknown_policy: UnknownPolicy::default(),
+            m_missing_tag_policy: MissingTagPolicy::default(),
+            m_precedence: OperatorPrecedence::default(),
+            m_limits: RuleLimits::default(),
+            m_regexes: Mutex::new(HashMap::new()),
+        }
     }
 
-    #[test]
-    fn test_map_rule_tokens_with_logical_op() {
-        let tokens = vec![
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            "&".to_string(),
-            "size".to_string(),
-            "=".to_string(),
-            "large".to_string(),
-        ];
-        let mapped = RuleParser::map_rule_tokens(&tokens);
+    // Parser for loaded tags, with each tag's constraints
+    pub fn from_tags(tags: Vec<types::Tag>) -> Self {
+        let mut mapped_tags = HashMap::new();
+        let mut constraints = HashMap::new();
+        for tag in tags {
+            constraints.insert(tag.name.clone(), tag.constraints);
+            mapped_tags.insert(tag.name, tag.values);
+        }
 
-        assert_eq!(mapped.len(), 7);
-        assert_eq!(find_token(&mapped, "colour"), Some(&TokenType::TagName));
-        assert_eq!(find_token(&mapped, "red"), Some(&TokenType::TagValue));
-        assert_eq!(find_token(&mapped, "&"), Some(&TokenType::LogicalOp));
-        assert_eq!(find_token(&mapped, "size"), Some(&TokenType::TagName));
-        assert_eq!(find_token(&mapped, "large"), Some(&TokenType::TagValue));
-        assert_eq!(count_token(&mapped, "="), 2);
+        RuleParser::new(mapped_tags).with_tag_constraints(constraints)
     }
 
-    // Tests for contains_logical_op
-    #[test]
-    fn test_contains_logical_op_with_and() {
-        let tokens = vec![
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            "&".to_string(),
-        ];
-        assert!(RuleParser::contains_logical_op(&tokens));
+    pub fn with_tag_constraints(mut self, constraints: types::TagConstraintsMap) -> Self {
+        self.m_tag_constraints = constraints;
+        self
     }
 
-    #[test]
-    fn test_contains_logical_op_with_or() {
-        let tokens = vec![
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            "|".to_string(),
-        ];
-        assert!(RuleParser::contains_logical_op(&tokens));
+    pub fn with_case_folding(mut self, case_folding: CaseFolding) -> Self {
+        self.m_case_folding = case_folding;
+        self
     }
 
-    #[test]
-    fn test_contains_logical_op_without_logical_ops() {
-        let tokens = vec!["colour".to_string(), "=".to_string(), "red".to_string()];
-        assert!(!RuleParser::contains_logical_op(&tokens));
+    // Functions rules may call -- e.g. "before" for "-created_at before(2024-01-01)"
+    pub fn with_plugins(mut self, plugins: PluginMap) -> Self {
+        self.m_plugins = plugins;
+        self
     }
 
-    // Tests for create_leaf_node
-    #[test]
-    fn test_create_leaf_node_equals() {
-        let tokens = vec!["colour".to_string(), "=".to_string(), "red".to_string()];
-        let result = RuleParser::create_leaf_node(tokens);
-
-        assert!(result.is_ok());
-        let node = result.unwrap();
-        assert_eq!(node.token, crate::parser::types::Token::Equals);
-        assert!(node.left.is_none());
-        assert!(node.right.is_none());
+    // Groups of values or tags no object can hold together, for satisfiability checks
+    pub fn with_exclusive_groups(mut self, groups: Vec<ExclusiveGroup>) -> Self {
+        self.m_exclusive_groups = groups;
+        self
     }
 
-    #[test]
-    fn test_create_leaf_node_not_equals() {
-        let tokens = vec!["colour".to_string(), "!".to_string(), "red".to_string()];
-        let result = RuleParser::create_leaf_node(tokens);
-
-        assert!(result.is_ok());
-        let node = result.unwrap();
-        assert_eq!(node.token, crate::parser::types::Token::NotEquals);
-        assert!(node.left.is_none());
-        assert!(node.right.is_none());
+    // Whether values outside a tag's listed values make comparisons Unknown, and whether rules
+    // left Unknown match -- e.g. UnknownPolicy::UnknownIsFalse
+    pub fn with_unknown_policy(mut self, policy: UnknownPolicy) -> Self {
+        self.m_unknown_policy = policy;
+        self
     }
 
-    #[test]
-    fn test_create_leaf_node_invalid_length() {
-        let tokens = vec!["colour".to_string(), "=".to_string()];
-        let result = RuleParser::create_leaf_node(tokens);
-        assert!(result.is_err());
+    // How comparisons on a tag an object doesn't have evaluate -- e.g.
+    // MissingTagPolicy::SkipClause
+    pub fn with_missing_tag_policy(mut self, policy: MissingTagPolicy) -> Self {
+        self.m_missing_tag_policy = policy;
+        self
     }
 
-    #[test]
-    fn test_create_leaf_node_invalid_operator() {
-        let tokens = vec!["colour".to_string(), "&".to_string(), "red".to_string()];
-        let result = RuleParser::create_leaf_node(tokens);
-        assert!(result.is_err());
+    pub fn missing_tag_policy(&self) -> MissingTagPolicy {
+        self.m_missing_tag_policy
     }
 
-    // Tests for find_lowest_prec_op_index
-    #[test]
-    fn test_find_lowest_prec_op_index_single_and() {
-        let tokens = vec![
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            "&".to_string(),
-            "size".to_string(),
-            "=".to_string(),
-            "large".to_string(),
-        ];
-        let index = RuleParser::find_lowest_prec_op_index(&tokens);
-        assert_eq!(index, 3); // The "&" is at index 3
+    // How tightly '&', '|' and '->' bind -- e.g. '|' over '&'. The table is expected to have
+    // passed OperatorPrecedence::check.
+    pub fn with_precedence(mut self, precedence: OperatorPrecedence) -> Self {
+        self.m_precedence = precedence;
+        self
     }
 
-    #[test]
-    fn test_find_lowest_prec_op_index_and_before_or() {
-        let tokens = vec![
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            "&".to_string(),
-            "size".to_string(),
-            "=".to_string(),
-            "large".to_string(),
-            "|".to_string(),
-            "shape".to_string(),
-            "=".to_string(),
-            "circle".to_string(),
-        ];
-        let index = RuleParser::find_lowest_prec_op_index(&tokens);
-        // "&" has precedence 0, "|" has precedence 1, so "|" is lower precedence
-        assert_eq!(index, 7);
+    // How deeply a rule may nest groups and how many tokens it may have
+    pub fn with_rule_limits(mut self, limits: RuleLimits) -> Self {
+        self.m_limits = limits;
+        self
     }
 
-    #[test]
-    fn test_find_lowest_prec_op_index_nested_parens() {
-        let tokens = vec![
-            "(".to_string(),
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            ")".to_string(),
-            "&".to_string(),
-            "size".to_string(),
-            "=".to_string(),
-            "large".to_string(),
-        ];
-        let index = RuleParser::find_lowest_prec_op_index(&tokens);
-        // The "&" is at depth 0, while operators inside parens are at depth 1
-        assert_eq!(index, 5); // The "&" is at index 5
+    fn find_plugin(&self, name: &str) -> Option<&dyn RulePlugin> {
+        let fold = |s: &str| self.m_case_folding.fold(s);
+        let name = fold(name);
+
+        self.m_plugins
+            .iter()
+            .find(|(plugin_name, _)| fold(plugin_name) == name)
+            .map(|(_, plugin)| plugin.as_ref())
     }
 
-    // Tests for build_ast
-    #[test]
-    fn test_build_ast_simple_leaf() {
-        let tokens = vec!["colour".to_string(), "=".to_string(), "red".to_string()];
-        let result = RuleParser::build_ast(tokens);
+    // A call must name a registered plugin, which accepts its arguments
+    fn check_call(&self, call: &str) -> Result<(), RulesError> {
+        let (name, args) = plugin::parse_call(call).ok_or_else(|| {
+            RulesError::RuleParseError(format!("Invalid function call: {}", call))
+        })?;
 
-        assert!(result.is_ok());
-        let node = result.unwrap();
-        assert_eq!(node.token, crate::parser::types::Token::Equals);
+        let plugin = self.find_plugin(name).ok_or_else(|| {
+            RulesError::RuleParseError(format!("Rule calls unknown function: {}", name))
+        })?;
+
+        plugin.validate(&args).map_err(|reason| {
+            RulesError::RuleParseError(format!("Invalid arguments to {}: {}", name, reason))
+        })
+    }
+
+    // Regex compared with '~', compiled once. Like tag patterns, they ignore case -- e.g.
+    // "^ab[0-9]+$" matches "AB12".
+    fn regex(&self, pattern: &str) -> Result<Regex, RulesError> {
+        let mut regexes = self.m_regexes.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(regex) = regexes.get(pattern) {
+            return Ok(regex.clone());
+        }
+
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| {
+                RulesError::RuleParseError(format!("Invalid regex '{}': {}", pattern, e))
+            })?;
+        regexes.insert(pattern.to_string(), regex.clone());
+        Ok(regex)
+    }
+
+    // Name and values of the tag matching `name`, folded per the case-folding policy. Both
+    // sides are folded, so tags mapped with their original case still match.
+    fn find_tag(&self, name: &str) -> Option<(&types::TagName, types::TagValues)> {
+        let fold = |s: &str| self.m_case_folding.fold(s);
+        let name = fold(name);
+
+        self.m_mapped_tags
+            .iter()
+            .find(|(tag_name, _)| fold(tag_name) == name)
+            .map(|(tag_name, values)| (tag_name, values.iter().map(|v| fold(v)).collect()))
+    }
+
+    pub fn validate_rule(&self, rule: &str) -> Result<(), RulesError> {
+        self.validate_rule_internal(rule)
+    }
+
+    // Add every rule of `rules` which is invalid to `report`, each located by rule_error_at,
+    // rather than stopping at the first. Valid rules which can never or always match are added
+    // as warnings.
+    pub fn validate_rules(&self, rules: &[LoadedRule], report: &mut ValidationReport) {
+        for loaded in rules {
+            match self.validate_rule_internal(&loaded.rule) {
+                Err(e) => report.add(&loaded.file, loaded.line, rule_error_at(loaded, e)),
+                Ok(()) => report.warnings.extend(self.match_warning(loaded)),
+            }
+        }
+    }
+
+    // Warning for a valid rule which can never match, or matches every object, naming the
+    // comparisons responsible -- e.g. "Rule can never match, as colour = red and colour ! red
+    // can't all hold" for "- colour = red & colour ! red"
+    pub(crate) fn match_warning(&self, loaded: &LoadedRule) -> Option<Diagnostic> {
+        // E.g. "colour = red, size = small and size ! small"
+        let written = |clauses: Vec<Clause>, conjunction: &str| {
+            let clauses: Vec<String> = clauses.iter().map(Clause::to_string).collect();
+            match clauses.split_last() {
+                Some((last, rest)) if !rest.is_empty() => {
+                    format!("{} {} {}", rest.join(", "), conjunction, last)
+                }
+                _ => clauses.join(""),
+            }
+        };
+
+        let (code, message) = if let Ok(Some(clauses)) = self.contradiction(&loaded.rule) {
+            let message = if clauses.len() > 1 {
+                format!(
+                    "Rule can never match, as {} can't all hold",
+                    written(clauses, "and")
+                )
+            } else {
+                format!(
+                    "Rule can never match, as {} can't hold",
+                    written(clauses, "and")
+                )
+            };
+            (DiagnosticCode::Unsatisfiable, message)
+        } else if let Ok(Some(clauses)) = self.tautology(&loaded.rule) {
+            let message = format!(
+                "Rule always matches, as {} holds for every object",
+                written(clauses, "or")
+            );
+            (DiagnosticCode::AlwaysMatches, message)
+        } else {
+            return None;
+        };
+
+        Some(Diagnostic::warning(
+            code,
+            &loaded.file,
+            loaded.line,
+            message,
+        ))
+    }
+
+    fn validate_rule_internal(&self, line: &str) -> Result<(), RulesError> {
+        if file::line_blank_or_comment(line) {
+            return Ok(());
+        }
+
+        self.checked_tree(line).map(|_| ())
+    }
+
+    // A rule line's syntax tree once its syntax and tags are checked, with each '!' group's
+    // negation pushed down to its comparisons
+    fn checked_tree(&self, line: &str) -> Result<Node, RulesError> {
+        let (root, tokens) = self
+            .read_rule(line)
+            .map_err(|e| Self::add_error_context(e, line))?;
+
+        self.check_valid_tags(&tokens)
+            .map_err(|e| Self::add_error_context(e, line))?;
+
+        // Plugin calls and rank comparisons with variables can't be negated
+        self.push_negation(root, false)
+            .map_err(|e| Self::add_error_context(e, line))
+    }
+
+    // A rule line's syntax tree and its tokens with their types, with flag shorthand, ranges and
+    // comma lists expanded -- e.g. "- colour = red, blue" gives '|' over colour = red and
+    // colour = blue
+    fn read_rule(&self, line: &str) -> Result<(Node, MappedRuleTokens), RulesError> {
+        let lexemes = self.tokenise_rule(line)?;
+        self.check_rule_limits(line, &lexemes)?;
+        RuleReader::new(line, lexemes, &self.m_precedence).read()
+    }
+
+    // A rule's lexemes must fit the parser's RuleLimits, as checking, expanding and evaluating its
+    // syntax tree recurses per group, and explaining or tracing it per operator. Errors give the
+    // column of the first lexeme over a limit.
+    fn check_rule_limits(&self, line: &str, lexemes: &[Lexeme]) -> Result<(), RulesError> {
+        let limit_at = |lexeme: &Lexeme, message: String| {
+            let column = line[..lexeme.span.start].chars().count() + 1;
+            RulesError::RuleLimitError(format!("{}{}{}", message, err::COLUMN_SUFFIX, column))
+        };
+
+        let mut depth = 0;
+        for lexeme in lexemes {
+            if lexeme.is_op('(') {
+                depth += 1;
+                if depth > self.m_limits.max_depth {
+                    return Err(limit_at(
+                        lexeme,
+                        format!(
+                            "Rule nests groups deeper than the limit of {}",
+                            self.m_limits.max_depth
+                        ),
+                    ));
+                }
+            } else if lexeme.is_op(')') {
+                depth = depth.saturating_sub(1);
+            }
+        }
+
+        if let Some(lexeme) = lexemes.get(self.m_limits.max_length) {
+            return Err(limit_at(
+                lexeme,
+                format!(
+                    "Rule is longer than the limit of {} tokens",
+                    self.m_limits.max_length
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Byte offsets of a rule's comparisons, without its name, dash, priority and outcome -- e.g.
+    // "promo_1: -[10] colour = red => reject" gives 15..27, for "colour = red"
+    fn rule_body(rule: &str) -> Result<Span, RulesError> {
+        let (_name, unnamed) = string::split_name(rule)?;
+        let (dashed, _outcome) = string::split_outcome(unnamed)?;
+        let normalised = string::normalise(dashed)?;
+        let (_priority, body) = string::split_priority(&normalised)?;
+
+        // Each step keeps the start or end of what the one before gave, so the body is found from
+        // what they trimmed -- the name, the dash and the whitespace around it, and the priority
+        let after_dash = &dashed.trim_start()[1..];
+        let normalised_start = rule.len() - unnamed.len()
+            + (dashed.len() - dashed.trim_start().len())
+            + 1
+            + (after_dash.len() - after_dash.trim_start().len());
+        let start = normalised_start + normalised.len() - body.len();
+        Ok(start..start + body.len())
+    }
+
+    // Every comparison in a rule, with comma shorthand expanded -- e.g. "-colour = red, blue"
+    // gives [colour = red, colour = blue]. Tag names and values are folded per the case-folding
+    // policy, but not checked against the tag definitions.
+    pub fn clauses(&self, rule: &str) -> Result<Vec<Clause>, RulesError> {
+        let case_folding = self.m_case_folding;
+        if file::line_blank_or_comment(rule) {
+            return Ok(Vec::new());
+        }
+
+        let (_root, tokens) = self
+            .read_rule(rule)
+            .map_err(|e| Self::add_error_context(e, rule))?;
+        let mut clauses: Vec<Clause> = Vec::new();
+        let mut tag_name: Option<String> = None;
+        let mut comparison_token: Option<Token> = None;
+
+        for (key, token_type, _paren_depth) in tokens.iter() {
+            match token_type {
+                TokenType::TagName => {
+                    tag_name = Some(case_folding.fold(key));
+                }
+                TokenType::ComparisonOp => {
+                    comparison_token = Self::token_from_str(key);
+                }
+                TokenType::Presence => {
+                    if let Some(tag_name) = &tag_name {
+                        clauses.push(Clause {
+                            tag_name: tag_name.clone(),
+                            comparison_op: ComparisonOp::PRES,
+                            tag_value: String::new(),
+                        });
+                    }
+                }
+                TokenType::TagValue => {
+                    let comparison_op = comparison_token
+                        .as_ref()
+                        .and_then(|token| Self::comparison_op_for(token, key));
+                    if let (Some(tag_name), Some(comparison_op)) = (&tag_name, comparison_op) {
+                        // Lowercasing a regex would change it -- e.g. \D to \d
+                        let tag_value = if comparison_op == ComparisonOp::REGX {
+                            key.clone()
+                        } else {
+                            case_folding.fold(key)
+                        };
+                        clauses.push(Clause {
+                            tag_name: tag_name.clone(),
+                            comparison_op,
+                            tag_value,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(clauses)
+    }
+
+    fn add_error_context(error: RulesError, rule: &str) -> RulesError {
+        error.map_rule_message(|msg| format!("'{}': {}", rule, msg))
+    }
+
+    // Expand flag shorthand into comparisons -- e.g. "is_fragile & !is_boxed" gives
+    // "is_fragile = true & is_boxed = false". A tag name followed by '&', '|', ')', '->' or the
+    // end of the rule is a flag. A '!' before a presence check negates it as a group -- e.g.
+    // "!colour?" gives "! ( colour ? )". Each "!!" folds away, so "!!is_fragile" is
+    // "is_fragile = true". Added lexemes have the span of the flag or '!'. Errors if a known tag
+    // written as a flag isn't one.
+    fn expand_flag_shorthand(&self, lexemes: Vec<Lexeme>) -> Result<Vec<Lexeme>, RulesError> {
+        let mut expanded: Vec<Lexeme> = Vec::new();
+        let mut expect_tag_name = true;
+        let mut negated: Option<Span> = None;
+        let mut negated_presence = false;
+        let mut folded = false;
+
+        for (i, lexeme) in lexemes.iter().enumerate() {
+            // Second '!' of a folded pair
+            if std::mem::take(&mut folded) {
+                continue;
+            }
+
+            if lexeme.kind == LexemeKind::Op {
+                if negated.is_some() {
+                    break;
+                }
+
+                if lexeme.is_op('?') && std::mem::take(&mut negated_presence) {
+                    expanded.push(lexeme.clone());
+                    expanded.push(Lexeme::op(')', lexeme.span.clone()));
+                    continue;
+                }
+
+                if lexeme.is_op('!') && expect_tag_name {
+                    // A negation negated cancels out -- e.g. "!!(colour = red)"
+                    if lexemes.get(i + 1).is_some_and(|next| next.is_op('!')) {
+                        folded = true;
+                        continue;
+                    }
+                    // '!' before a group negates the group -- e.g. "!(colour = red & size = large)"
+                    if lexemes.get(i + 1).is_some_and(|next| next.is_op('(')) {
+                        expanded.push(lexeme.clone());
+                    } else {
+                        negated = Some(lexeme.span.clone());
+                    }
+                    continue;
+                }
+
+                expect_tag_name = LHS_CHARS.iter().any(|c| lexeme.is_op(*c));
+                expanded.push(lexeme.clone());
+                continue;
+            }
+
+            if !expect_tag_name {
+                expanded.push(lexeme.clone());
+                continue;
+            }
+
+            expect_tag_name = false;
+
+            let next = lexemes.get(i + 1);
+            if let Some(bang) = &negated
+                && next.is_some_and(|next| next.is_op('?'))
+            {
+                expanded.push(Lexeme::op('!', bang.clone()));
+                expanded.push(Lexeme::op('(', bang.clone()));
+                negated_presence = true;
+            }
+            expanded.push(lexeme.clone());
+
+            let is_flag = next
+                .is_none_or(|next| ['&', '|', ')', IMPLIES_CHAR].iter().any(|c| next.is_op(*c)));
+
+            if is_flag {
+                if self.is_non_flag_tag(&lexeme.text) {
+                    return Err(RulesError::RuleParseError(format!(
+                        "'{}' is not a flag tag; compare it to a value",
+                        lexeme.text
+                    )));
+                }
+                let value = if negated.is_some() { "false" } else { "true" };
+                expanded.push(Lexeme::op('=', lexeme.span.clone()));
+                expanded.push(Lexeme::new(LexemeKind::Word, value, lexeme.span.clone()));
+            } else if negated.is_some() && !negated_presence {
+                return Err(RulesError::RuleParseError(format!(
+                    "'!' before a tag name can only negate a flag -- e.g. -!{}",
+                    lexeme.text
+                )));
+            }
+
+            negated = None;
+        }
+
+        if negated.is_some() {
+            return Err(RulesError::RuleParseError(
+                "'!' must be followed by a flag tag name".to_string(),
+            ));
+        }
+
+        Ok(expanded)
+    }
+
+    // Whether `name` is a known tag other than a flag, whose values are true and false -- e.g.
+    // colour. Unknown tags are left for check_valid_tags to report.
+    fn is_non_flag_tag(&self, name: &str) -> bool {
+        let Some((tag_name, values)) = self.find_tag(name) else {
+            return false;
+        };
+        let flag = self
+            .m_tag_constraints
+            .get(tag_name)
+            .is_some_and(|constraints| constraints.flag)
+            || (values.len() == 2
+                && ["true", "false"]
+                    .iter()
+                    .all(|v| values.contains(&v.to_string())));
+        !flag
+    }
+
+    // Expand ranges into comma lists of their values -- e.g. "floor = 2..4" gives
+    // "floor = 2 , 3 , 4". Only '=' compares with a range, and calls, quoted values and regexes
+    // are left as they are. The values and commas have the range's span.
+    fn expand_ranges(lexemes: Vec<Lexeme>) -> Result<Vec<Lexeme>, RulesError> {
+        let mut expanded: Vec<Lexeme> = Vec::new();
+        let mut last_comparison_op: Option<char> = None;
+
+        for (i, lexeme) in lexemes.iter().enumerate() {
+            if let Some(op) = ['=', '!', '<', '>', '~']
+                .into_iter()
+                .find(|op| lexeme.is_op(*op))
+            {
+                last_comparison_op = Some(op);
+            }
+
+            let range = if lexeme.kind != LexemeKind::Word || last_comparison_op == Some('~') {
+                None
+            } else {
+                string::split_range(&lexeme.text)?
+            };
+            let Some((start, end)) = range else {
+                expanded.push(lexeme.clone());
+                continue;
+            };
+
+            let follows_equals = match i.checked_sub(1).map(|previous| &lexemes[previous]) {
+                Some(previous) if previous.is_op('=') => true,
+                Some(previous) if previous.is_op(',') => last_comparison_op == Some('='),
+                _ => false,
+            };
+            if !follows_equals {
+                return Err(RulesError::RuleParseError(format!(
+                    "Range '{}' can only be compared with '=' -- e.g. floor = 1..10",
+                    lexeme.text
+                )));
+            }
+            if end - start >= MAX_RANGE_VALUES {
+                return Err(RulesError::RuleParseError(format!(
+                    "Range '{}' has more than {} values",
+                    lexeme.text, MAX_RANGE_VALUES
+                )));
+            }
+
+            for value in start..=end {
+                if value > start {
+                    expanded.push(Lexeme::op(',', lexeme.span.clone()));
+                }
+                expanded.push(Lexeme::new(
+                    LexemeKind::Word,
+                    &value.to_string(),
+                    lexeme.span.clone(),
+                ));
+            }
+        }
+
+        Ok(expanded)
+    }
+
+    fn call_depth_change(c: char) -> i32 {
+        match c {
+            '(' => 1,
+            ')' => -1,
+            _ => 0,
+        }
+    }
+
+    // The lexemes of a rule line's comparisons, with flag shorthand and ranges expanded -- e.g.
+    // "- is_fragile & floor = 1..2" gives is_fragile = true & floor = 1 , 2
+    fn tokenise_rule(&self, line: &str) -> Result<Vec<Lexeme>, RulesError> {
+        let body = Self::rule_body(line)?;
+        Self::expand_ranges(self.expand_flag_shorthand(lex_rule(line, body)?)?)
+    }
+
+    fn check_valid_tags(&self, tokens: &MappedRuleTokens) -> Result<(), RulesError> {
+        let mut last_tag_name: Option<String> = None;
+        let mut last_comparison_op: Option<Token> = None;
+
+        for (key, token_type, _paren_depth) in tokens.iter() {
+            let written = key;
+            let key = self.m_case_folding.fold(key);
+            if *token_type == TokenType::TagName {
+                // Macros the rule's file defines were expanded when it was loaded
+                if key.starts_with(VARIABLE_PREFIX) {
+                    return Err(RulesError::RuleParseError(format!(
+                        "Unknown macro '{}', expected one defined in the rule's file like {}warm = \
+                         colour = red",
+                        written, VARIABLE_PREFIX
+                    )));
+                }
+                if self.find_tag(&key).is_none() {
+                    return Err(RulesError::RuleParseError(format!(
+                        "Rule contains invalid TagName: {}",
+                        key
+                    )));
+                }
+                last_tag_name = Some(key.clone());
+            } else if *token_type == TokenType::ComparisonOp {
+                last_comparison_op = Self::token_from_str(&key);
+            } else if *token_type == TokenType::TagValue {
+                // Arguments are checked by the plugin, and regexes compiled, rather than checked
+                // against the tag's values
+                if last_comparison_op == Some(Token::Call) {
+                    if plugin::parse_call(&key).is_some() {
+                        self.check_call(&key)?;
+                    } else {
+                        self.regex(written)?;
+                    }
+                    continue;
+                }
+
+                let tag_name = last_tag_name.as_ref().ok_or_else(|| {
+                    RulesError::RuleParseError(format!(
+                        "TagValue '{}' has no associated TagName",
+                        key
+                    ))
+                })?;
+
+                let (mapped_name, valid_values) = self.find_tag(tag_name).ok_or_else(|| {
+                    RulesError::RuleParseError(format!(
+                        "No TagName '{}' found for TagValue '{}'",
+                        tag_name, key
+                    ))
+                })?;
+
+                let constraints = self
+                    .m_tag_constraints
+                    .get(mapped_name)
+                    .cloned()
+                    .unwrap_or_default();
+
+                // The other tag's values are only known when the rule is evaluated
+                if let Some(other) = tag_reference(&key) {
+                    if !matches!(last_comparison_op, Some(Token::Equals | Token::NotEquals)) {
+                        return Err(RulesError::RuleParseError(format!(
+                            "Rule compares TagName '{}' with '{}', but only '=' and '!' take a tag",
+                            tag_name, key
+                        )));
+                    }
+                    if self.find_tag(other).is_none() {
+                        return Err(RulesError::RuleParseError(format!(
+                            "Rule compares TagName '{}' with unknown TagName '{}'",
+                            tag_name, other
+                        )));
+                    }
+                    continue;
+                }
+
+                // Variables only have a value when the rule is evaluated
+                let is_variable = key.starts_with(VARIABLE_PREFIX);
+                if is_variable {
+                    check_variable(&key)?;
+                }
+
+                // A glob must match a listed value, unless any value is allowed
+                if key.contains(string::WILDCARD) {
+                    if !matches!(last_comparison_op, Some(Token::Equals | Token::NotEquals)) {
+                        return Err(RulesError::RuleParseError(format!(
+                            "Rule compares TagName '{}' with '{}', but only '=' and '!' take a glob",
+                            tag_name, key
+                        )));
+                    }
+                    let any_value = constraints.open || constraints.pattern.is_some();
+                    if !any_value && !valid_values.iter().any(|v| string::glob_matches(&key, v)) {
+                        return Err(RulesError::RuleParseError(format!(
+                            "Rule contains invalid TagValue: '{}' matches none of the values of TagName '{}'",
+                            key, tag_name
+                        )));
+                    }
+                    continue;
+                }
+
+                if !is_variable && !constraints.allows(&valid_values, &key) {
+                    return Err(RulesError::RuleParseError(format!(
+                        "Rule contains invalid TagValue: '{}' is not a valid value for TagName '{}'",
+                        key, tag_name
+                    )));
+                }
+
+                // Rank comparisons need a declared order -- e.g. "- size: small < medium < large"
+                if let Some(op @ (Token::LessThan | Token::GreaterThan)) = &last_comparison_op
+                    && !constraints.ordered
+                {
+                    return Err(RulesError::RuleParseError(format!(
+                        "Rule compares TagName '{}' with '{}', but its values are not ordered",
+                        tag_name, op
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn token_from_str(token: &str) -> Option<Token> {
+        let mut chars = token.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Token::from_char(c),
+            _ => None,
+        }
+    }
+
+    fn comparison_op_from_token(token: &Token) -> Option<ComparisonOp> {
+        match token {
+            Token::Equals => Some(ComparisonOp::ISEQ),
+            Token::NotEquals => Some(ComparisonOp::NOEQ),
+            Token::LessThan => Some(ComparisonOp::LSTH),
+            Token::GreaterThan => Some(ComparisonOp::GRTH),
+            Token::Call => Some(ComparisonOp::CALL),
+            _ => None,
+        }
+    }
+
+    // Comparison a token makes with `tag_value`. '~' calls a plugin when the value is a call,
+    // and otherwise matches it as a regex -- e.g. "before(2024-01-01)" or "^AB[0-9]+$"
+    fn comparison_op_for(token: &Token, tag_value: &str) -> Option<ComparisonOp> {
+        match Self::comparison_op_from_token(token)? {
+            ComparisonOp::CALL if plugin::parse_call(tag_value).is_none() => {
+                Some(ComparisonOp::REGX)
+            }
+            comparison_op => Some(comparison_op),
+        }
+    }
+
+    pub(crate) fn string_to_rule(&self, rule_str: &str) -> Result<AstRule, RulesError> {
+        // E.g., "- colour = red & size = large" becomes '&' over its two comparisons
+        Ok(AstRule {
+            root_node: self.checked_tree(rule_str)?,
+        })
+    }
+
+    // A rule in canonical form, keeping its name, priority and outcome -- e.g.
+    // "p:-[5](size=small|size=large)&colour=red=>reject" gives
+    // "p: -[5] (size = small | size = large) & colour = red => reject". Errors if the rule is
+    // invalid, or if what it would be written as doesn't parse back to the same rule.
+    pub fn format_rule(&self, rule: &str) -> Result<String, RulesError> {
+        let (name, unnamed) = string::split_name(rule)?;
+        let ast = self.string_to_rule(rule)?;
+
+        let mut formatted = Self::with_priority_and_outcome(
+            unnamed,
+            ast.to_canonical_string_with(&self.m_precedence),
+        )?;
+        if let Some(name) = name {
+            formatted = format!("{}{} {}", name, string::NAME_SEPARATOR, formatted);
+        }
+
+        if self.string_to_rule(&formatted).ok().as_ref() != Some(&ast) {
+            return Err(RulesError::RuleParseError(format!(
+                "Can't format '{}': it would be written as '{}', which isn't the same rule",
+                rule, formatted
+            )));
+        }
+        Ok(formatted)
+    }
+
+    // `dashed`, comparisons written from their dash, with the priority and outcome of `rule`, which
+    // has no name -- e.g. "- colour = red" for "-[5] colour=red => reject" gives
+    // "-[5] colour = red => reject"
+    fn with_priority_and_outcome(rule: &str, dashed: String) -> Result<String, RulesError> {
+        let (rule, outcome) = string::split_outcome(rule)?;
+        let (priority, _body) = string::split_priority(&string::normalise(rule.trim())?)?;
+
+        let mut written = dashed;
+        if priority != 0 {
+            written = written.replacen('-', &format!("-[{}]", priority), 1);
+        }
+        if let Some(outcome) = outcome {
+            written = format!("{} {} {}", written, string::OUTCOME_ARROW, outcome);
+        }
+        Ok(written)
+    }
+
+    // `node` with each '!' group's negation pushed down to its comparisons by De Morgan's laws,
+    // so parsed rules and their subrules only hold '&', '|' and comparisons -- e.g.
+    // "!(colour = red & size > small)" gives "colour ! red | size ! medium & size ! large"
+    fn push_negation(&self, mut node: Node, negate: bool) -> Result<Node, RulesError> {
+        if let Some(clause) = &node.clause {
+            return if negate {
+                self.negated_leaf(clause)
+            } else {
+                Ok(node)
+            };
+        }
+
+        let token = match node.token {
+            Token::Not => {
+                let operand = node.left.take().ok_or_else(|| {
+                    RulesError::RuleParseError("'!' is missing its group".to_string())
+                })?;
+                return self.push_negation(*operand, !negate);
+            }
+            Token::And if negate => Token::Or,
+            Token::Or if negate => Token::And,
+            ref token => token.clone(),
+        };
+        if node.left.is_none() || node.right.is_none() || !matches!(token, Token::And | Token::Or) {
+            let push = |child: Option<Box<Node>>| -> Result<Option<Box<Node>>, RulesError> {
+                child
+                    .map(|child| Ok(Box::new(self.push_negation(*child, negate)?)))
+                    .transpose()
+            };
+            return Ok(Node {
+                token,
+                left: push(node.left.take())?,
+                right: push(node.right.take())?,
+                clause: None,
+            });
+        }
+
+        // Down the chain of the operator rather than recursing, so the tree keeps its shape
+        let operands = node
+            .into_chain()
+            .into_iter()
+            .map(|operand| self.push_negation(operand, negate))
+            .collect::<Result<Vec<Node>, RulesError>>()?;
+        Ok(Node::from_chain(token, operands).unwrap_or_default())
+    }
+
+    // Comparisons holding exactly when `clause` doesn't, '&'ed together -- e.g. "size ! small"
+    // for "size < medium"
+    fn negated_leaf(&self, clause: &Clause) -> Result<Node, RulesError> {
+        let leaf = |clause: Clause| Node {
+            token: Token::for_comparison(&clause.comparison_op),
+            clause: Some(clause),
+            left: None,
+            right: None,
+        };
+        let Some(clauses) = self.negate_clause(clause) else {
+            return Err(RulesError::RuleParseError(format!(
+                "Can't negate '{}': plugin calls, regexes and rank comparisons with variables have no opposite",
+                clause
+            )));
+        };
+
+        let negated = clauses.into_iter().map(leaf).reduce(|left, right| Node {
+            token: Token::And,
+            left: Some(Box::new(left)),
+            right: Some(Box::new(right)),
+            clause: None,
+        });
+        // Nothing ranks outside the lowest or highest value, so the negation always holds --
+        // e.g. "!(size < small)" gives "size ! small | size = small"
+        Ok(negated.unwrap_or_else(|| Node {
+            token: Token::Or,
+            left: Some(Box::new(leaf(Clause {
+                comparison_op: ComparisonOp::NOEQ,
+                ..clause.clone()
+            }))),
+            right: Some(Box::new(leaf(Clause {
+                comparison_op: ComparisonOp::ISEQ,
+                ..clause.clone()
+            }))),
+            clause: None,
+        }))
+    }
+
+    // Whether `obj` satisfies `rule` -- e.g. {colour: [red]} satisfies "-colour = red | size = large"
+    pub fn rule_matches(&self, rule: &str, obj: &types::Object) -> Result<bool, RulesError> {
+        self.rule_matches_with_context(rule, obj, &EvalContext::default(), audit::unix_timestamp())
+    }
+
+    // Whether `obj` satisfies `rule` with its variables filled in from `context`, and $today as
+    // the date at `timestamp` -- e.g. {region: [eu]} satisfies "- region = $ctx.region" when
+    // the context has region = eu
+    pub fn rule_matches_with_context(
+        &self,
+        rule: &str,
+        obj: &types::Object,
+        context: &EvalContext,
+        timestamp: u64,
+    ) -> Result<bool, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        if self.m_missing_tag_policy == MissingTagPolicy::Error {
+            self.check_tags_present(&self.clauses(rule)?, obj, rule)?;
+        }
+        let root = if rule.contains(VARIABLE_PREFIX) {
+            self.resolve_variables(&ast.root_node, context, timestamp)?
+        } else {
+            ast.root_node
+        };
+        Ok(self.evaluate_node(&root, obj))
+    }
+
+    // How each node of the rule evaluated against the object, with variables filled in as for
+    // rule_matches -- e.g. "- colour = red & size = large" against { colour: [red] } gives '&'
+    // false above "colour = red" true and "size = large" false
+    pub fn explain(
+        &self,
+        rule: &str,
+        obj: &types::Object,
+        timestamp: u64,
+    ) -> Result<ExplanationNode, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        let root = if rule.contains(VARIABLE_PREFIX) {
+            self.resolve_variables(&ast.root_node, &EvalContext::default(), timestamp)?
+        } else {
+            ast.root_node
+        };
+        Ok(self.explain_node(&root, obj))
+    }
+
+    // Every operand is explained, even once an operator's outcome is known
+    fn explain_node(&self, node: &Node, obj: &types::Object) -> ExplanationNode {
+        if let Some(clause) = &node.clause {
+            return ExplanationNode {
+                token: node.token.clone(),
+                clause: Some(clause.clone()),
+                values: self.tag_values(obj, &clause.tag_name),
+                matched: self.clause_matches(clause, obj),
+                children: Vec::new(),
+            };
+        }
+
+        let children: Vec<ExplanationNode> = [&node.left, &node.right]
+            .into_iter()
+            .flatten()
+            .map(|child| self.explain_node(child, obj))
+            .collect();
+        let matched = match node.token {
+            Token::And => !children.is_empty() && children.iter().all(|child| child.matched),
+            Token::Or => children.iter().any(|child| child.matched),
+            _ => false,
+        };
+        ExplanationNode {
+            token: node.token.clone(),
+            clause: None,
+            values: Vec::new(),
+            matched,
+            children,
+        }
+    }
+
+    // The nodes of `rule` visited evaluating it against `obj`, as rule_matches_with_context
+    // would, and whether it matched -- e.g. "- colour = red & size = large" against
+    // { colour: [blue] } visits '&' then "colour = red", skipping "size = large"
+    pub fn trace(
+        &self,
+        rule: &str,
+        obj: &types::Object,
+        context: &EvalContext,
+        timestamp: u64,
+    ) -> Result<(Vec<TraceStep>, bool), RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        if self.m_missing_tag_policy == MissingTagPolicy::Error {
+            self.check_tags_present(&self.clauses(rule)?, obj, rule)?;
+        }
+        let root = if rule.contains(VARIABLE_PREFIX) {
+            self.resolve_variables(&ast.root_node, context, timestamp)?
+        } else {
+            ast.root_node
+        };
+
+        let mut steps: Vec<TraceStep> = Vec::new();
+        let truth = self.trace_node(&root, obj, 0, &mut steps);
+        Ok((steps, truth.collapse(self.m_unknown_policy)))
+    }
+
+    // Records `node` then the operands evaluated, short-circuiting as evaluate_node does, or as
+    // node_truth does under an UnknownPolicy other than TwoValued
+    fn trace_node(
+        &self,
+        node: &Node,
+        obj: &types::Object,
+        depth: usize,
+        steps: &mut Vec<TraceStep>,
+    ) -> Truth {
+        let index = steps.len();
+        steps.push(TraceStep {
+            depth,
+            token: node.token.clone(),
+            clause: node.clause.clone(),
+            values: node
+                .clause
+                .as_ref()
+                .map_or_else(Vec::new, |clause| self.tag_values(obj, &clause.tag_name)),
+            result: false,
+        });
+
+        let truth = match (&node.token, &node.clause, &node.left, &node.right) {
+            (_, Some(clause), _, _) if self.m_unknown_policy == UnknownPolicy::TwoValued => {
+                Truth::from(self.clause_matches(clause, obj))
+            }
+            (_, Some(clause), _, _) => self.clause_truth(clause, obj),
+            (Token::And, None, Some(left), Some(right)) => {
+                match self.trace_node(left, obj, depth + 1, steps) {
+                    Truth::False => Truth::False,
+                    truth => truth.and(self.trace_node(right, obj, depth + 1, steps)),
+                }
+            }
+            (Token::Or, None, Some(left), Some(right)) => {
+                match self.trace_node(left, obj, depth + 1, steps) {
+                    Truth::True => Truth::True,
+                    truth => truth.or(self.trace_node(right, obj, depth + 1, steps)),
+                }
+            }
+            _ => Truth::False,
+        };
+        steps[index].result = truth.collapse(self.m_unknown_policy);
+        truth
+    }
+
+    // The object's values for `tag_name`, whatever its case -- e.g. [red] for colour
+    fn tag_values(&self, obj: &types::Object, tag_name: &str) -> Vec<String> {
+        let fold = |s: &str| self.m_case_folding.fold(s);
+        obj.iter()
+            .filter(|(k, _)| fold(k) == fold(tag_name))
+            .flat_map(|(_, values)| values.iter().cloned())
+            .collect()
+    }
+
+    // Copy of `node` with each variable replaced by its value
+    fn resolve_variables(
+        &self,
+        node: &Node,
+        context: &EvalContext,
+        timestamp: u64,
+    ) -> Result<Node, RulesError> {
+        let operands = node.chain();
+        if operands.len() > 1 {
+            // Down the chain of the operator rather than recursing, so the tree keeps its shape
+            let resolved = operands
+                .into_iter()
+                .map(|operand| self.resolve_variables(operand, context, timestamp))
+                .collect::<Result<Vec<Node>, RulesError>>()?;
+            return Ok(Node::from_chain(node.token.clone(), resolved).unwrap_or_default());
+        }
+
+        let resolve_child = |child: &Option<Box<Node>>| -> Result<Option<Box<Node>>, RulesError> {
+            child
+                .as_ref()
+                .map(|child| Ok(Box::new(self.resolve_variables(child, context, timestamp)?)))
+                .transpose()
+        };
+
+        let clause = match &node.clause {
+            Some(clause) if clause.tag_value.starts_with(VARIABLE_PREFIX) => Some(Clause {
+                tag_value: self.variable_value(&clause.tag_value, context, timestamp)?,
+                ..clause.clone()
+            }),
+            clause => clause.clone(),
+        };
+
+        Ok(Node {
+            token: node.token.clone(),
+            left: resolve_child(&node.left)?,
+            right: resolve_child(&node.right)?,
+            clause,
+        })
+    }
+
+    // Value of a variable -- e.g. "eu" for $ctx.region, or "2025-01-01" for $today
+    fn variable_value(
+        &self,
+        variable: &str,
+        context: &EvalContext,
+        timestamp: u64,
+    ) -> Result<String, RulesError> {
+        let folded = self.m_case_folding.fold(variable);
+        if folded == TODAY_VARIABLE {
+            return Ok(time::format_date(timestamp));
+        }
+
+        check_variable(&folded)?;
+        let name = folded.trim_start_matches(CONTEXT_VARIABLE_PREFIX);
+        context
+            .variables
+            .iter()
+            .find(|(key, _)| self.m_case_folding.fold(key) == name)
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| {
+                RulesError::RuleParseError(format!(
+                    "No value for {} in the evaluation context",
+                    variable
+                ))
+            })
+    }
+
+    // Whether `obj` satisfies an already parsed rule -- e.g. one built with Rules::query
+    pub fn ast_matches(&self, ast: &AstRule, obj: &types::Object) -> bool {
+        self.evaluate_node(&ast.root_node, obj)
+    }
+
+    // Rule in a canonical form, so rules differing only in spacing, case, parentheses or operand
+    // order compare equal -- e.g. "- (Size>small)&colour=red" gives "colour = red & size > small"
+    pub fn canonical_rule(&self, rule: &str) -> Result<String, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        Ok(self.canonical_node(&ast.root_node))
+    }
+
+    fn canonical_node(&self, node: &Node) -> String {
+        if let Some(clause) = &node.clause {
+            return self.canonical_clause(clause);
+        }
+
+        // Chains of one operator are flattened, so "(a & b) & c" and "a & (b & c)" are the same
+        let mut operands: Vec<String> = Vec::new();
+        self.collect_operands(node, &node.token, &mut operands);
+        operands.sort();
+        operands.dedup();
+
+        operands.join(&format!(" {} ", node.token))
+    }
+
+    fn collect_operands(&self, node: &Node, token: &Token, operands: &mut Vec<String>) {
+        // From a stack rather than recursing, as a long rule's chain is as deep as it is long
+        let mut nodes: Vec<&Node> = vec![node];
+        while let Some(node) = nodes.pop() {
+            if node.clause.is_none() && node.token == *token {
+                nodes.extend(node.right.as_deref());
+                nodes.extend(node.left.as_deref());
+            } else if node.clause.is_some() {
+                operands.push(self.canonical_node(node));
+            } else {
+                operands.push(format!("({})", self.canonical_node(node)));
+            }
+        }
+    }
+
+    fn canonical_clause(&self, clause: &Clause) -> String {
+        self.fold_clause(clause).to_string()
+    }
+
+    fn fold_clause(&self, clause: &Clause) -> Clause {
+        Clause {
+            tag_name: self.m_case_folding.fold(&clause.tag_name),
+            comparison_op: clause.comparison_op,
+            tag_value: self.m_case_folding.fold(&clause.tag_value),
+        }
+    }
+
+    // Conditions under which an object matches both rules, each a conjunction in canonical form
+    // -- e.g. "- colour = red, blue" and "- size = large" give ["colour = blue & size = large",
+    // "colour = red & size = large"]. Empty if no valid object could match both.
+    pub fn overlap(&self, first: &str, second: &str) -> Result<Vec<String>, RulesError> {
+        let (first, second) = (self.string_to_rule(first)?, self.string_to_rule(second)?);
+
+        let expanded = self
+            .conjunctions(&first.root_node)
+            .zip(self.conjunctions(&second.root_node))
+            .filter(|(left, right)| left.len().saturating_mul(right.len()) <= MAX_CONJUNCTIONS);
+        let Some((left, right)) = expanded else {
+            // Too large to expand, so assume they overlap
+            return Ok(vec![format!(
+                "({}) & ({})",
+                self.canonical_node(&first.root_node),
+                self.canonical_node(&second.root_node)
+            )]);
+        };
+
+        let mut overlap: Vec<String> = Vec::new();
+        for l in &left {
+            for r in &right {
+                let clauses = [l.clone(), r.clone()].concat();
+                if !self.conjunction_satisfiable(&clauses) {
+                    continue;
+                }
+
+                let mut terms: Vec<String> =
+                    clauses.iter().map(|c| self.canonical_clause(c)).collect();
+                terms.sort();
+                terms.dedup();
+                overlap.push(terms.join(" & "));
+            }
+        }
+
+        overlap.sort();
+        overlap.dedup();
+        Ok(overlap)
+    }
+
+    // Rule in disjunctive normal form, leaving out conjunctions which can't hold or which imply
+    // another, so rules matching the same objects give the same form however they're written --
+    // e.g. "- colour = red & (size = small | size = large)" and "- size = large & colour = red |
+    // colour = red & size = small" both give "colour = red & size = large | colour = red & size =
+    // small". Rules too large to expand give their canonical form.
+    pub fn normal_form(&self, rule: &str) -> Result<String, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        let Some(conjunctions) = self.conjunctions(&ast.root_node) else {
+            return Ok(self.canonical_node(&ast.root_node));
+        };
+
+        let terms: Vec<String> = self
+            .reduce_groups(&conjunctions, |clauses| {
+                !self.conjunction_satisfiable(clauses)
+            })
+            .iter()
+            .map(|term| {
+                let clauses: Vec<String> = term.iter().map(Clause::to_string).collect();
+                clauses.join(" & ")
+            })
+            .collect();
+
+        Ok(terms.join(" | "))
+    }
+
+    // Rule in conjunctive normal form, the counterpart of normal_form for SAT-style checks, leaving
+    // out clauses every valid object meets or which absorb another -- e.g. "- colour = red |
+    // size = large & shape = circle" gives (colour = red | shape = circle) & (colour = red | size
+    // = large). A rule which always matches gives no clauses. Errors if the rule is invalid or
+    // would have more than MAX_CONJUNCTIONS clauses.
+    pub fn rule_to_cnf(&self, rule: &str) -> Result<CnfRule, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        let Some(disjunctions) = self.disjunctions(&ast.root_node) else {
+            return Err(RulesError::RuleParseError(format!(
+                "Rule has more than {} CNF clauses",
+                MAX_CONJUNCTIONS
+            )));
+        };
+
+        Ok(CnfRule {
+            clauses: self.reduce_groups(&disjunctions, |clauses| self.disjunction_holds(clauses)),
+        })
+    }
+
+    // The rule written as its normal form, or in canonical form if it can never match -- e.g.
+    // "- (Size>small)&(colour=red,blue)" gives "- colour = blue & size > small | colour = red &
+    // size > small". Rules differing only in spacing, case, grouping or operand order give the
+    // same rule.
+    pub fn canonicalize(&self, rule: &str) -> Result<String, RulesError> {
+        let normal = self.normal_form(rule)?;
+        if normal.is_empty() {
+            return Ok(format!("- {}", self.canonical_rule(rule)?));
+        }
+        Ok(format!("- {}", normal))
+    }
+
+    // What a rule has in common with any rule duplicating it: the rule canonicalized, with its
+    // priority and outcome but not its name -- e.g. "p: -[5](size=small)&colour=red => reject"
+    // and "-[5] colour = red & size = small => reject" both give the second
+    pub fn duplicate_key(&self, rule: &str) -> Result<String, RulesError> {
+        let (_name, unnamed) = string::split_name(rule)?;
+        Self::with_priority_and_outcome(unnamed, self.canonicalize(rule)?)
+    }
+
+    // Hash of the canonicalized rule, the same across runs and platforms -- e.g. "9c4f0a1b2d3e4f56"
+    pub fn fingerprint(&self, rule: &str) -> Result<String, RulesError> {
+        Ok(audit::content_hash(self.canonicalize(rule)?.as_bytes()))
+    }
+
+    // Whether two rules match exactly the same objects, by comparing their normal forms
+    pub fn is_equivalent(&self, first: &str, second: &str) -> Result<bool, RulesError> {
+        Ok(self.normal_form(first)? == self.normal_form(second)?)
+    }
+
+    // Whether two rules match exactly the same valid objects, given the values each tag can have,
+    // i.e. each implies the other -- e.g. "- colour = red" and "- colour = red & size = small |
+    // colour = red & size ! small", which is_equivalent tells apart. False when unsure, if either
+    // rule is too large to check or they call plugins differently.
+    pub fn rules_equivalent(&self, first: &str, second: &str) -> Result<bool, RulesError> {
+        if self.is_equivalent(first, second)? {
+            return Ok(true);
+        }
+        Ok(self.implies(first, second)? && self.implies(second, first)?)
+    }
+
+    // Combinations of the values of every tag the rules compare which none of them match -- e.g.
+    // "{ colour: [green] }" when rules only cover red and blue. Each tag has one of its values or,
+    // unless it's required, none, and combinations breaking an exclusive group are left out. Tags
+    // without listed values, like pattern tags, are left off every combination.
+    pub fn coverage(
+        &self,
+        rules: &[&str],
+        max_examples: usize,
+    ) -> Result<CoverageReport, RulesError> {
+        let asts = rules
+            .iter()
+            .map(|rule| self.string_to_rule(rule))
+            .collect::<Result<Vec<AstRule>, RulesError>>()?;
+        let space = self.tag_space(rules, MAX_COVERAGE_COMBINATIONS)?;
+
+        let mut report = CoverageReport {
+            combinations: space.combinations.len(),
+            ..Default::default()
+        };
+        for combination in &space.combinations {
+            let obj = Self::combination_object(&space.tags, combination);
+            if asts
+                .iter()
+                .any(|ast| self.evaluate_node(&ast.root_node, &obj))
+            {
+                continue;
+            }
+            report.uncovered += 1;
+            if report.examples.len() < max_examples {
+                report.examples.push(obj);
+            }
+        }
+        report.tags = space.tags;
+        report.skipped_tags = space.skipped_tags;
+
+        Ok(report)
+    }
+
+    // Whether the rule matches each combination of values of the tags it compares, as for
+    // coverage -- e.g. "- colour = red" gives rows red: true, blue: false, (none): false
+    pub fn truth_table(&self, rule: &str) -> Result<TruthTable, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        let space = self.tag_space(&[rule], MAX_TRUTH_TABLE_ROWS)?;
+
+        let rows = space
+            .combinations
+            .into_iter()
+            .map(|values| {
+                let obj = Self::combination_object(&space.tags, &values);
+                TruthTableRow {
+                    matched: self.evaluate_node(&ast.root_node, &obj),
+                    values,
+                }
+            })
+            .collect();
+
+        Ok(TruthTable {
+            rule: rule.trim().to_string(),
+            tags: space.tags,
+            skipped_tags: space.skipped_tags,
+            rows,
+        })
+    }
+
+    fn combination_object(tags: &[types::TagName], values: &[Option<String>]) -> types::Object {
+        tags.iter()
+            .zip(values)
+            .filter_map(|(tag_name, value)| {
+                value
+                    .as_ref()
+                    .map(|value| (tag_name.clone(), vec![value.clone()]))
+            })
+            .collect()
+    }
+
+    // Combinations of values of every tag the rules compare, each tag having one of its values
+    // or, unless it's required, none. Combinations breaking an exclusive group are left out, and
+    // more than `limit` before that is an error.
+    fn tag_space(&self, rules: &[&str], limit: usize) -> Result<TagSpace, RulesError> {
+        let mut tag_names: BTreeSet<types::TagName> = BTreeSet::new();
+        for rule in rules {
+            for clause in self.clauses(rule)? {
+                if let Some((tag_name, _)) = self.find_tag(&clause.tag_name) {
+                    tag_names.insert(tag_name.clone());
+                }
+            }
+        }
+
+        let mut space = TagSpace {
+            tags: Vec::new(),
+            skipped_tags: Vec::new(),
+            combinations: Vec::new(),
+        };
+        let mut options: Vec<Vec<Option<String>>> = Vec::new();
+        for tag_name in tag_names {
+            let values = &self.m_mapped_tags[&tag_name];
+            if values.is_empty() {
+                space.skipped_tags.push(tag_name);
+                continue;
+            }
+
+            let mut tag_options: Vec<Option<String>> = values.iter().cloned().map(Some).collect();
+            if !self
+                .m_tag_constraints
+                .get(&tag_name)
+                .is_some_and(|constraints| constraints.required)
+            {
+                tag_options.push(None);
+            }
+            space.tags.push(tag_name);
+            options.push(tag_options);
+        }
+
+        let total = options
+            .iter()
+            .try_fold(1usize, |total, tag_options| {
+                total.checked_mul(tag_options.len())
+            })
+            .filter(|total| *total <= limit)
+            .ok_or_else(|| {
+                RulesError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Tags {} have more than {} combinations of values to check",
+                        space.tags.join(", "),
+                        limit
+                    ),
+                ))
+            })?;
+
+        // Counts through every combination, the first tag's value changing fastest
+        let mut positions = vec![0; options.len()];
+        for _ in 0..total {
+            let combination: Vec<Option<String>> = options
+                .iter()
+                .zip(&positions)
+                .map(|(tag_options, position)| tag_options[*position].clone())
+                .collect();
+            for (position, tag_options) in positions.iter_mut().zip(&options) {
+                *position += 1;
+                if *position < tag_options.len() {
+                    break;
+                }
+                *position = 0;
+            }
+
+            let obj = Self::combination_object(&space.tags, &combination);
+            if objects::check_exclusive_groups(&obj, &self.m_exclusive_groups, self.m_case_folding)
+                .is_empty()
+            {
+                space.combinations.push(combination);
+            }
+        }
+
+        Ok(space)
+    }
+
+    // Up to `n` distinct random valid objects the rule matches and `n` it doesn't, built from its
+    // conjunctions and the values of the tags it compares. The same seed gives the same examples.
+    pub fn generate_examples(
+        &self,
+        rule: &str,
+        n: usize,
+        seed: u64,
+    ) -> Result<RuleExamples, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        let conjunctions: Option<Vec<Vec<&Clause>>> =
+            self.conjunctions(&ast.root_node).map(|conjunctions| {
+                conjunctions
+                    .into_iter()
+                    .filter(|clauses| self.conjunction_satisfiable(clauses))
+                    .collect()
+            });
+
+        // Tags the rule compares, and those every object needs
+        let mut tag_names: BTreeSet<types::TagName> = BTreeSet::new();
+        for clause in self.clauses(rule)? {
+            if let Some((tag_name, _)) = self.find_tag(&clause.tag_name) {
+                tag_names.insert(tag_name.clone());
+            }
+        }
+        for (tag_name, constraints) in &self.m_tag_constraints {
+            if constraints.required && self.m_mapped_tags.contains_key(tag_name) {
+                tag_names.insert(tag_name.clone());
+            }
+        }
+
+        let valid = |obj: &types::Object| {
+            objects::validate_object(
+                obj,
+                &self.m_mapped_tags,
+                &self.m_tag_constraints,
+                self.m_case_folding,
+            )
+            .is_ok()
+                && objects::check_exclusive_groups(
+                    obj,
+                    &self.m_exclusive_groups,
+                    self.m_case_folding,
+                )
+                .is_empty()
+        };
+
+        let mut rng = fastrand::Rng::with_seed(seed);
+        let mut examples = RuleExamples::default();
+        let unsatisfiable = conjunctions.as_ref().is_some_and(Vec::is_empty);
+
+        for _ in 0..n.saturating_mul(EXAMPLE_ATTEMPTS) {
+            if examples.matching.len() >= n || unsatisfiable {
+                break;
+            }
+            let conjunction = conjunctions
+                .as_ref()
+                .map(|conjunctions| conjunctions[rng.usize(..conjunctions.len())].clone());
+
+            let obj = self.example_object(&tag_names, conjunction.as_deref(), &mut rng);
+            if valid(&obj)
+                && self.evaluate_node(&ast.root_node, &obj)
+                && !examples.matching.contains(&obj)
+            {
+                examples.matching.push(obj);
+            }
+        }
+
+        for attempt in 0..n.saturating_mul(EXAMPLE_ATTEMPTS) {
+            if examples.non_matching.len() >= n {
+                break;
+            }
+
+            // Every other attempt breaks one clause of a conjunction, for a near miss
+            let near_miss = conjunctions
+                .as_ref()
+                .filter(|conjunctions| attempt % 2 == 0 && !conjunctions.is_empty())
+                .and_then(|conjunctions| {
+                    let conjunction = &conjunctions[rng.usize(..conjunctions.len())];
+                    let broken = rng.usize(..conjunction.len());
+                    let mut clauses: Vec<Clause> = self.negate_clause(conjunction[broken])?;
+                    clauses.extend(
+                        conjunction
+                            .iter()
+                            .enumerate()
+                            .filter(|(i, _)| *i != broken)
+                            .map(|(_, clause)| (*clause).clone()),
+                    );
+                    Some(clauses)
+                });
+            let near_miss: Option<Vec<&Clause>> =
+                near_miss.as_ref().map(|clauses| clauses.iter().collect());
+
+            let obj = self.example_object(&tag_names, near_miss.as_deref(), &mut rng);
+            if valid(&obj)
+                && !self.evaluate_node(&ast.root_node, &obj)
+                && !examples.non_matching.contains(&obj)
+            {
+                examples.non_matching.push(obj);
+            }
+        }
+
+        Ok(examples)
+    }
+
+    // Random object with values of `tag_names`, meeting the clauses given if it can. Tags the
+    // clauses don't mention get a random value, or none if they aren't required.
+    fn example_object(
+        &self,
+        tag_names: &BTreeSet<types::TagName>,
+        clauses: Option<&[&Clause]>,
+        rng: &mut fastrand::Rng,
+    ) -> types::Object {
+        let fold = |s: &str| self.m_case_folding.fold(s);
+        let mut obj = types::Object::new();
+
+        for tag_name in tag_names {
+            let values = &self.m_mapped_tags[tag_name];
+            let constraints = self
+                .m_tag_constraints
+                .get(tag_name)
+                .cloned()
+                .unwrap_or_default();
+            let clauses: Vec<&Clause> = clauses
+                .unwrap_or_default()
+                .iter()
+                .filter(|clause| fold(&clause.tag_name) == fold(tag_name))
+                .copied()
+                .collect();
+            let values_for = |op: ComparisonOp| -> Vec<String> {
+                clauses
+                    .iter()
+                    .filter(|clause| clause.comparison_op == op)
+                    .map(|clause| fold(&clause.tag_value))
+                    .collect()
+            };
+            let checks_presence = |op: ComparisonOp| clauses.iter().any(|c| c.comparison_op == op);
+            if checks_presence(ComparisonOp::ABSN) {
+                continue;
+            }
+            let excluded = values_for(ComparisonOp::NOEQ);
+            let is_excluded = |value: &str| {
+                excluded
+                    .iter()
+                    .any(|pattern| string::glob_matches(pattern, &fold(value)))
+            };
+            let rank = |value: &str| values.iter().position(|v| fold(v) == fold(value));
+
+            // Declared spelling of each value the clauses need, and for a glob one of the values
+            // it matches -- e.g. "red" for "re*"
+            let mut chosen: Vec<String> = values_for(ComparisonOp::ISEQ)
+                .iter()
+                .filter_map(|value| {
+                    if value.contains(string::WILDCARD) {
+                        let matching: Vec<&String> = values
+                            .iter()
+                            .filter(|v| string::glob_matches(value, &fold(v)) && !is_excluded(v))
+                            .collect();
+                        return (!matching.is_empty())
+                            .then(|| matching[rng.usize(..matching.len())].clone());
+                    }
+                    Some(
+                        values
+                            .iter()
+                            .find(|v| fold(v) == *value)
+                            .cloned()
+                            .unwrap_or_else(|| value.clone()),
+                    )
+                })
+                .collect();
+
+            for clause in &clauses {
+                let Some(target) = rank(&clause.tag_value) else {
+                    continue;
+                };
+                let meets = |value: &String| {
+                    rank(value).is_some_and(|r| match clause.comparison_op {
+                        ComparisonOp::LSTH => r < target,
+                        ComparisonOp::GRTH => r > target,
+                        _ => true,
+                    })
+                };
+                if !matches!(
+                    clause.comparison_op,
+                    ComparisonOp::LSTH | ComparisonOp::GRTH
+                ) || chosen.iter().any(meets)
+                {
+                    continue;
+                }
+
+                let candidates: Vec<&String> = values
+                    .iter()
+                    .filter(|v| meets(v) && !is_excluded(v))
+                    .collect();
+                if !candidates.is_empty() {
+                    chosen.push(candidates[rng.usize(..candidates.len())].clone());
+                }
+            }
+
+            // Sometimes another value, so examples aren't all minimal
+            let extra = if chosen.is_empty() {
+                constraints.required || checks_presence(ComparisonOp::PRES) || rng.bool()
+            } else {
+                !constraints.single && rng.u8(..4) == 0
+            };
+            if extra {
+                let candidates: Vec<&String> = values
+                    .iter()
+                    .filter(|v| !is_excluded(v) && !chosen.contains(v))
+                    .collect();
+                if !candidates.is_empty() {
+                    chosen.push(candidates[rng.usize(..candidates.len())].clone());
+                }
+            }
+
+            if !chosen.is_empty() {
+                obj.insert(tag_name.clone(), chosen);
+            }
+        }
+
+        obj
+    }
+
+    // The fewest changes to the object's tags which would make the rule match it, along with the
+    // conditions it fails -- e.g. "- size > medium" and "{ size: small }" give "- size: small",
+    // "+ size: large" if size is single-valued. None if the rule already matches.
+    pub fn why_not(
+        &self,
+        rule: &str,
+        obj: &types::Object,
+    ) -> Result<Option<MatchExplanation>, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        if self.evaluate_node(&ast.root_node, obj) {
+            return Ok(None);
+        }
+
+        let Some(conjunctions) = self.conjunctions(&ast.root_node) else {
+            return Err(RulesError::RuleParseError(format!(
+                "Rule is too large to explain: {}",
+                rule.trim()
+            )));
+        };
+
+        // Working edits first, then fewest edits, then fewest failed conditions
+        let explanation = conjunctions
+            .iter()
+            .filter(|clauses| self.conjunction_satisfiable(clauses))
+            .map(|clauses| self.explain_conjunction(clauses, obj))
+            .min_by_key(|(explanation, works)| {
+                (!works, explanation.edits.len(), explanation.failed.len())
+            })
+            .map(|(explanation, _)| explanation)
+            .ok_or_else(|| {
+                RulesError::RuleParseError(format!("Rule can never match: {}", rule.trim()))
+            })?;
+
+        Ok(Some(explanation))
+    }
+
+    // Edits meeting each clause the object fails, and whether the edited object then meets all
+    // of them
+    fn explain_conjunction(
+        &self,
+        clauses: &[&Clause],
+        obj: &types::Object,
+    ) -> (MatchExplanation, bool) {
+        let fold = |s: &str| self.m_case_folding.fold(s);
+        let mut edited = obj.clone();
+        let mut explanation = MatchExplanation {
+            failed: Vec::new(),
+            edits: Vec::new(),
+            complete: true,
+        };
+
+        for clause in clauses {
+            if self.clause_matches(clause, &edited) {
+                continue;
+            }
+            explanation.failed.push((*clause).clone());
+
+            let Some((tag_name, values)) = self.find_tag(&clause.tag_name) else {
+                continue;
+            };
+            let declared = self.m_mapped_tags[tag_name].clone();
+            let single = self
+                .m_tag_constraints
+                .get(tag_name)
+                .is_some_and(|constraints| constraints.single);
+            let key = edited
+                .keys()
+                .find(|k| fold(k) == fold(tag_name))
+                .cloned()
+                .unwrap_or_else(|| tag_name.clone());
+            let current: Vec<String> = edited.get(&key).cloned().unwrap_or_default();
+            let value = fold(&clause.tag_value);
+
+            // Values the other clauses on this tag rule out
+            let excluded: Vec<String> = clauses
+                .iter()
+                .filter(|other| {
+                    fold(&other.tag_name) == fold(tag_name)
+                        && other.comparison_op == ComparisonOp::NOEQ
+                })
+                .map(|other| fold(&other.tag_value))
+                .collect();
+
+            let (removed, added): (Vec<String>, Option<String>) = match clause.comparison_op {
+                ComparisonOp::ISEQ => {
+                    let added = declared
+                        .iter()
+                        .find(|v| fold(v) == value)
+                        .cloned()
+                        .unwrap_or_else(|| clause.tag_value.clone());
+                    let removed = if single { current.clone() } else { Vec::new() };
+                    (removed, Some(added))
+                }
+                ComparisonOp::NOEQ => (
+                    current
+                        .iter()
+                        .filter(|v| fold(v) == value)
+                        .cloned()
+                        .collect(),
+                    None,
+                ),
+                ComparisonOp::LSTH | ComparisonOp::GRTH => {
+                    let target = values.iter().position(|v| *v == value).unwrap_or(0);
+                    // The nearest value on the right side of the target
+                    let mut ranks: Vec<usize> = if clause.comparison_op == ComparisonOp::LSTH {
+                        (0..target).rev().collect()
+                    } else {
+                        (target + 1..values.len()).collect()
+                    };
+                    ranks.retain(|r| !excluded.contains(&values[*r]));
+                    let added = ranks.first().map(|r| declared[*r].clone());
+                    let removed = if single { current.clone() } else { Vec::new() };
+                    (removed, added)
+                }
+                ComparisonOp::REGX => {
+                    let added = self.regex(&clause.tag_value).ok().and_then(|regex| {
+                        declared
+                            .iter()
+                            .find(|v| regex.is_match(&fold(v)) && !excluded.contains(&fold(v)))
+                            .cloned()
+                    });
+                    if added.is_none() {
+                        explanation.complete = false;
+                        continue;
+                    }
+                    let removed = if single { current.clone() } else { Vec::new() };
+                    (removed, added)
+                }
+                ComparisonOp::PRES => {
+                    let added = declared
+                        .iter()
+                        .find(|v| !excluded.contains(&fold(v)))
+                        .cloned();
+                    if added.is_none() {
+                        explanation.complete = false;
+                        continue;
+                    }
+                    (Vec::new(), added)
+                }
+                // Removing every value removes the tag
+                ComparisonOp::ABSN => (current.clone(), None),
+                ComparisonOp::CALL => {
+                    explanation.complete = false;
+                    continue;
+                }
+            };
+
+            let mut updated: Vec<String> = current
+                .iter()
+                .filter(|v| !removed.contains(v))
+                .cloned()
+                .collect();
+            for value in removed {
+                explanation.edits.push(TagEdit::Remove {
+                    tag_name: tag_name.clone(),
+                    value,
+                });
+            }
+            if let Some(value) = added {
+                updated.push(value.clone());
+                explanation.edits.push(TagEdit::Add {
+                    tag_name: tag_name.clone(),
+                    value,
+                });
+            }
+
+            if updated.is_empty() {
+                edited.remove(&key);
+            } else {
+                edited.insert(key, updated);
+            }
+        }
+
+        let works = clauses
+            .iter()
+            .all(|clause| self.clause_matches(clause, &edited))
+            && objects::check_exclusive_groups(
+                &edited,
+                &self.m_exclusive_groups,
+                self.m_case_folding,
+            )
+            .is_empty();
+        (explanation, works)
+    }
+
+    // Whether every valid object matching `rule` also matches `other` -- e.g. "- colour = red &
+    // size = small" implies "- colour = red". False when unsure, if either rule is too large to
+    // check or `other` calls a plugin.
+    pub fn implies(&self, rule: &str, other: &str) -> Result<bool, RulesError> {
+        let (rule, other) = (self.string_to_rule(rule)?, self.string_to_rule(other)?);
+        let (Some(conjunctions), Some(other_conjunctions)) = (
+            self.conjunctions(&rule.root_node),
+            self.conjunctions(&other.root_node),
+        ) else {
+            return Ok(false);
+        };
+
+        // `other` fails when one clause of each of its conjunctions fails
+        let mut negations: Vec<Vec<Vec<Clause>>> = Vec::new();
+        for conjunction in &other_conjunctions {
+            let alternatives: Option<Vec<Vec<Clause>>> = conjunction
+                .iter()
+                .map(|clause| self.negate_clause(clause))
+                .collect();
+            let Some(alternatives) = alternatives else {
+                return Ok(false);
+            };
+            negations.push(alternatives);
+        }
+
+        let mut budget = MAX_CONJUNCTIONS;
+        for conjunction in conjunctions {
+            let clauses = conjunction.into_iter().cloned().collect();
+            if self.counterexample(clauses, &negations, &mut budget) != Some(false) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Whether the clauses can hold along with one alternative of each negation, i.e. some object
+    // matches one rule but not the other. None if the search runs out of budget.
+    fn counterexample(
+        &self,
+        clauses: Vec<Clause>,
+        negations: &[Vec<Vec<Clause>>],
+        budget: &mut usize,
+    ) -> Option<bool> {
+        *budget = budget.checked_sub(1)?;
+
+        let refs: Vec<&Clause> = clauses.iter().collect();
+        if !self.conjunction_satisfiable(&refs) {
+            return Some(false);
+        }
+        let Some((alternatives, rest)) = negations.split_first() else {
+            return Some(true);
+        };
+
+        for alternative in alternatives {
+            let extended = [clauses.clone(), alternative.clone()].concat();
+            if self.counterexample(extended, rest, budget)? {
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    // Clauses which together hold exactly when `clause` doesn't -- e.g. "size < medium" fails
+    // when "size ! small". None for plugin calls, which can't be negated.
+    fn negate_clause(&self, clause: &Clause) -> Option<Vec<Clause>> {
+        let negated = |comparison_op: ComparisonOp, tag_value: &str| Clause {
+            tag_name: clause.tag_name.clone(),
+            comparison_op,
+            tag_value: tag_value.to_string(),
+        };
+
+        match clause.comparison_op {
+            ComparisonOp::ISEQ => Some(vec![negated(ComparisonOp::NOEQ, &clause.tag_value)]),
+            ComparisonOp::NOEQ => Some(vec![negated(ComparisonOp::ISEQ, &clause.tag_value)]),
+            ComparisonOp::PRES => Some(vec![negated(ComparisonOp::ABSN, "")]),
+            ComparisonOp::ABSN => Some(vec![negated(ComparisonOp::PRES, "")]),
+            // No value on the matching side of the target
+            ComparisonOp::LSTH | ComparisonOp::GRTH => Some(
+                self.ranked_values(clause)?
+                    .iter()
+                    .map(|v| negated(ComparisonOp::NOEQ, v))
+                    .collect(),
+            ),
+            ComparisonOp::CALL | ComparisonOp::REGX => None,
+        }
+    }
+
+    // The values a rank comparison matches, in the tag's order -- e.g. ["medium", "large"] for
+    // "size > small". None if the tag or the target value isn't known.
+    fn ranked_values(&self, clause: &Clause) -> Option<Vec<String>> {
+        let (_, values) = self.find_tag(&clause.tag_name)?;
+        let value = self.m_case_folding.fold(&clause.tag_value);
+        let target = values.iter().position(|v| *v == value)?;
+
+        Some(
+            values
+                .iter()
+                .enumerate()
+                .filter(|(rank, _)| match clause.comparison_op {
+                    ComparisonOp::LSTH => *rank < target,
+                    _ => *rank > target,
+                })
+                .map(|(_, v)| v.clone())
+                .collect(),
+        )
+    }
+
+    // The rule as a JsonLogic expression, with rank comparisons as the values they match -- e.g.
+    // "- size > small" gives {"some": [{"var": "size"}, {"in": [{"var": ""}, ["medium",
+    // "large"]]}]}
+    #[cfg(feature = "json_logic")]
+    pub fn rule_to_json_logic(&self, rule: &str) -> Result<serde_json::Value, RulesError> {
+        self.string_to_rule(rule)?
+            .to_json_logic_with(&|clause| self.ranked_values(clause))
+    }
+
+    // How the rule was parsed, each operator above its operands -- e.g. "- a = b & c = d" gives
+    // "&\n|-- a = b\n`-- c = d\n"
+    pub fn render_tree(&self, rule: &str) -> Result<String, RulesError> {
+        Ok(self.string_to_rule(rule)?.to_string())
+    }
+
+    // Whether any valid object could match the rule. A rule can't match if every way of
+    // satisfying it needs a value both present and absent, two values of a (single) tag, a rank
+    // no value has, or two members of an exclusive group -- e.g. "- size = small & size ! small".
+    // Plugin calls are assumed to hold for some value.
+    pub fn is_satisfiable(&self, rule: &str) -> Result<bool, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+
+        Ok(match self.conjunctions(&ast.root_node) {
+            Some(conjunctions) => conjunctions
+                .iter()
+                .any(|clauses| self.conjunction_satisfiable(clauses)),
+            None => true,
+        })
+    }
+
+    // The comparisons which stop a rule ever matching, if it never can -- e.g. [size = small,
+    // size ! small] for "- colour = red & size = small & size ! small". Each way of satisfying the
+    // rule gives the fewest comparisons which can't hold together, in the order they're written.
+    // None if the rule can match, or is too large to check.
+    pub fn contradiction(&self, rule: &str) -> Result<Option<Vec<Clause>>, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        let Some(conjunctions) = self.conjunctions(&ast.root_node) else {
+            return Ok(None);
+        };
+        if conjunctions
+            .iter()
+            .any(|clauses| self.conjunction_satisfiable(clauses))
+        {
+            return Ok(None);
+        }
+
+        // Dropping each comparison the rest still can't hold without
+        let mut conflicting: Vec<Clause> = Vec::new();
+        for mut clauses in conjunctions {
+            let mut i = 0;
+            while i < clauses.len() {
+                let mut without = clauses.clone();
+                without.remove(i);
+                if self.conjunction_satisfiable(&without) {
+                    i += 1;
+                } else {
+                    clauses = without;
+                }
+            }
+            for clause in clauses {
+                if !conflicting.contains(clause) {
+                    conflicting.push(clause.clone());
+                }
+            }
+        }
+
+        Ok(Some(conflicting))
+    }
+
+    // The comparisons which make a rule match every valid object, if it does -- e.g.
+    // [colour = red, colour ! red] for "- colour = red | colour ! red | size = small". Only the
+    // fewest ways of satisfying the rule which together cover every object are kept. None if some
+    // object doesn't match, or if unsure, when the rule calls a plugin or is too large to check.
+    pub fn tautology(&self, rule: &str) -> Result<Option<Vec<Clause>>, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        let Some(mut conjunctions) = self.conjunctions(&ast.root_node) else {
+            return Ok(None);
+        };
+        if !self.covers_every_object(&conjunctions) {
+            return Ok(None);
+        }
+
+        // Dropping each way of satisfying the rule the rest still cover every object without
+        let mut i = 0;
+        while i < conjunctions.len() {
+            let mut without = conjunctions.clone();
+            without.remove(i);
+            if self.covers_every_object(&without) {
+                conjunctions = without;
+            } else {
+                i += 1;
+            }
+        }
+
+        let mut covering: Vec<Clause> = Vec::new();
+        for clause in conjunctions.into_iter().flatten() {
+            if !covering.contains(clause) {
+                covering.push(clause.clone());
+            }
+        }
+        Ok(Some(covering))
+    }
+
+    // Whether every valid object meets all the clauses of one of the conjunctions, i.e. no object
+    // fails one clause of each. False if unsure.
+    fn covers_every_object(&self, conjunctions: &[Vec<&Clause>]) -> bool {
+        let mut negations: Vec<Vec<Vec<Clause>>> = Vec::new();
+        for conjunction in conjunctions {
+            let alternatives: Option<Vec<Vec<Clause>>> = conjunction
+                .iter()
+                .map(|clause| self.negate_clause(clause))
+                .collect();
+            let Some(alternatives) = alternatives else {
+                return false;
+            };
+            negations.push(alternatives);
+        }
+
+        let mut budget = MAX_CONJUNCTIONS;
+        self.counterexample(Vec::new(), &negations, &mut budget) == Some(false)
+    }
+
+    // The rule as alternatives, each a list of clauses which must all hold -- e.g.
+    // "a & (b | c)" gives [[a, b], [a, c]]. None if there would be more than MAX_CONJUNCTIONS.
+    fn conjunctions<'a>(&self, node: &'a Node) -> Option<Vec<Vec<&'a Clause>>> {
+        self.expand(node, &Token::And)
+    }
+
+    // The rule as requirements, each a list of clauses of which at least one must hold -- e.g.
+    // "a | b & c" gives [[a, b], [a, c]]. None if there would be more than MAX_CONJUNCTIONS.
+    fn disjunctions<'a>(&self, node: &'a Node) -> Option<Vec<Vec<&'a Clause>>> {
+        self.expand(node, &Token::Or)
+    }
+
+    // The rule as groups of clauses, `within` joining the clauses of each group and the other
+    // operator joining the groups, so `within` is distributed over the other operator
+    fn expand<'a>(&self, node: &'a Node, within: &Token) -> Option<Vec<Vec<&'a Clause>>> {
+        if let Some(clause) = &node.clause {
+            return Some(vec![vec![clause]]);
+        }
+
+        if node.left.is_none()
+            || node.right.is_none()
+            || !matches!(node.token, Token::And | Token::Or)
+        {
+            return Some(Vec::new());
+        }
+
+        // The chain of the operator is combined from the right, as it groups
+        let mut operands = node.chain().into_iter().rev();
+        let mut right = self.expand(operands.next()?, within)?;
+        for operand in operands {
+            let left = self.expand(operand, within)?;
+            right = if node.token == *within {
+                if left.len().saturating_mul(right.len()) > MAX_CONJUNCTIONS {
+                    return None;
+                }
+                left.iter()
+                    .flat_map(|l| right.iter().map(move |r| [l.clone(), r.clone()].concat()))
+                    .collect()
+            } else {
+                [left, right].concat()
+            };
+            if right.len() > MAX_CONJUNCTIONS {
+                return None;
+            }
+        }
+
+        Some(right)
+    }
+
+    // Groups of clauses case folded, sorted and deduplicated, leaving out groups `redundant` says
+    // add nothing and groups containing all of another's clauses, which that one absorbs -- e.g.
+    // "a | a & b" is just "a", and "a & (a | b)" is just "a"
+    fn reduce_groups(
+        &self,
+        groups: &[Vec<&Clause>],
+        redundant: impl Fn(&[&Clause]) -> bool,
+    ) -> Vec<Vec<Clause>> {
+        let mut folded: Vec<BTreeMap<String, Clause>> = groups
+            .iter()
+            .filter(|clauses| !redundant(clauses))
+            .map(|clauses| {
+                clauses
+                    .iter()
+                    .map(|clause| {
+                        let clause = self.fold_clause(clause);
+                        (clause.to_string(), clause)
+                    })
+                    .collect()
+            })
+            .collect();
+        folded.sort_by(|a, b| a.keys().cmp(b.keys()));
+        folded.dedup_by(|a, b| a.keys().eq(b.keys()));
+
+        folded
+            .iter()
+            .filter(|group| {
+                !folded.iter().any(|other| {
+                    other.len() < group.len() && other.keys().all(|key| group.contains_key(key))
+                })
+            })
+            .map(|group| group.values().cloned().collect())
+            .collect()
+    }
+
+    // Whether every valid object meets at least one of the clauses, i.e. their negations can't
+    // all hold -- e.g. "size = small | size ! small". False for plugin calls and regexes.
+    fn disjunction_holds(&self, clauses: &[&Clause]) -> bool {
+        let negations: Option<Vec<Vec<Clause>>> = clauses
+            .iter()
+            .map(|clause| self.negate_clause(clause))
+            .collect();
+        let Some(negations) = negations else {
+            return false;
+        };
+
+        let negations: Vec<&Clause> = negations.iter().flatten().collect();
+        !self.conjunction_satisfiable(&negations)
+    }
+
+    fn conjunction_satisfiable(&self, clauses: &[&Clause]) -> bool {
+        let fold = |s: &str| self.m_case_folding.fold(s);
+
+        let mut by_tag: BTreeMap<String, Vec<&Clause>> = BTreeMap::new();
+        for clause in clauses {
+            by_tag
+                .entry(fold(&clause.tag_name))
+                .or_default()
+                .push(clause);
+        }
+
+        // Values each tag must have, and tags the object must have
+        let mut required_values: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let mut present_tags: BTreeSet<String> = BTreeSet::new();
+
+        for (tag_name, clauses) in &by_tag {
+            let Some((mapped_name, values)) = self.find_tag(tag_name) else {
+                continue;
+            };
+            let constraints = self
+                .m_tag_constraints
+                .get(mapped_name)
+                .cloned()
+                .unwrap_or_default();
+
+            // Globs and other tags are assumed to match a value which fits
+            let values_for = |op: ComparisonOp| -> BTreeSet<String> {
+                clauses
+                    .iter()
+                    .filter(|clause| {
+                        clause.comparison_op == op
+                            && !clause.tag_value.contains(string::WILDCARD)
+                            && tag_reference(&clause.tag_value).is_none()
+                    })
+                    .map(|clause| fold(&clause.tag_value))
+                    .collect()
+            };
+            let (equals, not_equals) = (
+                values_for(ComparisonOp::ISEQ),
+                values_for(ComparisonOp::NOEQ),
+            );
+            let (below, above) = (
+                values_for(ComparisonOp::LSTH),
+                values_for(ComparisonOp::GRTH),
+            );
+
+            if !equals.is_disjoint(&not_equals) || (constraints.single && equals.len() > 1) {
+                return false;
+            }
+
+            let needs_tag = clauses.iter().any(|clause| {
+                matches!(
+                    clause.comparison_op,
+                    ComparisonOp::ISEQ
+                        | ComparisonOp::LSTH
+                        | ComparisonOp::GRTH
+                        | ComparisonOp::PRES
+                )
+            });
+            // An object having a tag with only listed values needs one the clauses allow -- e.g.
+            // "colour? & colour ! red & colour ! blue & colour ! green" can't hold
+            let closed = !constraints.open && constraints.pattern.is_none() && !values.is_empty();
+            if (needs_tag || constraints.required)
+                && closed
+                && values.iter().all(|v| not_equals.contains(v))
+            {
+                return false;
+            }
+
+            // A missing tag fails every comparison needing a value -- e.g. "!colour? & colour ~ ^r",
+            // and objects without a required tag match nothing
+            let has = |op: ComparisonOp| clauses.iter().any(|clause| clause.comparison_op == op);
+            if has(ComparisonOp::ABSN) && constraints.required {
+                return false;
+            }
+            if has(ComparisonOp::ABSN)
+                && [
+                    ComparisonOp::ISEQ,
+                    ComparisonOp::LSTH,
+                    ComparisonOp::GRTH,
+                    ComparisonOp::REGX,
+                    ComparisonOp::PRES,
+                ]
+                .into_iter()
+                .any(has)
+            {
+                return false;
+            }
+
+            if constraints.ordered && !(below.is_empty() && above.is_empty()) {
+                let rank = |v: &str| values.iter().position(|r| r == v);
+                let fits = |r: usize| {
+                    below.iter().filter_map(|t| rank(t)).all(|t| r < t)
+                        && above.iter().filter_map(|t| rank(t)).all(|t| r > t)
+                };
+                let available: Vec<usize> = values
+                    .iter()
+                    .filter(|v| !not_equals.contains(*v))
+                    .filter_map(|v| rank(v))
+                    .collect();
+
+                let satisfied = if constraints.single {
+                    // The object's one value must be every '=' value and fit every comparison
+                    match equals.iter().next() {
+                        Some(value) => rank(value).is_some_and(fits),
+                        None => available.iter().any(|r| fits(*r)),
+                    }
+                } else {
+                    // Each comparison can be met by a different value
+                    below
+                        .iter()
+                        .filter_map(|t| rank(t))
+                        .all(|t| available.iter().any(|r| *r < t))
+                        && above
+                            .iter()
+                            .filter_map(|t| rank(t))
+                            .all(|t| available.iter().any(|r| *r > t))
+                };
+                if !satisfied {
+                    return false;
+                }
+            }
+
+            if needs_tag {
+                present_tags.insert(self.m_case_folding.fold(tag_name));
+            }
+            required_values.insert(
+                self.m_case_folding.fold(tag_name),
+                equals.iter().map(|v| self.m_case_folding.fold(v)).collect(),
+            );
+        }
+
+        // Exclusive groups are stored folded
+        self.m_exclusive_groups.iter().all(|group| match group {
+            ExclusiveGroup::Values { tag_name, values } => {
+                let required = required_values.get(tag_name);
+                values
+                    .iter()
+                    .filter(|v| required.is_some_and(|r| r.contains(*v)))
+                    .count()
+                    < 2
+            }
+            ExclusiveGroup::Tags { tag_names } => {
+                tag_names
+                    .iter()
+                    .filter(|t| present_tags.contains(*t))
+                    .count()
+                    < 2
+            }
+        })
+    }
+
+    // Whether `obj` satisfies the subtree under `node`. The right operand is only evaluated when
+    // the left doesn't decide the result -- e.g. "a & b" skips b when a fails, "a | b" when a holds,
+    // so plugin calls there aren't made.
+    fn evaluate_node(&self, node: &Node, obj: &types::Object) -> bool {
+        if self.m_unknown_policy != UnknownPolicy::TwoValued {
+            return self.node_truth(node, obj).collapse(self.m_unknown_policy);
+        }
+
+        match (&node.token, &node.clause, &node.left, &node.right) {
+            (_, Some(clause), _, _) => self.clause_matches(clause, obj),
+            (Token::And, None, Some(_), Some(_)) => node
+                .chain()
+                .into_iter()
+                .all(|operand| self.evaluate_node(operand, obj)),
+            (Token::Or, None, Some(_), Some(_)) => node
+                .chain()
+                .into_iter()
+                .any(|operand| self.evaluate_node(operand, obj)),
+            _ => false,
+        }
+    }
+
+    // Three-valued evaluation, short-circuiting once the operands so far decide the result
+    fn node_truth(&self, node: &Node, obj: &types::Object) -> Truth {
+        match (&node.token, &node.clause, &node.left, &node.right) {
+            (_, Some(clause), _, _) => self.clause_truth(clause, obj),
+            (Token::And, None, Some(_), Some(_)) => {
+                let mut truth = Truth::True;
+                for operand in node.chain() {
+                    truth = truth.and(self.node_truth(operand, obj));
+                    if truth == Truth::False {
+                        break;
+                    }
+                }
+                truth
+            }
+            (Token::Or, None, Some(_), Some(_)) => {
+                let mut truth = Truth::False;
+                for operand in node.chain() {
+                    truth = truth.or(self.node_truth(operand, obj));
+                    if truth == Truth::True {
+                        break;
+                    }
+                }
+                truth
+            }
+            _ => Truth::False,
+        }
+    }
+
+    // A clause under three-valued logic. Values outside the tag's listed values might be any
+    // of them, so a clause no known value decides is Unknown if the object has one -- e.g.
+    // "vendor = acme" against { vendor: [other] }. Plugin calls and presence checks are always
+    // decided.
+    fn clause_truth(&self, clause: &Clause, obj: &types::Object) -> Truth {
+        let matched = self.clause_matches(clause, obj);
+        let has_unknown_value = || {
+            let fold = |s: &str| self.m_case_folding.fold(s);
+            let Some((_, listed)) = self.find_tag(&clause.tag_name) else {
+                return false;
+            };
+            !listed.is_empty()
+                && obj
+                    .iter()
+                    .filter(|(k, _)| fold(k) == fold(&clause.tag_name))
+                    .flat_map(|(_, values)| values)
+                    .any(|v| !listed.contains(&fold(v)))
+        };
+
+        match clause.comparison_op {
+            ComparisonOp::CALL | ComparisonOp::REGX | ComparisonOp::PRES | ComparisonOp::ABSN => {
+                Truth::from(matched)
+            }
+            // Comparisons with another tag compare the values themselves, listed or not
+            _ if tag_reference(&clause.tag_value).is_some() => Truth::from(matched),
+            // '!' is decided false by a known value equal to the one compared
+            ComparisonOp::NOEQ if !matched => Truth::False,
+            ComparisonOp::NOEQ => {
+                if has_unknown_value() {
+                    Truth::Unknown
+                } else {
+                    Truth::True
+                }
+            }
+            _ if matched => Truth::True,
+            _ if has_unknown_value() => Truth::Unknown,
+            _ => Truth::False,
+        }
+    }
+
+    // Every comparison in `rule`, in rule order, with whether `obj` satisfies it. Nothing is
+    // short-circuited, so comparisons are reported even where the rule's result doesn't need
+    // them -- e.g. "- colour = red & size = large" against { colour: [blue] } gives
+    // [(colour = red, false), (size = large, false)]
+    pub fn clause_hits(
+        &self,
+        rule: &str,
+        obj: &types::Object,
+        context: &EvalContext,
+        timestamp: u64,
+    ) -> Result<Vec<(Clause, bool)>, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        let root = if rule.contains(VARIABLE_PREFIX) {
+            self.resolve_variables(&ast.root_node, context, timestamp)?
+        } else {
+            ast.root_node
+        };
+
+        let mut hits: Vec<(Clause, bool)> = Vec::new();
+        let mut nodes: Vec<&Node> = vec![&root];
+        while let Some(node) = nodes.pop() {
+            if let Some(clause) = &node.clause {
+                hits.push((clause.clone(), self.clause_matches(clause, obj)));
+            }
+            // Right first, so the left is visited first
+            nodes.extend(node.right.as_deref());
+            nodes.extend(node.left.as_deref());
+        }
+
+        Ok(hits)
+    }
+
+    // Result of `rule` against `obj` under three-valued logic, before Unknown is collapsed --
+    // e.g. Unknown for "- vendor = acme" against { vendor: [other] } when vendor is open
+    pub fn rule_truth(&self, rule: &str, obj: &types::Object) -> Result<Truth, RulesError> {
+        let ast = self.string_to_rule(rule)?;
+        Ok(self.node_truth(&ast.root_node, obj))
+    }
+
+    // Whether `obj` has a tag called `tag_name`, whatever its case
+    fn has_tag(&self, obj: &types::Object, tag_name: &str) -> bool {
+        let fold = |s: &str| self.m_case_folding.fold(s);
+        obj.keys().any(|k| fold(k) == fold(tag_name))
+    }
+
+    // Errors if `obj` lacks a tag compared in `rule`, for MissingTagPolicy::Error -- e.g.
+    // "Object is missing TagName 'size', compared in '- size = large'"
+    fn check_tags_present(
+        &self,
+        clauses: &[Clause],
+        obj: &types::Object,
+        rule: &str,
+    ) -> Result<(), RulesError> {
+        let is_presence_check = |clause: &Clause| {
+            matches!(
+                clause.comparison_op,
+                ComparisonOp::PRES | ComparisonOp::ABSN
+            )
+        };
+        // A tag compared against another needs both
+        let missing = clauses
+            .iter()
+            .filter(|clause| !is_presence_check(clause))
+            .flat_map(|clause| {
+                [
+                    Some(clause.tag_name.as_str()),
+                    tag_reference(&clause.tag_value),
+                ]
+            })
+            .flatten()
+            .find(|tag_name| !self.has_tag(obj, tag_name));
+        match missing {
+            Some(tag_name) => Err(RulesError::ObjectParseError(format!(
+                "Object is missing TagName '{}', compared in '{}'",
+                tag_name, rule
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // A clause holds when any of the object's values for the tag satisfies it. An object without
+    // the tag is handled per the missing tag policy, by default failing '=', '<' and '>' but
+    // passing '!'. Presence checks ignore the policy, being about whether the tag is there.
+    fn clause_matches(&self, clause: &Clause, obj: &types::Object) -> bool {
+        let fold = |s: &str| self.m_case_folding.fold(s);
+        let tag_name = fold(&clause.tag_name);
+        let tag_value = fold(&clause.tag_value);
+
+        let is_presence_check = matches!(
+            clause.comparison_op,
+            ComparisonOp::PRES | ComparisonOp::ABSN
+        );
+
+        match self.m_missing_tag_policy {
+            _ if is_presence_check => {}
+            MissingTagPolicy::FailClause if !self.has_tag(obj, &tag_name) => return false,
+            MissingTagPolicy::SkipClause if !self.has_tag(obj, &tag_name) => return true,
+            _ => {}
+        }
+
+        let obj_values: Vec<String> = obj
+            .iter()
+            .filter(|(k, _)| fold(k) == tag_name)
+            .flat_map(|(_, values)| values.iter().map(|v| fold(v)))
+            .collect();
+
+        // Holds when the tags share a value, for '=', or share none, for '!' -- e.g.
+        // "shipping_colour = @ordered_colour". An object without the other tag has no values for it.
+        if let Some(other) = tag_reference(&tag_value)
+            && matches!(
+                clause.comparison_op,
+                ComparisonOp::ISEQ | ComparisonOp::NOEQ
+            )
+        {
+            let shared = self
+                .tag_values(obj, other)
+                .iter()
+                .any(|v| obj_values.contains(&fold(v)));
+            return shared == (clause.comparison_op == ComparisonOp::ISEQ);
+        }
+
+        match clause.comparison_op {
+            ComparisonOp::CALL => {
+                let Some((name, args)) = plugin::parse_call(&tag_value) else {
+                    return false;
+                };
+                self.find_plugin(name)
+                    .is_some_and(|plugin| plugin.call(&obj_values, &args))
+            }
+            ComparisonOp::REGX => self
+                .regex(&clause.tag_value)
+                .is_ok_and(|regex| obj_values.iter().any(|v| regex.is_match(v))),
+            ComparisonOp::ISEQ | ComparisonOp::NOEQ if tag_value.contains(string::WILDCARD) => {
+                let matched = obj_values
+                    .iter()
+                    .any(|v| string::glob_matches(&tag_value, v));
+                matched == (clause.comparison_op == ComparisonOp::ISEQ)
+            }
+            ComparisonOp::ISEQ => obj_values.contains(&tag_value),
+            ComparisonOp::NOEQ => !obj_values.contains(&tag_value),
+            ComparisonOp::PRES => self.has_tag(obj, &tag_name),
+            ComparisonOp::ABSN => !self.has_tag(obj, &tag_name),
+            ComparisonOp::LSTH | ComparisonOp::GRTH => {
+                let Some((_, ranked)) = self.find_tag(&tag_name) else {
+                    return false;
+                };
+                let rank = |v: &str| ranked.iter().position(|r| r == v);
+                let Some(target) = rank(&tag_value) else {
+                    return false;
+                };
+
+                obj_values.iter().filter_map(|v| rank(v)).any(|r| {
+                    if clause.comparison_op == ComparisonOp::LSTH {
+                        r < target
+                    } else {
+                        r > target
+                    }
+                })
+            }
+        }
+    }
+
+    // One subrule per conjunction of the rule's disjunctive form -- e.g.
+    // "(colour = blue, red) & shape ! circle" gives [colour = blue & shape ! circle] and
+    // [colour = red & shape ! circle]
+    fn rule_to_dnf_subrules(
+        &self,
+        rule: AstRule,
+        rule_index: usize,
+        loaded: &LoadedRule,
+    ) -> Result<Vec<SubRule>, RulesError> {
+        let Some(conjunctions) = self.conjunctions(&rule.root_node) else {
+            return Err(RulesError::RuleParseError(format!(
+                "Rule has more than {} subrules",
+                MAX_CONJUNCTIONS
+            )));
+        };
+
+        Ok(conjunctions
+            .into_iter()
+            .map(|clauses| SubRule {
+                priority: loaded.priority(),
+                name: loaded.name().map(str::to_string),
+                ..SubRule::new(rule_index, clauses.into_iter().cloned().collect())
+            })
+            .collect())
+    }
+
+    // Main entry point for converting loaded rules.
+    // Converts every rule but drafts into Disjunctive Normal Form (DNF) subrules, each knowing
+    // its rule's position in `rules` so the rule's window can be checked when evaluating
+    pub fn parse_rules(&self, rules: &[LoadedRule]) -> Result<Vec<SubRule>, RulesError> {
+        let mut dnf_subrules: Vec<SubRule> = Vec::new();
+
+        for (rule_index, loaded) in rules.iter().enumerate() {
+            if loaded.is_draft() {
+                continue;
+            }
+
+            // Parse string to AST, then convert to DNF representation
+            let subrules: Vec<SubRule> = self
+                .string_to_rule(&loaded.rule)
+                .and_then(|rule| self.rule_to_dnf_subrules(rule, rule_index, loaded))
+                .map_err(|e| rule_error_at(loaded, e))?;
+
+            dnf_subrules.extend(subrules);
+        }
+
+        Ok(dnf_subrules)
+    }
+
+    // Whether `obj` meets every clause of a subrule, with variables filled in from `context`.
+    // A rule matches when any of its subrules does, so Unknown is collapsed per subrule.
+    pub fn subrule_matches(
+        &self,
+        subrule: &SubRule,
+        obj: &types::Object,
+        context: &EvalContext,
+        timestamp: u64,
+    ) -> Result<bool, RulesError> {
+        if self.m_missing_tag_policy == MissingTagPolicy::Error {
+            let rule: Vec<String> = subrule.clauses.iter().map(Clause::to_string).collect();
+            self.check_tags_present(&subrule.clauses, obj, &format!("- {}", rule.join(" & ")))?;
+        }
+        let mut truth = Truth::True;
+
+        for clause in &subrule.clauses {
+            let resolved;
+            let clause = if clause.tag_value.starts_with(VARIABLE_PREFIX) {
+                resolved = Clause {
+                    tag_value: self.variable_value(&clause.tag_value, context, timestamp)?,
+                    ..clause.clone()
+                };
+                &resolved
+            } else {
+                clause
+            };
+
+            truth = if self.m_unknown_policy == UnknownPolicy::TwoValued {
+                Truth::from(self.clause_matches(clause, obj))
+            } else {
+                truth.and(self.clause_truth(clause, obj))
+            };
+            if truth == Truth::False {
+                return Ok(false);
+            }
+        }
+
+        Ok(truth.collapse(self.m_unknown_policy))
+    }
+
+    // Rules of `rules` in effect at `timestamp` which `obj` matches, highest priority first and
+    // stopping at the first under EvalMode::FirstMatch, with a rule's file and line added to its
+    // errors -- e.g. "my_rules.rules:3: No value for $ctx.region ..."
+    pub fn matching_rules(
+        &self,
+        rules: &[LoadedRule],
+        obj: &types::Object,
+        context: &EvalContext,
+        timestamp: u64,
+        mode: EvalMode,
+    ) -> Result<Vec<LoadedRule>, RulesError> {
+        Ok(self
+            .evaluated_rules(rules, obj, context, timestamp, mode)?
+            .into_iter()
+            .filter_map(|(loaded, matched)| matched.then_some(loaded))
+            .collect())
+    }
+
+    // Every rule of `rules` checked against `obj`, with whether it matched, in the order
+    // matching_rules checks them -- e.g. for counting how often each rule is evaluated
+    pub fn evaluated_rules(
+        &self,
+        rules: &[LoadedRule],
+        obj: &types::Object,
+        context: &EvalContext,
+        timestamp: u64,
+        mode: EvalMode,
+    ) -> Result<Vec<(LoadedRule, bool)>, RulesError> {
+        let mut in_effect: Vec<LoadedRule> = rules
+            .iter()
+            .filter(|loaded| loaded.in_effect_at(timestamp))
+            .cloned()
+            .collect();
+        sort_by_priority(&mut in_effect);
+        let mut evaluated: Vec<(LoadedRule, bool)> = Vec::new();
+
+        for loaded in in_effect {
+            let matched = self
+                .rule_matches_with_context(&loaded.rule, obj, context, timestamp)
+                .map_err(|e| rule_error_at(&loaded, e))?;
+            evaluated.push((loaded, matched));
+            if matched && mode == EvalMode::FirstMatch {
+                break;
+            }
+        }
+
+        Ok(evaluated)
+    }
+
+    // Traces of the rules of `rules` checked against `obj`, in the order evaluated_rules checks
+    // them -- e.g. for showing which comparisons decided each rule
+    pub fn traced_rules(
+        &self,
+        rules: &[LoadedRule],
+        obj: &types::Object,
+        context: &EvalContext,
+        timestamp: u64,
+        mode: EvalMode,
+    ) -> Result<Vec<RuleTrace>, RulesError> {
+        let mut in_effect: Vec<LoadedRule> = rules
+            .iter()
+            .filter(|loaded| loaded.in_effect_at(timestamp))
+            .cloned()
+            .collect();
+        sort_by_priority(&mut in_effect);
+        let mut traces: Vec<RuleTrace> = Vec::new();
+
+        for loaded in in_effect {
+            let (steps, matched) = self
+                .trace(&loaded.rule, obj, context, timestamp)
+                .map_err(|e| rule_error_at(&loaded, e))?;
+            traces.push(RuleTrace {
+                rule: loaded,
+                matched,
+                steps,
+            });
+            if matched && mode == EvalMode::FirstMatch {
+                break;
+            }
+        }
+
+        Ok(traces)
+    }
+
+    // Checks an object's tags and values against the loaded tags and exclusive groups
+    pub fn validate_object(&self, obj: &types::Object) -> Result<(), RulesError> {
+        objects::validate_object(
+            obj,
+            &self.m_mapped_tags,
+            &self.m_tag_constraints,
+            self.m_case_folding,
+        )?;
+
+        let conflicts =
+            objects::check_exclusive_groups(obj, &self.m_exclusive_groups, self.m_case_folding);
+        if let Some(conflict) = conflicts.first() {
+            return Err(RulesError::ObjectParseError(conflict.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Find a token in the token list
+    fn find_token<'a>(
+        tokens: &'a [(String, TokenType, TokenDepth)],
+        token_str: &str,
+    ) -> Option<&'a TokenType> {
+        tokens
+            .iter()
+            .find(|(s, _, _)| s == token_str)
+            .map(|(_, t, _)| t)
+    }
+
+    // Count occurrences of a token
+    fn count_token(tokens: &[(String, TokenType, TokenDepth)], token_str: &str) -> usize {
+        tokens.iter().filter(|(s, _, _)| s == token_str).count()
+    }
+
+    // Texts of a rule line's lexemes, with flag shorthand and ranges expanded
+    fn lexed(line: &str) -> Result<Vec<String>, RulesError> {
+        Ok(RuleParser::new(HashMap::new())
+            .tokenise_rule(line)?
+            .into_iter()
+            .map(|lexeme| lexeme.text)
+            .collect())
+    }
+
+    // Texts of a rule line's tokens as the reader types them, with comma lists expanded
+    fn read_tokens(line: &str) -> Result<Vec<String>, RulesError> {
+        let (_root, tokens) = RuleParser::new(HashMap::new()).read_rule(line)?;
+        Ok(tokens.into_iter().map(|(token, _, _)| token).collect())
+    }
+
+    // Message of the syntax error reading a rule line gives
+    fn read_error(line: &str) -> String {
+        match RuleParser::new(HashMap::new()).read_rule(line) {
+            Err(RulesError::RuleParseError(msg)) => msg,
+            other => panic!("Expected RuleParseError, got {:?}", other),
+        }
+    }
+
+    // Tests for tokenise_rule
+    #[test]
+    fn test_tokenise_rule_simple_rule() {
+        assert_eq!(lexed("-colour = red").unwrap(), vec!["colour", "=", "red"]);
+    }
+
+    #[test]
+    fn test_tokenise_rule_with_parentheses() {
+        assert_eq!(
+            lexed("-(colour = red)").unwrap(),
+            vec!["(", "colour", "=", "red", ")"]
+        );
+    }
+
+    #[test]
+    fn test_tokenise_rule_nested_parentheses() {
+        assert_eq!(
+            lexed("-((colour = red))").unwrap(),
+            vec!["(", "(", "colour", "=", "red", ")", ")"]
+        );
+    }
+
+    #[test]
+    fn test_tokenise_rule_with_and_operator() {
+        assert_eq!(
+            lexed("-colour = red & size = large").unwrap(),
+            vec!["colour", "=", "red", "&", "size", "=", "large"]
+        );
+    }
+
+    #[test]
+    fn test_tokenise_rule_with_or_operator() {
+        assert_eq!(
+            lexed("-colour = red | colour = blue").unwrap(),
+            vec!["colour", "=", "red", "|", "colour", "=", "blue"]
+        );
+    }
+
+    #[test]
+    fn test_tokenise_rule_with_not_equals() {
+        assert_eq!(lexed("-colour ! red").unwrap(), vec!["colour", "!", "red"]);
+        assert_eq!(lexed("-colour != red").unwrap(), vec!["colour", "!", "red"]);
+    }
+
+    #[test]
+    fn test_tokenise_rule_complex_nested() {
+        assert_eq!(
+            lexed("-((colour = red) & (size = large))").unwrap(),
+            vec![
+                "(", "(", "colour", "=", "red", ")", "&", "(", "size", "=", "large", ")", ")"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenise_rule_extra_whitespace() {
+        assert_eq!(
+            lexed("-  colour   =   red  ").unwrap(),
+            vec!["colour", "=", "red"]
+        );
+    }
+
+    #[test]
+    fn test_tokenise_rule_no_spaces() {
+        assert_eq!(lexed("-colour=red").unwrap(), vec!["colour", "=", "red"]);
+    }
+
+    #[test]
+    fn test_tokenise_rule_spans() {
+        let line = "promo: -[2] colour == \"dark red\" -> created_at ~ before(2024-01-01)";
+        let lexemes = RuleParser::new(HashMap::new()).tokenise_rule(line).unwrap();
+
+        let kinds: Vec<LexemeKind> = lexemes.iter().map(|lexeme| lexeme.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                LexemeKind::Word,
+                LexemeKind::Op,
+                LexemeKind::Quoted,
+                LexemeKind::Op,
+                LexemeKind::Word,
+                LexemeKind::Op,
+                LexemeKind::Call,
+            ]
+        );
+        let written: Vec<&str> = lexemes
+            .iter()
+            .map(|lexeme| &line[lexeme.span.clone()])
+            .collect();
+        assert_eq!(
+            written,
+            vec![
+                "colour",
+                "==",
+                "\"dark red\"",
+                "->",
+                "created_at",
+                "~",
+                "before(2024-01-01)"
+            ]
+        );
+        assert_eq!(lexemes[2].text, "dark red");
+        assert_eq!(lexemes[3].text, "→");
+
+        // Added lexemes have the span of what they were expanded from
+        let lexemes = RuleParser::new(HashMap::new())
+            .tokenise_rule("- is_fragile")
+            .unwrap();
+        assert!(lexemes.iter().all(|lexeme| lexeme.span == (2..12)));
+    }
+
+    #[test]
+    fn test_tokenise_rule_unmatched_parens() {
+        let msg = read_error("-(colour = red");
+        assert!(
+            msg.contains("Unmatched opening parenthesis at column 2"),
+            "{}",
+            msg
+        );
+
+        let msg = read_error("-colour = red)");
+        assert!(
+            msg.contains("Unmatched closing parenthesis at column 14"),
+            "{}",
+            msg
+        );
+
+        assert!(matches!(
+            RuleParser::new(HashMap::new()).tokenise_rule("- created_at ~ before(2024-01-01"),
+            Err(RulesError::RuleParseError(msg)) if msg.ends_with("at column 22")
+        ));
+    }
+
+    // Tests for the token types read_rule gives
+    #[test]
+    fn test_read_rule_token_types() {
+        let (_root, tokens) = RuleParser::new(HashMap::new())
+            .read_rule("-colour = red & (size = large)")
+            .unwrap();
+
+        assert_eq!(tokens.len(), 7);
+        assert_eq!(find_token(&tokens, "colour"), Some(&TokenType::TagName));
+        assert_eq!(find_token(&tokens, "red"), Some(&TokenType::TagValue));
+        assert_eq!(find_token(&tokens, "&"), Some(&TokenType::LogicalOp));
+        assert_eq!(find_token(&tokens, "size"), Some(&TokenType::TagName));
+        assert_eq!(find_token(&tokens, "large"), Some(&TokenType::TagValue));
+        assert_eq!(count_token(&tokens, "="), 2);
+        assert_eq!(tokens[4].2, 1);
+
+        let (_root, tokens) = RuleParser::new(HashMap::new())
+            .read_rule("-!(colour = red) & created_at before(2024-01-01)")
+            .unwrap();
+        assert_eq!(find_token(&tokens, "!"), Some(&TokenType::Negation));
+        assert_eq!(find_token(&tokens, "~"), Some(&TokenType::ComparisonOp));
+    }
+
+    // Tests for the syntax tree read_rule gives
+    #[test]
+    fn test_read_rule_simple_leaf() {
+        let (node, _) = RuleParser::new(HashMap::new())
+            .read_rule("-colour = red")
+            .unwrap();
+        assert_eq!(node.token, crate::parser::types::Token::Equals);
         assert!(node.left.is_none());
         assert!(node.right.is_none());
+
+        let (node, _) = RuleParser::new(HashMap::new())
+            .read_rule("-colour ! red")
+            .unwrap();
+        assert_eq!(node.token, crate::parser::types::Token::NotEquals);
+    }
+
+    #[test]
+    fn test_read_rule_with_and() {
+        let (node, _) = RuleParser::new(HashMap::new())
+            .read_rule("-colour = red & size = large")
+            .unwrap();
+        assert_eq!(node.token, crate::parser::types::Token::And);
+        assert!(node.left.is_some());
+        assert!(node.right.is_some());
+    }
+
+    #[test]
+    fn test_read_rule_with_or() {
+        let (node, _) = RuleParser::new(HashMap::new())
+            .read_rule("-colour = red | colour = blue")
+            .unwrap();
+        assert_eq!(node.token, crate::parser::types::Token::Or);
+        assert!(node.left.is_some());
+        assert!(node.right.is_some());
+    }
+
+    #[test]
+    fn test_read_rule_strips_outer_parens() {
+        let (node, _) = RuleParser::new(HashMap::new())
+            .read_rule("-((colour = red))")
+            .unwrap();
+        assert_eq!(node.token, crate::parser::types::Token::Equals);
+    }
+
+    #[test]
+    fn test_read_rule_and_before_or() {
+        // '&' binds tighter than '|', so '|' is at the root
+        let (node, _) = RuleParser::new(HashMap::new())
+            .read_rule("-colour = red & size = large | shape = circle")
+            .unwrap();
+        assert_eq!(node.token, crate::parser::types::Token::Or);
+        assert_eq!(
+            node.left.as_ref().unwrap().token,
+            crate::parser::types::Token::And
+        );
+
+        // Unless parens say otherwise
+        let (node, _) = RuleParser::new(HashMap::new())
+            .read_rule("-colour = red & (size = large | shape = circle)")
+            .unwrap();
+        assert_eq!(node.token, crate::parser::types::Token::And);
+        assert_eq!(
+            node.right.as_ref().unwrap().token,
+            crate::parser::types::Token::Or
+        );
+    }
+
+    // Tests for syntax errors
+    #[test]
+    fn test_read_rule_valid() {
+        for line in [
+            "-colour = red",
+            "-(colour = red)",
+            "-colour = red & size = large",
+            "-(colour = red) & (size ! small)",
+            "-colour == \"red\" -> !(size != large)",
+        ] {
+            assert!(
+                RuleParser::new(HashMap::new()).read_rule(line).is_ok(),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_rule_starts_with_comparison_op() {
+        let msg = read_error("-= red");
+        assert!(msg.contains("Expected a tag name, found '='"), "{}", msg);
+    }
+
+    #[test]
+    fn test_read_rule_two_tag_names_in_a_row() {
+        let msg = read_error("-colour size = red");
+        assert!(
+            msg.contains("Expected a comparison after 'colour', found 'size' at column 9"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn test_read_rule_two_comparison_ops_in_a_row() {
+        let msg = read_error("-colour = != red");
+        assert!(
+            msg.contains("Expected a tag value after '=', found '!=' at column 11"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn test_read_rule_two_tag_values_in_a_row() {
+        let msg = read_error("-colour = red blue");
+        assert!(
+            msg.contains("Expected '&', '|' or '->', found 'blue' at column 15"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn test_read_rule_two_logical_ops_in_a_row() {
+        let msg = read_error("-colour = red & | size = large");
+        assert!(
+            msg.contains("Expected a tag name, found '|' at column 17"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn test_read_rule_ends_early() {
+        for (line, expected) in [
+            ("-colour = red & size =", "a tag value"),
+            ("-colour = red &", "a tag name"),
+            ("-colour = red ->", "a tag name"),
+        ] {
+            let msg = read_error(line);
+            assert!(msg.contains("Rule ends after"), "{}", msg);
+            assert!(msg.contains(expected), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn test_read_rule_empty_rule() {
+        assert!(read_error("-").contains("Empty rule"));
+        assert!(read_error("- ()").contains("Expected a tag name, found ')'"));
+    }
+
+    #[test]
+    fn test_read_rule_columns_count_characters() {
+        // The name, priority and any multi-byte characters before the error are counted
+        let msg = read_error("promo: -[2] colour = \"café\" & & size = large");
+        assert!(msg.ends_with("at column 31"), "{}", msg);
+    }
+
+    // Helper function to create test tags
+    fn create_test_tags() -> HashMap<String, Vec<String>> {
+        let mut tags = HashMap::new();
+        tags.insert(
+            "colour".to_string(),
+            vec!["red".to_string(), "blue".to_string(), "green".to_string()],
+        );
+        tags.insert(
+            "size".to_string(),
+            vec![
+                "small".to_string(),
+                "medium".to_string(),
+                "large".to_string(),
+            ],
+        );
+        tags.insert(
+            "shape".to_string(),
+            vec!["circle".to_string(), "square".to_string()],
+        );
+        tags
+    }
+
+    // Tests for check_valid_tags
+    #[test]
+    fn test_check_valid_tags_all_valid() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let tokens = vec![
+            ("colour".to_string(), TokenType::TagName, 0),
+            ("=".to_string(), TokenType::ComparisonOp, 0),
+            ("red".to_string(), TokenType::TagValue, 0),
+            ("&".to_string(), TokenType::LogicalOp, 0),
+            ("size".to_string(), TokenType::TagName, 0),
+            ("=".to_string(), TokenType::ComparisonOp, 0),
+            ("large".to_string(), TokenType::TagValue, 0),
+        ];
+
+        let result = parser.check_valid_tags(&tokens);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_check_valid_tags_invalid_tag_name() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let tokens = vec![
+            ("invalid_tag".to_string(), TokenType::TagName, 0),
+            ("=".to_string(), TokenType::ComparisonOp, 0),
+            ("red".to_string(), TokenType::TagValue, 0),
+        ];
+
+        let result = parser.check_valid_tags(&tokens);
+        assert!(result.is_err());
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("invalid TagName"));
+            assert!(msg.contains("invalid_tag"));
+        } else {
+            panic!("Expected RuleParseError about invalid tag name");
+        }
+    }
+
+    #[test]
+    fn test_check_valid_tags_invalid_tag_value() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let tokens = vec![
+            ("colour".to_string(), TokenType::TagName, 0),
+            ("=".to_string(), TokenType::ComparisonOp, 0),
+            ("purple".to_string(), TokenType::TagValue, 0),
+        ];
+
+        let result = parser.check_valid_tags(&tokens);
+        assert!(result.is_err());
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("invalid TagValue"));
+            assert!(msg.contains("purple"));
+        } else {
+            panic!("Expected RuleParseError about invalid tag value");
+        }
+    }
+
+    #[test]
+    fn test_check_valid_tags_with_parentheses() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let (_root, tokens) = parser.read_rule("-(colour = blue)").unwrap();
+
+        let result = parser.check_valid_tags(&tokens);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_check_valid_tags_multiple_conditions() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let tokens = vec![
+            ("colour".to_string(), TokenType::TagName, 0),
+            ("=".to_string(), TokenType::ComparisonOp, 0),
+            ("red".to_string(), TokenType::TagValue, 0),
+            ("&".to_string(), TokenType::LogicalOp, 0),
+            ("size".to_string(), TokenType::TagName, 0),
+            ("!".to_string(), TokenType::ComparisonOp, 0),
+            ("small".to_string(), TokenType::TagValue, 0),
+            ("|".to_string(), TokenType::LogicalOp, 0),
+            ("shape".to_string(), TokenType::TagName, 0),
+            ("=".to_string(), TokenType::ComparisonOp, 0),
+            ("circle".to_string(), TokenType::TagValue, 0),
+        ];
+
+        let result = parser.check_valid_tags(&tokens);
+        assert!(result.is_ok());
+    }
+
+    // Tests for validate_rule
+    // Note: validate_rule expects rules to start with '-' (as they appear in config files)
+    // and normalises them by removing the first character
+    #[test]
+    fn test_validate_rule_valid() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let valid_rules = vec![
+            "-colour = red",
+            "-colour = red & size = large",
+            "-colour = red | colour = blue",
+            "-(colour = red)",
+            "-(colour = red) & (size = large)",
+            "-((colour = red) | (colour = blue)) & (size = large)",
+            "-colour ! red",
+            "-colour = red & size ! small",
+        ];
+
+        for rule in valid_rules {
+            let result = parser.validate_rule(rule);
+            assert!(result.is_ok(), "Expected rule to be valid: {}", rule);
+        }
+    }
+
+    #[test]
+    fn test_validate_rule_invalid() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let invalid_rules = vec![
+            // Missing dash at start
+            "colour = red",
+            "colour = red & size = large",
+            "(colour = red)",
+            // Multiple dashes
+            "-colour = -red",
+            "-colour - red",
+            "--colour = red",
+            "-colour = red & -size = large",
+            // Missing operands (remember first char is skipped)
+            "-colour =",
+            "-= red",
+            "-colour",
+            // Double operators
+            "-colour = = red",
+            "-colour red",
+            // Ending with operator
+            "-colour = red &",
+            "-colour = red |",
+            // Starting with operator (after '-' is removed)
+            "-& colour = red",
+            "-| colour = red",
+            // Mismatched parentheses
+            "-(colour = red",
+            "-colour = red)",
+            "-((colour = red)",
+            // Empty or only operators (first char removed)
+            "--",
+            "-=",
+            "-&",
+            "-()",
+            // Invalid sequences
+            "-colour & size = large",
+            "-colour = red blue",
+        ];
+
+        for rule in invalid_rules {
+            let result = parser.validate_rule(rule);
+            assert!(result.is_err(), "Expected rule to be invalid: {}", rule);
+        }
+    }
+
+    #[test]
+    fn test_validate_rule_invalid_tag_names() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let invalid_rules = vec!["-invalid_tag = red", "-colour = red & unknown = value"];
+
+        for rule in invalid_rules {
+            let result = parser.validate_rule(rule);
+            assert!(
+                result.is_err(),
+                "Expected rule to be invalid due to unknown tag: {}",
+                rule
+            );
+            if let Err(RulesError::RuleParseError(msg)) = result {
+                assert!(msg.contains("invalid TagName") || msg.contains("invalid TagValue"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_rule_invalid_tag_values() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let invalid_rules = vec!["-colour = purple", "-colour = red & size = huge"];
+
+        for rule in invalid_rules {
+            let result = parser.validate_rule(rule);
+            assert!(
+                result.is_err(),
+                "Expected rule to be invalid due to unknown value: {}",
+                rule
+            );
+            if let Err(RulesError::RuleParseError(msg)) = result {
+                assert!(msg.contains("invalid TagValue"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_rule_pattern_tag() {
+        let mut tags = create_test_tags();
+        tags.insert("sku".to_string(), vec![]);
+
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "sku".to_string(),
+            crate::parser::tags::get_tag_from_line(r"- sku: /^[A-Z]{2}\d{4}$/")
+                .unwrap()
+                .constraints,
+        );
+
+        let parser = RuleParser::new(tags).with_tag_constraints(constraints);
+
+        assert!(parser.validate_rule("-sku = AB1234").is_ok());
+        assert!(parser.validate_rule("-sku = ab1234 & colour = red").is_ok());
+
+        let result = parser.validate_rule("-sku = ABC123");
+        assert!(result.is_err());
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("invalid TagValue"));
+        }
+    }
+
+    #[test]
+    fn test_clauses() {
+        let clauses = RuleParser::new(HashMap::new())
+            .clauses("-(Colour = Red, blue) & size ! small")
+            .unwrap();
+
+        let rendered: Vec<String> = clauses.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            rendered,
+            vec!["colour = red", "colour = blue", "size ! small"]
+        );
+        assert_eq!(clauses[2].comparison_op, ComparisonOp::NOEQ);
+    }
+
+    #[test]
+    fn test_clauses_skips_comments_and_rejects_bad_syntax() {
+        assert!(
+            RuleParser::new(HashMap::new())
+                .clauses("# comment")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            RuleParser::new(HashMap::new())
+                .clauses("-colour = = red")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_tokenise_rule_flag_shorthand() {
+        assert_eq!(
+            lexed("-is_fragile").unwrap(),
+            vec!["is_fragile", "=", "true"]
+        );
+        assert_eq!(
+            lexed("-!is_fragile & (colour = red | !is_boxed)").unwrap(),
+            vec![
+                "is_fragile",
+                "=",
+                "false",
+                "&",
+                "(",
+                "colour",
+                "=",
+                "red",
+                "|",
+                "is_boxed",
+                "=",
+                "false",
+                ")"
+            ]
+        );
+        assert_eq!(
+            read_tokens("-colour ! red, blue").unwrap(),
+            vec!["colour", "!", "red", "&", "colour", "!", "blue"]
+        );
+        assert_eq!(
+            read_tokens("-colour = red, blue").unwrap(),
+            vec!["colour", "=", "red", "|", "colour", "=", "blue"]
+        );
+
+        assert!(lexed("-!colour = red").is_err());
+        // '!' before a group negates the group, flags and all
+        assert_eq!(
+            lexed("-!(is_fragile)").unwrap(),
+            vec!["!", "(", "is_fragile", "=", "true", ")"]
+        );
+        assert!(lexed("-is_fragile & !").is_err());
+    }
+
+    #[test]
+    fn test_validate_rule_flag_shorthand() {
+        let mut tags = create_test_tags();
+        tags.insert(
+            "is_fragile".to_string(),
+            vec!["true".to_string(), "false".to_string()],
+        );
+        let parser = RuleParser::new(tags);
+
+        for rule in [
+            "-is_fragile",
+            "-!is_fragile",
+            "-is_fragile & colour = red",
+            "-(colour = red | !is_fragile) & size = large",
+            "-is_fragile = false",
+        ] {
+            assert!(
+                parser.validate_rule(rule).is_ok(),
+                "Rule should be valid: {}",
+                rule
+            );
+        }
+
+        // Only flag tags can be written bare, with or without '!'
+        for (rule, tag_name) in [
+            ("- colour", "colour"),
+            ("-!colour", "colour"),
+            ("-(size = large | shape) & is_fragile", "shape"),
+        ] {
+            let result = parser.validate_rule(rule);
+            if let Err(RulesError::RuleParseError(msg)) = result {
+                let expected = format!("'{}' is not a flag tag; compare it to a value", tag_name);
+                assert!(msg.contains(&expected), "{}: {}", rule, msg);
+            } else {
+                panic!("Expected RuleParseError about non-flag tag for {}", rule);
+            }
+        }
+
+        let clauses = RuleParser::new(HashMap::new())
+            .clauses("-!is_fragile")
+            .unwrap();
+        assert_eq!(clauses.len(), 1);
+        assert_eq!(clauses[0].to_string(), "is_fragile = false");
+    }
+
+    #[test]
+    fn test_validate_rule_case_folding() {
+        let mut tags = HashMap::new();
+        tags.insert(
+            "Colour".to_string(),
+            vec!["Red".to_string(), "Blue".to_string()],
+        );
+
+        // Tags mapped with their original case still match when folding
+        let parser = RuleParser::new(tags.clone());
+        assert!(parser.validate_rule("-colour = red").is_ok());
+        assert!(parser.validate_rule("-COLOUR = Blue").is_ok());
+
+        let parser = RuleParser::new(tags).with_case_folding(CaseFolding::Preserve);
+        assert!(parser.validate_rule("-Colour = Red").is_ok());
+        assert!(parser.validate_rule("-colour = Red").is_err());
+        assert!(parser.validate_rule("-Colour = red").is_err());
+    }
+
+    #[test]
+    fn test_validate_rule_ordered_comparisons() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "size".to_string(),
+            types::TagConstraints {
+                ordered: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags()).with_tag_constraints(constraints);
+
+        for rule in [
+            "-size > small",
+            "-size<large & colour = red",
+            "-(size > small) | size < medium, large",
+        ] {
+            assert!(
+                parser.validate_rule(rule).is_ok(),
+                "Rule should be valid: {}",
+                rule
+            );
+        }
+
+        assert!(parser.validate_rule("-size > huge").is_err());
+
+        let result = parser.validate_rule("-colour < red");
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("'colour' with '<', but its values are not ordered"));
+        } else {
+            panic!("Expected RuleParseError about unordered tag");
+        }
+
+        let clauses = RuleParser::new(HashMap::new())
+            .clauses("-size > small & size < large")
+            .unwrap();
+        assert_eq!(clauses[0].comparison_op, ComparisonOp::GRTH);
+        assert_eq!(clauses[1].comparison_op, ComparisonOp::LSTH);
+        assert_eq!(clauses[1].to_string(), "size < large");
+    }
+
+    #[test]
+    fn test_rule_matches() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "size".to_string(),
+            types::TagConstraints {
+                ordered: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags()).with_tag_constraints(constraints);
+
+        let mut obj = types::Object::new();
+        obj.insert(
+            "Colour".to_string(),
+            vec!["Red".to_string(), "green".to_string()],
+        );
+        obj.insert("size".to_string(), vec!["medium".to_string()]);
+
+        for rule in [
+            "-colour = red",
+            "-colour = blue, green",
+            "-(colour = red) & (size = medium)",
+            "-colour = blue | size > small",
+            "-size < large & shape ! circle",
+            "-shape ! circle, square",
+        ] {
+            assert!(parser.rule_matches(rule, &obj).unwrap(), "{}", rule);
+        }
+
+        for rule in [
+            "-colour ! red",
+            "-colour ! blue, green",
+            "-colour = blue",
+            "-(colour = red) & (size = large)",
+            "-size > medium",
+            "-shape = circle",
+        ] {
+            assert!(!parser.rule_matches(rule, &obj).unwrap(), "{}", rule);
+        }
+
+        assert!(parser.rule_matches("-colour = purple", &obj).is_err());
+    }
+
+    // Dates as YYYY-MM-DD sort as strings
+    struct DateCompare {
+        name: &'static str,
+        before: bool,
+    }
+
+    impl RulePlugin for DateCompare {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn validate(&self, args: &[String]) -> Result<(), String> {
+            match args {
+                [date] if date.len() == 10 && date.chars().filter(|c| *c == '-').count() == 2 => {
+                    Ok(())
+                }
+                _ => Err("expected one date, e.g. 2024-01-01".to_string()),
+            }
+        }
+
+        fn call(&self, values: &[String], args: &[String]) -> bool {
+            values
+                .iter()
+                .any(|v| (*v < args[0]) == self.before && *v != args[0])
+        }
+    }
+
+    fn plugin_parser() -> RuleParser {
+        let mut tags = create_test_tags();
+        tags.insert("created_at".to_string(), Vec::new());
+
+        let mut plugins: PluginMap = HashMap::new();
+        for (name, before) in [("before", true), ("after", false)] {
+            plugins.insert(
+                name.to_string(),
+                std::sync::Arc::new(DateCompare { name, before }),
+            );
+        }
+
+        RuleParser::new(tags).with_plugins(plugins)
+    }
+
+    #[test]
+    fn test_rule_with_function_call() {
+        let parser = plugin_parser();
+
+        let mut obj = types::Object::new();
+        obj.insert("created_at".to_string(), vec!["2023-06-01".to_string()]);
+        obj.insert("colour".to_string(), vec!["red".to_string()]);
+
+        for rule in [
+            "-created_at before(2024-01-01)",
+            "-colour = red & created_at Before(2024-01-01)",
+            "-(created_at after(2025-01-01), before(2024-01-01))",
+            "-created_at ~ before(2024-01-01)",
+        ] {
+            assert!(parser.rule_matches(rule, &obj).unwrap(), "{}", rule);
+        }
+
+        for rule in [
+            "-created_at after(2024-01-01)",
+            "-colour = blue | created_at before(2023-01-01)",
+            "-size before(2024-01-01)",
+        ] {
+            assert!(!parser.rule_matches(rule, &obj).unwrap(), "{}", rule);
+        }
+
+        assert_eq!(
+            parser
+                .canonical_rule("-(created_at before(2024-01-01))&colour=red")
+                .unwrap(),
+            "colour = red & created_at ~ before(2024-01-01)"
+        );
     }
 
     #[test]
-    fn test_build_ast_with_and() {
-        let tokens = vec![
+    fn test_rule_with_invalid_function_call() {
+        let parser = plugin_parser();
+
+        let result = parser.validate_rule("-created_at since(2024-01-01)");
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("unknown function: since"));
+        } else {
+            panic!("Expected RuleParseError about unknown function");
+        }
+
+        let result = parser.validate_rule("-created_at before(yesterday)");
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("Invalid arguments to before"));
+        } else {
+            panic!("Expected RuleParseError about invalid arguments");
+        }
+
+        assert!(
+            parser
+                .validate_rule("-updated_at before(2024-01-01)")
+                .is_err()
+        );
+        assert!(
+            parser
+                .validate_rule("-created_at before(2024-01-01")
+                .is_err()
+        );
+        assert!(
+            parser
+                .validate_rule("-created_at before(2024-01-01) - colour = red")
+                .is_err()
+        );
+        assert!(
+            parser
+                .validate_rule("-!created_at before(2024-01-01)")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_rules_with_annotations() {
+        let content =
+            "# Comment\n@on_match notify.rhai\n@Draft red\n\n- colour = red\n- shape = circle";
+        let rules = parse_rules_from_str("test.rules", content).unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].line, 5);
+        assert_eq!(rules[0].rule, "- colour = red");
+        assert_eq!(rules[0].annotation("on_match"), Some("notify.rhai"));
+        assert_eq!(rules[0].draft_name(), Some("red"));
+        assert!(rules[1].annotations.is_empty());
+        assert_eq!(rules[1].annotation("on_match"), None);
+    }
+
+    #[test]
+    fn test_parse_rules_with_invalid_annotations() {
+        let result = parse_rules_from_str("test.rules", "- colour = red\n@on_match notify.rhai");
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("test.rules:2"));
+            assert!(msg.contains("isn't followed by a rule"));
+        } else {
+            panic!("Expected RuleParseError about dangling annotation");
+        }
+
+        let result = parse_rules_from_str("test.rules", "@on-match notify.rhai\n- colour = red");
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("test.rules:1"));
+            assert!(msg.contains("Invalid annotation name"));
+        } else {
+            panic!("Expected RuleParseError about annotation name");
+        }
+    }
+
+    #[test]
+    fn test_parse_rules_with_drafts() {
+        let rules = parse_rules_from_str("test.rules", "@draft red_large\n- colour = red").unwrap();
+        assert!(rules[0].is_draft());
+
+        for content in ["@draft\n- colour = red", "@draft red large\n- colour = red"] {
+            let result = parse_rules_from_str("test.rules", content);
+            if let Err(RulesError::RuleParseError(msg)) = result {
+                assert!(msg.contains("test.rules:2"));
+                assert!(msg.contains("Invalid name for @draft"));
+            } else {
+                panic!("Expected RuleParseError about draft name");
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_inline_tests() {
+        let content = "# Header\n- colour = red\n#test: match { colour: red }\n\n@draft blue\n- colour = blue\n  #test: no_match { colour: [red, green] }\n";
+        let tests =
+            parse_inline_tests_from_str("a.rules", content, CaseFolding::default()).unwrap();
+
+        assert_eq!(tests.len(), 2);
+        assert_eq!(tests[0].line, 3);
+        assert_eq!(tests[0].rule.line, 2);
+        assert!(tests[0].expect_match);
+        assert_eq!(tests[0].object["colour"], vec!["red"]);
+        assert_eq!(tests[1].rule.rule, "- colour = blue");
+        assert!(!tests[1].expect_match);
+        assert_eq!(tests[1].object["colour"], vec!["red", "green"]);
+
+        for (content, expected) in [
+            (
+                "#test: match { colour: red }\n- colour = red",
+                "a.rules:1: Test isn't under a rule",
+            ),
+            (
+                "- colour = red\n#test: matches { colour: red }",
+                "a.rules:2: Invalid test",
+            ),
+            ("- colour = red\n#test: match colour", "a.rules:2: "),
+        ] {
+            let result = parse_inline_tests_from_str("a.rules", content, CaseFolding::default());
+            if let Err(RulesError::RuleParseError(msg)) = result {
+                assert!(msg.starts_with(expected), "{}", msg);
+            } else {
+                panic!("Expected RuleParseError for {:?}", content);
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove_annotation() {
+        let content =
+            "@draft a\n- colour = red\n\n@draft b\n# Larger\n@on_match x.rhai\n- size = large\n";
+        assert_eq!(
+            remove_annotation(content, 7, "draft"),
+            "@draft a\n- colour = red\n\n# Larger\n@on_match x.rhai\n- size = large\n"
+        );
+        assert_eq!(
+            remove_annotation(content, 2, "draft"),
+            "- colour = red\n\n@draft b\n# Larger\n@on_match x.rhai\n- size = large\n"
+        );
+        assert_eq!(
+            remove_annotation("- colour = red", 1, "draft"),
+            "- colour = red"
+        );
+    }
+
+    #[test]
+    fn test_parse_rules_with_time_scope() {
+        let content = "@effective_from 2025-01-01\n@expires 2025-07-01T12:00:00Z\n- colour = red";
+        let rules = parse_rules_from_str("test.rules", content).unwrap();
+        assert_eq!(rules[0].effective_from(), Some(1_735_689_600));
+        assert_eq!(rules[0].expires(), Some(1_751_371_200));
+        assert!(!rules[0].in_effect_at(1_735_689_599));
+        assert!(rules[0].in_effect_at(1_735_689_600));
+        assert!(!rules[0].in_effect_at(1_751_371_200));
+
+        let result = parse_rules_from_str("test.rules", "@expires soon\n- colour = red");
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("test.rules:2"));
+            assert!(msg.contains("Invalid timestamp for @expires"));
+        } else {
+            panic!("Expected RuleParseError about timestamp");
+        }
+
+        let content = "@effective_from 2025-07-01\n@expires 2025-01-01\n- colour = red";
+        let result = parse_rules_from_str("test.rules", content);
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("expires before it takes effect"));
+        } else {
+            panic!("Expected RuleParseError about empty time window");
+        }
+    }
+
+    #[test]
+    fn test_canonical_rule() {
+        let parser = RuleParser::new(create_test_tags());
+        let canonical = |rule: &str| parser.canonical_rule(rule).unwrap();
+
+        assert_eq!(
+            canonical("- (Size=small)&colour=red"),
+            "colour = red & size = small"
+        );
+        assert_eq!(
+            canonical("-colour = red & (size = small & shape = circle)"),
+            canonical("- (shape = circle & colour = red) & size = small")
+        );
+        assert_eq!(
+            canonical("-(colour = red, blue) & size = small"),
+            "(colour = blue | colour = red) & size = small"
+        );
+        assert_ne!(
+            canonical("-colour = red & size = small"),
+            canonical("-colour = red | size = small")
+        );
+        assert!(parser.canonical_rule("-colour = purple").is_err());
+    }
+
+    #[test]
+    fn test_is_satisfiable() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "colour".to_string(),
+            types::TagConstraints {
+                single: true,
+                ..Default::default()
+            },
+        );
+        constraints.insert(
+            "size".to_string(),
+            types::TagConstraints {
+                ordered: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags())
+            .with_tag_constraints(constraints)
+            .with_exclusive_groups(vec![
+                ExclusiveGroup::Values {
+                    tag_name: "shape".to_string(),
+                    values: vec!["circle".to_string(), "square".to_string()],
+                },
+                ExclusiveGroup::Tags {
+                    tag_names: vec!["colour".to_string(), "shape".to_string()],
+                },
+            ]);
+
+        for rule in [
+            "-colour = red",
+            "-size = small & size = large",
+            "-size > small & size < medium",
+            "-(colour = red & colour = blue) | size = small",
+            "-colour = red & shape ! circle",
+            "-Size = Small & size ! large",
+        ] {
+            assert!(parser.is_satisfiable(rule).unwrap(), "{}", rule);
+        }
+
+        for rule in [
+            "-colour = red & colour = blue",
+            "-size = small & size ! small",
+            "-Size = small & size ! Small",
+            "-size > large",
+            "-size > small & size ! medium & size ! large",
+            "-shape = circle & shape = square",
+            "-colour = red & shape = circle",
+            "-(colour = red | colour = blue) & colour = green",
+        ] {
+            assert!(!parser.is_satisfiable(rule).unwrap(), "{}", rule);
+        }
+
+        assert!(parser.is_satisfiable("-colour = purple").is_err());
+    }
+
+    #[test]
+    fn test_overlap() {
+        let parser = RuleParser::new(create_test_tags());
+
+        assert_eq!(
+            parser
+                .overlap("-colour = red, blue", "-Size = large")
+                .unwrap(),
+            vec![
+                "colour = blue & size = large",
+                "colour = red & size = large"
+            ]
+        );
+        assert_eq!(
+            parser
+                .overlap("-colour = red", "-colour = red & shape ! circle")
+                .unwrap(),
+            vec!["colour = red & shape ! circle"]
+        );
+        assert!(
+            parser
+                .overlap("-size = small", "-size ! small")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(parser.overlap("-colour = red", "-colour = purple").is_err());
+    }
+
+    #[test]
+    fn test_normal_form() {
+        let parser = RuleParser::new(create_test_tags());
+
+        assert_eq!(
+            parser
+                .normal_form("-colour = red & (size = small | size = large)")
+                .unwrap(),
+            "colour = red & size = large | colour = red & size = small"
+        );
+        assert!(
+            parser
+                .is_equivalent(
+                    "-colour = red & (size = small | size = large)",
+                    "-Size = large & colour = red | colour = red & size = small"
+                )
+                .unwrap()
+        );
+        assert!(
+            parser
+                .is_equivalent(
+                    "-colour = red | colour = red & size = small",
+                    "-colour = red"
+                )
+                .unwrap()
+        );
+        assert!(
+            parser
+                .is_equivalent(
+                    "-colour = red | size = small & size ! small",
+                    "-colour = red"
+                )
+                .unwrap()
+        );
+        assert!(
+            !parser
+                .is_equivalent(
+                    "-colour = red & size = small",
+                    "-colour = red | size = small"
+                )
+                .unwrap()
+        );
+        assert!(parser.normal_form("-colour = purple").is_err());
+    }
+
+    #[test]
+    fn test_implies() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "size".to_string(),
+            types::TagConstraints {
+                ordered: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags()).with_tag_constraints(constraints);
+        let implies = |rule: &str, other: &str| parser.implies(rule, other).unwrap();
+
+        assert!(implies("-colour = red & size = small", "-colour = red"));
+        assert!(implies("-colour = red", "-colour = red, blue"));
+        assert!(implies("-size = large", "-size > small"));
+        assert!(implies("-size > medium", "-size = large"));
+        assert!(implies(
+            "-shape ! circle & colour = red",
+            "-colour ! blue | colour = red"
+        ));
+        assert!(implies("-size = small & size ! small", "-colour = red"));
+
+        assert!(!implies("-colour = red", "-colour = red & size = small"));
+        assert!(!implies("-colour = red, blue", "-colour = red"));
+        assert!(!implies("-size > small", "-size = large"));
+        assert!(!implies("-colour ! red", "-colour = blue"));
+        assert!(parser.implies("-colour = red", "-colour = purple").is_err());
+    }
+
+    #[test]
+    fn test_generate_examples() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "size".to_string(),
+            types::TagConstraints {
+                ordered: true,
+                single: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags()).with_tag_constraints(constraints);
+
+        for rule in [
+            "-colour = red & size > small",
+            "-(colour = blue | shape ! circle) & size < large",
+            "-colour ! red & shape ! circle",
+        ] {
+            let examples = parser.generate_examples(rule, 5, 7).unwrap();
+            assert_eq!(examples.matching.len(), 5, "{}", rule);
+            assert_eq!(examples.non_matching.len(), 5, "{}", rule);
+            for obj in &examples.matching {
+                assert!(
+                    parser.rule_matches(rule, obj).unwrap(),
+                    "{}: {:?}",
+                    rule,
+                    obj
+                );
+                assert!(obj.get("size").is_none_or(|values| values.len() == 1));
+            }
+            for obj in &examples.non_matching {
+                assert!(
+                    !parser.rule_matches(rule, obj).unwrap(),
+                    "{}: {:?}",
+                    rule,
+                    obj
+                );
+            }
+            assert_eq!(parser.generate_examples(rule, 5, 7).unwrap(), examples);
+        }
+
+        // Only two objects match: large, or large with red
+        let examples = parser
+            .generate_examples("-size > medium & colour ! blue & colour ! green", 5, 1)
+            .unwrap();
+        assert_eq!(examples.matching.len(), 2);
+
+        let examples = parser
+            .generate_examples("-size = small & size ! small", 5, 1)
+            .unwrap();
+        assert!(examples.matching.is_empty());
+        assert_eq!(examples.non_matching.len(), 4);
+    }
+
+    #[test]
+    fn test_why_not() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "size".to_string(),
+            types::TagConstraints {
+                ordered: true,
+                single: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags()).with_tag_constraints(constraints);
+        let object = |pairs: &[(&str, &str)]| -> types::Object {
+            let mut obj = types::Object::new();
+            for (tag_name, value) in pairs {
+                obj.entry(tag_name.to_string())
+                    .or_default()
+                    .push(value.to_string());
+            }
+            obj
+        };
+        let add = |tag_name: &str, value: &str| TagEdit::Add {
+            tag_name: tag_name.to_string(),
+            value: value.to_string(),
+        };
+        let remove = |tag_name: &str, value: &str| TagEdit::Remove {
+            tag_name: tag_name.to_string(),
+            value: value.to_string(),
+        };
+
+        let obj = object(&[("colour", "red"), ("size", "small")]);
+        assert!(parser.why_not("-colour = red", &obj).unwrap().is_none());
+
+        // The nearest larger size replaces the single value
+        let explanation = parser
+            .why_not("-colour = red & size > small", &obj)
+            .unwrap()
+            .unwrap();
+        assert_eq!(explanation.failed[0].to_string(), "size > small");
+        assert_eq!(
+            explanation.edits,
+            vec![remove("size", "small"), add("size", "medium")]
+        );
+        assert!(explanation.complete);
+
+        // The branch needing fewest changes is explained
+        let explanation = parser
+            .why_not(
+                "-colour = blue & shape = square & size = large | colour ! red",
+                &obj,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(explanation.edits, vec![remove("colour", "red")]);
+
+        let explanation = parser
+            .why_not("-colour = blue & size > small & size ! medium", &obj)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            explanation.edits,
+            vec![
+                add("colour", "blue"),
+                remove("size", "small"),
+                add("size", "large")
+            ]
+        );
+
+        assert!(
+            parser
+                .why_not("-size = small & size ! small", &obj)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
             "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            "&".to_string(),
+            types::TagConstraints {
+                required: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags())
+            .with_tag_constraints(constraints)
+            .with_exclusive_groups(vec![ExclusiveGroup::Tags {
+                tag_names: vec!["colour".to_string(), "shape".to_string()],
+            }]);
+
+        // Colour always has a value, and objects with a colour can't have a shape
+        let report = parser
+            .coverage(&["-colour = red", "-colour = blue & size = small"], 2)
+            .unwrap();
+        assert_eq!(report.tags, vec!["colour", "size"]);
+        assert_eq!(report.combinations, 12);
+        assert_eq!(report.uncovered, 7);
+        assert_eq!(report.examples.len(), 2);
+        assert_eq!(report.examples[0]["colour"], vec!["green"]);
+        assert_eq!(report.examples[0]["size"], vec!["small"]);
+        assert_eq!(report.examples[1]["colour"], vec!["blue"]);
+        assert_eq!(report.examples[1]["size"], vec!["medium"]);
+
+        let report = parser
+            .coverage(&["-colour = red, blue, green"], 10)
+            .unwrap();
+        assert_eq!(report.uncovered, 0);
+        assert!(report.examples.is_empty());
+    }
+
+    #[test]
+    fn test_truth_table() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "size".to_string(),
+            types::TagConstraints {
+                required: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags()).with_tag_constraints(constraints);
+
+        let table = parser
+            .truth_table("- shape = circle | size = large")
+            .unwrap();
+        assert_eq!(table.rule, "- shape = circle | size = large");
+        assert_eq!(table.tags, vec!["shape", "size"]);
+        // Shape has two values or none, and size always has one of three
+        assert_eq!(table.rows.len(), 9);
+        assert_eq!(
+            table.rows[0].values,
+            vec![Some("circle".to_string()), Some("small".to_string())]
+        );
+        assert!(table.rows[0].matched);
+        assert_eq!(table.rows[2].values, vec![None, Some("small".to_string())]);
+        assert!(!table.rows[2].matched);
+        assert_eq!(table.rows.iter().filter(|row| row.matched).count(), 5);
+
+        let csv = table.to_csv().unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "shape,size,matched");
+        assert_eq!(lines[1], "circle,small,true");
+        assert_eq!(lines[3], ",small,false");
+        assert_eq!(lines.len(), 10);
+
+        assert!(parser.truth_table("- colour = purple").is_err());
+    }
+
+    #[test]
+    fn test_generated_rules_normal_form() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
             "size".to_string(),
-            "=".to_string(),
-            "large".to_string(),
+            types::TagConstraints {
+                ordered: true,
+                ..Default::default()
+            },
+        );
+        let tags = create_test_tags();
+        let parser = RuleParser::new(tags.clone()).with_tag_constraints(constraints.clone());
+        let mut generator = crate::testing::RuleGenerator::new(&tags, 1)
+            .with_tag_constraints(&constraints)
+            .with_max_depth(4);
+
+        // A rule and its normal form agree on every combination of tag values
+        for rule in generator.generate_many(200).unwrap() {
+            let ast = parser.string_to_rule(&rule).unwrap();
+            let normal = parser.normal_form(&rule).unwrap();
+            let normal_ast = (!normal.is_empty())
+                .then(|| parser.string_to_rule(&format!("- {}", normal)).unwrap());
+
+            let space = parser.tag_space(&[rule.as_str()], 1000).unwrap();
+            for combination in &space.combinations {
+                let obj = RuleParser::combination_object(&space.tags, combination);
+                let expected = parser.evaluate_node(&ast.root_node, &obj);
+                let actual = normal_ast
+                    .as_ref()
+                    .is_some_and(|normal| parser.evaluate_node(&normal.root_node, &obj));
+                assert_eq!(
+                    expected, actual,
+                    "{} with normal form {} on {:?}",
+                    rule, normal, obj
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_tree() {
+        let parser = RuleParser::new(create_test_tags());
+
+        assert_eq!(
+            parser
+                .render_tree("- colour = red & (size = small, large)")
+                .unwrap(),
+            "&\n|-- colour = red\n`-- |\n    |-- size = small\n    `-- size = large\n"
+        );
+
+        // Without parentheses the comma list's '|' binds last
+        assert_eq!(
+            parser
+                .render_tree("- colour = red & size = small, large")
+                .unwrap(),
+            "|\n|-- &\n|   |-- colour = red\n|   `-- size = small\n`-- size = large\n"
+        );
+        assert_eq!(
+            parser.render_tree("- shape ! circle").unwrap(),
+            "shape ! circle\n"
+        );
+        assert!(parser.render_tree("- colour = purple").is_err());
+    }
+
+    #[test]
+    fn test_migrate_rule() {
+        let v2 = |rule: &str| migrate_rule(rule, GrammarVersion::V2).unwrap();
+        assert_eq!(
+            v2("- colour ! red, blue & size = large"),
+            "- colour != \"red\", \"blue\" & size == \"large\""
+        );
+        assert_eq!(
+            v2("-(colour=red|size>small)"),
+            "-(colour==\"red\"|size>\"small\")"
+        );
+
+        // Flags, negated flags and calls keep their shorthand
+        assert_eq!(
+            v2("- !is_fragile & is_boxed & created_at ~ before(2024-01-01)"),
+            "- !is_fragile & is_boxed & created_at ~ before(2024-01-01)"
+        );
+
+        // Already in v2, and back again
+        assert_eq!(v2("- colour != \"red\""), "- colour != \"red\"");
+        assert_eq!(
+            migrate_rule("- colour != \"red\" & size == large", GrammarVersion::V1).unwrap(),
+            "- colour ! red & size = large"
+        );
+
+        assert!(migrate_rule("- colour = \"red", GrammarVersion::V2).is_err());
+    }
+
+    #[test]
+    fn test_v2_grammar() {
+        let parser = RuleParser::new(create_test_tags());
+
+        assert!(
+            parser
+                .is_equivalent(
+                    "- colour != \"red\", \"blue\" & (size == large | shape = \"circle\")",
+                    "- colour ! red, blue & (size = large | shape = circle)",
+                )
+                .unwrap()
+        );
+        assert_eq!(
+            RuleParser::new(HashMap::new())
+                .clauses("- colour == \"Red\"")
+                .unwrap()[0]
+                .tag_value,
+            "red"
+        );
+
+        // A quoted value can have spaces, but is still checked against the tag's values
+        let err = parser
+            .validate_rule("- colour = \"light red\"")
+            .unwrap_err();
+        assert!(err.to_string().contains("'light red' is not a valid value"));
+        assert!(parser.validate_rule("- colour = \"red").is_err());
+        let clauses = RuleParser::new(HashMap::new())
+            .clauses("- colour = \"Navy-Blue Sky\", red")
+            .unwrap();
+        assert_eq!(clauses[0].tag_value, "navy-blue sky");
+        assert_eq!(clauses[0].to_string(), "colour = \"navy-blue sky\"");
+        assert_eq!(clauses[1].tag_value, "red");
+        assert!(parser.validate_rule("- colour = \"\"").is_err());
+        assert!(parser.validate_rule("- colour == = red").is_err());
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let canonical = "- colour = blue & size = large | colour = red & size = large";
+        for rule in [
+            "- (Size=large)&(colour=red,blue)",
+            "- colour == \"blue\" & size = large | size = large & colour = red",
+            "-size = large & (colour = red | colour = red & shape = circle | colour = blue)",
+        ] {
+            assert_eq!(parser.canonicalize(rule).unwrap(), canonical);
+            assert_eq!(
+                parser.fingerprint(rule).unwrap(),
+                audit::content_hash(canonical.as_bytes())
+            );
+        }
+
+        // Canonical rules are valid rules, and canonicalize to themselves
+        assert_eq!(parser.canonicalize(canonical).unwrap(), canonical);
+        assert_ne!(
+            parser.fingerprint("- colour = red").unwrap(),
+            parser.fingerprint("- colour = blue").unwrap()
+        );
+
+        // No normal form, so only the operands are sorted
+        assert_eq!(
+            parser
+                .canonicalize("- size ! small & size = small")
+                .unwrap(),
+            "- size ! small & size = small"
+        );
+    }
+
+    #[test]
+    fn test_variables() {
+        let parser = RuleParser::new(create_test_tags());
+        let obj = |colour: &str| HashMap::from([("colour".to_string(), vec![colour.to_string()])]);
+        let context = EvalContext::new().with("Colour", "red");
+
+        // Variables aren't checked against the tag's values
+        assert!(parser.validate_rule("- colour = $ctx.colour").is_ok());
+        assert!(
+            parser
+                .validate_rule("- colour = $today | size = $ctx.size")
+                .is_ok()
+        );
+        assert!(parser.validate_rule("- colour = $colour").is_err());
+        assert!(parser.validate_rule("- colour = $ctx.").is_err());
+
+        let rule = "- colour = $ctx.colour & size ! $ctx.colour";
+        assert!(
+            parser
+                .rule_matches_with_context(rule, &obj("red"), &context, 0)
+                .unwrap()
+        );
+        assert!(
+            !parser
+                .rule_matches_with_context(rule, &obj("blue"), &context, 0)
+                .unwrap()
+        );
+        assert!(matches!(
+            parser.rule_matches(rule, &obj("red")),
+            Err(RulesError::RuleParseError(msg)) if msg.contains("$ctx.colour")
+        ));
+
+        // $today is the date at the time of evaluation
+        let dated = HashMap::from([("colour".to_string(), vec!["2025-01-01".to_string()])]);
+        let new_year = 1_735_723_815;
+        for (timestamp, expected) in [(new_year, true), (new_year + 86_400, false)] {
+            assert_eq!(
+                parser
+                    .rule_matches_with_context("- colour = $today", &dated, &context, timestamp)
+                    .unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_explain() {
+        let parser = RuleParser::new(create_test_tags());
+        let obj = HashMap::from([
+            ("colour".to_string(), vec!["red".to_string()]),
+            ("size".to_string(), vec!["small".to_string()]),
+        ]);
+
+        let explanation = parser
+            .explain("- colour = red & (size = large | shape ! circle)", &obj, 0)
+            .unwrap();
+        assert!(explanation.matched);
+        assert_eq!(explanation.token, Token::And);
+        assert_eq!(explanation.children.len(), 2);
+
+        // Every operand is explained, with the object's values at each comparison
+        let size = &explanation.children[1].children[0];
+        assert!(!size.matched);
+        assert_eq!(size.values, vec!["small".to_string()]);
+        assert_eq!(
+            explanation.to_string(),
+            "&: true\n\
+             |-- colour = red: true (red)\n\
+             `-- |: true\n    \
+             |-- size = large: false (small)\n    \
+             `-- shape ! circle: true (no values)\n"
+        );
+
+        assert!(!parser.explain("- colour = blue", &obj, 0).unwrap().matched);
+        assert!(parser.explain("- colour = purple", &obj, 0).is_err());
+    }
+
+    // Counts the objects it's called on, always passing
+    struct CountCalls {
+        calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl RulePlugin for CountCalls {
+        fn name(&self) -> &str {
+            "counted"
+        }
+
+        fn call(&self, _values: &[String], _args: &[String]) -> bool {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            true
+        }
+    }
+
+    #[test]
+    fn test_short_circuit_evaluation() {
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut plugins: PluginMap = HashMap::new();
+        plugins.insert(
+            "counted".to_string(),
+            std::sync::Arc::new(CountCalls {
+                calls: calls.clone(),
+            }),
+        );
+        let parser = RuleParser::new(create_test_tags()).with_plugins(plugins);
+        let obj = HashMap::from([("colour".to_string(), vec!["red".to_string()])]);
+        let visits = |rule: &str| -> (bool, usize) {
+            calls.store(0, std::sync::atomic::Ordering::SeqCst);
+            let ast = parser.string_to_rule(rule).unwrap();
+            let matched = parser.ast_matches(&ast, &obj);
+            (matched, calls.load(std::sync::atomic::Ordering::SeqCst))
+        };
+
+        // The right subtree isn't visited when the left decides the result
+        assert_eq!(visits("- colour = blue & shape counted()"), (false, 0));
+        assert_eq!(visits("- colour = red | shape counted()"), (true, 0));
+        assert_eq!(
+            visits("- colour = blue & (shape counted() | size counted())"),
+            (false, 0)
+        );
+
+        // Otherwise it is
+        assert_eq!(visits("- colour = red & shape counted()"), (true, 1));
+        assert_eq!(visits("- colour = blue | shape counted()"), (true, 1));
+        assert_eq!(
+            visits("- colour = red & (shape counted() | size counted())"),
+            (true, 1)
+        );
+    }
+
+    #[test]
+    fn test_rule_priority() {
+        let parser = RuleParser::new(create_test_tags());
+        let obj = HashMap::from([("colour".to_string(), vec!["red".to_string()])]);
+        let loaded = |line: usize, rule: &str| LoadedRule {
+            file: "test.rules".to_string(),
+            line,
+            rule: rule.to_string(),
+            annotations: Vec::new(),
+        };
+
+        // The priority is part of the rule line, before the comparisons
+        assert!(parser.rule_matches("-[10] colour = red", &obj).unwrap());
+        assert!(parser.rule_matches("- [10] colour = red", &obj).unwrap());
+        assert_eq!(loaded(1, "-[10] colour = red").priority(), 10);
+        assert_eq!(loaded(1, "- colour = red").priority(), 0);
+        assert!(parser.validate_rule("-[high] colour = red").is_err());
+        assert!(parser.validate_rule("-[10 colour = red").is_err());
+
+        let subrules = parser
+            .parse_rules(&[loaded(1, "-[5] colour = red, blue")])
+            .unwrap();
+        assert!(subrules.iter().all(|subrule| subrule.priority == 5));
+
+        // Higher priorities match first, ties in file then line order
+        let rules = vec![
+            loaded(1, "- colour = red"),
+            loaded(2, "-[10] colour = red"),
+            loaded(3, "-[1] colour ! blue"),
+            loaded(4, "-[10] shape ! circle"),
         ];
-        let result = RuleParser::build_ast(tokens);
+        let context = EvalContext::default();
+        let lines = |mode: EvalMode| -> Vec<usize> {
+            parser
+                .matching_rules(&rules, &obj, &context, 0, mode)
+                .unwrap()
+                .iter()
+                .map(|loaded| loaded.line)
+                .collect()
+        };
+        assert_eq!(lines(EvalMode::AllMatches), vec![2, 4, 3, 1]);
+        assert_eq!(lines(EvalMode::FirstMatch), vec![2]);
+    }
 
-        assert!(result.is_ok());
-        let node = result.unwrap();
-        assert_eq!(node.token, crate::parser::types::Token::And);
-        assert!(node.left.is_some());
-        assert!(node.right.is_some());
+    #[test]
+    fn test_rule_outcome() {
+        let parser = RuleParser::new(create_test_tags());
+        let obj = HashMap::from([("colour".to_string(), vec!["red".to_string()])]);
+        let loaded = |rule: &str| LoadedRule {
+            file: "test.rules".to_string(),
+            line: 1,
+            rule: rule.to_string(),
+            annotations: Vec::new(),
+        };
+
+        // The outcome follows the comparisons, and isn't part of what's matched
+        assert!(
+            parser
+                .rule_matches("- colour = red => reject", &obj)
+                .unwrap()
+        );
+        assert!(
+            parser
+                .rule_matches("-[10] colour = red=>reject", &obj)
+                .unwrap()
+        );
+        assert!(
+            !parser
+                .rule_matches("- colour = blue => reject", &obj)
+                .unwrap()
+        );
+        assert_eq!(loaded("- colour = red => reject").outcome(), Some("reject"));
+        assert_eq!(loaded("- colour = red").outcome(), None);
+        assert!(parser.validate_rule("- colour = red =>").is_err());
+        assert!(parser.validate_rule("- colour = red => send back").is_err());
+
+        // Migrating leaves the outcome as it is
+        assert_eq!(
+            migrate_rule("- colour = red => reject", GrammarVersion::V2).unwrap(),
+            "- colour == \"red\" => reject"
+        );
+        assert_eq!(
+            migrate_rule("- colour == \"red\" => reject", GrammarVersion::V1).unwrap(),
+            "- colour = red => reject"
+        );
+    }
+
+    #[test]
+    fn test_rule_weight() {
+        let rules = parse_rules_from_str(
+            "test.rules",
+            "@weight 2.5\n- colour = red\n@weight -1\n- size = large\n- shape = circle\n",
+        )
+        .unwrap();
+        let weights: Vec<f64> = rules.iter().map(LoadedRule::weight).collect();
+        assert_eq!(weights, vec![2.5, -1.0, types::DEFAULT_WEIGHT]);
+
+        for weight in ["heavy", "", "inf"] {
+            let content = format!("@weight {}\n- colour = red", weight);
+            assert!(parse_rules_from_str("test.rules", &content).is_err());
+        }
     }
 
     #[test]
-    fn test_build_ast_with_or() {
-        let tokens = vec![
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            "|".to_string(),
+    fn test_three_valued_evaluation() {
+        assert_eq!(Truth::Unknown.and(Truth::False), Truth::False);
+        assert_eq!(Truth::Unknown.and(Truth::True), Truth::Unknown);
+        assert_eq!(Truth::Unknown.or(Truth::True), Truth::True);
+        assert_eq!(Truth::Unknown.or(Truth::False), Truth::Unknown);
+
+        // "purple" isn't one of colour's listed values
+        let obj = HashMap::from([
+            ("colour".to_string(), vec!["purple".to_string()]),
+            ("size".to_string(), vec!["large".to_string()]),
+        ]);
+        let parser = RuleParser::new(create_test_tags());
+        let truth = |rule: &str| parser.rule_truth(rule, &obj).unwrap();
+        assert_eq!(truth("- colour = red"), Truth::Unknown);
+        assert_eq!(truth("- colour ! red"), Truth::Unknown);
+        assert_eq!(truth("- colour = red | size = large"), Truth::True);
+        assert_eq!(truth("- colour = red & size = small"), Truth::False);
+        assert_eq!(truth("- colour = red & size = large"), Truth::Unknown);
+        assert_eq!(truth("- shape = circle"), Truth::False);
+
+        // A known value decides the clause whatever the others are
+        let mixed = HashMap::from([(
             "colour".to_string(),
-            "=".to_string(),
-            "blue".to_string(),
-        ];
-        let result = RuleParser::build_ast(tokens);
+            vec!["purple".to_string(), "red".to_string()],
+        )]);
+        assert_eq!(
+            parser.rule_truth("- colour = red", &mixed).unwrap(),
+            Truth::True
+        );
+        assert_eq!(
+            parser.rule_truth("- colour ! red", &mixed).unwrap(),
+            Truth::False
+        );
 
-        assert!(result.is_ok());
-        let node = result.unwrap();
-        assert_eq!(node.token, crate::parser::types::Token::Or);
-        assert!(node.left.is_some());
-        assert!(node.right.is_some());
+        // Unknown rules are collapsed per the policy, two-valued comparing values as they are
+        let matches = |policy: UnknownPolicy, rule: &str| {
+            RuleParser::new(create_test_tags())
+                .with_unknown_policy(policy)
+                .rule_matches(rule, &obj)
+                .unwrap()
+        };
+        assert!(matches(UnknownPolicy::TwoValued, "- colour ! red"));
+        assert!(!matches(UnknownPolicy::UnknownIsFalse, "- colour ! red"));
+        assert!(matches(
+            UnknownPolicy::UnknownIsTrue,
+            "- colour = red & size = large"
+        ));
+        assert!(!matches(
+            UnknownPolicy::UnknownIsTrue,
+            "- colour = red & size = small"
+        ));
+
+        // Subrules collapse the same way
+        let loaded = LoadedRule {
+            file: "test.rules".to_string(),
+            line: 1,
+            rule: "- colour = red, blue & size = large".to_string(),
+            annotations: Vec::new(),
+        };
+        let subrule_matches = |policy: UnknownPolicy| {
+            let parser = RuleParser::new(create_test_tags()).with_unknown_policy(policy);
+            parser
+                .parse_rules(std::slice::from_ref(&loaded))
+                .unwrap()
+                .iter()
+                .any(|subrule| {
+                    parser
+                        .subrule_matches(subrule, &obj, &EvalContext::default(), 0)
+                        .unwrap()
+                })
+        };
+        assert!(!subrule_matches(UnknownPolicy::TwoValued));
+        assert!(!subrule_matches(UnknownPolicy::UnknownIsFalse));
+        assert!(subrule_matches(UnknownPolicy::UnknownIsTrue));
     }
 
     #[test]
-    fn test_build_ast_strips_outer_parens() {
-        let tokens = vec![
-            "(".to_string(),
-            "colour".to_string(),
-            "=".to_string(),
-            "red".to_string(),
-            ")".to_string(),
-        ];
-        let result = RuleParser::build_ast(tokens);
+    fn test_missing_tag_policy() {
+        let obj = HashMap::from([("colour".to_string(), vec!["red".to_string()])]);
+        let matches = |policy: MissingTagPolicy, rule: &str| {
+            RuleParser::new(create_test_tags())
+                .with_missing_tag_policy(policy)
+                .rule_matches(rule, &obj)
+        };
 
-        assert!(result.is_ok());
-        let node = result.unwrap();
-        // Should strip parens and create a leaf node
-        assert_eq!(node.token, crate::parser::types::Token::Equals);
+        // By default the object is compared as if size had no values
+        assert!(!matches(MissingTagPolicy::NoValues, "- size = large").unwrap());
+        assert!(matches(MissingTagPolicy::NoValues, "- size ! large").unwrap());
+
+        assert!(!matches(MissingTagPolicy::FailClause, "- size ! large").unwrap());
+        assert!(
+            matches(
+                MissingTagPolicy::FailClause,
+                "- colour = red | size ! large"
+            )
+            .unwrap()
+        );
+
+        assert!(
+            matches(
+                MissingTagPolicy::SkipClause,
+                "- colour = red & size = large"
+            )
+            .unwrap()
+        );
+        assert!(
+            !matches(
+                MissingTagPolicy::SkipClause,
+                "- colour = blue & size = large"
+            )
+            .unwrap()
+        );
+
+        assert!(matches(MissingTagPolicy::Error, "- colour = red").unwrap());
+        assert!(matches!(
+            matches(MissingTagPolicy::Error, "- colour = red | size = large"),
+            Err(RulesError::ObjectParseError(msg)) if msg.contains("'size'")
+        ));
     }
 
-    // Tests for check_rule_syntax
     #[test]
-    fn test_check_rule_syntax_valid_simple() {
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_ok());
+    fn test_trace() {
+        let parser = RuleParser::new(create_test_tags());
+        let obj = HashMap::from([
+            ("colour".to_string(), vec!["blue".to_string()]),
+            ("size".to_string(), vec!["large".to_string()]),
+        ]);
+        let trace = |rule: &str| {
+            let (steps, matched) = parser
+                .trace(rule, &obj, &EvalContext::default(), 0)
+                .unwrap();
+            let steps: Vec<(usize, String)> = steps
+                .iter()
+                .map(|step| (step.depth, step.to_string()))
+                .collect();
+            (steps, matched)
+        };
+
+        // The right operand of '&' isn't visited once the left fails
+        assert_eq!(
+            trace("- colour = red & size = large"),
+            (
+                vec![
+                    (0, "&: false".to_string()),
+                    (1, "colour = red: false (blue)".to_string()),
+                ],
+                false
+            )
+        );
+
+        // Each node comes before its operands
+        assert_eq!(
+            trace("- colour = red | size = large & shape ! circle"),
+            (
+                vec![
+                    (0, "|: true".to_string()),
+                    (1, "colour = red: false (blue)".to_string()),
+                    (1, "&: true".to_string()),
+                    (2, "size = large: true (large)".to_string()),
+                    (2, "shape ! circle: true (no values)".to_string()),
+                ],
+                true
+            )
+        );
     }
 
     #[test]
-    fn test_check_rule_syntax_valid_with_parentheses() {
-        let tokens = vec![
-            ("(".to_string(), TokenType::TagName, 1),
-            ("colour".to_string(), TokenType::TagName, 1),
-            ("=".to_string(), TokenType::ComparisonOp, 1),
-            ("red".to_string(), TokenType::TagValue, 1),
-            (")".to_string(), TokenType::LogicalOp, 1),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_ok());
+    fn test_read_rule_group_negation() {
+        // A '!' where a tag name would go negates the group after it
+        for line in ["-!(colour = red)", "-colour = red & !(size = large)"] {
+            let (_root, tokens) = RuleParser::new(HashMap::new()).read_rule(line).unwrap();
+            assert_eq!(find_token(&tokens, "!"), Some(&TokenType::Negation));
+        }
     }
 
     #[test]
-    fn test_check_rule_syntax_valid_with_logical_op() {
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 1),
-            ("=".to_string(), TokenType::ComparisonOp, 1),
-            ("red".to_string(), TokenType::TagValue, 1),
-            ("&".to_string(), TokenType::LogicalOp, 1),
-            ("size".to_string(), TokenType::TagName, 1),
-            ("=".to_string(), TokenType::ComparisonOp, 1),
-            ("large".to_string(), TokenType::TagValue, 1),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_ok());
+    fn test_double_negation() {
+        let mut tags = create_test_tags();
+        tags.insert(
+            "is_fragile".to_string(),
+            vec!["true".to_string(), "false".to_string()],
+        );
+        let parser = RuleParser::new(tags);
+
+        // Each "!!" cancels out, before a group, a flag or a presence check
+        assert_eq!(lexed("-!!(colour = red)").unwrap()[..2], ["(", "colour"]);
+        for (rule, canonical) in [
+            ("- !!(colour = red)", "- colour = red"),
+            ("- !!!(colour = red)", "- colour ! red"),
+            (
+                "- size = small & !!(colour = red | shape = circle)",
+                "- colour = red & size = small | shape = circle & size = small",
+            ),
+            ("- !!is_fragile", "- is_fragile = true"),
+            ("- !!!is_fragile", "- is_fragile = false"),
+            ("- !!colour?", "- colour?"),
+        ] {
+            assert!(parser.validate_rule(rule).is_ok(), "{}", rule);
+            assert_eq!(parser.canonicalize(rule).unwrap(), canonical);
+        }
+
+        let obj = HashMap::from([("colour".to_string(), vec!["red".to_string()])]);
+        assert!(parser.rule_matches("- !!(colour = red)", &obj).unwrap());
+        assert!(!parser.rule_matches("- !!!(colour = red)", &obj).unwrap());
     }
 
     #[test]
-    fn test_check_rule_syntax_valid_complex() {
-        let tokens = vec![
-            ("(".to_string(), TokenType::TagName, 1),
-            ("colour".to_string(), TokenType::TagName, 1),
-            ("=".to_string(), TokenType::ComparisonOp, 1),
-            ("red".to_string(), TokenType::TagValue, 1),
-            (")".to_string(), TokenType::LogicalOp, 1),
-            ("&".to_string(), TokenType::LogicalOp, 0),
-            ("(".to_string(), TokenType::TagName, 1),
-            ("size".to_string(), TokenType::TagName, 1),
-            ("!".to_string(), TokenType::ComparisonOp, 1),
-            ("small".to_string(), TokenType::TagValue, 1),
-            (")".to_string(), TokenType::LogicalOp, 1),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_ok());
+    fn test_group_negation() {
+        assert_eq!(
+            lexed("-!(colour = red & size = large)").unwrap()[..2],
+            ["!", "("]
+        );
+        let (root, _) = RuleParser::new(HashMap::new())
+            .read_rule("-!(colour = red & size = large)")
+            .unwrap();
+        assert_eq!(root.token, Token::Not);
+
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "size".to_string(),
+            types::TagConstraints {
+                ordered: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags()).with_tag_constraints(constraints);
+        let canonical = |rule: &str| parser.canonical_rule(rule).unwrap();
+
+        // De Morgan's laws push the negation down to the comparisons
+        assert_eq!(
+            canonical("- !(colour = red & size = large)"),
+            canonical("- colour ! red | size ! large")
+        );
+        assert_eq!(
+            canonical("- shape = circle & !(colour = red, blue)"),
+            canonical("- shape = circle & colour ! red & colour ! blue")
+        );
+        assert_eq!(
+            canonical("- !(size > small | !(colour ! green))"),
+            canonical("- size ! medium & size ! large & colour ! green")
+        );
+        assert_eq!(
+            canonical("- !(size < small)"),
+            canonical("- size ! small | size = small")
+        );
+
+        let obj = HashMap::from([
+            ("colour".to_string(), vec!["red".to_string()]),
+            ("size".to_string(), vec!["small".to_string()]),
+        ]);
+        assert!(
+            parser
+                .rule_matches("- !(colour = red & size = large)", &obj)
+                .unwrap()
+        );
+        assert!(
+            !parser
+                .rule_matches("- !(colour = red | size = large)", &obj)
+                .unwrap()
+        );
+
+        // Subrules are of the negated form
+        let rules = parse_rules_from_str("test.rules", "- !(colour = red & size = large)").unwrap();
+        let subrules = parser.parse_rules(&rules).unwrap();
+        let clauses: Vec<String> = subrules
+            .iter()
+            .flat_map(|subrule| subrule.clauses.iter().map(Clause::to_string))
+            .collect();
+        assert_eq!(clauses, vec!["colour ! red", "size ! large"]);
+
+        // '!' only negates a group or a flag
+        assert!(parser.validate_rule("- !(colour = red)").is_ok());
+        assert!(
+            parser
+                .validate_rule("- colour = red & !(size = large)")
+                .is_ok()
+        );
+        assert!(parser.validate_rule("- !colour = red").is_err());
+        assert!(parser.validate_rule("- !()").is_err());
+        assert!(
+            parser
+                .validate_rule("- colour = red !(size = large)")
+                .is_err()
+        );
+        assert_eq!(
+            migrate_rule("- !(colour ! red)", GrammarVersion::V2).unwrap(),
+            "- !(colour != \"red\")"
+        );
+        assert!(matches!(
+            parser.validate_rule("- !(size > $ctx.size)"),
+            Err(RulesError::RuleParseError(msg)) if msg.contains("Can't negate 'size > $ctx.size'")
+        ));
     }
 
     #[test]
-    fn test_check_rule_syntax_starts_with_comparison_op() {
-        let tokens = vec![
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
-        if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("must start with a tag name"));
-        } else {
-            panic!("Expected RuleParseError");
+    fn test_range_values() {
+        assert_eq!(
+            read_tokens("-floor = 2..4").unwrap(),
+            vec![
+                "floor", "=", "2", "|", "floor", "=", "3", "|", "floor", "=", "4"
+            ]
+        );
+
+        let mut tags = create_test_tags();
+        tags.insert(
+            "floor".to_string(),
+            (1..=5).map(|floor| floor.to_string()).collect(),
+        );
+        let parser = RuleParser::new(tags);
+        let obj = |floor: &str| HashMap::from([("floor".to_string(), vec![floor.to_string()])]);
+        let matches = |rule: &str, floor: &str| parser.rule_matches(rule, &obj(floor)).unwrap();
+
+        assert!(matches("- floor = 2..4", "3"));
+        assert!(!matches("- floor = 2..4", "5"));
+        assert!(matches("- floor = 1, 4..5", "5"));
+        assert!(matches("- floor = 3..3 & colour ! red", "3"));
+        assert!(!matches("- !(floor = 2..4)", "2"));
+
+        // Every value of the range must be one of the tag's values
+        assert!(matches!(
+            parser.validate_rule("- floor = 4..6"),
+            Err(RulesError::RuleParseError(msg)) if msg.contains("'6' is not a valid value")
+        ));
+
+        for rule in [
+            "- floor = 4..2",
+            "- floor = 1..x",
+            "- floor ! 1..3",
+            "- floor = 1..5000",
+        ] {
+            assert!(parser.validate_rule(rule).is_err(), "{}", rule);
         }
     }
 
     #[test]
-    fn test_check_rule_syntax_starts_with_tag_value() {
-        let tokens = vec![
-            ("red".to_string(), TokenType::TagValue, 0),
-            ("&".to_string(), TokenType::LogicalOp, 0),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
-        if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("must start with a tag name"));
-        } else {
-            panic!("Expected RuleParseError");
-        }
+    fn test_glob_values() {
+        assert!(string::glob_matches("re*", "red"));
+        assert!(string::glob_matches("re*", "re"));
+        assert!(string::glob_matches("*e*n", "green"));
+        assert!(string::glob_matches("*", ""));
+        assert!(!string::glob_matches("re*", "ruby"));
+        assert!(!string::glob_matches("*ee", "green"));
+
+        let parser = RuleParser::new(create_test_tags());
+        let obj = HashMap::from([
+            ("colour".to_string(), vec!["green".to_string()]),
+            ("shape".to_string(), vec!["square".to_string()]),
+        ]);
+        let matches = |rule: &str| parser.rule_matches(rule, &obj).unwrap();
+
+        assert!(matches("- colour = gr*"));
+        assert!(matches("- colour = RE*, *een"));
+        assert!(!matches("- colour = *e*d"));
+        assert!(matches("- colour ! b* & shape = s*"));
+        assert!(!matches("- !(colour = *n)"));
+
+        // A glob must match one of the tag's values
+        assert!(matches!(
+            parser.validate_rule("- colour = pur*"),
+            Err(RulesError::RuleParseError(msg)) if msg.contains("'pur*' matches none")
+        ));
+        assert!(parser.validate_rule("- size > s*").is_err());
+
+        // Globs are assumed to match, rather than compared as values
+        assert!(
+            parser
+                .is_satisfiable("- colour = r* & colour = red")
+                .unwrap()
+        );
     }
 
     #[test]
-    fn test_check_rule_syntax_two_tag_names_in_a_row() {
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("size".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
-        if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("Invalid token sequence"));
-        } else {
-            panic!("Expected RuleParseError");
-        }
+    fn test_regex_values() {
+        let mut tags = create_test_tags();
+        tags.insert("sku".to_string(), Vec::new());
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "sku".to_string(),
+            types::TagConstraints {
+                open: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(tags).with_tag_constraints(constraints);
+
+        let clauses = RuleParser::new(HashMap::new())
+            .clauses("- sku ~ ^AB\\d+$")
+            .unwrap();
+        assert_eq!(clauses[0].comparison_op, ComparisonOp::REGX);
+        // Regexes aren't lowercased, which would turn \D into \d
+        assert_eq!(clauses[0].tag_value, "^AB\\d+$");
+
+        let obj = |sku: &str| {
+            HashMap::from([
+                ("sku".to_string(), vec![sku.to_string()]),
+                ("colour".to_string(), vec!["red".to_string()]),
+            ])
+        };
+        let matches = |rule: &str, sku: &str| parser.rule_matches(rule, &obj(sku)).unwrap();
+        assert!(matches("- sku ~ ^AB[0-9]+$", "ab123"));
+        assert!(!matches("- sku ~ ^AB[0-9]+$", "ab12x"));
+        assert!(matches("- sku ~ ^\\D{2} & colour ~ ^r", "XY9"));
+        assert!(!matches("- sku ~ ^\\D{2}", "X99"));
+        assert!(!matches("- size ~ ^s", "ab1"));
+
+        // Regexes are compiled when the rule is checked
+        assert!(matches!(
+            parser.validate_rule("- sku ~ ^AB[0-9+$"),
+            Err(RulesError::RuleParseError(msg)) if msg.contains("Invalid regex '^AB[0-9+$'")
+        ));
+        assert!(parser.validate_rule("- !(sku ~ ^AB)").is_err());
+
+        // A value shaped like a call still calls a plugin
+        assert!(matches!(
+            parser.validate_rule("- sku ~ before(2024-01-01)"),
+            Err(RulesError::RuleParseError(msg)) if msg.contains("unknown function: before")
+        ));
     }
 
     #[test]
-    fn test_check_rule_syntax_two_comparison_ops_in_a_row() {
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("!".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
-        if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("Invalid token sequence"));
-        } else {
-            panic!("Expected RuleParseError");
-        }
+    fn test_presence_checks() {
+        let parser = RuleParser::new(create_test_tags());
+
+        let clauses = RuleParser::new(HashMap::new())
+            .clauses("- colour? & size = large")
+            .unwrap();
+        assert_eq!(clauses[0].comparison_op, ComparisonOp::PRES);
+        assert_eq!(clauses[0].to_string(), "colour?");
+
+        let with_colour = HashMap::from([("colour".to_string(), vec!["blue".to_string()])]);
+        let without_colour = HashMap::from([("size".to_string(), vec!["large".to_string()])]);
+        let matches = |rule: &str, obj: &types::Object| parser.rule_matches(rule, obj).unwrap();
+        assert!(matches("- colour?", &with_colour));
+        assert!(!matches("- colour?", &without_colour));
+        assert!(matches("- !colour? & size = large", &without_colour));
+        assert!(!matches("- !colour?", &with_colour));
+        assert!(matches("- !(colour? | shape?)", &without_colour));
+        assert!(matches("- (colour?) | size = small", &with_colour));
+
+        // Presence checks are about missing tags, so ignore the missing tag policy
+        let strict =
+            RuleParser::new(create_test_tags()).with_missing_tag_policy(MissingTagPolicy::Error);
+        assert!(strict.rule_matches("- !colour?", &without_colour).unwrap());
+
+        assert!(!parser.is_satisfiable("- !colour? & colour = red").unwrap());
+        assert!(!parser.is_satisfiable("- colour? & !colour?").unwrap());
+        assert!(parser.is_satisfiable("- !colour? & colour ! red").unwrap());
+
+        // '?' takes no value
+        assert!(matches!(
+            parser.validate_rule("- colour? red"),
+            Err(RulesError::RuleParseError(msg)) if msg.contains("'?' takes no value")
+        ));
+        assert!(parser.validate_rule("- colour = ?").is_err());
+        assert!(parser.validate_rule("- purple?").is_err());
     }
 
     #[test]
-    fn test_check_rule_syntax_two_tag_values_in_a_row() {
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-            ("blue".to_string(), TokenType::TagValue, 0),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
-        if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("Invalid token sequence"));
-        } else {
-            panic!("Expected RuleParseError");
-        }
+    fn test_escaped_values() {
+        let mut tags = create_test_tags();
+        tags.insert(
+            "dish".to_string(),
+            vec![
+                "fish & chips".to_string(),
+                "a|b".to_string(),
+                "say \"hi\"".to_string(),
+                "x=>y".to_string(),
+                "1..3".to_string(),
+            ],
+        );
+        let parser = RuleParser::new(tags);
+        let obj = |dish: &str| HashMap::from([("dish".to_string(), vec![dish.to_string()])]);
+        let matches = |rule: &str, dish: &str| parser.rule_matches(rule, &obj(dish)).unwrap();
+
+        // Operators, quotes and range separators in a quoted value are part of it
+        assert!(matches("- dish = \"fish & chips\" | dish = \"a|b\"", "a|b"));
+        assert!(matches("- dish = \"say \\\"hi\\\"\"", "say \"hi\""));
+        assert!(matches("- dish = \"x=>y\" => reject", "x=>y"));
+        assert!(matches("- dish = \"1..3\"", "1..3"));
+        assert!(matches("- dish ~ \"^(fish|chips)\"", "fish & chips"));
+        assert!(!matches("- dish ! \"fish & chips\"", "fish & chips"));
+
+        // Values are quoted and escaped again when written out
+        let clauses = RuleParser::new(HashMap::new())
+            .clauses("- dish = \"say \\\"hi\\\"\", \"a|b\"")
+            .unwrap();
+        assert_eq!(clauses[0].tag_value, "say \"hi\"");
+        assert_eq!(clauses[0].to_string(), "dish = \"say \\\"hi\\\"\"");
+        assert_eq!(clauses[1].to_string(), "dish = \"a|b\"");
+        assert_eq!(
+            parser
+                .canonical_rule("- dish = \"a|b\" & colour = red")
+                .unwrap(),
+            "colour = red & dish = \"a|b\""
+        );
+
+        let v2 = migrate_rule("- dish = \"fish & chips\", red", GrammarVersion::V2).unwrap();
+        assert_eq!(v2, "- dish == \"fish & chips\", \"red\"");
+        let v1 = migrate_rule(&v2, GrammarVersion::V1).unwrap();
+        assert_eq!(v1, "- dish = \"fish & chips\", red");
+
+        assert!(parser.validate_rule("- dish = \"fish & chips").is_err());
+        assert!(parser.validate_rule("- dish = fish & chips").is_err());
     }
 
     #[test]
-    fn test_check_rule_syntax_two_logical_ops_in_a_row() {
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-            ("&".to_string(), TokenType::LogicalOp, 0),
-            ("|".to_string(), TokenType::LogicalOp, 0),
-            ("size".to_string(), TokenType::TagName, 0),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
+    fn test_named_rules() {
+        let parser = RuleParser::new(create_test_tags());
+        let content = "@weight 2\npromo_1: -[10] colour = red => reject\n#test: match { colour: red }\n- size = large\n";
+        let rules = parse_rules_from_str("a.rules", content).unwrap();
+        assert_eq!(rules[0].name(), Some("promo_1"));
+        assert_eq!(rules[0].priority(), 10);
+        assert_eq!(rules[0].outcome(), Some("reject"));
+        assert_eq!(rules[1].name(), None);
+
+        // The name isn't part of what the rule matches
+        assert!(parser.validate_rule(&rules[0].rule).is_ok());
+        let subrules = parser.parse_rules(&rules).unwrap();
+        assert_eq!(subrules[0].name.as_deref(), Some("promo_1"));
+        assert_eq!(subrules[1].name, None);
+
+        let error = rule_error_at(
+            &rules[0],
+            RulesError::RuleParseError("Invalid tag".to_string()),
+        );
+        assert!(
+            error
+                .to_string()
+                .ends_with("a.rules:2 (promo_1): Invalid tag")
+        );
+
+        assert_eq!(
+            migrate_rule("promo_1: - colour ! red => reject", GrammarVersion::V2).unwrap(),
+            "promo_1: - colour != \"red\" => reject"
+        );
+
+        // Annotations and tests go with the rule
+        assert_eq!(remove_rule(content, 2), "- size = large\n");
+
+        let result = parse_rules_from_str("a.rules", "promo-1: - colour = red");
         if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("Invalid token sequence"));
+            assert!(msg.contains("a.rules:1: Invalid rule name: 'promo-1'"));
         } else {
-            panic!("Expected RuleParseError");
+            panic!("Expected RuleParseError about the rule name");
         }
-    }
+        assert!(parser.validate_rule("colour = red").is_err());
 
-    #[test]
-    fn test_check_rule_syntax_ends_with_tag_name() {
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-            ("&".to_string(), TokenType::LogicalOp, 0),
-            ("size".to_string(), TokenType::TagName, 0),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
+        let mut rules = parse_rules_from_str("a.rules", "promo_1: - colour = red").unwrap();
+        rules.extend(
+            parse_rules_from_str("b.rules", "- size = large\npromo_1: - size = small").unwrap(),
+        );
+        let result = check_rule_names(&rules);
         if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("must end with a tag value"));
+            assert!(msg.contains("b.rules:2: Rule name 'promo_1' is already used at a.rules:1"));
         } else {
-            panic!("Expected RuleParseError");
+            panic!("Expected RuleParseError about a duplicate rule name");
         }
     }
 
     #[test]
-    fn test_check_rule_syntax_ends_with_comparison_op() {
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
+    fn test_annotation_calls() {
+        let content = "@description(\"Red (or pink) items\") @severity(high)\n@owner alice\n@draft(red) @weight( 2 )\n- colour = red\n";
+        let rules = parse_rules_from_str("a.rules", content).unwrap();
+        let meta = rules[0].meta();
+        assert_eq!(meta.description.as_deref(), Some("Red (or pink) items"));
+        assert_eq!(meta.severity, Some(types::Severity::High));
+        assert_eq!(meta.owner.as_deref(), Some("alice"));
+        assert_eq!(rules[0].draft_name(), Some("red"));
+        assert_eq!(rules[0].weight(), 2.0);
+        assert_eq!(
+            meta.to_string(),
+            "@description(\"Red (or pink) items\") @severity(high) @owner(alice)"
+        );
+
+        // Other annotations on the line are kept
+        assert_eq!(
+            remove_annotation(content, 4, types::DRAFT),
+            "@description(\"Red (or pink) items\") @severity(high)\n@owner alice\n@weight(2)\n- colour = red\n"
+        );
+
+        for line in [
+            "@severity(high",
+            "@severity(high) owner",
+            "@description(\"Red\" items)",
+        ] {
+            let result = get_annotations_from_line(line);
+            if let Err(RulesError::RuleParseError(msg)) = result {
+                assert!(msg.contains("expected annotations like @severity(high)"));
+            } else {
+                panic!("Expected RuleParseError for '{}'", line);
+            }
+        }
+
+        let result = parse_rules_from_str("a.rules", "@severity(urgent)\n- colour = red");
         if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("must end with a tag value"));
+            assert!(msg.contains("Invalid severity for @severity: 'urgent'"));
         } else {
-            panic!("Expected RuleParseError");
+            panic!("Expected RuleParseError about the severity");
         }
     }
 
     #[test]
-    fn test_check_rule_syntax_ends_with_logical_op() {
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-            ("&".to_string(), TokenType::LogicalOp, 0),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
+    fn test_parse_rules_with_includes() {
+        let source = |path: &str, content: &str| (path.to_string(), content.to_string());
+
+        // Included rules keep their own file, and come once
+        let rules = parse_rules_with_includes(
+            "cfg",
+            vec![
+                source("cfg/a.rules", "#include b.rules\n- colour = red"),
+                source("cfg/b.rules", "- size = large"),
+                source("cfg/c.rules", "#include ./b.rules\n#including nothing"),
+            ],
+        )
+        .unwrap();
+        let locations: Vec<String> = rules
+            .iter()
+            .map(|loaded| format!("{}:{}", loaded.file, loaded.line))
+            .collect();
+        assert_eq!(locations, vec!["cfg/a.rules:2", "cfg/b.rules:1"]);
+
+        let result = parse_rules_with_includes(
+            "cfg",
+            vec![
+                source("cfg/a.rules", "#include b.rules"),
+                source("cfg/b.rules", "- size = large\n#include a.rules"),
+            ],
+        );
         if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("must end with a tag value"));
+            assert!(msg.starts_with(
+                "cfg/b.rules:2: Include cycle: cfg/a.rules -> cfg/b.rules -> cfg/a.rules"
+            ));
+            assert!(msg.ends_with("(included from cfg/a.rules:1)"));
         } else {
-            panic!("Expected RuleParseError");
+            panic!("Expected RuleParseError about an include cycle");
         }
-    }
 
-    #[test]
-    fn test_check_rule_syntax_empty_rule() {
-        let tokens = vec![];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
+        let result = parse_rules_with_includes(
+            "cfg",
+            vec![
+                source("cfg/a.rules", "#include b.rules"),
+                source("cfg/b.rules", "@draft\n- size = large"),
+            ],
+        );
         if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("Empty rule"));
+            assert!(msg.starts_with("cfg/b.rules:2: "));
+            assert!(msg.ends_with("(included from cfg/a.rules:1)"));
         } else {
-            panic!("Expected RuleParseError");
+            panic!("Expected RuleParseError from the included file");
+        }
+
+        for (include, expected) in [
+            ("missing.rules", "Included file not found: missing.rules"),
+            ("../a.rules", "Invalid include: '../a.rules'"),
+            (
+                "shared/common.tags",
+                "Invalid include: 'shared/common.tags'",
+            ),
+        ] {
+            let content = format!("#include {}", include);
+            let result = parse_rules_with_includes("cfg", vec![source("cfg/a.rules", &content)]);
+            if let Err(RulesError::RuleParseError(msg)) = result {
+                assert!(msg.contains(expected), "{}", msg);
+            } else {
+                panic!("Expected RuleParseError for '{}'", include);
+            }
         }
     }
 
     #[test]
-    fn test_check_rule_syntax_only_parentheses() {
-        let tokens = vec![
-            ("(".to_string(), TokenType::TagName, 1),
-            (")".to_string(), TokenType::LogicalOp, 1),
-        ];
-        let result = RuleParser::check_rule_syntax(&tokens);
-        assert!(result.is_err());
+    fn test_read_rules_collects_failures() {
+        let source = |path: &str, content: &str| (path.to_string(), content.to_string());
+        let mut report = ValidationReport::default();
+        let rules = read_rules_with_includes(
+            "cfg",
+            vec![
+                source(
+                    "cfg/a.rules",
+                    "@on-match x\n- colour = red\npromo-1: - size = large\n#include b.rules\n#include missing.rules",
+                ),
+                source(
+                    "cfg/b.rules",
+                    "- shape = circle\n@expires soon\n- colour = blue\n@weight 2",
+                ),
+            ],
+            &mut report,
+        );
+        let lines: Vec<usize> = rules.iter().map(|loaded| loaded.line).collect();
+        assert_eq!(lines, vec![2, 1]);
+
+        let locations: Vec<String> = report
+            .failures
+            .iter()
+            .map(|failure| format!("{}:{}", failure.location.file, failure.location.line))
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                "cfg/a.rules:1",
+                "cfg/a.rules:3",
+                "cfg/b.rules:3",
+                "cfg/b.rules:4",
+                "cfg/a.rules:5"
+            ]
+        );
+        assert!(
+            report.failures[2]
+                .error
+                .to_string()
+                .ends_with("(included from cfg/a.rules:4)")
+        );
+        assert!(report.to_string().starts_with("5 invalid rule lines:\n  "));
+
+        // Rules are checked after reading, and a reused name is reported at each reuse
+        let rules = parse_rules_from_str(
+            "a.rules",
+            "p: - colour = red\np: - colour = (blue\np: - size = large",
+        )
+        .unwrap();
+        let mut report = ValidationReport::default();
+        report_duplicate_names(&rules, &mut report);
+        RuleParser::new(create_test_tags()).validate_rules(&rules, &mut report);
+        let located: Vec<(usize, Option<usize>)> = report
+            .failures
+            .iter()
+            .map(|failure| (failure.location.line, failure.location.column))
+            .collect();
+        assert_eq!(located, vec![(2, None), (3, None), (2, Some(15))]);
+        assert!(matches!(
+            report.into_result(),
+            Err(RulesError::ValidationError(_))
+        ));
+        assert!(ValidationReport::default().into_result().is_ok());
+    }
+
+    #[test]
+    fn test_macros() {
+        let content = "$warm = colour = red | colour = blue\n\
+                       $warm_and_big = $warm & size = large\n\
+                       - $warm_and_big & shape = \"$warm\"\n\
+                       - !$warm & region = $ctx.region";
+        let rules = parse_rules_from_str("a.rules", content).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].line, 3);
+        assert_eq!(
+            rules[0].rule,
+            "- ((colour = red | colour = blue) & size = large) & shape = \"$warm\""
+        );
+        assert_eq!(
+            rules[1].rule,
+            "- !(colour = red | colour = blue) & region = $ctx.region"
+        );
+
+        let parser = RuleParser::new(create_test_tags());
+        let rules =
+            parse_rules_from_str("a.rules", "$warm = colour = red | colour = blue\n- $warm")
+                .unwrap();
+        assert_eq!(parser.parse_rules(&rules).unwrap().len(), 2);
+
+        let rules = parse_rules_from_str("a.rules", "- $big\n$big = size = large").unwrap();
+        assert_eq!(rules[0].rule, "- (size = large)");
+
+        for (content, expected) in [
+            (
+                "$a = $b & size = large\n$b = $a",
+                "a.rules:1: Macro cycle: $a -> $b -> $a",
+            ),
+            ("$a = $a | size = large", "a.rules:1: Macro cycle: $a -> $a"),
+            (
+                "$a = size = large\n$a = size = small",
+                "a.rules:2: Macro '$a' is already defined at line 1",
+            ),
+            (
+                "$today = size = large",
+                "a.rules:1: Macro name '$today' is reserved",
+            ),
+            (
+                "$ctx = size = large",
+                "a.rules:1: Macro name '$ctx' is reserved",
+            ),
+            (
+                "$big size = large",
+                "a.rules:1: Invalid macro: '$big size = large'",
+            ),
+            (
+                "@draft big\n$big = size = large\n- $big",
+                "a.rules:1: Annotation isn't followed",
+            ),
+        ] {
+            let result = parse_rules_from_str("a.rules", content);
+            if let Err(RulesError::RuleParseError(msg)) = result {
+                assert!(msg.starts_with(expected), "{}", msg);
+            } else {
+                panic!("Expected RuleParseError for '{}'", content);
+            }
+        }
+
+        let rules = parse_rules_from_str("a.rules", "- $wram & size = large").unwrap();
+        let result = parser.parse_rules(&rules);
         if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("Empty rule"));
+            assert!(msg.contains("Unknown macro '$wram'"), "{}", msg);
         } else {
-            panic!("Expected RuleParseError");
+            panic!("Expected RuleParseError about an unknown macro");
         }
     }
 
-    // Helper function to create test tags
-    fn create_test_tags() -> HashMap<String, Vec<String>> {
-        let mut tags = HashMap::new();
+    #[test]
+    fn test_tag_references() {
+        let mut tags = create_test_tags();
         tags.insert(
-            "colour".to_string(),
-            vec!["red".to_string(), "blue".to_string(), "green".to_string()],
+            "trim".to_string(),
+            vec!["red".to_string(), "blue".to_string()],
         );
-        tags.insert(
-            "size".to_string(),
-            vec![
-                "small".to_string(),
-                "medium".to_string(),
-                "large".to_string(),
-            ],
+        let parser = RuleParser::new(tags.clone());
+
+        let mut obj = types::Object::new();
+        obj.insert(
+            "colour".to_string(),
+            vec!["red".to_string(), "green".to_string()],
         );
-        tags.insert(
-            "shape".to_string(),
-            vec!["circle".to_string(), "square".to_string()],
+        obj.insert("trim".to_string(), vec!["Red".to_string()]);
+
+        for rule in [
+            "- colour = @trim",
+            "- trim = @colour & size ! @colour",
+            "- colour = @Trim & colour = red",
+        ] {
+            assert!(parser.rule_matches(rule, &obj).unwrap(), "{}", rule);
+        }
+        for rule in ["- colour ! @trim", "- size = @colour"] {
+            assert!(!parser.rule_matches(rule, &obj).unwrap(), "{}", rule);
+        }
+        assert!(
+            parser
+                .is_satisfiable("- colour = @trim & colour = red")
+                .unwrap()
         );
-        tags
+
+        for (rule, expected) in [
+            ("- colour = @finish", "with unknown TagName 'finish'"),
+            ("- size < @trim", "only '=' and '!' take a tag"),
+        ] {
+            let result = parser.validate_rule(rule);
+            if let Err(RulesError::RuleParseError(msg)) = result {
+                assert!(msg.contains(expected), "{}", msg);
+            } else {
+                panic!("Expected RuleParseError for '{}'", rule);
+            }
+        }
+
+        let strict = RuleParser::new(tags).with_missing_tag_policy(MissingTagPolicy::Error);
+        obj.remove("trim");
+        let result = strict.rule_matches("- colour = @trim", &obj);
+        if let Err(RulesError::ObjectParseError(msg)) = result {
+            assert!(msg.contains("missing TagName 'trim'"));
+        } else {
+            panic!("Expected ObjectParseError about the referenced tag");
+        }
     }
 
-    // Tests for check_valid_tags
     #[test]
-    fn test_check_valid_tags_all_valid() {
-        let parser = RuleParser {
-            m_mapped_tags: create_test_tags(),
+    fn test_implication() {
+        let parser = RuleParser::new(create_test_tags());
+        let object = |colour: &str, size: &str| {
+            let mut obj = types::Object::new();
+            obj.insert("colour".to_string(), vec![colour.to_string()]);
+            obj.insert("size".to_string(), vec![size.to_string()]);
+            obj
         };
 
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-            ("&".to_string(), TokenType::LogicalOp, 0),
-            ("size".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("large".to_string(), TokenType::TagValue, 0),
-        ];
+        let rule = "- colour = red -> size = large";
+        assert!(parser.rule_matches(rule, &object("red", "large")).unwrap());
+        assert!(!parser.rule_matches(rule, &object("red", "small")).unwrap());
+        assert!(parser.rule_matches(rule, &object("blue", "small")).unwrap());
+        assert!(
+            parser
+                .is_equivalent(rule, "- !(colour = red) | size = large")
+                .unwrap()
+        );
 
-        let result = parser.check_valid_tags(&tokens);
-        assert!(result.is_ok());
+        // Binds loosest, and groups to the right
+        assert!(
+            parser
+                .is_equivalent(
+                    "- colour = red | colour = blue -> size = large & shape = circle",
+                    "- !(colour = red | colour = blue) | (size = large & shape = circle)",
+                )
+                .unwrap()
+        );
+        assert!(
+            parser
+                .is_equivalent(
+                    "- colour = red -> size = large -> shape = circle",
+                    "- colour = red -> (size = large -> shape = circle)",
+                )
+                .unwrap()
+        );
+
+        let rule = "promo: -[5] colour = red->size = large => reject";
+        assert!(parser.validate_rule(rule).is_ok());
+        assert_eq!(
+            migrate_rule(rule, GrammarVersion::V2).unwrap(),
+            "promo: -[5] colour == \"red\"->size == \"large\" => reject"
+        );
+        assert_eq!(
+            migrate_rule(
+                "- colour == \"red\" -> size == \"large\"",
+                GrammarVersion::V1
+            )
+            .unwrap(),
+            "- colour = red -> size = large"
+        );
+
+        assert!(parser.validate_rule("- colour = red ->").is_err());
+        assert!(parser.validate_rule("- -> size = large").is_err());
+        assert!(
+            parser
+                .validate_rule("- colour = red - size = large")
+                .is_err()
+        );
     }
 
     #[test]
-    fn test_check_valid_tags_invalid_tag_name() {
-        let parser = RuleParser {
-            m_mapped_tags: create_test_tags(),
-        };
-
-        let tokens = vec![
-            ("invalid_tag".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-        ];
+    fn test_operator_precedence() {
+        let mut obj = types::Object::new();
+        obj.insert("colour".to_string(), vec!["blue".to_string()]);
+        obj.insert("shape".to_string(), vec!["circle".to_string()]);
+        let rule = "- colour = red & size = large | shape = circle";
+
+        let parser = RuleParser::new(create_test_tags());
+        assert!(parser.rule_matches(rule, &obj).unwrap());
+
+        let precedence = OperatorPrecedence::default().with("|", 2);
+        assert!(precedence.check().is_ok());
+        let parser = RuleParser::new(create_test_tags()).with_precedence(precedence);
+        assert!(!parser.rule_matches(rule, &obj).unwrap());
+        assert!(
+            parser
+                .is_equivalent(rule, "- colour = red & (size = large | shape = circle)")
+                .unwrap()
+        );
 
-        let result = parser.check_valid_tags(&tokens);
-        assert!(result.is_err());
-        if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("invalid TagName"));
-            assert!(msg.contains("invalid_tag"));
-        } else {
-            panic!("Expected RuleParseError about invalid tag name");
-        }
+        let mut missing = OperatorPrecedence::default();
+        missing.levels.remove("->");
+        assert!(missing.check().is_err());
+        assert!(OperatorPrecedence::default().with("^", 2).check().is_err());
     }
 
     #[test]
-    fn test_check_valid_tags_invalid_tag_value() {
-        let parser = RuleParser {
-            m_mapped_tags: create_test_tags(),
+    fn test_error_location() {
+        let rules = parse_rules_from_str(
+            "a.rules",
+            "- colour = red\npromo_1: - colour = red & & size = large\n- colour = rad",
+        )
+        .unwrap();
+        let parser = RuleParser::new(create_test_tags());
+        let error = |i: usize| {
+            let result = parser.validate_rule(&rules[i].rule);
+            rule_error_at(&rules[i], result.unwrap_err())
         };
 
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("purple".to_string(), TokenType::TagValue, 0),
-        ];
+        let named = error(1);
+        assert!(named.to_string().contains(
+            "a.rules:2:27 (promo_1): 'promo_1: - colour = red & & size = large': Expected"
+        ));
+        let location = named.location().unwrap();
+        assert_eq!(location.file, "a.rules");
+        assert_eq!(location.line, 2);
+        assert_eq!(location.column, Some(27));
+
+        let unlocated = error(2);
+        assert!(unlocated.to_string().contains("a.rules:3: "));
+        assert_eq!(unlocated.location().unwrap().column, None);
+
+        let bare = parser.validate_rule("- colour = (red").unwrap_err();
+        assert_eq!(bare.location(), None);
+        assert_eq!(bare.column(), Some(12));
+        assert_eq!(
+            err::split_column("Unmatched at column 3"),
+            Some(("Unmatched", 3))
+        );
+        assert_eq!(err::split_column("Invalid tag"), None);
+    }
 
-        let result = parser.check_valid_tags(&tokens);
-        assert!(result.is_err());
-        if let Err(RulesError::RuleParseError(msg)) = result {
-            assert!(msg.contains("invalid TagValue"));
-            assert!(msg.contains("purple"));
-        } else {
-            panic!("Expected RuleParseError about invalid tag value");
+    #[test]
+    fn test_error_codes() {
+        let parser = RuleParser::new(create_test_tags());
+        for (rule, code) in [
+            ("- (colour = red", "R001"),
+            ("- colour = red)", "R001"),
+            ("- colour = red & & size = large", "R002"),
+            ("- colour red", "R002"),
+            ("- colour = red &", "R003"),
+            ("- colr = red", "R004"),
+            ("- colour = purple", "R005"),
+            ("promo-1: - colour = red", "R006"),
+            ("- colour = red & $wram", "R009"),
+            ("- colour since(2025)", "R011"),
+            ("- colour < red", "R012"),
+            ("- colour = $region", "R013"),
+            ("-[x] colour = red", "R015"),
+        ] {
+            let error = parser.validate_rule(rule).unwrap_err();
+            assert_eq!(error.code().as_str(), code, "{}: {}", rule, error);
+        }
+
+        for (content, code) in [
+            ("@on-match x\n- colour = red", "R008"),
+            ("@weight 2", "R008"),
+            ("$a = $a", "R009"),
+            ("p: - colour = red\np: - size = large", "R007"),
+        ] {
+            let error = parse_rules_from_str("a.rules", content)
+                .and_then(|rules| check_rule_names(&rules))
+                .unwrap_err();
+            assert_eq!(error.code().as_str(), code, "{}: {}", content, error);
         }
+
+        let diagnostic = RulesError::RuleParseError(
+            "a.rules:2:7 (p): '- colour = (red': Unmatched opening parenthesis at column 12"
+                .to_string(),
+        )
+        .diagnostic();
+        assert_eq!(diagnostic.message, "Unmatched opening parenthesis");
+        assert_eq!(
+            diagnostic.to_string(),
+            "a.rules:2:7: error[R001]: Unmatched opening parenthesis"
+        );
+        let diagnostic = parser
+            .validate_rule("- (colour = red")
+            .unwrap_err()
+            .diagnostic();
+        assert_eq!(diagnostic.span.column, Some(3));
+        assert_eq!(
+            diagnostic.to_string(),
+            "column 3: error[R001]: Unmatched opening parenthesis"
+        );
+        let unknown = RulesError::RuleParseError("Something new".to_string());
+        assert_eq!(unknown.code(), types::DiagnosticCode::InvalidRule);
     }
 
     #[test]
-    fn test_check_valid_tags_with_parentheses() {
-        let parser = RuleParser {
-            m_mapped_tags: create_test_tags(),
-        };
+    fn test_format_rule() {
+        let parser = RuleParser::new(create_test_tags());
+        let format = |rule: &str| parser.format_rule(rule).unwrap();
+
+        for (rule, formatted) in [
+            ("-colour=red&size=large", "- colour = red & size = large"),
+            (
+                "-(size=small|size=large)&colour=red",
+                "- (size = small | size = large) & colour = red",
+            ),
+            (
+                "- ((colour = red)) | (size = small & shape = circle)",
+                "- colour = red | size = small & shape = circle",
+            ),
+            (
+                "- colour = red & (size = small & shape = circle)",
+                "- colour = red & size = small & shape = circle",
+            ),
+            ("- colour = red, blue", "- colour = red | colour = blue"),
+            (
+                "- !(colour = red | size = small)",
+                "- colour ! red & size ! small",
+            ),
+            ("- colour? & !size?", "- colour? & !(size?)"),
+            ("- colour = $ctx.colour", "- colour = $ctx.colour"),
+            ("- colour ~ ^r", "- colour ~ ^r"),
+            ("- colour == \"red\"", "- colour = red"),
+            (
+                "promo_1:-[10]colour=red=>reject",
+                "promo_1: -[10] colour = red => reject",
+            ),
+        ] {
+            assert_eq!(format(rule), formatted, "{}", rule);
+            assert_eq!(format(formatted), formatted, "{}", formatted);
+        }
 
-        let tokens = vec![
-            ("(".to_string(), TokenType::TagName, 1),
-            ("colour".to_string(), TokenType::TagName, 1),
-            ("=".to_string(), TokenType::ComparisonOp, 1),
-            ("blue".to_string(), TokenType::TagValue, 1),
-            (")".to_string(), TokenType::LogicalOp, 1),
-        ];
+        // '|' binding tighter than '&' needs the opposite parentheses
+        let mut precedence = OperatorPrecedence::default();
+        precedence.levels.insert("|".to_string(), 2);
+        let parser = RuleParser::new(create_test_tags()).with_precedence(precedence);
+        assert_eq!(
+            parser
+                .format_rule("- colour = red & size = small | size = large")
+                .unwrap(),
+            "- colour = red & size = small | size = large"
+        );
+        assert_eq!(
+            parser
+                .format_rule("- (colour = red & size = small) | size = large")
+                .unwrap(),
+            "- (colour = red & size = small) | size = large"
+        );
 
-        let result = parser.check_valid_tags(&tokens);
-        assert!(result.is_ok());
+        assert!(parser.format_rule("- colour = purple").is_err());
     }
 
     #[test]
-    fn test_check_valid_tags_multiple_conditions() {
-        let parser = RuleParser {
-            m_mapped_tags: create_test_tags(),
-        };
-
-        let tokens = vec![
-            ("colour".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("red".to_string(), TokenType::TagValue, 0),
-            ("&".to_string(), TokenType::LogicalOp, 0),
-            ("size".to_string(), TokenType::TagName, 0),
-            ("!".to_string(), TokenType::ComparisonOp, 0),
-            ("small".to_string(), TokenType::TagValue, 0),
-            ("|".to_string(), TokenType::LogicalOp, 0),
-            ("shape".to_string(), TokenType::TagName, 0),
-            ("=".to_string(), TokenType::ComparisonOp, 0),
-            ("circle".to_string(), TokenType::TagValue, 0),
-        ];
+    fn test_duplicate_key() {
+        let parser = RuleParser::new(create_test_tags());
+        let key = |rule: &str| parser.duplicate_key(rule).unwrap();
 
-        let result = parser.check_valid_tags(&tokens);
-        assert!(result.is_ok());
+        assert_eq!(key("-colour=red"), "- colour = red");
+        assert_eq!(
+            key("p: -[5](size=small)&colour=red => reject"),
+            "-[5] colour = red & size = small => reject"
+        );
+        assert_eq!(
+            key("- colour = red & (size = small | size = large)"),
+            key("- size = large & colour = red | colour = red & size = small")
+        );
+        assert_ne!(key("- colour = red"), key("-[1] colour = red"));
+        assert_ne!(key("- colour = red"), key("- colour = red => reject"));
+        assert!(parser.duplicate_key("- colour = purple").is_err());
     }
 
-    // Tests for validate_rule
-    // Note: validate_rule expects rules to start with '-' (as they appear in config files)
-    // and normalises them by removing the first character
     #[test]
-    fn test_validate_rule_valid() {
-        let parser = RuleParser {
-            m_mapped_tags: create_test_tags(),
+    fn test_contradiction_and_tautology() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "colour".to_string(),
+            types::TagConstraints {
+                single: true,
+                ..Default::default()
+            },
+        );
+        constraints.insert(
+            "shape".to_string(),
+            types::TagConstraints {
+                required: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags()).with_tag_constraints(constraints);
+        let clauses = |clauses: Option<Vec<Clause>>| {
+            clauses.map(|clauses| clauses.iter().map(Clause::to_string).collect::<Vec<_>>())
         };
+        let contradiction = |rule: &str| clauses(parser.contradiction(rule).unwrap());
+        let tautology = |rule: &str| clauses(parser.tautology(rule).unwrap());
 
-        let valid_rules = vec![
-            "-colour = red",
-            "-colour = red & size = large",
-            "-colour = red | colour = blue",
-            "-(colour = red)",
-            "-(colour = red) & (size = large)",
-            "-((colour = red) | (colour = blue)) & (size = large)",
-            "-colour ! red",
-            "-colour = red & size ! small",
-        ];
+        // Only the comparisons which conflict are named
+        assert_eq!(
+            contradiction("- colour = red & colour = blue & size = small"),
+            Some(vec![
+                "colour = red".to_string(),
+                "colour = blue".to_string()
+            ])
+        );
+        assert_eq!(
+            contradiction("- size = small & (colour = red | size ! small)"),
+            None
+        );
+        assert_eq!(
+            contradiction("- size? & size ! small & size ! medium & size ! large"),
+            Some(vec![
+                "size?".to_string(),
+                "size ! small".to_string(),
+                "size ! medium".to_string(),
+                "size ! large".to_string()
+            ])
+        );
+        assert_eq!(
+            contradiction("- !shape? & size = small"),
+            Some(vec!["!shape?".to_string()])
+        );
+        assert_eq!(contradiction("- size = small & size = large"), None);
 
-        for rule in valid_rules {
-            let result = parser.validate_rule(rule);
-            assert!(result.is_ok(), "Expected rule to be valid: {}", rule);
-        }
+        assert_eq!(
+            tautology("- colour = red | colour ! red | size = small"),
+            Some(vec!["colour = red".to_string(), "colour ! red".to_string()])
+        );
+        assert_eq!(
+            tautology("- size? | !size?"),
+            Some(vec!["size?".to_string(), "!size?".to_string()])
+        );
+        // A required tag always has one of its values, and a single tag only one
+        assert!(tautology("- shape = circle | shape = square").is_some());
+        assert!(tautology("- colour ! red | colour ! blue").is_some());
+        assert_eq!(tautology("- size ! small | size ! large"), None);
+        assert_eq!(
+            tautology("- size = small | size = medium | size = large"),
+            None
+        );
+        assert_eq!(tautology("- colour = red"), None);
+
+        let loaded = |rule: &str| LoadedRule {
+            file: "a.rules".to_string(),
+            line: 2,
+            rule: rule.to_string(),
+            annotations: Vec::new(),
+        };
+        let warning = parser
+            .match_warning(&loaded("- colour = red & colour = blue"))
+            .unwrap();
+        assert_eq!(
+            warning.to_string(),
+            "a.rules:2: warning[R016]: Rule can never match, as colour = red and colour = blue \
+             can't all hold"
+        );
+        let warning = parser
+            .match_warning(&loaded("- size = small | size ! small"))
+            .unwrap();
+        assert_eq!(warning.code, types::DiagnosticCode::AlwaysMatches);
+        assert_eq!(
+            warning.message,
+            "Rule always matches, as size = small or size ! small holds for every object"
+        );
+        assert!(parser.match_warning(&loaded("- colour = red")).is_none());
     }
 
     #[test]
-    fn test_validate_rule_invalid() {
-        let parser = RuleParser {
-            m_mapped_tags: create_test_tags(),
+    fn test_rules_equivalent() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "size".to_string(),
+            types::TagConstraints {
+                ordered: true,
+                single: true,
+                ..Default::default()
+            },
+        );
+        constraints.insert(
+            "shape".to_string(),
+            types::TagConstraints {
+                required: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags()).with_tag_constraints(constraints);
+        let equivalent = |first: &str, second: &str| {
+            let result = parser.rules_equivalent(first, second).unwrap();
+            assert_eq!(result, parser.rules_equivalent(second, first).unwrap());
+            result
         };
 
-        let invalid_rules = vec![
-            // Missing dash at start
-            "colour = red",
-            "colour = red & size = large",
-            "(colour = red)",
-            // Multiple dashes
-            "-colour = -red",
-            "-colour - red",
-            "--colour = red",
-            "-colour = red & -size = large",
-            // Missing operands (remember first char is skipped)
-            "-colour =",
-            "-= red",
-            "-colour",
-            // Double operators
-            "-colour = = red",
-            "-colour red",
-            // Ending with operator
-            "-colour = red &",
-            "-colour = red |",
-            // Starting with operator (after '-' is removed)
-            "-& colour = red",
-            "-| colour = red",
-            // Mismatched parentheses
-            "-(colour = red",
-            "-colour = red)",
-            "-((colour = red)",
-            // Empty or only operators (first char removed)
-            "--",
-            "-=",
-            "-&",
-            "-()",
-            // Invalid sequences
-            "-colour & size = large",
-            "-colour = red blue",
-        ];
+        for (first, second) in [
+            (
+                "- colour = red",
+                "- colour = red & size = small | colour = red & size ! small",
+            ),
+            (
+                "- !(colour ! red | size = small) | colour = red & size = small",
+                "- colour = red",
+            ),
+            ("- size > small", "- size = medium, large"),
+            (
+                "- (size = small, medium) & colour = red",
+                "- colour = red & size < large",
+            ),
+            ("- shape?", "- shape = circle | shape = square"),
+            ("- colour = red | colour ! red", "- shape?"),
+            ("- size = small & size = large", "- !shape?"),
+        ] {
+            assert!(equivalent(first, second), "{} <=> {}", first, second);
+        }
 
-        for rule in invalid_rules {
-            let result = parser.validate_rule(rule);
-            assert!(result.is_err(), "Expected rule to be invalid: {}", rule);
+        for (first, second) in [
+            ("- colour = red", "- colour = red & size = small"),
+            ("- colour = red | colour = blue", "- colour ! green"),
+            ("- size > small", "- size ! small"),
+        ] {
+            assert!(!equivalent(first, second), "{} <=> {}", first, second);
         }
+
+        assert!(
+            parser
+                .rules_equivalent("- colour = purple", "- colour = red")
+                .is_err()
+        );
     }
 
     #[test]
-    fn test_validate_rule_invalid_tag_names() {
-        let parser = RuleParser {
-            m_mapped_tags: create_test_tags(),
-        };
+    fn test_rule_to_cnf() {
+        let mut constraints = HashMap::new();
+        constraints.insert(
+            "shape".to_string(),
+            types::TagConstraints {
+                required: true,
+                ..Default::default()
+            },
+        );
+        let parser = RuleParser::new(create_test_tags()).with_tag_constraints(constraints);
+        let cnf = |rule: &str| parser.rule_to_cnf(rule).unwrap().to_string();
 
-        let invalid_rules = vec!["-invalid_tag = red", "-colour = red & unknown = value"];
+        assert_eq!(
+            cnf("- colour = red | size = large & shape = circle"),
+            "(colour = red | shape = circle) & (colour = red | size = large)"
+        );
+        assert_eq!(
+            cnf("- (Colour = RED, blue) & size = small"),
+            "(colour = blue | colour = red) & size = small"
+        );
+        assert_eq!(
+            cnf("- !(colour = red & size = small)"),
+            "(colour ! red | size ! small)"
+        );
 
-        for rule in invalid_rules {
-            let result = parser.validate_rule(rule);
-            assert!(
-                result.is_err(),
-                "Expected rule to be invalid due to unknown tag: {}",
-                rule
-            );
-            if let Err(RulesError::RuleParseError(msg)) = result {
-                assert!(msg.contains("invalid TagName") || msg.contains("invalid TagValue"));
+        // "a & (a | b)" is just "a", and clauses every object meets are left out
+        assert_eq!(
+            cnf("- colour = red & (colour = red | size = small)"),
+            "colour = red"
+        );
+        assert_eq!(
+            cnf("- (shape = circle | shape = square) & colour = red"),
+            "colour = red"
+        );
+
+        let always = parser.rule_to_cnf("- colour = red | colour ! red").unwrap();
+        assert!(always.clauses.is_empty());
+
+        let clauses = parser
+            .rule_to_cnf("- colour = red | size = small")
+            .unwrap()
+            .clauses;
+        assert_eq!(clauses.len(), 1);
+        assert_eq!(clauses[0][1].comparison_op, ComparisonOp::ISEQ);
+        assert_eq!(clauses[0][1].tag_value, "small");
+
+        assert!(parser.rule_to_cnf("- colour = purple").is_err());
+    }
+
+    #[test]
+    fn test_rule_limits() {
+        let parser = RuleParser::new(create_test_tags());
+
+        // Rejected before it's read into a syntax tree, whose checks would recurse per group
+        let nested = format!("- {}colour = red{}", "(".repeat(1000), ")".repeat(1000));
+        match parser.validate_rule(&nested) {
+            Err(e @ RulesError::RuleLimitError(_)) => {
+                assert!(e.to_string().contains("deeper than the limit of 64"));
+                assert_eq!(e.column(), Some(67));
+                assert_eq!(e.code(), DiagnosticCode::RuleTooLarge);
             }
+            other => panic!("Expected RuleLimitError, got {:?}", other),
         }
+
+        // Long flat rules fit the default limits
+        let flat = vec!["colour = red"; 500].join(" | ");
+        assert!(parser.validate_rule(&format!("- {}", flat)).is_ok());
+
+        let parser = parser.with_rule_limits(RuleLimits {
+            max_depth: 2,
+            max_length: 7,
+        });
+        assert!(parser.validate_rule("- ((colour = red))").is_ok());
+        assert!(
+            parser
+                .validate_rule("- colour = red | colour = blue")
+                .is_ok()
+        );
+
+        let error = parser.validate_rule("- (((colour = red)))").unwrap_err();
+        assert!(matches!(error, RulesError::RuleLimitError(_)));
+        assert_eq!(error.column(), Some(5));
+
+        let error = parser
+            .validate_rule("- colour = red | colour = blue | colour = green")
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Rule is longer than the limit of 7 tokens")
+        );
+        assert_eq!(error.column(), Some(32));
+
+        // Ranges are counted once expanded
+        assert!(parser.validate_rule("- size = 1..3").is_err());
     }
 
     #[test]
-    fn test_validate_rule_invalid_tag_values() {
-        let parser = RuleParser {
-            m_mapped_tags: create_test_tags(),
-        };
+    fn test_read_rule_without_recursion() {
+        let parser = RuleParser::new(HashMap::new()).with_rule_limits(RuleLimits {
+            max_depth: usize::MAX,
+            max_length: usize::MAX,
+        });
+
+        // Operators at the same level still group to the right
+        let flat = format!("- {}", vec!["colour = red"; 5000].join(" | "));
+        let (root, tokens) = parser.read_rule(&flat).unwrap();
+        assert_eq!(tokens.len(), 5000 * 4 - 1);
+        let mut node = &root;
+        let mut chain = 1;
+        while let Some(right) = &node.right {
+            assert_eq!(node.token, Token::Or);
+            node = right;
+            chain += 1;
+        }
+        assert_eq!(chain, 5000);
 
-        let invalid_rules = vec!["-colour = purple", "-colour = red & size = huge"];
+        let nested = format!(
+            "- {}colour = red{} & size = small",
+            "!(".repeat(1000),
+            ")".repeat(1000)
+        );
+        let (root, tokens) = parser.read_rule(&nested).unwrap();
+        assert_eq!(root.token, Token::And);
+        assert_eq!(tokens.iter().map(|(_, _, depth)| *depth).max(), Some(1000));
+        let mut node = root.left.as_deref().unwrap();
+        let mut negations = 0;
+        while node.token == Token::Not {
+            node = node.left.as_deref().unwrap();
+            negations += 1;
+        }
+        assert_eq!(negations, 1000);
+
+        assert!(
+            parser
+                .read_rule(&format!("- {}colour = red", "(".repeat(1000)))
+                .is_err_and(|e| e
+                    .to_string()
+                    .contains("Unmatched opening parenthesis at column 1002"))
+        );
+    }
 
-        for rule in invalid_rules {
-            let result = parser.validate_rule(rule);
-            assert!(
-                result.is_err(),
-                "Expected rule to be invalid due to unknown value: {}",
-                rule
-            );
-            if let Err(RulesError::RuleParseError(msg)) = result {
-                assert!(msg.contains("invalid TagValue"));
-            }
+    #[test]
+    fn test_long_rule_without_recursion() {
+        let parser = RuleParser::new(create_test_tags()).with_rule_limits(RuleLimits {
+            max_depth: 64,
+            max_length: usize::MAX,
+        });
+        let obj = types::Object::from([("colour".to_string(), vec!["blue".to_string()])]);
+
+        // Each pass loops down a chain of one operator, however long
+        let any = format!("- {}", vec!["colour = red"; 30000].join(" | "));
+        assert!(parser.validate_rule(&any).is_ok());
+        let ast = parser.string_to_rule(&any).unwrap();
+        assert_eq!(ast.root_node.chain().len(), 30000);
+        assert_eq!(parser.rule_truth(&any, &obj).unwrap(), Truth::False);
+        assert!(!parser.rule_matches(&any, &obj).unwrap());
+        assert_eq!(parser.canonicalize(&any).unwrap(), "- colour = red");
+        assert!(parser.is_satisfiable(&any).unwrap());
+        assert_eq!(parser.format_rule(&any).unwrap().len(), any.len());
+        assert_eq!(ast.to_canonical_string(), any);
+        assert_eq!(ast.to_dot().matches(" -> ").count(), 59998);
+        assert_eq!(ast.root_node.to_string().lines().count(), 59999);
+        #[cfg(feature = "serde")]
+        {
+            // Each chain is one list, so formats with a nesting limit read it back
+            let serialized = serde_yaml::to_string(&ast).unwrap();
+            let reloaded: AstRule = serde_yaml::from_str(&serialized).unwrap();
+            assert!(reloaded == ast);
         }
+        drop(ast);
+
+        let all = format!(
+            "- !({})",
+            vec!["colour = red & size = small"; 15000].join(" & ")
+        );
+        assert!(parser.validate_rule(&all).is_ok());
+        assert_eq!(parser.rule_truth(&all, &obj).unwrap(), Truth::True);
+        assert_eq!(
+            parser.canonicalize(&all).unwrap(),
+            "- colour ! red | size ! small"
+        );
+        let contradiction = format!("{} & size = small & size ! small", any.replace('|', "&"));
+        assert!(!parser.is_satisfiable(&contradiction).unwrap());
     }
 }
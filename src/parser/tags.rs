@@ -1,17 +1,302 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 // Parser for .tags files
 use crate::err::RulesError;
-use crate::types::Tag;
+use crate::types::{
+    CaseFolding, Diagnostic, DiagnosticCode, DuplicateTagPolicy, ExclusiveGroup, ParsedTags, Tag,
+    TagConstraints, TagLimits, TagLoadOptions,
+};
 use crate::utils::file;
-use crate::utils::string::{StringUtils, normalise};
+use crate::utils::string::{self, StringUtils, normalise};
+
+use regex::{Regex, RegexBuilder};
+
+// Prefix of a line declaring mutually exclusive values or tags
+const EXCLUSIVE_PREFIX: &str = "@exclusive";
+
+// Parse "@exclusive colour: red, green" or "@exclusive discount, clearance". Members are kept
+// as written, and folded with the tags when loaded.
+pub fn get_exclusive_group_from_line(line: &str) -> Result<Option<ExclusiveGroup>, RulesError> {
+    let Some(declaration) = line.trim().strip_prefix(EXCLUSIVE_PREFIX) else {
+        return Ok(None);
+    };
+
+    let split_members = |members: &str| -> Vec<String> {
+        members
+            .split(',')
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty())
+            .collect()
+    };
+
+    let group = match declaration.split_once(':') {
+        Some((tag_name, values)) => ExclusiveGroup::Values {
+            tag_name: tag_name.trim().to_string(),
+            values: split_members(values),
+        },
+        None => ExclusiveGroup::Tags {
+            tag_names: split_members(declaration),
+        },
+    };
+
+    let (members, names_ok) = match &group {
+        ExclusiveGroup::Values { tag_name, values } => {
+            (values, tag_name.split_whitespace().count() == 1)
+        }
+        ExclusiveGroup::Tags { tag_names } => (tag_names, true),
+    };
+
+    if !names_ok || members.len() < 2 || members.iter().any(|m| m.contains(' ')) {
+        return Err(RulesError::TagParseError(format!(
+            "Errors parsing line: '{}': Exclusive groups need one tag name and at least two values, or at least two tag names",
+            line
+        )));
+    }
+
+    Ok(Some(group))
+}
+
+// Group with its members folded per `case_folding` -- e.g. "Colour: Red, Green" gives
+// "colour: red, green" when lowercasing
+fn fold_exclusive_group(group: ExclusiveGroup, case_folding: CaseFolding) -> ExclusiveGroup {
+    let fold_all = |members: Vec<String>| members.iter().map(|m| case_folding.fold(m)).collect();
+
+    match group {
+        ExclusiveGroup::Values { tag_name, values } => ExclusiveGroup::Values {
+            tag_name: case_folding.fold(&tag_name),
+            values: fold_all(values),
+        },
+        ExclusiveGroup::Tags { tag_names } => ExclusiveGroup::Tags {
+            tag_names: fold_all(tag_names),
+        },
+    }
+}
+
+// Every member of an exclusive group must be a parsed tag or tag value. The group and tags
+// are expected folded alike.
+fn check_exclusive_group(group: &ExclusiveGroup, tags: &[Tag]) -> Result<(), RulesError> {
+    let find_tag = |name: &str| tags.iter().find(|t| t.name == name);
+
+    match group {
+        ExclusiveGroup::Values { tag_name, values } => {
+            let tag = find_tag(tag_name).ok_or_else(|| {
+                RulesError::TagParseError(format!(
+                    "Exclusive group '{}' references unknown tag '{}'",
+                    group, tag_name
+                ))
+            })?;
+
+            for value in values {
+                if !tag.values.contains(value) {
+                    return Err(RulesError::TagParseError(format!(
+                        "Exclusive group '{}' references unknown value '{}' of tag '{}'",
+                        group, value, tag_name
+                    )));
+                }
+            }
+        }
+        ExclusiveGroup::Tags { tag_names } => {
+            for tag_name in tag_names {
+                if find_tag(tag_name).is_none() {
+                    return Err(RulesError::TagParseError(format!(
+                        "Exclusive group '{}' references unknown tag '{}'",
+                        group, tag_name
+                    )));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Attributes which can follow a tag name -- e.g. "- vendor (open): acme, globex"
+const TAG_ATTRIBUTES: &[&str] = &["open", "closed", "required", "flag", "single"];
+
+// Attributes which take a whole number -- e.g. "- colour (max_values=20): red, blue"
+const LIMIT_ATTRIBUTES: &[&str] = &["max_values", "max_length"];
+
+// Value of a limit attribute -- e.g. "max_values" in ["open", "max_values=20"] gives 20
+fn get_limit_from_attributes(attributes: &[String], key: &str) -> Option<usize> {
+    attributes.iter().find_map(|a| {
+        let (name, value) = a.split_once('=')?;
+        (name == key).then(|| value.parse().ok())?
+    })
+}
+
+// Values of a boolean flag tag -- e.g. "- is_fragile (flag)" or "- is_fragile: true, false"
+const FLAG_VALUES: [&str; 2] = ["true", "false"];
+
+// Split "- vendor (open): acme" into "- vendor: acme" and ["open"]. Flag tags may leave out
+// the values -- e.g. "- is_fragile (flag)" gives "- is_fragile:" and ["flag"]
+fn split_attributes(line: &str) -> Result<(String, Vec<String>), RulesError> {
+    let (name, values) = match line.split_once(':') {
+        Some((name, values)) => (name, Some(values)),
+        None => (line.trim_end(), None),
+    };
+
+    let Some(open_idx) = name.find('(') else {
+        return Ok((line.to_string(), Vec::new()));
+    };
+
+    let close_idx = match name.rfind(')') {
+        Some(idx) if idx > open_idx && name[idx + 1..].trim().is_empty() => idx,
+        _ => {
+            return Err(RulesError::TagParseError(format!(
+                "Errors parsing line: '{}': Tag attributes must be closed with ')' before ':'",
+                line
+            )));
+        }
+    };
+
+    // Normalise spacing around '=' -- e.g. "max_values = 20" gives "max_values=20"
+    let attributes: Vec<String> = name[open_idx + 1..close_idx]
+        .split(',')
+        .map(|a| {
+            let a = a.trim().to_lowercase();
+            match a.split_once('=') {
+                Some((key, value)) => format!("{}={}", key.trim(), value.trim()),
+                None => a,
+            }
+        })
+        .collect();
+
+    for attribute in &attributes {
+        match attribute.split_once('=') {
+            Some((key, value)) if LIMIT_ATTRIBUTES.contains(&key) => {
+                if value.parse::<usize>().is_err() {
+                    return Err(RulesError::TagParseError(format!(
+                        "Errors parsing line: '{}': Tag attribute '{}' needs a whole number -- e.g. {}=10",
+                        line, key, key
+                    )));
+                }
+            }
+            None if TAG_ATTRIBUTES.contains(&attribute.as_str()) => {}
+            _ => {
+                return Err(RulesError::TagParseError(format!(
+                    "Errors parsing line: '{}': Unknown tag attribute '{}'",
+                    line, attribute
+                )));
+            }
+        }
+    }
+
+    if attributes.iter().any(|a| a == "open") && attributes.iter().any(|a| a == "closed") {
+        return Err(RulesError::TagParseError(format!(
+            "Errors parsing line: '{}': Tag cannot be both open and closed",
+            line
+        )));
+    }
+
+    let is_flag_tag = attributes.iter().any(|a| a == "flag");
+
+    if is_flag_tag && attributes.iter().any(|a| a == "open") {
+        return Err(RulesError::TagParseError(format!(
+            "Errors parsing line: '{}': Flag tags cannot be open",
+            line
+        )));
+    }
+
+    let values = match values {
+        Some(values) => values,
+        None if is_flag_tag => "",
+        None => {
+            return Err(RulesError::TagParseError(format!(
+                "Errors parsing line: '{}': Tag must contain a ':' separator",
+                line
+            )));
+        }
+    };
+
+    // Flag tags can only restate their own values
+    if is_flag_tag
+        && values.split(',').any(|v| {
+            let v = v.trim();
+            !v.is_empty() && !FLAG_VALUES.iter().any(|f| f.eq_ignore_ascii_case(v))
+        })
+    {
+        return Err(RulesError::TagParseError(format!(
+            "Errors parsing line: '{}': Flag tags can only have the values true and false",
+            line
+        )));
+    }
+
+    Ok((
+        format!("{}:{}", name[..open_idx].trim_end(), values),
+        attributes,
+    ))
+}
+
+// Pattern source of a pattern-constrained tag -- e.g. "- sku: /^[A-Z]{2}\d{4}$/" gives "^[A-Z]{2}\d{4}$"
+fn get_pattern_from_tag(line: &str) -> Option<&str> {
+    let (_, values) = line.split_once(':')?;
+    let values = values.trim();
+
+    if values.len() >= 2 && values.starts_with('/') && values.ends_with('/') {
+        Some(&values[1..values.len() - 1])
+    } else {
+        None
+    }
+}
+
+// Separator between the values of an ordered tag, lowest rank first
+const ORDER_SEPARATOR: char = '<';
+
+// Rewrite "- size: small < medium < large" as "- size: small, medium, large", noting it's ordered
+fn split_ordered(line: &str) -> Result<(String, bool), RulesError> {
+    if get_pattern_from_tag(line).is_some() {
+        return Ok((line.to_string(), false));
+    }
+
+    let Some((name, values)) = line.split_once(':') else {
+        return Ok((line.to_string(), false));
+    };
+
+    // Quoted values may hold '<' or ','
+    let ranked = string::split_outside_quotes(values, ORDER_SEPARATOR);
+    if ranked.len() < 2 {
+        return Ok((line.to_string(), false));
+    }
+
+    if ranked
+        .iter()
+        .any(|value| string::split_outside_quotes(value, ',').len() > 1)
+    {
+        return Err(RulesError::TagParseError(format!(
+            "Errors parsing line: '{}': Ordered tag values must all be separated by '<'",
+            line
+        )));
+    }
+
+    Ok((format!("{}:{}", name, ranked.join(",")), true))
+}
+
+// Patterns are case-insensitive, like the rest of the config files
+fn compile_pattern(pattern: &str) -> Result<Regex, RulesError> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| {
+            RulesError::TagParseError(format!("Invalid tag pattern '/{}/': {}", pattern, e))
+        })
+}
 
 pub fn validate_tag(line: &str) -> Result<(), RulesError> {
     if file::line_blank_or_comment(line) {
         return Ok(());
     }
 
-    let parts: Vec<&str> = line.split(":").collect();
+    let (line, _attributes) = split_attributes(line)?;
+    let line = line.as_str();
+
+    if let Some(pattern) = get_pattern_from_tag(line) {
+        return validate_pattern_tag(line, pattern);
+    }
+
+    let (line, _ordered) = split_ordered(line)?;
+    let line = line.as_str();
+
+    let parts: Vec<&str> = string::split_outside_quotes(line, ':');
     let mut errors: HashSet<&str> = HashSet::new();
 
     // Check parts length BEFORE accessing
@@ -40,10 +325,15 @@ pub fn validate_tag(line: &str) -> Result<(), RulesError> {
         }
     }
 
-    for value in values.split(",") {
-        // Contains space and it isn't trailing or leading
-        if value.trim().contains(" ") {
-            errors.insert("Tag values cannot contain spaces");
+    for value in string::split_outside_quotes(values, ',') {
+        // Contains space and it isn't trailing or leading, or quoted -- e.g. "dark red"
+        let value = value.trim();
+        let quoted = string::unquote(value) != value;
+        if value.contains(" ") && !quoted {
+            errors.insert("Tag values cannot contain spaces unless quoted");
+        }
+        if value.contains(string::VALUE_QUOTE) && !quoted {
+            errors.insert("Tag values can only be quoted as a whole");
         }
     }
 
@@ -61,43 +351,399 @@ pub fn validate_tag(line: &str) -> Result<(), RulesError> {
     Ok(())
 }
 
-fn get_name_from_tag(parts: &Vec<&str>) -> Result<String, RulesError> {
+// Pattern tags only contain a name and a pattern, which may itself contain ':' or ','
+fn validate_pattern_tag(line: &str, pattern: &str) -> Result<(), RulesError> {
+    let name = line.split(':').next().unwrap_or_default().trim();
+
+    if name.at(0) != Some('-') {
+        return Err(RulesError::TagParseError(format!(
+            "Errors parsing line: '{}': Tag must begin with '-'",
+            line
+        )));
+    }
+
+    let name_no_dash: String = name.chars().skip(1).collect();
+    if name_no_dash.split_whitespace().count() != 1 {
+        return Err(RulesError::TagParseError(format!(
+            "Errors parsing line: '{}': Tag name cannot contain spaces",
+            line
+        )));
+    }
+
+    if pattern.is_empty() {
+        return Err(RulesError::TagParseError(format!(
+            "Errors parsing line: '{}': Tag pattern cannot be empty",
+            line
+        )));
+    }
+
+    compile_pattern(pattern)?;
+
+    Ok(())
+}
+
+fn get_name_from_tag(parts: &[&str]) -> Result<String, RulesError> {
     normalise(parts[0])
 }
 
-fn get_values_from_tag(parts: &Vec<&str>) -> Vec<String> {
-    parts[1].split(',').map(|v| v.trim().to_string()).collect()
+fn get_values_from_tag(parts: &[&str]) -> Vec<String> {
+    string::split_outside_quotes(parts[1], ',')
+        .into_iter()
+        .map(|v| string::unquote(v.trim()))
+        .collect()
 }
 
+// Pattern-constrained tags have no enumerated values
 pub fn get_name_and_values_from_tag(line: &str) -> Result<(String, Vec<String>), RulesError> {
     validate_tag(line)?;
-    let parts: Vec<&str> = line.trim().split(':').collect();
+    let (line, attributes) = split_attributes(line)?;
+    let (line, _ordered) = split_ordered(&line)?;
+    let line = line.as_str();
+    let parts: Vec<&str> = string::split_outside_quotes(line.trim(), ':');
 
     let name: String = get_name_from_tag(&parts)?;
+    if get_pattern_from_tag(line).is_some() {
+        return Ok((name, Vec::new()));
+    }
+
+    if attributes.iter().any(|a| a == "flag") {
+        return Ok((name, FLAG_VALUES.iter().map(|f| f.to_string()).collect()));
+    }
+
     let values: Vec<String> = get_values_from_tag(&parts);
     Ok((name, values))
 }
 
-pub fn parse_tags() -> Result<Vec<Tag>, RulesError> {
-    let mut tags: Vec<Tag> = Vec::new();
-    let all_files: Vec<String> = file::read_files_in_dir("config/*.tags")?;
+pub fn get_tag_from_line(line: &str) -> Result<Tag, RulesError> {
+    let (name, values) = get_name_and_values_from_tag(line)?;
+    let (line_no_attributes, attributes) = split_attributes(line)?;
+    let (_, ordered) = split_ordered(&line_no_attributes)?;
+
+    if ordered && attributes.iter().any(|a| a == "open") {
+        return Err(RulesError::TagParseError(format!(
+            "Errors parsing line: '{}': Ordered tags cannot be open, every value needs a rank",
+            line
+        )));
+    }
+
+    let pattern = match get_pattern_from_tag(line) {
+        Some(pattern) => Some(compile_pattern(pattern)?),
+        None => None,
+    };
+
+    let tag = Tag {
+        name,
+        values,
+        constraints: TagConstraints {
+            pattern,
+            open: attributes.iter().any(|a| a == "open"),
+            required: attributes.iter().any(|a| a == "required"),
+            flag: attributes.iter().any(|a| a == "flag"),
+            single: attributes.iter().any(|a| a == "single"),
+            ordered,
+            max_values: get_limit_from_attributes(&attributes, "max_values"),
+            max_length: get_limit_from_attributes(&attributes, "max_length"),
+        },
+    };
+
+    check_tag_limits(&tag).map_err(|e| match e {
+        RulesError::TagParseError(msg) => {
+            RulesError::TagParseError(format!("Errors parsing line: '{}': {}", line, msg))
+        }
+        other => other,
+    })?;
+
+    Ok(tag)
+}
+
+// A tag's values must fit its own max_values and max_length attributes
+pub fn check_tag_limits(tag: &Tag) -> Result<(), RulesError> {
+    if let Some(max_values) = tag.constraints.max_values
+        && tag.values.len() > max_values
+    {
+        return Err(RulesError::TagParseError(format!(
+            "Tag '{}' has {} values, more than its limit of {}",
+            tag.name,
+            tag.values.len(),
+            max_values
+        )));
+    }
+
+    if let Some(max_length) = tag.constraints.max_length
+        && let Some(value) = tag.values.iter().find(|v| v.chars().count() > max_length)
+    {
+        return Err(RulesError::TagParseError(format!(
+            "Tag '{}' value '{}' is longer than its limit of {} characters",
+            tag.name, value, max_length
+        )));
+    }
+
+    Ok(())
+}
+
+// Total tags and values must fit the global limits -- e.g. at most 1000 tags
+pub fn check_tag_budget(
+    limits: &TagLimits,
+    tag_count: usize,
+    value_count: usize,
+) -> Result<(), RulesError> {
+    if let Some(max_tags) = limits.max_tags
+        && tag_count > max_tags
+    {
+        return Err(RulesError::TagParseError(format!(
+            "Tags exceed the limit of {} tags",
+            max_tags
+        )));
+    }
+
+    if let Some(max_values) = limits.max_values
+        && value_count > max_values
+    {
+        return Err(RulesError::TagParseError(format!(
+            "Tags exceed the limit of {} values in total",
+            max_values
+        )));
+    }
+
+    Ok(())
+}
+
+// Tag line with normalised spacing and duplicate values removed -- e.g.
+// "-colour(OPEN) :red,blue , red" gives "- colour (open): red, blue"
+pub fn format_tag_line(line: &str) -> Result<String, RulesError> {
+    let tag = get_tag_from_line(line)?;
+    let (_, attributes) = split_attributes(line)?;
+
+    let mut unique_attributes: Vec<String> = Vec::new();
+    for attribute in attributes {
+        if !unique_attributes.contains(&attribute) {
+            unique_attributes.push(attribute);
+        }
+    }
+
+    let name = if unique_attributes.is_empty() {
+        format!("- {}", tag.name)
+    } else {
+        format!("- {} ({})", tag.name, unique_attributes.join(", "))
+    };
+
+    if let Some(pattern) = get_pattern_from_tag(line) {
+        return Ok(format!("{}: /{}/", name, pattern));
+    }
+
+    if unique_attributes.iter().any(|a| a == "flag") {
+        return Ok(name);
+    }
+
+    let mut values: Vec<String> = Vec::new();
+    for value in tag.values {
+        if !value.is_empty() && !values.iter().any(|v| v.eq_ignore_ascii_case(&value)) {
+            values.push(value);
+        }
+    }
+
+    let values: Vec<String> = values.iter().map(|v| string::quote_if_needed(v)).collect();
+    let separator = if tag.constraints.ordered { " < " } else { ", " };
+    Ok(format!("{}: {}", name, values.join(separator)))
+}
+
+// Contents of a .tags file with tags sorted by name and each line formatted. Comments
+// directly above a declaration move with it; comments before the first blank line stay on top.
+pub fn format_tags(content: &str) -> Result<String, RulesError> {
+    let mut header: Vec<String> = Vec::new();
+    // Comments waiting for the declaration they sit above
+    let mut pending: Vec<String> = Vec::new();
+    let mut tag_blocks: Vec<(String, Vec<String>)> = Vec::new();
+    let mut exclusive_lines: Vec<String> = Vec::new();
+    let mut seen_declaration = false;
+
+    for line in content.lines() {
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() {
+            if !seen_declaration {
+                header.append(&mut pending);
+            }
+            continue;
+        }
+
+        if trimmed.starts_with('#') {
+            pending.push(trimmed.to_string());
+            continue;
+        }
 
-    for file in all_files.iter() {
-        for line in file.lines() {
+        seen_declaration = true;
+        let mut block = std::mem::take(&mut pending);
+
+        if let Some(group) = get_exclusive_group_from_line(line)? {
+            block.push(format!("{} {}", EXCLUSIVE_PREFIX, group));
+            exclusive_lines.extend(block);
+        } else {
+            let name = get_tag_from_line(line)?.name.to_lowercase();
+            block.push(format_tag_line(line)?);
+            tag_blocks.push((name, block));
+        }
+    }
+
+    // Stable, so duplicate tags keep their order
+    tag_blocks.sort_by(|a, b| a.0.cmp(&b.0));
+    let tag_lines: Vec<String> = tag_blocks.into_iter().flat_map(|(_, b)| b).collect();
+
+    let sections: Vec<String> = [header, tag_lines, exclusive_lines, pending]
+        .into_iter()
+        .filter(|section| !section.is_empty())
+        .map(|section| section.join("\n"))
+        .collect();
+
+    Ok(sections.join("\n\n"))
+}
+
+// Parse every .tags file matching `pattern`, resolving tags defined more than once per the
+// duplicate policy. Tag names and values are folded per the case-folding policy, so "Colour"
+// and "colour" are the same tag unless case is preserved. Loading stops as soon as the tags
+// exceed the global limits.
+pub fn parse_tags_with_options(
+    pattern: &str,
+    options: TagLoadOptions,
+) -> Result<ParsedTags, RulesError> {
+    parse_tags_from_sources(file::read_files_in_dir_with_paths(pattern)?, options)
+}
+
+// Parse tags from in-memory sources of path and content, as parse_tags_with_options does for
+// files -- e.g. [("my_tags.tags", "- colour: red")]
+pub fn parse_tags_from_sources(
+    sources: Vec<(String, String)>,
+    options: TagLoadOptions,
+) -> Result<ParsedTags, RulesError> {
+    let case_folding = options.case_folding;
+    let mut parsed = ParsedTags::default();
+    let mut value_count: usize = 0;
+    // Folded tag name to its index in parsed.tags and where it was defined
+    let mut seen: HashMap<String, (usize, String)> = HashMap::new();
+
+    for (path, content) in sources {
+        for (i, line) in content.lines().enumerate() {
             if file::line_blank_or_comment(line) {
                 continue;
             }
 
-            let raw_tag = get_name_and_values_from_tag(line)?;
-            let tag = Tag {
-                name: raw_tag.0,
-                values: raw_tag.1,
+            if let Some(group) = get_exclusive_group_from_line(line)? {
+                parsed
+                    .exclusive_groups
+                    .push(fold_exclusive_group(group, case_folding));
+                continue;
+            }
+
+            let mut tag = get_tag_from_line(line)?;
+            tag.name = case_folding.fold(&tag.name);
+            tag.values = tag.values.iter().map(|v| case_folding.fold(v)).collect();
+
+            let mut listed: HashSet<&str> = HashSet::new();
+            for value in &tag.values {
+                if !listed.insert(value) {
+                    parsed.diagnostics.push(Diagnostic::warning(
+                        DiagnosticCode::DuplicateValue,
+                        &path,
+                        i + 1,
+                        format!("Tag '{}' lists value '{}' more than once", tag.name, value),
+                    ));
+                }
+            }
+
+            let location = format!("{}:{}", path, i + 1);
+            let key = tag.name.clone();
+
+            let Some((idx, first_location)) = seen.get(&key).cloned() else {
+                seen.insert(key, (parsed.tags.len(), location.clone()));
+                value_count += tag.values.len();
+                parsed.tags.push(tag);
+
+                check_tag_budget(&options.limits, parsed.tags.len(), value_count)
+                    .map_err(|e| add_location(e, &location))?;
+                continue;
             };
-            tags.push(tag);
+
+            let previous_count = parsed.tags[idx].values.len();
+
+            match options.duplicate_tags {
+                DuplicateTagPolicy::Error => {
+                    return Err(RulesError::TagParseError(format!(
+                        "Tag '{}' is defined more than once: {} and {}",
+                        tag.name, first_location, location
+                    )));
+                }
+                DuplicateTagPolicy::Merge => {
+                    merge_tag(&mut parsed.tags[idx], tag);
+                    parsed.diagnostics.push(Diagnostic::warning(
+                        DiagnosticCode::DuplicateTag,
+                        &path,
+                        i + 1,
+                        format!("Tag '{}' duplicates {}: values merged", key, first_location),
+                    ));
+                }
+                DuplicateTagPolicy::LastWins => {
+                    parsed.tags[idx] = tag;
+                    parsed.diagnostics.push(Diagnostic::warning(
+                        DiagnosticCode::DuplicateTag,
+                        &path,
+                        i + 1,
+                        format!(
+                            "Tag '{}' duplicates {}: earlier definition replaced",
+                            key, first_location
+                        ),
+                    ));
+                }
+            }
+
+            // Merged definitions must still fit the tag's own limits
+            check_tag_limits(&parsed.tags[idx]).map_err(|e| add_location(e, &location))?;
+
+            value_count = value_count - previous_count + parsed.tags[idx].values.len();
+            check_tag_budget(&options.limits, parsed.tags.len(), value_count)
+                .map_err(|e| add_location(e, &location))?;
+        }
+    }
+
+    for group in &parsed.exclusive_groups {
+        check_exclusive_group(group, &parsed.tags)?;
+    }
+
+    Ok(parsed)
+}
+
+// Prefix a tag error with where it happened -- e.g. "config/a.tags:3: ..."
+fn add_location(error: RulesError, location: &str) -> RulesError {
+    match error {
+        RulesError::TagParseError(msg) => {
+            RulesError::TagParseError(format!("{}: {}", location, msg))
+        }
+        other => other,
+    }
+}
+
+fn merge_tag(existing: &mut Tag, other: Tag) {
+    for value in other.values {
+        if !existing.values.contains(&value) {
+            existing.values.push(value);
         }
     }
 
-    Ok(tags)
+    if existing.constraints.pattern.is_none() {
+        existing.constraints.pattern = other.constraints.pattern;
+    }
+    existing.constraints.open |= other.constraints.open;
+    existing.constraints.required |= other.constraints.required;
+    existing.constraints.flag |= other.constraints.flag;
+    existing.constraints.single |= other.constraints.single;
+    existing.constraints.ordered |= other.constraints.ordered;
+    existing.constraints.max_values = existing
+        .constraints
+        .max_values
+        .or(other.constraints.max_values);
+    existing.constraints.max_length = existing
+        .constraints
+        .max_length
+        .or(other.constraints.max_length);
 }
 
 #[cfg(test)]
@@ -219,4 +865,341 @@ mod tests {
             assert_eq!(values, vec!["Red".to_string(), "Blue".to_string()]);
         }
     }
+
+    #[test]
+    fn test_validate_tag_pattern_valid() {
+        let tag = r"- sku: /^[A-Z]{2}\d{4}$/";
+        assert!(validate_tag(tag).is_ok());
+    }
+
+    #[test]
+    fn test_validate_tag_pattern_with_separators() {
+        // Commas and colons belong to the pattern, not the tag syntax
+        let tag = r"- time: /^\d{1,2}:\d{2}$/";
+        assert!(validate_tag(tag).is_ok());
+    }
+
+    #[test]
+    fn test_validate_tag_pattern_invalid_regex() {
+        let tag = "- sku: /^[A-Z/";
+        let result = validate_tag(tag);
+
+        assert!(result.is_err());
+        if let Err(RulesError::TagParseError(msg)) = result {
+            assert!(msg.contains("Invalid tag pattern"));
+        } else {
+            panic!("Expected TagParseError about invalid pattern");
+        }
+    }
+
+    #[test]
+    fn test_validate_tag_pattern_empty() {
+        let tag = "- sku: //";
+        let result = validate_tag(tag);
+
+        assert!(result.is_err());
+        if let Err(RulesError::TagParseError(msg)) = result {
+            assert!(msg.contains("cannot be empty"));
+        } else {
+            panic!("Expected TagParseError about empty pattern");
+        }
+    }
+
+    #[test]
+    fn test_get_tag_from_line_pattern() {
+        let tag = get_tag_from_line(r"- sku: /^[A-Z]{2}\d{4}$/").unwrap();
+
+        assert_eq!(tag.name, "sku");
+        assert!(tag.values.is_empty());
+
+        let constraints = tag.constraints;
+        assert!(constraints.allows(&tag.values, "AB1234"));
+        assert!(constraints.allows(&tag.values, "ab1234"));
+        assert!(!constraints.allows(&tag.values, "ABC123"));
+    }
+
+    #[test]
+    fn test_get_tag_from_line_without_pattern() {
+        let tag = get_tag_from_line("- Color: Red, Blue").unwrap();
+
+        assert_eq!(tag.values, vec!["Red".to_string(), "Blue".to_string()]);
+        assert!(tag.constraints.pattern.is_none());
+    }
+
+    #[test]
+    fn test_validate_tag_with_attributes() {
+        assert!(validate_tag("- Vendor (open): Acme, Globex").is_ok());
+        assert!(validate_tag("- Colour (closed): Red, Blue").is_ok());
+        assert!(validate_tag("- Sku (open): /^[A-Z]+$/").is_ok());
+    }
+
+    #[test]
+    fn test_validate_tag_unknown_attribute() {
+        let result = validate_tag("- Vendor (fuzzy): Acme");
+
+        assert!(result.is_err());
+        if let Err(RulesError::TagParseError(msg)) = result {
+            assert!(msg.contains("Unknown tag attribute 'fuzzy'"));
+        } else {
+            panic!("Expected TagParseError about unknown attribute");
+        }
+    }
+
+    #[test]
+    fn test_validate_tag_open_and_closed() {
+        let result = validate_tag("- Vendor (open, closed): Acme");
+
+        assert!(result.is_err());
+        if let Err(RulesError::TagParseError(msg)) = result {
+            assert!(msg.contains("both open and closed"));
+        } else {
+            panic!("Expected TagParseError about conflicting attributes");
+        }
+    }
+
+    #[test]
+    fn test_validate_tag_unclosed_attributes() {
+        assert!(validate_tag("- Vendor (open: Acme").is_err());
+    }
+
+    #[test]
+    fn test_get_tag_from_line_open() {
+        let tag = get_tag_from_line("- Vendor (open): Acme, Globex").unwrap();
+
+        assert_eq!(tag.name, "Vendor");
+        assert_eq!(tag.values, vec!["Acme".to_string(), "Globex".to_string()]);
+        assert!(tag.constraints.open);
+        assert!(tag.constraints.allows(&tag.values, "initech"));
+    }
+
+    #[test]
+    fn test_get_tag_from_line_closed_by_default() {
+        let tag = get_tag_from_line("- Colour: red, blue").unwrap();
+
+        assert!(!tag.constraints.open);
+        assert!(!tag.constraints.allows(&tag.values, "green"));
+
+        let tag = get_tag_from_line("- Colour (closed): red, blue").unwrap();
+        assert!(!tag.constraints.open);
+    }
+
+    #[test]
+    fn test_get_tag_from_line_required() {
+        let tag = get_tag_from_line("- Colour (required, open): red, blue").unwrap();
+
+        assert!(tag.constraints.required);
+        assert!(tag.constraints.open);
+
+        let tag = get_tag_from_line("- Colour: red, blue").unwrap();
+        assert!(!tag.constraints.required);
+        assert!(!tag.constraints.single);
+
+        let tag = get_tag_from_line("- Status (single): active, retired").unwrap();
+        assert!(tag.constraints.single);
+    }
+
+    #[test]
+    fn test_get_exclusive_group_from_line() {
+        let group = get_exclusive_group_from_line("@exclusive Colour: Red, Green").unwrap();
+        assert_eq!(
+            group,
+            Some(ExclusiveGroup::Values {
+                tag_name: "Colour".to_string(),
+                values: vec!["Red".to_string(), "Green".to_string()],
+            })
+        );
+        assert_eq!(
+            fold_exclusive_group(group.unwrap(), CaseFolding::Lowercase).to_string(),
+            "colour: red, green"
+        );
+
+        let group = get_exclusive_group_from_line("@exclusive discount, clearance").unwrap();
+        assert_eq!(
+            group,
+            Some(ExclusiveGroup::Tags {
+                tag_names: vec!["discount".to_string(), "clearance".to_string()],
+            })
+        );
+
+        assert_eq!(
+            get_exclusive_group_from_line("- Colour: Red").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_get_exclusive_group_from_line_too_few_members() {
+        assert!(get_exclusive_group_from_line("@exclusive colour: red").is_err());
+        assert!(get_exclusive_group_from_line("@exclusive discount").is_err());
+        assert!(get_exclusive_group_from_line("@exclusive dark colour: red, blue").is_err());
+    }
+
+    #[test]
+    fn test_check_exclusive_group_unknown_members() {
+        let tags = vec![get_tag_from_line("- colour: red, green").unwrap()];
+
+        let group = ExclusiveGroup::Values {
+            tag_name: "colour".to_string(),
+            values: vec!["red".to_string(), "purple".to_string()],
+        };
+        assert!(check_exclusive_group(&group, &tags).is_err());
+
+        let group = ExclusiveGroup::Tags {
+            tag_names: vec!["colour".to_string(), "size".to_string()],
+        };
+        assert!(check_exclusive_group(&group, &tags).is_err());
+
+        let group = ExclusiveGroup::Values {
+            tag_name: "colour".to_string(),
+            values: vec!["red".to_string(), "green".to_string()],
+        };
+        assert!(check_exclusive_group(&group, &tags).is_ok());
+    }
+
+    #[test]
+    fn test_format_tag_line() {
+        assert_eq!(
+            format_tag_line("-colour :red,blue , RED,red").unwrap(),
+            "- colour: red, blue"
+        );
+        assert_eq!(
+            format_tag_line("-  size(OPEN,required)  : small").unwrap(),
+            "- size (open, required): small"
+        );
+        assert_eq!(
+            format_tag_line("- sku:/^[a-z]{2}\\d{4}$/").unwrap(),
+            "- sku: /^[a-z]{2}\\d{4}$/"
+        );
+        assert!(format_tag_line("- colour red").is_err());
+    }
+
+    #[test]
+    fn test_format_tags() {
+        let content = "# Header\n\n# Sizes\n- size: small,large\n\n- colour: red, blue, red\n@exclusive colour:red,blue\n- brand: acme\n# Trailing";
+        let expected = "# Header\n\n- brand: acme\n- colour: red, blue\n# Sizes\n- size: small, large\n\n@exclusive colour: red, blue\n\n# Trailing";
+
+        assert_eq!(format_tags(content).unwrap(), expected);
+        assert_eq!(format_tags(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_tags_invalid_line() {
+        assert!(format_tags("- colour: red\n- bad line").is_err());
+    }
+
+    #[test]
+    fn test_get_tag_from_line_flag() {
+        let tag = get_tag_from_line("- is_fragile (flag)").unwrap();
+        assert_eq!(tag.name, "is_fragile");
+        assert_eq!(tag.values, vec!["true".to_string(), "false".to_string()]);
+
+        let tag = get_tag_from_line("- is_boxed (flag, required): false, true").unwrap();
+        assert_eq!(tag.values, vec!["true".to_string(), "false".to_string()]);
+        assert!(tag.constraints.required);
+
+        assert!(get_tag_from_line("- is_fragile (flag): yes, no").is_err());
+        assert!(get_tag_from_line("- is_fragile (flag, open)").is_err());
+        assert!(get_tag_from_line("- colour (required)").is_err());
+
+        assert_eq!(
+            format_tag_line("-is_fragile(FLAG):true,false").unwrap(),
+            "- is_fragile (flag)"
+        );
+    }
+
+    #[test]
+    fn test_get_tag_from_line_ordered() {
+        let tag = get_tag_from_line("- size: small < medium <large").unwrap();
+        assert_eq!(tag.values, vec!["small", "medium", "large"]);
+        assert!(tag.constraints.ordered);
+
+        let tag = get_tag_from_line("- size: small, medium").unwrap();
+        assert!(!tag.constraints.ordered);
+
+        assert!(get_tag_from_line("- size: small < medium, large").is_err());
+        assert!(get_tag_from_line("- size (open): small < large").is_err());
+        assert!(get_tag_from_line("- size: extra small < large").is_err());
+
+        assert_eq!(
+            format_tag_line("-size:small<medium < small<large").unwrap(),
+            "- size: small < medium < large"
+        );
+    }
+
+    #[test]
+    fn test_get_tag_from_line_limits() {
+        let tag = get_tag_from_line("- code (max_values = 3, MAX_LENGTH=4): ab12, cd34").unwrap();
+        assert_eq!(tag.constraints.max_values, Some(3));
+        assert_eq!(tag.constraints.max_length, Some(4));
+
+        let result = get_tag_from_line("- code (max_values=1): ab12, cd34");
+        if let Err(RulesError::TagParseError(msg)) = result {
+            assert!(msg.contains("has 2 values, more than its limit of 1"));
+        } else {
+            panic!("Expected TagParseError about too many values");
+        }
+
+        assert!(get_tag_from_line("- code (max_length=3): ab12").is_err());
+        assert!(get_tag_from_line("- code (max_length=many): ab12").is_err());
+        assert!(get_tag_from_line("- code (max_size=3): ab12").is_err());
+
+        assert_eq!(
+            format_tag_line("- code (max_values = 3): ab12").unwrap(),
+            "- code (max_values=3): ab12"
+        );
+    }
+
+    #[test]
+    fn test_check_tag_budget() {
+        let limits = TagLimits {
+            max_tags: Some(2),
+            max_values: Some(5),
+        };
+
+        assert!(check_tag_budget(&limits, 2, 5).is_ok());
+        assert!(check_tag_budget(&limits, 3, 5).is_err());
+        assert!(check_tag_budget(&limits, 2, 6).is_err());
+        assert!(check_tag_budget(&TagLimits::default(), 1000, 1000).is_ok());
+    }
+
+    #[test]
+    fn test_tag_error_codes() {
+        for (line, code) in [
+            ("- colour red", "T001"),
+            ("- colour: red: blue", "T001"),
+            ("colour: red", "T002"),
+            ("- colour (open, closed): red", "T005"),
+            ("- colour (fuzzy): red", "T005"),
+            ("- colour (flag, open)", "T005"),
+            ("- colour (max_values=1): red, blue", "T007"),
+            ("- sku: /[A-Z/", "T008"),
+            ("- sku: //", "T008"),
+            ("- my colour: red", "T009"),
+            ("- colour: dark red", "T010"),
+        ] {
+            let error = get_tag_from_line(line).err().unwrap();
+            assert_eq!(error.code().as_str(), code, "{}: {}", line, error);
+        }
+        let error = get_exclusive_group_from_line("@exclusive colour: red").unwrap_err();
+        assert_eq!(error.code().as_str(), "T006");
+
+        let source = |content: &str| vec![("a.tags".to_string(), content.to_string())];
+        let options = TagLoadOptions {
+            duplicate_tags: DuplicateTagPolicy::Error,
+            ..TagLoadOptions::default()
+        };
+        let result = parse_tags_from_sources(source("- colour: red\n- colour: blue"), options);
+        assert_eq!(result.err().unwrap().code().as_str(), "T004");
+
+        let parsed = parse_tags_from_sources(
+            source("- size: small\n- colour: red, Blue, blue"),
+            TagLoadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(parsed.diagnostics.len(), 1);
+        assert_eq!(
+            parsed.diagnostics[0].to_string(),
+            "a.tags:2: warning[T003]: Tag 'colour' lists value 'blue' more than once"
+        );
+    }
 }
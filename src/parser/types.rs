@@ -1,4 +1,7 @@
 // Parser-specific types
+use crate::types::{Clause, ComparisonOp, OperatorPrecedence};
+#[cfg(feature = "json_logic")]
+use crate::{err::RulesError, parser::rules, utils::string};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
@@ -6,6 +9,8 @@ pub enum TokenType {
     ComparisonOp, // =
     TagValue,     // "red"
     LogicalOp,    // &
+    Negation,     // ! before a group
+    Presence,     // ? after a tag name
 }
 
 pub type TokenDepth = i32;
@@ -13,40 +18,221 @@ pub type TokenDepth = i32;
 // Tokens, their type and their parenthesis depth -- e.g. [("colour", TagName, 2), ("=", ComparisonOp, 0)]
 pub type MappedRuleTokens = Vec<(String, TokenType, TokenDepth)>;
 
+// Byte offsets of a lexeme in the rule line it was read from -- e.g. 2..8 for "colour" in
+// "- colour = red"
+pub type Span = std::ops::Range<usize>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LexemeKind {
+    Word,   // colour, red
+    Quoted, // "dark red", never an operator, range or call
+    Call,   // before(2024-01-01), parens and all
+    Op,     // =, &, ( or -> as '→'
+}
+
+// A piece of a rule as the lexer reads it, before the parser gives it a TokenType -- e.g. the
+// Op "=" at 9..11 for "==" in "- colour == red". Lexemes added by rewriting shorthand have the
+// span of what they were rewritten from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub kind: LexemeKind,
+    pub text: String,
+    pub span: Span,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
-    LeftParen,  // (
-    RightParen, // )
-    Equals,     // =
-    NotEquals,  // !
-    And,        // &
-    Or,         // |
-    Comma,      // ,
-    Invalid,    // Initialiser
+    LeftParen,   // (
+    RightParen,  // )
+    Equals,      // =
+    NotEquals,   // !
+    LessThan,    // <
+    GreaterThan, // >
+    Call,        // ~
+    Exists,      // ? after a tag name
+    And,         // &
+    Or,          // |
+    Comma,       // ,
+    Not,         // ! before a group, only while the rule is parsed
+    Implies,     // ->, only until the rule's tree is built
+    Invalid,     // Initialiser
 }
 
+// Node of a parsed rule, either a comparison or an '&' or '|' over two nodes -- e.g. & with
+// colour = red on the left and size = large on the right. A '!' group is a Not node over its
+// left operand until its negation is pushed down to the comparisons.
+#[derive(Debug)]
 pub struct Node {
     pub token: Token,
     pub left: Option<Box<Node>>,
     pub right: Option<Box<Node>>,
+    // Comparison held by a leaf node -- e.g. colour = red
+    pub clause: Option<Clause>,
 }
 
+// Syntax tree of one rule, from Rules::parse_rule
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AstRule {
     pub root_node: Node,
 }
 
 // Impls
 
+impl Lexeme {
+    pub fn new(kind: LexemeKind, text: &str, span: Span) -> Self {
+        Lexeme {
+            kind,
+            text: text.to_string(),
+            span,
+        }
+    }
+
+    pub fn op(c: char, span: Span) -> Self {
+        Lexeme::new(LexemeKind::Op, &c.to_string(), span)
+    }
+
+    pub fn is_op(&self, c: char) -> bool {
+        self.kind == LexemeKind::Op && self.text.starts_with(c)
+    }
+
+    // Whether this can be a tag name or value -- e.g. red or "dark red", but not '&'
+    pub fn is_word(&self) -> bool {
+        matches!(self.kind, LexemeKind::Word | LexemeKind::Quoted)
+    }
+}
+
+// Children are dropped from a stack rather than recursively, as a long rule's chain of
+// operators is as deep as it is long
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut children: Vec<Box<Node>> = self.left.take().into_iter().collect();
+        children.extend(self.right.take());
+        while let Some(mut child) = children.pop() {
+            children.extend(child.left.take());
+            children.extend(child.right.take());
+        }
+    }
+}
+
+// Chains of one operator are copied in a loop, as for dropping
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        let operands = self.chain();
+        if operands.len() > 1 {
+            let copied: Vec<Node> = operands.into_iter().cloned().collect();
+            return Node::from_chain(self.token.clone(), copied).unwrap_or_default();
+        }
+        Node {
+            token: self.token.clone(),
+            left: self.left.clone(),
+            right: self.right.clone(),
+            clause: self.clause.clone(),
+        }
+    }
+}
+
+// Pairs of nodes are compared from a stack, as for dropping
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        let mut pairs: Vec<(&Node, &Node)> = vec![(self, other)];
+        while let Some((first, second)) = pairs.pop() {
+            if first.token != second.token || first.clause != second.clause {
+                return false;
+            }
+            for children in [(&first.left, &second.left), (&first.right, &second.right)] {
+                match children {
+                    (Some(first), Some(second)) => pairs.push((first, second)),
+                    (None, None) => {}
+                    _ => return false,
+                }
+            }
+        }
+        true
+    }
+}
+
 impl Default for Node {
     fn default() -> Self {
         Node {
             token: Token::Invalid,
             left: None,
             right: None,
+            clause: None,
         }
     }
 }
 
+// A node as serialized, with a chain of one operator as one list of operands so it nests only as
+// deep as the rule's groups -- e.g. {token: And, operands: [a, b, c]} for "a & b & c"
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct SerializedNode<'a> {
+    token: &'a Token,
+    clause: &'a Option<Clause>,
+    operands: Vec<SerializedNode<'a>>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct DeserializedNode {
+    token: Token,
+    #[serde(default)]
+    clause: Option<Clause>,
+    #[serde(default)]
+    operands: Vec<DeserializedNode>,
+}
+
+#[cfg(feature = "serde")]
+impl<'a> From<&'a Node> for SerializedNode<'a> {
+    fn from(node: &'a Node) -> Self {
+        let operands: Vec<&Node> = match (&node.left, &node.right) {
+            (Some(_), Some(_)) => node.chain(),
+            (left, right) => left
+                .iter()
+                .chain(right)
+                .map(|child| child.as_ref())
+                .collect(),
+        };
+        SerializedNode {
+            token: &node.token,
+            clause: &node.clause,
+            operands: operands.into_iter().map(SerializedNode::from).collect(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<DeserializedNode> for Node {
+    fn from(node: DeserializedNode) -> Self {
+        let mut operands: Vec<Node> = node.operands.into_iter().map(Node::from).collect();
+        if operands.len() > 1 {
+            return Node::from_chain(node.token, operands).unwrap_or_default();
+        }
+        Node {
+            token: node.token,
+            left: operands.pop().map(Box::new),
+            right: None,
+            clause: node.clause,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Node {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedNode::from(self).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Node {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        DeserializedNode::deserialize(deserializer).map(Node::from)
+    }
+}
+
 impl Token {
     pub fn as_char(&self) -> char {
         match self {
@@ -54,9 +240,15 @@ impl Token {
             Token::RightParen => ')',
             Token::Equals => '=',
             Token::NotEquals => '!',
+            Token::LessThan => '<',
+            Token::GreaterThan => '>',
+            Token::Call => '~',
+            Token::Exists => '?',
             Token::And => '&',
             Token::Or => '|',
             Token::Comma => ',',
+            Token::Not => '!',
+            Token::Implies => '→',
             Token::Invalid => panic!("Invalid token has no character representation"),
         }
     }
@@ -67,10 +259,383 @@ impl Token {
             ')' => Some(Token::RightParen),
             '=' => Some(Token::Equals),
             '!' => Some(Token::NotEquals),
+            '<' => Some(Token::LessThan),
+            '>' => Some(Token::GreaterThan),
+            '~' => Some(Token::Call),
+            '?' => Some(Token::Exists),
             '&' => Some(Token::And),
             '|' => Some(Token::Or),
             ',' => Some(Token::Comma),
+            '→' => Some(Token::Implies),
             _ => None,
         }
     }
+
+    // Token of a leaf node holding a comparison -- e.g. Token::Equals for colour = red
+    pub fn for_comparison(comparison_op: &ComparisonOp) -> Self {
+        match comparison_op {
+            ComparisonOp::ISEQ => Token::Equals,
+            ComparisonOp::NOEQ => Token::NotEquals,
+            ComparisonOp::LSTH => Token::LessThan,
+            ComparisonOp::GRTH => Token::GreaterThan,
+            ComparisonOp::CALL | ComparisonOp::REGX => Token::Call,
+            ComparisonOp::PRES | ComparisonOp::ABSN => Token::Exists,
+        }
+    }
+}
+
+impl Node {
+    // Operands of the chain of this node's operator down its right side, left to right -- e.g.
+    // [a, b & c, d] for "a | b & c | d". Operators at one level group to the right, so passes
+    // over a long flat rule loop down its chain rather than recursing once per operand. A
+    // comparison, or an operator missing an operand, is its own only operand.
+    pub fn chain(&self) -> Vec<&Node> {
+        let mut operands: Vec<&Node> = Vec::new();
+        let mut node = self;
+        while node.continues_chain(&self.token)
+            && let (Some(left), Some(right)) = (&node.left, &node.right)
+        {
+            operands.push(left);
+            node = right;
+        }
+        operands.push(node);
+        operands
+    }
+
+    // As chain, taking the operands out of the tree
+    pub fn into_chain(mut self) -> Vec<Node> {
+        let token = self.token.clone();
+        let mut operands: Vec<Node> = Vec::new();
+        while self.continues_chain(&token)
+            && let (Some(left), Some(right)) = (self.left.take(), self.right.take())
+        {
+            operands.push(*left);
+            self = *right;
+        }
+        operands.push(self);
+        operands
+    }
+
+    // Operands joined by `token`, grouping to the right as rules are read -- e.g. "a & (b & c)"
+    // for [a, b, c]. None if there are no operands.
+    pub fn from_chain(token: Token, operands: Vec<Node>) -> Option<Node> {
+        operands.into_iter().rev().reduce(|right, left| Node {
+            token: token.clone(),
+            left: Some(Box::new(left)),
+            right: Some(Box::new(right)),
+            clause: None,
+        })
+    }
+
+    fn continues_chain(&self, token: &Token) -> bool {
+        self.token == *token && self.clause.is_none() && self.left.is_some() && self.right.is_some()
+    }
+
+    // This node written as rule syntax, without the dash, with single spaces and parentheses only
+    // where `precedence` needs them -- e.g. "colour = red & (size = small | size = large)"
+    pub fn to_rule_string(&self, precedence: &OperatorPrecedence) -> String {
+        if let Some(clause) = &self.clause {
+            // Absence has no syntax of its own, only as a negated presence check
+            return match clause.comparison_op {
+                ComparisonOp::ABSN => format!("!({}?)", clause.tag_name),
+                _ => clause.to_string(),
+            };
+        }
+
+        let operand = |child: &Option<Box<Node>>| {
+            child
+                .as_ref()
+                .map_or_else(String::new, |child| child.to_rule_string(precedence))
+        };
+        if self.token == Token::Not {
+            return format!("!({})", operand(&self.left));
+        }
+
+        // An operand needs parentheses if it binds more loosely, or as tightly but is another
+        // operator -- e.g. "(a | b) & c", but not "a & b & c"
+        let level = |node: &Node| precedence.level(&node.token.as_char().to_string());
+        let grouped = |child: &Node| {
+            let text = child.to_rule_string(precedence);
+            if child.clause.is_none()
+                && child.token != Token::Not
+                && child.token != self.token
+                && level(child) <= level(self)
+            {
+                format!("({})", text)
+            } else {
+                text
+            }
+        };
+        let operands = self.chain();
+        if operands.len() > 1 {
+            let written: Vec<String> = operands.into_iter().map(grouped).collect();
+            return written.join(&format!(" {} ", self.token));
+        }
+        format!(
+            "{} {} {}",
+            self.left.as_deref().map_or_else(String::new, grouped),
+            self.token,
+            self.right.as_deref().map_or_else(String::new, grouped)
+        )
+    }
+
+    // Adds this node and its children to a Graphviz DOT graph, one statement per line, with ids
+    // from `prefix` and `next_id`, returning this node's id -- e.g. "r0n1 [label=\"colour =
+    // red\", shape=box];" and "r0n0 -> r0n1;". Operators are ellipses and comparisons boxes.
+    pub(crate) fn write_dot(
+        &self,
+        lines: &mut Vec<String>,
+        prefix: &str,
+        next_id: &mut usize,
+    ) -> String {
+        // Nodes still to write, and edges to write once the node they point to is written with
+        // its children, on a stack rather than recursing, as a long rule's chain is as deep as it
+        // is long
+        enum Step<'a> {
+            Write(&'a Node, Option<String>),
+            Edge(String, String),
+        }
+
+        let root_id = format!("{}n{}", prefix, next_id);
+        let mut steps = vec![Step::Write(self, None)];
+        while let Some(step) = steps.pop() {
+            let (node, parent_id) = match step {
+                Step::Write(node, parent_id) => (node, parent_id),
+                Step::Edge(parent_id, id) => {
+                    lines.push(format!("{} -> {};", parent_id, id));
+                    continue;
+                }
+            };
+            let id = format!("{}n{}", prefix, next_id);
+            *next_id += 1;
+
+            match &node.clause {
+                Some(clause) => lines.push(format!(
+                    "{} [label=\"{}\", shape=box];",
+                    id,
+                    dot_escape(&clause.to_string())
+                )),
+                None => lines.push(format!("{} [label=\"{}\"];", id, node.token)),
+            }
+
+            if let Some(parent_id) = parent_id {
+                steps.push(Step::Edge(parent_id, id.clone()));
+            }
+            for child in [&node.right, &node.left].into_iter().flatten() {
+                steps.push(Step::Write(child, Some(id.clone())));
+            }
+        }
+
+        root_id
+    }
+
+    // Writes each node on one line after its connector, then its children indented under its
+    // prefix -- e.g. "|-- &" followed by "|   |-- colour = red". Nodes still to write are kept on
+    // a stack, with their prefix and connector, rather than recursing.
+    fn write_tree(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut nodes: Vec<(&Node, String, String)> = vec![(self, String::new(), String::new())];
+        while let Some((node, prefix, connector)) = nodes.pop() {
+            match &node.clause {
+                Some(clause) => writeln!(f, "{}{}", connector, clause)?,
+                None => writeln!(f, "{}{}", connector, node.token)?,
+            }
+
+            let children: Vec<&Node> = [&node.left, &node.right]
+                .into_iter()
+                .flatten()
+                .map(|child| child.as_ref())
+                .collect();
+            // Last first, so the first is written first
+            for (i, child) in children.iter().enumerate().rev() {
+                let last = i + 1 == children.len();
+                let (branch, indent) = if last {
+                    ("`-- ", "    ")
+                } else {
+                    ("|-- ", "|   ")
+                };
+                nodes.push((
+                    child,
+                    format!("{}{}", prefix, indent),
+                    format!("{}{}", prefix, branch),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// An operator above its operands, one per line -- e.g.
+// &
+// |-- colour = red
+// `-- |
+//     |-- size = small
+//     `-- size = large
+impl std::fmt::Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write_tree(f)
+    }
+}
+
+impl AstRule {
+    // The rule written back as rule syntax, with the default operator precedence -- e.g.
+    // "-(size=small|size=large)&colour=red" gives "- (size = small | size = large) & colour = red".
+    // Operands keep their order, and shorthand such as comma lists is written out as expanded.
+    pub fn to_canonical_string(&self) -> String {
+        self.to_canonical_string_with(&OperatorPrecedence::default())
+    }
+
+    // As to_canonical_string, with parentheses for rules parsed with `precedence`
+    pub fn to_canonical_string_with(&self, precedence: &OperatorPrecedence) -> String {
+        format!("- {}", self.root_node.to_rule_string(precedence))
+    }
+
+    // The rule's tree as a Graphviz DOT graph, each operator pointing to its operands -- e.g.
+    // "- colour = red & size = large" gives '&' with an edge to each comparison. Render it with
+    // `dot -Tsvg`.
+    pub fn to_dot(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        self.root_node.write_dot(&mut lines, "", &mut 0);
+
+        let mut dot = String::from("digraph rule {\n");
+        for line in lines {
+            dot.push_str(&format!("    {}\n", line));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(feature = "json_logic")]
+impl AstRule {
+    // The rule as a JsonLogic expression, for evaluating it with a JsonLogic library against data
+    // holding each tag as a list of values, as objects do -- e.g. "- colour = red & size?" gives
+    // {"and": [{"in": ["red", {"var": "colour"}]}, {"!!": {"var": "size"}}]}. Chains of one
+    // operator are one list. Values are compared as written. Errors on comparisons JsonLogic
+    // can't express: rank comparisons, which need the tag's values (see
+    // Rules::rule_to_json_logic), wildcards, regexes, plugin calls, variables and tag references.
+    pub fn to_json_logic(&self) -> Result<serde_json::Value, RulesError> {
+        self.to_json_logic_with(&|_| None)
+    }
+
+    // As to_json_logic, with `ranked` giving the values on the matching side of a rank comparison
+    // -- e.g. ["medium", "large"] for "size > small"
+    pub fn to_json_logic_with(
+        &self,
+        ranked: &dyn Fn(&Clause) -> Option<Vec<String>>,
+    ) -> Result<serde_json::Value, RulesError> {
+        self.root_node.to_json_logic(ranked)
+    }
+}
+
+#[cfg(feature = "json_logic")]
+impl Node {
+    fn to_json_logic(
+        &self,
+        ranked: &dyn Fn(&Clause) -> Option<Vec<String>>,
+    ) -> Result<serde_json::Value, RulesError> {
+        use serde_json::json;
+
+        if let Some(clause) = &self.clause {
+            return clause_to_json_logic(clause, ranked);
+        }
+        if self.token == Token::Not {
+            let operand = match &self.left {
+                Some(operand) => operand.to_json_logic(ranked)?,
+                None => serde_json::Value::Null,
+            };
+            return Ok(json!({ "!": operand }));
+        }
+
+        // Operands of the same operator as one list -- e.g. "a & b & c" gives {"and": [a, b, c]}
+        let mut operands = Vec::new();
+        let mut stack: Vec<&Node> = vec![self];
+        while let Some(node) = stack.pop() {
+            if node.clause.is_none() && node.token == self.token {
+                stack.extend(
+                    [&node.right, &node.left]
+                        .into_iter()
+                        .flatten()
+                        .map(|c| c.as_ref()),
+                );
+            } else {
+                operands.push(node.to_json_logic(ranked)?);
+            }
+        }
+
+        let op = match self.token {
+            Token::And => "and",
+            _ => "or",
+        };
+        Ok(json!({ op: operands }))
+    }
+}
+
+// One comparison as a JsonLogic expression -- e.g. "colour ! red" gives
+// {"!": {"in": ["red", {"var": "colour"}]}}
+#[cfg(feature = "json_logic")]
+fn clause_to_json_logic(
+    clause: &Clause,
+    ranked: &dyn Fn(&Clause) -> Option<Vec<String>>,
+) -> Result<serde_json::Value, RulesError> {
+    use serde_json::json;
+
+    let unsupported = |what: &str| {
+        Err(RulesError::RuleParseError(format!(
+            "JsonLogic has no {}: {}",
+            what, clause
+        )))
+    };
+    let var = json!({ "var": clause.tag_name });
+
+    match clause.comparison_op {
+        ComparisonOp::ISEQ | ComparisonOp::NOEQ => {
+            let value = &clause.tag_value;
+            if value.contains(string::WILDCARD) {
+                return unsupported("wildcards");
+            }
+            if value.starts_with(rules::VARIABLE_PREFIX) {
+                return unsupported("variables");
+            }
+            if rules::tag_reference(value).is_some() {
+                return unsupported("tag references");
+            }
+
+            let contains = json!({ "in": [value, var] });
+            Ok(match clause.comparison_op {
+                ComparisonOp::ISEQ => contains,
+                _ => json!({ "!": contains }),
+            })
+        }
+        ComparisonOp::PRES => Ok(json!({ "!!": var })),
+        ComparisonOp::ABSN => Ok(json!({ "!": var })),
+        // Any of the tag's values among those on the matching side
+        ComparisonOp::LSTH | ComparisonOp::GRTH => match ranked(clause) {
+            Some(values) => Ok(json!({ "some": [var, { "in": [{ "var": "" }, values] }] })),
+            None => unsupported("ranks without the tag's values"),
+        },
+        ComparisonOp::REGX => unsupported("regexes"),
+        ComparisonOp::CALL => unsupported("plugin calls"),
+    }
+}
+
+// `text` as the inside of a quoted DOT string -- e.g. colour = "dark red" gives
+// colour = \"dark red\"
+pub(crate) fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl std::fmt::Display for AstRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.root_node)
+    }
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Invalid => write!(f, "<invalid>"),
+            other => write!(f, "{}", other.as_char()),
+        }
+    }
 }
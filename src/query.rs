@@ -0,0 +1,283 @@
+// Rules built in Rust rather than written as rule strings -- e.g.
+// rules.query().tag("colour").eq("red").or().tag("size").ne("small").run(&objects)
+use crate::ast::{AstRule, Node, Token};
+use crate::err::RulesError;
+use crate::parser::rules::RuleParser;
+use crate::types::{Clause, ComparisonOp, Object, TagName};
+
+// Query waiting for its next comparison, from Rules::query, Query::and or Query::or
+pub struct QueryTerm {
+    m_parser: RuleParser,
+    // Comparisons '&'ed together, '|'ed with each other -- e.g. [[colour = red], [size ! small]]
+    m_disjuncts: Vec<Vec<Clause>>,
+}
+
+// Query waiting for the operator comparing a tag -- e.g. after tag("colour")
+pub struct QueryComparison {
+    m_term: QueryTerm,
+    m_tag_name: TagName,
+}
+
+// Query ending in a comparison, ready to run or extend with and() or or()
+pub struct Query {
+    m_term: QueryTerm,
+}
+
+impl QueryTerm {
+    pub(crate) fn new(parser: RuleParser) -> Self {
+        QueryTerm {
+            m_parser: parser,
+            m_disjuncts: vec![Vec::new()],
+        }
+    }
+
+    // Start a comparison on `tag_name` -- e.g. tag("colour")
+    pub fn tag(self, tag_name: &str) -> QueryComparison {
+        QueryComparison {
+            m_term: self,
+            m_tag_name: tag_name.to_string(),
+        }
+    }
+}
+
+impl QueryComparison {
+    fn compare(mut self, comparison_op: ComparisonOp, tag_value: &str) -> Query {
+        if let Some(conjunction) = self.m_term.m_disjuncts.last_mut() {
+            conjunction.push(Clause {
+                tag_name: self.m_tag_name,
+                comparison_op,
+                tag_value: tag_value.to_string(),
+            });
+        }
+
+        Query {
+            m_term: self.m_term,
+        }
+    }
+
+    // tag = value -- e.g. colour = red
+    pub fn eq(self, tag_value: &str) -> Query {
+        self.compare(ComparisonOp::ISEQ, tag_value)
+    }
+
+    // tag ! value -- e.g. colour ! red
+    pub fn ne(self, tag_value: &str) -> Query {
+        self.compare(ComparisonOp::NOEQ, tag_value)
+    }
+
+    // tag < value, on an ordered tag -- e.g. size < large
+    pub fn lt(self, tag_value: &str) -> Query {
+        self.compare(ComparisonOp::LSTH, tag_value)
+    }
+
+    // tag > value, on an ordered tag -- e.g. size > small
+    pub fn gt(self, tag_value: &str) -> Query {
+        self.compare(ComparisonOp::GRTH, tag_value)
+    }
+
+    // The object has the tag, whatever its values -- e.g. colour?
+    pub fn exists(self) -> Query {
+        self.compare(ComparisonOp::PRES, "")
+    }
+
+    // The object lacks the tag -- e.g. !colour?
+    pub fn missing(self) -> Query {
+        self.compare(ComparisonOp::ABSN, "")
+    }
+}
+
+impl Query {
+    // '&' the next comparison onto this one. Binds tighter than or(), as in rules.
+    pub fn and(self) -> QueryTerm {
+        self.m_term
+    }
+
+    // '|' the next comparison onto everything before it
+    pub fn or(mut self) -> QueryTerm {
+        self.m_term.m_disjuncts.push(Vec::new());
+        self.m_term
+    }
+
+    /// Builds the rule's syntax tree, checking every comparison against the
+    /// loaded tags as if it were written in a rule.
+    ///
+    /// # Returns
+    /// * `Ok(AstRule)` the same as parsing the equivalent rule string
+    /// * `Err(RulesError)` if a tag or value isn't defined, or a value can't be compared
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let ast = rules.query().tag("colour").eq("red").build()?;
+    /// ```
+    pub fn build(&self) -> Result<AstRule, RulesError> {
+        let parser = &self.m_term.m_parser;
+        for clause in self.m_term.m_disjuncts.iter().flatten() {
+            parser.validate_rule(&format!("- {}", clause))?;
+        }
+
+        let join = |token: Token, nodes: Vec<Node>| {
+            nodes
+                .into_iter()
+                .reduce(|left, right| Node {
+                    token: token.clone(),
+                    left: Some(Box::new(left)),
+                    right: Some(Box::new(right)),
+                    clause: None,
+                })
+                .unwrap_or_default()
+        };
+
+        let conjunctions: Vec<Node> = self
+            .m_term
+            .m_disjuncts
+            .iter()
+            .map(|conjunction| {
+                let leaves = conjunction.iter().map(|clause| Node {
+                    token: Token::for_comparison(&clause.comparison_op),
+                    clause: Some(clause.clone()),
+                    left: None,
+                    right: None,
+                });
+                join(Token::And, leaves.collect())
+            })
+            .collect();
+
+        Ok(AstRule {
+            root_node: join(Token::Or, conjunctions),
+        })
+    }
+
+    /// Whether an object matches the query.
+    ///
+    /// # Arguments
+    /// * `obj` - Object to check, e.g. `{colour: [red], size: [large]}`
+    ///
+    /// # Returns
+    /// * `Ok(bool)` whether the object matches
+    /// * `Err(RulesError)` if the query is invalid, see [`Query::build`]
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let large = rules.query().tag("size").eq("large").matches(&obj)?;
+    /// ```
+    pub fn matches(&self, obj: &Object) -> Result<bool, RulesError> {
+        let ast = self.build()?;
+        Ok(self.m_term.m_parser.ast_matches(&ast, obj))
+    }
+
+    /// Objects matching the query, in the order given.
+    ///
+    /// # Arguments
+    /// * `objects` - Objects to check
+    ///
+    /// # Returns
+    /// * `Ok(Vec<&Object>)` with every matching object
+    /// * `Err(RulesError)` if the query is invalid, see [`Query::build`]
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let matched = rules
+    ///     .query()
+    ///     .tag("colour").eq("red")
+    ///     .or()
+    ///     .tag("size").ne("small")
+    ///     .run(&objects)?;
+    /// ```
+    pub fn run<'a>(&self, objects: &'a [Object]) -> Result<Vec<&'a Object>, RulesError> {
+        let ast = self.build()?;
+        let parser = &self.m_term.m_parser;
+        Ok(objects
+            .iter()
+            .filter(|obj| parser.ast_matches(&ast, obj))
+            .collect())
+    }
+}
+
+// The query as a rule -- e.g. "- colour = red | size ! small"
+impl std::fmt::Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let disjuncts: Vec<String> = self
+            .m_term
+            .m_disjuncts
+            .iter()
+            .map(|conjunction| {
+                conjunction
+                    .iter()
+                    .map(Clause::to_string)
+                    .collect::<Vec<String>>()
+                    .join(" & ")
+            })
+            .collect();
+        write!(f, "- {}", disjuncts.join(" | "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn new_query() -> QueryTerm {
+        let mut tags = HashMap::new();
+        tags.insert(
+            "colour".to_string(),
+            vec!["red".to_string(), "blue".to_string()],
+        );
+        tags.insert(
+            "size".to_string(),
+            vec![
+                "small".to_string(),
+                "medium".to_string(),
+                "large".to_string(),
+            ],
+        );
+        QueryTerm::new(RuleParser::new(tags))
+    }
+
+    fn object(colour: &str, size: &str) -> Object {
+        HashMap::from([
+            ("colour".to_string(), vec![colour.to_string()]),
+            ("size".to_string(), vec![size.to_string()]),
+        ])
+    }
+
+    #[test]
+    fn test_query() {
+        let query = new_query()
+            .tag("colour")
+            .eq("red")
+            .and()
+            .tag("size")
+            .eq("large")
+            .or()
+            .tag("size")
+            .ne("small");
+        assert_eq!(
+            query.to_string(),
+            "- colour = red & size = large | size ! small"
+        );
+
+        // '&' binds tighter than '|', as in rules
+        assert_eq!(
+            query.build().unwrap().to_string(),
+            "|\n|-- &\n|   |-- colour = red\n|   `-- size = large\n`-- size ! small\n"
+        );
+
+        let objects = vec![
+            object("red", "large"),
+            object("blue", "small"),
+            object("blue", "medium"),
+        ];
+        let matched = query.run(&objects).unwrap();
+        assert_eq!(matched, vec![&objects[0], &objects[2]]);
+        assert!(!query.matches(&objects[1]).unwrap());
+
+        // Comparisons are checked against the tags when the query is built
+        let unknown = new_query().tag("colour").eq("green");
+        assert!(matches!(
+            unknown.run(&objects),
+            Err(RulesError::RuleParseError(_))
+        ));
+        assert!(new_query().tag("shape").eq("circle").build().is_err());
+    }
+}
@@ -0,0 +1,289 @@
+// Config files pulled from remote sources -- e.g. https://config.example.com/base.rules
+#[cfg(feature = "s3")]
+pub mod s3;
+
+use crate::err::RulesError;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+// Validators from the last pull of each file, under the config directory
+pub const REMOTE_DIR: &str = ".remote";
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// Sent with the next request so an unchanged file isn't downloaded again
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheValidators {
+    pub etag: Option<String>,
+    // HTTP date -- e.g. "Wed, 21 Oct 2015 07:28:00 GMT"
+    pub last_modified: Option<String>,
+}
+
+// File fetched from a remote source
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteFile {
+    // Name in the config directory, the last segment of the URL -- e.g. "base.rules"
+    pub name: String,
+    pub url: String,
+    // None if the source reported the file unchanged since the last pull
+    pub content: Option<String>,
+    pub validators: CacheValidators,
+}
+
+fn invalid_input(msg: String) -> RulesError {
+    RulesError::IoError(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+// Name a URL's file is stored under -- e.g. "https://example.com/v2/base.rules?x=1" gives
+// "base.rules". Only .tags, .rules and .yaml files can be pulled.
+pub fn file_name(url: &str) -> Result<String, RulesError> {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let name = path.rsplit('/').next().unwrap_or(path);
+
+    let pullable = [".tags", ".rules", ".yaml"]
+        .iter()
+        .any(|extension| name.len() > extension.len() && name.ends_with(extension));
+    if !pullable {
+        return Err(invalid_input(format!(
+            "'{}' doesn't name a .tags, .rules or .yaml file",
+            url
+        )));
+    }
+
+    Ok(name.to_string())
+}
+
+fn validators_path(config_dir: &str, name: &str) -> String {
+    format!("{}/{}/{}", config_dir, REMOTE_DIR, name)
+}
+
+// Validators saved for `name`, if it was last pulled from `url` and is still on disk
+fn read_validators(config_dir: &str, name: &str, url: &str) -> CacheValidators {
+    if !Path::new(config_dir).join(name).exists() {
+        return CacheValidators::default();
+    }
+    let Ok(content) = fs::read_to_string(validators_path(config_dir, name)) else {
+        return CacheValidators::default();
+    };
+
+    let mut validators = CacheValidators::default();
+    let mut same_url = false;
+    for line in content.lines() {
+        match line.split_once('\t') {
+            Some(("url", value)) => same_url = value == url,
+            Some(("etag", value)) => validators.etag = Some(value.to_string()),
+            Some(("last_modified", value)) => validators.last_modified = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    if same_url {
+        validators
+    } else {
+        CacheValidators::default()
+    }
+}
+
+// Remember a pulled file's validators for the next pull -- e.g. ".remote/base.rules"
+pub fn save_validators(config_dir: &str, file: &RemoteFile) -> Result<(), RulesError> {
+    fs::create_dir_all(format!("{}/{}", config_dir, REMOTE_DIR))?;
+
+    let mut content = format!("url\t{}\n", file.url);
+    if let Some(etag) = &file.validators.etag {
+        content.push_str(&format!("etag\t{}\n", etag));
+    }
+    if let Some(last_modified) = &file.validators.last_modified {
+        content.push_str(&format!("last_modified\t{}\n", last_modified));
+    }
+
+    fs::write(validators_path(config_dir, &file.name), content)?;
+    Ok(())
+}
+
+fn status_error(url: &str, status: u16) -> RulesError {
+    let kind = match status {
+        401 | 403 => io::ErrorKind::PermissionDenied,
+        404 => io::ErrorKind::NotFound,
+        _ => io::ErrorKind::Other,
+    };
+    RulesError::IoError(io::Error::new(
+        kind,
+        format!("GET {}: HTTP {}", url, status),
+    ))
+}
+
+// Fetch one file over HTTP, conditional on the validators from the last pull
+fn get(
+    url: &str,
+    request_url: &str,
+    headers: &[(String, String)],
+    validators: CacheValidators,
+) -> Result<(Option<String>, CacheValidators), RulesError> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .http_status_as_error(false)
+        .timeout_global(Some(TIMEOUT))
+        .build()
+        .into();
+    let transport_error =
+        |e: ureq::Error| RulesError::IoError(io::Error::other(format!("GET {}: {}", url, e)));
+
+    let mut request = agent.get(request_url);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    if let Some(etag) = &validators.etag {
+        request = request.header("If-None-Match", etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.header("If-Modified-Since", last_modified);
+    }
+
+    let mut response = request.call().map_err(transport_error)?;
+    let status = response.status().as_u16();
+    if status == 304 {
+        return Ok((None, validators));
+    }
+    if !(200..300).contains(&status) {
+        return Err(status_error(url, status));
+    }
+
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = CacheValidators {
+        etag: header("etag"),
+        last_modified: header("last-modified"),
+    };
+
+    let content = response
+        .body_mut()
+        .read_to_string()
+        .map_err(transport_error)?;
+    Ok((Some(content), validators))
+}
+
+// Fetch a file for the config directory, skipping the download if it hasn't changed since the
+// last pull. Supports http(s):// URLs, and s3:// with the `s3` feature.
+pub fn fetch(config_dir: &str, url: &str) -> Result<RemoteFile, RulesError> {
+    let name = file_name(url)?;
+    let validators = read_validators(config_dir, &name, url);
+
+    let (content, validators) = match url.split_once("://") {
+        Some(("http" | "https", _)) => get(url, url, &[], validators)?,
+        #[cfg(feature = "s3")]
+        Some(("s3", _)) => {
+            let (request_url, headers) = s3::signed_request(url)?;
+            get(url, &request_url, &headers, validators)?
+        }
+        _ => {
+            return Err(invalid_input(format!(
+                "Unsupported source '{}', expected an http(s):// URL{}",
+                url,
+                if cfg!(feature = "s3") {
+                    " or s3://bucket/key"
+                } else {
+                    ""
+                }
+            )));
+        }
+    };
+
+    Ok(RemoteFile {
+        name,
+        url: url.to_string(),
+        content,
+        validators,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Serve one canned response per connection, returning each request's headers
+    fn serve(responses: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    request.push_str(&line.to_lowercase());
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        (format!("http://{}", addr), handle)
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(
+            file_name("https://example.com/v2/base.rules?x=1").unwrap(),
+            "base.rules"
+        );
+        assert_eq!(file_name("s3://bucket/config/a.tags").unwrap(), "a.tags");
+        assert!(file_name("https://example.com/base.txt").is_err());
+        assert!(file_name("https://example.com/.rules").is_err());
+    }
+
+    #[test]
+    fn test_fetch_with_validators() {
+        let test_dir = "src/api/tests/test_config/test_remote_fetch";
+        let _ = fs::remove_dir_all(test_dir);
+        fs::create_dir_all(test_dir).unwrap();
+
+        let (base, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 14\r\n\r\n- colour = red",
+            "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        ]);
+        let url = format!("{}/base.rules", base);
+
+        let file = fetch(test_dir, &url).unwrap();
+        assert_eq!(file.name, "base.rules");
+        assert_eq!(file.content.as_deref(), Some("- colour = red"));
+        assert_eq!(file.validators.etag.as_deref(), Some("\"v1\""));
+
+        // Validators are only sent once the file is on disk
+        fs::write(format!("{}/base.rules", test_dir), "- colour = red").unwrap();
+        save_validators(test_dir, &file).unwrap();
+        let file = fetch(test_dir, &url).unwrap();
+        assert_eq!(file.content, None);
+
+        let result = fetch(test_dir, &url);
+        if let Err(RulesError::IoError(e)) = result {
+            assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        } else {
+            panic!("Expected NotFound for HTTP 404");
+        }
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+
+        let _ = fs::remove_dir_all(test_dir);
+    }
+}
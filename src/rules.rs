@@ -1,9 +1,35 @@
+use crate::api::cache::{self, EvaluationCache};
+use crate::api::policy::{self, WritePolicy};
+use crate::api::write;
+use crate::api::{audit, changeset, history};
+use crate::ast::AstRule;
+use crate::engine::{Engine, RuleSet};
 use crate::err::RulesError;
-use crate::parser::rules::RuleParser;
+use crate::parser::objects;
+use crate::parser::plugin::{PluginMap, RulePlugin};
+use crate::parser::rules::{self, RuleParser};
 use crate::parser::tags;
-use crate::types::{TagName, TagValues};
-use crate::utils::file;
-use std::collections::HashMap;
+use crate::parser::types::dot_escape;
+use crate::query::QueryTerm;
+use crate::testing::RuleGenerator;
+use crate::types::{
+    self, AuditEntry, AuditOperation, CacheOptions, CacheStats, CaseFolding, ChangeSet, Clause,
+    ClauseHits, CnfRule, CoverageReport, Diagnostic, DuplicateTagPolicy, EvalContext, EvalMode,
+    EvaluationOptions, EvaluationReport, EvaluationTrace, ExclusionConflict, ExclusiveGroup,
+    ExplanationNode, FileVersion, GrammarVersion, InlineTestResult, LintWarning, LoadedObject,
+    LoadedRule, MatchExplanation, MatchedRule, MissingTagPolicy, Object, ObjectId, ObjectMatches,
+    OperatorPrecedence, RequiredTagPolicy, RuleAnalysis, RuleConflict, RuleCoverage, RuleDuplicate,
+    RuleExamples, RuleHits, RuleId, RuleLimits, RuleStats, ShadowDivergence, ShadowReport,
+    Subsumption, TagConstraintsMap, TagLimits, TagLoadOptions, TagName, TagUsage, TagValues, Truth,
+    TruthTable, UnknownPolicy, ValidationReport, ValueRemovalImpact, WritePayload, WriteRequest,
+};
+use crate::utils::{file, metrics, string, time};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 /// Main API for the rules engine.
 ///
@@ -31,6 +57,34 @@ pub struct Rules {
     config_dir: String,
     /// Cached tags loaded from config files
     tags: HashMap<TagName, TagValues>,
+    /// Cached constraints (e.g. value patterns) of the loaded tags
+    tag_constraints: TagConstraintsMap,
+    /// Groups of values or tags which can't appear together on an object
+    exclusive_groups: Vec<ExclusiveGroup>,
+    /// How tags are loaded -- duplicates, case-folding and size limits
+    tag_load_options: TagLoadOptions,
+    /// Warnings produced by the last tag load
+    tag_diagnostics: Vec<Diagnostic>,
+    /// Options passed to the evaluator
+    evaluation_options: EvaluationOptions,
+    /// How tightly '&', '|' and '->' bind when rules are parsed
+    operator_precedence: OperatorPrecedence,
+    /// How deeply rules may nest groups and how many tokens they may have
+    rule_limits: RuleLimits,
+    /// Who config writes are attributed to in the audit log
+    audit_actor: Option<String>,
+    /// Consulted before every config write, if set
+    write_policy: Option<Box<dyn WritePolicy>>,
+    /// Functions rules may call, by name
+    plugins: PluginMap,
+    /// Results of earlier evaluations, if caching is enabled
+    cache: Option<Mutex<EvaluationCache>>,
+    /// The rules compiled for evaluation by the last tag load, dropped by writes and setting
+    /// changes and compiled again when next needed
+    ruleset: Mutex<Option<Arc<RuleSet>>>,
+    /// Sends matches of rules annotated with `@notify` to webhooks, if set
+    #[cfg(feature = "webhooks")]
+    notifier: Option<crate::webhooks::Notifier>,
 }
 
 impl Rules {
@@ -47,13 +101,30 @@ impl Rules {
         Self {
             config_dir: config_dir.into(),
             tags: HashMap::new(),
+            tag_constraints: HashMap::new(),
+            exclusive_groups: Vec::new(),
+            tag_load_options: TagLoadOptions::default(),
+            tag_diagnostics: Vec::new(),
+            evaluation_options: EvaluationOptions::default(),
+            operator_precedence: OperatorPrecedence::default(),
+            rule_limits: RuleLimits::default(),
+            audit_actor: None,
+            write_policy: None,
+            plugins: HashMap::new(),
+            cache: None,
+            ruleset: Mutex::new(None),
+            #[cfg(feature = "webhooks")]
+            notifier: None,
         }
     }
 
     /// Loads all tags from .tags files in the config directory.
     ///
     /// This should be called after creating a new Rules instance to populate
-    /// the tag definitions needed for rule validation.
+    /// the tag definitions needed for rule validation. The rules are compiled
+    /// against the new tags too, so evaluating objects doesn't read the
+    /// .rules files. Invalid rules don't fail the load, but are reported when
+    /// an object is evaluated.
     ///
     /// # Returns
     /// * `Ok(())` if tags were loaded successfully
@@ -66,154 +137,3284 @@ impl Rules {
     /// ```
     pub fn load_tags(&mut self) -> Result<(), RulesError> {
         let pattern = format!("{}/*.tags", self.config_dir);
-        let all_files = file::read_files_in_dir(&pattern)?;
+        let parsed = tags::parse_tags_with_options(&pattern, self.tag_load_options);
+        metrics::record_reload(parsed.is_ok());
+        let parsed = parsed?;
 
         // Clear existing tags
         self.tags.clear();
+        self.tag_constraints.clear();
 
-        for file_content in all_files.iter() {
-            for line in file_content.lines() {
-                if file::line_blank_or_comment(line) {
+        // Names and values are already folded per the case-folding policy
+        for tag in parsed.tags {
+            self.tag_constraints
+                .insert(tag.name.clone(), tag.constraints);
+            self.tags.insert(tag.name, tag.values);
+        }
+
+        self.exclusive_groups = parsed.exclusive_groups;
+        self.tag_diagnostics = parsed.diagnostics;
+        self.invalidate_cache();
+        let compiled = self.compile().ok().map(Arc::new);
+        *self.ruleset.lock().unwrap_or_else(|e| e.into_inner()) = compiled;
+
+        Ok(())
+    }
+
+    /// Sets how tags defined more than once are handled by [`Rules::load_tags`].
+    ///
+    /// Defaults to [`DuplicateTagPolicy::Merge`].
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.set_duplicate_tag_policy(DuplicateTagPolicy::Error);
+    /// rules.load_tags()?;
+    /// ```
+    pub fn set_duplicate_tag_policy(&mut self, policy: DuplicateTagPolicy) {
+        self.tag_load_options.duplicate_tags = policy;
+    }
+
+    /// Sets how tag names and values are cased by [`Rules::load_tags`], and so
+    /// how rules and objects must match them.
+    ///
+    /// Defaults to [`CaseFolding::Lowercase`], where `- Colour: Red` loads as
+    /// `colour: red` and matching ignores case. [`CaseFolding::Preserve`] keeps
+    /// tags as written and makes matching case-sensitive.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.set_case_folding(CaseFolding::Preserve);
+    /// rules.load_tags()?;
+    /// ```
+    pub fn set_case_folding(&mut self, case_folding: CaseFolding) {
+        self.tag_load_options.case_folding = case_folding;
+    }
+
+    /// Sets caps on the total number of tags and values, enforced by
+    /// [`Rules::load_tags`] and [`Rules::write_tag`].
+    ///
+    /// Defaults to no limits. Per-tag limits are declared in the tags file
+    /// instead, e.g. `- colour (max_values=20, max_length=16): red`.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.set_tag_limits(TagLimits {
+    ///     max_tags: Some(500),
+    ///     max_values: Some(10_000),
+    /// });
+    /// rules.load_tags()?;
+    /// ```
+    pub fn set_tag_limits(&mut self, limits: TagLimits) {
+        self.tag_load_options.limits = limits;
+    }
+
+    /// Sets caps on the size of each rule, so a pathological rule is rejected
+    /// with [`RulesError::RuleLimitError`] rather than exhausting the stack
+    /// when it's checked or evaluated.
+    ///
+    /// Defaults to groups nested at most 64 deep and at most 2,000 tokens
+    /// per rule, counting ranges and flags once expanded. The limits apply
+    /// to every rule parsed from then on, including [`Rules::compile`] and
+    /// [`Rules::engine`].
+    ///
+    /// Checking, evaluating, analysing, rendering and serializing a rule loop
+    /// down chains of one operator, so `max_length` can be raised well past
+    /// the default. Explaining or tracing a rule, and debug-printing its
+    /// syntax tree, still recurse once per operand, and can exhaust the stack
+    /// and abort the process for rules of tens of thousands of tokens.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.set_rule_limits(RuleLimits {
+    ///     max_depth: 16,
+    ///     max_length: 500,
+    /// });
+    /// rules.validate_rules()?;
+    /// ```
+    pub fn set_rule_limits(&mut self, limits: RuleLimits) {
+        self.rule_limits = limits;
+        self.invalidate_cache();
+    }
+
+    /// Sets how evaluation treats objects missing a tag marked `(required)`.
+    ///
+    /// Defaults to [`RequiredTagPolicy::NoMatch`], where such objects match no
+    /// rules. [`RequiredTagPolicy::Error`] fails evaluation instead. Every
+    /// evaluation follows the policy, from [`Rules::evaluate`] to
+    /// [`Rules::matching_rules`], [`Rules::evaluate_object`] and
+    /// [`Rules::evaluate_with_context`].
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.set_required_tag_policy(RequiredTagPolicy::Error);
+    /// rules.evaluate()?;
+    /// ```
+    pub fn set_required_tag_policy(&mut self, policy: RequiredTagPolicy) {
+        self.evaluation_options.required_tags = policy;
+    }
+
+    /// Sets whether evaluation finds every rule an object matches, or stops
+    /// at the first.
+    ///
+    /// Defaults to [`EvalMode::AllMatches`]. [`EvalMode::FirstMatch`] returns
+    /// only the highest priority matching rule, ties going to the first in
+    /// file then line order, for
+    /// classification, from [`Rules::matching_rules`], [`Rules::evaluate`],
+    /// [`Rules::compile`] and the rest. Cached results are dropped.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.set_eval_mode(EvalMode::FirstMatch);
+    /// let category = rules.matching_rules(&obj)?.into_iter().next();
+    /// ```
+    pub fn set_eval_mode(&mut self, mode: EvalMode) {
+        self.evaluation_options.mode = mode;
+        self.invalidate_cache();
+    }
+
+    /// Sets how evaluation treats object values outside a tag's listed
+    /// values, which only open tags allow.
+    ///
+    /// Defaults to [`UnknownPolicy::TwoValued`], comparing them like any
+    /// other value. Under [`UnknownPolicy::UnknownIsFalse`] and
+    /// [`UnknownPolicy::UnknownIsTrue`], comparisons no listed value decides
+    /// are Unknown, combined with three-valued logic, e.g.
+    /// `Unknown | true` is true. Rules still Unknown at the end don't match,
+    /// or match, respectively. Cached results are dropped.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// // - vendor (open): acme, globex
+    /// rules.set_unknown_policy(UnknownPolicy::UnknownIsFalse);
+    /// // { vendor: initech } no longer matches "- vendor ! acme"
+    /// let matched = rules.matching_rules(&obj)?;
+    /// ```
+    pub fn set_unknown_policy(&mut self, policy: UnknownPolicy) {
+        self.evaluation_options.unknown_values = policy;
+        self.invalidate_cache();
+    }
+
+    /// Sets how evaluation treats a comparison on a tag the object doesn't
+    /// have, e.g. `- size = large` against `{colour: red}`.
+    ///
+    /// Defaults to [`MissingTagPolicy::NoValues`], comparing as if the tag
+    /// had no values, so `=`, `<` and `>` fail and `!` holds.
+    /// [`MissingTagPolicy::FailClause`] fails every such comparison,
+    /// [`MissingTagPolicy::SkipClause`] lets every such comparison hold, and
+    /// [`MissingTagPolicy::Error`] fails evaluation of a rule making one. It
+    /// applies to [`Rules::matching_rules`], [`Rules::evaluate`],
+    /// [`Rules::compile`] and the rest. Cached results are dropped.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.set_missing_tag_policy(MissingTagPolicy::Error);
+    /// let report = rules.evaluate()?;
+    /// ```
+    pub fn set_missing_tag_policy(&mut self, policy: MissingTagPolicy) {
+        self.evaluation_options.missing_tags = policy;
+        self.invalidate_cache();
+    }
+
+    /// Sets how tightly the logical operators `&`, `|` and `->` bind when
+    /// rules are parsed, a higher level binding tighter.
+    ///
+    /// Defaults to `&` at 1, `|` at 0 and `->` at -1, so `a | b & c` is
+    /// `a | (b & c)`. Comparisons always bind tighter than any of them, and
+    /// operators at the same level group to the right. The table must give a
+    /// level to each of the three and nothing else. It applies to every rule
+    /// parsed from then on, including [`Rules::compile`] and
+    /// [`Rules::engine`]. Cached results are dropped.
+    ///
+    /// # Arguments
+    /// * `precedence` - Level of each logical operator
+    ///
+    /// # Returns
+    /// * `Ok(())` if the table was set
+    /// * `Err(RulesError)` if it misses one of the operators or names an unknown one
+    ///
+    /// # Examples
+    /// ```ignore
+    /// // "- colour = red & size = large | shape = circle" now needs the colour
+    /// rules.set_operator_precedence(OperatorPrecedence::default().with("|", 2))?;
+    /// ```
+    pub fn set_operator_precedence(
+        &mut self,
+        precedence: OperatorPrecedence,
+    ) -> Result<(), RulesError> {
+        precedence.check()?;
+        self.operator_precedence = precedence;
+        self.invalidate_cache();
+        Ok(())
+    }
+
+    /// The precedence of the logical operators, as set by
+    /// [`Rules::set_operator_precedence`].
+    pub fn operator_precedence(&self) -> &OperatorPrecedence {
+        &self.operator_precedence
+    }
+
+    /// Sets who later config writes are attributed to in the audit log, such
+    /// as a user name or service. `None` records writes without an actor.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.set_audit_actor(Some("alice"));
+    /// rules.write_rule("my_rules", "- colour = red")?;
+    /// ```
+    pub fn set_audit_actor(&mut self, actor: Option<&str>) {
+        self.audit_actor = actor.map(String::from);
+    }
+
+    /// Sets a policy consulted before every config write, which can deny it.
+    ///
+    /// The policy is given the operation, the file, the actor set with
+    /// [`Rules::set_audit_actor`] and what is being written. A denied write
+    /// fails with a permission denied error and nothing is written. Closures
+    /// of type `Fn(&WriteRequest) -> Result<(), String>` can be used as
+    /// policies.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.set_write_policy(|request: &WriteRequest| {
+    ///     if request.file == "safety.rules" && request.actor != Some("admin") {
+    ///         return Err("only admin may change safety rules".to_string());
+    ///     }
+    ///     Ok(())
+    /// });
+    /// ```
+    pub fn set_write_policy(&mut self, policy: impl WritePolicy + 'static) {
+        self.write_policy = Some(Box::new(policy));
+    }
+
+    /// Removes the write policy, so every write is allowed.
+    pub fn clear_write_policy(&mut self) {
+        self.write_policy = None;
+    }
+
+    fn check_write(
+        &self,
+        operation: AuditOperation,
+        file: &str,
+        payload: WritePayload,
+    ) -> Result<(), RulesError> {
+        policy::check(
+            self.write_policy.as_deref(),
+            &WriteRequest {
+                operation,
+                file,
+                actor: self.audit_actor.as_deref(),
+                payload,
+            },
+        )
+    }
+
+    /// Config writes recorded in the audit log, oldest first.
+    ///
+    /// Every `write_tag`, `write_rule`, `write_object`, `format_tags_file` and
+    /// `format_file` call appends an entry to `audit.log` in the config directory, with the
+    /// time, operation, file, a hash of the file's new content and the actor.
+    ///
+    /// # Arguments
+    /// * `file` - Only return writes to this file, e.g. `"my_tags.tags"`
+    ///
+    /// # Returns
+    /// * `Ok(Vec<AuditEntry>)` with the matching entries
+    /// * `Err(RulesError)` if the log can't be read or has an invalid entry
+    ///
+    /// # Examples
+    /// ```ignore
+    /// for entry in rules.audit_log(Some("my_tags.tags"))? {
+    ///     println!("{} {:?} by {:?}", entry.timestamp, entry.operation, entry.actor);
+    /// }
+    /// ```
+    pub fn audit_log(&self, file: Option<&str>) -> Result<Vec<AuditEntry>, RulesError> {
+        let mut entries = audit::read(&self.config_dir)?;
+        if let Some(file) = file {
+            entries.retain(|entry| entry.file == file);
+        }
+        Ok(entries)
+    }
+
+    // Keep the file's content before a write, so manual edits since the last write aren't lost
+    fn snapshot(&self, file: &str) -> Result<(), RulesError> {
+        history::snapshot(&self.config_dir, file)
+    }
+
+    fn record_write(
+        &self,
+        operation: AuditOperation,
+        file: &str,
+        rule_id: Option<&RuleId>,
+    ) -> Result<(), RulesError> {
+        self.invalidate_cache();
+        self.snapshot(file)?;
+        audit::append(
+            &self.config_dir,
+            operation,
+            file,
+            self.audit_actor.as_deref(),
+            rule_id,
+        )?;
+        Ok(())
+    }
+
+    /// Saved versions of a config file, oldest first.
+    ///
+    /// Every write through this API keeps the file's content before and after
+    /// the write under `.history/` in the config directory. Identical content
+    /// is stored once, and a version is only added when the content changes.
+    ///
+    /// # Arguments
+    /// * `file` - Name of the file in the config directory, e.g. `"my_rules.rules"`
+    ///
+    /// # Returns
+    /// * `Ok(Vec<FileVersion>)` with every version, empty if the file has none
+    /// * `Err(RulesError)` if the history can't be read
+    ///
+    /// # Examples
+    /// ```ignore
+    /// for version in rules.history("my_rules.rules")? {
+    ///     println!("{} {} {}", version.version, version.timestamp, version.content_hash);
+    /// }
+    /// ```
+    pub fn history(&self, file: &str) -> Result<Vec<FileVersion>, RulesError> {
+        history::read(&self.config_dir, file)
+    }
+
+    /// Restores a config file to an earlier version.
+    ///
+    /// The restored content is checked first: a .tags file must parse, and
+    /// every rule must still be valid against the resulting tags. A restored
+    /// .rules file must contain only valid rules. The current content is kept
+    /// as a version, so a rollback can itself be rolled back.
+    ///
+    /// # Arguments
+    /// * `file` - Name of the file in the config directory, e.g. `"my_rules.rules"`
+    /// * `version` - Version to restore, as listed by [`Rules::history`]
+    ///
+    /// # Returns
+    /// * `Ok(())` if the file was restored
+    /// * `Err(RulesError)` if the version doesn't exist or would leave the
+    ///   config invalid, in which case the file is left untouched
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let versions = rules.history("my_rules.rules")?;
+    /// rules.rollback("my_rules.rules", versions[0].version)?;
+    /// ```
+    pub fn rollback(&mut self, file: &str, version: usize) -> Result<(), RulesError> {
+        check_config_file_name(file)?;
+        self.check_write(
+            AuditOperation::Rollback,
+            file,
+            WritePayload::Rollback { version },
+        )?;
+
+        let content = history::content(&self.config_dir, file, version)?;
+        let files = [(file.to_string(), content)];
+        self.check_restorable(&files)?;
+        self.replace_files(AuditOperation::Rollback, &files)
+    }
+
+    /// Makes a draft rule active, so normal evaluation matches it.
+    ///
+    /// Drafts are rules annotated with `@draft <name>`. They're validated and
+    /// listed like any other rule, and evaluated by [`Rules::shadow_compare`],
+    /// but [`Rules::matching_rules`] leaves them out. Promoting one removes
+    /// its `@draft` annotation, after checking the rule is still valid, and
+    /// records the change in the audit log as `promote_rule`.
+    ///
+    /// # Arguments
+    /// * `name` - Name given in the `@draft` annotation, e.g. `"red_large"`
+    ///
+    /// # Returns
+    /// * `Ok(LoadedRule)` with the rule as it now stands
+    /// * `Err(RulesError)` if no draft or more than one has the name, the rule
+    ///   is invalid or the write policy denies it, leaving the file untouched
+    ///
+    /// # Examples
+    /// ```ignore
+    /// // config/my_rules.rules
+    /// // @draft red_large
+    /// // - colour = red & size = large
+    /// let report = rules.shadow_compare(&rules, &objects)?;
+    /// rules.promote_rule("red_large")?;
+    /// ```
+    pub fn promote_rule(&mut self, name: &str) -> Result<LoadedRule, RulesError> {
+        let drafts: Vec<LoadedRule> = self
+            .rules()?
+            .into_iter()
+            .filter(|loaded| loaded.draft_name() == Some(name))
+            .collect();
+
+        let draft = match drafts.as_slice() {
+            [draft] => draft,
+            [] => {
+                return Err(RulesError::IoError(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No draft rule named '{}'", name),
+                )));
+            }
+            _ => {
+                let locations: Vec<String> = drafts
+                    .iter()
+                    .map(|loaded| format!("{}:{}", loaded.file, loaded.line))
+                    .collect();
+                return Err(RulesError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Draft '{}' is defined more than once: {}",
+                        name,
+                        locations.join(", ")
+                    ),
+                )));
+            }
+        };
+
+        let file = changeset::file_name(&draft.file);
+        check_config_file_name(&file)?;
+        self.check_write(
+            AuditOperation::PromoteRule,
+            &file,
+            WritePayload::PromoteRule {
+                name,
+                rule: &draft.rule,
+            },
+        )?;
+
+        let content = fs::read_to_string(&draft.file)?;
+        let promoted_content = rules::remove_annotation(&content, draft.line, types::DRAFT);
+        let removed_lines = content.lines().count() - promoted_content.lines().count();
+        let files = [(file, promoted_content)];
+        self.check_restorable(&files)?;
+
+        let mut promoted = draft.clone();
+        promoted.line -= removed_lines;
+        promoted
+            .annotations
+            .retain(|annotation| annotation.name != types::DRAFT);
+        self.replace_files_for_rule(AuditOperation::PromoteRule, &files, Some(&promoted.id()))?;
+        Ok(promoted)
+    }
+
+    /// Removes a named rule from its file, along with its annotations and the
+    /// `#test:` lines under it.
+    ///
+    /// The rest of the config is checked as it would be without the rule
+    /// before the file is written through the history, and the change is
+    /// recorded in the audit log as `delete_rule`.
+    ///
+    /// # Arguments
+    /// * `name` - Name the rule is written with, e.g. `"promo_1"`
+    ///
+    /// # Returns
+    /// * `Ok(LoadedRule)` with the rule as it was before it was removed
+    /// * `Err(RulesError)` if no rule has the name or the write policy denies
+    ///   it, leaving the file untouched
+    ///
+    /// # Examples
+    /// ```ignore
+    /// // config/my_rules.rules
+    /// // promo_1: - colour = red & size = large
+    /// let deleted = rules.delete_rule("promo_1")?;
+    /// println!("removed {}", deleted.rule);
+    /// ```
+    pub fn delete_rule(&mut self, name: &str) -> Result<LoadedRule, RulesError> {
+        let rule = self.find_rule_by_name(name)?.ok_or_else(|| {
+            RulesError::IoError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No rule named '{}'", name),
+            ))
+        })?;
+
+        let file = changeset::file_name(&rule.file);
+        check_config_file_name(&file)?;
+        self.check_write(
+            AuditOperation::DeleteRule,
+            &file,
+            WritePayload::DeleteRule {
+                name,
+                rule: &rule.rule,
+            },
+        )?;
+
+        let content = fs::read_to_string(&rule.file)?;
+        let files = [(file, rules::remove_rule(&content, rule.line))];
+        self.check_restorable(&files)?;
+
+        self.replace_files_for_rule(AuditOperation::DeleteRule, &files, Some(&rule.id()))?;
+        Ok(rule)
+    }
+
+    /// Removes every rule which duplicates an earlier one in its file, as
+    /// listed by [`Rules::duplicate_rules`], along with its annotations and
+    /// the `#test:` lines under it.
+    ///
+    /// The first of each set of duplicates is kept. The rest of the config is
+    /// checked as it would be without the removed rules before anything is
+    /// saved, and each changed file is recorded in the audit log as
+    /// `dedup_rules`.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<RuleDuplicate>)` with the rules removed, as they were before, empty if
+    ///   there were none
+    /// * `Err(RulesError)` if a rule is invalid or the write policy denies it, leaving
+    ///   every file untouched
+    ///
+    /// # Examples
+    /// ```ignore
+    /// for removed in rules.dedup_rules()? {
+    ///     println!("removed {}", removed);
+    /// }
+    /// ```
+    pub fn dedup_rules(&mut self) -> Result<Vec<RuleDuplicate>, RulesError> {
+        let duplicates = self.duplicate_rules()?;
+        let mut lines_by_path: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for duplicate in &duplicates {
+            lines_by_path
+                .entry(&duplicate.rule.file)
+                .or_default()
+                .push(duplicate.rule.line);
+        }
+
+        let mut files = Vec::new();
+        for (path, lines) in lines_by_path {
+            // Removing the last rule first keeps the lines of those above it
+            let mut content = fs::read_to_string(path)?;
+            for line in lines.iter().rev() {
+                content = rules::remove_rule(&content, *line);
+            }
+            files.push((changeset::file_name(path), content));
+        }
+
+        for (file, _) in &files {
+            check_config_file_name(file)?;
+            self.check_write(AuditOperation::DedupRules, file, WritePayload::Format)?;
+        }
+        self.check_restorable(&files)?;
+        self.replace_files(AuditOperation::DedupRules, &files)?;
+
+        Ok(duplicates)
+    }
+
+    /// Rewrites every rule in the config directory's .rules files in another
+    /// grammar version, e.g. from the legacy `colour ! red` to the v2
+    /// `colour != "red"`.
+    ///
+    /// Spacing, comma lists, flag shorthand, plugin calls, annotations and
+    /// comments are kept as they are. Each rewritten rule is checked to match
+    /// exactly the same objects as the original before anything is saved, and
+    /// each changed file is recorded in the audit log as `migrate_rules`.
+    ///
+    /// # Arguments
+    /// * `to` - Grammar version to rewrite the rules in
+    ///
+    /// # Returns
+    /// * `Ok(usize)` with how many rules were rewritten, 0 if all were already in `to`
+    /// * `Err(RulesError)` if a rule is invalid, a rewritten rule isn't equivalent or the
+    ///   write policy denies it, leaving every file untouched
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let migrated = rules.migrate_syntax(GrammarVersion::V2)?;
+    /// println!("Migrated {} rules", migrated);
+    /// ```
+    pub fn migrate_syntax(&mut self, to: GrammarVersion) -> Result<usize, RulesError> {
+        let parser = self.rule_parser();
+        let pattern = format!("{}/*.rules", self.config_dir);
+        let mut files: Vec<(String, String)> = Vec::new();
+        let mut migrated = 0;
+
+        for (path, content) in file::read_files_in_dir_with_paths(&pattern)? {
+            let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+            let mut changed = false;
+
+            for loaded in rules::parse_rules_from_str(&path, &content)? {
+                // Rules using macros are left as written, the macros being in the current syntax
+                if lines[loaded.line - 1].trim() != loaded.rule {
+                    continue;
+                }
+
+                let located = |e: RulesError| {
+                    e.map_rule_message(|msg| format!("{}:{}: {}", path, loaded.line, msg))
+                };
+
+                let rule = rules::migrate_rule(&loaded.rule, to).map_err(located)?;
+                if rule == loaded.rule {
+                    continue;
+                }
+                if !parser.is_equivalent(&loaded.rule, &rule).map_err(located)? {
+                    return Err(RulesError::RuleParseError(format!(
+                        "{}:{}: Migrated rule isn't equivalent to the original: {} became {}",
+                        path, loaded.line, loaded.rule, rule
+                    )));
+                }
+
+                let line = &mut lines[loaded.line - 1];
+                *line = line.replacen(&loaded.rule, &rule, 1);
+                changed = true;
+                migrated += 1;
+            }
+
+            if changed {
+                if content.ends_with('\n') {
+                    lines.push(String::new());
+                }
+                files.push((changeset::file_name(&path), lines.join("\n")));
+            }
+        }
+
+        for (file, _) in &files {
+            check_config_file_name(file)?;
+            self.check_write(AuditOperation::MigrateRules, file, WritePayload::Format)?;
+        }
+        self.check_restorable(&files)?;
+        self.replace_files(AuditOperation::MigrateRules, &files)?;
+
+        Ok(migrated)
+    }
+
+    /// Rewrites a .rules file in the config directory into canonical form.
+    ///
+    /// Each rule is written with single spaces around its operators and
+    /// parentheses only where precedence needs them, e.g.
+    /// `-(size=small|size=large)&colour=red` becomes
+    /// `- (size = small | size = large) & colour = red`. Comma lists, ranges,
+    /// flags and `!` groups are written out as the parser expands them.
+    /// Names, priorities, outcomes, indentation, annotations and comments are
+    /// kept, and rules using macros are left as written. The file is recorded
+    /// in the audit log as `format_rules` if anything changed.
+    ///
+    /// # Arguments
+    /// * `file_name` - Name of the file (with or without .rules extension)
+    ///
+    /// # Returns
+    /// * `Ok(usize)` with how many rules were rewritten, 0 if all were already canonical
+    /// * `Err(RulesError)` if the file can't be read, a rule is invalid or the write
+    ///   policy denies it, leaving the file untouched
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let formatted = rules.format_file("my_rules")?;
+    /// println!("Formatted {} rules", formatted);
+    /// ```
+    pub fn format_file(&mut self, file_name: &str) -> Result<usize, RulesError> {
+        let file = write::rule::normalise_filename(file_name);
+        check_config_file_name(&file)?;
+        let path = format!("{}/{}", self.config_dir, file);
+        let content = fs::read_to_string(&path)?;
+
+        let parser = self.rule_parser();
+        let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+        let mut formatted = 0;
+
+        for loaded in rules::parse_rules_from_str(&path, &content)? {
+            // Rules using macros are left as written, like migrate_syntax leaves them
+            if lines[loaded.line - 1].trim() != loaded.rule {
+                continue;
+            }
+
+            let rule = parser
+                .format_rule(&loaded.rule)
+                .map_err(|e| rules::rule_error_at(&loaded, e))?;
+            if rule != loaded.rule {
+                let line = &mut lines[loaded.line - 1];
+                *line = line.replacen(&loaded.rule, &rule, 1);
+                formatted += 1;
+            }
+        }
+
+        if formatted == 0 {
+            return Ok(0);
+        }
+        if content.ends_with('\n') {
+            lines.push(String::new());
+        }
+
+        let files = [(file, lines.join("\n"))];
+        self.check_write(
+            AuditOperation::FormatRules,
+            &files[0].0,
+            WritePayload::Format,
+        )?;
+        self.check_restorable(&files)?;
+        self.replace_files(AuditOperation::FormatRules, &files)?;
+
+        Ok(formatted)
+    }
+
+    // Check that config files can be replaced with new content, as a roll out would -- e.g.
+    // [("base.rules", "- colour = red")]
+    pub(crate) fn check_roll_out(&self, files: &[(String, String)]) -> Result<(), RulesError> {
+        for (file, content) in files {
+            check_config_file_name(file)?;
+            self.check_write(
+                AuditOperation::RollOut,
+                file,
+                WritePayload::RollOut { content },
+            )?;
+        }
+
+        self.check_restorable(files)
+    }
+
+    // Overwrite config files, keeping their old content as versions. The files must already
+    // have passed check_restorable.
+    pub(crate) fn replace_files(
+        &mut self,
+        operation: AuditOperation,
+        files: &[(String, String)],
+    ) -> Result<(), RulesError> {
+        self.replace_files_for_rule(operation, files, None)
+    }
+
+    // As replace_files, recording the rule written in the audit log -- e.g. a promoted draft
+    fn replace_files_for_rule(
+        &mut self,
+        operation: AuditOperation,
+        files: &[(String, String)],
+        rule_id: Option<&RuleId>,
+    ) -> Result<(), RulesError> {
+        for (file, content) in files {
+            self.snapshot(file)?;
+            fs::write(Path::new(&self.config_dir).join(file), content)?;
+            self.record_write(operation, file, rule_id)?;
+        }
+
+        if files.iter().any(|(file, _)| file.ends_with(".tags")) {
+            self.load_tags()?;
+        }
+
+        Ok(())
+    }
+
+    // Check every rule against the config as it would be with each file set to its new content
+    fn check_restorable(&self, files: &[(String, String)]) -> Result<(), RulesError> {
+        let parser = if files.iter().any(|(file, _)| file.ends_with(".tags")) {
+            let pattern = format!("{}/*.tags", self.config_dir);
+            let sources = with_replacements(
+                file::read_files_in_dir_with_paths(&pattern)?,
+                files,
+                ".tags",
+            );
+
+            let parsed = tags::parse_tags_from_sources(sources, self.tag_load_options)?;
+            RuleParser::from_tags(parsed.tags)
+                .with_case_folding(self.tag_load_options.case_folding)
+                .with_plugins(self.plugins.clone())
+        } else {
+            self.rule_parser()
+        };
+
+        let pattern = format!("{}/*.rules", self.config_dir);
+        let sources = with_replacements(
+            file::read_files_in_dir_with_paths(&pattern)?,
+            files,
+            ".rules",
+        );
+        let rules = rules::parse_rules_with_includes(&self.config_dir, sources)?;
+        rules::check_rule_names(&rules)?;
+
+        for loaded in rules {
+            parser
+                .validate_rule(&loaded.rule)
+                .map_err(|e| rules::rule_error_at(&loaded, e))?;
+        }
+
+        for (file, content) in files.iter().filter(|(file, _)| file.ends_with(".yaml")) {
+            objects::parse_objects_from_str(file, content, self.tag_load_options.case_folding)?;
+        }
+
+        Ok(())
+    }
+
+    /// Pulls config files from remote sources into the config directory.
+    ///
+    /// Each URL names one .tags, .rules or .yaml file, stored under the last
+    /// segment of its path. The ETag and Last-Modified of every pulled file
+    /// are kept under `.remote/` in the config directory, so pulling again
+    /// only downloads files that have changed. Changed files are checked
+    /// together as a rollback would be, then written through the history,
+    /// audit log and write policy like any other write.
+    ///
+    /// `s3://bucket/key` URLs are supported with the `s3` feature, using
+    /// credentials from the standard `AWS_*` environment variables.
+    ///
+    /// # Arguments
+    /// * `urls` - Files to pull, e.g. `["https://config.example.com/base.rules"]`
+    ///
+    /// # Returns
+    /// * `Ok(Vec<String>)` with the names of the files that changed
+    /// * `Err(RulesError)` if a file can't be fetched or would leave the config
+    ///   invalid, in which case no file is written
+    ///
+    /// # Examples
+    /// ```ignore
+    /// // At startup and on every refresh
+    /// let changed = rules.pull(&["https://config.example.com/base.rules"])?;
+    /// ```
+    #[cfg(feature = "remote")]
+    pub fn pull(&mut self, urls: &[&str]) -> Result<Vec<String>, RulesError> {
+        let mut fetched: Vec<crate::remote::RemoteFile> = Vec::new();
+        for url in urls {
+            let file = crate::remote::fetch(&self.config_dir, url)?;
+            if let Some(other) = fetched.iter().find(|other| other.name == file.name) {
+                return Err(RulesError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "'{}' and '{}' would both be pulled into {}",
+                        other.url, file.url, file.name
+                    ),
+                )));
+            }
+            fetched.push(file);
+        }
+
+        let mut changed = Vec::new();
+        for file in &fetched {
+            let Some(content) = &file.content else {
+                continue;
+            };
+            check_config_file_name(&file.name)?;
+
+            let current = fs::read_to_string(Path::new(&self.config_dir).join(&file.name)).ok();
+            if current.as_ref() == Some(content) {
+                continue;
+            }
+
+            self.check_write(
+                AuditOperation::Pull,
+                &file.name,
+                WritePayload::Pull {
+                    url: &file.url,
+                    content,
+                },
+            )?;
+            changed.push((file.name.clone(), content.clone()));
+        }
+
+        self.check_restorable(&changed)?;
+        self.replace_files(AuditOperation::Pull, &changed)?;
+
+        for file in fetched.iter().filter(|file| file.content.is_some()) {
+            crate::remote::save_validators(&self.config_dir, file)?;
+        }
+
+        Ok(changed.into_iter().map(|(file, _)| file).collect())
+    }
+
+    /// Differences between this configuration and `other`, ignoring formatting.
+    ///
+    /// Tags are compared by name and definition, so reordering the values of
+    /// an unordered tag isn't a change. Rules are compared per file in a
+    /// normal form, so reformatted or rewritten but equivalent rules are
+    /// unchanged (see [`Rules::is_equivalent`]), and
+    /// objects are compared by file, type and position. Both instances should
+    /// have their tags loaded.
+    ///
+    /// # Arguments
+    /// * `other` - The configuration to compare against, e.g. a proposed change
+    ///
+    /// # Returns
+    /// * `Ok(ChangeSet)` with what `other` adds, removes and modifies
+    /// * `Err(RulesError)` if a rules or objects file can't be read
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let current = Rules::new("config");
+    /// let proposed = Rules::new("proposed");
+    /// let changes = current.changeset(&proposed)?;
+    /// println!("{}", changes);
+    /// ```
+    pub fn changeset(&self, other: &Rules) -> Result<ChangeSet, RulesError> {
+        Ok(ChangeSet {
+            tags: changeset::diff_tags(
+                &self.tags,
+                &self.tag_constraints,
+                &other.tags,
+                &other.tag_constraints,
+            ),
+            rules: {
+                let (old_rules, new_rules) =
+                    changeset::cancel_equivalent(self.canonical_rules()?, other.canonical_rules()?);
+                changeset::diff_rules(old_rules, new_rules)
+            },
+            objects: changeset::diff_objects(self.objects()?, other.objects()?),
+        })
+    }
+
+    // File name, canonical form and canonicalized form of each rule. Rules which don't parse against
+    // these tags are compared as written.
+    fn canonical_rules(&self) -> Result<Vec<(String, String, String)>, RulesError> {
+        let parser = self.rule_parser();
+
+        Ok(self
+            .rules()?
+            .into_iter()
+            .map(|loaded| {
+                let rule = parser
+                    .canonical_rule(&loaded.rule)
+                    .unwrap_or_else(|_| loaded.rule.clone());
+                let normal = parser.canonicalize(&loaded.rule).unwrap_or(loaded.rule);
+                (changeset::file_name(&loaded.file), rule, normal)
+            })
+            .collect())
+    }
+
+    /// Evaluates objects against this configuration and a candidate, and
+    /// reports every object whose matched rules differ.
+    ///
+    /// Matched rules are compared by file name and canonical form, so a rule
+    /// which was moved or reformatted in the candidate isn't a divergence.
+    /// Objects the candidate can't evaluate, e.g. because it removed a tag
+    /// value they use, are reported as divergences with the error. Both
+    /// configurations are evaluated at the same time, and neither is changed.
+    ///
+    /// The candidate's `@draft` rules are evaluated as if they were active,
+    /// so comparing a configuration with itself shows what promoting its
+    /// drafts would change.
+    ///
+    /// # Arguments
+    /// * `candidate` - The configuration to try out, with its tags loaded
+    /// * `objects` - Objects to evaluate, e.g. a sample of recent traffic
+    ///
+    /// # Returns
+    /// * `Ok(ShadowReport)` with the divergences, in object order
+    /// * `Err(RulesError)` if an object is invalid for this configuration
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let mut candidate = Rules::new("proposed");
+    /// candidate.load_tags()?;
+    /// let report = rules.shadow_compare(&candidate, &objects)?;
+    /// if !report.is_empty() {
+    ///     println!("{}", report);
+    /// }
+    /// ```
+    pub fn shadow_compare(
+        &self,
+        candidate: &Rules,
+        objects: &[Object],
+    ) -> Result<ShadowReport, RulesError> {
+        let timestamp = audit::unix_timestamp();
+        let (parser, candidate_parser) = (self.rule_parser(), candidate.rule_parser());
+        let keyed = |parser: &RuleParser, matched: Vec<LoadedRule>| {
+            matched
+                .into_iter()
+                .map(|loaded| {
+                    let rule = parser
+                        .canonicalize(&loaded.rule)
+                        .unwrap_or_else(|_| loaded.rule.clone());
+                    ((changeset::file_name(&loaded.file), rule), loaded)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut report = ShadowReport {
+            evaluated: objects.len(),
+            divergences: Vec::new(),
+        };
+
+        for (index, obj) in objects.iter().enumerate() {
+            let active = self.matching_rules_at(obj, timestamp)?;
+            let candidate_matched = candidate
+                .validate_object(obj)
+                .and_then(|_| candidate.matching_rules_with_drafts(obj, timestamp));
+            let (removed, added, candidate_error) = match candidate_matched {
+                Ok(matched) => {
+                    let (removed, added) = changeset::diff_matches(
+                        keyed(&parser, active),
+                        keyed(&candidate_parser, matched),
+                    );
+                    (removed, added, None)
+                }
+                Err(e) => (active, Vec::new(), Some(e.to_string())),
+            };
+
+            if !removed.is_empty() || !added.is_empty() || candidate_error.is_some() {
+                report.divergences.push(ShadowDivergence {
+                    index,
+                    object: obj.clone(),
+                    removed,
+                    added,
+                    candidate_error,
+                });
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn objects(&self) -> Result<Vec<LoadedObject>, RulesError> {
+        objects::parse_objects(
+            &format!("{}/*.yaml", self.config_dir),
+            self.tag_load_options.case_folding,
+        )
+    }
+
+    /// Registers a function rules can call on a tag's values.
+    ///
+    /// A rule calls a plugin by writing its name and arguments after a tag
+    /// name, with no space before the parenthesis. Rules calling unregistered
+    /// functions, or passing arguments the plugin's `validate` rejects, are
+    /// invalid. Registering a plugin with the same name replaces it.
+    ///
+    /// # Arguments
+    /// * `plugin` - The plugin, whose `name` is used in rules
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.register_plugin(Before);
+    /// rules.write_rule("my_rules", "- created_at before(2024-01-01)")?;
+    /// ```
+    pub fn register_plugin(&mut self, plugin: impl RulePlugin + 'static) {
+        self.plugins
+            .insert(plugin.name().to_string(), Arc::new(plugin));
+        self.invalidate_cache();
+    }
+
+    /// Caches the results of [`Rules::matching_rules`] per object.
+    ///
+    /// Objects are keyed by a hash of their tags and values, so evaluating an
+    /// identical object again returns the earlier result without reading the
+    /// rules. The cache is emptied by [`Rules::load_tags`] and by every write
+    /// through this API. Edits made to .rules files by hand take effect on the
+    /// next reload.
+    ///
+    /// # Arguments
+    /// * `options` - How many objects to keep, and for how long
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.enable_cache(CacheOptions {
+    ///     capacity: 10_000,
+    ///     ttl: Some(Duration::from_secs(60)),
+    /// });
+    /// ```
+    pub fn enable_cache(&mut self, options: CacheOptions) {
+        self.cache = Some(Mutex::new(EvaluationCache::new(options)));
+    }
+
+    /// Stops caching evaluation results, dropping any cached.
+    pub fn disable_cache(&mut self) {
+        self.cache = None;
+    }
+
+    /// Hits, misses and size of the evaluation cache, if it's enabled.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.lock().unwrap_or_else(|e| e.into_inner()).stats())
+    }
+
+    fn cached_matches(&self, obj: &Object, timestamp: u64) -> Option<Vec<LoadedRule>> {
+        let cache = self.cache.as_ref()?;
+        let cached = cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(obj, timestamp);
+        metrics::record_cache_lookup(cached.is_some());
+        cached
+    }
+
+    fn cache_version(&self) -> Option<u64> {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.lock().unwrap_or_else(|e| e.into_inner()).version())
+    }
+
+    // Drops cached results and the compiled rules, after a write or a change to how rules are
+    // parsed or evaluated
+    fn invalidate_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap_or_else(|e| e.into_inner()).invalidate();
+        }
+        *self.ruleset.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    // The rules as compiled for evaluation, compiling them again if they were dropped since
+    pub(crate) fn compiled(&self) -> Result<Arc<RuleSet>, RulesError> {
+        let mut ruleset = self.ruleset.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(compiled) = ruleset.as_ref() {
+            return Ok(Arc::clone(compiled));
+        }
+
+        let compiled = Arc::new(self.compile()?);
+        *ruleset = Some(Arc::clone(&compiled));
+        Ok(compiled)
+    }
+
+    /// Directory the config files are read from and written to.
+    pub fn config_dir(&self) -> &str {
+        &self.config_dir
+    }
+
+    /// Warnings from the last [`Rules::load_tags`] call, such as how duplicate
+    /// tags were resolved (T004) or a value listed twice for one tag (T003).
+    pub fn tag_diagnostics(&self) -> &[Diagnostic] {
+        &self.tag_diagnostics
+    }
+
+    /// Writes a tag to a .tags file.
+    ///
+    /// # Arguments
+    /// * `file_name` - Name of the file (with or without .tags extension)
+    /// * `tag_name` - Name of the tag (without the leading '-')
+    /// * `tag_values` - Vector of values for the tag
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.write_tag("my_tags", "colour", vec!["red", "blue"])?;
+    /// ```
+    pub fn write_tag(
+        &mut self,
+        file_name: &str,
+        tag_name: impl Into<String>,
+        tag_values: Vec<impl Into<String>>,
+    ) -> Result<(), RulesError> {
+        let tag_name = tag_name.into();
+        let tag_values: Vec<String> = tag_values.into_iter().map(|v| v.into()).collect();
+        let file = write::tag::normalise_filename(file_name);
+
+        let (tag_name_folded, tag_values_folded) =
+            self.check_tag_write(&file, &tag_name, &tag_values, &self.tags)?;
+
+        // Write to file
+        self.snapshot(&file)?;
+        write::tag::write_with_base_dir(
+            file_name,
+            tag_name.clone(),
+            tag_values.clone(),
+            &self.config_dir,
+        )?;
+        self.record_write(AuditOperation::WriteTag, &file, None)?;
+
+        // Update cached tags (append if exists)
+        merge_tag(&mut self.tags, tag_name_folded, tag_values_folded);
+
+        Ok(())
+    }
+
+    // Checks a write of `tag_values` to `tag_name` in `file` against the write policy, and the
+    // global limits given the tags in `loaded`, before the file is touched. Returns the name and
+    // values folded as load_tags would, for consistent lookup in the cache.
+    fn check_tag_write(
+        &self,
+        file: &str,
+        tag_name: &str,
+        tag_values: &[String],
+        loaded: &HashMap<TagName, TagValues>,
+    ) -> Result<(TagName, TagValues), RulesError> {
+        self.check_write(
+            AuditOperation::WriteTag,
+            file,
+            WritePayload::Tag {
+                name: tag_name,
+                values: tag_values,
+            },
+        )?;
+
+        let tag_name_folded = self.tag_load_options.case_folding.fold(tag_name);
+        let mut tag_values_folded: Vec<String> = Vec::new();
+        for value in tag_values {
+            let value = self.tag_load_options.case_folding.fold(value);
+            if !tag_values_folded.contains(&value) {
+                tag_values_folded.push(value);
+            }
+        }
+
+        let existing = loaded.get(&tag_name_folded);
+        let new_value_count = tag_values_folded
+            .iter()
+            .filter(|v| existing.is_none_or(|values| !values.contains(v)))
+            .count();
+        tags::check_tag_budget(
+            &self.tag_load_options.limits,
+            loaded.len() + usize::from(existing.is_none()),
+            loaded.values().map(Vec::len).sum::<usize>() + new_value_count,
+        )?;
+
+        Ok((tag_name_folded, tag_values_folded))
+    }
+
+    /// Imports tag definitions from a CSV file into a .tags file.
+    ///
+    /// Each row holds a tag name, a value and an optional description, with an
+    /// optional `tag,value,description` header row. Values are merged into
+    /// existing tags through the same validated path as `write_tag`, so values
+    /// already present are skipped. Descriptions are accepted so spreadsheet
+    /// exports can be used as-is, but aren't stored.
+    ///
+    /// # Arguments
+    /// * `csv_path` - Path to the CSV file
+    /// * `file_name` - Name of the .tags file to write to (with or without extension)
+    ///
+    /// # Returns
+    /// * `Ok(())` if every row was imported
+    /// * `Err(RulesError)` if a row is invalid, or a tag is refused by the
+    ///   write policy, the tag limits or the .tags file, in which case nothing
+    ///   is written
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.import_tags_csv("exports/catalogue.csv", "catalogue")?;
+    /// ```
+    pub fn import_tags_csv(&mut self, csv_path: &str, file_name: &str) -> Result<(), RulesError> {
+        // Read and validate every row before writing anything
+        let imported = crate::api::import::read_tags_csv(csv_path)?;
+
+        // Check every tag as write_tag would, against the tags and file as the tags before it
+        // leave them, so one refused part way through doesn't leave the others written
+        let file = write::tag::normalise_filename(file_name);
+        let mut loaded = self.tags.clone();
+        let mut content = write::tag::read(file_name, &self.config_dir)?;
+        for (tag_name, tag_values) in &imported {
+            let (tag_name_folded, tag_values_folded) =
+                self.check_tag_write(&file, tag_name, tag_values, &loaded)?;
+            content = write::tag::with_tag(&content, tag_name, tag_values)?;
+            merge_tag(&mut loaded, tag_name_folded, tag_values_folded);
+        }
+
+        for (tag_name, tag_values) in imported {
+            self.write_tag(file_name, tag_name, tag_values)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites a .tags file in the config directory into a normalised form.
+    ///
+    /// Tags are sorted alphabetically, duplicate values are removed and
+    /// spacing is normalised. Comments are kept, moving with the tag they sit
+    /// directly above.
+    ///
+    /// # Arguments
+    /// * `file_name` - Name of the file (with or without .tags extension)
+    ///
+    /// # Returns
+    /// * `Ok(())` if the file was rewritten
+    /// * `Err(RulesError)` if the file can't be read or contains an invalid line,
+    ///   in which case it is left untouched
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.format_tags_file("my_tags")?;
+    /// ```
+    pub fn format_tags_file(&self, file_name: &str) -> Result<(), RulesError> {
+        let file = write::tag::normalise_filename(file_name);
+        self.check_write(AuditOperation::FormatTags, &file, WritePayload::Format)?;
+        self.snapshot(&file)?;
+        write::tag::format_with_base_dir(file_name, &self.config_dir)?;
+        self.record_write(AuditOperation::FormatTags, &file, None)
+    }
+
+    /// Writes a rule to a .rules file.
+    ///
+    /// The rule is validated against the current tag definitions before writing,
+    /// and rejected if no object could ever match it (see [`Rules::is_satisfiable`])
+    /// or it duplicates a rule already in the file, whatever its spacing,
+    /// grouping or operand order (see [`Rules::duplicate_rules`]).
+    ///
+    /// # Arguments
+    /// * `file_name` - Name of the file (with or without .rules extension)
+    /// * `rule` - The rule string to write (should start with '-', or a name such as
+    ///   `promo_1:` and then '-')
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.write_rule("my_rules", "- colour = red & size = large")?;
+    /// rules.write_rule("my_rules", "promo_1: - colour = blue")?;
+    /// ```
+    pub fn write_rule(&self, file_name: &str, rule: &str) -> Result<(), RulesError> {
+        let file = write::rule::normalise_filename(file_name);
+        if let (Some(name), _) = string::split_name(rule)?
+            && let Some(existing) = self.find_rule_by_name(name)?
+        {
+            return Err(RulesError::RuleParseError(format!(
+                "Rule name '{}' is already used at {}:{}",
+                name, existing.file, existing.line
+            )));
+        }
+        self.check_write(AuditOperation::WriteRule, &file, WritePayload::Rule(rule))?;
+        self.snapshot(&file)?;
+        write::rule::write_with_parser(file_name, rule, &self.rule_parser(), &self.config_dir)?;
+
+        // The rule is appended to the file
+        let content = fs::read_to_string(Path::new(&self.config_dir).join(&file))?;
+        let rule_id = RuleId::new(&file, content.lines().count(), rule);
+        self.record_write(AuditOperation::WriteRule, &file, Some(&rule_id))
+    }
+
+    /// Writes an object definition to a .yaml file.
+    ///
+    /// # Arguments
+    /// * `file_name` - Name of the file
+    /// * `obj_type` - Type/category of the object
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let mut obj = HashMap::new();
+    /// obj.insert("colour".to_string(), vec!["red".to_string()]);
+    /// rules.write_object("objects.yaml", "shapes", obj)?;
+    /// ```
+    pub fn write_object(
+        &self,
+        file_name: &str,
+        obj_type: impl Into<String>,
+        obj: HashMap<String, Vec<String>>,
+    ) -> Result<(), RulesError> {
+        let obj_type = obj_type.into();
+        self.check_write(
+            AuditOperation::WriteObject,
+            file_name,
+            WritePayload::Object {
+                obj_type: &obj_type,
+                object: &obj,
+            },
+        )?;
+
+        self.snapshot(file_name)?;
+        write::object::write_with_base_dir(file_name, obj_type, obj, &self.config_dir)?;
+        self.record_write(AuditOperation::WriteObject, file_name, None)
+    }
+
+    /// Validates a rule string against the current tag definitions.
+    ///
+    /// This checks syntax and ensures all referenced tags and values exist.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to validate (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(())` if the rule is valid
+    /// * `Err(RulesError)` with details if validation fails
+    ///
+    /// # Examples
+    /// ```ignore
+    /// rules.validate_rule("- colour = red & size = large")?;
+    /// ```
+    pub fn validate_rule(&self, rule: &str) -> Result<(), RulesError> {
+        self.rule_parser().validate_rule(rule)
+    }
+
+    /// Validates every rule in the config directory, reporting all that fail
+    /// rather than only the first.
+    ///
+    /// Each line of the .rules files which can't be read, such as a bad
+    /// annotation or rule name, or whose rule is invalid, is listed with its
+    /// file, line and any column. Loading functions such as
+    /// [`Rules::rules`] still stop at the first.
+    ///
+    /// Valid rules which can never match, or match every object, given the
+    /// values each tag can have, are reported as warnings naming the
+    /// comparisons responsible, e.g. `- colour = red & colour ! red` or
+    /// `- colour = red | colour ! red`.
+    ///
+    /// # Returns
+    /// * `Ok(ValidationReport)` if every rule is valid, with any warnings
+    /// * `Err(RulesError::ValidationError)` with a [`ValidationReport`] of every
+    ///   failing line and warning, in file then line order
+    ///
+    /// # Examples
+    /// ```ignore
+    /// match rules.validate_rules() {
+    ///     Ok(report) => report.warnings.iter().for_each(|w| eprintln!("{}", w)),
+    ///     Err(RulesError::ValidationError(report)) => {
+    ///         for failure in &report.failures {
+    ///             eprintln!("{}", failure.error);
+    ///         }
+    ///     }
+    ///     Err(e) => return Err(e),
+    /// }
+    /// ```
+    pub fn validate_rules(&self) -> Result<ValidationReport, RulesError> {
+        let pattern = format!("{}/*.rules", self.config_dir);
+        let mut report = ValidationReport::default();
+        let rules = rules::read_rules_with_includes(
+            &self.config_dir,
+            file::read_files_in_dir_with_paths(&pattern)?,
+            &mut report,
+        );
+        rules::report_duplicate_names(&rules, &mut report);
+        self.rule_parser().validate_rules(&rules, &mut report);
+
+        report.failures.sort_by(|first, second| {
+            (&first.location.file, first.location.line)
+                .cmp(&(&second.location.file, second.location.line))
+        });
+        report.warnings.sort_by(|first, second| {
+            (&first.span.file, first.span.line).cmp(&(&second.span.file, second.span.line))
+        });
+        report.into_result()
+    }
+
+    /// Parses a rule into its syntax tree, for analyses and transpilers which
+    /// shouldn't re-parse rule text themselves.
+    ///
+    /// The rule is validated against the loaded tags first. Use
+    /// [`crate::ast::walk`] with an [`crate::ast::AstVisitor`] to visit each
+    /// node, or [`crate::ast::fold`] to combine the tree into one value.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to parse (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(AstRule)` whose `root_node` is a comparison or an `&` or `|` over two nodes
+    /// * `Err(RulesError)` if the rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let ast = rules.parse_rule("- colour = red & size = large")?;
+    /// assert_eq!(ast.root_node.token, Token::And);
+    /// ```
+    pub fn parse_rule(&self, rule: &str) -> Result<AstRule, RulesError> {
+        self.rule_parser().string_to_rule(rule)
+    }
+
+    /// Renders how a rule was parsed as a tree, each operator above its
+    /// operands, to see how precedence, parentheses and comma lists were read.
+    ///
+    /// `&` binds tighter than `|`, and a comma list becomes a chain of `|`
+    /// unless it's in parentheses, so the tree shows exactly which
+    /// comparisons each operator joins.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to render (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(String)` with one line per node, children indented under their operator
+    /// * `Err(RulesError)` if the rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// print!("{}", rules.render_tree("- colour = red & (size = small, large)")?);
+    /// // &
+    /// // |-- colour = red
+    /// // `-- |
+    /// //     |-- size = small
+    /// //     `-- size = large
+    /// ```
+    pub fn render_tree(&self, rule: &str) -> Result<String, RulesError> {
+        self.rule_parser().render_tree(rule)
+    }
+
+    /// Renders every rule in the config directory as one Graphviz DOT
+    /// graph, to review complex rules visually, e.g. with `dot -Tsvg`.
+    ///
+    /// Each rules file is a cluster, and each rule a note giving its line,
+    /// name, priority and outcome, pointing to its tree as
+    /// [`AstRule::to_dot`] draws it: operators as ellipses over their
+    /// operands, and comparisons as boxes. Drafts are dashed.
+    ///
+    /// # Returns
+    /// * `Ok(String)` with a `digraph` of every rule, in file then line order
+    /// * `Err(RulesError)` if a rule is invalid, naming its file and line
+    ///
+    /// # Examples
+    /// ```ignore
+    /// fs::write("rules.dot", rules.ruleset_to_dot()?)?;
+    /// // dot -Tsvg rules.dot -o rules.svg
+    /// ```
+    pub fn ruleset_to_dot(&self) -> Result<String, RulesError> {
+        let parser = self.rule_parser();
+        let mut dot = String::from("digraph rules {\n");
+        let mut current_file: Option<String> = None;
+
+        for (index, loaded) in self.rules()?.iter().enumerate() {
+            let ast = parser
+                .string_to_rule(&loaded.rule)
+                .map_err(|e| rules::rule_error_at(loaded, e))?;
+
+            if current_file.as_deref() != Some(loaded.file.as_str()) {
+                if current_file.is_some() {
+                    dot.push_str("    }\n");
+                }
+                dot.push_str(&format!(
+                    "    subgraph cluster_{} {{\n        label=\"{}\";\n",
+                    index,
+                    dot_escape(&loaded.file)
+                ));
+                current_file = Some(loaded.file.clone());
+            }
+
+            // E.g. "line 3", "promo_1", "[10]" and "=> reject", one per line of the note
+            let mut label = vec![format!("line {}", loaded.line)];
+            label.extend(loaded.name().map(str::to_string));
+            if loaded.priority() > 0 {
+                label.push(format!("[{}]", loaded.priority()));
+            }
+            label.extend(loaded.outcome().map(|outcome| format!("=> {}", outcome)));
+            let label: Vec<String> = label.iter().map(|part| dot_escape(part)).collect();
+            let style = if loaded.is_draft() {
+                ", style=dashed"
+            } else {
+                ""
+            };
+
+            let prefix = format!("r{}", index);
+            let mut lines = vec![format!(
+                "{} [label=\"{}\", shape=note{}];",
+                prefix,
+                label.join("\\n"),
+                style
+            )];
+            let root = ast.root_node.write_dot(&mut lines, &prefix, &mut 0);
+            lines.push(format!("{} -> {};", prefix, root));
+            for line in lines {
+                dot.push_str(&format!("        {}\n", line));
+            }
+        }
+
+        if current_file.is_some() {
+            dot.push_str("    }\n");
+        }
+        dot.push_str("}\n");
+        Ok(dot)
+    }
+
+    /// Converts a rule to a JsonLogic expression, so a JavaScript frontend
+    /// can evaluate it with a JsonLogic library instead of parsing rules.
+    ///
+    /// The data it's evaluated against holds each tag as a list of values,
+    /// as objects do -- e.g. `{"colour": ["red"], "size": ["large"]}`. Each
+    /// comparison becomes an `in` over the tag's list, and rank comparisons
+    /// the values on their side of the target, in the tag's order.
+    /// [`AstRule::to_json_logic`] does the same for a parsed rule, without
+    /// rank comparisons.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to convert (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(Value)` with the JsonLogic expression
+    /// * `Err(RulesError)` if the rule is invalid, or uses wildcards,
+    ///   regexes, plugin calls, variables or tag references, which JsonLogic
+    ///   can't express
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let logic = rules.rule_to_json_logic("- colour = red & size > small")?;
+    /// // {"and": [{"in": ["red", {"var": "colour"}]},
+    /// //          {"some": [{"var": "size"}, {"in": [{"var": ""}, ["medium", "large"]]}]}]}
+    /// ```
+    #[cfg(feature = "json_logic")]
+    pub fn rule_to_json_logic(&self, rule: &str) -> Result<serde_json::Value, RulesError> {
+        self.rule_parser().rule_to_json_logic(rule)
+    }
+
+    /// Checks whether any valid object could match a rule.
+    ///
+    /// A rule can never match if every way of satisfying it needs a value to
+    /// be both present and absent, two values of a `(single)` tag, an ordered
+    /// value that doesn't exist, two members of an `@exclusive` group, a
+    /// `(required)` tag to be missing, or a value other than all those a tag
+    /// lists. Plugin calls are assumed to hold for some value.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to check (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(true)` if some object could match the rule
+    /// * `Ok(false)` if none could, e.g. for `- size = small & size ! small`
+    /// * `Err(RulesError)` if the rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// if !rules.is_satisfiable("- colour = red & colour = blue")? {
+    ///     println!("rule can never match");
+    /// }
+    /// ```
+    pub fn is_satisfiable(&self, rule: &str) -> Result<bool, RulesError> {
+        self.rule_parser().is_satisfiable(rule)
+    }
+
+    /// The comparisons which stop a rule ever matching, if it never can (see
+    /// [`Rules::is_satisfiable`]).
+    ///
+    /// For each way of satisfying the rule, only the fewest comparisons which
+    /// can't hold together are given, so `- colour = red & size = small &
+    /// size ! small` gives `size = small` and `size ! small`.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to check (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(Some(Vec<Clause>))` with the comparisons, in the order they're written
+    /// * `Ok(None)` if some object could match the rule, or it's too large to check
+    /// * `Err(RulesError)` if the rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// if let Some(clauses) = rules.contradiction("- colour = red & colour ! red")? {
+    ///     println!("{} clauses conflict", clauses.len());
+    /// }
+    /// ```
+    pub fn contradiction(&self, rule: &str) -> Result<Option<Vec<Clause>>, RulesError> {
+        self.rule_parser().contradiction(rule)
+    }
+
+    /// The comparisons which make a rule match every valid object, if it does.
+    ///
+    /// Objects can have any of a tag's values, several unless it's `(single)`,
+    /// or none unless it's `(required)`. Only the fewest ways of satisfying
+    /// the rule which together cover every object are kept, so `- colour = red
+    /// | colour ! red | size = small` gives `colour = red` and `colour ! red`.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to check (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(Some(Vec<Clause>))` with the comparisons, in the order they're written
+    /// * `Ok(None)` if some object doesn't match, or if unsure, when the rule
+    ///   calls a plugin or is too large to check
+    /// * `Err(RulesError)` if the rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// if rules.tautology("- colour? | !colour?")?.is_some() {
+    ///     println!("rule always matches");
+    /// }
+    /// ```
+    pub fn tautology(&self, rule: &str) -> Result<Option<Vec<Clause>>, RulesError> {
+        self.rule_parser().tautology(rule)
+    }
+
+    /// Rewrites a rule in a deterministic normal form, so rules written
+    /// differently but matching the same objects give the same text.
+    ///
+    /// The rule is expanded into an OR of ANDs, leaving out combinations
+    /// which can never hold or are implied by another. Tag names and values
+    /// are case folded as the tags are, spacing is normalised, and the
+    /// comparisons in each AND and the ANDs themselves are sorted. A rule
+    /// which can never match has no normal form, so its comparisons are
+    /// only sorted, and a rule too large to expand is handled the same way.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to canonicalize (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(String)` with the canonical rule, starting with '-'
+    /// * `Err(RulesError)` if the rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// assert_eq!(
+    ///     rules.canonicalize("- (Size>small)&(colour=red,blue)")?,
+    ///     "- colour = blue & size > small | colour = red & size > small"
+    /// );
+    /// ```
+    pub fn canonicalize(&self, rule: &str) -> Result<String, RulesError> {
+        self.rule_parser().canonicalize(rule)
+    }
+
+    /// A short hash of a rule's canonical form, e.g. to deduplicate rules or
+    /// key a cache by rule.
+    ///
+    /// Rules with the same [`Rules::canonicalize`] output have the same
+    /// fingerprint. The hash doesn't depend on the process, platform or
+    /// crate version's hasher, so fingerprints can be stored.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to fingerprint (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(String)` with 16 hex digits
+    /// * `Err(RulesError)` if the rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// assert_eq!(
+    ///     rules.fingerprint("- colour = red & size = large")?,
+    ///     rules.fingerprint("- size=large & Colour=red")?
+    /// );
+    /// ```
+    pub fn fingerprint(&self, rule: &str) -> Result<String, RulesError> {
+        self.rule_parser().fingerprint(rule)
+    }
+
+    /// Rewrites a rule in conjunctive normal form, an AND of ORs, for
+    /// SAT-style checks which want clauses rather than alternatives.
+    ///
+    /// This is the counterpart of the OR of ANDs [`Rules::canonicalize`]
+    /// gives, normalised the same way: comparisons are case folded and
+    /// sorted, ORs which every valid object meets are left out, as are ORs
+    /// containing every comparison of another. A rule which always matches
+    /// has no clauses.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to convert (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(CnfRule)` whose `clauses` must all hold, each by one of its comparisons
+    /// * `Err(RulesError)` if the rule is invalid or would have more than 4096 clauses
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let cnf = rules.rule_to_cnf("- colour = red | size = large & shape = circle")?;
+    /// assert_eq!(
+    ///     cnf.to_string(),
+    ///     "(colour = red | shape = circle) & (colour = red | size = large)"
+    /// );
+    /// ```
+    pub fn rule_to_cnf(&self, rule: &str) -> Result<CnfRule, RulesError> {
+        self.rule_parser().rule_to_cnf(rule)
+    }
+
+    /// Checks whether two rules match exactly the same objects, however
+    /// they're written.
+    ///
+    /// Both rules are expanded into an OR of ANDs, leaving out combinations
+    /// which can never hold or which are implied by another, then compared.
+    /// This catches reordering, regrouping and distributing conditions, but
+    /// rules are only equivalent if they expand to the same combinations, so
+    /// `- colour = red` isn't equivalent to
+    /// `- colour = red & size = small | colour = red & size ! small`.
+    ///
+    /// # Arguments
+    /// * `first` - A rule string (should start with '-')
+    /// * `second` - The rule string to compare it with
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the rules are equivalent
+    /// * `Ok(false)` if they aren't
+    /// * `Err(RulesError)` if either rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// assert!(rules.is_equivalent(
+    ///     "- colour = red & (size = small | size = large)",
+    ///     "- size = large & colour = red | colour = red & size = small",
+    /// )?);
+    /// ```
+    pub fn is_equivalent(&self, first: &str, second: &str) -> Result<bool, RulesError> {
+        self.rule_parser().is_equivalent(first, second)
+    }
+
+    /// Checks whether two rules match exactly the same valid objects, e.g.
+    /// before replacing a rule with a tidier one.
+    ///
+    /// Unlike [`Rules::is_equivalent`], the values each tag can have are taken
+    /// into account, so conditions which cover every case cancel out:
+    /// `- colour = red` is equivalent to
+    /// `- colour = red & size = small | colour = red & size ! small`, and with
+    /// `size (single): small < medium < large`, `- size > small` is
+    /// equivalent to `- size = medium, large`. Each rule is checked to imply
+    /// the other, by searching for an object one matches and the other
+    /// doesn't.
+    ///
+    /// # Arguments
+    /// * `first` - A rule string (should start with '-')
+    /// * `second` - The rule string to compare it with
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the rules are equivalent
+    /// * `Ok(false)` if they aren't, or if unsure, when a rule is too large to
+    ///   check or they call plugins differently
+    /// * `Err(RulesError)` if either rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let ugly = "- !(colour ! red | size = small) | colour = red & size = small";
+    /// assert!(rules.rules_equivalent(ugly, "- colour = red")?);
+    /// ```
+    pub fn rules_equivalent(&self, first: &str, second: &str) -> Result<bool, RulesError> {
+        self.rule_parser().rules_equivalent(first, second)
+    }
+
+    /// Random objects which a rule matches, and which it doesn't, e.g. for
+    /// documentation, tests or previews.
+    ///
+    /// Matching objects are built from the ways the rule can be satisfied,
+    /// and half of the non-matching ones are near misses which meet all but
+    /// one condition. Every object is valid against the tag definitions, and
+    /// uses the tags the rule compares plus any `(required)` tags. The same
+    /// seed always gives the same objects.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to generate objects for (should start with '-')
+    /// * `n` - How many objects of each kind to generate
+    /// * `seed` - Seed for the random choices
+    ///
+    /// # Returns
+    /// * `Ok(RuleExamples)` with up to `n` distinct objects of each kind, fewer
+    ///   if there aren't that many, and no matching ones if the rule can never match
+    /// * `Err(RulesError)` if the rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let examples = rules.generate_examples("- colour = red & size > small", 3, 42)?;
+    /// for obj in &examples.matching {
+    ///     println!("{:?}", obj);
+    /// }
+    /// ```
+    pub fn generate_examples(
+        &self,
+        rule: &str,
+        n: usize,
+        seed: u64,
+    ) -> Result<RuleExamples, RulesError> {
+        self.rule_parser().generate_examples(rule, n, seed)
+    }
+
+    /// Whether a rule matches each combination of values of the tags it
+    /// compares, to check a small rule does what its author meant.
+    ///
+    /// Each tag takes one of its values or, unless it's `(required)`, none at
+    /// all, as for `coverage_gaps`. Combinations breaking an `@exclusive`
+    /// group are left out, as are tags which list no values, like pattern
+    /// tags. Tables over 10,000 rows are an error, so keep to rules comparing
+    /// a few tags. `TruthTable::to_csv` exports the table for a spreadsheet.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to tabulate (should start with '-')
+    ///
+    /// # Returns
+    /// * `Ok(TruthTable)` with a row per combination, the first tag's value changing fastest
+    /// * `Err(RulesError)` if the rule is invalid or there are too many combinations
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let table = rules.truth_table("- colour = red & size > small")?;
+    /// print!("{}", table.to_csv()?);
+    /// ```
+    pub fn truth_table(&self, rule: &str) -> Result<TruthTable, RulesError> {
+        self.rule_parser().truth_table(rule)
+    }
+
+    /// Explains how a rule evaluated against an object, node by node.
+    ///
+    /// Returns the rule's tree with the outcome of every `&`, `|` and
+    /// comparison, and at each comparison the object's values for the tag it
+    /// compares. Every operand is explained, even after an operator's outcome
+    /// is known. Variables are filled in as for [`Rules::matching_rules`].
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to explain (should start with '-')
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * `Ok(ExplanationNode)` for the rule's root, whose `matched` is whether the rule matches
+    /// * `Err(RulesError)` if the rule or object is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let explanation = rules.explain("- colour = red & size = large", &obj)?;
+    /// print!("{}", explanation);
+    /// // &: false
+    /// // |-- colour = red: true (red)
+    /// // `-- size = large: false (small)
+    /// ```
+    pub fn explain(&self, rule: &str, obj: &Object) -> Result<ExplanationNode, RulesError> {
+        self.validate_object(obj)?;
+        self.rule_parser()
+            .explain(rule, obj, audit::unix_timestamp())
+    }
+
+    /// Evaluates an object against the rules in the config directory,
+    /// recording every node each rule visited.
+    ///
+    /// Rules are checked as for [`Rules::evaluate_with_context`], so only
+    /// rules in effect are traced, and under [`EvalMode::FirstMatch`] none
+    /// after the first match. Each visited node records its operator or
+    /// comparison, the object's values for the tag it compares and its
+    /// result. Unlike [`Rules::explain`], operands skipped because the left
+    /// one decided an `&` or `|` aren't recorded. Results aren't cached.
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    /// * `context` - Values for the `$ctx` variables
+    ///
+    /// # Returns
+    /// * `Ok(EvaluationTrace)` with a trace per rule checked, highest priority first, then in
+    ///   file then line order
+    /// * `Err(RulesError)` if the object or a rule is invalid, or a rule in effect uses a
+    ///   variable the context has no value for
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let trace = rules.trace(&obj, &EvalContext::default())?;
+    /// print!("{}", trace);
+    /// // my_rules.rules:1 - colour = red & size = large: false
+    /// //   &: false
+    /// //     colour = red: false (blue)
+    /// ```
+    pub fn trace(
+        &self,
+        obj: &Object,
+        context: &EvalContext,
+    ) -> Result<EvaluationTrace, RulesError> {
+        self.validate_object(obj)?;
+        let mut rules = self.rules()?;
+        rules.retain(|loaded| !loaded.is_draft());
+        let traces = self.rule_parser().traced_rules(
+            &rules,
+            obj,
+            context,
+            audit::unix_timestamp(),
+            self.evaluation_options.mode,
+        )?;
+        Ok(EvaluationTrace { rules: traces })
+    }
+
+    /// Evaluates a rule against an object under three-valued logic, without
+    /// collapsing Unknown per [`Rules::set_unknown_policy`].
+    ///
+    /// Comparisons on a value outside the tag's listed values are Unknown
+    /// unless another value decides them, and `&` and `|` combine results as
+    /// in Kleene logic.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string to evaluate (should start with '-')
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * `Ok(Truth)` with whether the rule holds, doesn't, or can't be told
+    /// * `Err(RulesError)` if the rule or object is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// // - vendor (open): acme, globex
+    /// let truth = rules.rule_truth("- vendor = acme | colour = red", &obj)?;
+    /// assert_eq!(truth, Truth::Unknown);
+    /// ```
+    pub fn rule_truth(&self, rule: &str, obj: &Object) -> Result<Truth, RulesError> {
+        self.validate_object(obj)?;
+        self.rule_parser().rule_truth(rule, obj)
+    }
+
+    /// Explains why a rule doesn't match an object the author expected it to.
+    ///
+    /// Of the ways the rule can be satisfied, finds the one needing the fewest
+    /// changes to the object, and returns the conditions the object fails
+    /// along with the tags to add or remove. Ordered comparisons suggest the
+    /// nearest value that passes, and single-valued tags have their current
+    /// value replaced. Failed plugin calls are reported, but can't be fixed by
+    /// an edit, so the explanation is marked incomplete.
+    ///
+    /// # Arguments
+    /// * `rule` - The rule string expected to match (should start with '-')
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * `Ok(None)` if the rule matches the object
+    /// * `Ok(Some(MatchExplanation))` with the failed conditions and edits
+    /// * `Err(RulesError)` if the rule or object is invalid, or the rule can never match
+    ///
+    /// # Examples
+    /// ```ignore
+    /// if let Some(explanation) = rules.why_not("- colour = red & size > medium", &obj)? {
+    ///     print!("{}", explanation);
+    /// }
+    /// ```
+    pub fn why_not(
+        &self,
+        rule: &str,
+        obj: &Object,
+    ) -> Result<Option<MatchExplanation>, RulesError> {
+        self.validate_object(obj)?;
+        self.rule_parser().why_not(rule, obj)
+    }
+
+    /// Validates an object against the current tag definitions.
+    ///
+    /// Every attribute must be a known tag, and every value must be one of the
+    /// tag's values (or match its pattern, for pattern-constrained tags). Tags
+    /// marked `(required)` must be present.
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * `Ok(())` if the object is valid
+    /// * `Err(RulesError)` with details if validation fails
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let mut obj = HashMap::new();
+    /// obj.insert("sku".to_string(), vec!["AB1234".to_string()]);
+    /// rules.validate_object(&obj)?;
+    /// ```
+    pub fn validate_object(&self, obj: &Object) -> Result<(), RulesError> {
+        objects::validate_object(
+            obj,
+            &self.tags,
+            &self.tag_constraints,
+            self.tag_load_options.case_folding,
+        )?;
+
+        if let Some(conflict) = self.check_exclusions(obj).first() {
+            return Err(RulesError::ObjectParseError(conflict.to_string()));
+        }
+
+        Ok(())
+    }
+
+    // Whether `obj` is evaluated at all, per the RequiredTagPolicy: false if it's missing a
+    // required tag under NoMatch, so it matches no rules, and an error under Error. Every
+    // evaluation path checks this before validating the object.
+    pub(crate) fn check_required_tags(&self, obj: &Object) -> Result<bool, RulesError> {
+        objects::check_required_tags(
+            obj,
+            &self.tag_constraints,
+            self.tag_load_options.case_folding,
+            self.evaluation_options.required_tags,
+        )
+    }
+
+    /// Checks an object against the `@exclusive` groups declared in .tags files.
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * Every group the object holds more than one member of, with the members found
+    ///
+    /// # Examples
+    /// ```ignore
+    /// for conflict in rules.check_exclusions(&obj) {
+    ///     println!("{:?} conflict: {:?}", conflict.group, conflict.found);
+    /// }
+    /// ```
+    pub fn check_exclusions(&self, obj: &Object) -> Vec<ExclusionConflict> {
+        objects::check_exclusive_groups(
+            obj,
+            &self.exclusive_groups,
+            self.tag_load_options.case_folding,
+        )
+    }
+
+    /// Finds every rule clause in the config directory's .rules files that
+    /// references a tag, optionally narrowed to one of its values.
+    /// Without a value, clauses comparing another tag against it, like
+    /// `shipping_colour = @colour`, are included too.
+    ///
+    /// Rules are only syntax-checked, so clauses using values that are no
+    /// longer defined are still reported. A clause with a glob, like
+    /// `colour = re*`, uses each value it matches.
+    ///
+    /// # Arguments
+    /// * `tag_name` - Name of the tag to look for
+    /// * `tag_value` - If given, only clauses comparing against this value, or a glob matching it, are returned
+    ///
+    /// # Returns
+    /// * `Ok(Vec<TagUsage>)` with the file, line, rule and clause of each usage
+    /// * `Err(RulesError)` if a rules file can't be read or contains a malformed rule
+    ///
+    /// # Examples
+    /// ```ignore
+    /// for usage in rules.tag_usages("colour", Some("red"))? {
+    ///     println!("{}:{} {}", usage.file, usage.line, usage.clause);
+    /// }
+    /// ```
+    pub fn tag_usages(
+        &self,
+        tag_name: &str,
+        tag_value: Option<&str>,
+    ) -> Result<Vec<TagUsage>, RulesError> {
+        let case_folding = self.tag_load_options.case_folding;
+        let tag_name = case_folding.fold(tag_name);
+        let tag_value = tag_value.map(|v| case_folding.fold(v));
+        let parser = RuleParser::new(HashMap::new()).with_case_folding(case_folding);
+        let mut usages: Vec<TagUsage> = Vec::new();
+
+        for loaded in self.rules()? {
+            for clause in parser.clauses(&loaded.rule)? {
+                // Comparisons against the tag use it too -- e.g. "shipping_colour = @colour"
+                let referenced = tag_value.is_none()
+                    && rules::tag_reference(&clause.tag_value) == Some(tag_name.as_str());
+                if clause.tag_name != tag_name && !referenced {
+                    continue;
+                }
+
+                // Globs use each value they match -- e.g. "colour = re*" uses red
+                if tag_value.as_ref().is_some_and(|v| {
+                    *v != clause.tag_value
+                        && !(clause.tag_value.contains(string::WILDCARD)
+                            && string::glob_matches(&clause.tag_value, v))
+                }) {
+                    continue;
+                }
+
+                usages.push(TagUsage {
+                    file: loaded.file.clone(),
+                    line: loaded.line,
+                    rule: loaded.rule.clone(),
+                    clause,
+                });
+            }
+        }
+
+        Ok(usages)
+    }
+
+    /// Reports which rules and objects in the config directory would become
+    /// invalid if a value were removed from a tag.
+    ///
+    /// Values of open or pattern-constrained tags are never required, so
+    /// removing them has no impact. A rule with a glob, like `colour = re*`,
+    /// is affected if the value is the only one its glob matches.
+    ///
+    /// # Arguments
+    /// * `tag_name` - Name of the tag
+    /// * `tag_value` - Value that would be removed
+    ///
+    /// # Returns
+    /// * `Ok(ValueRemovalImpact)` listing affected rules and objects
+    /// * `Err(RulesError)` if a config file can't be read or parsed
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let impact = rules.can_remove_value("colour", "red")?;
+    /// if !impact.is_safe() {
+    ///     println!("{} rules and {} objects use red", impact.rules.len(), impact.objects.len());
+    /// }
+    /// ```
+    pub fn can_remove_value(
+        &self,
+        tag_name: &str,
+        tag_value: &str,
+    ) -> Result<ValueRemovalImpact, RulesError> {
+        let tag_name = self.tag_load_options.case_folding.fold(tag_name);
+        let tag_value = self.tag_load_options.case_folding.fold(tag_value);
+
+        if let Some(constraints) = self.tag_constraints.get(&tag_name)
+            && (constraints.open || constraints.pattern.is_some())
+        {
+            return Ok(ValueRemovalImpact::default());
+        }
+
+        // A glob only stops validating once it matches none of the values left -- e.g.
+        // "colour = re*" is fine without red while rose remains
+        let remaining: Vec<&String> = self
+            .tags
+            .get(&tag_name)
+            .into_iter()
+            .flatten()
+            .filter(|v| **v != tag_value)
+            .collect();
+        let rules = self
+            .tag_usages(&tag_name, Some(&tag_value))?
+            .into_iter()
+            .filter(|usage| {
+                let glob = &usage.clause.tag_value;
+                !glob.contains(string::WILDCARD)
+                    || !remaining.iter().any(|v| string::glob_matches(glob, v))
+            })
+            .collect();
+
+        let pattern = format!("{}/*.yaml", self.config_dir);
+        let objects = objects::parse_objects(&pattern, self.tag_load_options.case_folding)?
+            .into_iter()
+            .filter(|loaded| {
+                loaded
+                    .object
+                    .get(&tag_name)
+                    .is_some_and(|values| values.contains(&tag_value))
+            })
+            .collect();
+
+        Ok(ValueRemovalImpact { rules, objects })
+    }
+
+    /// The tags loaded by [`Rules::load_tags`] and [`Rules::write_tag`], keyed
+    /// by name.
+    pub fn tags(&self) -> &HashMap<TagName, TagValues> {
+        &self.tags
+    }
+
+    /// A generator of random rules which are valid against the loaded tags,
+    /// e.g. to fuzz the engine or build a benchmark corpus.
+    ///
+    /// Ordered tags may be compared with '<' and '>'. Annotations, exclusive
+    /// groups and plugin calls aren't generated, so rules are valid but may
+    /// never match.
+    ///
+    /// # Arguments
+    /// * `seed` - Seed for the random choices
+    ///
+    /// # Returns
+    /// * A `RuleGenerator`, which can be given a maximum depth and operator mix
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let corpus = rules.rule_generator(42).with_max_depth(4).generate_many(1000)?;
+    /// ```
+    pub fn rule_generator(&self, seed: u64) -> RuleGenerator {
+        RuleGenerator::new(&self.tags, seed).with_tag_constraints(&self.tag_constraints)
+    }
+
+    /// Finds a rule by its [`RuleId`], e.g. one recorded in the audit log or
+    /// a lint warning, after the file may have been edited.
+    ///
+    /// The rule is found by its file and the hash of its text, so it's found
+    /// even if lines added or removed above it have moved it. If the same
+    /// text is on more than one line of the file, the nearest to the
+    /// recorded line is returned.
+    ///
+    /// # Arguments
+    /// * `id` - Identity of the rule, from [`LoadedRule::id`]
+    ///
+    /// # Returns
+    /// * `Ok(Some(LoadedRule))` where the rule is now
+    /// * `Ok(None)` if the rule has been changed or removed
+    /// * `Err(RulesError)` if a rules file can't be read
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let id = RuleId::parse("my_rules.rules:3#9c4f0a1b2d3e4f56").unwrap();
+    /// if let Some(loaded) = rules.find_rule(&id)? {
+    ///     println!("now at line {}", loaded.line);
+    /// }
+    /// ```
+    pub fn find_rule(&self, id: &RuleId) -> Result<Option<LoadedRule>, RulesError> {
+        Ok(self
+            .rules()?
+            .into_iter()
+            .filter(|loaded| id.same_rule(loaded))
+            .min_by_key(|loaded| loaded.line.abs_diff(id.line)))
+    }
+
+    /// Finds a rule by the name it's written with, e.g. `promo_1` for
+    /// `promo_1: - colour = red`.
+    ///
+    /// Names are unique across the config directory, and stay with the rule
+    /// however its file is edited.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the rule
+    ///
+    /// # Returns
+    /// * `Ok(Some(LoadedRule))` with the rule of that name
+    /// * `Ok(None)` if no rule has the name
+    /// * `Err(RulesError)` if a rules file can't be read, or two rules have the same name
+    ///
+    /// # Examples
+    /// ```ignore
+    /// if let Some(loaded) = rules.find_rule_by_name("promo_1")? {
+    ///     println!("{}:{} {}", loaded.file, loaded.line, loaded.rule);
+    /// }
+    /// ```
+    pub fn find_rule_by_name(&self, name: &str) -> Result<Option<LoadedRule>, RulesError> {
+        Ok(self
+            .rules()?
+            .into_iter()
+            .find(|loaded| loaded.name() == Some(name)))
+    }
+
+    /// Starts a query over the loaded tags, built in Rust rather than
+    /// written as a rule string.
+    ///
+    /// Comparisons are chained with `and()` and `or()`, where `and()` binds
+    /// tighter, as `&` does in rules. Tags and values are checked against the
+    /// loaded tags when the query runs.
+    ///
+    /// # Returns
+    /// * A `QueryTerm`, waiting for the first `tag(...)` comparison
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let matched = rules
+    ///     .query()
+    ///     .tag("colour").eq("red")
+    ///     .or()
+    ///     .tag("size").ne("small")
+    ///     .run(&objects)?;
+    /// ```
+    pub fn query(&self) -> QueryTerm {
+        QueryTerm::new(self.rule_parser())
+    }
+
+    /// Loads an [`Engine`] over this config directory, with the same tag
+    /// loading and evaluation options.
+    ///
+    /// The engine holds its own copy of the tags, subrules and objects, so
+    /// it can be inspected or reloaded without changing `self`.
+    ///
+    /// # Returns
+    /// * `Ok(Engine)` with the config loaded
+    /// * `Err(RulesError)` if a file is invalid or an object misses a required tag
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let engine = rules.engine()?;
+    /// println!("{}", engine.stats());
+    /// ```
+    pub fn engine(&self) -> Result<Engine, RulesError> {
+        let mut engine = Engine::new(&self.config_dir)
+            .with_tag_load_options(self.tag_load_options)
+            .with_options(self.evaluation_options)
+            .with_operator_precedence(self.operator_precedence.clone())
+            .with_rule_limits(self.rule_limits)
+            .with_plugins(self.plugins.clone());
+        engine.load()?;
+        Ok(engine)
+    }
+
+    /// Parses every rule in the config directory once, for evaluating many
+    /// objects without reading the .rules files again.
+    ///
+    /// Rules are converted to DNF subrules and indexed by the tags objects
+    /// need to match them, against the tags loaded now. Drafts are left out,
+    /// and `@effective_from` and `@expires` are checked when evaluating.
+    /// Later writes and edits aren't seen by the rule set, so compile again
+    /// after changing the rules.
+    ///
+    /// # Returns
+    /// * `Ok(RuleSet)` with every rule compiled
+    /// * `Err(RulesError)` if a rules file can't be read or a rule is invalid, naming its
+    ///   file and line
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let ruleset = rules.compile()?;
+    /// for obj in &objects {
+    ///     for matched in ruleset.evaluate(obj)? {
+    ///         println!("{}", matched.id);
+    ///     }
+    /// }
+    /// ```
+    pub fn compile(&self) -> Result<RuleSet, RulesError> {
+        Ok(RuleSet::new(
+            self.rule_parser(),
+            self.rules()?,
+            self.tag_load_options.case_folding,
+        )?
+        .with_mode(self.evaluation_options.mode))
+    }
+
+    /// Lists every rule in the .rules files of the config directory, with the
+    /// file and line it was found on.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<LoadedRule>)` in file then line order
+    /// * `Err(RulesError)` if a rules file can't be read, or two rules have the same name
+    ///
+    /// # Examples
+    /// ```ignore
+    /// for loaded in rules.rules()? {
+    ///     println!("{}:{} {}", loaded.file, loaded.line, loaded.rule);
+    /// }
+    /// ```
+    pub fn rules(&self) -> Result<Vec<LoadedRule>, RulesError> {
+        let pattern = format!("{}/*.rules", self.config_dir);
+        let rules = rules::parse_rules_with_includes(
+            &self.config_dir,
+            file::read_files_in_dir_with_paths(&pattern)?,
+        )?;
+        rules::check_rule_names(&rules)?;
+
+        Ok(rules)
+    }
+
+    /// Finds the rules in the config directory which an object satisfies.
+    ///
+    /// An object missing a required tag matches no rules, or is an error, per
+    /// [`Rules::set_required_tag_policy`]. Any other object is validated
+    /// first, so unknown tags or values are reported as errors rather than
+    /// silently failing to match. Rules are matched as
+    /// compiled by [`Rules::load_tags`], or again after a write through this
+    /// API, without reading the .rules files; edits made by hand are seen
+    /// after the next [`Rules::load_tags`]. With the `webhooks`
+    /// feature and a notifier set, matches of rules annotated with `@notify`
+    /// are queued for their webhooks.
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * `Ok(Vec<LoadedRule>)` with every matching rule, highest priority first, then in file
+    ///   then line order
+    /// * `Err(RulesError)` if the object or a rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let mut obj = HashMap::new();
+    /// obj.insert("colour".to_string(), vec!["red".to_string()]);
+    /// let matched = rules.matching_rules(&obj)?;
+    /// ```
+    pub fn matching_rules(&self, obj: &Object) -> Result<Vec<LoadedRule>, RulesError> {
+        let timestamp = audit::unix_timestamp();
+        let matched = self.matching_rules_at(obj, timestamp)?;
+
+        #[cfg(feature = "webhooks")]
+        self.notify_matches(obj, &matched, timestamp);
+
+        Ok(matched)
+    }
+
+    /// Scores an object by the rules it satisfies, rather than listing them.
+    ///
+    /// Each matching rule adds its weight, set with a `@weight` annotation
+    /// above it, e.g. `@weight 2.5`. Rules without one weigh 1, so the score
+    /// of unweighted rules is the number matched. Matching is as for
+    /// [`Rules::matching_rules`], so under [`EvalMode::FirstMatch`] only the
+    /// first matching rule counts.
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * `Ok(f64)` with the sum of the weights of the matching rules, 0 if none match
+    /// * `Err(RulesError)` if the object or a rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let risk = rules.score(&obj)?;
+    /// if risk > 5.0 {
+    ///     println!("review");
+    /// }
+    /// ```
+    pub fn score(&self, obj: &Object) -> Result<f64, RulesError> {
+        let matched = self.matching_rules(obj)?;
+        Ok(types::total_weight(matched.iter().map(LoadedRule::weight)))
+    }
+
+    /// Evaluates an object held in memory, such as a request payload, against
+    /// the rules in the config directory.
+    ///
+    /// Nothing is written, so objects don't need to be saved to a .yaml file
+    /// first. Like [`Rules::matching_rules`], the object is validated, results
+    /// are cached if caching is enabled and matches are sent to webhooks. The
+    /// .rules files aren't read either, the rules being matched as compiled
+    /// by [`Rules::load_tags`].
+    ///
+    /// # Arguments
+    /// * `object` - HashMap representing the object's properties
+    ///
+    /// # Returns
+    /// * `Ok(Vec<MatchedRule>)` with every matching rule, highest priority first, then in file
+    ///   then line order
+    /// * `Err(RulesError)` if the object or a rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let payload: Object = serde_json::from_str(body)?;
+    /// for matched in rules.evaluate_object(&payload)? {
+    ///     println!("{} {}", matched.id, matched.rule);
+    /// }
+    /// ```
+    pub fn evaluate_object(&self, object: &Object) -> Result<Vec<MatchedRule>, RulesError> {
+        Ok(self
+            .matching_rules(object)?
+            .into_iter()
+            .map(MatchedRule::from)
+            .collect())
+    }
+
+    /// Finds the rules an object satisfies, with variables in rules filled in
+    /// from an evaluation context.
+    ///
+    /// `$ctx.<name>` in a rule is replaced by the context's value for
+    /// `name`, e.g. `- region = $ctx.region`, so one rule set can depend on
+    /// request-time parameters. `$today` is the evaluation date, e.g.
+    /// `2025-01-01`. Results aren't cached.
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    /// * `context` - Values for the `$ctx` variables
+    ///
+    /// # Returns
+    /// * `Ok(Vec<LoadedRule>)` with every matching rule, highest priority first, then in file
+    ///   then line order
+    /// * `Err(RulesError)` if the object or a rule is invalid, or a rule in effect uses a
+    ///   variable the context has no value for
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let context = EvalContext::new().with("region", "eu");
+    /// let matched = rules.evaluate_with_context(&obj, &context)?;
+    /// ```
+    pub fn evaluate_with_context(
+        &self,
+        obj: &Object,
+        context: &EvalContext,
+    ) -> Result<Vec<LoadedRule>, RulesError> {
+        if !self.check_required_tags(obj)? {
+            return Ok(Vec::new());
+        }
+        self.validate_object(obj)?;
+        let timestamp = audit::unix_timestamp();
+        let matched = self
+            .compiled()?
+            .matching_rules_at(obj, context, timestamp)?;
+
+        #[cfg(feature = "webhooks")]
+        self.notify_matches(obj, &matched, timestamp);
+
+        Ok(matched)
+    }
+
+    /// Finds the rules an object satisfies at a point in time.
+    ///
+    /// Rules annotated with `@effective_from` or `@expires` are only matched
+    /// within their window: from `@effective_from`, up to but not including
+    /// `@expires`. [`Rules::matching_rules`] uses the current time.
+    ///
+    /// # Arguments
+    /// * `obj` - HashMap representing the object's properties
+    /// * `timestamp` - Time to evaluate at, in Unix seconds
+    ///
+    /// # Returns
+    /// * `Ok(Vec<LoadedRule>)` with every matching rule in effect, highest priority first, then
+    ///   in file then line order
+    /// * `Err(RulesError)` if the object or a rule is invalid
+    ///
+    /// # Examples
+    /// ```ignore
+    /// // As the rules stood at the start of 2025
+    /// let matched = rules.matching_rules_at(&obj, 1735689600)?;
+    /// ```
+    pub fn matching_rules_at(
+        &self,
+        obj: &Object,
+        timestamp: u64,
+    ) -> Result<Vec<LoadedRule>, RulesError> {
+        if !self.check_required_tags(obj)? {
+            return Ok(Vec::new());
+        }
+        self.validate_object(obj)?;
+
+        if let Some(matched) = self.cached_matches(obj, timestamp) {
+            return Ok(matched);
+        }
+        let cache_version = self.cache_version();
+
+        let ruleset = self.compiled()?;
+        let matched = ruleset.matching_rules_at(obj, &EvalContext::default(), timestamp)?;
+        if let (Some(cache), Some(version)) = (&self.cache, cache_version) {
+            let valid = cache::validity(ruleset.rules(), timestamp);
+            cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(obj, &matched, version, valid);
+        }
+        Ok(matched)
+    }
+
+    // Rules `obj` matches at `timestamp`, drafts included, read from the .rules files -- e.g. for
+    // shadow evaluation of a candidate configuration
+    fn matching_rules_with_drafts(
+        &self,
+        obj: &Object,
+        timestamp: u64,
+    ) -> Result<Vec<LoadedRule>, RulesError> {
+        let started = Instant::now();
+        let rules = self.rules()?;
+        let matched = self.rule_parser().matching_rules(
+            &rules,
+            obj,
+            &EvalContext::default(),
+            timestamp,
+            self.evaluation_options.mode,
+        )?;
+
+        metrics::record_evaluation(rules.len(), &matched, started.elapsed());
+        Ok(matched)
+    }
+
+    /// Warnings about rules which are valid but probably mistakes, currently
+    /// rules whose `@expires` time has passed but which are still in a file,
+    /// rules which can never match or match every object, naming the
+    /// comparisons responsible, rules equivalent to an earlier rule in the
+    /// same file, rules implied by an earlier rule, and drafts sharing a name.
+    ///
+    /// Rules are in priority order, file then line, so a rule only matching
+    /// objects an earlier rule in effect at the same times also matches adds
+    /// nothing of its own, and is reported with the earlier rule named.
+    ///
+    /// Every rule is validated first.
+    ///
+    /// # Arguments
+    /// * `timestamp` - Time to check against, in Unix seconds
+    ///
+    /// # Returns
+    /// * `Ok(Vec<LintWarning>)` in file then line order, empty if nothing looks wrong
+    /// * `Err(RulesError)` if a rule is invalid, naming its file and line
+    ///
+    /// # Examples
+    /// ```ignore
+    /// for warning in rules.lint(unix_timestamp())? {
+    ///     println!("warning: {}", warning);
+    /// }
+    /// ```
+    pub fn lint(&self, timestamp: u64) -> Result<Vec<LintWarning>, RulesError> {
+        let parser = self.rule_parser();
+        let mut warnings = Vec::new();
+        let mut drafts: HashMap<String, (String, usize)> = HashMap::new();
+        let mut fingerprints: HashMap<(String, String), usize> = HashMap::new();
+        let mut earlier_rules: Vec<LoadedRule> = Vec::new();
+
+        for loaded in self.rules()? {
+            parser
+                .validate_rule(&loaded.rule)
+                .map_err(|e| rules::rule_error_at(&loaded, e))?;
+
+            if let Some(expires) = loaded.expires()
+                && expires <= timestamp
+            {
+                warnings.push(LintWarning {
+                    file: loaded.file.clone(),
+                    line: loaded.line,
+                    rule_id: loaded.id(),
+                    message: format!(
+                        "Rule expired at {} and can be removed: {}",
+                        time::format_timestamp(expires),
+                        loaded.rule
+                    ),
+                });
+            }
+
+            // Rules which can never or always match, naming the comparisons responsible
+            let satisfiable = parser.is_satisfiable(&loaded.rule)?;
+            if let Some(warning) = parser.match_warning(&loaded) {
+                warnings.push(LintWarning {
+                    file: loaded.file.clone(),
+                    line: loaded.line,
+                    rule_id: loaded.id(),
+                    message: format!("{}: {}", warning.message, loaded.rule),
+                });
+            }
+
+            // Rules which can never match are already warned about
+            let key = (loaded.file.clone(), parser.fingerprint(&loaded.rule)?);
+            let equivalent_to = fingerprints.get(&key).copied();
+            match equivalent_to {
+                _ if !satisfiable => {}
+                Some(line) => warnings.push(LintWarning {
+                    file: loaded.file.clone(),
+                    line: loaded.line,
+                    rule_id: loaded.id(),
+                    message: format!(
+                        "Rule is equivalent to the rule at line {}: {}",
+                        line, loaded.rule
+                    ),
+                }),
+                None => {
+                    fingerprints.insert(key, loaded.line);
+                }
+            }
+
+            if satisfiable && equivalent_to.is_none() {
+                for earlier in &earlier_rules {
+                    if earlier.in_effect_throughout(&loaded)
+                        && parser.implies(&loaded.rule, &earlier.rule)?
+                    {
+                        warnings.push(LintWarning {
+                            file: loaded.file.clone(),
+                            line: loaded.line,
+                            rule_id: loaded.id(),
+                            message: format!(
+                                "Rule only matches objects {}:{} already matches ({}): {}",
+                                earlier.file, earlier.line, earlier.rule, loaded.rule
+                            ),
+                        });
+                        break;
+                    }
+                }
+            }
+
+            if let Some(name) = loaded.draft_name() {
+                match drafts.get(name) {
+                    Some((file, line)) => warnings.push(LintWarning {
+                        file: loaded.file.clone(),
+                        line: loaded.line,
+                        rule_id: loaded.id(),
+                        message: format!(
+                            "Draft '{}' is also defined at {}:{}, so it can't be promoted",
+                            name, file, line
+                        ),
+                    }),
+                    None => {
+                        drafts.insert(name.to_string(), (loaded.file.clone(), loaded.line));
+                    }
+                }
+            } else {
+                earlier_rules.push(loaded);
+            }
+        }
+
+        Ok(warnings)
+    }
+
+    /// Pairs of rules which some object could match at once, with the
+    /// conditions under which both match, for review.
+    ///
+    /// Every matching rule applies to an object, so an overlap is often
+    /// intended, but it can also be a broad rule claiming objects a narrower
+    /// one was written for, or two rules disagreeing about the same objects.
+    /// Rules never in effect at the same time, and drafts, are left out. The
+    /// overlap is each combination of conditions which could hold together,
+    /// so `- colour = red, blue` and `- size = large` overlap on
+    /// `colour = blue & size = large` and `colour = red & size = large`.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<RuleConflict>)` in file then line order of the first rule
+    /// * `Err(RulesError)` if a rule is invalid, naming its file and line
+    ///
+    /// # Examples
+    /// ```ignore
+    /// for conflict in rules.rule_conflicts()? {
+    ///     println!("{}", conflict);
+    /// }
+    /// ```
+    pub fn rule_conflicts(&self) -> Result<Vec<RuleConflict>, RulesError> {
+        let parser = self.rule_parser();
+        let mut rules = self.rules()?;
+        rules.retain(|loaded| !loaded.is_draft());
+
+        for loaded in &rules {
+            parser
+                .validate_rule(&loaded.rule)
+                .map_err(|e| rules::rule_error_at(loaded, e))?;
+        }
+
+        let mut conflicts = Vec::new();
+        for (index, first) in rules.iter().enumerate() {
+            for second in &rules[index + 1..] {
+                if !first.in_effect_with(second) {
                     continue;
                 }
 
-                let (name, values) = tags::get_name_and_values_from_tag(line)?;
+                let overlap = parser.overlap(&first.rule, &second.rule)?;
+                if !overlap.is_empty() {
+                    conflicts.push(RuleConflict {
+                        first: first.clone(),
+                        second: second.clone(),
+                        overlap,
+                    });
+                }
+            }
+        }
 
-                // Normalize to lowercase for consistent lookup
-                let name = name.to_lowercase();
-                let values: Vec<String> = values.iter().map(|v| v.to_lowercase()).collect();
+        Ok(conflicts)
+    }
 
-                // Merge values if tag already exists
-                self.tags
-                    .entry(name)
-                    .and_modify(|existing_values| existing_values.extend(values.clone()))
-                    .or_insert(values);
+    /// Pairs of rules where one makes the other redundant, or where both
+    /// match some object but map it to different outcomes, across every rule
+    /// file.
+    ///
+    /// Rules are compared by the conjunctions they expand to, using the tag
+    /// definitions, so `- colour = red` subsumes `- colour = red & size = large`,
+    /// and `- shape = circle | shape = square` subsumes `- shape = circle` as
+    /// well as any rule when `shape` is `(required)` and lists only those
+    /// values. Rules matching exactly the same objects are reported once, as
+    /// equivalent. Rules which can never match, drafts, and rules never in
+    /// effect at the same time are left out. Rules too large to expand aren't
+    /// reported as subsumed.
+    ///
+    /// # Returns
+    /// * `Ok(RuleAnalysis)` with pairs in file then line order of the earlier rule
+    /// * `Err(RulesError)` if a rule is invalid, naming its file and line
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let analysis = rules.analyze()?;
+    /// for subsumption in &analysis.subsumptions {
+    ///     println!("{}", subsumption);
+    /// }
+    /// ```
+    pub fn analyze(&self) -> Result<RuleAnalysis, RulesError> {
+        let parser = self.rule_parser();
+        let mut rules = Vec::new();
+        for loaded in self.rules()? {
+            if loaded.is_draft() {
+                continue;
+            }
+            parser
+                .validate_rule(&loaded.rule)
+                .map_err(|e| rules::rule_error_at(&loaded, e))?;
+            if parser.is_satisfiable(&loaded.rule)? {
+                rules.push(loaded);
             }
         }
 
-        Ok(())
+        let mut analysis = RuleAnalysis::default();
+        for (index, first) in rules.iter().enumerate() {
+            for second in &rules[index + 1..] {
+                if !first.in_effect_with(second) {
+                    continue;
+                }
+
+                let narrows = parser.implies(&first.rule, &second.rule)?;
+                let broadens = parser.implies(&second.rule, &first.rule)?;
+                if narrows || broadens {
+                    let (broader, narrower) = if broadens {
+                        (first, second)
+                    } else {
+                        (second, first)
+                    };
+                    analysis.subsumptions.push(Subsumption {
+                        broader: broader.clone(),
+                        narrower: narrower.clone(),
+                        equivalent: narrows && broadens,
+                    });
+                }
+
+                if let (Some(outcome), Some(other_outcome)) = (first.outcome(), second.outcome())
+                    && outcome != other_outcome
+                {
+                    let overlap = parser.overlap(&first.rule, &second.rule)?;
+                    if !overlap.is_empty() {
+                        analysis.outcome_conflicts.push(RuleConflict {
+                            first: first.clone(),
+                            second: second.clone(),
+                            overlap,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(analysis)
     }
 
-    /// Writes a tag to a .tags file.
+    /// Rules which duplicate an earlier rule in the same file, for review or
+    /// removal with [`Rules::dedup_rules`].
     ///
-    /// # Arguments
-    /// * `file_name` - Name of the file (with or without .tags extension)
-    /// * `tag_name` - Name of the tag (without the leading '-')
-    /// * `tag_values` - Vector of values for the tag
+    /// Rules are compared in canonical form (see [`Rules::canonicalize`]), so
+    /// spacing, case, grouping and operand order are ignored: `-colour=red`
+    /// duplicates `- colour = red`, and `- (size = small, large) & colour = red`
+    /// duplicates `- colour = red & (size = large | size = small)`. Their
+    /// priorities, outcomes and annotations must be the same too, but their
+    /// names aren't compared.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<RuleDuplicate>)` in file then line order, each with the first rule it duplicates
+    /// * `Err(RulesError)` if a rule is invalid, naming its file and line
     ///
     /// # Examples
     /// ```ignore
-    /// rules.write_tag("my_tags", "colour", vec!["red", "blue"])?;
+    /// for duplicate in rules.duplicate_rules()? {
+    ///     println!("{}", duplicate);
+    /// }
     /// ```
-    pub fn write_tag(
-        &mut self,
-        file_name: &str,
-        tag_name: impl Into<String>,
-        tag_values: Vec<impl Into<String>>,
-    ) -> Result<(), RulesError> {
-        let tag_name = tag_name.into();
-        let tag_values: Vec<String> = tag_values.into_iter().map(|v| v.into()).collect();
+    pub fn duplicate_rules(&self) -> Result<Vec<RuleDuplicate>, RulesError> {
+        let parser = self.rule_parser();
+        let mut rules = self.rules()?;
+        rules.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
 
-        // Write to file
-        crate::api::write::tag::write_with_base_dir(
-            file_name,
-            tag_name.clone(),
-            tag_values.clone(),
-            &self.config_dir,
-        )?;
+        // First rule of each file and duplicate key, with rules differing in annotations kept apart
+        let mut first: HashMap<(String, String), Vec<LoadedRule>> = HashMap::new();
+        let mut duplicates = Vec::new();
+        for loaded in rules {
+            let key = parser
+                .duplicate_key(&loaded.rule)
+                .map_err(|e| rules::rule_error_at(&loaded, e))?;
+            let earlier = first.entry((loaded.file.clone(), key)).or_default();
+
+            match earlier
+                .iter()
+                .find(|earlier| earlier.annotations == loaded.annotations)
+            {
+                Some(original) => duplicates.push(RuleDuplicate {
+                    duplicate_of: original.clone(),
+                    rule: loaded,
+                }),
+                None => earlier.push(loaded),
+            }
+        }
 
-        // Normalize to lowercase for consistent lookup in cache
-        let tag_name_lower = tag_name.to_lowercase();
-        let tag_values_lower: Vec<String> = tag_values.iter().map(|v| v.to_lowercase()).collect();
+        Ok(duplicates)
+    }
 
-        // Update cached tags (append if exists)
-        self.tags
-            .entry(tag_name_lower)
-            .and_modify(|existing| existing.extend(tag_values_lower.clone()))
-            .or_insert(tag_values_lower);
+    /// Combinations of tag values which no rule matches, to find gaps in the
+    /// rules before real objects fall into them.
+    ///
+    /// Every combination of the tags the rules compare is evaluated, each tag
+    /// having one of its values or, unless it's `(required)`, none at all.
+    /// Combinations breaking an `@exclusive` group are left out, as are tags
+    /// which list no values, like pattern tags. Only rules in effect now,
+    /// and not drafts, count as covering a combination. Tags with many
+    /// values multiply quickly, so more than 100,000 combinations is an error.
+    ///
+    /// # Arguments
+    /// * `max_examples` - Most uncovered combinations to return
+    ///
+    /// # Returns
+    /// * `Ok(CoverageReport)` with how many combinations match no rule, and the first of them
+    /// * `Err(RulesError)` if a rule is invalid or there are too many combinations
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let report = rules.coverage_gaps(10)?;
+    /// print!("{}", report);
+    /// ```
+    pub fn coverage_gaps(&self, max_examples: usize) -> Result<CoverageReport, RulesError> {
+        let timestamp = audit::unix_timestamp();
+        let rules: Vec<String> = self
+            .rules()?
+            .into_iter()
+            .filter(|loaded| !loaded.is_draft() && loaded.in_effect_at(timestamp))
+            .map(|loaded| loaded.rule)
+            .collect();
+        let rules: Vec<&str> = rules.iter().map(String::as_str).collect();
 
-        Ok(())
+        self.rule_parser().coverage(&rules, max_examples)
     }
 
-    /// Writes a rule to a .rules file.
+    /// Evaluates a corpus of objects, such as a day of production traffic,
+    /// and reports which rules and tag values it never exercised.
     ///
-    /// The rule is validated against the current tag definitions before writing.
+    /// Every object is checked against every rule in effect now, whatever
+    /// the [`EvalMode`], counting the objects matching each rule and
+    /// satisfying each of its comparisons. A comparison nothing satisfies
+    /// points at the part of a rule that's dead. Values of the tags the
+    /// rules compare which no object held are listed too. Drafts are left
+    /// out, and rules outside their time window match nothing.
     ///
     /// # Arguments
-    /// * `file_name` - Name of the file (with or without .rules extension)
-    /// * `rule` - The rule string to write (should start with '-')
+    /// * `objects` - Objects to evaluate, each validated first
+    ///
+    /// # Returns
+    /// * `Ok(RuleCoverage)` with the hits of each rule and comparison, and the values never held
+    /// * `Err(RulesError)` if an object or rule is invalid, or a rule uses a `$ctx` variable
     ///
     /// # Examples
     /// ```ignore
-    /// rules.write_rule("my_rules", "- colour = red & size = large")?;
+    /// let coverage = rules.rule_coverage(&objects)?;
+    /// for loaded in coverage.unmatched_rules() {
+    ///     println!("never matched: {}", loaded.rule);
+    /// }
     /// ```
-    pub fn write_rule(&self, file_name: &str, rule: &str) -> Result<(), RulesError> {
-        crate::api::write::rule::write_with_base_dir(
-            file_name,
-            rule,
-            self.tags.clone(),
-            &self.config_dir,
-        )
+    pub fn rule_coverage(&self, objects: &[Object]) -> Result<RuleCoverage, RulesError> {
+        let timestamp = audit::unix_timestamp();
+        let parser = self.rule_parser();
+        let context = EvalContext::default();
+        let fold = |s: &str| self.tag_load_options.case_folding.fold(s);
+        for obj in objects {
+            self.validate_object(obj)?;
+        }
+
+        let mut coverage = RuleCoverage {
+            objects: objects.len(),
+            ..Default::default()
+        };
+        // Folded values of each compared tag which an object held, by folded tag name
+        let mut held: HashMap<TagName, HashSet<String>> = HashMap::new();
+
+        for loaded in self.rules()? {
+            if loaded.is_draft() {
+                continue;
+            }
+            let mut hits = RuleHits {
+                rule: loaded.clone(),
+                matched: 0,
+                clauses: Vec::new(),
+            };
+
+            for obj in objects.iter().filter(|_| loaded.in_effect_at(timestamp)) {
+                let clause_hits = parser
+                    .clause_hits(&loaded.rule, obj, &context, timestamp)
+                    .map_err(|e| rules::rule_error_at(&loaded, e))?;
+                for (i, (clause, matched)) in clause_hits.into_iter().enumerate() {
+                    let values = obj
+                        .iter()
+                        .filter(|(tag_name, _)| fold(tag_name) == fold(&clause.tag_name))
+                        .flat_map(|(_, values)| values.iter().map(|value| fold(value)));
+                    held.entry(fold(&clause.tag_name))
+                        .or_default()
+                        .extend(values);
+
+                    if hits.clauses.len() <= i {
+                        hits.clauses.push(ClauseHits { clause, matched: 0 });
+                    }
+                    hits.clauses[i].matched += usize::from(matched);
+                }
+
+                let matched = parser
+                    .rule_matches_with_context(&loaded.rule, obj, &context, timestamp)
+                    .map_err(|e| rules::rule_error_at(&loaded, e))?;
+                hits.matched += usize::from(matched);
+            }
+
+            // Rules no object was checked against still list their comparisons
+            if hits.clauses.is_empty() {
+                hits.clauses = parser
+                    .clauses(&loaded.rule)
+                    .map_err(|e| rules::rule_error_at(&loaded, e))?
+                    .into_iter()
+                    .map(|clause| ClauseHits { clause, matched: 0 })
+                    .collect();
+            }
+            for clause_hits in &hits.clauses {
+                held.entry(fold(&clause_hits.clause.tag_name)).or_default();
+            }
+            coverage.rules.push(hits);
+        }
+
+        for (tag_name, values) in &self.tags {
+            let Some(held) = held.get(&fold(tag_name)) else {
+                continue;
+            };
+            let unexercised: Vec<String> = values
+                .iter()
+                .filter(|value| !held.contains(&fold(value)))
+                .cloned()
+                .collect();
+            if !unexercised.is_empty() {
+                coverage
+                    .unexercised_values
+                    .insert(tag_name.clone(), unexercised);
+            }
+        }
+
+        Ok(coverage)
     }
 
-    /// Writes an object definition to a .yaml file.
+    /// Runs the example objects given under rules with `#test:` comments.
+    ///
+    /// A `#test: match { tag: value }` line under a rule says the rule should
+    /// match the object, and `#test: no_match { ... }` that it shouldn't.
+    /// Tests are comments, so they never affect evaluation. Each object is
+    /// validated, then checked against the rule's condition alone, whatever
+    /// its `@effective_from`, `@expires` or `@draft` annotations.
+    ///
+    /// # Returns
+    /// * `Ok(Vec<InlineTestResult>)` in file then line order, passing or not
+    /// * `Err(RulesError)` if a rules file can't be read or has a malformed test
+    ///
+    /// # Examples
+    /// ```ignore
+    /// for result in rules.run_inline_tests()? {
+    ///     if !result.passed() {
+    ///         println!("{}", result);
+    ///     }
+    /// }
+    /// ```
+    pub fn run_inline_tests(&self) -> Result<Vec<InlineTestResult>, RulesError> {
+        let pattern = format!("{}/*.rules", self.config_dir);
+        let parser = self.rule_parser();
+        let mut results: Vec<InlineTestResult> = Vec::new();
+
+        for (path, content) in file::read_files_in_dir_with_paths(&pattern)? {
+            for test in rules::parse_inline_tests_from_str(
+                &path,
+                &content,
+                self.tag_load_options.case_folding,
+            )? {
+                let outcome = self
+                    .validate_object(&test.object)
+                    .and_then(|_| parser.rule_matches(&test.rule.rule, &test.object))
+                    .map_err(|e| e.to_string());
+                results.push(InlineTestResult { test, outcome });
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Runs the `@on_match` Rhai script of each matched rule that has one.
+    ///
+    /// Scripts are paths relative to the config directory and see the object
+    /// as `object`, the rule as `rule`, `file` and `line`, and every matched
+    /// rule as `matched`. [`Rules::matching_rules`] doesn't run them itself.
     ///
     /// # Arguments
-    /// * `file_name` - Name of the file
-    /// * `obj_type` - Type/category of the object
-    /// * `obj` - HashMap representing the object's properties
+    /// * `obj` - The object the rules were matched against
+    /// * `matched` - Rules returned by [`Rules::matching_rules`]
+    ///
+    /// # Returns
+    /// * `Ok(Vec<ScriptResult>)` with each script's output, in rule order
+    /// * `Err(RulesError)` if a script is missing, outside the config directory or fails
     ///
     /// # Examples
     /// ```ignore
-    /// let mut obj = HashMap::new();
-    /// obj.insert("colour".to_string(), vec!["red".to_string()]);
-    /// rules.write_object("objects.yaml", "shapes", obj)?;
+    /// let matched = rules.matching_rules(&obj)?;
+    /// for result in rules.run_match_scripts(&obj, &matched)? {
+    ///     println!("{}: {}", result.script, result.output);
+    /// }
     /// ```
-    pub fn write_object(
+    #[cfg(feature = "scripting")]
+    pub fn run_match_scripts(
         &self,
-        file_name: &str,
-        obj_type: impl Into<String>,
-        obj: HashMap<String, Vec<String>>,
-    ) -> Result<(), RulesError> {
-        crate::api::write::object::write_with_base_dir(
-            file_name,
-            obj_type.into(),
-            obj,
-            &self.config_dir,
-        )
+        obj: &Object,
+        matched: &[LoadedRule],
+    ) -> Result<Vec<crate::scripting::ScriptResult>, RulesError> {
+        crate::scripting::run_on_match(&self.config_dir, obj, matched)
     }
 
-    /// Validates a rule string against the current tag definitions.
+    /// Sends matches of rules annotated with `@notify <webhook>` to webhooks.
     ///
-    /// This checks syntax and ensures all referenced tags and values exist.
+    /// Every match found by [`Rules::matching_rules`] of a rule naming a
+    /// configured webhook is queued, then POSTed as JSON in batches from a
+    /// background thread, so evaluation never waits on a webhook. Failed
+    /// batches are retried with backoff. Replaces any earlier notifier,
+    /// which sends its queued events first.
     ///
     /// # Arguments
-    /// * `rule` - The rule string to validate (should start with '-')
+    /// * `notifier` - Webhook URLs by name, and how events are batched and retried
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let webhooks = HashMap::from([(
+    ///     "pager".to_string(),
+    ///     "https://hooks.example.com/pager".to_string(),
+    /// )]);
+    /// rules.set_notifier(Notifier::new(webhooks, WebhookOptions::default()));
+    /// ```
+    #[cfg(feature = "webhooks")]
+    pub fn set_notifier(&mut self, notifier: crate::webhooks::Notifier) {
+        self.notifier = Some(notifier);
+    }
+
+    /// Stops sending matches to webhooks, after sending any queued events.
+    #[cfg(feature = "webhooks")]
+    pub fn clear_notifier(&mut self) {
+        self.notifier = None;
+    }
+
+    /// The notifier set with [`Rules::set_notifier`], e.g. to flush it or read its stats.
+    #[cfg(feature = "webhooks")]
+    pub fn notifier(&self) -> Option<&crate::webhooks::Notifier> {
+        self.notifier.as_ref()
+    }
+
+    // Queues the matches of rules annotated with @notify for their webhooks, if a notifier is set
+    #[cfg(feature = "webhooks")]
+    pub(crate) fn notify_matches(&self, obj: &Object, matched: &[LoadedRule], timestamp: u64) {
+        if let Some(notifier) = &self.notifier {
+            let object_id = format!("{:016x}", cache::fingerprint(obj, 0));
+            notifier.notify(obj, &object_id, matched, timestamp);
+        }
+    }
+
+    fn rule_parser(&self) -> RuleParser {
+        RuleParser::new(self.tags.clone())
+            .with_tag_constraints(self.tag_constraints.clone())
+            .with_case_folding(self.tag_load_options.case_folding)
+            .with_plugins(self.plugins.clone())
+            .with_exclusive_groups(self.exclusive_groups.clone())
+            .with_unknown_policy(self.evaluation_options.unknown_values)
+            .with_missing_tag_policy(self.evaluation_options.missing_tags)
+            .with_precedence(self.operator_precedence.clone())
+            .with_rule_limits(self.rule_limits)
+    }
+
+    /// Evaluates every object in the .yaml files of the config directory
+    /// against the rules in effect now.
+    ///
+    /// Drafts are left out. Objects missing a required tag are left out, or
+    /// fail evaluation, per [`Rules::set_required_tag_policy`]. Every other
+    /// object is validated first, so unknown tags or values are reported as
+    /// errors naming the object's file and position. Rules are compiled once
+    /// and only the subrules an object could match are checked, as for
+    /// [`Engine::evaluate_object`]. The report counts how often each rule was
+    /// checked and matched, see [`EvaluationReport::stats`], for finding rules
+    /// to tune or prune.
     ///
     /// # Returns
-    /// * `Ok(())` if the rule is valid
-    /// * `Err(RulesError)` with details if validation fails
+    /// * `Ok(EvaluationReport)` with the rules each object matched, in file order
+    /// * `Err(RulesError)` if a file, object or rule is invalid
     ///
     /// # Examples
     /// ```ignore
-    /// rules.validate_rule("- colour = red & size = large")?;
+    /// let report = rules.evaluate()?;
+    /// for result in report.matched() {
+    ///     println!("{} matched {} rules", result.object.obj_type, result.matched.len());
+    /// }
+    /// for stats in report.stats().iter().filter(|stats| stats.matched == 0) {
+    ///     println!("never matched: {}", stats.rule.rule);
+    /// }
     /// ```
-    pub fn validate_rule(&self, rule: &str) -> Result<(), RulesError> {
-        let parser = RuleParser::new(self.tags.clone());
-        parser.validate_rule(rule)
+    pub fn evaluate(&self) -> Result<EvaluationReport, RulesError> {
+        let engine = self.engine()?;
+        let ruleset = engine.ruleset();
+        let timestamp = audit::unix_timestamp();
+
+        // Counted in the rule set's priority order, and reported in file then line order
+        let mut rule_stats: Vec<RuleStats> = ruleset
+            .rules()
+            .iter()
+            .map(|loaded| RuleStats {
+                rule: loaded.clone(),
+                evaluated: 0,
+                matched: 0,
+            })
+            .collect();
+
+        let mut results: Vec<ObjectMatches> = Vec::new();
+        for loaded_object in engine.objects() {
+            self.validate_object(&loaded_object.object)
+                .map_err(|e| object_error_at(loaded_object, e))?;
+
+            let started = Instant::now();
+            let evaluated = ruleset.evaluated_rules(
+                &loaded_object.object,
+                &EvalContext::default(),
+                timestamp,
+            )?;
+            let mut matched: Vec<LoadedRule> = Vec::new();
+            for (rule_index, is_match) in evaluated {
+                let stats = &mut rule_stats[rule_index];
+                stats.evaluated += 1;
+                if is_match {
+                    stats.matched += 1;
+                    matched.push(stats.rule.clone());
+                }
+            }
+            metrics::record_evaluation(rule_stats.len(), &matched, started.elapsed());
+            results.push(ObjectMatches {
+                object: loaded_object.clone(),
+                matched,
+            });
+        }
+
+        Ok(EvaluationReport {
+            timestamp,
+            results,
+            skipped_objects: engine.stats().skipped_objects,
+            rule_stats: ruleset
+                .file_order()
+                .iter()
+                .map(|rule_index| rule_stats[*rule_index].clone())
+                .collect(),
+        })
     }
 
-    /// Evaluates rules against objects.
+    /// Evaluates every object in the .yaml files of the config directory
+    /// against the rules in effect now, calling `callback` with each match as
+    /// it's found rather than collecting them.
+    ///
+    /// Rules are compiled once for all the objects. As with
+    /// [`Rules::evaluate`], drafts are left out, objects missing a required
+    /// tag are left out or fail evaluation, and invalid objects are errors
+    /// naming their file and position. Matches already passed to `callback`
+    /// stay delivered if a later object fails.
+    ///
+    /// # Arguments
+    /// * `callback` - Called with the object and rule of each match, objects in file order and
+    ///   each object's rules highest priority first, then in file then line order
     ///
-    /// Note: Currently uses the default orchestrator which reads from the config directory.
-    /// Parameters will be supported in a future update.
+    /// # Returns
+    /// * `Ok(usize)` with the number of matches passed to `callback`
+    /// * `Err(RulesError)` if a file, object or rule is invalid
     ///
     /// # Examples
     /// ```ignore
-    /// rules.evaluate()?;
+    /// rules.evaluate_with(|object_id, matched| {
+    ///     sender.send(format!("{} {}", object_id, matched.id)).unwrap();
+    /// })?;
     /// ```
-    pub fn evaluate(&self) -> Result<(), RulesError> {
-        crate::api::entry::evaluate()
+    pub fn evaluate_with<F: FnMut(ObjectId, &MatchedRule)>(
+        &self,
+        mut callback: F,
+    ) -> Result<usize, RulesError> {
+        let engine = self.engine()?;
+        let mut matches = 0;
+
+        for loaded_object in engine.objects() {
+            let matched = engine
+                .ruleset()
+                .evaluate(&loaded_object.object)
+                .map_err(|e| object_error_at(loaded_object, e))?;
+            for matched_rule in &matched {
+                callback(loaded_object.id(), matched_rule);
+            }
+            matches += matched.len();
+        }
+
+        Ok(matches)
     }
 
     /// Debug method to print loaded tags
@@ -225,3 +3426,55 @@ impl Rules {
         }
     }
 }
+
+// An object's error with where it was found in front -- e.g.
+// "my_objects.yaml: object 0 of type 'shapes': Object contains invalid TagName: doors"
+fn object_error_at(loaded: &LoadedObject, error: RulesError) -> RulesError {
+    match error {
+        RulesError::ObjectParseError(msg) => RulesError::ObjectParseError(format!(
+            "{}: object {} of type '{}': {}",
+            loaded.file, loaded.index, loaded.obj_type, msg
+        )),
+        other => other,
+    }
+}
+
+// Adds `tag_values` to `tag_name`'s cached values, skipping those it already has, or caches the
+// tag if it's new
+fn merge_tag(tags: &mut HashMap<TagName, TagValues>, tag_name: TagName, tag_values: TagValues) {
+    let cached = tags.entry(tag_name).or_default();
+    for value in tag_values {
+        if !cached.contains(&value) {
+            cached.push(value);
+        }
+    }
+}
+
+// Only files directly in the config directory are versioned -- e.g. "my_rules.rules", not "../x"
+fn check_config_file_name(file: &str) -> Result<(), RulesError> {
+    if Path::new(file).file_name().and_then(|name| name.to_str()) != Some(file) {
+        return Err(RulesError::IoError(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not a file in the config directory", file),
+        )));
+    }
+
+    Ok(())
+}
+
+// Sources with each of `files` with the extension swapped in or added -- e.g. a restored .tags file
+fn with_replacements(
+    mut sources: Vec<(String, String)>,
+    files: &[(String, String)],
+    extension: &str,
+) -> Vec<(String, String)> {
+    for (file, content) in files.iter().filter(|(file, _)| file.ends_with(extension)) {
+        let is_replaced = |path: &str| Path::new(path).file_name() == Some(file.as_ref());
+        match sources.iter_mut().find(|(path, _)| is_replaced(path)) {
+            Some(source) => source.1 = content.clone(),
+            None => sources.push((file.clone(), content.clone())),
+        }
+    }
+
+    sources
+}
@@ -0,0 +1,221 @@
+// Rhai scripts run when an annotated rule matches -- `@on_match notify.rhai`
+use crate::err::RulesError;
+use crate::types::{LoadedRule, Object};
+
+use rhai::{Array, Dynamic, Engine, Map, Scope};
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+// Annotation naming the script to run -- e.g. "@on_match notify.rhai"
+pub const ON_MATCH: &str = "on_match";
+
+// Limits so a runaway script can't stall evaluation
+const MAX_OPERATIONS: u64 = 100_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+
+// Outcome of one on_match script
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptResult {
+    // Rule the script is attached to -- e.g. "config/my_rules.rules"
+    pub file: String,
+    pub line: usize,
+    // Script path as written in the annotation -- e.g. "notify.rhai"
+    pub script: String,
+    // Value the script evaluated to, empty for ()
+    pub output: String,
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(MAX_CALL_LEVELS);
+    engine.set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH);
+    engine
+}
+
+fn script_error(loaded: &LoadedRule, msg: impl std::fmt::Display) -> RulesError {
+    RulesError::IoError(io::Error::other(format!(
+        "{}:{}: {}",
+        loaded.file, loaded.line, msg
+    )))
+}
+
+// Object as a Rhai map of tag name to array of values -- e.g. #{ colour: ["red"] }
+fn object_to_map(obj: &Object) -> Map {
+    obj.iter()
+        .map(|(name, values)| {
+            let values: Array = values.iter().cloned().map(Dynamic::from).collect();
+            (name.as_str().into(), Dynamic::from_array(values))
+        })
+        .collect()
+}
+
+// Scripts must stay inside the config directory -- e.g. "hooks/notify.rhai", not "../notify.rhai"
+fn check_script_path(loaded: &LoadedRule, script: &str) -> Result<(), RulesError> {
+    let path = Path::new(script);
+    let escapes = path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+
+    if script.is_empty() || escapes {
+        return Err(RulesError::IoError(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{}:{}: Script path must be relative to the config directory: '{}'",
+                loaded.file, loaded.line, script
+            ),
+        )));
+    }
+
+    Ok(())
+}
+
+// Runs the on_match script of each matched rule that has one, in order. Each script sees
+// `object`, `rule`, `file`, `line` and `matched` (every matched rule) in scope.
+pub fn run_on_match(
+    config_dir: &str,
+    obj: &Object,
+    matched: &[LoadedRule],
+) -> Result<Vec<ScriptResult>, RulesError> {
+    let engine = engine();
+    let object = object_to_map(obj);
+    let matched_rules: Array = matched
+        .iter()
+        .map(|loaded| Dynamic::from(loaded.rule.clone()))
+        .collect();
+    let mut results = Vec::new();
+
+    for loaded in matched {
+        let Some(script) = loaded.annotation(ON_MATCH) else {
+            continue;
+        };
+        check_script_path(loaded, script)?;
+
+        let path = Path::new(config_dir).join(script);
+        let source = fs::read_to_string(&path).map_err(|e| {
+            RulesError::IoError(io::Error::new(
+                e.kind(),
+                format!(
+                    "{}:{}: Failed to read script '{}': {}",
+                    loaded.file,
+                    loaded.line,
+                    path.display(),
+                    e
+                ),
+            ))
+        })?;
+
+        let mut scope = Scope::new();
+        scope.push_constant("object", object.clone());
+        scope.push_constant("rule", loaded.rule.clone());
+        scope.push_constant("file", loaded.file.clone());
+        scope.push_constant("line", loaded.line as i64);
+        scope.push_constant("matched", matched_rules.clone());
+
+        let value = engine
+            .eval_with_scope::<Dynamic>(&mut scope, &source)
+            .map_err(|e| script_error(loaded, format!("Script '{}' failed: {}", script, e)))?;
+
+        results.push(ScriptResult {
+            file: loaded.file.clone(),
+            line: loaded.line,
+            script: script.to_string(),
+            output: if value.is_unit() {
+                String::new()
+            } else {
+                value.to_string()
+            },
+        });
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rules;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+
+        fs::write(format!("{}/test.tags", test_dir), "- colour: red, blue").unwrap();
+        fs::write(
+            format!("{}/test.rules", test_dir),
+            "@on_match notify.rhai\n- colour = red\n- colour = blue",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/notify.rhai", test_dir),
+            "`${rule} at ${line}: ${object.colour[0]}, ${matched.len()} matched`",
+        )
+        .unwrap();
+
+        test_dir
+    }
+
+    fn object(colour: &str) -> Object {
+        let mut obj = Object::new();
+        obj.insert("colour".to_string(), vec![colour.to_string()]);
+        obj
+    }
+
+    #[test]
+    fn test_run_on_match() {
+        let test_dir = setup_test_env("test_scripting_on_match");
+        let mut rules = Rules::new(&test_dir);
+        rules.load_tags().unwrap();
+
+        let obj = object("red");
+        let matched = rules.matching_rules(&obj).unwrap();
+        let results = rules.run_match_scripts(&obj, &matched).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].script, "notify.rhai");
+        assert_eq!(results[0].line, 2);
+        assert_eq!(results[0].output, "- colour = red at 2: red, 1 matched");
+
+        // Rules without an on_match annotation run nothing
+        let obj = object("blue");
+        let matched = rules.matching_rules(&obj).unwrap();
+        assert!(rules.run_match_scripts(&obj, &matched).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[test]
+    fn test_run_on_match_errors() {
+        let test_dir = setup_test_env("test_scripting_on_match_errors");
+        let mut rules = Rules::new(&test_dir);
+        rules.load_tags().unwrap();
+        let obj = object("red");
+
+        fs::write(format!("{}/notify.rhai", test_dir), "loop {}").unwrap();
+        let matched = rules.matching_rules(&obj).unwrap();
+        let result = rules.run_match_scripts(&obj, &matched);
+        if let Err(RulesError::IoError(e)) = result {
+            assert!(e.to_string().contains("test.rules:2"));
+        } else {
+            panic!("Expected runaway script to fail");
+        }
+
+        fs::write(
+            format!("{}/test.rules", test_dir),
+            "@on_match ../notify.rhai\n- colour = red",
+        )
+        .unwrap();
+        rules.load_tags().unwrap();
+        let matched = rules.matching_rules(&obj).unwrap();
+        let result = rules.run_match_scripts(&obj, &matched);
+        if let Err(RulesError::IoError(e)) = result {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        } else {
+            panic!("Expected script outside the config directory to be rejected");
+        }
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
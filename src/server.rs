@@ -0,0 +1,372 @@
+// HTTP API exposing the engine -- `rules serve`
+use crate::Rules;
+use crate::err::RulesError;
+use crate::types::{LoadedRule, Object, Severity};
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(feature = "metrics")]
+use crate::utils::metrics::EVALUATION_SECONDS;
+#[cfg(feature = "metrics")]
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+#[cfg(feature = "metrics")]
+use std::sync::OnceLock;
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:3000";
+
+// Evaluation latency histogram buckets, in seconds
+#[cfg(feature = "metrics")]
+const LATENCY_BUCKETS: [f64; 8] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5];
+
+type SharedRules = Arc<RwLock<Rules>>;
+
+// Rules errors as JSON -- config problems are the caller's, IO problems are ours
+struct ApiError(RulesError);
+
+impl From<RulesError> for ApiError {
+    fn from(error: RulesError) -> Self {
+        ApiError(error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.0 {
+            RulesError::TagParseError(_)
+            | RulesError::RuleParseError(_)
+            | RulesError::ObjectParseError(_)
+            | RulesError::RuleLimitError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (status, Json(json!({ "error": self.0.to_string() }))).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct ValidateRequest {
+    rule: String,
+}
+
+#[derive(Deserialize)]
+struct EvaluateRequest {
+    object: HashMap<String, Value>,
+}
+
+// A panicking handler can't leave Rules half-updated, so a poisoned lock is still usable
+fn read(rules: &SharedRules) -> RwLockReadGuard<'_, Rules> {
+    rules.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write(rules: &SharedRules) -> RwLockWriteGuard<'_, Rules> {
+    rules.write().unwrap_or_else(|e| e.into_inner())
+}
+
+fn rule_json(loaded: &LoadedRule) -> Value {
+    let meta = loaded.meta();
+    json!({
+        "id": loaded.id().to_string(),
+        "file": loaded.file,
+        "line": loaded.line,
+        "rule": loaded.rule,
+        "name": loaded.name(),
+        "outcome": loaded.outcome(),
+        "description": meta.description,
+        "severity": meta.severity.as_ref().map(Severity::as_str),
+        "owner": meta.owner,
+    })
+}
+
+// JSON object payload as an Object -- e.g. {"colour": "red", "doors": [3, 5]}
+fn json_to_object(payload: HashMap<String, Value>) -> Result<Object, RulesError> {
+    let scalar = |value: &Value| match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        other => Err(RulesError::ObjectParseError(format!(
+            "Object values must be scalars or lists of scalars, found {}",
+            other
+        ))),
+    };
+
+    payload
+        .into_iter()
+        .map(|(tag_name, value)| {
+            let values = match &value {
+                Value::Array(items) => items.iter().map(scalar).collect::<Result<_, _>>()?,
+                other => vec![scalar(other)?],
+            };
+            Ok((tag_name, values))
+        })
+        .collect()
+}
+
+async fn list_tags(State(rules): State<SharedRules>) -> Json<Value> {
+    let rules = read(&rules);
+    let tags: BTreeMap<_, _> = rules.tags().iter().collect();
+
+    Json(json!({ "tags": tags }))
+}
+
+async fn list_rules(State(rules): State<SharedRules>) -> Result<Json<Value>, ApiError> {
+    let loaded = read(&rules).rules()?;
+    let rules: Vec<Value> = loaded.iter().map(rule_json).collect();
+
+    Ok(Json(json!({ "rules": rules })))
+}
+
+async fn validate_rule(
+    State(rules): State<SharedRules>,
+    Json(request): Json<ValidateRequest>,
+) -> Json<Value> {
+    match read(&rules).validate_rule(&request.rule) {
+        Ok(()) => Json(json!({ "valid": true })),
+        Err(e) => Json(json!({ "valid": false, "error": e.to_string() })),
+    }
+}
+
+async fn evaluate(
+    State(rules): State<SharedRules>,
+    Json(request): Json<EvaluateRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let obj = json_to_object(request.object)?;
+    let matched = read(&rules).matching_rules(&obj)?;
+    let matched: Vec<Value> = matched.iter().map(rule_json).collect();
+
+    Ok(Json(json!({ "matched": matched })))
+}
+
+async fn reload(State(rules): State<SharedRules>) -> Result<Json<Value>, ApiError> {
+    let mut rules = write(&rules);
+    rules.load_tags()?;
+
+    Ok(Json(json!({
+        "tags": rules.tags().len(),
+        "rules": rules.rules()?.len(),
+    })))
+}
+
+// Prometheus recorder for the process, installed the first time a router is built. If the
+// embedding application installed its own recorder first, that one is kept and /metrics is empty.
+#[cfg(feature = "metrics")]
+fn prometheus() -> &'static PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+    HANDLE.get_or_init(|| {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Full(EVALUATION_SECONDS.into()), &LATENCY_BUCKETS)
+            .expect("latency buckets are not empty")
+            .build_recorder();
+        let handle = recorder.handle();
+        let _ = metrics::set_global_recorder(recorder);
+        handle
+    })
+}
+
+pub fn router(rules: Rules) -> Router {
+    let router = Router::new()
+        .route("/tags", get(list_tags))
+        .route("/rules", get(list_rules))
+        .route("/rules/validate", post(validate_rule))
+        .route("/evaluate", post(evaluate))
+        .route("/reload", post(reload));
+
+    #[cfg(feature = "metrics")]
+    let router = {
+        let handle = prometheus();
+        router.route("/metrics", get(move || async move { handle.render() }))
+    };
+
+    router.with_state(Arc::new(RwLock::new(rules)))
+}
+
+// Load the config in `config_dir` and serve it on `addr` until the process is stopped
+pub fn serve(config_dir: &str, addr: &str) -> Result<(), RulesError> {
+    let mut rules = Rules::new(config_dir);
+    rules.load_tags()?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        axum::serve(listener, router(rules)).await?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{Body, to_bytes};
+    use axum::http::Request;
+    use std::fs;
+    use tower::ServiceExt;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour: red, blue\n- size: small < medium < large",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/test.rules", test_dir),
+            "# Rules\n- colour = red & size > small\n- colour = blue",
+        )
+        .unwrap();
+
+        test_dir
+    }
+
+    async fn send(router: &Router, method: &str, uri: &str, body: Value) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_server_endpoints() {
+        let test_dir = setup_test_env("test_server_endpoints");
+        let mut rules = Rules::new(&test_dir);
+        rules.load_tags().unwrap();
+        let router = router(rules);
+
+        let (status, body) = send(&router, "GET", "/tags", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["tags"]["colour"], json!(["red", "blue"]));
+
+        let (_, body) = send(&router, "GET", "/rules", Value::Null).await;
+        assert_eq!(body["rules"].as_array().unwrap().len(), 2);
+        assert_eq!(body["rules"][0]["line"], json!(2));
+        assert!(
+            body["rules"][0]["id"]
+                .as_str()
+                .unwrap()
+                .starts_with("test.rules:2#")
+        );
+
+        let (_, body) = send(
+            &router,
+            "POST",
+            "/rules/validate",
+            json!({ "rule": "- colour = green" }),
+        )
+        .await;
+        assert_eq!(body["valid"], json!(false));
+        assert!(body["error"].as_str().unwrap().contains("green"));
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/evaluate",
+            json!({ "object": { "colour": "Red", "size": ["large"] } }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["matched"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            body["matched"][0]["rule"],
+            json!("- colour = red & size > small")
+        );
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/evaluate",
+            json!({ "object": { "colour": "green" } }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body["error"].as_str().unwrap().contains("green"));
+
+        fs::write(format!("{}/more.tags", test_dir), "- shape: circle").unwrap();
+        let (status, body) = send(&router, "POST", "/reload", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["tags"], json!(3));
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[tokio::test]
+    async fn test_server_required_tags() {
+        let test_dir = setup_test_env("test_server_required_tags");
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour (required): red, blue\n- size: small < medium < large",
+        )
+        .unwrap();
+        let mut rules = Rules::new(&test_dir);
+        rules.load_tags().unwrap();
+        let router = router(rules);
+
+        // Objects missing a required tag match nothing under the default policy
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/evaluate",
+            json!({ "object": { "size": "large" } }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["matched"], json!([]));
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_server_metrics() {
+        let test_dir = setup_test_env("test_server_metrics");
+        let mut rules = Rules::new(&test_dir);
+        rules.load_tags().unwrap();
+        let router = router(rules);
+
+        send(
+            &router,
+            "POST",
+            "/evaluate",
+            json!({ "object": { "colour": "blue" } }),
+        )
+        .await;
+        send(&router, "POST", "/reload", Value::Null).await;
+
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let metrics = String::from_utf8(bytes.to_vec()).unwrap();
+
+        // Other tests evaluate concurrently, so only check what's there
+        assert!(metrics.contains("rules_evaluated_total"));
+        assert!(metrics.contains(&format!(
+            "rules_rule_matches_total{{rule=\"{}/test.rules:3\"}}",
+            test_dir
+        )));
+        assert!(metrics.contains("rules_evaluation_duration_seconds_bucket"));
+        assert!(metrics.contains("rules_reloads_total{result=\"ok\"}"));
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
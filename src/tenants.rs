@@ -0,0 +1,309 @@
+// Isolated configurations per tenant, each in its own subdirectory -- e.g. config/tenants/acme
+use crate::Rules;
+use crate::err::RulesError;
+use crate::types::{AuditOperation, LoadedRule, Object};
+use crate::utils::file;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub struct Tenants {
+    // Directory holding one config directory per tenant -- e.g. "config/tenants"
+    m_root: String,
+    m_tenants: BTreeMap<String, Rules>,
+}
+
+// Tenant keys name directories, so only letters, digits, '-' and '_' -- e.g. "acme-eu"
+fn check_key(key: &str) -> Result<(), RulesError> {
+    let valid = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !valid {
+        return Err(RulesError::IoError(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid tenant key: '{}'", key),
+        )));
+    }
+
+    Ok(())
+}
+
+fn unknown_tenant(key: &str) -> RulesError {
+    RulesError::IoError(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Unknown tenant: '{}'", key),
+    ))
+}
+
+// Prefix an error with the tenant it came from -- e.g. "acme: my_rules.rules:3: ..."
+fn tenant_error(key: &str, e: RulesError) -> RulesError {
+    match e {
+        RulesError::RuleParseError(msg) => RulesError::RuleParseError(format!("{}: {}", key, msg)),
+        RulesError::TagParseError(msg) => RulesError::TagParseError(format!("{}: {}", key, msg)),
+        RulesError::ObjectParseError(msg) => {
+            RulesError::ObjectParseError(format!("{}: {}", key, msg))
+        }
+        RulesError::IoError(e) => {
+            RulesError::IoError(io::Error::new(e.kind(), format!("{}: {}", key, e)))
+        }
+        other => other,
+    }
+}
+
+impl Tenants {
+    pub fn new(root: impl Into<String>) -> Self {
+        Self {
+            m_root: root.into(),
+            m_tenants: BTreeMap::new(),
+        }
+    }
+
+    pub fn root(&self) -> &str {
+        &self.m_root
+    }
+
+    // Tenant keys, sorted
+    pub fn keys(&self) -> Vec<&str> {
+        self.m_tenants.keys().map(String::as_str).collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Rules> {
+        self.m_tenants.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Rules> {
+        self.m_tenants.get_mut(key)
+    }
+
+    // Load every subdirectory of the root as a tenant, replacing any loaded before. Nothing
+    // changes if a tenant fails to load.
+    pub fn load(&mut self) -> Result<(), RulesError> {
+        let mut tenants = BTreeMap::new();
+
+        for entry in fs::read_dir(&self.m_root)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            // Skip directories that can't be tenants -- e.g. ".history"
+            let key = entry.file_name().to_string_lossy().to_string();
+            if check_key(&key).is_err() {
+                continue;
+            }
+
+            let rules = self.load_tenant(&key)?;
+            tenants.insert(key, rules);
+        }
+
+        self.m_tenants = tenants;
+        Ok(())
+    }
+
+    fn load_tenant(&self, key: &str) -> Result<Rules, RulesError> {
+        let config_dir = Path::new(&self.m_root).join(key);
+        let mut rules = Rules::new(config_dir.display().to_string());
+        rules.load_tags().map_err(|e| tenant_error(key, e))?;
+        Ok(rules)
+    }
+
+    // Add a tenant, creating its config directory if it doesn't exist
+    pub fn add_tenant(&mut self, key: &str) -> Result<&mut Rules, RulesError> {
+        check_key(key)?;
+        fs::create_dir_all(Path::new(&self.m_root).join(key))?;
+
+        let rules = self.load_tenant(key)?;
+        Ok(self
+            .m_tenants
+            .entry(key.to_string())
+            .insert_entry(rules)
+            .into_mut())
+    }
+
+    // Stop serving a tenant. Its config directory is left in place.
+    pub fn remove_tenant(&mut self, key: &str) -> Option<Rules> {
+        self.m_tenants.remove(key)
+    }
+
+    // Reload one tenant's tags from disk, leaving the others untouched
+    pub fn reload(&mut self, key: &str) -> Result<(), RulesError> {
+        let rules = self
+            .m_tenants
+            .get_mut(key)
+            .ok_or_else(|| unknown_tenant(key))?;
+        rules.load_tags().map_err(|e| tenant_error(key, e))
+    }
+
+    // Rules in the tenant's config that match the object
+    pub fn matching_rules(&self, key: &str, obj: &Object) -> Result<Vec<LoadedRule>, RulesError> {
+        let rules = self.get(key).ok_or_else(|| unknown_tenant(key))?;
+        rules.matching_rules(obj).map_err(|e| tenant_error(key, e))
+    }
+
+    // Copy the .tags and .rules files of a base config into every tenant, replacing files with
+    // the same name. Every tenant is checked before anything is written, so a file that would
+    // break one tenant's rules, or that its write policy denies, leaves all of them untouched.
+    pub fn roll_out(&mut self, base_dir: &str) -> Result<(), RulesError> {
+        let mut files = Vec::new();
+        for extension in ["tags", "rules"] {
+            let pattern = format!("{}/*.{}", base_dir, extension);
+            for (path, content) in file::read_files_in_dir_with_paths(&pattern)? {
+                let name = Path::new(&path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or(path);
+                files.push((name, content));
+            }
+        }
+
+        if files.is_empty() {
+            return Err(RulesError::IoError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No .tags or .rules files in '{}'", base_dir),
+            )));
+        }
+
+        for (key, rules) in self.m_tenants.iter() {
+            rules
+                .check_roll_out(&files)
+                .map_err(|e| tenant_error(key, e))?;
+        }
+
+        for (key, rules) in self.m_tenants.iter_mut() {
+            rules
+                .replace_files(AuditOperation::RollOut, &files)
+                .map_err(|e| tenant_error(key, e))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+
+        for (key, colours) in [("acme", "red, blue"), ("globex", "green")] {
+            let tenant_dir = format!("{}/tenants/{}", test_dir, key);
+            fs::create_dir_all(&tenant_dir).unwrap();
+            fs::write(
+                format!("{}/test.tags", tenant_dir),
+                format!("- colour: {}", colours),
+            )
+            .unwrap();
+        }
+        fs::write(
+            format!("{}/tenants/acme/test.rules", test_dir),
+            "- colour = red",
+        )
+        .unwrap();
+
+        fs::create_dir_all(format!("{}/base", test_dir)).unwrap();
+        fs::write(
+            format!("{}/base/base.tags", test_dir),
+            "- size: small, large",
+        )
+        .unwrap();
+        fs::write(format!("{}/base/base.rules", test_dir), "- size = large").unwrap();
+
+        test_dir
+    }
+
+    fn object(tag: &str, value: &str) -> Object {
+        let mut obj = Object::new();
+        obj.insert(tag.to_string(), vec![value.to_string()]);
+        obj
+    }
+
+    #[test]
+    fn test_tenants() {
+        let test_dir = setup_test_env("test_tenants");
+        let mut tenants = Tenants::new(format!("{}/tenants", test_dir));
+        tenants.load().unwrap();
+        assert_eq!(tenants.keys(), vec!["acme", "globex"]);
+
+        let matched = tenants
+            .matching_rules("acme", &object("colour", "red"))
+            .unwrap();
+        assert_eq!(matched.len(), 1);
+
+        // Tenants don't share tags
+        assert!(
+            tenants
+                .matching_rules("globex", &object("colour", "red"))
+                .is_err()
+        );
+
+        let result = tenants.matching_rules("initech", &object("colour", "red"));
+        if let Err(RulesError::IoError(e)) = result {
+            assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        } else {
+            panic!("Expected NotFound for unknown tenant");
+        }
+
+        tenants.add_tenant("initech").unwrap();
+        assert!(Path::new(&format!("{}/tenants/initech", test_dir)).is_dir());
+        assert!(tenants.add_tenant("../initech").is_err());
+
+        // Reloading one tenant leaves the others as they were
+        fs::write(
+            format!("{}/tenants/globex/test.tags", test_dir),
+            "- colour: green, red",
+        )
+        .unwrap();
+        tenants.reload("globex").unwrap();
+        assert!(
+            tenants
+                .matching_rules("globex", &object("colour", "red"))
+                .unwrap()
+                .is_empty()
+        );
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[test]
+    fn test_roll_out() {
+        let test_dir = setup_test_env("test_tenants_roll_out");
+        let mut tenants = Tenants::new(format!("{}/tenants", test_dir));
+        tenants.load().unwrap();
+
+        tenants.roll_out(&format!("{}/base", test_dir)).unwrap();
+        for key in ["acme", "globex"] {
+            let matched = tenants
+                .matching_rules(key, &object("size", "large"))
+                .unwrap();
+            assert_eq!(matched.len(), 1);
+            assert_eq!(
+                tenants
+                    .get(key)
+                    .unwrap()
+                    .history("base.rules")
+                    .unwrap()
+                    .len(),
+                1
+            );
+        }
+
+        // A base file that breaks one tenant's rules isn't written to any of them
+        fs::write(format!("{}/base/base.rules", test_dir), "- colour = red").unwrap();
+        let result = tenants.roll_out(&format!("{}/base", test_dir));
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.starts_with("globex: "));
+        } else {
+            panic!("Expected RuleParseError from globex");
+        }
+        let content = fs::read_to_string(format!("{}/tenants/acme/base.rules", test_dir)).unwrap();
+        assert_eq!(content, "- size = large");
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
@@ -0,0 +1,645 @@
+// Golden-file snapshots of evaluation runs, for rule-base regression tests -- e.g.
+// testing::snapshot(&rules, &objects, "tests/golden/base.snap"), and random rules for fuzzing
+// and benchmarks -- e.g. RuleGenerator::new(&tags, 42).generate()
+use crate::Rules;
+use crate::api::{audit, changeset};
+use crate::err::RulesError;
+use crate::types::{LoadedRule, Object, TagConstraintsMap, TagName, TagValues};
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+// Set to rewrite golden files with the current results instead of comparing against them
+pub const UPDATE_ENV: &str = "RULES_UPDATE_SNAPSHOTS";
+
+const HEADER: &str = "# Rule evaluation snapshot, rewrite with RULES_UPDATE_SNAPSHOTS=1";
+const INPUTS: &str = "inputs ";
+const OBJECT: &str = "object ";
+const MATCH_INDENT: &str = "  ";
+
+// Outcome of comparing a run against its golden file
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotResult {
+    // The results are the same as recorded
+    Matched,
+    // The golden file didn't exist or updates were requested, so it was written
+    Written,
+    // The results differ, described line by line
+    Mismatch(String),
+}
+
+// Chance, out of 100, that a generated condition below the top is a nested & or | rather than
+// a single comparison
+const BRANCH_PERCENT: u32 = 60;
+
+// Characters a tag value can't contain and still be written in a rule -- e.g. "a|b"
+const RULE_SYNTAX_CHARS: &[char] = &['(', ')', '=', '!', '<', '>', '~', '?', '&', '|', ',', '#'];
+
+// Relative weights of the operators in generated rules -- e.g. and: 2, or: 1 makes '&' twice
+// as common as '|'. Ordered comparisons are only used on ordered tags.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OperatorMix {
+    pub and: u32,
+    pub or: u32,
+    pub equals: u32,
+    pub not_equals: u32,
+    pub less_than: u32,
+    pub greater_than: u32,
+    // Chance, out of 100, that a comparison lists several values -- e.g. "colour = red, blue"
+    pub list_percent: u32,
+}
+
+// Random rules which are valid against a tag map -- e.g. "- (colour = red | size > small) &
+// shape ! circle". The same seed always gives the same rules.
+pub struct RuleGenerator {
+    // Tags with values that can be written in a rule, in name order, and whether each is ordered
+    m_tags: Vec<(TagName, TagValues, bool)>,
+    m_max_depth: usize,
+    m_operator_mix: OperatorMix,
+    m_rng: fastrand::Rng,
+}
+
+// A generated condition, before it's written out
+enum GeneratedNode {
+    Comparison(String),
+    // A comparison listing several values, which reads as an '|'
+    List(String),
+    Branch(char, Box<GeneratedNode>, Box<GeneratedNode>),
+}
+
+// One object and the rules it matched -- e.g. "object 0 { colour: [red] }"
+struct Entry {
+    header: String,
+    matches: Vec<String>,
+}
+
+// Tags by name, values in the object's order -- e.g. "{ colour: [red], size: [small] }"
+fn format_object(obj: &Object) -> String {
+    let mut tags: Vec<(&String, &Vec<String>)> = obj.iter().collect();
+    tags.sort_by(|a, b| a.0.cmp(b.0));
+
+    let tags: Vec<String> = tags
+        .iter()
+        .map(|(name, values)| format!("{}: [{}]", name, values.join(", ")))
+        .collect();
+    format!("{{ {} }}", tags.join(", "))
+}
+
+// Files by name only, so the golden file doesn't depend on where the config directory is
+fn format_match(loaded: &LoadedRule) -> String {
+    format!(
+        "{}:{} {}",
+        changeset::file_name(&loaded.file),
+        loaded.line,
+        loaded.rule
+    )
+}
+
+// Hash of the tags, rules and objects, to tell a changed rule base from a changed engine
+fn inputs_hash(rules: &Rules, objects: &[Object]) -> Result<String, RulesError> {
+    let mut inputs = String::new();
+
+    let mut tags: Vec<_> = rules.tags().iter().collect();
+    tags.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, values) in tags {
+        let _ = writeln!(inputs, "tag {}: {}", name, values.join(", "));
+    }
+    for loaded in rules.rules()? {
+        let _ = writeln!(inputs, "rule {}", format_match(&loaded));
+    }
+    for obj in objects {
+        let _ = writeln!(inputs, "object {}", format_object(obj));
+    }
+
+    Ok(audit::content_hash(inputs.as_bytes()))
+}
+
+fn render(inputs: &str, entries: &[Entry]) -> String {
+    let mut out = format!("{}\n{}{}\n", HEADER, INPUTS, inputs);
+    for entry in entries {
+        out.push_str(&entry.header);
+        out.push('\n');
+        for rule in &entry.matches {
+            out.push_str(MATCH_INDENT);
+            out.push_str(rule);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+// Inputs hash and entries of a golden file
+fn parse(content: &str) -> (String, Vec<Entry>) {
+    let mut inputs = String::new();
+    let mut entries: Vec<Entry> = Vec::new();
+
+    for line in content.lines() {
+        if let Some(hash) = line.strip_prefix(INPUTS) {
+            inputs = hash.trim().to_string();
+        } else if line.starts_with(OBJECT) {
+            entries.push(Entry {
+                header: line.to_string(),
+                matches: Vec::new(),
+            });
+        } else if let Some(rule) = line.strip_prefix(MATCH_INDENT)
+            && let Some(entry) = entries.last_mut()
+        {
+            entry.matches.push(rule.to_string());
+        }
+    }
+
+    (inputs, entries)
+}
+
+// Differences per object, in the style of a shadow report -- e.g. "object 2 { ... }:\n  - ..."
+fn describe_mismatch(
+    recorded: &(String, Vec<Entry>),
+    current: &(String, Vec<Entry>),
+) -> Option<String> {
+    let mut out = String::new();
+    let (recorded_inputs, recorded) = recorded;
+    let (current_inputs, current) = current;
+
+    for index in 0..recorded.len().max(current.len()) {
+        match (recorded.get(index), current.get(index)) {
+            (Some(old), Some(new)) => {
+                let removed: Vec<&String> = old
+                    .matches
+                    .iter()
+                    .filter(|r| !new.matches.contains(r))
+                    .collect();
+                let added: Vec<&String> = new
+                    .matches
+                    .iter()
+                    .filter(|r| !old.matches.contains(r))
+                    .collect();
+                if old.header == new.header && removed.is_empty() && added.is_empty() {
+                    if old.matches != new.matches {
+                        let _ = writeln!(out, "{}: matched rules reordered", new.header);
+                    }
+                    continue;
+                }
+
+                let _ = writeln!(out, "{}:", new.header);
+                if old.header != new.header {
+                    let _ = writeln!(out, "  was {}", old.header);
+                }
+                for rule in removed {
+                    let _ = writeln!(out, "  - {}", rule);
+                }
+                for rule in added {
+                    let _ = writeln!(out, "  + {}", rule);
+                }
+            }
+            (Some(old), None) => {
+                let _ = writeln!(out, "{}: no longer evaluated", old.header);
+            }
+            (None, Some(new)) => {
+                let _ = writeln!(out, "{}: not in the snapshot", new.header);
+            }
+            (None, None) => {}
+        }
+    }
+
+    if out.is_empty() {
+        return None;
+    }
+
+    let cause = if recorded_inputs == current_inputs {
+        "The tags, rules and objects are unchanged, so evaluation itself has changed"
+    } else {
+        "The tags, rules or objects have changed since the snapshot was recorded"
+    };
+    Some(format!(
+        "{}\n{}\nRerun with {}=1 to accept the new results",
+        out.trim_end(),
+        cause,
+        UPDATE_ENV
+    ))
+}
+
+/// Evaluates `objects` and compares the results against a golden file.
+///
+/// The golden file records a hash of the tags, rules and objects, then each
+/// object with the rules it matched. If it doesn't exist, or the
+/// `RULES_UPDATE_SNAPSHOTS` environment variable is set, it's written with
+/// the current results instead. Rules are evaluated as of now, like
+/// [`Rules::matching_rules`], without notifying webhooks.
+///
+/// # Arguments
+/// * `rules` - Rules with their tags loaded
+/// * `objects` - Objects to evaluate, in the order they're recorded
+/// * `golden` - Path to the golden file, e.g. `"tests/golden/base.snap"`
+///
+/// # Returns
+/// * `Ok(SnapshotResult)` saying whether the results matched, differed or were written
+/// * `Err(RulesError)` if an object or rule is invalid, or the file can't be read or written
+///
+/// # Examples
+/// ```ignore
+/// match testing::check_snapshot(&rules, &objects, "tests/golden/base.snap")? {
+///     SnapshotResult::Mismatch(diff) => eprintln!("{}", diff),
+///     _ => {}
+/// }
+/// ```
+pub fn check_snapshot(
+    rules: &Rules,
+    objects: &[Object],
+    golden: impl AsRef<Path>,
+) -> Result<SnapshotResult, RulesError> {
+    let golden = golden.as_ref();
+    let timestamp = audit::unix_timestamp();
+
+    let mut entries = Vec::new();
+    for (index, obj) in objects.iter().enumerate() {
+        let matched = rules.matching_rules_at(obj, timestamp)?;
+        entries.push(Entry {
+            header: format!("{}{} {}", OBJECT, index, format_object(obj)),
+            matches: matched.iter().map(format_match).collect(),
+        });
+    }
+    let inputs = inputs_hash(rules, objects)?;
+
+    let update = env::var_os(UPDATE_ENV).is_some_and(|value| !value.is_empty() && value != "0");
+    if update || !golden.exists() {
+        if let Some(dir) = golden.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(golden, render(&inputs, &entries))?;
+        return Ok(SnapshotResult::Written);
+    }
+
+    let recorded = parse(&fs::read_to_string(golden)?);
+    match describe_mismatch(&recorded, &(inputs, entries)) {
+        Some(diff) => Ok(SnapshotResult::Mismatch(diff)),
+        None => Ok(SnapshotResult::Matched),
+    }
+}
+
+/// Asserts that evaluating `objects` gives the results in a golden file,
+/// writing it on the first run.
+///
+/// A one-line regression test for a rule base. See [`check_snapshot`] for
+/// the file format and how to accept new results.
+///
+/// # Panics
+/// If the results differ from the golden file, describing each changed
+/// object, or if evaluation fails.
+///
+/// # Examples
+/// ```ignore
+/// #[test]
+/// fn base_rules_unchanged() {
+///     let mut rules = Rules::new("config");
+///     rules.load_tags().unwrap();
+///     rules::testing::snapshot(&rules, &objects(), "tests/golden/base.snap");
+/// }
+/// ```
+pub fn snapshot(rules: &Rules, objects: &[Object], golden: impl AsRef<Path>) {
+    let golden = golden.as_ref();
+    match check_snapshot(rules, objects, golden) {
+        Ok(SnapshotResult::Matched | SnapshotResult::Written) => {}
+        Ok(SnapshotResult::Mismatch(diff)) => {
+            panic!("Snapshot {} doesn't match:\n{}", golden.display(), diff)
+        }
+        Err(e) => panic!("Snapshot {} failed: {}", golden.display(), e),
+    }
+}
+
+impl Default for OperatorMix {
+    fn default() -> Self {
+        OperatorMix {
+            and: 3,
+            or: 2,
+            equals: 4,
+            not_equals: 2,
+            less_than: 1,
+            greater_than: 1,
+            list_percent: 20,
+        }
+    }
+}
+
+impl GeneratedNode {
+    // Binds less tightly than '&', so needs parentheses under it
+    fn is_or(&self) -> bool {
+        matches!(
+            self,
+            GeneratedNode::List(_) | GeneratedNode::Branch('|', _, _)
+        )
+    }
+
+    fn write(&self, parent: Option<char>) -> String {
+        let text = match self {
+            GeneratedNode::Comparison(text) | GeneratedNode::List(text) => text.clone(),
+            GeneratedNode::Branch(op, left, right) => {
+                format!(
+                    "{} {} {}",
+                    left.write(Some(*op)),
+                    op,
+                    right.write(Some(*op))
+                )
+            }
+        };
+
+        if parent == Some('&') && self.is_or() {
+            format!("({})", text)
+        } else {
+            text
+        }
+    }
+}
+
+impl RuleGenerator {
+    /// Creates a generator of random rules comparing the given tags.
+    ///
+    /// Tags without values, and values which can't be written in a rule,
+    /// are never used. Without tag constraints no tag is ordered, so no
+    /// rule uses '<' or '>'.
+    ///
+    /// # Arguments
+    /// * `tags` - Tag names and their values, as loaded from .tags files
+    /// * `seed` - Seed for the random choices
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let mut generator = RuleGenerator::new(rules.tags(), 42).with_max_depth(4);
+    /// let corpus = generator.generate_many(1000)?;
+    /// ```
+    pub fn new(tags: &HashMap<TagName, TagValues>, seed: u64) -> Self {
+        let mut tags: Vec<(TagName, TagValues, bool)> = tags
+            .iter()
+            .map(|(tag_name, values)| {
+                let values: TagValues = values
+                    .iter()
+                    .filter(|value| {
+                        !value.is_empty()
+                            && !value.contains(char::is_whitespace)
+                            && !value.contains(RULE_SYNTAX_CHARS)
+                    })
+                    .cloned()
+                    .collect();
+                (tag_name.clone(), values, false)
+            })
+            .filter(|(_, values, _)| !values.is_empty())
+            .collect();
+        tags.sort_by(|a, b| a.0.cmp(&b.0));
+
+        RuleGenerator {
+            m_tags: tags,
+            m_max_depth: 3,
+            m_operator_mix: OperatorMix::default(),
+            m_rng: fastrand::Rng::with_seed(seed),
+        }
+    }
+
+    // Ordered tags can also be compared with '<' and '>'
+    pub fn with_tag_constraints(mut self, constraints: &TagConstraintsMap) -> Self {
+        for (tag_name, _, ordered) in &mut self.m_tags {
+            *ordered = constraints.get(tag_name).is_some_and(|c| c.ordered);
+        }
+        self
+    }
+
+    // Most levels of '&' and '|' above each comparison -- e.g. 0 gives single comparisons
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.m_max_depth = max_depth;
+        self
+    }
+
+    pub fn with_operator_mix(mut self, operator_mix: OperatorMix) -> Self {
+        self.m_operator_mix = operator_mix;
+        self
+    }
+
+    /// Generates one random rule.
+    ///
+    /// The top of the rule is an '&' or '|' unless the maximum depth is 0,
+    /// and each condition below it is nested further by chance until the
+    /// maximum depth is reached.
+    ///
+    /// # Returns
+    /// * `Ok(String)` with the rule, starting with '-'
+    /// * `Err(RulesError)` if there are no tags with values, or the operator mix leaves no
+    ///   operator to use
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let rule = generator.generate()?;
+    /// assert!(rules.validate_rule(&rule).is_ok());
+    /// ```
+    pub fn generate(&mut self) -> Result<String, RulesError> {
+        if self.m_tags.is_empty() {
+            return Err(RulesError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "No tags with values to generate rules from",
+            )));
+        }
+
+        let node = self.generate_node(self.m_max_depth, true)?;
+        Ok(format!("- {}", node.write(None)))
+    }
+
+    // The given number of random rules, e.g. a benchmark corpus
+    pub fn generate_many(&mut self, n: usize) -> Result<Vec<String>, RulesError> {
+        (0..n).map(|_| self.generate()).collect()
+    }
+
+    fn generate_node(&mut self, depth: usize, top: bool) -> Result<GeneratedNode, RulesError> {
+        let mix = self.m_operator_mix;
+        let branch =
+            depth > 0 && mix.and + mix.or > 0 && (top || self.m_rng.u32(..100) < BRANCH_PERCENT);
+        if !branch {
+            return self.generate_comparison();
+        }
+
+        let op = if self.m_rng.u32(..mix.and + mix.or) < mix.and {
+            '&'
+        } else {
+            '|'
+        };
+        let left = self.generate_node(depth - 1, false)?;
+        let right = self.generate_node(depth - 1, false)?;
+        Ok(GeneratedNode::Branch(op, Box::new(left), Box::new(right)))
+    }
+
+    fn generate_comparison(&mut self) -> Result<GeneratedNode, RulesError> {
+        let mix = self.m_operator_mix;
+        let (tag_name, values, ordered) = &self.m_tags[self.m_rng.usize(..self.m_tags.len())];
+
+        let mut ops: Vec<(char, u32)> = vec![('=', mix.equals), ('!', mix.not_equals)];
+        if *ordered {
+            ops.push(('<', mix.less_than));
+            ops.push(('>', mix.greater_than));
+        }
+        let total: u32 = ops.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            return Err(RulesError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("No comparison operators to use on TagName '{}'", tag_name),
+            )));
+        }
+
+        let mut pick = self.m_rng.u32(..total);
+        let mut op = '=';
+        for (candidate, weight) in ops {
+            if pick < weight {
+                op = candidate;
+                break;
+            }
+            pick -= weight;
+        }
+
+        // A list names each value once -- e.g. never "colour = red, red"
+        let mut chosen: Vec<&String> = vec![&values[self.m_rng.usize(..values.len())]];
+        if values.len() > 1 && self.m_rng.u32(..100) < mix.list_percent {
+            let extra = self.m_rng.usize(1..values.len());
+            for _ in 0..extra {
+                let value = &values[self.m_rng.usize(..values.len())];
+                if !chosen.contains(&value) {
+                    chosen.push(value);
+                }
+            }
+        }
+
+        let values: Vec<&str> = chosen.iter().map(|value| value.as_str()).collect();
+        let text = format!("{} {} {}", tag_name, op, values.join(", "));
+        Ok(if values.len() > 1 {
+            GeneratedNode::List(text)
+        } else {
+            GeneratedNode::Comparison(text)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour: red, blue\n- size: small, large",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/test.rules", test_dir),
+            "- colour = red\n- size = large",
+        )
+        .unwrap();
+        test_dir
+    }
+
+    fn object(colour: &str, size: &str) -> Object {
+        let mut obj = Object::new();
+        obj.insert("colour".to_string(), vec![colour.to_string()]);
+        obj.insert("size".to_string(), vec![size.to_string()]);
+        obj
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let test_dir = setup_test_env("test_snapshot");
+        let golden = format!("{}/golden/base.snap", test_dir);
+        let objects = vec![object("red", "large"), object("blue", "small")];
+
+        let mut rules = Rules::new(&test_dir);
+        rules.load_tags().unwrap();
+
+        assert_eq!(
+            check_snapshot(&rules, &objects, &golden).unwrap(),
+            SnapshotResult::Written
+        );
+        let content = fs::read_to_string(&golden).unwrap();
+        assert!(content.contains(
+            "object 0 { colour: [red], size: [large] }\n  test.rules:1 - colour = red\n  test.rules:2 - size = large\n"
+        ));
+        assert!(content.ends_with("object 1 { colour: [blue], size: [small] }\n"));
+        snapshot(&rules, &objects, &golden);
+
+        // A changed rule is reported against the object it affects
+        fs::write(
+            format!("{}/test.rules", test_dir),
+            "- colour = blue\n- size = large",
+        )
+        .unwrap();
+        rules.load_tags().unwrap();
+        let result = check_snapshot(&rules, &objects, &golden).unwrap();
+        if let SnapshotResult::Mismatch(diff) = result {
+            assert!(diff.starts_with(
+                "object 0 { colour: [red], size: [large] }:\n  - test.rules:1 - colour = red\n"
+            ));
+            assert!(diff.contains("  + test.rules:1 - colour = blue"));
+            assert!(diff.contains("rules or objects have changed"));
+        } else {
+            panic!("Expected a mismatch after changing a rule");
+        }
+
+        let result = check_snapshot(&rules, &objects[..1], &golden).unwrap();
+        if let SnapshotResult::Mismatch(diff) = result {
+            assert!(
+                diff.contains("object 1 { colour: [blue], size: [small] }: no longer evaluated")
+            );
+        } else {
+            panic!("Expected a mismatch after dropping an object");
+        }
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+
+    #[test]
+    fn test_rule_generator() {
+        let test_dir = setup_test_env("test_rule_generator");
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour: red, blue, green\n- size: small < medium < large\n- code: /[A-Z]+/",
+        )
+        .unwrap();
+
+        let mut rules = Rules::new(&test_dir);
+        rules.load_tags().unwrap();
+
+        let corpus = rules.rule_generator(7).generate_many(100).unwrap();
+        assert_eq!(corpus, rules.rule_generator(7).generate_many(100).unwrap());
+        for rule in &corpus {
+            assert!(rules.validate_rule(rule).is_ok(), "{}", rule);
+            // The pattern tag has no values to compare
+            assert!(!rule.contains("code"));
+        }
+        assert!(
+            corpus
+                .iter()
+                .any(|rule| rule.contains(" > ") || rule.contains(" < "))
+        );
+
+        // Only '&' and '=', and single comparisons at depth 0
+        let only_and = OperatorMix {
+            or: 0,
+            not_equals: 0,
+            less_than: 0,
+            greater_than: 0,
+            list_percent: 0,
+            ..Default::default()
+        };
+        let mut generator = rules.rule_generator(7).with_operator_mix(only_and);
+        for rule in generator.generate_many(20).unwrap() {
+            assert!(rule.contains(" & ") && !rule.contains(['|', '!', '<', '>', ',', '(']));
+        }
+        let rule = rules
+            .rule_generator(7)
+            .with_max_depth(0)
+            .generate()
+            .unwrap();
+        assert!(!rule.contains(['&', '|']));
+
+        assert!(RuleGenerator::new(&HashMap::new(), 7).generate().is_err());
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
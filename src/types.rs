@@ -1,6 +1,12 @@
 // Shared domain types
 
-use std::collections::HashMap;
+use crate::api::{audit, changeset::file_name};
+use crate::err::{ErrorLocation, RulesError};
+use crate::parser::types::Token;
+use crate::utils::{string, time};
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
 
 // Aliases
 
@@ -19,36 +25,1074 @@ pub type Object = HashMap<String, Vec<String>>;
 // Clause in subrule -- e.g. "colour": "green"
 pub type TagKvMap = HashMap<String, String>;
 
+// Tag name to its extra constraints -- e.g. "sku": { pattern: /^[A-Z]{2}\d{4}$/ }
+pub type TagConstraintsMap = HashMap<TagName, TagConstraints>;
+
 // Structs
 
 pub struct Tag {
     pub name: TagName,
     pub values: TagValues,
+    pub constraints: TagConstraints,
+}
+
+// How to handle a tag name defined more than once across .tags files
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DuplicateTagPolicy {
+    // Combine the values of every definition
+    #[default]
+    Merge,
+    // Reject the config
+    Error,
+    // Keep only the last definition, in file then line order
+    LastWins,
+}
+
+// How tag names and values are cased on load, and so how rules and objects must match them
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CaseFolding {
+    // Fold names and values to lowercase, so matching ignores case
+    #[default]
+    Lowercase,
+    // Keep names and values as written, so matching is case-sensitive
+    Preserve,
+}
+
+// Caps on the total size of the loaded tags, so generated configs can't grow without bound
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TagLimits {
+    // Number of distinct tags
+    pub max_tags: Option<usize>,
+    // Number of values across every tag
+    pub max_values: Option<usize>,
+}
+
+// Caps on the size of one rule, checked once it's tokenised so a pathological rule -- e.g.
+// thousands of nested parentheses -- is rejected before it's read into a syntax tree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuleLimits {
+    // Deepest nesting of groups -- e.g. 2 for "((colour = red))"
+    pub max_depth: usize,
+    // Most tokens, after ranges and flags are expanded -- e.g. 3 for "colour = red". Checking and
+    // evaluating, rendering and serializing a rule loop down chains of one operator, but
+    // explaining or tracing it, and debug-printing its tree, recurse per operand, so raising this
+    // far past the default can exhaust the stack there and abort the process.
+    pub max_length: usize,
+}
+
+impl Default for RuleLimits {
+    fn default() -> Self {
+        RuleLimits {
+            max_depth: 64,
+            max_length: 2000,
+        }
+    }
+}
+
+// Options controlling how .tags files are loaded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TagLoadOptions {
+    pub duplicate_tags: DuplicateTagPolicy,
+    pub case_folding: CaseFolding,
+    pub limits: TagLimits,
+}
+
+// How evaluation treats objects missing a tag marked (required)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RequiredTagPolicy {
+    // Leave the object out of evaluation, so it matches no rules
+    #[default]
+    NoMatch,
+    // Fail evaluation
+    Error,
+}
+
+// How many of the rules an object satisfies evaluation returns
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EvalMode {
+    // Every matching rule -- e.g. for auditing
+    #[default]
+    AllMatches,
+    // Only the highest priority matching rule, then the first in file then line order -- e.g.
+    // for classifying objects
+    FirstMatch,
+}
+
+// Result of a rule or comparison under three-valued logic -- e.g. Unknown for "vendor = acme"
+// on an object whose vendor isn't one of the listed values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Truth {
+    True,
+    False,
+    Unknown,
+}
+
+// How evaluation treats object values outside a tag's listed values, which open tags allow
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum UnknownPolicy {
+    // Compare them like any other value -- e.g. "vendor ! acme" holds for vendor = other
+    #[default]
+    TwoValued,
+    // Comparisons on them are Unknown, and rules still Unknown at the end don't match
+    UnknownIsFalse,
+    // Comparisons on them are Unknown, and rules still Unknown at the end match
+    UnknownIsTrue,
+}
+
+// How evaluation treats a comparison on a tag the object doesn't have -- e.g. "size = large"
+// against { colour: [red] }
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MissingTagPolicy {
+    // Compare as if the tag had no values, so '=', '<' and '>' fail and '!' holds
+    #[default]
+    NoValues,
+    // Every comparison on it fails, '!' included
+    FailClause,
+    // Every comparison on it holds, leaving the rest of the rule to decide
+    SkipClause,
+    // Fail evaluation of a rule comparing it
+    Error,
+}
+
+// Options controlling how objects are evaluated against rules
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EvaluationOptions {
+    pub required_tags: RequiredTagPolicy,
+    pub mode: EvalMode,
+    pub unknown_values: UnknownPolicy,
+    pub missing_tags: MissingTagPolicy,
+}
+
+// Logical operators as written in rules, each needing a precedence level
+pub const LOGICAL_OPERATORS: [&str; 3] = ["&", "|", "->"];
+
+// How tightly each logical operator binds when rules are parsed, a higher level binding tighter
+// -- e.g. '&' at 1 over '|' at 0 reads "a | b & c" as "a | (b & c)". Comparisons bind tighter than
+// any of them, and operators at the same level group to the right.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperatorPrecedence {
+    pub levels: HashMap<String, i32>,
 }
 
+impl Default for OperatorPrecedence {
+    fn default() -> Self {
+        let levels = [("->", -1), ("|", 0), ("&", 1)]
+            .into_iter()
+            .map(|(operator, level)| (operator.to_string(), level))
+            .collect();
+        Self { levels }
+    }
+}
+
+// Size and lifetime of cached evaluation results -- e.g. 1024 objects for 60 seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheOptions {
+    // Most objects kept, the least recently used are dropped first. 0 caches nothing.
+    pub capacity: usize,
+    // How long a result stays valid, if not until the next reload
+    pub ttl: Option<Duration>,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            ttl: None,
+        }
+    }
+}
+
+// Counters for the evaluation cache -- e.g. 90 hits and 10 misses over 10 entries
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    // Bumped on every reload or write, which empties the cache
+    pub ruleset_version: u64,
+}
+
+// Tags parsed from .tags files, with warnings -- e.g. how duplicates were resolved
+#[derive(Default)]
+pub struct ParsedTags {
+    pub tags: Vec<Tag>,
+    pub exclusive_groups: Vec<ExclusiveGroup>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+// Members which can't appear together on one object
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExclusiveGroup {
+    // Values of one tag -- e.g. "@exclusive colour: red, green"
+    Values {
+        tag_name: TagName,
+        values: TagValues,
+    },
+    // Whole tags -- e.g. "@exclusive discount, clearance"
+    Tags {
+        tag_names: Vec<TagName>,
+    },
+}
+
+// Object holding more than one member of an exclusive group
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExclusionConflict {
+    pub group: ExclusiveGroup,
+    // Members of the group found on the object
+    pub found: Vec<String>,
+}
+
+// Constraints declared on a tag beyond its enumerated values
+#[derive(Debug, Clone, Default)]
+pub struct TagConstraints {
+    // Values must match this pattern instead of the enumerated list -- e.g. /^[A-Z]{2}\d{4}$/
+    pub pattern: Option<Regex>,
+    // Any value is allowed, the enumerated list is advisory -- e.g. "- vendor (open): acme"
+    pub open: bool,
+    // Every object must have this tag -- e.g. "- colour (required): red, blue"
+    pub required: bool,
+    // Boolean tag with the values true and false -- e.g. "- is_fragile (flag)"
+    pub flag: bool,
+    // Objects may have at most one of the tag's values -- e.g. "- status (single): active, retired"
+    pub single: bool,
+    // Values are ranked in declared order, for < and > -- e.g. "- size: small < medium < large"
+    pub ordered: bool,
+    // Most values the tag may declare -- e.g. "- colour (max_values=20): red, blue"
+    pub max_values: Option<usize>,
+    // Longest value the tag may declare, in characters -- e.g. "- code (max_length=4): ab12"
+    pub max_length: Option<usize>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ComparisonOp {
     ISEQ,
     NOEQ,
+    // Rank comparisons on ordered tags -- e.g. size > small
+    LSTH,
+    GRTH,
+    // Plugin function call on the tag's values -- e.g. created_at before(2024-01-01)
+    CALL,
+    // Regex any of the tag's values must match, written with '~' like a call -- e.g.
+    // sku ~ ^AB[0-9]+$
+    REGX,
+    // Whether the object has the tag at all, whatever its values -- e.g. colour? and !colour?
+    PRES,
+    ABSN,
     // To be supported in future:
     // GREQ,
     // LEEQ,
 }
 
+// Object loaded from a .yaml file, with the group it was listed under -- e.g. "shapes"
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedObject {
+    pub file: String,
+    pub obj_type: String,
+    // Position within its type's list
+    pub index: usize,
+    pub object: Object,
+}
+
+// Where a loaded object was found -- e.g. the first of the shapes in config/my_objects.yaml
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ObjectId {
+    pub file: String,
+    pub obj_type: String,
+    pub index: usize,
+}
+
+// Rule read from a .rules file, with where it was found -- e.g. config/my_rules.rules:3
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedRule {
+    pub file: String,
+    pub line: usize,
+    pub rule: String,
+    // Annotations on the lines directly above the rule
+    pub annotations: Vec<RuleAnnotation>,
+}
+
+// Identity of a loaded rule, the same across reloads -- e.g. my_rules.rules:3#9c4f0a1b2d3e4f56.
+// The file is relative to the config directory, and the hash is of the rule's text, so a rule
+// moved by edits elsewhere in its file can still be found by its hash
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RuleId {
+    pub file: String,
+    pub line: usize,
+    pub hash: String,
+}
+
+// Values for $ctx variables in rules, supplied per evaluation -- e.g. region = eu for
+// "- region = $ctx.region"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EvalContext {
+    pub variables: HashMap<String, String>,
+}
+
+// Annotations scoping a rule to a time window -- e.g. "@expires 2025-07-01"
+pub const EFFECTIVE_FROM: &str = "effective_from";
+pub const EXPIRES: &str = "expires";
+
+// Annotation keeping a rule out of evaluation until it's promoted -- e.g. "@draft red_large"
+pub const DRAFT: &str = "draft";
+
+// Annotation giving a rule's weight towards an object's score -- e.g. "@weight 2.5"
+pub const WEIGHT: &str = "weight";
+
+// Weight of a rule without a @weight annotation
+pub const DEFAULT_WEIGHT: f64 = 1.0;
+
+// Annotations describing a rule for people -- e.g. `@description("Red items") @owner(alice)`
+pub const DESCRIPTION: &str = "description";
+pub const SEVERITY: &str = "severity";
+pub const OWNER: &str = "owner";
+
+// Score from the weights of matching rules, 0 when there are none -- sum() would give -0
+pub fn total_weight(weights: impl Iterator<Item = f64>) -> f64 {
+    weights.fold(0.0, |total, weight| total + weight)
+}
+
+// Annotation on the rule below it -- e.g. "@on_match notify.rhai"
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleAnnotation {
+    pub name: String,
+    // Everything after the name, trimmed. Empty for markers -- e.g. "@draft"
+    pub value: String,
+}
+
+// How serious a rule's matches are, from @severity -- e.g. High for "@severity(high)"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+// What a rule's annotations say about it -- e.g. `@description("Red items") @severity(high)`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleMeta {
+    pub description: Option<String>,
+    pub severity: Option<Severity>,
+    pub owner: Option<String>,
+}
+
+// Rule syntax version -- e.g. V1 writes colour ! red, V2 writes colour != "red"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GrammarVersion {
+    V1,
+    V2,
+}
+
+// Configuration write recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditOperation {
+    WriteTag,
+    WriteRule,
+    WriteObject,
+    FormatTags,
+    FormatRules,
+    Rollback,
+    RollOut,
+    Pull,
+    PromoteRule,
+    MigrateRules,
+    DeleteRule,
+    DedupRules,
+}
+
+// Audit log entry -- e.g. 1760000000 write_tag colours.tags 9c4f0a1b2d3e4f56 alice
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub operation: AuditOperation,
+    // File written, relative to the config directory
+    pub file: String,
+    // Hash of the file's content after the write
+    pub content_hash: String,
+    // Who made the write, if set with Rules::set_audit_actor
+    pub actor: Option<String>, // Rule written, for writes of a single rule -- e.g. write_rule
+    pub rule_id: Option<RuleId>,
+}
+
+// Snapshot of a config file kept under .history -- e.g. version 3 of my_rules.rules
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileVersion {
+    // Position in the file's history, from 1
+    pub version: usize,
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub content_hash: String,
+}
+
+// Single comparison within a rule -- e.g. colour = red
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clause {
+    pub tag_name: TagName,
+    pub comparison_op: ComparisonOp,
+    pub tag_value: String,
+}
+
+// Where a rule clause references a tag -- e.g. config/my_rules.rules:3 "colour = red"
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagUsage {
+    pub file: String,
+    pub line: usize,
+    pub rule: String,
+    pub clause: Clause,
+}
+
+// What would become invalid if a tag value were removed
+#[derive(Debug, Default)]
+pub struct ValueRemovalImpact {
+    pub rules: Vec<TagUsage>,
+    pub objects: Vec<LoadedObject>,
+}
+
 pub enum LogicalOp {
     AND,
     OR,
 }
 
+// One conjunction of a rule's disjunctive normal form -- e.g. colour = blue & shape ! circle
+#[derive(Debug, Clone, PartialEq)]
 pub struct SubRule {
     pub expected_count: i32,
     pub actual_count: i32,
     // No. elements (tag_kvs) should be == no. elements (comparison_op - 1)
     pub comparison_ops: Vec<ComparisonOp>,
     pub tag_kvs: TagKvMap,
+    // Position of the rule it came from in the rules it was converted with
+    pub rule_index: usize,
+    // Every comparison, in rule order
+    pub clauses: Vec<Clause>,
+    // Priority of the rule it came from -- e.g. 10 for "-[10] colour = red"
+    pub priority: u32,
+    // Name of the rule it came from -- e.g. "promo_1" for "promo_1: - colour = red"
+    pub name: Option<String>,
+}
+
+// A rule in conjunctive normal form, for SAT-style checks -- e.g. "- colour = red | size = large
+// & shape = circle" gives (colour = red | shape = circle) & (colour = red | size = large)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CnfRule {
+    // Disjunctions which must all hold, each of clauses of which at least one must. Empty if the
+    // rule always matches.
+    pub clauses: Vec<Vec<Clause>>,
+}
+
+// Health of a running daemon, as reported by DaemonHandle::status
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DaemonStatus {
+    // Whether the last reload succeeded. The daemon keeps serving the previous config if not.
+    pub healthy: bool,
+    // Seconds since the Unix epoch
+    pub started_at: u64,
+    pub last_reload_at: Option<u64>,
+    // Reloads attempted after the initial load, including failed ones
+    pub reloads: u64,
+    pub last_error: Option<String>,
+    pub tag_count: usize,
+    pub rule_count: usize,
+    pub evaluations: u64,
+}
+
+// What an Engine has loaded, from Engine::stats
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EngineStats {
+    pub tag_count: usize,
+    pub subrule_count: usize,
+    pub object_count: usize,
+    // Objects left out for missing a required tag, under RequiredTagPolicy::NoMatch
+    pub skipped_objects: usize,
+    // Successful loads, including the first
+    pub loads: u64,
+    // Seconds since the Unix epoch
+    pub last_load_at: Option<u64>,
+}
+
+// Content of a pending config write, as given to the write policy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePayload<'a> {
+    // Values being added to a tag -- e.g. colour: red, blue
+    Tag {
+        name: &'a str,
+        values: &'a [String],
+    },
+    // Rule being appended -- e.g. "- colour = red"
+    Rule(&'a str),
+    // Object being added under a type -- e.g. shapes: { colour: [red] }
+    Object {
+        obj_type: &'a str,
+        object: &'a Object,
+    },
+    // Rewrite of a whole file, leaving its meaning unchanged
+    Format,
+    // Restore of an earlier version of the file
+    Rollback {
+        version: usize,
+    },
+    // Shared base file replacing a tenant's copy -- e.g. base.rules from Tenants::roll_out
+    RollOut {
+        content: &'a str,
+    },
+    // File pulled from a remote source -- e.g. base.rules from https://config.example.com
+    Pull {
+        url: &'a str,
+        content: &'a str,
+    },
+    // Draft rule becoming active -- e.g. "red_large", "- colour = red & size = large"
+    PromoteRule {
+        name: &'a str,
+        rule: &'a str,
+    },
+    // Named rule being removed -- e.g. "promo_1", "promo_1: - colour = red"
+    DeleteRule {
+        name: &'a str,
+        rule: &'a str,
+    },
+}
+
+// Config write about to be made -- e.g. write_rule to safety.rules by alice
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WriteRequest<'a> {
+    pub operation: AuditOperation,
+    // Name of the file in the config directory -- e.g. "safety.rules"
+    pub file: &'a str,
+    // Who the write is attributed to, as set with Rules::set_audit_actor
+    pub actor: Option<&'a str>,
+    pub payload: WritePayload<'a>,
+}
+
+// How an item differs between two configurations
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+// Tag whose definition differs -- e.g. colour gained green
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagChange {
+    pub kind: ChangeKind,
+    pub name: TagName,
+    // Empty for added tags
+    pub old_values: TagValues,
+    // Empty for removed tags
+    pub new_values: TagValues,
+}
+
+// Rule which differs, in canonical form -- e.g. my_rules.rules "colour = red" became "colour = blue"
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleChange {
+    pub kind: ChangeKind,
+    // Name of the .rules file -- e.g. "my_rules.rules"
+    pub file: String,
+    pub old_rule: Option<String>,
+    pub new_rule: Option<String>,
+}
+
+// Object which differs, by file, type and position -- e.g. objects.yaml shapes[2]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectChange {
+    pub kind: ChangeKind,
+    // Name of the .yaml file -- e.g. "objects.yaml"
+    pub file: String,
+    pub obj_type: String,
+    pub index: usize,
+    pub old_object: Option<Object>,
+    pub new_object: Option<Object>,
+}
+
+// Semantic differences between two configurations, ignoring formatting
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChangeSet {
+    pub tags: Vec<TagChange>,
+    pub rules: Vec<RuleChange>,
+    pub objects: Vec<ObjectChange>,
+}
+
+// Object whose matched rules differ between the active and a candidate configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowDivergence {
+    // Position in the objects compared
+    pub index: usize,
+    pub object: Object,
+    // Rules only the active configuration matched
+    pub removed: Vec<LoadedRule>,
+    // Rules only the candidate matched
+    pub added: Vec<LoadedRule>,
+    // Why the candidate couldn't evaluate the object -- e.g. it removed a tag value the object uses
+    pub candidate_error: Option<String>,
+}
+
+// Result of evaluating objects against the active and a candidate configuration
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShadowReport {
+    // Objects evaluated against both
+    pub evaluated: usize,
+    pub divergences: Vec<ShadowDivergence>,
+}
+
+// Rule an object matched, from Rules::evaluate_object -- e.g. my_rules.rules:3 "- colour = red"
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedRule {
+    pub id: RuleId,
+    pub file: String,
+    pub line: usize,
+    pub rule: String,
+    // Name of the rule -- e.g. "promo_1" for "promo_1: - colour = red"
+    pub name: Option<String>,
+    // Description, severity and owner from the rule's annotations
+    pub meta: RuleMeta,
+    // What to do with a matching object -- e.g. "reject" for "- colour = red => reject"
+    pub outcome: Option<String>,
+    // Weight towards the object's score -- e.g. 2.5 for "@weight 2.5"
+    pub weight: f64,
+}
+
+// Rules a loaded object matched -- e.g. shapes[0] in config/my_objects.yaml
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectMatches {
+    pub object: LoadedObject,
+    // Highest priority first, then in file then line order
+    pub matched: Vec<LoadedRule>,
+}
+
+// Result of evaluating every object in a config directory against its rules, from Rules::evaluate
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EvaluationReport {
+    // Seconds since the Unix epoch the rules were evaluated at
+    pub timestamp: u64,
+    // One per object evaluated, in file order
+    pub results: Vec<ObjectMatches>,
+    // Objects left out for missing a required tag, under RequiredTagPolicy::NoMatch
+    pub skipped_objects: usize,
+    // One per rule which isn't a draft, in file then line order
+    pub rule_stats: Vec<RuleStats>,
+}
+
+// How often a rule was checked and matched over an evaluation, from EvaluationReport::stats --
+// e.g. my_rules.rules:3 evaluated 40 times, matched 3
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleStats {
+    pub rule: LoadedRule,
+    // Objects the rule was checked against. Rules the subrule index rules out for an object
+    // aren't checked, nor are rules outside their time window, or under EvalMode::FirstMatch
+    // rules after an object's first match.
+    pub evaluated: usize,
+    pub matched: usize,
+}
+
+// Two rules an object could match at once, for review -- e.g. a broad rule shadowing a narrow one
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleConflict {
+    pub first: LoadedRule,
+    pub second: LoadedRule,
+    // Conditions under which both match, one conjunction each -- e.g. "colour = red & size = large"
+    pub overlap: Vec<String>,
+}
+
+// Rule which duplicates an earlier one in its file, from Rules::duplicate_rules -- e.g.
+// "-colour=red" at line 5 duplicating "- colour = red" at line 2
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleDuplicate {
+    pub rule: LoadedRule,
+    // Earliest rule in the file it duplicates, which is kept
+    pub duplicate_of: LoadedRule,
+}
+
+// Rule matching every object another rule matches, from Rules::analyze -- e.g.
+// "- colour = red" subsuming "- colour = red & size = large"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subsumption {
+    pub broader: LoadedRule,
+    pub narrower: LoadedRule,
+    // Whether both match exactly the same objects -- e.g. "- size = small, large" and
+    // "- size = large | size = small"
+    pub equivalent: bool,
+}
+
+// Rule pairs found by Rules::analyze, across every rule file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleAnalysis {
+    // Rules made redundant by a broader rule, in file then line order of the earlier rule
+    pub subsumptions: Vec<Subsumption>,
+    // Rules with different outcomes which some object matches at once -- e.g. "=> approve"
+    // and "=> reject" both matching "colour = red & size = large"
+    pub outcome_conflicts: Vec<RuleConflict>,
+}
+
+// Combinations of tag values no rule matches, from Rules::coverage_gaps
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CoverageReport {
+    // Tags the rules compare, whose values were combined
+    pub tags: Vec<TagName>,
+    // Tags the rules compare which list no values to combine -- e.g. pattern tags
+    pub skipped_tags: Vec<TagName>,
+    // Valid combinations checked
+    pub combinations: usize,
+    // Combinations no rule matches
+    pub uncovered: usize,
+    // The first uncovered combinations, as objects with one value per tag present
+    pub examples: Vec<Object>,
+}
+
+// Which rules and tag values a corpus of objects exercised, from Rules::rule_coverage
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleCoverage {
+    // Objects evaluated
+    pub objects: usize,
+    // One per rule which isn't a draft, in file then line order
+    pub rules: Vec<RuleHits>,
+    // Listed values of the tags the rules compare which no object held, by tag name
+    pub unexercised_values: BTreeMap<TagName, Vec<String>>,
+}
+
+// How many objects of a corpus matched a rule and each of its comparisons
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleHits {
+    pub rule: LoadedRule,
+    pub matched: usize,
+    // One per comparison, in rule order
+    pub clauses: Vec<ClauseHits>,
+}
+
+// How many objects of a corpus satisfied one comparison in a rule -- e.g. colour = red by 3
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClauseHits {
+    pub clause: Clause,
+    pub matched: usize,
+}
+
+// Example object under a rule in a .rules file -- e.g. "#test: no_match { colour: blue }"
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlineTest {
+    pub file: String,
+    // Line of the test itself
+    pub line: usize,
+    // The rule above the test
+    pub rule: LoadedRule,
+    pub object: Object,
+    // Whether the rule should match the object
+    pub expect_match: bool,
+}
+
+// Outcome of running an inline test, from Rules::run_inline_tests
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlineTestResult {
+    pub test: InlineTest,
+    // Whether the rule matched, or why the object couldn't be evaluated
+    pub outcome: Result<bool, String>,
+}
+
+// Random objects a rule does and doesn't match, from Rules::generate_examples
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleExamples {
+    pub matching: Vec<Object>,
+    // Half are near misses, meeting all but one condition of a way to match
+    pub non_matching: Vec<Object>,
+}
+
+// Change to one of an object's tags -- e.g. adding large to size
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagEdit {
+    Add { tag_name: TagName, value: String },
+    Remove { tag_name: TagName, value: String },
+}
+
+// Why a rule doesn't match an object, from Rules::why_not
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchExplanation {
+    // Conditions the object fails, of the way to match needing fewest changes
+    pub failed: Vec<Clause>,
+    // Changes to the object which would make the rule match it
+    pub edits: Vec<TagEdit>,
+    // Whether the edits are enough, which they aren't if a plugin call fails
+    pub complete: bool,
+}
+
+// How one node of a rule evaluated against an object, from Rules::explain -- e.g. '&' failing
+// because of its "size = large" operand
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExplanationNode {
+    // '&' or '|', or the comparison's operator at a leaf
+    pub token: Token,
+    // The comparison at a leaf -- e.g. colour = red
+    pub clause: Option<Clause>,
+    // The object's values for the tag a leaf compares, empty if it doesn't have the tag
+    pub values: Vec<String>,
+    pub matched: bool,
+    // Operands of an '&' or '|', left then right
+    pub children: Vec<ExplanationNode>,
+}
+
+// Every rule checked against an object and the nodes each visited, from Rules::trace
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EvaluationTrace {
+    // One per rule checked, in the order checked -- highest priority first, then in file then
+    // line order, stopping at the first match under EvalMode::FirstMatch
+    pub rules: Vec<RuleTrace>,
+}
+
+// The nodes of one rule visited while checking an object
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleTrace {
+    pub rule: LoadedRule,
+    pub matched: bool,
+    // In visit order, each node before its operands. Operands skipped because the left one
+    // decided an '&' or '|' aren't visited.
+    pub steps: Vec<TraceStep>,
+}
+
+// One node visited during evaluation -- e.g. "size = large" at depth 1 failing on { size: [small] }
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceStep {
+    // Nodes above this one, 0 for the rule's root
+    pub depth: usize,
+    // '&' or '|', or the comparison's operator at a leaf
+    pub token: Token,
+    // The comparison at a leaf, with variables filled in -- e.g. region = eu
+    pub clause: Option<Clause>,
+    // The object's values for the tag a leaf compares, empty if it doesn't have the tag
+    pub values: Vec<String>,
+    // With Unknown collapsed per the UnknownPolicy
+    pub result: bool,
+}
+
+// Whether a rule matches each combination of values of the tags it compares, from
+// Rules::truth_table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TruthTable {
+    pub rule: String,
+    // Column order of each row's values
+    pub tags: Vec<TagName>,
+    // Tags the rule compares which list no values to combine -- e.g. pattern tags
+    pub skipped_tags: Vec<TagName>,
+    pub rows: Vec<TruthTableRow>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TruthTableRow {
+    // Value of each tag, None if the object doesn't have it
+    pub values: Vec<Option<String>>,
+    pub matched: bool,
 }
 
 // Impls
 
+impl ComparisonOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ComparisonOp::ISEQ => "=",
+            ComparisonOp::NOEQ => "!",
+            ComparisonOp::LSTH => "<",
+            ComparisonOp::GRTH => ">",
+            ComparisonOp::CALL | ComparisonOp::REGX => "~",
+            ComparisonOp::PRES => "?",
+            ComparisonOp::ABSN => "!?",
+        }
+    }
+}
+
+impl AuditOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOperation::WriteTag => "write_tag",
+            AuditOperation::WriteRule => "write_rule",
+            AuditOperation::WriteObject => "write_object",
+            AuditOperation::FormatTags => "format_tags",
+            AuditOperation::FormatRules => "format_rules",
+            AuditOperation::Rollback => "rollback",
+            AuditOperation::RollOut => "roll_out",
+            AuditOperation::Pull => "pull",
+            AuditOperation::PromoteRule => "promote_rule",
+            AuditOperation::MigrateRules => "migrate_rules",
+            AuditOperation::DeleteRule => "delete_rule",
+            AuditOperation::DedupRules => "dedup_rules",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "write_tag" => Some(AuditOperation::WriteTag),
+            "write_rule" => Some(AuditOperation::WriteRule),
+            "write_object" => Some(AuditOperation::WriteObject),
+            "format_tags" => Some(AuditOperation::FormatTags),
+            "format_rules" => Some(AuditOperation::FormatRules),
+            "rollback" => Some(AuditOperation::Rollback),
+            "roll_out" => Some(AuditOperation::RollOut),
+            "pull" => Some(AuditOperation::Pull),
+            "promote_rule" => Some(AuditOperation::PromoteRule),
+            "migrate_rules" => Some(AuditOperation::MigrateRules),
+            "delete_rule" => Some(AuditOperation::DeleteRule),
+            "dedup_rules" => Some(AuditOperation::DedupRules),
+            _ => None,
+        }
+    }
+}
+
+impl GrammarVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GrammarVersion::V1 => "v1",
+            GrammarVersion::V2 => "v2",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "v1" => Some(GrammarVersion::V1),
+            "v2" => Some(GrammarVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "low" => Some(Severity::Low),
+            "medium" => Some(Severity::Medium),
+            "high" => Some(Severity::High),
+            "critical" => Some(Severity::Critical),
+            _ => None,
+        }
+    }
+}
+
+impl RuleMeta {
+    // No description, severity or owner
+    pub fn is_empty(&self) -> bool {
+        *self == RuleMeta::default()
+    }
+}
+
+impl ValueRemovalImpact {
+    // Nothing references the value
+    pub fn is_safe(&self) -> bool {
+        self.rules.is_empty() && self.objects.is_empty()
+    }
+}
+
+// In the call form annotations can be written in -- e.g. `@description("Red items")`, or
+// "@draft" for markers
+impl std::fmt::Display for RuleAnnotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.value.is_empty() {
+            write!(f, "@{}", self.name)
+        } else {
+            write!(
+                f,
+                "@{}({})",
+                self.name,
+                string::quote_if_needed(&self.value)
+            )
+        }
+    }
+}
+
+// As annotations -- e.g. `@description("Red items") @severity(high) @owner(alice)`
+impl std::fmt::Display for RuleMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let annotations: Vec<String> = [
+            (DESCRIPTION, self.description.as_deref()),
+            (SEVERITY, self.severity.as_ref().map(Severity::as_str)),
+            (OWNER, self.owner.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            value.map(|value| {
+                RuleAnnotation {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                }
+                .to_string()
+            })
+        })
+        .collect();
+        write!(f, "{}", annotations.join(" "))
+    }
+}
+
+impl std::fmt::Display for ExclusiveGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExclusiveGroup::Values { tag_name, values } => {
+                write!(f, "{}: {}", tag_name, values.join(", "))
+            }
+            ExclusiveGroup::Tags { tag_names } => write!(f, "{}", tag_names.join(", ")),
+        }
+    }
+}
+
+impl std::fmt::Display for ExclusionConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Object has mutually exclusive {} from group '{}'",
+            self.found.join(", "),
+            self.group
+        )
+    }
+}
+
+impl std::fmt::Display for Clause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Presence checks have no value -- e.g. colour? and !colour?, and calls are written
+        // as they are -- e.g. before(2024-01-01)
+        let tag_value = match self.comparison_op {
+            ComparisonOp::PRES => return write!(f, "{}?", self.tag_name),
+            ComparisonOp::ABSN => return write!(f, "!{}?", self.tag_name),
+            ComparisonOp::CALL => self.tag_value.clone(),
+            _ => string::quote_if_needed(&self.tag_value),
+        };
+        write!(
+            f,
+            "{} {} {}",
+            self.tag_name,
+            self.comparison_op.as_str(),
+            tag_value
+        )
+    }
+}
+
+impl CaseFolding {
+    pub fn fold(self, s: &str) -> String {
+        match self {
+            CaseFolding::Lowercase => s.to_lowercase(),
+            CaseFolding::Preserve => s.to_string(),
+        }
+    }
+}
+
+impl TagConstraints {
+    // Whether `value` is allowed for a tag with these constraints and enumerated `values`
+    pub fn allows(&self, values: &[String], value: &str) -> bool {
+        match &self.pattern {
+            Some(pattern) => pattern.is_match(value),
+            None => self.open || values.iter().any(|v| v == value),
+        }
+    }
+}
+
 impl Default for SubRule {
     fn default() -> Self {
         SubRule {
@@ -56,6 +1100,1078 @@ impl Default for SubRule {
             actual_count: 0,
             comparison_ops: Vec::new(),
             tag_kvs: HashMap::new(),
+            rule_index: 0,
+            clauses: Vec::new(),
+            priority: 0,
+            name: None,
+        }
+    }
+}
+
+impl SubRule {
+    // Subrule which holds when every clause does -- e.g. [colour = blue, shape ! circle]
+    pub fn new(rule_index: usize, clauses: Vec<Clause>) -> Self {
+        SubRule {
+            expected_count: clauses.len() as i32,
+            actual_count: 0,
+            comparison_ops: clauses.iter().map(|clause| clause.comparison_op).collect(),
+            tag_kvs: clauses
+                .iter()
+                .map(|clause| (clause.tag_name.clone(), clause.tag_value.clone()))
+                .collect(),
+            rule_index,
+            clauses,
+            priority: 0,
+            name: None,
+        }
+    }
+}
+
+// Summary on one line -- e.g. "healthy: 3 tags, 12 rules, 40 evaluations, 2 reloads"
+impl std::fmt::Display for DaemonStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} tags, {} rules, {} evaluations, {} reloads",
+            if self.healthy { "healthy" } else { "unhealthy" },
+            self.tag_count,
+            self.rule_count,
+            self.evaluations,
+            self.reloads
+        )?;
+
+        match &self.last_error {
+            Some(error) => write!(f, " (last reload failed: {})", error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for EngineStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} tags, {} subrules, {} objects",
+            self.tag_count, self.subrule_count, self.object_count
+        )?;
+
+        if self.skipped_objects > 0 {
+            write!(
+                f,
+                " ({} skipped for missing required tags)",
+                self.skipped_objects
+            )?;
         }
+        Ok(())
+    }
+}
+
+impl OperatorPrecedence {
+    // Set the level of `operator` -- e.g. with("|", 2) for '|' to bind tighter than '&'
+    pub fn with(mut self, operator: &str, level: i32) -> Self {
+        self.levels.insert(operator.to_string(), level);
+        self
+    }
+
+    pub fn level(&self, operator: &str) -> Option<i32> {
+        self.levels.get(operator).copied()
+    }
+
+    // Errors unless the table has a level for each of LOGICAL_OPERATORS, and nothing else
+    pub fn check(&self) -> Result<(), RulesError> {
+        let mut operators: Vec<&String> = self.levels.keys().collect();
+        operators.sort();
+        if let Some(unknown) = operators
+            .iter()
+            .find(|operator| !LOGICAL_OPERATORS.contains(&operator.as_str()))
+        {
+            return Err(RulesError::RuleParseError(format!(
+                "Operator precedence has unknown operator '{}', expected one of {}",
+                unknown,
+                LOGICAL_OPERATORS.join(", ")
+            )));
+        }
+        if let Some(missing) = LOGICAL_OPERATORS
+            .iter()
+            .find(|operator| !self.levels.contains_key(**operator))
+        {
+            return Err(RulesError::RuleParseError(format!(
+                "Operator precedence has no level for '{}'",
+                missing
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl EvalContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Set the value of $ctx.`name` -- e.g. with("region", "eu")
+    pub fn with(mut self, name: &str, value: &str) -> Self {
+        self.variables.insert(name.to_string(), value.to_string());
+        self
+    }
+}
+
+impl RuleId {
+    pub fn new(file: &str, line: usize, rule: &str) -> Self {
+        RuleId {
+            file: file_name(file),
+            line,
+            hash: audit::content_hash(rule.trim().as_bytes()),
+        }
+    }
+
+    // Inverse of Display -- e.g. "my_rules.rules:3#9c4f0a1b2d3e4f56"
+    pub fn parse(s: &str) -> Option<Self> {
+        let (location, hash) = s.rsplit_once('#')?;
+        let (file, line) = location.rsplit_once(':')?;
+        if file.is_empty() || hash.is_empty() {
+            return None;
+        }
+
+        Some(RuleId {
+            file: file.to_string(),
+            line: line.parse().ok()?,
+            hash: hash.to_string(),
+        })
+    }
+
+    // Whether `loaded` is this rule, even if edits elsewhere in its file have moved it
+    pub fn same_rule(&self, loaded: &LoadedRule) -> bool {
+        let id = loaded.id();
+        id.file == self.file && id.hash == self.hash
+    }
+}
+
+impl LoadedObject {
+    pub fn id(&self) -> ObjectId {
+        ObjectId {
+            file: self.file.clone(),
+            obj_type: self.obj_type.clone(),
+            index: self.index,
+        }
+    }
+}
+
+impl LoadedRule {
+    // Identity of the rule -- e.g. my_rules.rules:3#9c4f0a1b2d3e4f56
+    pub fn id(&self) -> RuleId {
+        RuleId::new(&self.file, self.line, &self.rule)
+    }
+
+    // Name the rule is written with, unique across the config -- e.g. "promo_1" for
+    // "promo_1: - colour = red". Rules without one, or with an invalid one, have no name.
+    pub fn name(&self) -> Option<&str> {
+        string::split_name(&self.rule)
+            .ok()
+            .and_then(|(name, _)| name)
+    }
+
+    // Priority the rule is written with, higher winning conflicts -- e.g. 10 for
+    // "-[10] colour = red". Rules without one, or with an invalid one, have priority 0.
+    pub fn priority(&self) -> u32 {
+        let (_name, rule) = string::split_name(&self.rule).unwrap_or((None, &self.rule));
+        let rule = rule.trim().strip_prefix('-').unwrap_or_default();
+        string::split_priority(rule).map_or(0, |(priority, _)| priority)
+    }
+
+    // Outcome the rule maps matching objects to -- e.g. "reject" for "- colour = red => reject"
+    pub fn outcome(&self) -> Option<&str> {
+        string::split_outcome(&self.rule)
+            .ok()
+            .and_then(|(_, outcome)| outcome)
+    }
+
+    // Weight the rule adds to the score of objects matching it -- e.g. "@weight 2.5"
+    pub fn weight(&self) -> f64 {
+        self.annotation(WEIGHT)
+            .and_then(|weight| weight.parse().ok())
+            .unwrap_or(DEFAULT_WEIGHT)
+    }
+
+    // Description, severity and owner from the rule's annotations -- e.g.
+    // `@description("Red items") @severity(high)`. An invalid severity is left out.
+    pub fn meta(&self) -> RuleMeta {
+        RuleMeta {
+            description: self.annotation(DESCRIPTION).map(str::to_string),
+            severity: self.annotation(SEVERITY).and_then(Severity::parse),
+            owner: self.annotation(OWNER).map(str::to_string),
+        }
+    }
+
+    // Value of the rule's annotation called `name`, the last if there's more than one
+    pub fn annotation(&self, name: &str) -> Option<&str> {
+        self.annotations
+            .iter()
+            .rev()
+            .find(|annotation| annotation.name == name)
+            .map(|annotation| annotation.value.as_str())
+    }
+
+    // Unix seconds the rule applies from -- e.g. "@effective_from 2025-01-01"
+    pub fn effective_from(&self) -> Option<u64> {
+        self.annotation(EFFECTIVE_FROM)
+            .and_then(time::parse_timestamp)
+    }
+
+    // Unix seconds the rule stops applying at -- e.g. "@expires 2025-07-01"
+    pub fn expires(&self) -> Option<u64> {
+        self.annotation(EXPIRES).and_then(time::parse_timestamp)
+    }
+
+    // Whether the rule applies at `timestamp`, in Unix seconds
+    pub fn in_effect_at(&self, timestamp: u64) -> bool {
+        self.effective_from().is_none_or(|from| from <= timestamp)
+            && self.expires().is_none_or(|expires| timestamp < expires)
+    }
+
+    // Name the rule is promoted by, if it's a draft -- e.g. "@draft red_large"
+    pub fn draft_name(&self) -> Option<&str> {
+        self.annotation(DRAFT)
+    }
+
+    pub fn is_draft(&self) -> bool {
+        self.draft_name().is_some()
+    }
+
+    // Whether the rule applies whenever `other` does
+    pub fn in_effect_throughout(&self, other: &LoadedRule) -> bool {
+        let starts_first = match (self.effective_from(), other.effective_from()) {
+            (Some(from), Some(other_from)) => from <= other_from,
+            (from, _) => from.is_none(),
+        };
+        let ends_last = match (self.expires(), other.expires()) {
+            (Some(expires), Some(other_expires)) => expires >= other_expires,
+            (expires, _) => expires.is_none(),
+        };
+        starts_first && ends_last
+    }
+
+    // Whether there's a time both rules apply at
+    pub fn in_effect_with(&self, other: &LoadedRule) -> bool {
+        let from = self
+            .effective_from()
+            .max(other.effective_from())
+            .unwrap_or(0);
+        let until = match (self.expires(), other.expires()) {
+            (Some(a), Some(b)) => a.min(b),
+            (expires, None) | (None, expires) => expires.unwrap_or(u64::MAX),
+        };
+        from < until
+    }
+}
+
+// Line of a .rules file which failed to load or parse, with its error -- e.g. line 3 of
+// my_rules.rules for "my_rules.rules:3:12: Expected a tag name, found '&'"
+#[derive(Debug)]
+pub struct RuleFailure {
+    pub location: ErrorLocation,
+    pub error: RulesError,
+}
+
+// Every failing line of a set of .rules files, in file then line order, from
+// Rules::validate_rules
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub failures: Vec<RuleFailure>,
+    // Valid rules which are probably mistakes -- e.g. one which can never match
+    pub warnings: Vec<Diagnostic>,
+}
+
+// Stable code of a kind of problem in the config, for tools to match on rather than messages --
+// e.g. "R001" for an unmatched parenthesis. A code always means the same kind of problem, and
+// codes of removed kinds aren't reused. R is for rules, T for tags, O for objects and F for files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiagnosticCode {
+    InvalidRule,
+    UnmatchedParen,
+    UnexpectedToken,
+    IncompleteRule,
+    UnknownTag,
+    UnknownValue,
+    InvalidRuleName,
+    DuplicateRuleName,
+    InvalidAnnotation,
+    InvalidMacro,
+    InvalidInclude,
+    InvalidCall,
+    InvalidComparison,
+    UnknownVariable,
+    RuleTooLarge,
+    InvalidPrefix,
+    Unsatisfiable,
+    DuplicateRule,
+    AlwaysMatches,
+    InvalidTag,
+    MissingSeparator,
+    MissingDash,
+    DuplicateValue,
+    DuplicateTag,
+    InvalidAttribute,
+    InvalidExclusiveGroup,
+    TagLimitExceeded,
+    InvalidPattern,
+    InvalidTagName,
+    InvalidTagValue,
+    InvalidObject,
+    FileError,
+}
+
+// Whether a diagnostic stops the config loading -- e.g. Warning for a tag merged with another
+// of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DiagnosticSeverity {
+    Warning,
+    Error,
+}
+
+// Where in the config a diagnostic is, counting from 1 -- e.g. my_rules.rules, line 3, column
+// 12. A rule checked on its own has only a column, counted from the start of the rule.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiagnosticSpan {
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+// A problem with a tag, rule or object, for tools -- e.g. R001 with "Unmatched opening
+// parenthesis" at my_rules.rules:3:12
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub code: DiagnosticCode,
+    pub severity: DiagnosticSeverity,
+    // The problem without where it is, which is in the span
+    pub message: String,
+    pub span: DiagnosticSpan,
+}
+
+// Warning about a rule that is valid but probably a mistake -- e.g. an expired rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintWarning {
+    pub file: String,
+    pub line: usize,
+    // Rule the warning is about
+    pub rule_id: RuleId,
+    pub message: String,
+}
+
+// -- e.g. "config/my_objects.yaml shapes[0]"
+impl std::fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}[{}]", self.file, self.obj_type, self.index)
+    }
+}
+
+impl std::fmt::Display for RuleId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}#{}", self.file, self.line, self.hash)
+    }
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    // Add the error of line `line` of `file`, with the column it gives if any
+    pub fn add(&mut self, file: &str, line: usize, error: RulesError) {
+        let location = ErrorLocation {
+            file: file.to_string(),
+            line,
+            column: error.column(),
+        };
+        self.failures.push(RuleFailure { location, error });
+    }
+
+    // The first failure's error, as failing on the first error would have given
+    pub fn first_error(self) -> Result<(), RulesError> {
+        match self.failures.into_iter().next() {
+            Some(failure) => Err(failure.error),
+            None => Ok(()),
+        }
+    }
+
+    // Every failure as a diagnostic, in order, then every warning
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.failures
+            .iter()
+            .map(|failure| failure.error.diagnostic())
+            .chain(self.warnings.iter().cloned())
+            .collect()
+    }
+
+    // The report for its warnings, or Err with the whole report if anything failed
+    pub fn into_result(self) -> Result<Self, RulesError> {
+        if self.is_valid() {
+            Ok(self)
+        } else {
+            Err(RulesError::ValidationError(self))
+        }
+    }
+}
+
+impl DiagnosticCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiagnosticCode::InvalidRule => "R000",
+            DiagnosticCode::UnmatchedParen => "R001",
+            DiagnosticCode::UnexpectedToken => "R002",
+            DiagnosticCode::IncompleteRule => "R003",
+            DiagnosticCode::UnknownTag => "R004",
+            DiagnosticCode::UnknownValue => "R005",
+            DiagnosticCode::InvalidRuleName => "R006",
+            DiagnosticCode::DuplicateRuleName => "R007",
+            DiagnosticCode::InvalidAnnotation => "R008",
+            DiagnosticCode::InvalidMacro => "R009",
+            DiagnosticCode::InvalidInclude => "R010",
+            DiagnosticCode::InvalidCall => "R011",
+            DiagnosticCode::InvalidComparison => "R012",
+            DiagnosticCode::UnknownVariable => "R013",
+            DiagnosticCode::RuleTooLarge => "R014",
+            DiagnosticCode::InvalidPrefix => "R015",
+            DiagnosticCode::Unsatisfiable => "R016",
+            DiagnosticCode::DuplicateRule => "R017",
+            DiagnosticCode::AlwaysMatches => "R018",
+            DiagnosticCode::InvalidTag => "T000",
+            DiagnosticCode::MissingSeparator => "T001",
+            DiagnosticCode::MissingDash => "T002",
+            DiagnosticCode::DuplicateValue => "T003",
+            DiagnosticCode::DuplicateTag => "T004",
+            DiagnosticCode::InvalidAttribute => "T005",
+            DiagnosticCode::InvalidExclusiveGroup => "T006",
+            DiagnosticCode::TagLimitExceeded => "T007",
+            DiagnosticCode::InvalidPattern => "T008",
+            DiagnosticCode::InvalidTagName => "T009",
+            DiagnosticCode::InvalidTagValue => "T010",
+            DiagnosticCode::InvalidObject => "O000",
+            DiagnosticCode::FileError => "F000",
+        }
+    }
+}
+
+impl DiagnosticSeverity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiagnosticSeverity::Warning => "warning",
+            DiagnosticSeverity::Error => "error",
+        }
+    }
+}
+
+impl Diagnostic {
+    // A warning about line `line` of `file` -- e.g. a tag defined there a second time
+    pub fn warning(code: DiagnosticCode, file: &str, line: usize, message: String) -> Self {
+        Diagnostic {
+            code,
+            severity: DiagnosticSeverity::Warning,
+            message,
+            span: DiagnosticSpan {
+                file: Some(file.to_string()),
+                line: Some(line),
+                column: None,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for DiagnosticCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// -- e.g. "my_rules.rules:3:12", or "column 12" for a rule checked on its own
+impl std::fmt::Display for DiagnosticSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.file, self.line, self.column) {
+            (Some(file), Some(line), Some(column)) => write!(f, "{}:{}:{}", file, line, column),
+            (Some(file), Some(line), None) => write!(f, "{}:{}", file, line),
+            (_, _, Some(column)) => write!(f, "column {}", column),
+            _ => Ok(()),
+        }
+    }
+}
+
+// -- e.g. "my_rules.rules:3:12: error[R002]: Expected a tag name, found '&'"
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.span != DiagnosticSpan::default() {
+            write!(f, "{}: ", self.span)?;
+        }
+        write!(
+            f,
+            "{}[{}]: {}",
+            self.severity.as_str(),
+            self.code,
+            self.message
+        )
+    }
+}
+
+// -- e.g. "2 invalid rule lines:\n  my_rules.rules:3: ...\n  my_rules.rules:7: ..."
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} invalid rule lines:", self.failures.len())?;
+        for failure in &self.failures {
+            write!(f, "\n  {}", failure.error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl std::fmt::Display for CnfRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let disjunctions: Vec<String> = self
+            .clauses
+            .iter()
+            .map(|disjunction| {
+                let clauses: Vec<String> = disjunction.iter().map(Clause::to_string).collect();
+                match clauses.len() {
+                    1 => clauses.join(""),
+                    _ => format!("({})", clauses.join(" | ")),
+                }
+            })
+            .collect();
+        write!(f, "{}", disjunctions.join(" & "))
+    }
+}
+
+impl std::fmt::Display for RuleConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{} and {}:{} both match when: {}",
+            self.first.file,
+            self.first.line,
+            self.second.file,
+            self.second.line,
+            self.overlap.join(" | ")
+        )
+    }
+}
+
+impl std::fmt::Display for RuleDuplicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{} duplicates line {}: {}",
+            self.rule.file, self.rule.line, self.duplicate_of.line, self.rule.rule
+        )
+    }
+}
+
+impl std::fmt::Display for Subsumption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{} {} {}:{}",
+            self.broader.file,
+            self.broader.line,
+            if self.equivalent {
+                "is equivalent to"
+            } else {
+                "subsumes"
+            },
+            self.narrower.file,
+            self.narrower.line
+        )
+    }
+}
+
+impl RuleAnalysis {
+    // Whether no rule is subsumed and no outcomes conflict
+    pub fn is_empty(&self) -> bool {
+        self.subsumptions.is_empty() && self.outcome_conflicts.is_empty()
+    }
+}
+
+impl std::fmt::Display for RuleAnalysis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for subsumption in &self.subsumptions {
+            writeln!(f, "{}", subsumption)?;
+        }
+        for conflict in &self.outcome_conflicts {
+            writeln!(
+                f,
+                "{} (=> {} and => {})",
+                conflict,
+                conflict.first.outcome().unwrap_or_default(),
+                conflict.second.outcome().unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} of {} combinations of {} match no rule",
+            self.uncovered,
+            self.combinations,
+            self.tags.join(", ")
+        )?;
+        for example in &self.examples {
+            let mut values: Vec<String> = example
+                .iter()
+                .map(|(tag_name, values)| format!("{} = {}", tag_name, values.join(", ")))
+                .collect();
+            values.sort();
+            writeln!(f, "  {{ {} }}", values.join(", "))?;
+        }
+        if !self.skipped_tags.is_empty() {
+            writeln!(
+                f,
+                "Tags without listed values were left out: {}",
+                self.skipped_tags.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl RuleCoverage {
+    // Rules no object matched, in file then line order
+    pub fn unmatched_rules(&self) -> impl Iterator<Item = &LoadedRule> {
+        self.rules
+            .iter()
+            .filter(|hits| hits.matched == 0)
+            .map(|hits| &hits.rule)
+    }
+}
+
+// Rules no object matched, then values no object held -- e.g. "1 of 3 rules matched no objects"
+impl std::fmt::Display for RuleCoverage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} of {} rules matched none of {} objects",
+            self.unmatched_rules().count(),
+            self.rules.len(),
+            self.objects
+        )?;
+        for loaded in self.unmatched_rules() {
+            writeln!(f, "  {}:{} {}", loaded.file, loaded.line, loaded.rule)?;
+        }
+        if !self.unexercised_values.is_empty() {
+            writeln!(f, "Values no object held:")?;
+            for (tag_name, values) in &self.unexercised_values {
+                writeln!(f, "  {}: {}", tag_name, values.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl InlineTestResult {
+    pub fn passed(&self) -> bool {
+        self.outcome == Ok(self.test.expect_match)
+    }
+}
+
+impl std::fmt::Display for InlineTestResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let expected = if self.test.expect_match {
+            "match"
+        } else {
+            "no_match"
+        };
+        write!(f, "{}:{}: ", self.test.file, self.test.line)?;
+        match &self.outcome {
+            _ if self.passed() => write!(f, "ok ({})", expected),
+            Ok(_) => write!(
+                f,
+                "expected {} but got {}: {}",
+                expected,
+                if self.test.expect_match {
+                    "no_match"
+                } else {
+                    "match"
+                },
+                self.test.rule.rule
+            ),
+            Err(error) => write!(f, "object couldn't be evaluated: {}", error),
+        }
+    }
+}
+
+impl std::fmt::Display for TagEdit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TagEdit::Add { tag_name, value } => write!(f, "+ {}: {}", tag_name, value),
+            TagEdit::Remove { tag_name, value } => write!(f, "- {}: {}", tag_name, value),
+        }
+    }
+}
+
+impl std::fmt::Display for MatchExplanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let failed: Vec<String> = self.failed.iter().map(Clause::to_string).collect();
+        writeln!(f, "Fails: {}", failed.join(" & "))?;
+        for edit in &self.edits {
+            writeln!(f, "  {}", edit)?;
+        }
+        if !self.complete {
+            writeln!(f, "  (plugin calls must also pass)")?;
+        }
+        Ok(())
+    }
+}
+
+impl ExplanationNode {
+    // Writes this node on one line after `connector`, then its children indented under
+    // `prefix` -- e.g. "|-- colour = red: true (red)"
+    fn write_tree(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        prefix: &str,
+        connector: &str,
+    ) -> std::fmt::Result {
+        match &self.clause {
+            Some(clause) if self.values.is_empty() => {
+                writeln!(f, "{}{}: {} (no values)", connector, clause, self.matched)?
+            }
+            Some(clause) => writeln!(
+                f,
+                "{}{}: {} ({})",
+                connector,
+                clause,
+                self.matched,
+                self.values.join(", ")
+            )?,
+            None => writeln!(f, "{}{}: {}", connector, self.token, self.matched)?,
+        }
+
+        for (i, child) in self.children.iter().enumerate() {
+            let last = i + 1 == self.children.len();
+            let (branch, indent) = if last {
+                ("`-- ", "    ")
+            } else {
+                ("|-- ", "|   ")
+            };
+            child.write_tree(
+                f,
+                &format!("{}{}", prefix, indent),
+                &format!("{}{}", prefix, branch),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// The rule's tree with each node's outcome, and the object's values at leaves -- e.g.
+// &: false
+// |-- colour = red: true (red)
+// `-- size = large: false (small)
+impl std::fmt::Display for ExplanationNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write_tree(f, "", "")
+    }
+}
+
+impl EvaluationTrace {
+    // Rules which matched, in the order checked
+    pub fn matched(&self) -> impl Iterator<Item = &LoadedRule> {
+        self.rules
+            .iter()
+            .filter(|trace| trace.matched)
+            .map(|trace| &trace.rule)
+    }
+}
+
+// Each rule checked, then the nodes it visited indented by depth -- e.g.
+// "my_rules.rules:3 - colour = red & size = large: false\n  &: false\n    colour = red: false (blue)\n"
+impl std::fmt::Display for EvaluationTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for trace in &self.rules {
+            writeln!(
+                f,
+                "{}:{} {}: {}",
+                trace.rule.file, trace.rule.line, trace.rule.rule, trace.matched
+            )?;
+            for step in &trace.steps {
+                writeln!(f, "{}{}", "  ".repeat(step.depth + 1), step)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The node and its result, with a leaf's values -- e.g. "colour = red: true (red)"
+impl std::fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.clause {
+            Some(clause) if self.values.is_empty() => {
+                write!(f, "{}: {} (no values)", clause, self.result)
+            }
+            Some(clause) => write!(
+                f,
+                "{}: {} ({})",
+                clause,
+                self.result,
+                self.values.join(", ")
+            ),
+            None => write!(f, "{}: {}", self.token, self.result),
+        }
+    }
+}
+
+impl TruthTable {
+    // The table with a header row, absent values as empty cells and outcomes as true or false --
+    // e.g. "colour,size,matched\nred,small,true\n"
+    pub fn to_csv(&self) -> Result<String, std::io::Error> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+
+        let mut header: Vec<&str> = self.tags.iter().map(String::as_str).collect();
+        header.push("matched");
+        writer.write_record(&header)?;
+
+        for row in &self.rows {
+            let mut record: Vec<&str> = row
+                .values
+                .iter()
+                .map(|value| value.as_deref().unwrap_or(""))
+                .collect();
+            record.push(if row.matched { "true" } else { "false" });
+            writer.write_record(&record)?;
+        }
+
+        let bytes = writer
+            .into_inner()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        String::from_utf8(bytes)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl ChangeKind {
+    // Marker used when printing changes -- e.g. "+ tag colour"
+    pub fn symbol(&self) -> char {
+        match self {
+            ChangeKind::Added => '+',
+            ChangeKind::Removed => '-',
+            ChangeKind::Modified => '~',
+        }
+    }
+}
+
+impl ShadowReport {
+    pub fn is_empty(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+impl From<LoadedRule> for MatchedRule {
+    fn from(loaded: LoadedRule) -> Self {
+        MatchedRule {
+            id: loaded.id(),
+            name: loaded.name().map(str::to_string),
+            meta: loaded.meta(),
+            outcome: loaded.outcome().map(str::to_string),
+            weight: loaded.weight(),
+            file: loaded.file,
+            line: loaded.line,
+            rule: loaded.rule,
+        }
+    }
+}
+
+impl Truth {
+    // Kleene '&' -- False if either side is, Unknown if either side is and neither is False
+    pub fn and(self, other: Truth) -> Truth {
+        match (self, other) {
+            (Truth::False, _) | (_, Truth::False) => Truth::False,
+            (Truth::True, Truth::True) => Truth::True,
+            _ => Truth::Unknown,
+        }
+    }
+
+    // Kleene '|' -- True if either side is, Unknown if either side is and neither is True
+    pub fn or(self, other: Truth) -> Truth {
+        match (self, other) {
+            (Truth::True, _) | (_, Truth::True) => Truth::True,
+            (Truth::False, Truth::False) => Truth::False,
+            _ => Truth::Unknown,
+        }
+    }
+
+    // Whether a rule with this result matches, Unknown going the way `policy` says
+    pub fn collapse(self, policy: UnknownPolicy) -> bool {
+        match self {
+            Truth::True => true,
+            Truth::False => false,
+            Truth::Unknown => policy == UnknownPolicy::UnknownIsTrue,
+        }
+    }
+}
+
+impl From<bool> for Truth {
+    fn from(value: bool) -> Self {
+        if value { Truth::True } else { Truth::False }
+    }
+}
+
+impl ObjectMatches {
+    // Sum of the weights of the rules the object matched
+    pub fn score(&self) -> f64 {
+        total_weight(self.matched.iter().map(LoadedRule::weight))
+    }
+}
+
+impl RuleStats {
+    // Share of the objects the rule was checked against which it matched, 0 if none were
+    pub fn match_rate(&self) -> f64 {
+        if self.evaluated == 0 {
+            return 0.0;
+        }
+        self.matched as f64 / self.evaluated as f64
+    }
+}
+
+// One line -- e.g. "my_rules.rules:3 - colour = red: matched 3 of 40"
+impl std::fmt::Display for RuleStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{} {}: matched {} of {}",
+            self.rule.file, self.rule.line, self.rule.rule, self.matched, self.evaluated
+        )
+    }
+}
+
+impl EvaluationReport {
+    // How often each rule was checked and matched, in file then line order
+    pub fn stats(&self) -> &[RuleStats] {
+        &self.rule_stats
+    }
+
+    // Results of objects matching at least one rule
+    pub fn matched(&self) -> impl Iterator<Item = &ObjectMatches> {
+        self.results
+            .iter()
+            .filter(|result| !result.matched.is_empty())
+    }
+}
+
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.rules.is_empty() && self.objects.is_empty()
+    }
+}
+
+// Each matching object, then its rules -- e.g. "config/my_objects.yaml shapes[0]:"
+impl std::fmt::Display for EvaluationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} objects matched",
+            self.matched().count(),
+            self.results.len()
+        )?;
+        if self.skipped_objects > 0 {
+            write!(
+                f,
+                " ({} skipped for missing required tags)",
+                self.skipped_objects
+            )?;
+        }
+        writeln!(f)?;
+
+        for result in self.matched() {
+            writeln!(f, "{}:", result.object.id())?;
+            for loaded in &result.matched {
+                write!(f, "  {}:{} {}", loaded.file, loaded.line, loaded.rule)?;
+                let meta = loaded.meta();
+                if !meta.is_empty() {
+                    write!(f, " {}", meta)?;
+                }
+                writeln!(f)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// One change per line -- e.g. "~ tag colour: red, blue -> red, blue, green"
+impl std::fmt::Display for ShadowReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} of {} objects diverge",
+            self.divergences.len(),
+            self.evaluated
+        )?;
+
+        for divergence in &self.divergences {
+            writeln!(f, "object {}:", divergence.index)?;
+            for (symbol, rules) in [("-", &divergence.removed), ("+", &divergence.added)] {
+                for loaded in rules {
+                    writeln!(
+                        f,
+                        "  {} {}:{} {}",
+                        symbol, loaded.file, loaded.line, loaded.rule
+                    )?;
+                }
+            }
+            if let Some(error) = &divergence.candidate_error {
+                writeln!(f, "  ! {}", error)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for ChangeSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for change in &self.tags {
+            write!(f, "{} tag {}", change.kind.symbol(), change.name)?;
+            match change.kind {
+                ChangeKind::Added => writeln!(f, ": {}", change.new_values.join(", "))?,
+                ChangeKind::Removed => writeln!(f, ": {}", change.old_values.join(", "))?,
+                ChangeKind::Modified => writeln!(
+                    f,
+                    ": {} -> {}",
+                    change.old_values.join(", "),
+                    change.new_values.join(", ")
+                )?,
+            }
+        }
+
+        for change in &self.rules {
+            write!(f, "{} rule {}: ", change.kind.symbol(), change.file)?;
+            match (&change.old_rule, &change.new_rule) {
+                (Some(old), Some(new)) => writeln!(f, "{} -> {}", old, new)?,
+                (Some(rule), None) | (None, Some(rule)) => writeln!(f, "{}", rule)?,
+                (None, None) => writeln!(f)?,
+            }
+        }
+
+        for change in &self.objects {
+            writeln!(
+                f,
+                "{} object {} {}[{}]",
+                change.kind.symbol(),
+                change.file,
+                change.obj_type,
+                change.index
+            )?;
+        }
+
+        Ok(())
     }
 }
@@ -5,22 +5,40 @@ use std::fs;
 
 use glob::glob;
 
-pub fn read_files_in_dir(pattern: &str) -> Result<Vec<String>, RulesError> {
+// Contents of each file matching `pattern`, with its path -- e.g. ("config/my_tags.tags", "- Colour: Red")
+pub fn read_files_in_dir_with_paths(pattern: &str) -> Result<Vec<(String, String)>, RulesError> {
     let mut contents = Vec::new();
 
     for entry in glob(pattern)? {
         let path = entry?;
-        let file_content = fs::read_to_string(path)?;
-        contents.push(file_content);
+        let file_content = fs::read_to_string(&path)?;
+        contents.push((path.display().to_string(), file_content));
     }
+    // Sorted by path, so files are always read in the same order
+    contents.sort_by(|a, b| a.0.cmp(&b.0));
 
     Ok(contents)
 }
 
+// Path, modification time and size of each file matching `pattern`, to tell when files change
+#[cfg(feature = "watch")]
+pub fn file_stamps(pattern: &str) -> Result<Vec<(String, std::time::SystemTime, u64)>, RulesError> {
+    let mut stamps = Vec::new();
+
+    for entry in glob(pattern)? {
+        let path = entry?;
+        let metadata = fs::metadata(&path)?;
+        stamps.push((
+            path.display().to_string(),
+            metadata.modified()?,
+            metadata.len(),
+        ));
+    }
+
+    Ok(stamps)
+}
+
 pub fn line_blank_or_comment(line: &str) -> bool {
     let trimmed = line.trim();
-    if trimmed.is_empty() || trimmed.starts_with('#') {
-        return true;
-    }
-    return false;
+    trimmed.is_empty() || trimmed.starts_with('#')
 }
@@ -0,0 +1,147 @@
+// Evaluation and reload metrics, recorded through the `metrics` facade when the feature is on
+use crate::types::LoadedRule;
+
+use std::time::Duration;
+
+// Rules checked against objects
+#[cfg(feature = "metrics")]
+pub const RULES_EVALUATED: &str = "rules_evaluated_total";
+
+// Objects matching each rule, labelled by rule location -- e.g. rule="config/a.rules:3"
+#[cfg(feature = "metrics")]
+pub const RULE_MATCHES: &str = "rules_rule_matches_total";
+
+// Time to evaluate one object against every rule
+#[cfg(feature = "metrics")]
+pub const EVALUATION_SECONDS: &str = "rules_evaluation_duration_seconds";
+
+// Evaluation cache lookups, labelled result="hit" or result="miss"
+#[cfg(feature = "metrics")]
+pub const CACHE_LOOKUPS: &str = "rules_cache_lookups_total";
+
+// Tag reloads, labelled result="ok" or result="error"
+#[cfg(feature = "metrics")]
+pub const RELOADS: &str = "rules_reloads_total";
+
+pub fn record_evaluation(rules_evaluated: usize, matched: &[LoadedRule], elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(RULES_EVALUATED).increment(rules_evaluated as u64);
+        ::metrics::histogram!(EVALUATION_SECONDS).record(elapsed.as_secs_f64());
+
+        for loaded in matched {
+            let rule = format!("{}:{}", loaded.file, loaded.line);
+            ::metrics::counter!(RULE_MATCHES, "rule" => rule).increment(1);
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = (rules_evaluated, matched, elapsed);
+}
+
+pub fn record_cache_lookup(hit: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let result = if hit { "hit" } else { "miss" };
+        ::metrics::counter!(CACHE_LOOKUPS, "result" => result).increment(1);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = hit;
+}
+
+pub fn record_reload(success: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let result = if success { "ok" } else { "error" };
+        ::metrics::counter!(RELOADS, "result" => result).increment(1);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    let _ = success;
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::Rules;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use metrics_util::{CompositeKey, MetricKind};
+    use std::collections::HashMap;
+    use std::fs;
+
+    fn setup_test_env(test_name: &str) -> String {
+        let test_dir = format!("src/api/tests/test_config/{}", test_name);
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+
+        fs::write(
+            format!("{}/test.tags", test_dir),
+            "- colour: red, blue\n- size: small, large",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/test.rules", test_dir),
+            "- colour = red\n- colour = blue\n- size = small",
+        )
+        .unwrap();
+
+        test_dir
+    }
+
+    fn labels(key: &CompositeKey) -> Vec<(String, String)> {
+        key.key()
+            .labels()
+            .map(|label| (label.key().to_string(), label.value().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_record_evaluation() {
+        let test_dir = setup_test_env("test_record_evaluation");
+        let mut rules = Rules::new(&test_dir);
+        rules.load_tags().unwrap();
+        let mut obj = HashMap::new();
+        obj.insert("colour".to_string(), vec!["blue".to_string()]);
+
+        // Installed for this thread only, so other tests' evaluations aren't recorded
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let matched = ::metrics::with_local_recorder(&recorder, || rules.matching_rules(&obj));
+        assert_eq!(matched.unwrap().len(), 1);
+
+        let recorded: Vec<(CompositeKey, DebugValue)> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| (key, value))
+            .collect();
+        let find = |kind: MetricKind, name: &str| {
+            recorded
+                .iter()
+                .find(|(key, _)| key.kind() == kind && key.key().name() == name)
+                .unwrap_or_else(|| panic!("{} wasn't recorded", name))
+        };
+
+        let (key, value) = find(MetricKind::Counter, RULES_EVALUATED);
+        assert!(labels(key).is_empty());
+        assert_eq!(*value, DebugValue::Counter(3));
+
+        let (key, value) = find(MetricKind::Counter, RULE_MATCHES);
+        let rule = format!("{}/test.rules:2", test_dir);
+        assert_eq!(labels(key), vec![("rule".to_string(), rule)]);
+        assert_eq!(*value, DebugValue::Counter(1));
+
+        let (key, value) = find(MetricKind::Histogram, EVALUATION_SECONDS);
+        assert!(labels(key).is_empty());
+        match value {
+            DebugValue::Histogram(seconds) => {
+                assert_eq!(seconds.len(), 1);
+                assert!(seconds[0].into_inner() >= 0.0);
+            }
+            other => panic!("{} recorded as {:?}", EVALUATION_SECONDS, other),
+        }
+
+        let _ = fs::remove_dir_all(&test_dir);
+    }
+}
@@ -1,2 +1,4 @@
 pub mod file;
+pub mod metrics;
 pub mod string;
+pub mod time;
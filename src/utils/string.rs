@@ -18,7 +18,7 @@ pub fn normalise(string: &str) -> Result<String, RulesError> {
         ));
     }
 
-    let dash_count = string.matches('-').count();
+    let dash_count = count_dashes_outside_calls(string);
     if dash_count > 1 {
         return Err(RulesError::RuleParseError(format!(
             "Rule should only contain 1 dash, found {}",
@@ -34,3 +34,320 @@ pub fn normalise(string: &str) -> Result<String, RulesError> {
         .trim()
         .to_string())
 }
+
+// Arrow between a rule's comparisons and its outcome -- e.g. "- colour = red => reject"
+pub const OUTCOME_ARROW: &str = "=>";
+
+// A rule without its outcome, and the outcome if it has one -- e.g. "- colour = red => reject"
+// gives ("- colour = red", Some("reject"))
+pub fn split_outcome(rule: &str) -> Result<(&str, Option<&str>), RulesError> {
+    let Some(arrow) = find_outside_quotes(rule, OUTCOME_ARROW) else {
+        return Ok((rule, None));
+    };
+    let (rule, outcome) = (&rule[..arrow], &rule[arrow + OUTCOME_ARROW.len()..]);
+
+    let outcome = outcome.trim();
+    if outcome.is_empty() || !outcome.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(RulesError::RuleParseError(format!(
+            "Invalid outcome: '{}', expected a name like reject",
+            outcome
+        )));
+    }
+
+    Ok((rule.trim_end(), Some(outcome)))
+}
+
+// Between a rule's name and its dash -- e.g. "promo_1: - colour = red"
+pub const NAME_SEPARATOR: char = ':';
+
+// A rule's name and the rest of the rule -- e.g. "promo_1: - colour = red" gives
+// (Some("promo_1"), "- colour = red"). Rules starting with their dash have no name.
+pub fn split_name(rule: &str) -> Result<(Option<&str>, &str), RulesError> {
+    let trimmed = rule.trim_start();
+    if trimmed.starts_with('-') {
+        return Ok((None, rule));
+    }
+
+    // Anything but a single word before the separator is left for the dash check to report
+    let Some((name, rest)) = trimmed.split_once(NAME_SEPARATOR) else {
+        return Ok((None, rule));
+    };
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Ok((None, rule));
+    }
+
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(RulesError::RuleParseError(format!(
+            "Invalid rule name: '{}', expected a name like promo_1",
+            name
+        )));
+    }
+
+    Ok((Some(name), rest.trim_start()))
+}
+
+// A rule's priority and the rest of the rule, after its dash -- e.g. "[10] colour = red" gives
+// (10, "colour = red"). Rules without one have priority 0.
+pub fn split_priority(rule: &str) -> Result<(u32, &str), RulesError> {
+    let Some(bracketed) = rule.trim_start().strip_prefix('[') else {
+        return Ok((0, rule));
+    };
+
+    let invalid = || {
+        RulesError::RuleParseError(format!(
+            "Invalid priority: '[{}', expected a whole number like -[10]",
+            bracketed.split_whitespace().next().unwrap_or_default()
+        ))
+    };
+    let (priority, rest) = bracketed.split_once(']').ok_or_else(invalid)?;
+    let priority = priority.trim().parse::<u32>().map_err(|_| invalid())?;
+
+    Ok((priority, rest.trim_start()))
+}
+
+// Between the ends of a range of whole numbers -- e.g. "- floor = 1..10"
+pub const RANGE_SEPARATOR: &str = "..";
+
+// The ends of a range value, both included -- e.g. "1..10" gives Some((1, 10)). Values without
+// the separator aren't ranges, and give None.
+pub fn split_range(value: &str) -> Result<Option<(u64, u64)>, RulesError> {
+    let Some((start, end)) = value.split_once(RANGE_SEPARATOR) else {
+        return Ok(None);
+    };
+
+    let invalid = || {
+        RulesError::RuleParseError(format!(
+            "Invalid range: '{}', expected whole numbers from low to high like 1..10",
+            value
+        ))
+    };
+    let start = start.parse::<u64>().map_err(|_| invalid())?;
+    let end = end.parse::<u64>().map_err(|_| invalid())?;
+    if start > end {
+        return Err(invalid());
+    }
+
+    Ok(Some((start, end)))
+}
+
+// Stands for any run of characters in a tag value, making it a glob -- e.g. "- colour = re*"
+pub const WILDCARD: char = '*';
+
+// Whether `value` matches the glob `pattern`, where each '*' matches any run of characters,
+// including none -- e.g. "re*" matches "red" and "re", but not "ruby"
+pub fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    // Position of the last '*' seen, and of the value when it was
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        if p < pattern.len() && pattern[p] == WILDCARD {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == value[v] {
+            p += 1;
+            v += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last '*' match one more character
+            p = star + 1;
+            v = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == WILDCARD)
+}
+
+// Quote around a tag value with spaces or reserved characters -- e.g. colour = "fish & chips"
+pub const VALUE_QUOTE: char = '"';
+
+// Escapes a quote or backslash inside a quoted value -- e.g. "8\" disc"
+pub const VALUE_ESCAPE: char = '\\';
+
+// Characters with a meaning in rules or .tags files, which a value only contains when quoted
+pub const RESERVED_CHARS: &[char] = &['(', ')', '=', '!', '<', '>', '~', '?', '&', '|', ',', ':'];
+
+// Characters of a quoted value hidden while its rule is migrated, so the value stays one
+// word -- e.g. the space and '&' in "fish & chips". Each is swapped for a private use
+// character from HIDDEN_BASE on.
+const HIDDEN_CHARS: &[char] = &[
+    ' ', '.', '"', '\\', '(', ')', '=', '!', '<', '>', '~', '?', '&', '|', ',', ':',
+];
+const HIDDEN_BASE: u32 = 0xE000;
+
+// Read a quoted value up to its closing quote, the opening quote already read, with escapes
+// undone -- e.g. `8\" disc"` gives `8" disc`. None if the quote is never closed. A backslash
+// before anything but a quote or backslash is kept, so regexes like "^\d" are unchanged.
+pub fn read_quoted(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            VALUE_QUOTE => return Some(value),
+            VALUE_ESCAPE => match chars.next() {
+                Some(next @ (VALUE_QUOTE | VALUE_ESCAPE)) => value.push(next),
+                Some(next) => {
+                    value.push(c);
+                    value.push(next);
+                }
+                None => return None,
+            },
+            _ => value.push(c),
+        }
+    }
+    None
+}
+
+// `value` without its surrounding quotes and escapes, if it's quoted -- e.g. `"dark red"` gives
+// "dark red". Unquoted values are returned as they are.
+pub fn unquote(value: &str) -> String {
+    let mut chars = value.chars();
+    if chars.next() == Some(VALUE_QUOTE)
+        && let Some(unquoted) = read_quoted(&mut chars)
+        && chars.next().is_none()
+    {
+        return unquoted;
+    }
+    value.to_string()
+}
+
+// `value` quoted, with quotes and backslashes in it escaped -- e.g. `8" disc` gives `"8\" disc"`
+pub fn quote(value: &str) -> String {
+    let escaped = value
+        .replace(VALUE_ESCAPE, "\\\\")
+        .replace(VALUE_QUOTE, "\\\"");
+    format!("{}{}{}", VALUE_QUOTE, escaped, VALUE_QUOTE)
+}
+
+// `value` as written in a rule or .tags file, quoted if it has spaces, reserved characters,
+// quotes or a range separator -- e.g. "dark red" gives `"dark red"`, but "red" stays as it is
+pub fn quote_if_needed(value: &str) -> String {
+    if value.contains(char::is_whitespace)
+        || value.contains(RESERVED_CHARS)
+        || value.contains(VALUE_QUOTE)
+        || value.contains(RANGE_SEPARATOR)
+    {
+        quote(value)
+    } else {
+        value.to_string()
+    }
+}
+
+// `value` with its spaces and reserved characters hidden from rule migration -- e.g. "fish &
+// chips" gives one word. Other whitespace is hidden as a space.
+pub fn hide_reserved(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .map(|c| match HIDDEN_CHARS.iter().position(|h| *h == c) {
+            Some(i) => char::from_u32(HIDDEN_BASE + i as u32).unwrap_or(c),
+            None => c,
+        })
+        .collect()
+}
+
+// `token` with the characters hide_reserved hid restored
+pub fn reveal_reserved(token: &str) -> String {
+    token
+        .chars()
+        .map(|c| {
+            let hidden = (c as u32).checked_sub(HIDDEN_BASE);
+            hidden
+                .and_then(|i| HIDDEN_CHARS.get(i as usize))
+                .copied()
+                .unwrap_or(c)
+        })
+        .collect()
+}
+
+// Whether hide_reserved hid anything in `token`
+pub fn has_hidden(token: &str) -> bool {
+    reveal_reserved(token) != token
+}
+
+// `string` split at each `separator` outside quoted values -- e.g. `red, "fish, chips"` at ','
+// gives ["red", " \"fish, chips\""]
+pub fn split_outside_quotes(string: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in string.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            VALUE_ESCAPE if quoted => escaped = true,
+            VALUE_QUOTE => quoted = !quoted,
+            _ if c == separator && !quoted => {
+                parts.push(&string[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&string[start..]);
+
+    parts
+}
+
+// Byte position of the first `pattern` outside quoted values -- e.g. the arrow in
+// `- colour = "a=>b" => reject`
+fn find_outside_quotes(string: &str, pattern: &str) -> Option<usize> {
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in string.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            VALUE_ESCAPE if quoted => escaped = true,
+            VALUE_QUOTE => quoted = !quoted,
+            _ if !quoted && string[i..].starts_with(pattern) => return Some(i),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+// Dashes in function call arguments are part of the argument -- e.g. "before(2024-01-01)". A call
+// is a name directly followed by '(', unlike a group, which follows an operator or a space.
+// Dashes in a regex after '~' are part of the regex too -- e.g. "^AB[0-9]+$", as are dashes in a
+// quoted value -- e.g. "\"navy-blue\"". The dash of an implication arrow isn't counted -- e.g.
+// "colour = red -> size = large".
+fn count_dashes_outside_calls(string: &str) -> usize {
+    let mut count = 0;
+    let mut call_depth = 0;
+    let mut previous: Option<char> = None;
+    let mut quoted = false;
+    let mut escaped = false;
+    // After a '~', and whether its value has started
+    let mut in_regex = false;
+    let mut regex_started = false;
+
+    let mut chars = string.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            _ if escaped => escaped = false,
+            VALUE_ESCAPE if quoted => escaped = true,
+            VALUE_QUOTE if call_depth == 0 => quoted = !quoted,
+            _ if quoted => {}
+            '(' if call_depth > 0 => call_depth += 1,
+            '(' if previous.is_some_and(|p| p.is_alphanumeric() || p == '_') => call_depth = 1,
+            ')' if call_depth > 0 => call_depth -= 1,
+            '~' if call_depth == 0 => in_regex = true,
+            c if c.is_whitespace() && regex_started => (in_regex, regex_started) = (false, false),
+            '-' if call_depth == 0 && !in_regex && chars.peek() != Some(&'>') => count += 1,
+            _ => {}
+        }
+        if in_regex && c != '~' && !c.is_whitespace() {
+            regex_started = true;
+        }
+        previous = Some(c);
+    }
+
+    count
+}
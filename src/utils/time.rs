@@ -0,0 +1,141 @@
+// Timestamps in rule annotations, as Unix seconds -- e.g. "@expires 2025-01-01"
+
+pub const SECONDS_PER_DAY: u64 = 86_400;
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Year, month and day of a count of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(digits: &str, len: usize) -> Option<T> {
+    if digits.len() != len || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// Unix seconds, a date or a UTC date and time -- e.g. "1735689600", "2025-01-01" or
+// "2025-01-01T09:30:00Z"
+pub fn parse_timestamp(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()) {
+        return value.parse().ok();
+    }
+
+    let (date, time) = match value.split_once('T') {
+        Some((date, time)) => (date, Some(time.strip_suffix('Z')?)),
+        None => (value, None),
+    };
+
+    let mut parts = date.split('-');
+    let year: i64 = parse_number(parts.next()?, 4)?;
+    let month: u32 = parse_number(parts.next()?, 2)?;
+    let day: u32 = parse_number(parts.next()?, 2)?;
+    if parts.next().is_some()
+        || !(1..=12).contains(&month)
+        || day == 0
+        || day > days_in_month(year, month)
+    {
+        return None;
+    }
+
+    let seconds_of_day = match time {
+        Some(time) => {
+            let mut parts = time.split(':');
+            let hour: u64 = parse_number(parts.next()?, 2)?;
+            let minute: u64 = parse_number(parts.next()?, 2)?;
+            let second: u64 = parse_number(parts.next()?, 2)?;
+            if parts.next().is_some() || hour > 23 || minute > 59 || second > 59 {
+                return None;
+            }
+            hour * 3600 + minute * 60 + second
+        }
+        None => 0,
+    };
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(days * SECONDS_PER_DAY + seconds_of_day)
+}
+
+// UTC date and time of Unix seconds -- e.g. "2025-01-01T09:30:00Z"
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days((timestamp / SECONDS_PER_DAY) as i64);
+    let seconds_of_day = timestamp % SECONDS_PER_DAY;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+// UTC date of Unix seconds -- e.g. "2025-01-01"
+pub fn format_date(timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days((timestamp / SECONDS_PER_DAY) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("0"), Some(0));
+        assert_eq!(parse_timestamp("1735689600"), Some(1_735_689_600));
+        assert_eq!(parse_timestamp("1970-01-01"), Some(0));
+        assert_eq!(parse_timestamp("2025-01-01"), Some(1_735_689_600));
+        assert_eq!(parse_timestamp("2024-02-29"), Some(1_709_164_800));
+        assert_eq!(parse_timestamp("2025-01-01T09:30:15Z"), Some(1_735_723_815));
+
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("2025-13-01"), None);
+        assert_eq!(parse_timestamp("2025-02-29"), None);
+        assert_eq!(parse_timestamp("2025-1-01"), None);
+        assert_eq!(parse_timestamp("2025-01-01T09:30:15"), None);
+        assert_eq!(parse_timestamp("2025-01-01T24:00:00Z"), None);
+        assert_eq!(parse_timestamp("1969-12-31"), None);
+        assert_eq!(parse_timestamp("next tuesday"), None);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(1_735_723_815), "2025-01-01T09:30:15Z");
+        assert_eq!(format_timestamp(1_709_164_800), "2024-02-29T00:00:00Z");
+        assert_eq!(format_date(1_735_723_815), "2025-01-01");
+    }
+}
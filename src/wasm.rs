@@ -0,0 +1,104 @@
+// WebAssembly bindings, so rule-authoring UIs check rules with the backend's grammar. There is
+// no filesystem in the browser, so tags are passed in as .tags file content.
+use crate::err::RulesError;
+use crate::parser::rules::RuleParser;
+use crate::parser::tags;
+use crate::parser::types::Node;
+use crate::types::TagLoadOptions;
+
+use serde_json::{Value, json};
+use wasm_bindgen::prelude::*;
+
+// Parser for rules against `tags_source` -- e.g. "- colour: red, blue\n- size: small"
+fn rule_parser(tags_source: &str) -> Result<RuleParser, RulesError> {
+    let sources = vec![("tags".to_string(), tags_source.to_string())];
+    let parsed = tags::parse_tags_from_sources(sources, TagLoadOptions::default())?;
+
+    Ok(RuleParser::from_tags(parsed.tags))
+}
+
+// Leaves are comparisons, branches are & and | -- e.g. {"op": "=", "tag": "colour", "value": "red"}
+fn node_to_json(node: &Node) -> Value {
+    if let Some(clause) = &node.clause {
+        return json!({
+            "op": clause.comparison_op.as_str(),
+            "tag": clause.tag_name,
+            "value": clause.tag_value,
+        });
+    }
+
+    json!({
+        "op": node.token.as_char().to_string(),
+        "left": node.left.as_deref().map(node_to_json),
+        "right": node.right.as_deref().map(node_to_json),
+    })
+}
+
+fn check_rule(rule: &str, tags_source: &str) -> Result<(), RulesError> {
+    rule_parser(tags_source)?.validate_rule(rule)
+}
+
+fn rule_to_json(rule: &str, tags_source: &str) -> Result<String, RulesError> {
+    let ast = rule_parser(tags_source)?.string_to_rule(rule)?;
+    Ok(node_to_json(&ast.root_node).to_string())
+}
+
+fn to_js_error(error: RulesError) -> JsError {
+    JsError::new(&error.to_string())
+}
+
+/// Validates `rule` against the tags in `tags`, the content of a `.tags` file.
+///
+/// Throws an `Error` describing the first problem found.
+#[wasm_bindgen]
+pub fn validate_rule(rule: &str, tags: &str) -> Result<(), JsError> {
+    check_rule(rule, tags).map_err(to_js_error)
+}
+
+/// Parses `rule` against the tags in `tags` and returns its syntax tree as JSON.
+///
+/// Throws an `Error` if the rule is invalid.
+#[wasm_bindgen]
+pub fn parse_to_json(rule: &str, tags: &str) -> Result<String, JsError> {
+    rule_to_json(rule, tags).map_err(to_js_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAGS: &str = "# Test tags\n- colour: red, blue\n- size: small < medium < large";
+
+    #[test]
+    fn test_check_rule() {
+        assert!(check_rule("- Colour = red & size > small", TAGS).is_ok());
+
+        let result = check_rule("- colour = green", TAGS);
+        if let Err(RulesError::RuleParseError(msg)) = result {
+            assert!(msg.contains("green"));
+        } else {
+            panic!("Expected RuleParseError about unknown value");
+        }
+
+        assert!(check_rule("- colour = red", "- colour red").is_err());
+    }
+
+    #[test]
+    fn test_rule_to_json() {
+        let json = rule_to_json("- (colour = red, blue) & size > small", TAGS).unwrap();
+        let ast: Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(ast["op"], "&");
+        assert_eq!(ast["left"]["op"], "|");
+        assert_eq!(
+            ast["left"]["left"],
+            json!({ "op": "=", "tag": "colour", "value": "red" })
+        );
+        assert_eq!(
+            ast["right"],
+            json!({ "op": ">", "tag": "size", "value": "small" })
+        );
+
+        assert!(rule_to_json("- colour = red &", TAGS).is_err());
+    }
+}
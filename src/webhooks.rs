@@ -0,0 +1,441 @@
+// Webhook notifications when annotated rules match -- `@notify pager`
+use crate::types::{LoadedRule, Object, RuleId};
+
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Annotation naming the webhooks a rule's matches are sent to -- e.g. "@notify pager, audit"
+pub const NOTIFY: &str = "notify";
+
+// How events are batched and retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WebhookOptions {
+    // Events sent in one request at most
+    pub batch_size: usize,
+    // Longest an event waits for its batch to fill
+    pub flush_interval: Duration,
+    // Attempts after the first before a batch is dropped
+    pub max_retries: u32,
+    // Wait before the first retry, doubled for each one after
+    pub retry_backoff: Duration,
+    // Limit on each request, including reading the response
+    pub timeout: Duration,
+}
+
+// Rule matched by an object, as POSTed to a webhook
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchEvent {
+    // Name of the webhook, as given in the rule's @notify annotation -- e.g. "pager"
+    pub webhook: String,
+    pub rule: String,
+    pub rule_id: RuleId,
+    pub file: String,
+    pub line: usize,
+    // Fingerprint of the object's tags and values, the same for identical objects
+    pub object_id: String,
+    // Unix seconds the object was evaluated at
+    pub timestamp: u64,
+    // The object's tags and values
+    pub context: Object,
+}
+
+// Delivery counts since the notifier started
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NotifierStats {
+    // Events in batches the webhook accepted
+    pub delivered: u64,
+    // Events in batches dropped after every retry failed
+    pub dropped: u64,
+    // Requests repeated after a failure
+    pub retries: u64,
+}
+
+enum Message {
+    Event(MatchEvent),
+    Flush(Sender<()>),
+}
+
+// Sends match events to webhooks from a background thread, so evaluation never waits on them
+pub struct Notifier {
+    m_webhooks: HashMap<String, String>,
+    m_sender: Option<Sender<Message>>,
+    m_worker: Option<JoinHandle<()>>,
+    m_stats: Arc<Mutex<NotifierStats>>,
+}
+
+impl Default for WebhookOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 100,
+            flush_interval: Duration::from_secs(5),
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl MatchEvent {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "webhook": self.webhook,
+            "rule": self.rule,
+            "rule_id": self.rule_id.to_string(),
+            "file": self.file,
+            "line": self.line,
+            "object_id": self.object_id,
+            "timestamp": self.timestamp,
+            "context": self.context,
+        })
+    }
+}
+
+// Webhook names in a rule's @notify annotation -- e.g. "pager, audit" gives ["pager", "audit"]
+pub fn webhook_names(loaded: &LoadedRule) -> Vec<&str> {
+    loaded
+        .annotation(NOTIFY)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn lock(stats: &Mutex<NotifierStats>) -> std::sync::MutexGuard<'_, NotifierStats> {
+    stats.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// POST a batch, retrying with backoff on transport errors, 408, 429 and 5xx responses
+fn deliver(
+    agent: &ureq::Agent,
+    url: &str,
+    events: &[MatchEvent],
+    options: &WebhookOptions,
+    stats: &Mutex<NotifierStats>,
+) {
+    let events: Vec<Value> = events.iter().map(MatchEvent::to_json).collect();
+    let body = json!({ "events": events }).to_string();
+
+    let mut backoff = options.retry_backoff;
+    for attempt in 0..=options.max_retries {
+        if attempt > 0 {
+            thread::sleep(backoff);
+            backoff *= 2;
+            lock(stats).retries += 1;
+        }
+
+        let response = agent
+            .post(url)
+            .header("Content-Type", "application/json")
+            .send(body.as_str());
+        let retryable = match response {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                if (200..300).contains(&status) {
+                    lock(stats).delivered += events.len() as u64;
+                    return;
+                }
+                matches!(status, 408 | 429 | 500..)
+            }
+            Err(_) => true,
+        };
+        if !retryable {
+            break;
+        }
+    }
+
+    lock(stats).dropped += events.len() as u64;
+}
+
+fn run_worker(
+    webhooks: HashMap<String, String>,
+    options: WebhookOptions,
+    receiver: mpsc::Receiver<Message>,
+    stats: Arc<Mutex<NotifierStats>>,
+) {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .http_status_as_error(false)
+        .timeout_global(Some(options.timeout))
+        .build()
+        .into();
+    let batch_size = options.batch_size.max(1);
+
+    // Queued events by webhook name, and when the oldest must be sent by
+    let mut pending: BTreeMap<String, Vec<MatchEvent>> = BTreeMap::new();
+    let mut deadline: Option<Instant> = None;
+
+    let flush_all = |pending: &mut BTreeMap<String, Vec<MatchEvent>>| {
+        for (name, events) in std::mem::take(pending) {
+            deliver(&agent, &webhooks[&name], &events, &options, &stats);
+        }
+    };
+
+    loop {
+        let message = match deadline {
+            Some(deadline) => {
+                receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match message {
+            Ok(Message::Event(event)) => {
+                let events = pending.entry(event.webhook.clone()).or_default();
+                events.push(event);
+                if events.len() >= batch_size {
+                    let name = events[0].webhook.clone();
+                    let events = pending.remove(&name).unwrap_or_default();
+                    deliver(&agent, &webhooks[&name], &events, &options, &stats);
+                }
+                deadline = deadline.or_else(|| Some(Instant::now() + options.flush_interval));
+            }
+            Ok(Message::Flush(reply)) => {
+                flush_all(&mut pending);
+                let _ = reply.send(());
+            }
+            Err(RecvTimeoutError::Timeout) => flush_all(&mut pending),
+            Err(RecvTimeoutError::Disconnected) => {
+                flush_all(&mut pending);
+                break;
+            }
+        }
+
+        if pending.is_empty() {
+            deadline = None;
+        }
+    }
+}
+
+impl Notifier {
+    // Start sending to `webhooks`, a map of name to URL -- e.g. "pager" to
+    // "https://hooks.example.com/pager"
+    pub fn new(webhooks: HashMap<String, String>, options: WebhookOptions) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let stats = Arc::new(Mutex::new(NotifierStats::default()));
+
+        let worker = {
+            let webhooks = webhooks.clone();
+            let stats = Arc::clone(&stats);
+            thread::spawn(move || run_worker(webhooks, options, receiver, stats))
+        };
+
+        Self {
+            m_webhooks: webhooks,
+            m_sender: Some(sender),
+            m_worker: Some(worker),
+            m_stats: stats,
+        }
+    }
+
+    // Queue an event for each webhook named by the matched rules. Names without a configured
+    // webhook are skipped.
+    pub fn notify(&self, obj: &Object, object_id: &str, matched: &[LoadedRule], timestamp: u64) {
+        let Some(sender) = &self.m_sender else {
+            return;
+        };
+
+        for loaded in matched {
+            for name in webhook_names(loaded) {
+                if !self.m_webhooks.contains_key(name) {
+                    continue;
+                }
+
+                let _ = sender.send(Message::Event(MatchEvent {
+                    webhook: name.to_string(),
+                    rule: loaded.rule.clone(),
+                    rule_id: loaded.id(),
+                    file: loaded.file.clone(),
+                    line: loaded.line,
+                    object_id: object_id.to_string(),
+                    timestamp,
+                    context: obj.clone(),
+                }));
+            }
+        }
+    }
+
+    // Send every queued event now, waiting until each batch is delivered or dropped
+    pub fn flush(&self) {
+        let Some(sender) = &self.m_sender else {
+            return;
+        };
+
+        let (reply, done) = mpsc::channel();
+        if sender.send(Message::Flush(reply)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+
+    pub fn stats(&self) -> NotifierStats {
+        *lock(&self.m_stats)
+    }
+}
+
+// Queued events are sent before the notifier goes away
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.m_sender = None;
+        if let Some(worker) = self.m_worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::RuleAnnotation;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    // Answer each request with the next status, returning the request bodies
+    fn serve(statuses: Vec<u16>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                let response = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            bodies
+        });
+
+        (format!("http://{}", addr), handle)
+    }
+
+    fn notified(rule: &str, webhooks: &str) -> LoadedRule {
+        LoadedRule {
+            file: "test.rules".to_string(),
+            line: 2,
+            rule: rule.to_string(),
+            annotations: vec![RuleAnnotation {
+                name: NOTIFY.to_string(),
+                value: webhooks.to_string(),
+            }],
+        }
+    }
+
+    fn options() -> WebhookOptions {
+        WebhookOptions {
+            batch_size: 2,
+            flush_interval: Duration::from_secs(60),
+            max_retries: 1,
+            retry_backoff: Duration::from_millis(1),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn object() -> Object {
+        let mut obj = Object::new();
+        obj.insert("colour".to_string(), vec!["red".to_string()]);
+        obj
+    }
+
+    #[test]
+    fn test_webhook_names() {
+        assert_eq!(
+            webhook_names(&notified("- colour = red", "pager, audit,")),
+            vec!["pager", "audit"]
+        );
+        let mut loaded = notified("- colour = red", "pager");
+        loaded.annotations.clear();
+        assert!(webhook_names(&loaded).is_empty());
+    }
+
+    #[test]
+    fn test_batching_and_retry() {
+        // The first batch fails once, then both batches are accepted
+        let (base, server) = serve(vec![503, 200, 200]);
+        let webhooks = HashMap::from([("pager".to_string(), format!("{}/hook", base))]);
+        let notifier = Notifier::new(webhooks, options());
+
+        let matched = vec![
+            notified("- colour = red", "pager"),
+            notified("- colour = blue", "audit"),
+        ];
+        for _ in 0..3 {
+            notifier.notify(&object(), "abc", &matched, 1_760_000_000);
+        }
+        notifier.flush();
+
+        assert_eq!(
+            notifier.stats(),
+            NotifierStats {
+                delivered: 3,
+                dropped: 0,
+                retries: 1,
+            }
+        );
+
+        let bodies = server.join().unwrap();
+        let batch: Value = serde_json::from_str(&bodies[1]).unwrap();
+        assert_eq!(batch["events"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            batch["events"][0],
+            json!({
+                "webhook": "pager",
+                "rule": "- colour = red",
+                "rule_id": RuleId::new("test.rules", 2, "- colour = red").to_string(),
+                "file": "test.rules",
+                "line": 2,
+                "object_id": "abc",
+                "timestamp": 1_760_000_000u64,
+                "context": { "colour": ["red"] },
+            })
+        );
+        let batch: Value = serde_json::from_str(&bodies[2]).unwrap();
+        assert_eq!(batch["events"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_dropped_after_retries() {
+        let (base, server) = serve(vec![500, 500, 400]);
+        let webhooks = HashMap::from([("pager".to_string(), format!("{}/hook", base))]);
+        let notifier = Notifier::new(webhooks, options());
+
+        let matched = vec![notified("- colour = red", "pager")];
+        notifier.notify(&object(), "abc", &matched, 0);
+        notifier.flush();
+        // Client errors aren't retried
+        notifier.notify(&object(), "abc", &matched, 0);
+        notifier.flush();
+
+        assert_eq!(
+            notifier.stats(),
+            NotifierStats {
+                delivered: 0,
+                dropped: 2,
+                retries: 1,
+            }
+        );
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+}